@@ -0,0 +1,76 @@
+/* C bindings for interledger-packet. See src/lib.rs for the safety
+ * requirements of each function. */
+
+#ifndef INTERLEDGER_PACKET_H
+#define INTERLEDGER_PACKET_H
+
+#include <stddef.h>
+#include <stdint.h>
+
+#ifdef __cplusplus
+extern "C" {
+#endif
+
+#define ILP_OK 0
+#define ILP_ERROR_NULL_POINTER -1
+#define ILP_ERROR_PARSE -2
+#define ILP_ERROR_INVALID_FIELD -3
+#define ILP_ERROR_PANIC -4
+
+#define ILP_PACKET_TYPE_PREPARE 12
+#define ILP_PACKET_TYPE_FULFILL 13
+#define ILP_PACKET_TYPE_REJECT 14
+
+/* Borrowed bytes. `ptr` may be NULL when `len` is zero. */
+typedef struct {
+  const uint8_t *ptr;
+  size_t len;
+} IlpSlice;
+
+/* Bytes owned by the library; release with `ilp_buffer_free`. */
+typedef struct {
+  uint8_t *ptr;
+  size_t len;
+} IlpBuffer;
+
+typedef struct {
+  uint64_t amount;
+  /* Milliseconds since the Unix epoch. */
+  int64_t expires_at_ms;
+  uint8_t execution_condition[32];
+  IlpSlice destination;
+  IlpSlice data;
+} IlpPrepare;
+
+typedef struct {
+  uint8_t fulfillment[32];
+  IlpSlice data;
+} IlpFulfill;
+
+typedef struct {
+  /* ASCII, e.g. "F02" (not NUL-terminated). */
+  uint8_t code[3];
+  /* Empty when there is no `triggered_by` address. */
+  IlpSlice triggered_by;
+  IlpSlice message;
+  IlpSlice data;
+} IlpReject;
+
+int32_t ilp_packet_type(const uint8_t *buffer, size_t len);
+
+/* The slices of `out` point into `buffer`. */
+int32_t ilp_prepare_parse(const uint8_t *buffer, size_t len, IlpPrepare *out);
+int32_t ilp_fulfill_parse(const uint8_t *buffer, size_t len, IlpFulfill *out);
+int32_t ilp_reject_parse(const uint8_t *buffer, size_t len, IlpReject *out);
+
+int32_t ilp_prepare_build(const IlpPrepare *fields, IlpBuffer *out);
+int32_t ilp_fulfill_build(const IlpFulfill *fields, IlpBuffer *out);
+int32_t ilp_reject_build(const IlpReject *fields, IlpBuffer *out);
+
+void ilp_buffer_free(IlpBuffer buffer);
+
+#ifdef __cplusplus
+}
+#endif
+
+#endif /* INTERLEDGER_PACKET_H */
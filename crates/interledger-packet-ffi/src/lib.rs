@@ -0,0 +1,508 @@
+//! # interledger-packet-ffi
+//!
+//! A C ABI for parsing and building ILP packets with `interledger-packet`, so
+//! that components that aren't written in Rust can share the same codec. The
+//! declarations are in `include/interledger_packet.h`.
+//!
+//! Every function returns `ILP_OK` or a (negative) `ILP_ERROR_*` code. The
+//! slices of a parsed packet point into the caller's input buffer. A built
+//! packet is owned by this library until it is released with `ilp_buffer_free`.
+//!
+//! Times are milliseconds since the Unix epoch.
+
+use std::panic::{self, UnwindSafe};
+use std::slice;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use bytes::BytesMut;
+
+pub const ILP_OK: i32 = 0;
+/// A required pointer was null.
+pub const ILP_ERROR_NULL_POINTER: i32 = -1;
+/// The input isn't a valid packet (of the expected type).
+pub const ILP_ERROR_PARSE: i32 = -2;
+/// A field to build a packet from is invalid (e.g. an ILP address).
+pub const ILP_ERROR_INVALID_FIELD: i32 = -3;
+/// An internal error. This is a bug.
+pub const ILP_ERROR_PANIC: i32 = -4;
+
+/// Borrowed bytes. `ptr` may be null when `len` is zero.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IlpSlice {
+    pub ptr: *const u8,
+    pub len: usize,
+}
+
+/// Bytes that are owned by this library.
+#[repr(C)]
+#[derive(Debug)]
+pub struct IlpBuffer {
+    pub ptr: *mut u8,
+    pub len: usize,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IlpPrepare {
+    pub amount: u64,
+    pub expires_at_ms: i64,
+    pub execution_condition: [u8; 32],
+    pub destination: IlpSlice,
+    pub data: IlpSlice,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IlpFulfill {
+    pub fulfillment: [u8; 32],
+    pub data: IlpSlice,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct IlpReject {
+    /// The ASCII error code, e.g. `F02`.
+    pub code: [u8; 3],
+    /// Empty when the Reject has no `triggered_by` address.
+    pub triggered_by: IlpSlice,
+    pub message: IlpSlice,
+    pub data: IlpSlice,
+}
+
+/// Returns the type of the packet in the buffer (`12` for a Prepare, `13` for a
+/// Fulfill, or `14` for a Reject), without validating the rest of it.
+///
+/// # Safety
+///
+/// `buffer` must point to `len` readable bytes.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_packet_type(buffer: *const u8, len: usize) -> i32 {
+    let input = match as_bytes(buffer, len) {
+        Some(input) => input,
+        None => return ILP_ERROR_NULL_POINTER,
+    };
+    match input.first().map(|&byte| ilp::PacketType::try_from(byte)) {
+        Some(Ok(packet_type)) => packet_type as i32,
+        _ => ILP_ERROR_PARSE,
+    }
+}
+
+/// # Safety
+///
+/// `buffer` must point to `len` readable bytes, and `out` to an `IlpPrepare`.
+/// The slices of `out` are only valid as long as `buffer` is.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_prepare_parse(
+    buffer: *const u8,
+    len: usize,
+    out: *mut IlpPrepare,
+) -> i32 {
+    let input = match as_bytes(buffer, len) {
+        Some(input) if !out.is_null() => input,
+        _ => return ILP_ERROR_NULL_POINTER,
+    };
+    guard(|| {
+        let prepare = match ilp::Prepare::try_from(BytesMut::from(input)) {
+            Ok(prepare) => prepare,
+            Err(_error) => return ILP_ERROR_PARSE,
+        };
+        let packet = prepare.as_ref();
+        let mut execution_condition = [0; 32];
+        execution_condition.copy_from_slice(prepare.execution_condition());
+        *out = IlpPrepare {
+            amount: prepare.amount(),
+            expires_at_ms: to_unix_millis(prepare.expires_at()),
+            execution_condition,
+            destination: borrow(input, packet, prepare.destination().as_ref()),
+            data: borrow(input, packet, prepare.data()),
+        };
+        ILP_OK
+    })
+}
+
+/// The expiry is truncated to the millisecond.
+///
+/// # Safety
+///
+/// `fields` must point to an `IlpPrepare` (with valid slices), and `out` to an
+/// `IlpBuffer`. On success, `out` must be released with `ilp_buffer_free`.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_prepare_build(
+    fields: *const IlpPrepare,
+    out: *mut IlpBuffer,
+) -> i32 {
+    if fields.is_null() || out.is_null() {
+        return ILP_ERROR_NULL_POINTER;
+    }
+    let fields = &*fields;
+    let (destination, data) = match (
+        as_bytes(fields.destination.ptr, fields.destination.len),
+        as_bytes(fields.data.ptr, fields.data.len),
+    ) {
+        (Some(destination), Some(data)) => (destination, data),
+        _ => return ILP_ERROR_NULL_POINTER,
+    };
+    guard(|| {
+        let destination = match ilp::Addr::try_from(destination) {
+            Ok(destination) => destination,
+            Err(_error) => return ILP_ERROR_INVALID_FIELD,
+        };
+        let prepare = ilp::PrepareBuilder {
+            amount: fields.amount,
+            expires_at: from_unix_millis(fields.expires_at_ms),
+            execution_condition: &fields.execution_condition,
+            destination,
+            data,
+        }.build();
+        *out = IlpBuffer::from(BytesMut::from(prepare));
+        ILP_OK
+    })
+}
+
+/// # Safety
+///
+/// `buffer` must point to `len` readable bytes, and `out` to an `IlpFulfill`.
+/// The slices of `out` are only valid as long as `buffer` is.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_fulfill_parse(
+    buffer: *const u8,
+    len: usize,
+    out: *mut IlpFulfill,
+) -> i32 {
+    let input = match as_bytes(buffer, len) {
+        Some(input) if !out.is_null() => input,
+        _ => return ILP_ERROR_NULL_POINTER,
+    };
+    guard(|| {
+        let fulfill = match ilp::Fulfill::try_from(BytesMut::from(input)) {
+            Ok(fulfill) => fulfill,
+            Err(_error) => return ILP_ERROR_PARSE,
+        };
+        let mut fulfillment = [0; 32];
+        fulfillment.copy_from_slice(fulfill.fulfillment());
+        *out = IlpFulfill {
+            fulfillment,
+            data: borrow(input, fulfill.as_ref(), fulfill.data()),
+        };
+        ILP_OK
+    })
+}
+
+/// # Safety
+///
+/// `fields` must point to an `IlpFulfill` (with a valid slice), and `out` to an
+/// `IlpBuffer`. On success, `out` must be released with `ilp_buffer_free`.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_fulfill_build(
+    fields: *const IlpFulfill,
+    out: *mut IlpBuffer,
+) -> i32 {
+    if fields.is_null() || out.is_null() {
+        return ILP_ERROR_NULL_POINTER;
+    }
+    let fields = &*fields;
+    let data = match as_bytes(fields.data.ptr, fields.data.len) {
+        Some(data) => data,
+        None => return ILP_ERROR_NULL_POINTER,
+    };
+    guard(|| {
+        let fulfill = ilp::FulfillBuilder {
+            fulfillment: &fields.fulfillment,
+            data,
+        }.build();
+        *out = IlpBuffer::from(BytesMut::from(fulfill));
+        ILP_OK
+    })
+}
+
+/// # Safety
+///
+/// `buffer` must point to `len` readable bytes, and `out` to an `IlpReject`.
+/// The slices of `out` are only valid as long as `buffer` is.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_reject_parse(
+    buffer: *const u8,
+    len: usize,
+    out: *mut IlpReject,
+) -> i32 {
+    let input = match as_bytes(buffer, len) {
+        Some(input) if !out.is_null() => input,
+        _ => return ILP_ERROR_NULL_POINTER,
+    };
+    guard(|| {
+        let reject = match ilp::Reject::try_from(BytesMut::from(input)) {
+            Ok(reject) => reject,
+            Err(_error) => return ILP_ERROR_PARSE,
+        };
+        let packet = reject.as_ref();
+        let triggered_by = match reject.triggered_by() {
+            Some(triggered_by) => borrow(input, packet, triggered_by.as_ref()),
+            None => IlpSlice { ptr: input.as_ptr(), len: 0 },
+        };
+        *out = IlpReject {
+            code: <[u8; 3]>::from(reject.code()),
+            triggered_by,
+            message: borrow(input, packet, reject.message()),
+            data: borrow(input, packet, reject.data()),
+        };
+        ILP_OK
+    })
+}
+
+/// # Safety
+///
+/// `fields` must point to an `IlpReject` (with valid slices), and `out` to an
+/// `IlpBuffer`. On success, `out` must be released with `ilp_buffer_free`.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_reject_build(
+    fields: *const IlpReject,
+    out: *mut IlpBuffer,
+) -> i32 {
+    if fields.is_null() || out.is_null() {
+        return ILP_ERROR_NULL_POINTER;
+    }
+    let fields = &*fields;
+    let (triggered_by, message, data) = match (
+        as_bytes(fields.triggered_by.ptr, fields.triggered_by.len),
+        as_bytes(fields.message.ptr, fields.message.len),
+        as_bytes(fields.data.ptr, fields.data.len),
+    ) {
+        (Some(triggered_by), Some(message), Some(data)) =>
+            (triggered_by, message, data),
+        _ => return ILP_ERROR_NULL_POINTER,
+    };
+    guard(|| {
+        let triggered_by = if triggered_by.is_empty() {
+            None
+        } else {
+            match ilp::Addr::try_from(triggered_by) {
+                Ok(triggered_by) => Some(triggered_by),
+                Err(_error) => return ILP_ERROR_INVALID_FIELD,
+            }
+        };
+        let reject = ilp::RejectBuilder {
+            code: ilp::ErrorCode::new(fields.code),
+            message,
+            triggered_by,
+            data,
+        }.build();
+        *out = IlpBuffer::from(BytesMut::from(reject));
+        ILP_OK
+    })
+}
+
+/// Release a buffer that was returned by one of the `ilp_*_build` functions.
+///
+/// # Safety
+///
+/// `buffer` must have been returned by this library, and not freed already.
+#[no_mangle]
+pub unsafe extern "C" fn ilp_buffer_free(buffer: IlpBuffer) {
+    if !buffer.ptr.is_null() {
+        let bytes = slice::from_raw_parts_mut(buffer.ptr, buffer.len);
+        drop(Box::from_raw(bytes as *mut [u8]));
+    }
+}
+
+impl From<BytesMut> for IlpBuffer {
+    fn from(bytes: BytesMut) -> Self {
+        let bytes = Box::<[u8]>::from(&bytes[..]);
+        let len = bytes.len();
+        IlpBuffer {
+            ptr: Box::into_raw(bytes) as *mut u8,
+            len,
+        }
+    }
+}
+
+unsafe fn as_bytes<'a>(ptr: *const u8, len: usize) -> Option<&'a [u8]> {
+    if len == 0 {
+        Some(&[])
+    } else if ptr.is_null() {
+        None
+    } else {
+        Some(slice::from_raw_parts(ptr, len))
+    }
+}
+
+/// Point to the same bytes as `field` (which is within the parsed `packet`),
+/// but in the caller's `input` (which `packet` is a copy of).
+fn borrow(input: &[u8], packet: &[u8], field: &[u8]) -> IlpSlice {
+    let offset = field.as_ptr() as usize - packet.as_ptr() as usize;
+    IlpSlice {
+        ptr: input[offset..].as_ptr(),
+        len: field.len(),
+    }
+}
+
+/// Unwinding into the caller is undefined behavior.
+fn guard<F: FnOnce() -> i32 + UnwindSafe>(run: F) -> i32 {
+    panic::catch_unwind(run).unwrap_or(ILP_ERROR_PANIC)
+}
+
+fn to_unix_millis(time: SystemTime) -> i64 {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => since_epoch.as_millis() as i64,
+        Err(error) => -(error.duration().as_millis() as i64),
+    }
+}
+
+fn from_unix_millis(millis: i64) -> SystemTime {
+    if millis < 0 {
+        UNIX_EPOCH - Duration::from_millis(millis.wrapping_neg() as u64)
+    } else {
+        UNIX_EPOCH + Duration::from_millis(millis as u64)
+    }
+}
+
+#[cfg(test)]
+mod test_ffi {
+    use std::ptr;
+
+    use super::*;
+
+    static CONDITION: [u8; 32] = [0x11; 32];
+    static FULFILLMENT: [u8; 32] = [0x22; 32];
+
+    fn slice(bytes: &[u8]) -> IlpSlice {
+        IlpSlice { ptr: bytes.as_ptr(), len: bytes.len() }
+    }
+
+    unsafe fn to_vec(slice: IlpSlice) -> Vec<u8> {
+        as_bytes(slice.ptr, slice.len).unwrap().to_vec()
+    }
+
+    #[test]
+    fn test_prepare() {
+        let fields = IlpPrepare {
+            amount: 123,
+            expires_at_ms: 1_528_404_522_483,
+            execution_condition: CONDITION,
+            destination: slice(b"test.alice"),
+            data: slice(b"some data"),
+        };
+        unsafe {
+            let mut buffer = IlpBuffer { ptr: ptr::null_mut(), len: 0 };
+            assert_eq!(ilp_prepare_build(&fields, &mut buffer), ILP_OK);
+            assert_eq!(ilp_packet_type(buffer.ptr, buffer.len), 12);
+            let expect = ilp::PrepareBuilder {
+                amount: 123,
+                expires_at: UNIX_EPOCH + Duration::from_millis(1_528_404_522_483),
+                execution_condition: &CONDITION,
+                destination: ilp::Addr::try_from(b"test.alice").unwrap(),
+                data: b"some data",
+            }.build();
+            assert_eq!(as_bytes(buffer.ptr, buffer.len).unwrap(), expect.as_ref());
+
+            let mut parsed = fields;
+            parsed.destination = slice(b"");
+            assert_eq!(ilp_prepare_parse(buffer.ptr, buffer.len, &mut parsed), ILP_OK);
+            assert_eq!(parsed.amount, 123);
+            assert_eq!(parsed.expires_at_ms, 1_528_404_522_483);
+            assert_eq!(parsed.execution_condition, CONDITION);
+            assert_eq!(to_vec(parsed.destination), b"test.alice");
+            assert_eq!(to_vec(parsed.data), b"some data");
+            // The slices point into the input.
+            assert!(parsed.data.ptr >= buffer.ptr as *const u8);
+
+            assert_eq!(
+                ilp_fulfill_parse(buffer.ptr, buffer.len, &mut IlpFulfill {
+                    fulfillment: FULFILLMENT,
+                    data: slice(b""),
+                }),
+                ILP_ERROR_PARSE,
+            );
+            ilp_buffer_free(buffer);
+        }
+    }
+
+    #[test]
+    fn test_prepare_invalid() {
+        let fields = IlpPrepare {
+            amount: 0,
+            expires_at_ms: -1,
+            execution_condition: CONDITION,
+            destination: slice(b"test.invalid address!"),
+            data: slice(b""),
+        };
+        unsafe {
+            let mut buffer = IlpBuffer { ptr: ptr::null_mut(), len: 0 };
+            assert_eq!(
+                ilp_prepare_build(&fields, &mut buffer),
+                ILP_ERROR_INVALID_FIELD,
+            );
+            assert_eq!(
+                ilp_prepare_build(ptr::null(), &mut buffer),
+                ILP_ERROR_NULL_POINTER,
+            );
+            assert_eq!(ilp_packet_type(ptr::null(), 1), ILP_ERROR_NULL_POINTER);
+            assert_eq!(ilp_packet_type(b"\x01".as_ptr(), 1), ILP_ERROR_PARSE);
+            assert_eq!(ilp_packet_type(ptr::null(), 0), ILP_ERROR_PARSE);
+        }
+    }
+
+    #[test]
+    fn test_fulfill() {
+        let fields = IlpFulfill {
+            fulfillment: FULFILLMENT,
+            data: slice(b"some data"),
+        };
+        unsafe {
+            let mut buffer = IlpBuffer { ptr: ptr::null_mut(), len: 0 };
+            assert_eq!(ilp_fulfill_build(&fields, &mut buffer), ILP_OK);
+            assert_eq!(ilp_packet_type(buffer.ptr, buffer.len), 13);
+
+            let mut parsed = IlpFulfill { fulfillment: [0; 32], data: slice(b"") };
+            assert_eq!(ilp_fulfill_parse(buffer.ptr, buffer.len, &mut parsed), ILP_OK);
+            assert_eq!(parsed.fulfillment, FULFILLMENT);
+            assert_eq!(to_vec(parsed.data), b"some data");
+            ilp_buffer_free(buffer);
+        }
+    }
+
+    #[test]
+    fn test_reject() {
+        let fields = IlpReject {
+            code: *b"F02",
+            triggered_by: slice(b""),
+            message: slice(b"no route"),
+            data: slice(b""),
+        };
+        unsafe {
+            let mut buffer = IlpBuffer { ptr: ptr::null_mut(), len: 0 };
+            assert_eq!(ilp_reject_build(&fields, &mut buffer), ILP_OK);
+            assert_eq!(ilp_packet_type(buffer.ptr, buffer.len), 14);
+
+            let mut parsed = IlpReject {
+                code: *b"T00",
+                triggered_by: slice(b"x"),
+                message: slice(b""),
+                data: slice(b"x"),
+            };
+            assert_eq!(ilp_reject_parse(buffer.ptr, buffer.len, &mut parsed), ILP_OK);
+            assert_eq!(&parsed.code, b"F02");
+            assert_eq!(parsed.triggered_by.len, 0);
+            assert_eq!(to_vec(parsed.message), b"no route");
+            assert_eq!(parsed.data.len, 0);
+            ilp_buffer_free(buffer);
+
+            let fields = IlpReject {
+                triggered_by: slice(b"test.connector"),
+                ..fields
+            };
+            let mut buffer = IlpBuffer { ptr: ptr::null_mut(), len: 0 };
+            assert_eq!(ilp_reject_build(&fields, &mut buffer), ILP_OK);
+            assert_eq!(ilp_reject_parse(buffer.ptr, buffer.len, &mut parsed), ILP_OK);
+            assert_eq!(to_vec(parsed.triggered_by), b"test.connector");
+            ilp_buffer_free(buffer);
+        }
+    }
+
+    #[test]
+    fn test_unix_millis() {
+        for &millis in &[0, 1, -1, 1_528_404_522_483, -62_167_219_200_000] {
+            assert_eq!(to_unix_millis(from_unix_millis(millis)), millis);
+        }
+    }
+}
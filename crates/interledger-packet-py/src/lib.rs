@@ -0,0 +1,407 @@
+//! # interledger-packet-py
+//!
+//! Python bindings for `interledger-packet`, for tooling that reads and writes
+//! ILP packets (e.g. from the relay's logs or from traffic captures).
+//!
+//! ```python
+//! import interledger_packet as ilp
+//!
+//! packet = ilp.parse(raw)
+//! if isinstance(packet, ilp.Reject):
+//!     print(packet.code, packet.triggered_by)
+//! ```
+//!
+//! Invalid packets and fields raise `ValueError`. Times are timezone-aware
+//! `datetime`s (a naive `datetime` is rejected).
+
+use std::convert::TryFrom;
+use std::time::SystemTime;
+
+use bytes::BytesMut;
+use chrono::{DateTime, FixedOffset, Utc};
+use pyo3::exceptions::PyValueError;
+use pyo3::prelude::*;
+use pyo3::types::PyBytes;
+
+#[pyclass(module = "interledger_packet")]
+#[derive(Clone)]
+pub struct Prepare {
+    packet: ilp::Prepare,
+}
+
+#[pymethods]
+impl Prepare {
+    #[new]
+    #[pyo3(signature = (amount, expires_at, execution_condition, destination, data = None))]
+    fn new(
+        amount: u64,
+        expires_at: DateTime<FixedOffset>,
+        execution_condition: &[u8],
+        destination: &str,
+        data: Option<&[u8]>,
+    ) -> PyResult<Self> {
+        let packet = ilp::PrepareBuilder {
+            amount,
+            expires_at: to_system_time(expires_at),
+            execution_condition: to_hash(execution_condition, "execution_condition")?,
+            destination: parse_addr(destination)?,
+            data: data.unwrap_or(&[]),
+        }.build();
+        Ok(Prepare { packet })
+    }
+
+    #[getter]
+    fn amount(&self) -> u64 {
+        self.packet.amount()
+    }
+
+    #[getter]
+    fn expires_at(&self) -> DateTime<Utc> {
+        DateTime::from(self.packet.expires_at())
+    }
+
+    #[getter]
+    fn execution_condition<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.packet.execution_condition())
+    }
+
+    #[getter]
+    fn destination(&self) -> String {
+        self.packet.destination().to_string()
+    }
+
+    #[getter]
+    fn data<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.packet.data())
+    }
+
+    /// Whether this is an ILDCP request.
+    fn is_ildcp_request(&self) -> bool {
+        ilp::ildcp::Request::try_from(self.packet.clone()).is_ok()
+    }
+
+    fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.packet.as_ref())
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        self.packet == other.packet
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.packet)
+    }
+}
+
+#[pyclass(module = "interledger_packet")]
+#[derive(Clone)]
+pub struct Fulfill {
+    packet: ilp::Fulfill,
+}
+
+#[pymethods]
+impl Fulfill {
+    #[new]
+    #[pyo3(signature = (fulfillment, data = None))]
+    fn new(fulfillment: &[u8], data: Option<&[u8]>) -> PyResult<Self> {
+        let packet = ilp::FulfillBuilder {
+            fulfillment: to_hash(fulfillment, "fulfillment")?,
+            data: data.unwrap_or(&[]),
+        }.build();
+        Ok(Fulfill { packet })
+    }
+
+    #[getter]
+    fn fulfillment<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.packet.fulfillment())
+    }
+
+    #[getter]
+    fn data<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.packet.data())
+    }
+
+    fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.packet.as_ref())
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        self.packet == other.packet
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.packet)
+    }
+}
+
+#[pyclass(module = "interledger_packet")]
+#[derive(Clone)]
+pub struct Reject {
+    packet: ilp::Reject,
+}
+
+#[pymethods]
+impl Reject {
+    #[new]
+    #[pyo3(signature = (code, message = None, triggered_by = None, data = None))]
+    fn new(
+        code: &str,
+        message: Option<&[u8]>,
+        triggered_by: Option<&str>,
+        data: Option<&[u8]>,
+    ) -> PyResult<Self> {
+        let code = match code.as_bytes() {
+            &[a, b, c] => ilp::ErrorCode::new([a, b, c]),
+            _ => return Err(PyValueError::new_err("code must be 3 characters")),
+        };
+        let triggered_by = match triggered_by {
+            Some(triggered_by) => Some(parse_addr(triggered_by)?),
+            None => None,
+        };
+        let packet = ilp::RejectBuilder {
+            code,
+            message: message.unwrap_or(&[]),
+            triggered_by,
+            data: data.unwrap_or(&[]),
+        }.build();
+        Ok(Reject { packet })
+    }
+
+    /// The error code, e.g. `"F02"`.
+    #[getter]
+    fn code(&self) -> String {
+        self.packet.code().to_string()
+    }
+
+    #[getter]
+    fn triggered_by(&self) -> Option<String> {
+        self.packet.triggered_by()
+            .map(|triggered_by| triggered_by.to_string())
+    }
+
+    #[getter]
+    fn message<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.packet.message())
+    }
+
+    #[getter]
+    fn data<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.packet.data())
+    }
+
+    fn __bytes__<'py>(&self, py: Python<'py>) -> Bound<'py, PyBytes> {
+        PyBytes::new(py, self.packet.as_ref())
+    }
+
+    fn __eq__(&self, other: &Self) -> bool {
+        self.packet == other.packet
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.packet)
+    }
+}
+
+/// The ILDCP (peer config) response, carried in the data of a Fulfill.
+#[pyclass(module = "interledger_packet")]
+#[derive(Clone)]
+pub struct IldcpResponse {
+    response: ilp::ildcp::Response,
+}
+
+#[pymethods]
+impl IldcpResponse {
+    #[new]
+    fn new(client_address: &str, asset_scale: u8, asset_code: &str)
+        -> PyResult<Self>
+    {
+        let response = ilp::ildcp::ResponseBuilder {
+            client_address: parse_addr(client_address)?,
+            asset_scale,
+            asset_code: asset_code.as_bytes(),
+        }.build();
+        Ok(IldcpResponse { response })
+    }
+
+    #[staticmethod]
+    fn from_fulfill(fulfill: &Fulfill) -> PyResult<Self> {
+        ilp::ildcp::Response::try_from(fulfill.packet.clone())
+            .map(|response| IldcpResponse { response })
+            .map_err(|error| PyValueError::new_err(error.to_string()))
+    }
+
+    fn to_fulfill(&self) -> Fulfill {
+        Fulfill {
+            packet: ilp::Fulfill::from(self.response.clone()),
+        }
+    }
+
+    #[getter]
+    fn client_address(&self) -> String {
+        self.response.client_address().to_string()
+    }
+
+    #[getter]
+    fn asset_scale(&self) -> u8 {
+        self.response.asset_scale()
+    }
+
+    #[getter]
+    fn asset_code(&self) -> String {
+        String::from_utf8_lossy(self.response.asset_code()).into_owned()
+    }
+
+    fn __repr__(&self) -> String {
+        format!("{:?}", self.response)
+    }
+}
+
+/// Parse a Prepare, Fulfill, or Reject.
+#[pyfunction]
+fn parse(py: Python, buffer: &[u8]) -> PyResult<PyObject> {
+    match ilp::Packet::try_from(BytesMut::from(buffer)) {
+        Ok(ilp::Packet::Prepare(packet)) =>
+            Ok(Py::new(py, Prepare { packet })?.into_any()),
+        Ok(ilp::Packet::Fulfill(packet)) =>
+            Ok(Py::new(py, Fulfill { packet })?.into_any()),
+        Ok(ilp::Packet::Reject(packet)) =>
+            Ok(Py::new(py, Reject { packet })?.into_any()),
+        Err(error) => Err(PyValueError::new_err(error.to_string())),
+    }
+}
+
+/// Build an ILDCP request. The relay sends these with a 60 second expiry.
+#[pyfunction]
+fn ildcp_request(expires_at: DateTime<FixedOffset>) -> Prepare {
+    Prepare {
+        packet: ilp::ildcp::Request::new()
+            .to_prepare_expiring_at(to_system_time(expires_at)),
+    }
+}
+
+#[pymodule]
+fn interledger_packet(module: &Bound<PyModule>) -> PyResult<()> {
+    module.add_class::<Prepare>()?;
+    module.add_class::<Fulfill>()?;
+    module.add_class::<Reject>()?;
+    module.add_class::<IldcpResponse>()?;
+    module.add_function(wrap_pyfunction!(parse, module)?)?;
+    module.add_function(wrap_pyfunction!(ildcp_request, module)?)?;
+    Ok(())
+}
+
+fn parse_addr(address: &str) -> PyResult<ilp::Addr<'_>> {
+    ilp::Addr::try_from(address.as_bytes())
+        .map_err(|error| PyValueError::new_err(error.to_string()))
+}
+
+fn to_hash<'a>(bytes: &'a [u8], name: &str) -> PyResult<&'a [u8; 32]> {
+    <&[u8; 32]>::try_from(bytes).map_err(|_error| {
+        PyValueError::new_err(format!("{} must be 32 bytes", name))
+    })
+}
+
+fn to_system_time(time: DateTime<FixedOffset>) -> SystemTime {
+    SystemTime::from(time.with_timezone(&Utc))
+}
+
+#[cfg(test)]
+mod test_bindings {
+    use std::ffi::CString;
+
+    use pyo3::types::PyDict;
+
+    use super::*;
+
+    fn run(code: &str) {
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "interledger_packet").unwrap();
+            interledger_packet(&module).unwrap();
+            let globals = PyDict::new(py);
+            globals.set_item("ilp", module).unwrap();
+            let code = CString::new(format!("import datetime\n{}", code)).unwrap();
+            if let Err(error) = py.run(&code, Some(&globals), None) {
+                error.print(py);
+                panic!("python error");
+            }
+        });
+    }
+
+    #[test]
+    fn test_prepare() {
+        run(r#"
+expires_at = datetime.datetime(2018, 6, 7, 20, 48, 42, 483000, tzinfo=datetime.timezone.utc)
+prepare = ilp.Prepare(107, expires_at, b"\x11" * 32, "example.alice", b"some data")
+assert prepare.amount == 107
+assert prepare.expires_at == expires_at
+assert prepare.execution_condition == b"\x11" * 32
+assert prepare.destination == "example.alice"
+assert prepare.data == b"some data"
+assert not prepare.is_ildcp_request()
+
+parsed = ilp.parse(bytes(prepare))
+assert isinstance(parsed, ilp.Prepare)
+assert parsed == prepare
+"#);
+    }
+
+    #[test]
+    fn test_fulfill() {
+        run(r#"
+fulfill = ilp.Fulfill(b"\x22" * 32)
+assert fulfill.data == b""
+parsed = ilp.parse(bytes(fulfill))
+assert isinstance(parsed, ilp.Fulfill)
+assert parsed.fulfillment == b"\x22" * 32
+"#);
+    }
+
+    #[test]
+    fn test_reject() {
+        run(r#"
+reject = ilp.Reject("F02", b"no route", triggered_by="example.connector")
+parsed = ilp.parse(bytes(reject))
+assert isinstance(parsed, ilp.Reject)
+assert parsed.code == "F02"
+assert parsed.message == b"no route"
+assert parsed.triggered_by == "example.connector"
+assert ilp.Reject("T00").triggered_by is None
+"#);
+    }
+
+    #[test]
+    fn test_invalid() {
+        run(r#"
+for make in [
+    lambda: ilp.parse(b"\x0c\x01"),
+    lambda: ilp.parse(b""),
+    lambda: ilp.Reject("F2"),
+    lambda: ilp.Fulfill(b"short"),
+    lambda: ilp.Prepare(1, datetime.datetime.now(datetime.timezone.utc), b"\x00" * 32, "invalid address"),
+]:
+    try:
+        make()
+    except ValueError:
+        pass
+    else:
+        raise AssertionError("expected ValueError")
+"#);
+    }
+
+    #[test]
+    fn test_ildcp() {
+        run(r#"
+expires_at = datetime.datetime.now(datetime.timezone.utc)
+request = ilp.parse(bytes(ilp.ildcp_request(expires_at)))
+assert request.is_ildcp_request()
+assert request.destination == "peer.config"
+
+response = ilp.IldcpResponse("example.alice", 9, "XRP")
+parsed = ilp.IldcpResponse.from_fulfill(ilp.parse(bytes(response.to_fulfill())))
+assert parsed.client_address == "example.alice"
+assert parsed.asset_scale == 9
+assert parsed.asset_code == "XRP"
+"#);
+    }
+}
@@ -0,0 +1,19 @@
+#![no_main]
+use bytes::Bytes;
+use libfuzzer_sys::fuzz_target;
+
+use interledger_packet::{Addr, Address};
+
+fuzz_target!(|data: &[u8]| {
+    let addr = Addr::try_from(data);
+    let address = Address::try_from(Bytes::copy_from_slice(data));
+    assert_eq!(addr.is_ok(), address.is_ok());
+    if let Ok(addr) = addr {
+        assert_eq!(addr.to_string().as_bytes(), data);
+        let _ = format!("{:?}", addr);
+        let _ = addr.scheme();
+        let _ = addr.split_connection_tag();
+        assert!(addr.without_connection_tag().connection_tag().is_none());
+        let _ = addr.with_suffix(b"suffix");
+    }
+});
@@ -0,0 +1,18 @@
+#![no_main]
+use libfuzzer_sys::fuzz_target;
+
+use interledger_packet::{FulfillBuilder, PEER_PROTOCOL_FULFILLMENT};
+use interledger_packet::ildcp;
+
+fuzz_target!(|data: &[u8]| {
+    let fulfill = FulfillBuilder {
+        fulfillment: PEER_PROTOCOL_FULFILLMENT,
+        data,
+    }.build();
+    if let Ok(response) = ildcp::Response::try_from(fulfill) {
+        let _ = format!("{:?}", response);
+        let _ = response.client_address().to_string();
+        let _ = response.asset_scale();
+        let _ = response.asset_code();
+    }
+});
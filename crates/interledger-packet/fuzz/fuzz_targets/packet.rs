@@ -0,0 +1,32 @@
+#![no_main]
+use bytes::BytesMut;
+use libfuzzer_sys::fuzz_target;
+
+use interledger_packet::{Packet, PrepareHeader};
+
+fuzz_target!(|data: &[u8]| {
+    let validated = Packet::validate(data);
+    let _ = PrepareHeader::peek(data);
+
+    let packet = Packet::try_from(BytesMut::from(data));
+    assert_eq!(validated.is_ok(), packet.is_ok());
+    // Exercise the accessors (and formatting) of whatever parsed.
+    if let Ok(packet) = packet {
+        let _ = format!("{:?}", packet);
+        match packet {
+            Packet::Prepare(prepare) => {
+                let _ = prepare.destination().to_string();
+                let _ = prepare.expires_at();
+                let _ = prepare.data();
+            },
+            Packet::Fulfill(fulfill) => {
+                let _ = fulfill.data();
+            },
+            Packet::Reject(reject) => {
+                let _ = reject.to_string();
+                let _ = reject.triggered_by().map(|addr| addr.to_string());
+                let _ = reject.data();
+            },
+        }
+    }
+});
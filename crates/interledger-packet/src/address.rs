@@ -13,6 +13,9 @@ use std::str;
 use bytes::{BufMut, Bytes, BytesMut};
 
 const MAX_ADDRESS_LENGTH: usize = 1023;
+/// Separates a STREAM connection tag from the rest of the address, e.g.
+/// `g.alice.1234~order-5`.
+const CONNECTION_TAG_SEPARATOR: u8 = b'~';
 
 /// An ILP address backed by `Bytes`.
 #[derive(Clone, Eq, Hash, PartialEq)]
@@ -55,6 +58,43 @@ impl Address {
         self.as_addr().with_suffix(suffix)
     }
 
+    pub fn try_with_suffix(&self, segments: &[&[u8]])
+        -> Result<Self, AddressError>
+    {
+        self.as_addr().try_with_suffix(segments)
+    }
+
+    #[inline]
+    pub fn parent(&self) -> Option<Addr<'_>> {
+        self.as_addr().parent()
+    }
+
+    #[inline]
+    pub fn segments(&self) -> impl Iterator<Item = &[u8]> {
+        self.as_addr().segments()
+    }
+
+    #[inline]
+    pub fn starts_with_prefix(&self, prefix: &[u8]) -> bool {
+        self.as_addr().starts_with_prefix(prefix)
+    }
+
+    pub fn with_connection_tag(&self, tag: &[u8])
+        -> Result<Self, AddressError>
+    {
+        self.as_addr().with_connection_tag(tag)
+    }
+
+    #[inline]
+    pub fn connection_tag(&self) -> Option<&[u8]> {
+        self.as_addr().connection_tag()
+    }
+
+    #[inline]
+    pub fn without_connection_tag(&self) -> Addr<'_> {
+        self.as_addr().without_connection_tag()
+    }
+
     #[inline]
     pub fn as_addr(&self) -> Addr {
         Addr(self.0.as_ref())
@@ -170,16 +210,101 @@ impl<'a> Addr<'a> {
         Address::try_from(new_address.freeze())
     }
 
+    /// Like `with_suffix`, but appends several segments, e.g. `["bob", "123"]`
+    /// makes `test.alice` into `test.alice.bob.123`. Each segment must be
+    /// non-empty, and can't contain a `.`.
+    pub fn try_with_suffix(&self, segments: &[&[u8]])
+        -> Result<Address, AddressError>
+    {
+        let suffix_len: usize = segments
+            .iter()
+            .map(|segment| 1 + segment.len())
+            .sum();
+        let mut new_address = BytesMut::with_capacity(self.len() + suffix_len);
+
+        new_address.put_slice(self.0.as_ref());
+        for segment in segments {
+            if segment.contains(&b'.') {
+                return Err(AddressError {});
+            }
+            new_address.put_u8(b'.');
+            new_address.put_slice(segment);
+        }
+
+        Address::try_from(new_address.freeze())
+    }
+
+    /// The address without its last segment, e.g. `test.alice` for
+    /// `test.alice.1234`. A scheme alone isn't an address, so `test.alice`
+    /// has no parent.
+    pub fn parent(&self) -> Option<Addr<'a>> {
+        let end = self.0.iter().rposition(|&byte| byte == b'.')?;
+        let parent = &self.0[..end];
+        if parent.contains(&b'.') {
+            Some(Addr(parent))
+        } else {
+            None
+        }
+    }
+
+    /// The `.`-separated segments, starting with the scheme.
+    pub fn segments(&self) -> impl Iterator<Item = &'a [u8]> {
+        self.0.split(|&byte| byte == b'.')
+    }
+
+    /// Whether the address is `prefix`, or under it. Unlike
+    /// `<[u8]>::starts_with`, the prefix must end on a segment boundary:
+    /// `test.alice.1234` starts with `test.alice`, but not `test.ali`.
+    pub fn starts_with_prefix(&self, prefix: &[u8]) -> bool {
+        self.0.starts_with(prefix)
+            && (self.0.len() == prefix.len() || self.0[prefix.len()] == b'.')
+    }
+
     fn as_str(&self) -> &str {
         str::from_utf8(self.0).unwrap()
     }
 
+    /// Splits the address at its first `~`: into the address without the
+    /// STREAM connection tag, and the tag (which may contain more `~`s).
     pub fn split_connection_tag(&self) -> Option<(Addr, &[u8])> {
         self.0
             .iter()
-            .enumerate()
-            .find(|(_index, chr)| **chr == b'~')
-            .map(|(index, _chr)| (Addr(&self.0[..index]), &self.0[index+1..]))
+            .position(|&byte| byte == CONNECTION_TAG_SEPARATOR)
+            .map(|index| (Addr(&self.0[..index]), &self.0[index+1..]))
+    }
+
+    /// Appends a STREAM connection tag (`~` and the `tag`), like the JS
+    /// connector's `ilp-protocol-stream`, e.g. to tell apart the payments to a
+    /// receiver. The tag must be valid (see `is_valid_connection_tag`). If the
+    /// address already has a tag, the new one is appended to it.
+    pub fn with_connection_tag(&self, tag: &[u8])
+        -> Result<Address, AddressError>
+    {
+        if !is_valid_connection_tag(tag) {
+            return Err(AddressError {});
+        }
+        let mut new_address = BytesMut::with_capacity(self.len() + 1 + tag.len());
+        new_address.put_slice(self.0.as_ref());
+        new_address.put_u8(CONNECTION_TAG_SEPARATOR);
+        new_address.put_slice(tag);
+        Address::try_from(new_address.freeze())
+    }
+
+    /// The STREAM connection tag: everything after the first `~`.
+    pub fn connection_tag(&self) -> Option<&'a [u8]> {
+        self.0
+            .iter()
+            .position(|&byte| byte == CONNECTION_TAG_SEPARATOR)
+            .map(|index| &self.0[index + 1..])
+    }
+
+    /// The address without its STREAM connection tag (if any), e.g. to group
+    /// the payments to a receiver.
+    pub fn without_connection_tag(&self) -> Addr<'a> {
+        match self.0.iter().position(|&byte| byte == CONNECTION_TAG_SEPARATOR) {
+            Some(index) => Addr(&self.0[..index]),
+            None => *self,
+        }
     }
 }
 
@@ -217,6 +342,14 @@ impl<'a> PartialEq<[u8]> for Addr<'a> {
     }
 }
 
+/// Whether the `tag` can be appended to an address as a STREAM connection tag
+/// (see `Addr::with_connection_tag`): it must be non-empty, and only contain
+/// `A-Z`, `a-z`, `0-9`, `_`, `-`, and `~`, like an address segment without
+/// `.`s. An address can't be longer than 1023 bytes (including the tag).
+pub fn is_valid_connection_tag(tag: &[u8]) -> bool {
+    !tag.is_empty() && tag.iter().all(|&byte| is_segment_byte(byte))
+}
+
 static SCHEMES: &[&[u8]] = &[
     b"g", b"private", b"example", b"peer", b"self",
     b"test", b"test1", b"test2", b"test3", b"local",
@@ -431,6 +564,64 @@ mod test_addr {
         });
     }
 
+    #[test]
+    fn test_try_with_suffix() {
+        assert_eq!(
+            Addr::new(b"test.alice")
+                .try_with_suffix(&[b"bob", b"1234"])
+                .unwrap(),
+            Address::new(b"test.alice.bob.1234"),
+        );
+        assert_eq!(
+            Address::new(b"test.alice").try_with_suffix(&[]).unwrap(),
+            Address::new(b"test.alice"),
+        );
+        let invalid: &[&[&[u8]]] = &[
+            &[b""],
+            &[b"bob", b""],
+            &[b"bob.1234"],
+            &[b"b b"],
+        ];
+        for segments in invalid {
+            assert!(Addr::new(b"test.alice").try_with_suffix(segments).is_err());
+        }
+    }
+
+    #[test]
+    fn test_parent() {
+        assert_eq!(
+            Addr::new(b"test.alice.1234").parent(),
+            Some(Addr::new(b"test.alice")),
+        );
+        assert_eq!(
+            Address::new(b"test.alice.bob.1234").parent(),
+            Some(Addr::new(b"test.alice.bob")),
+        );
+        assert_eq!(Addr::new(b"test.alice").parent(), None);
+    }
+
+    #[test]
+    fn test_segments() {
+        assert_eq!(
+            Addr::new(b"test.alice.1234").segments().collect::<Vec<_>>(),
+            vec![&b"test"[..], b"alice", b"1234"],
+        );
+        assert_eq!(Address::new(b"g.bob").segments().count(), 2);
+    }
+
+    #[test]
+    fn test_starts_with_prefix() {
+        let addr = Addr::new(b"test.alice.1234");
+        assert!(addr.starts_with_prefix(b"test"));
+        assert!(addr.starts_with_prefix(b"test.alice"));
+        assert!(addr.starts_with_prefix(b"test.alice.1234"));
+        assert!(!addr.starts_with_prefix(b"test.ali"));
+        assert!(!addr.starts_with_prefix(b"test.alice."));
+        assert!(!addr.starts_with_prefix(b"test.alice.1234.5"));
+        assert!(!addr.starts_with_prefix(b""));
+        assert!(Address::new(b"g.bob").starts_with_prefix(b"g"));
+    }
+
     #[test]
     fn test_split_connection_tag() {
         assert_eq!(Addr::new(b"test.alice").split_connection_tag(), None);
@@ -444,6 +635,46 @@ mod test_addr {
         );
     }
 
+    #[test]
+    fn test_with_connection_tag() {
+        let address = Addr::new(b"test.alice")
+            .with_connection_tag(b"order-5_A")
+            .unwrap();
+        assert_eq!(address, Address::new(b"test.alice~order-5_A"));
+        assert_eq!(address.connection_tag(), Some(&b"order-5_A"[..]));
+        assert_eq!(address.without_connection_tag(), Addr::new(b"test.alice"));
+        assert_eq!(
+            address.with_connection_tag(b"2").unwrap(),
+            Address::new(b"test.alice~order-5_A~2"),
+        );
+
+        for tag in &[&b""[..], b"a.b", b"a b", b"a/b"] {
+            assert!(Addr::new(b"test.alice").with_connection_tag(tag).is_err());
+        }
+        let too_long = [b'a'; 1023 - b"test.alice~".len() + 1];
+        assert!(Addr::new(b"test.alice").with_connection_tag(&too_long).is_err());
+    }
+
+    #[test]
+    fn test_without_connection_tag() {
+        assert_eq!(Addr::new(b"test.alice").connection_tag(), None);
+        assert_eq!(
+            Addr::new(b"test.alice").without_connection_tag(),
+            Addr::new(b"test.alice"),
+        );
+        let addr = Addr::new(b"test.alice~1234~5678");
+        assert_eq!(addr.connection_tag(), Some(&b"1234~5678"[..]));
+        assert_eq!(addr.without_connection_tag(), Addr::new(b"test.alice"));
+    }
+
+    #[test]
+    fn test_is_valid_connection_tag() {
+        assert!(is_valid_connection_tag(b"aZ09_-~"));
+        assert!(!is_valid_connection_tag(b""));
+        assert!(!is_valid_connection_tag(b"a.b"));
+        assert!(!is_valid_connection_tag(b"a\x00"));
+    }
+
     #[test]
     fn test_debug() {
         assert_eq!(
@@ -0,0 +1,375 @@
+//! Bilateral Transfer Protocol (BTP/2.0) packets.
+//!
+//! # References
+//!
+//!   * <https://github.com/interledger/rfcs/blob/master/0023-bilateral-transfer-protocol/0023-bilateral-transfer-protocol.md>
+//!
+
+use std::time::SystemTime;
+
+use byteorder::{BigEndian, ReadBytesExt};
+use bytes::{BufMut, Bytes, BytesMut};
+use chrono::{DateTime, Utc};
+
+use crate::{ErrorCode, ParseError};
+use crate::oer::{BufOerExt, MutBufOerExt};
+
+/// The protocol that carries ILP Prepares, Fulfills, and Rejects.
+pub static PROTOCOL_ILP: &str = "ilp";
+/// The first Message on a connection authenticates the client with these
+/// protocols.
+pub static PROTOCOL_AUTH: &str = "auth";
+pub static PROTOCOL_AUTH_USERNAME: &str = "auth_username";
+pub static PROTOCOL_AUTH_TOKEN: &str = "auth_token";
+
+static GENERALIZED_TIME_FORMAT: &str = "%Y%m%d%H%M%S%.3fZ";
+const ERROR_CODE_LEN: usize = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum PacketType {
+    Response = 1,
+    Error = 2,
+    Message = 6,
+    Transfer = 7,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+#[repr(u8)]
+pub enum ContentType {
+    OctetStream = 0,
+    TextPlainUtf8 = 1,
+    Json = 2,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Packet {
+    Response {
+        request_id: u32,
+        protocol_data: Vec<ProtocolData>,
+    },
+    Error {
+        request_id: u32,
+        error: ErrorDetails,
+    },
+    Message {
+        request_id: u32,
+        protocol_data: Vec<ProtocolData>,
+    },
+    Transfer {
+        request_id: u32,
+        amount: u64,
+        protocol_data: Vec<ProtocolData>,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ErrorDetails {
+    pub code: ErrorCode,
+    pub name: String,
+    /// A `GeneralizedTime`, e.g. `20171230120000.000Z`.
+    pub triggered_at: String,
+    pub data: Bytes,
+    pub protocol_data: Vec<ProtocolData>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ProtocolData {
+    pub protocol_name: String,
+    pub content_type: ContentType,
+    pub data: Bytes,
+}
+
+impl Packet {
+    pub fn try_from(mut buffer: &[u8]) -> Result<Self, ParseError> {
+        let packet_type = buffer.read_u8()?;
+        let request_id = buffer.read_u32::<BigEndian>()?;
+        let mut contents = buffer.read_var_octet_string()?;
+        let reader = &mut contents;
+
+        Ok(match packet_type {
+            1 => Packet::Response {
+                request_id,
+                protocol_data: read_protocol_data(reader)?,
+            },
+            2 => {
+                if reader.len() < ERROR_CODE_LEN {
+                    return Err(ParseError::InvalidPacket({
+                        "BTP error code too short".to_owned()
+                    }));
+                }
+                let mut code = [0; ERROR_CODE_LEN];
+                code.copy_from_slice(&reader[..ERROR_CODE_LEN]);
+                reader.skip(ERROR_CODE_LEN)?;
+                let code = ErrorCode::new(code);
+                if !code.is_ia5() {
+                    return Err(ParseError::InvalidPacket({
+                        "invalid BTP error code".to_owned()
+                    }));
+                }
+                Packet::Error {
+                    request_id,
+                    error: ErrorDetails {
+                        code,
+                        name: read_string(reader)?,
+                        triggered_at: read_string(reader)?,
+                        data: Bytes::copy_from_slice(reader.read_var_octet_string()?),
+                        protocol_data: read_protocol_data(reader)?,
+                    },
+                }
+            },
+            6 => Packet::Message {
+                request_id,
+                protocol_data: read_protocol_data(reader)?,
+            },
+            7 => Packet::Transfer {
+                request_id,
+                amount: reader.read_u64::<BigEndian>()?,
+                protocol_data: read_protocol_data(reader)?,
+            },
+            _ => return Err(ParseError::WrongType({
+                format!("unknown BTP packet type: {}", packet_type)
+            })),
+        })
+    }
+
+    pub fn packet_type(&self) -> PacketType {
+        match self {
+            Packet::Response { .. } => PacketType::Response,
+            Packet::Error { .. } => PacketType::Error,
+            Packet::Message { .. } => PacketType::Message,
+            Packet::Transfer { .. } => PacketType::Transfer,
+        }
+    }
+
+    pub fn request_id(&self) -> u32 {
+        match self {
+            Packet::Response { request_id, .. } => *request_id,
+            Packet::Error { request_id, .. } => *request_id,
+            Packet::Message { request_id, .. } => *request_id,
+            Packet::Transfer { request_id, .. } => *request_id,
+        }
+    }
+
+    pub fn protocol_data(&self) -> &[ProtocolData] {
+        match self {
+            Packet::Response { protocol_data, .. } => protocol_data,
+            Packet::Error { error, .. } => &error.protocol_data,
+            Packet::Message { protocol_data, .. } => protocol_data,
+            Packet::Transfer { protocol_data, .. } => protocol_data,
+        }
+    }
+
+    /// Find the data of the first entry for the protocol `protocol_name`.
+    pub fn protocol(&self, protocol_name: &str) -> Option<&ProtocolData> {
+        self.protocol_data()
+            .iter()
+            .find(|entry| entry.protocol_name == protocol_name)
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut contents = BytesMut::new();
+        match self {
+            Packet::Response { protocol_data, .. }
+            | Packet::Message { protocol_data, .. } => {
+                put_protocol_data(&mut contents, protocol_data);
+            },
+            Packet::Error { error, .. } => {
+                contents.put_slice(&<[u8; 3]>::from(error.code));
+                contents.put_var_octet_string(error.name.as_bytes());
+                contents.put_var_octet_string(error.triggered_at.as_bytes());
+                contents.put_var_octet_string(&error.data[..]);
+                put_protocol_data(&mut contents, &error.protocol_data);
+            },
+            Packet::Transfer { amount, protocol_data, .. } => {
+                contents.put_u64(*amount);
+                put_protocol_data(&mut contents, protocol_data);
+            },
+        }
+
+        let mut buffer = BytesMut::with_capacity(1 + 4 + 9 + contents.len());
+        buffer.put_u8(self.packet_type() as u8);
+        buffer.put_u32(self.request_id());
+        buffer.put_var_octet_string(contents.freeze());
+        buffer.freeze()
+    }
+}
+
+impl ErrorDetails {
+    /// An error triggered now, without any data.
+    #[cfg(feature = "clock")]
+    pub fn new(code: ErrorCode, name: &str) -> Self {
+        ErrorDetails::new_at(code, name, SystemTime::now())
+    }
+
+    /// Like `new`, but doesn't read the system clock.
+    pub fn new_at(code: ErrorCode, name: &str, triggered_at: SystemTime) -> Self {
+        ErrorDetails {
+            code,
+            name: name.to_owned(),
+            triggered_at: DateTime::<Utc>::from(triggered_at)
+                .format(GENERALIZED_TIME_FORMAT)
+                .to_string(),
+            data: Bytes::new(),
+            protocol_data: Vec::new(),
+        }
+    }
+}
+
+impl ProtocolData {
+    pub fn new(
+        protocol_name: &str,
+        content_type: ContentType,
+        data: Bytes,
+    ) -> Self {
+        ProtocolData {
+            protocol_name: protocol_name.to_owned(),
+            content_type,
+            data,
+        }
+    }
+}
+
+fn read_string(reader: &mut &[u8]) -> Result<String, ParseError> {
+    Ok(String::from_utf8(reader.read_var_octet_string()?.to_vec())?)
+}
+
+fn read_protocol_data(reader: &mut &[u8])
+    -> Result<Vec<ProtocolData>, ParseError>
+{
+    let count = reader.read_var_uint()?;
+    // Each entry is at least 3 bytes, so don't trust larger counts.
+    let mut protocol_data = Vec::with_capacity(std::cmp::min(
+        count as usize,
+        reader.len() / 3,
+    ));
+    for _ in 0..count {
+        let protocol_name = read_string(reader)?;
+        let content_type = match reader.read_u8()? {
+            0 => ContentType::OctetStream,
+            1 => ContentType::TextPlainUtf8,
+            2 => ContentType::Json,
+            content_type => return Err(ParseError::InvalidPacket({
+                format!("unknown BTP content type: {}", content_type)
+            })),
+        };
+        let data = Bytes::copy_from_slice(reader.read_var_octet_string()?);
+        protocol_data.push(ProtocolData { protocol_name, content_type, data });
+    }
+    Ok(protocol_data)
+}
+
+fn put_protocol_data(buffer: &mut BytesMut, protocol_data: &[ProtocolData]) {
+    buffer.put_var_uint(protocol_data.len() as u64);
+    for entry in protocol_data {
+        buffer.put_var_octet_string(entry.protocol_name.as_bytes());
+        buffer.put_u8(entry.content_type as u8);
+        buffer.put_var_octet_string(&entry.data[..]);
+    }
+}
+
+#[cfg(test)]
+mod test_packet {
+    use lazy_static::lazy_static;
+
+    use super::*;
+
+    static MESSAGE_BYTES: &[u8] = b"\
+        \x06\x00\x00\x00\x01\x0a\
+        \x01\x01\x03\x69\x6c\x70\x00\x02\x01\x02\
+    ";
+
+    static TRANSFER_BYTES: &[u8] = b"\
+        \x07\x00\x00\x00\x03\x0a\
+        \x00\x00\x00\x00\x00\x00\x00\x64\x01\x00\
+    ";
+
+    lazy_static! {
+        static ref MESSAGE: Packet = Packet::Message {
+            request_id: 1,
+            protocol_data: vec![ProtocolData::new(
+                PROTOCOL_ILP,
+                ContentType::OctetStream,
+                Bytes::from_static(b"\x01\x02"),
+            )],
+        };
+
+        static ref ERROR: Packet = Packet::Error {
+            request_id: 2,
+            error: ErrorDetails {
+                code: ErrorCode::F00_BAD_REQUEST,
+                name: "NotAcceptedError".to_owned(),
+                triggered_at: "20171230120000.000Z".to_owned(),
+                data: Bytes::from_static(b"error data"),
+                protocol_data: vec![ProtocolData::new(
+                    "test",
+                    ContentType::Json,
+                    Bytes::from_static(b"{}"),
+                )],
+            },
+        };
+    }
+
+    #[test]
+    fn test_try_from() {
+        assert_eq!(Packet::try_from(MESSAGE_BYTES).unwrap(), *MESSAGE);
+        assert_eq!(
+            Packet::try_from(TRANSFER_BYTES).unwrap(),
+            Packet::Transfer {
+                request_id: 3,
+                amount: 100,
+                protocol_data: vec![],
+            },
+        );
+
+        // Unknown packet type:
+        assert!(Packet::try_from(b"\x05\x00\x00\x00\x01\x01\x00").is_err());
+        // Unknown content type:
+        assert!(Packet::try_from(b"\x06\x00\x00\x00\x01\x05\x01\x01\x00\x03\x00").is_err());
+        // Truncated:
+        assert!(Packet::try_from(&MESSAGE_BYTES[..10]).is_err());
+        // Error code isn't IA5:
+        let mut invalid_code = ERROR.clone();
+        if let Packet::Error { error, .. } = &mut invalid_code {
+            error.code = ErrorCode::new(*b"\x81F0");
+        }
+        assert!(Packet::try_from(&invalid_code.to_bytes()[..]).is_err());
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        assert_eq!(MESSAGE.to_bytes().as_ref(), MESSAGE_BYTES);
+        assert_eq!(
+            Packet::try_from(&ERROR.to_bytes()[..]).unwrap(),
+            *ERROR,
+        );
+    }
+
+    #[test]
+    fn test_protocol() {
+        assert_eq!(MESSAGE.request_id(), 1);
+        assert_eq!(MESSAGE.packet_type(), PacketType::Message);
+        assert_eq!(
+            MESSAGE.protocol(PROTOCOL_ILP).map(|entry| &entry.data[..]),
+            Some(&b"\x01\x02"[..]),
+        );
+        assert_eq!(MESSAGE.protocol(PROTOCOL_AUTH), None);
+        assert!(ERROR.protocol("test").is_some());
+    }
+
+    #[test]
+    fn test_error_details() {
+        let error = ErrorDetails::new(ErrorCode::T00_INTERNAL_ERROR, "InternalError");
+        assert_eq!(error.triggered_at.len(), "20171230120000.000Z".len());
+        assert!(error.triggered_at.ends_with('Z'));
+
+        let triggered_at = std::time::UNIX_EPOCH
+            + std::time::Duration::from_millis(1_514_635_200_123);
+        let error = ErrorDetails::new_at(
+            ErrorCode::T00_INTERNAL_ERROR,
+            "InternalError",
+            triggered_at,
+        );
+        assert_eq!(error.triggered_at, "20171230120000.123Z");
+    }
+}
@@ -1,5 +1,4 @@
 use std::fmt;
-use std::str;
 
 #[derive(Clone, Copy, Eq, PartialEq)]
 pub struct ErrorCode([u8; 3]);
@@ -28,6 +27,12 @@ impl ErrorCode {
         }
     }
 
+    /// Codes are IA5 (7-bit ASCII) strings.
+    #[inline]
+    pub(crate) fn is_ia5(self) -> bool {
+        self.0.iter().all(u8::is_ascii)
+    }
+
     // Error codes from: <https://github.com/interledger/rfcs/blob/master/0027-interledger-protocol-4/0027-interledger-protocol-4.md#error-codes>
 
     // Final errors:
@@ -68,15 +73,16 @@ impl From<ErrorCode> for [u8; 3] {
 impl fmt::Debug for ErrorCode {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         formatter.debug_tuple("ErrorCode")
-            .field(&str::from_utf8(&self.0[..]).map_err(|_| fmt::Error)?)
+            .field(&String::from_utf8_lossy(&self.0[..]))
             .finish()
     }
 }
 
 impl fmt::Display for ErrorCode {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
-        let as_str = str::from_utf8(&self.0[..]).map_err(|_| fmt::Error)?;
-        formatter.write_str(as_str)
+        // `ErrorCode::new` doesn't validate the bytes, and returning an error
+        // here would make `to_string` panic.
+        formatter.write_str(&String::from_utf8_lossy(&self.0[..]))
     }
 }
 
@@ -105,5 +111,17 @@ mod test_error_code {
             format!("{:?}", ErrorCode::F00_BAD_REQUEST),
             String::from("ErrorCode(\"F00\")")
         );
+        assert_eq!(ErrorCode::new(*b"\x81F0").to_string(), "\u{fffd}F0");
+        assert_eq!(
+            format!("{:?}", ErrorCode::new(*b"\x81F0")),
+            "ErrorCode(\"\u{fffd}F0\")",
+        );
+    }
+
+    #[test]
+    fn test_is_ia5() {
+        assert!(ErrorCode::F00_BAD_REQUEST.is_ia5());
+        assert!(ErrorCode::new(*b"???").is_ia5());
+        assert!(!ErrorCode::new(*b"\x81F0").is_ia5());
     }
 }
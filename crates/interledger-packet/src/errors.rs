@@ -41,3 +41,16 @@ quick_error! {
         }
     }
 }
+
+quick_error! {
+    /// A builder's field exceeds its maximum length (see `try_build`).
+    #[derive(Debug, PartialEq)]
+    pub enum BuildError {
+        DataTooLarge(len: usize) {
+            display("DataTooLarge {} bytes", len)
+        }
+        MessageTooLarge(len: usize) {
+            display("MessageTooLarge {} bytes", len)
+        }
+    }
+}
@@ -1,22 +1,20 @@
 use std::fmt;
-use std::time::{Duration, SystemTime};
+#[cfg(feature = "clock")]
+use std::time::Duration;
+use std::time::SystemTime;
 
 use byteorder::ReadBytesExt;
 use bytes::{BufMut, Bytes, BytesMut};
 
 use crate::{Addr, Fulfill, FulfillBuilder, ParseError, Prepare, PrepareBuilder};
+use crate::{PEER_PROTOCOL_CONDITION, PEER_PROTOCOL_FULFILLMENT};
 use crate::oer::{self, BufOerExt, MutBufOerExt};
 
 pub static DESTINATION: Addr<'static> = unsafe {
     Addr::new_unchecked(b"peer.config")
 };
 
-static PEER_PROTOCOL_FULFILLMENT: &[u8; 32] = &[0; 32];
-static PEER_PROTOCOL_CONDITION: &[u8; 32] = b"\
-    \x66\x68\x7a\xad\xf8\x62\xbd\x77\x6c\x8f\xc1\x8b\x8e\x9f\x8e\x20\
-    \x08\x97\x14\x85\x6e\xe2\x33\xb3\x90\x2a\x59\x1d\x0d\x5f\x29\x25\
-";
-
+#[cfg(feature = "clock")]
 const DEFAULT_EXPIRY_DURATION: Duration = Duration::from_secs(60);
 const ASSET_SCALE_LEN: usize = 1;
 
@@ -38,24 +36,32 @@ impl Request {
         }
     }
 
+    /// The Prepare expires in 60 seconds.
+    #[cfg(feature = "clock")]
     pub fn to_prepare(&self) -> Prepare {
+        self.to_prepare_expiring_at(SystemTime::now() + DEFAULT_EXPIRY_DURATION)
+    }
+
+    /// Like `to_prepare`, but doesn't read the system clock.
+    pub fn to_prepare_expiring_at(&self, expires_at: SystemTime) -> Prepare {
         PrepareBuilder {
             destination: DESTINATION,
             amount: 0,
             execution_condition: PEER_PROTOCOL_CONDITION,
-            expires_at: SystemTime::now() + DEFAULT_EXPIRY_DURATION,
+            expires_at,
             data: &[],
         }.build()
     }
 }
 
+#[cfg(feature = "clock")]
 impl From<Request> for Prepare {
     fn from(request: Request) -> Self {
         request.to_prepare()
     }
 }
 
-#[derive(PartialEq)]
+#[derive(Clone, PartialEq)]
 pub struct Response {
     buffer: Bytes,
     asset_scale: u8,
@@ -156,6 +162,8 @@ impl<'a> ResponseBuilder<'a> {
 
 #[cfg(test)]
 mod test_request {
+    use std::time::{Duration, UNIX_EPOCH};
+
     use bytes::BytesMut;
     use lazy_static::lazy_static;
 
@@ -204,6 +212,11 @@ mod test_request {
         assert_eq!(prepare.destination(), DESTINATION);
         assert_eq!(prepare.execution_condition(), PEER_PROTOCOL_CONDITION);
         assert_eq!(prepare.data(), b"");
+
+        let expires_at = UNIX_EPOCH + Duration::from_secs(1_514_635_200);
+        let prepare = request.to_prepare_expiring_at(expires_at);
+        assert_eq!(prepare.expires_at(), expires_at);
+        assert_eq!(prepare.destination(), DESTINATION);
     }
 }
 
@@ -0,0 +1,119 @@
+//! Human-readable renderings of packets, for logs and diagnostics. These are
+//! one-way: the binary fields are summarized (e.g. only the length of `data`).
+
+use chrono::{DateTime, SecondsFormat, Utc};
+use serde_json::{json, Value};
+
+use super::{Fulfill, Packet, Prepare, Reject};
+
+impl Packet {
+    pub fn to_json(&self) -> Value {
+        match self {
+            Packet::Prepare(prepare) => prepare.to_json(),
+            Packet::Fulfill(fulfill) => fulfill.to_json(),
+            Packet::Reject(reject) => reject.to_json(),
+        }
+    }
+}
+
+impl Prepare {
+    /// The amount is a string, since it may exceed the largest integer that a
+    /// JSON number can represent exactly.
+    pub fn to_json(&self) -> Value {
+        let expires_at = DateTime::<Utc>::from(self.expires_at())
+            .to_rfc3339_opts(SecondsFormat::Millis, true);
+        json!({
+            "type": "prepare",
+            "destination": self.destination().to_string(),
+            "amount": self.amount().to_string(),
+            "expires_at": expires_at,
+            "execution_condition": base64::encode(self.execution_condition()),
+            "data_length": self.data().len(),
+        })
+    }
+}
+
+impl Fulfill {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "type": "fulfill",
+            "fulfillment": base64::encode(self.fulfillment()),
+            "data_length": self.data().len(),
+        })
+    }
+}
+
+impl Reject {
+    /// Unlike `Debug`, an invalid UTF-8 message is rendered lossily rather
+    /// than failing.
+    pub fn to_json(&self) -> Value {
+        json!({
+            "type": "reject",
+            "code": self.code().to_string(),
+            "message": String::from_utf8_lossy(self.message()),
+            "triggered_by": self.triggered_by().map(|addr| addr.to_string()),
+            "data_length": self.data().len(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_json {
+    use crate::RejectBuilder;
+    use crate::fixtures::{FULFILL, PREPARE, REJECT};
+    use super::*;
+
+    #[test]
+    fn test_prepare() {
+        assert_eq!(
+            PREPARE.to_json(),
+            json!({
+                "type": "prepare",
+                "destination": "example.alice",
+                "amount": "107",
+                "expires_at": "2018-06-07T20:48:42.483Z",
+                "execution_condition": "EXtDTxpU6QRPT1SSOyz/nkptQgrigdUCXXuwQMS0wEo=",
+                "data_length": PREPARE.data().len(),
+            }),
+        );
+        assert_eq!(Packet::Prepare(PREPARE.clone()).to_json(), PREPARE.to_json());
+    }
+
+    #[test]
+    fn test_fulfill() {
+        let fulfill = FULFILL.to_json();
+        assert_eq!(fulfill["type"], "fulfill");
+        assert_eq!(
+            fulfill["fulfillment"],
+            base64::encode(FULFILL.fulfillment()),
+        );
+        assert_eq!(fulfill["data_length"], FULFILL.data().len());
+        assert_eq!(Packet::Fulfill(FULFILL.clone()).to_json(), fulfill);
+    }
+
+    #[test]
+    fn test_reject() {
+        assert_eq!(
+            REJECT.to_json(),
+            json!({
+                "type": "reject",
+                "code": "F99",
+                "message": "Some error",
+                "triggered_by": "example.connector",
+                "data_length": REJECT.data().len(),
+            }),
+        );
+        assert_eq!(Packet::Reject(REJECT.clone()).to_json(), REJECT.to_json());
+
+        let reject = RejectBuilder {
+            code: REJECT.code(),
+            message: b"invalid \xff",
+            triggered_by: None,
+            data: b"",
+        }.build();
+        let reject = reject.to_json();
+        assert_eq!(reject["message"], "invalid \u{fffd}");
+        assert_eq!(reject["triggered_by"], Value::Null);
+        assert_eq!(reject["data_length"], 0);
+    }
+}
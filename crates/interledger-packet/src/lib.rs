@@ -2,6 +2,21 @@
 //!
 //! Interledger packet serialization/deserialization.
 //!
+//! The default `clock` feature enables the functions that read the system
+//! clock. Disable it to build for `wasm32-unknown-unknown`, where
+//! `SystemTime::now` panics.
+//!
+//! The `stream` feature enables the `stream` module, which parses STREAM
+//! packets and implements their cryptography, for receivers that terminate
+//! STREAM payments.
+//!
+//! The `json` feature adds `to_json` to packets: a human-readable rendering
+//! (e.g. for logs) with the expiry as RFC 3339 and the binary fields in base64.
+//!
+//! The parsers are fed untrusted bytes, so they return a `ParseError` (rather
+//! than panic) on any input. The `cargo-fuzz` targets in `fuzz/` check this,
+//! e.g. `cargo +nightly fuzz run packet`.
+//!
 //! # References
 //!
 //!   * <https://github.com/interledger/rfcs/blob/master/0027-interledger-protocol-4/0027-interledger-protocol-4.md#packet-format>
@@ -9,18 +24,31 @@
 //!
 
 mod address;
+pub mod btp;
 mod error;
 mod errors;
 #[cfg(test)]
 mod fixtures;
 pub mod ildcp;
+#[cfg(any(feature = "json", test))]
+mod json;
 pub mod oer;
 mod packet;
+mod peer_protocol;
+mod reject_data;
+#[cfg(any(feature = "stream", test))]
+pub mod stream;
+pub mod timestamp;
+#[cfg(test)]
+mod test_vectors;
 
-pub use self::address::{Addr, Address, AddressError};
+pub use self::address::{Addr, Address, AddressError, is_valid_connection_tag};
 pub use self::error::{ErrorClass, ErrorCode};
-pub use self::errors::ParseError;
+pub use self::errors::{BuildError, ParseError};
 
 pub use self::packet::MaxPacketAmountDetails;
-pub use self::packet::{Fulfill, Packet, PacketType, Prepare, Reject};
+pub use self::packet::{Fulfill, Packet, PacketType, Prepare, PrepareHeader, Reject};
 pub use self::packet::{FulfillBuilder, PrepareBuilder, RejectBuilder};
+pub use self::peer_protocol::PeerProtocol;
+pub use self::peer_protocol::{PEER_PROTOCOL_CONDITION, PEER_PROTOCOL_FULFILLMENT};
+pub use self::reject_data::{InsufficientAmountDetails, RejectDiagnostics};
@@ -1,14 +1,23 @@
 #![forbid(unsafe_code)]
 
 use std::io::{Error, ErrorKind, Result};
+use std::time::SystemTime;
 use std::u64;
 
-use byteorder::{BigEndian, ByteOrder, ReadBytesExt};
-use bytes::{Buf, BufMut, BytesMut};
+use byteorder::{BigEndian, ByteOrder};
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use bytes::buf::BufExt;
+
+use crate::timestamp::{self, TIMESTAMP_LEN};
 
 const HIGH_BIT: u8 = 0x80;
 const LOWER_SEVEN_BITS: u8 = 0x7f;
 
+/// `YYYYMMDDHHmmss.fffZ`
+const GENERALIZED_TIME_LEN: usize = TIMESTAMP_LEN + 2;
+/// The length of the `YYYYMMDDHHmmss` prefix.
+const GENERALIZED_TIME_SECONDS_LEN: usize = 14;
+
 /// Returns the size (in bytes) of the buffer that encodes a VarOctetString of
 /// `length` bytes.
 pub fn predict_var_octet_string(length: usize) -> usize {
@@ -20,6 +29,11 @@ pub fn predict_var_octet_string(length: usize) -> usize {
     }
 }
 
+/// Returns the size (in bytes) of the buffer that encodes `value` as a VarUInt.
+pub fn predict_var_uint(value: u64) -> usize {
+    1 + predict_var_uint_size(value)
+}
+
 /// Returns the minimum number of bytes needed to encode the value.
 /// Returns an error of the value requires more than 8 bytes.
 fn predict_var_uint_size(value: u64) -> usize {
@@ -53,6 +67,12 @@ pub trait BufOerExt<'a> {
     fn skip_var_octet_string(&mut self) -> Result<()>;
     fn read_var_octet_string_length(&mut self) -> Result<usize>;
     fn read_var_uint(&mut self) -> Result<u64>;
+    fn read_generalized_time(&mut self) -> Result<SystemTime>;
+    fn read_sequence_of<T, E, F>(&mut self, read_item: F)
+        -> std::result::Result<Vec<T>, E>
+    where
+        E: From<Error>,
+        F: FnMut(&mut Self) -> std::result::Result<T, E>;
 }
 
 impl<'a> BufOerExt<'a> for &'a [u8] {
@@ -101,7 +121,76 @@ impl<'a> BufOerExt<'a> for &'a [u8] {
     #[doc(hidden)]
     #[inline]
     fn read_var_octet_string_length(&mut self) -> Result<usize> {
-        let length = self.read_u8()?;
+        self.get_var_octet_string_length()
+    }
+
+    /// Decodes variable-length octet unsigned integer to get `u64`.
+    #[inline]
+    fn read_var_uint(&mut self) -> Result<u64> {
+        self.get_var_uint()
+    }
+
+    /// Decodes an ASN.1 GeneralizedTime (a VarOctetString, in UTC):
+    /// `YYYYMMDDHHmmss.fffZ`, or `YYYYMMDDHHmmssZ`.
+    fn read_generalized_time(&mut self) -> Result<SystemTime> {
+        let time = self.read_var_octet_string()?;
+        let invalid = || {
+            Error::new(ErrorKind::InvalidData, "invalid GeneralizedTime")
+        };
+        let (seconds, millis): (&[u8], &[u8]) = match time.len() {
+            GENERALIZED_TIME_LEN
+                if time[GENERALIZED_TIME_SECONDS_LEN] == b'.' => (
+                    &time[..GENERALIZED_TIME_SECONDS_LEN],
+                    &time[GENERALIZED_TIME_SECONDS_LEN + 1..GENERALIZED_TIME_LEN - 1],
+                ),
+            len if len == GENERALIZED_TIME_SECONDS_LEN + 1 =>
+                (&time[..GENERALIZED_TIME_SECONDS_LEN], b"000"),
+            _ => return Err(invalid()),
+        };
+        if time.last() != Some(&b'Z') {
+            return Err(invalid());
+        }
+        let mut digits = [0; TIMESTAMP_LEN];
+        digits[..GENERALIZED_TIME_SECONDS_LEN].copy_from_slice(seconds);
+        digits[GENERALIZED_TIME_SECONDS_LEN..].copy_from_slice(millis);
+        timestamp::decode(&digits).map_err(|_error| invalid())
+    }
+
+    /// Decodes a SEQUENCE OF: a VarUInt quantity, then each item (with
+    /// `read_item`). Since every item is at least one byte, a quantity that
+    /// exceeds the remaining bytes is an error.
+    fn read_sequence_of<T, E, F>(&mut self, mut read_item: F)
+        -> std::result::Result<Vec<T>, E>
+    where
+        E: From<Error>,
+        F: FnMut(&mut Self) -> std::result::Result<T, E>,
+    {
+        let quantity = self.read_var_uint()?;
+        if quantity > self.len() as u64 {
+            return Err(Error::new(
+                ErrorKind::InvalidData,
+                "SEQUENCE OF quantity too large",
+            ).into());
+        }
+        let mut items = Vec::with_capacity(quantity as usize);
+        for _ in 0..quantity {
+            items.push(read_item(self)?);
+        }
+        Ok(items)
+    }
+}
+
+/// Decodes OER from any `Buf`, including one that isn't contiguous (e.g. the
+/// chunks of an HTTP body, or a chain of WebSocket frames), so that it doesn't
+/// need to be concatenated first. Unlike `BufOerExt`, octet strings are copied
+/// out of the buffer.
+///
+/// When an error is returned, part of the value may already have been
+/// consumed.
+pub trait BufOerReadExt: Buf {
+    /// Decodes the length prefix of a variable-length octet string.
+    fn get_var_octet_string_length(&mut self) -> Result<usize> {
+        let length = get_uint_be(self, 1)? as u8;
         if length & HIGH_BIT != 0 {
             let length_prefix_length = (length & LOWER_SEVEN_BITS) as usize;
             // TODO check for canonical length
@@ -111,27 +200,58 @@ impl<'a> BufOerExt<'a> for &'a [u8] {
                     "length prefix too large",
                 ))
             } else {
-                Ok(self.read_uint::<BigEndian>(length_prefix_length)? as usize)
+                Ok(get_uint_be(self, length_prefix_length)? as usize)
             }
         } else {
             Ok(length as usize)
         }
     }
 
+    /// Decodes variable-length octet string.
+    fn get_var_octet_string(&mut self) -> Result<Bytes> {
+        let length = self.get_var_octet_string_length()?;
+        if self.remaining() < length {
+            Err(Error::new(ErrorKind::UnexpectedEof, "buffer too small"))
+        } else {
+            Ok(BufExt::take(&mut *self, length).to_bytes())
+        }
+    }
+
+    fn advance_var_octet_string(&mut self) -> Result<()> {
+        let length = self.get_var_octet_string_length()?;
+        if self.remaining() < length {
+            Err(Error::new(ErrorKind::UnexpectedEof, "buffer too small"))
+        } else {
+            self.advance(length);
+            Ok(())
+        }
+    }
+
     /// Decodes variable-length octet unsigned integer to get `u64`.
-    #[inline]
-    fn read_var_uint(&mut self) -> Result<u64> {
-        let size = self.read_var_octet_string_length()?;
+    fn get_var_uint(&mut self) -> Result<u64> {
+        let size = self.get_var_octet_string_length()?;
         if size == 0 {
             Err(Error::new(ErrorKind::InvalidData, "zero-length VarUInt"))
         } else if size > 8 {
             Err(Error::new(ErrorKind::InvalidData, "VarUInt too large"))
         } else {
-            Ok(self.read_uint::<BigEndian>(size)?)
+            get_uint_be(self, size)
         }
     }
 }
 
+impl<B: Buf + ?Sized> BufOerReadExt for B {}
+
+/// Like `Buf::get_uint`, but returns an error instead of panicking when the
+/// buffer is too small.
+fn get_uint_be<B: Buf + ?Sized>(buf: &mut B, size: usize) -> Result<u64> {
+    if buf.remaining() < size {
+        Err(Error::new(ErrorKind::UnexpectedEof, "buffer too small"))
+    } else {
+        Ok(buf.get_uint(size))
+    }
+}
+
 pub trait MutBufOerExt: BufMut + Sized {
     /// Encodes bytes as variable-length octet encoded string and puts it into `Buf`.
     #[inline]
@@ -164,6 +284,29 @@ pub trait MutBufOerExt: BufMut + Sized {
         self.put_uint_be(uint, size);
     }
 
+    /// Encodes the (normalized) time as an ASN.1 GeneralizedTime:
+    /// `YYYYMMDDHHmmss.fffZ`.
+    fn put_generalized_time(&mut self, time: SystemTime) {
+        let digits = timestamp::encode(time);
+        self.put_var_octet_string_length(GENERALIZED_TIME_LEN);
+        self.put_slice(&digits[..GENERALIZED_TIME_SECONDS_LEN]);
+        self.put_u8(b'.');
+        self.put_slice(&digits[GENERALIZED_TIME_SECONDS_LEN..]);
+        self.put_u8(b'Z');
+    }
+
+    /// Encodes a SEQUENCE OF: the number of items (as a VarUInt), then each
+    /// item (with `put_item`).
+    fn put_sequence_of<T, F>(&mut self, items: &[T], mut put_item: F)
+    where
+        F: FnMut(&mut Self, &T),
+    {
+        self.put_var_uint(items.len() as u64);
+        for item in items {
+            put_item(self, item);
+        }
+    }
+
     #[doc(hidden)]
     #[inline]
     fn put_uint_be(&mut self, value: u64, size: usize) {
@@ -214,6 +357,15 @@ mod test_functions {
         assert_eq!(predict_var_uint_size(u64::MAX), 8);
     }
 
+    #[test]
+    fn test_predict_var_uint() {
+        for value in &[0, 0xff, 0xff + 1, 0x0102_0304, u64::MAX] {
+            let mut buffer = Vec::new();
+            buffer.put_var_uint(*value);
+            assert_eq!(predict_var_uint(*value), buffer.len());
+        }
+    }
+
     #[test]
     fn test_extract_var_octet_string() {
         assert_eq!(
@@ -313,7 +465,7 @@ mod test_buf_oer_ext {
             ErrorKind::UnexpectedEof,
         );
         assert_eq!(
-            (&LENGTH_TOO_HIGH_VARSTR.clone()[..])
+            (&LENGTH_TOO_HIGH_VARSTR[..])
                 .skip_var_octet_string()
                 .unwrap_err()
                 .kind(),
@@ -384,11 +536,144 @@ mod test_buf_oer_ext {
             );
         }
     }
+
+    #[test]
+    fn test_read_generalized_time() {
+        let tests: &[(&[u8], SystemTime)] = &[
+            (b"\x1320180607204842.483Z", *TIME_483),
+            (b"\x0f20180607204842Z", *TIME_000),
+        ];
+        for (buffer, time) in tests {
+            let mut reader = &buffer[..];
+            assert_eq!(reader.read_generalized_time().unwrap(), *time);
+            assert!(reader.is_empty());
+        }
+
+        let tests: &[(&[u8], ErrorKind)] = &[
+            (b"\x1320180607204842.483", ErrorKind::UnexpectedEof),
+            // Missing the "Z".
+            (b"\x1320180607204842.4830", ErrorKind::InvalidData),
+            (b"\x1320180607204842,483Z", ErrorKind::InvalidData),
+            (b"\x1220180607204842.48Z", ErrorKind::InvalidData),
+            (b"\x1320181307204842.483Z", ErrorKind::InvalidData),
+            (b"\x13201806072048a2.483Z", ErrorKind::InvalidData),
+        ];
+        for (buffer, error_kind) in tests {
+            assert_eq!(
+                (&buffer[..]).read_generalized_time().unwrap_err().kind(),
+                *error_kind,
+            );
+        }
+    }
+
+    #[test]
+    fn test_read_sequence_of() {
+        let read_u8 = |reader: &mut &[u8]| -> Result<u8> {
+            if reader.is_empty() {
+                Err(Error::new(ErrorKind::UnexpectedEof, "empty"))
+            } else {
+                Ok(reader.get_u8())
+            }
+        };
+
+        let mut reader = &b"\x01\x03\x0a\x0b\x0c\xff"[..];
+        assert_eq!(
+            reader.read_sequence_of(read_u8).unwrap(),
+            vec![0x0a, 0x0b, 0x0c],
+        );
+        assert_eq!(reader, &[0xff]);
+
+        let mut reader = &b"\x01\x00"[..];
+        assert!(reader.read_sequence_of(read_u8).unwrap().is_empty());
+
+        let tests: &[(&[u8], ErrorKind)] = &[
+            (b"", ErrorKind::UnexpectedEof),
+            // The quantity is larger than the remaining buffer.
+            (b"\x01\x03\x0a\x0b", ErrorKind::InvalidData),
+            (
+                b"\x08\xff\xff\xff\xff\xff\xff\xff\xff",
+                ErrorKind::InvalidData,
+            ),
+        ];
+        for (buffer, error_kind) in tests {
+            assert_eq!(
+                (&buffer[..]).read_sequence_of(read_u8).unwrap_err().kind(),
+                *error_kind,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_buf_oer_read_ext {
+    use super::*;
+    use super::fixtures::*;
+
+    #[test]
+    fn test_get_var_octet_string() {
+        // The string (and its length prefix) is split across two chunks.
+        let mut rest = vec![0x01];
+        rest.extend(&[0x00; 0x0101][..]);
+        let mut buffer = (&[0x82, 0x01][..]).chain(&rest[..]);
+        assert_eq!(buffer.get_var_octet_string().unwrap(), &[0x00; 0x0101][..]);
+        assert_eq!(buffer.remaining(), 0);
+
+        let mut buffer = (&TWO_BYTE_VARSTR[..1]).chain(&TWO_BYTE_VARSTR[1..]);
+        assert_eq!(buffer.get_var_octet_string().unwrap(), &[0x01, 0x02][..]);
+
+        assert_eq!(
+            (&[][..]).get_var_octet_string().unwrap_err().kind(),
+            ErrorKind::UnexpectedEof,
+        );
+        assert_eq!(
+            (&LENGTH_TOO_HIGH_VARSTR[..])
+                .get_var_octet_string()
+                .unwrap_err()
+                .kind(),
+            ErrorKind::UnexpectedEof,
+        );
+        assert_eq!(
+            (&[HIGH_BIT | 0x09][..])
+                .get_var_octet_string_length()
+                .unwrap_err()
+                .kind(),
+            ErrorKind::InvalidData,
+        );
+    }
+
+    #[test]
+    fn test_advance_var_octet_string() {
+        let mut buffer = (&TWO_BYTE_VARSTR[..2]).chain(&[0x02, 0x03][..]);
+        assert!(buffer.advance_var_octet_string().is_ok());
+        assert_eq!(buffer.bytes(), &[0x03]);
+        assert_eq!(
+            (&LENGTH_TOO_HIGH_VARSTR[..])
+                .advance_var_octet_string()
+                .unwrap_err()
+                .kind(),
+            ErrorKind::UnexpectedEof,
+        );
+    }
+
+    #[test]
+    fn test_get_var_uint() {
+        let mut buffer = (&[0x02, 0x01][..]).chain(&[0x02][..]);
+        assert_eq!(buffer.get_var_uint().unwrap(), 0x0102);
+        assert_eq!(
+            (&[0x00][..]).get_var_uint().unwrap_err().kind(),
+            ErrorKind::InvalidData,
+        );
+        assert_eq!(
+            (&[0x02, 0x01][..]).get_var_uint().unwrap_err().kind(),
+            ErrorKind::UnexpectedEof,
+        );
+    }
 }
 
 #[cfg(test)]
 mod buf_mut_oer_ext {
     use super::*;
+    use super::fixtures::*;
 
     #[test]
     fn test_put_var_octet_string() {
@@ -446,10 +731,167 @@ mod buf_mut_oer_ext {
             assert_eq!(writer, *buffer);
         }
     }
+
+    #[test]
+    fn test_put_generalized_time() {
+        let mut writer = Vec::new();
+        writer.put_generalized_time(*TIME_483);
+        assert_eq!(&writer[..], &b"\x1320180607204842.483Z"[..]);
+
+        writer.clear();
+        writer.put_generalized_time(*TIME_000);
+        assert_eq!(&writer[..], &b"\x1320180607204842.000Z"[..]);
+        assert_eq!((&writer[..]).read_generalized_time().unwrap(), *TIME_000);
+    }
+
+    #[test]
+    fn test_put_sequence_of() {
+        let mut writer = Vec::new();
+        writer.put_sequence_of(&[0x0102_u64, 0x03], |writer, item| {
+            writer.put_var_uint(*item);
+        });
+        assert_eq!(
+            &writer[..],
+            &b"\x01\x02\x02\x01\x02\x01\x03"[..],
+        );
+
+        let mut reader = &writer[..];
+        assert_eq!(
+            reader.read_sequence_of(|reader| reader.read_var_uint()).unwrap(),
+            vec![0x0102, 0x03],
+        );
+
+        writer.clear();
+        writer.put_sequence_of(&[] as &[u64], |_writer, _item| {});
+        assert_eq!(&writer[..], &b"\x01\x00"[..]);
+    }
+}
+
+#[cfg(test)]
+mod test_roundtrip {
+    use proptest::prelude::*;
+
+    use super::*;
+
+    /// Lengths around the boundaries of the length prefix encoding, as well as
+    /// arbitrary ones.
+    fn string_length() -> impl Strategy<Value = usize> {
+        prop_oneof![
+            prop::sample::select(vec![
+                0, 1, 127, 128, 129, 255, 256, 257, 65_535, 65_536,
+            ]),
+            0..70_000_usize,
+        ]
+    }
+
+    /// The contents don't affect the encoding, so a cheap pattern is used
+    /// instead of random bytes.
+    fn octet_string() -> impl Strategy<Value = Vec<u8>> {
+        (string_length(), any::<u8>()).prop_map(|(length, seed)| {
+            (0..length).map(|i| seed.wrapping_add(i as u8)).collect()
+        })
+    }
+
+    /// Every power of two (and its neighbours), and arbitrary values.
+    fn uint() -> impl Strategy<Value = u64> {
+        prop_oneof![
+            (0..64_u32, -1..=1_i64).prop_map(|(shift, offset)| {
+                (1_u64 << shift).wrapping_add(offset as u64)
+            }),
+            prop::sample::select(vec![0, u64::MAX - 1, u64::MAX]),
+            any::<u64>(),
+        ]
+    }
+
+    proptest! {
+        #[test]
+        fn test_var_octet_string(
+            string in octet_string(),
+            trailer in prop::collection::vec(any::<u8>(), 0..4),
+        ) {
+            let mut buffer = Vec::new();
+            buffer.put_var_octet_string(&string[..]);
+            prop_assert_eq!(buffer.len(), predict_var_octet_string(string.len()));
+            buffer.extend_from_slice(&trailer);
+
+            let mut reader = &buffer[..];
+            prop_assert_eq!(reader.peek_var_octet_string()?, &string[..]);
+            prop_assert_eq!(reader.read_var_octet_string()?, &string[..]);
+            prop_assert_eq!(reader, &trailer[..]);
+
+            let mut reader = &buffer[..];
+            reader.skip_var_octet_string()?;
+            prop_assert_eq!(reader, &trailer[..]);
+
+            prop_assert_eq!(
+                extract_var_octet_string(BytesMut::from(&buffer[..]))?,
+                BytesMut::from(&string[..]),
+            );
+        }
+
+        #[test]
+        fn test_truncated_var_octet_string(string in octet_string()) {
+            let mut buffer = Vec::new();
+            buffer.put_var_octet_string(&string[..]);
+            // Checking every prefix of the longer strings is too slow.
+            for end in (0..buffer.len()).rev().take(130) {
+                let truncated = &buffer[..end];
+                prop_assert!(truncated.peek_var_octet_string().is_err());
+                prop_assert!((&truncated[..]).read_var_octet_string().is_err());
+                prop_assert!((&truncated[..]).skip_var_octet_string().is_err());
+                prop_assert!(
+                    extract_var_octet_string(BytesMut::from(&buffer[..end])).is_err()
+                );
+            }
+        }
+
+        #[test]
+        fn test_var_octet_string_length(length in uint()) {
+            let length = length as usize;
+            let mut buffer = Vec::new();
+            buffer.put_var_octet_string_length(length);
+            let mut reader = &buffer[..];
+            prop_assert_eq!(reader.read_var_octet_string_length()?, length);
+            prop_assert!(reader.is_empty());
+        }
+
+        #[test]
+        fn test_var_uint(value in uint()) {
+            let mut buffer = Vec::new();
+            buffer.put_var_uint(value);
+            // The encoding is canonical: no leading zero bytes.
+            prop_assert_eq!(buffer.len(), 1 + predict_var_uint_size(value));
+            prop_assert!(buffer.len() == 2 || buffer[1] != 0);
+
+            let mut reader = &buffer[..];
+            prop_assert_eq!(reader.read_var_uint()?, value);
+            prop_assert!(reader.is_empty());
+            for end in 0..buffer.len() {
+                prop_assert!((&buffer[..end]).read_var_uint().is_err());
+            }
+        }
+
+        #[test]
+        fn test_uint_be(value in uint()) {
+            let size = predict_var_uint_size(value);
+            let mut buffer = Vec::new();
+            buffer.put_uint_be(value, size);
+            prop_assert_eq!(buffer.len(), size);
+            prop_assert_eq!(BigEndian::read_uint(&buffer, size), value);
+
+            buffer.clear();
+            buffer.put_u64_be(value);
+            prop_assert_eq!(BigEndian::read_u64(&buffer), value);
+        }
+    }
 }
 
 #[cfg(test)]
 mod fixtures {
+    use std::time::{Duration, SystemTime};
+
+    use lazy_static::lazy_static;
+
     pub static ZERO_LENGTH_VARSTR: &'static [u8] =
         &[0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
     pub static ONE_BYTE_VARSTR: &'static [u8] =
@@ -459,4 +901,13 @@ mod fixtures {
     /// This buffer is an incorrectly-encoded VarString.
     pub static LENGTH_TOO_HIGH_VARSTR: &'static [u8] =
         &[0x07, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06];
+
+    lazy_static! {
+        /// `2018-06-07T20:48:42.483Z`
+        pub static ref TIME_483: SystemTime = SystemTime::UNIX_EPOCH
+            + Duration::from_millis(1_528_404_522_483);
+        /// `2018-06-07T20:48:42.000Z`
+        pub static ref TIME_000: SystemTime = SystemTime::UNIX_EPOCH
+            + Duration::from_millis(1_528_404_522_000);
+    }
 }
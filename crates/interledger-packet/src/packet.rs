@@ -1,18 +1,20 @@
+use std::cmp;
 use std::fmt;
+use std::io;
 use std::io::prelude::*;
-use std::str;
+use std::ops::Range;
 use std::time::SystemTime;
 
 use byteorder::{BigEndian, ReadBytesExt};
 use bytes::{BufMut, BytesMut};
-use bytes::buf::ext::BufMutExt;
-use chrono::{DateTime, TimeZone, Utc};
+use chrono::{DateTime, Utc};
 
 use super::oer::{self, BufOerExt, MutBufOerExt};
-use super::{Addr, ErrorCode, ParseError};
+use super::timestamp::{self, TIMESTAMP_LEN};
+use super::{Addr, BuildError, ErrorCode, ParseError};
 
 const AMOUNT_LEN: usize = 8;
-const EXPIRY_LEN: usize = 17;
+const EXPIRY_LEN: usize = TIMESTAMP_LEN;
 const CONDITION_LEN: usize = 32;
 const FULFILLMENT_LEN: usize = 32;
 const ERROR_CODE_LEN: usize = 3;
@@ -20,8 +22,6 @@ const ERROR_CODE_LEN: usize = 3;
 const MAX_MESSAGE_LEN: usize = 8191;
 const MAX_DATA_LEN: usize = 32767;
 
-static INTERLEDGER_TIMESTAMP_FORMAT: &str = "%Y%m%d%H%M%S%3f";
-
 // TODO TryFrom([u8])
 #[derive(Clone, Copy, Debug, PartialEq)]
 #[repr(u8)]
@@ -65,6 +65,23 @@ impl Packet {
             ))),
         }
     }
+
+    /// Check the structure of a packet (like `try_from`), without copying or
+    /// taking ownership of the buffer.
+    pub fn validate(buffer: &[u8]) -> Result<PacketType, ParseError> {
+        let packet_type = match buffer.first() {
+            Some(&byte) => PacketType::try_from(byte)?,
+            None => return Err(ParseError::InvalidPacket({
+                "Unknown packet type: None".to_owned()
+            })),
+        };
+        match packet_type {
+            PacketType::Prepare => Prepare::validate(buffer)?,
+            PacketType::Fulfill => Fulfill::validate(buffer)?,
+            PacketType::Reject => Reject::validate(buffer)?,
+        }
+        Ok(packet_type)
+    }
 }
 
 impl From<Packet> for BytesMut {
@@ -116,29 +133,15 @@ pub struct PrepareBuilder<'a> {
 impl Prepare {
     // TODO change this to `TryFrom` when it is stabilized
     pub fn try_from(buffer: BytesMut) -> Result<Self, ParseError> {
-        let (content_offset, mut content) = deserialize_envelope(PacketType::Prepare, &buffer)?;
-        let content_len = content.len();
-        let amount = content.read_u64::<BigEndian>()?;
-
-        let mut expires_at = [0x00; 17];
-        content.read_exact(&mut expires_at)?;
-        let expires_at = str::from_utf8(&expires_at[..])?;
-        let expires_at: DateTime<Utc> =
-            Utc.datetime_from_str(&expires_at, INTERLEDGER_TIMESTAMP_FORMAT)?;
-        let expires_at = SystemTime::from(expires_at);
-
-        // Skip execution condition.
-        content.skip(CONDITION_LEN)?;
-        // Validate and skip the destination.
-        Addr::try_from(content.read_var_octet_string()?)?;
-
-        // Skip the data.
-        let data_offset = content_offset + content_len - content.len();
-        let data_len = content.read_var_octet_string()?.len();
-        if MAX_DATA_LEN < data_len {
-            return Err(ParseError::InvalidPacket("data too large".to_owned()));
-        }
-
+        let (content_offset, amount, expires_at, data_offset) = {
+            let header = parse_prepare(&buffer)?;
+            (
+                header.content_offset,
+                header.amount,
+                header.expires_at,
+                header.data_offset,
+            )
+        };
         Ok(Prepare {
             buffer,
             content_offset,
@@ -148,6 +151,12 @@ impl Prepare {
         })
     }
 
+    /// Check the structure of a Prepare (like `try_from`), without copying or
+    /// taking ownership of the buffer.
+    pub fn validate(buffer: &[u8]) -> Result<(), ParseError> {
+        parse_prepare(buffer).map(|_header| ())
+    }
+
     #[inline]
     pub fn amount(&self) -> u64 {
         self.amount
@@ -168,16 +177,14 @@ impl Prepare {
         self.expires_at
     }
 
+    /// The time is truncated to the millisecond (and clamped to the
+    /// representable range); see `timestamp::normalize`.
     #[inline]
     pub fn set_expires_at(&mut self, expires_at: SystemTime) {
-        self.expires_at = expires_at;
+        self.expires_at = timestamp::normalize(expires_at);
         let offset = self.content_offset + AMOUNT_LEN;
-        write!(
-            &mut self.buffer[offset..],
-            "{}",
-            DateTime::<Utc>::from(expires_at).format(INTERLEDGER_TIMESTAMP_FORMAT),
-        )
-        .unwrap();
+        self.buffer[offset..offset + EXPIRY_LEN]
+            .copy_from_slice(&timestamp::encode(expires_at));
     }
 
     /// The returned value always has a length of 32.
@@ -209,6 +216,19 @@ impl Prepare {
     pub fn into_data(mut self) -> BytesMut {
         oer::extract_var_octet_string(self.buffer.split_off(self.data_offset)).unwrap()
     }
+
+    /// The range of the envelope's content within the packet's bytes
+    /// (`as_ref()`). The length of the whole envelope is `content_range().end`.
+    #[inline]
+    pub fn content_range(&self) -> Range<usize> {
+        var_octet_string_range(&self.buffer, 1)
+    }
+
+    /// The range of `data()` within the packet's bytes (`as_ref()`).
+    #[inline]
+    pub fn data_range(&self) -> Range<usize> {
+        var_octet_string_range(&self.buffer, self.data_offset)
+    }
 }
 
 impl AsRef<[u8]> for Prepare {
@@ -240,6 +260,15 @@ impl fmt::Debug for Prepare {
 }
 
 impl<'a> PrepareBuilder<'a> {
+    /// Like `build`, but the `data` must not exceed its maximum length (32767
+    /// bytes), so that the Prepare can be parsed again.
+    pub fn try_build(&self) -> Result<Prepare, BuildError> {
+        validate_data(self.data)?;
+        Ok(self.build())
+    }
+
+    /// The lengths aren't validated, so an oversized `data` results in a
+    /// Prepare that peers can't parse. See `try_build`.
     pub fn build(&self) -> Prepare {
         const STATIC_LEN: usize = AMOUNT_LEN + EXPIRY_LEN + CONDITION_LEN;
         let destination_size = oer::predict_var_octet_string(self.destination.len());
@@ -252,16 +281,7 @@ impl<'a> PrepareBuilder<'a> {
         buffer.put_var_octet_string_length(content_len);
         let content_offset = buffer.len();
         buffer.put_u64_be(self.amount);
-
-        let mut writer = buffer.writer();
-        write!(
-            writer,
-            "{}",
-            DateTime::<Utc>::from(self.expires_at).format(INTERLEDGER_TIMESTAMP_FORMAT),
-        )
-        .unwrap();
-        let mut buffer = writer.into_inner();
-
+        buffer.put_slice(&timestamp::encode(self.expires_at));
         buffer.put_slice(&self.execution_condition[..]);
         buffer.put_var_octet_string(self.destination.as_ref());
         buffer.put_var_octet_string(self.data);
@@ -270,12 +290,97 @@ impl<'a> PrepareBuilder<'a> {
             buffer,
             content_offset,
             amount: self.amount,
-            expires_at: self.expires_at,
+            expires_at: timestamp::normalize(self.expires_at),
             data_offset: buf_size - data_size,
         }
     }
 }
 
+/// The fields of a Prepare that precede its `data`.
+///
+/// `peek` parses them from a prefix of the Prepare's bytes, so that e.g. the
+/// destination and expiry can be checked before the rest of a large Prepare
+/// has arrived. The `data` isn't validated (see `Prepare::validate`).
+#[derive(Clone, Debug, PartialEq)]
+pub struct PrepareHeader<'a> {
+    amount: u64,
+    expires_at: SystemTime,
+    execution_condition: &'a [u8],
+    destination: Addr<'a>,
+    content_offset: usize,
+    data_offset: usize,
+    packet_len: usize,
+}
+
+impl<'a> PrepareHeader<'a> {
+    /// Returns `Ok(None)` if `buffer` ends before the destination does, unless
+    /// the buffer already holds the whole (declared) packet.
+    pub fn peek(buffer: &'a [u8]) -> Result<Option<Self>, ParseError> {
+        match PrepareHeader::read(buffer) {
+            Ok(header) => Ok(Some(header)),
+            Err(ParseError::Io(error))
+                if error.kind() == io::ErrorKind::UnexpectedEof
+                    && is_incomplete(buffer) => Ok(None),
+            Err(error) => Err(error),
+        }
+    }
+
+    fn read(buffer: &'a [u8]) -> Result<Self, ParseError> {
+        let (content_offset, packet_len) =
+            read_envelope_len(PacketType::Prepare, buffer)?;
+        let content_end = cmp::min(buffer.len(), packet_len);
+        let mut content = &buffer[content_offset..content_end];
+        let amount = content.read_u64::<BigEndian>()?;
+
+        let mut expires_at = [0x00; EXPIRY_LEN];
+        content.read_exact(&mut expires_at)?;
+        let expires_at = timestamp::decode(&expires_at)?;
+
+        let condition_offset = content_end - content.len();
+        content.skip(CONDITION_LEN)?;
+        let destination = Addr::try_from(content.read_var_octet_string()?)?;
+
+        Ok(PrepareHeader {
+            amount,
+            expires_at,
+            execution_condition:
+                &buffer[condition_offset..condition_offset + CONDITION_LEN],
+            destination,
+            content_offset,
+            data_offset: content_end - content.len(),
+            packet_len,
+        })
+    }
+
+    #[inline]
+    pub fn amount(&self) -> u64 {
+        self.amount
+    }
+
+    #[inline]
+    pub fn expires_at(&self) -> SystemTime {
+        self.expires_at
+    }
+
+    /// The returned value always has a length of 32.
+    #[inline]
+    pub fn execution_condition(&self) -> &'a [u8] {
+        self.execution_condition
+    }
+
+    #[inline]
+    pub fn destination(&self) -> Addr<'a> {
+        self.destination
+    }
+
+    /// The length of the whole Prepare (including the `data`), as declared by
+    /// its envelope.
+    #[inline]
+    pub fn packet_len(&self) -> usize {
+        self.packet_len
+    }
+}
+
 #[derive(PartialEq, Clone)]
 pub struct Fulfill {
     buffer: BytesMut,
@@ -290,18 +395,29 @@ pub struct FulfillBuilder<'a> {
 
 impl Fulfill {
     pub fn try_from(buffer: BytesMut) -> Result<Self, ParseError> {
-        let (content_offset, mut content) = deserialize_envelope(PacketType::Fulfill, &buffer)?;
+        let content_offset = Fulfill::parse(&buffer)?;
+        Ok(Fulfill {
+            buffer,
+            content_offset,
+        })
+    }
+
+    /// Check the structure of a Fulfill (like `try_from`), without copying or
+    /// taking ownership of the buffer.
+    pub fn validate(buffer: &[u8]) -> Result<(), ParseError> {
+        Fulfill::parse(buffer).map(|_content_offset| ())
+    }
+
+    /// Returns the content offset.
+    fn parse(buffer: &[u8]) -> Result<usize, ParseError> {
+        let (content_offset, mut content) = deserialize_envelope(PacketType::Fulfill, buffer)?;
 
         content.skip(FULFILLMENT_LEN)?;
         let data_len = content.read_var_octet_string()?.len();
         if MAX_DATA_LEN < data_len {
             return Err(ParseError::InvalidPacket("data too large".to_owned()));
         }
-
-        Ok(Fulfill {
-            buffer,
-            content_offset,
-        })
+        Ok(content_offset)
     }
 
     /// The returned value always has a length of 32.
@@ -325,6 +441,19 @@ impl Fulfill {
         let data_offset = self.content_offset + FULFILLMENT_LEN;
         oer::extract_var_octet_string(self.buffer.split_off(data_offset)).unwrap()
     }
+
+    /// The range of the envelope's content within the packet's bytes
+    /// (`as_ref()`). The length of the whole envelope is `content_range().end`.
+    #[inline]
+    pub fn content_range(&self) -> Range<usize> {
+        var_octet_string_range(&self.buffer, 1)
+    }
+
+    /// The range of `data()` within the packet's bytes (`as_ref()`).
+    #[inline]
+    pub fn data_range(&self) -> Range<usize> {
+        var_octet_string_range(&self.buffer, self.content_offset + FULFILLMENT_LEN)
+    }
 }
 
 impl AsRef<[u8]> for Fulfill {
@@ -350,6 +479,13 @@ impl fmt::Debug for Fulfill {
 }
 
 impl<'a> FulfillBuilder<'a> {
+    /// Like `build`, but the `data` must not exceed its maximum length.
+    pub fn try_build(&self) -> Result<Fulfill, BuildError> {
+        validate_data(self.data)?;
+        Ok(self.build())
+    }
+
+    /// The lengths aren't validated. See `try_build`.
     pub fn build(&self) -> Fulfill {
         let data_size = oer::predict_var_octet_string(self.data.len());
         let content_len = FULFILLMENT_LEN + data_size;
@@ -387,15 +523,42 @@ pub struct RejectBuilder<'a> {
 
 impl Reject {
     pub fn try_from(buffer: BytesMut) -> Result<Self, ParseError> {
-        let (content_offset, mut content) = deserialize_envelope(PacketType::Reject, &buffer)?;
+        let (code, triggered_by_offset, message_offset, data_offset) =
+            Reject::parse(&buffer)?;
+        Ok(Reject {
+            buffer,
+            code,
+            triggered_by_offset,
+            message_offset,
+            data_offset,
+        })
+    }
+
+    /// Check the structure of a Reject (like `try_from`), without copying or
+    /// taking ownership of the buffer.
+    pub fn validate(buffer: &[u8]) -> Result<(), ParseError> {
+        Reject::parse(buffer).map(|_offsets| ())
+    }
+
+    /// Returns the code, and the offsets of `triggered_by`, the message, and
+    /// the data.
+    fn parse(buffer: &[u8]) -> Result<(ErrorCode, usize, usize, usize), ParseError> {
+        let (content_offset, mut content) = deserialize_envelope(PacketType::Reject, buffer)?;
         let content_len = content.len();
 
         let mut code = [0; 3];
         content.read_exact(&mut code)?;
         let code = ErrorCode::new(code);
+        if !code.is_ia5() {
+            return Err(ParseError::InvalidPacket("invalid error code".to_owned()));
+        }
 
         let triggered_by_offset = content_offset + content_len - content.len();
-        Addr::try_from(content.read_var_octet_string()?)?;
+        // `triggered_by` is optional (empty), but must be valid when present.
+        let triggered_by = content.read_var_octet_string()?;
+        if !triggered_by.is_empty() {
+            Addr::try_from(triggered_by)?;
+        }
 
         let message_offset = content_offset + content_len - content.len();
         let message_len = content.read_var_octet_string()?.len();
@@ -409,13 +572,7 @@ impl Reject {
             return Err(ParseError::InvalidPacket("data too large".to_owned()));
         }
 
-        Ok(Reject {
-            buffer,
-            code,
-            triggered_by_offset,
-            message_offset,
-            data_offset,
-        })
+        Ok((code, triggered_by_offset, message_offset, data_offset))
     }
 
     #[inline]
@@ -453,6 +610,19 @@ impl Reject {
     pub fn into_data(mut self) -> BytesMut {
         oer::extract_var_octet_string(self.buffer.split_off(self.data_offset)).unwrap()
     }
+
+    /// The range of the envelope's content within the packet's bytes
+    /// (`as_ref()`). The length of the whole envelope is `content_range().end`.
+    #[inline]
+    pub fn content_range(&self) -> Range<usize> {
+        var_octet_string_range(&self.buffer, 1)
+    }
+
+    /// The range of `data()` within the packet's bytes (`as_ref()`).
+    #[inline]
+    pub fn data_range(&self) -> Range<usize> {
+        var_octet_string_range(&self.buffer, self.data_offset)
+    }
 }
 
 impl AsRef<[u8]> for Reject {
@@ -472,17 +642,42 @@ impl fmt::Debug for Reject {
     fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
         formatter.debug_struct("Reject")
             .field("code", &self.code())
-            .field(
-                "message",
-                &str::from_utf8(self.message()).map_err(|_| fmt::Error)?,
-            )
+            // The message isn't validated (and is commonly logged), so invalid
+            // UTF-8 mustn't be an error here.
+            .field("message", &String::from_utf8_lossy(self.message()))
             .field("triggered_by", &self.triggered_by())
             .field("data_length", &self.data().len())
             .finish()
     }
 }
 
+/// A Reject is also an error, so that it can be boxed (e.g. by `tower`
+/// middleware) and downcast back.
+impl fmt::Display for Reject {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            formatter,
+            "{} {}",
+            self.code(),
+            String::from_utf8_lossy(self.message()),
+        )
+    }
+}
+
+impl std::error::Error for Reject {}
+
 impl<'a> RejectBuilder<'a> {
+    /// Like `build`, but the `message` (8191 bytes) and `data` must not exceed
+    /// their maximum lengths.
+    pub fn try_build(&self) -> Result<Reject, BuildError> {
+        if MAX_MESSAGE_LEN < self.message.len() {
+            return Err(BuildError::MessageTooLarge(self.message.len()));
+        }
+        validate_data(self.data)?;
+        Ok(self.build())
+    }
+
+    /// The lengths aren't validated. See `try_build`.
     pub fn build(&self) -> Reject {
         let triggered_by_size = oer::predict_var_octet_string(self.triggered_by_len());
         let message_size = oer::predict_var_octet_string(self.message.len());
@@ -520,6 +715,61 @@ impl<'a> RejectBuilder<'a> {
     }
 }
 
+fn validate_data(data: &[u8]) -> Result<(), BuildError> {
+    if MAX_DATA_LEN < data.len() {
+        Err(BuildError::DataTooLarge(data.len()))
+    } else {
+        Ok(())
+    }
+}
+
+/// Parse and validate a whole Prepare.
+fn parse_prepare(buffer: &[u8]) -> Result<PrepareHeader<'_>, ParseError> {
+    let header = PrepareHeader::read(buffer)?;
+    if buffer.len() < header.packet_len {
+        return Err(ParseError::Io(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "buffer too small",
+        )));
+    }
+    let mut data = &buffer[header.data_offset..header.packet_len];
+    let data_len = data.read_var_octet_string()?.len();
+    if MAX_DATA_LEN < data_len {
+        return Err(ParseError::InvalidPacket("data too large".to_owned()));
+    }
+    Ok(header)
+}
+
+/// Returns the content offset, and the length of the whole packet (which may
+/// be longer than `buffer`).
+fn read_envelope_len(packet_type: PacketType, mut reader: &[u8])
+    -> Result<(usize, usize), ParseError>
+{
+    let buffer_len = reader.len();
+    let got_type = reader.read_u8()?;
+    if got_type != packet_type as u8 {
+        return Err(ParseError::InvalidPacket(format!(
+            "Unexpected packet type: {:?}",
+            got_type,
+        )));
+    }
+    let content_len = reader.read_var_octet_string_length()?;
+    let content_offset = buffer_len - reader.len();
+    Ok((content_offset, content_offset + content_len))
+}
+
+/// Whether `buffer` is shorter than the packet that its envelope declares (or
+/// too short to declare it).
+fn is_incomplete(mut reader: &[u8]) -> bool {
+    if reader.read_u8().is_err() {
+        return true;
+    }
+    match reader.read_var_octet_string_length() {
+        Ok(content_len) => reader.len() < content_len,
+        Err(_) => true,
+    }
+}
+
 fn deserialize_envelope(
     packet_type: PacketType,
     mut reader: &[u8],
@@ -543,6 +793,15 @@ fn deserialize_envelope(
     }
 }
 
+/// The range of the contents of the var-octet-string at `offset`. The string
+/// must already have been validated.
+fn var_octet_string_range(buffer: &[u8], offset: usize) -> Range<usize> {
+    let mut reader = &buffer[offset..];
+    let length = reader.read_var_octet_string_length().unwrap();
+    let start = buffer.len() - reader.len();
+    start..start + length
+}
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct MaxPacketAmountDetails {
     amount_received: u64,
@@ -623,6 +882,18 @@ mod test_packet {
         assert!(Packet::try_from(BytesMut::from(&[0x99][..])).is_err());
     }
 
+    #[test]
+    fn test_validate() {
+        assert_eq!(Packet::validate(PREPARE_BYTES).unwrap(), PacketType::Prepare);
+        assert_eq!(Packet::validate(FULFILL_BYTES).unwrap(), PacketType::Fulfill);
+        assert_eq!(Packet::validate(REJECT_BYTES).unwrap(), PacketType::Reject);
+
+        assert!(Packet::validate(&[]).is_err());
+        assert!(Packet::validate(&[0x99]).is_err());
+        assert!(Packet::validate(&FULFILL_BYTES[..FULFILL_BYTES.len() - 1]).is_err());
+        assert!(Packet::validate(&REJECT_BYTES[..REJECT_BYTES.len() - 1]).is_err());
+    }
+
     #[test]
     fn test_into_bytes_mut() {
         assert_eq!(
@@ -643,7 +914,7 @@ mod test_packet {
 #[cfg(test)]
 mod test_prepare {
     use super::*;
-    use crate::fixtures::{self, PREPARE, PREPARE_BUILDER, PREPARE_BYTES};
+    use crate::fixtures::{self, FULFILL_BYTES, PREPARE, PREPARE_BUILDER, PREPARE_BYTES};
 
     #[test]
     fn test_try_from() {
@@ -699,6 +970,69 @@ mod test_prepare {
         assert_eq!(BytesMut::from(PREPARE.clone()), PREPARE_BYTES);
     }
 
+    #[test]
+    fn test_validate() {
+        assert!(Prepare::validate(PREPARE_BYTES).is_ok());
+        assert!(Prepare::validate(&PREPARE_BYTES[..PREPARE_BYTES.len() - 1]).is_err());
+        assert!(Prepare::validate(FULFILL_BYTES).is_err());
+
+        let with_huge_data = {
+            let mut with_huge_data = PREPARE_BUILDER.clone();
+            with_huge_data.data = &fixtures::HUGE_DATA;
+            with_huge_data.build()
+        };
+        assert!(Prepare::validate(with_huge_data.as_ref()).is_err());
+    }
+
+    #[test]
+    fn test_peek_header() {
+        // The destination ends after the envelope (4 bytes), the amount,
+        // expiry, and condition, and the destination itself (14 bytes).
+        let header_len = 4 + AMOUNT_LEN + EXPIRY_LEN + CONDITION_LEN + 14;
+        for len in 0..header_len {
+            assert_eq!(PrepareHeader::peek(&PREPARE_BYTES[..len]).unwrap(), None);
+        }
+        for &len in &[header_len, header_len + 1, PREPARE_BYTES.len()] {
+            let header = PrepareHeader::peek(&PREPARE_BYTES[..len])
+                .unwrap()
+                .unwrap();
+            assert_eq!(header.amount(), PREPARE.amount());
+            assert_eq!(header.expires_at(), PREPARE.expires_at());
+            assert_eq!(header.execution_condition(), PREPARE.execution_condition());
+            assert_eq!(header.destination(), PREPARE.destination());
+            assert_eq!(header.packet_len(), PREPARE_BYTES.len());
+        }
+
+        // The wrong packet type:
+        assert!(PrepareHeader::peek(&FULFILL_BYTES[..1]).is_err());
+        // An invalid destination:
+        let mut with_bad_address = BytesMut::from(PREPARE_BYTES);
+        with_bad_address[header_len - 1] = b'!';
+        assert!(PrepareHeader::peek(&with_bad_address[..header_len]).is_err());
+        // The envelope is too short for the header:
+        assert!(PrepareHeader::peek(&[12, 2, 0, 0]).is_err());
+    }
+
+    #[test]
+    fn test_try_build() {
+        assert_eq!(PREPARE_BUILDER.try_build().unwrap(), *PREPARE);
+
+        let max_data = vec![0; MAX_DATA_LEN];
+        let mut builder = PREPARE_BUILDER.clone();
+        builder.data = &max_data;
+        let prepare = builder.try_build().unwrap();
+        assert_eq!(
+            Prepare::try_from(BytesMut::from(prepare)).unwrap().data(),
+            &max_data[..],
+        );
+
+        builder.data = &fixtures::HUGE_DATA;
+        assert_eq!(
+            builder.try_build(),
+            Err(BuildError::DataTooLarge(fixtures::HUGE_DATA.len())),
+        );
+    }
+
     #[test]
     fn test_amount() {
         assert_eq!(PREPARE.amount(), PREPARE_BUILDER.amount);
@@ -752,6 +1086,16 @@ mod test_prepare {
             BytesMut::from(PREPARE.data()),
         );
     }
+
+    #[test]
+    fn test_ranges() {
+        let mut buffer = BytesMut::from(PREPARE_BYTES);
+        buffer.extend_from_slice(&[0x11, 0x12, 0x13]);
+        let packet = Prepare::try_from(buffer).unwrap();
+        assert_eq!(packet.content_range(), 4..PREPARE_BYTES.len());
+        assert_eq!(&packet.as_ref()[packet.data_range()], fixtures::DATA);
+        assert_eq!(PREPARE.data_range(), packet.data_range());
+    }
 }
 
 #[cfg(test)]
@@ -794,6 +1138,18 @@ mod test_fulfill {
         assert_eq!(BytesMut::from(FULFILL.clone()), FULFILL_BYTES);
     }
 
+    #[test]
+    fn test_try_build() {
+        assert_eq!(fixtures::FULFILL_BUILDER.try_build().unwrap(), *FULFILL);
+
+        let mut builder = fixtures::FULFILL_BUILDER.clone();
+        builder.data = &fixtures::HUGE_DATA;
+        assert_eq!(
+            builder.try_build(),
+            Err(BuildError::DataTooLarge(fixtures::HUGE_DATA.len())),
+        );
+    }
+
     #[test]
     fn test_fulfillment() {
         assert_eq!(FULFILL.fulfillment(), fixtures::FULFILLMENT);
@@ -811,6 +1167,16 @@ mod test_fulfill {
             BytesMut::from(FULFILL.data()),
         );
     }
+
+    #[test]
+    fn test_ranges() {
+        let mut buffer = BytesMut::from(FULFILL_BYTES);
+        buffer.extend_from_slice(&[0x11, 0x12, 0x13]);
+        let packet = Fulfill::try_from(buffer).unwrap();
+        assert_eq!(packet.content_range(), 4..FULFILL_BYTES.len());
+        assert_eq!(&packet.as_ref()[packet.data_range()], fixtures::DATA);
+        assert_eq!(FULFILL.data_range(), packet.data_range());
+    }
 }
 
 #[cfg(test)]
@@ -843,6 +1209,13 @@ mod test_reject {
             reject.message = &fixtures::HUGE_MESSAGE;
             BytesMut::from(reject.build())
         }).is_err());
+
+        // A packet with a code that isn't IA5.
+        assert!(Reject::try_from({
+            let mut reject = REJECT_BUILDER.clone();
+            reject.code = ErrorCode::new(*b"\x81F9");
+            BytesMut::from(reject.build())
+        }).is_err());
     }
 
     #[test]
@@ -850,6 +1223,25 @@ mod test_reject {
         assert_eq!(BytesMut::from(REJECT.clone()), REJECT_BYTES);
     }
 
+    #[test]
+    fn test_try_build() {
+        assert_eq!(REJECT_BUILDER.try_build().unwrap(), *REJECT);
+
+        let mut builder = REJECT_BUILDER.clone();
+        builder.message = &fixtures::HUGE_MESSAGE;
+        assert_eq!(
+            builder.try_build(),
+            Err(BuildError::MessageTooLarge(fixtures::HUGE_MESSAGE.len())),
+        );
+
+        let mut builder = REJECT_BUILDER.clone();
+        builder.data = &fixtures::HUGE_DATA;
+        assert_eq!(
+            builder.try_build(),
+            Err(BuildError::DataTooLarge(fixtures::HUGE_DATA.len())),
+        );
+    }
+
     #[test]
     fn test_code() {
         assert_eq!(REJECT.code(), REJECT_BUILDER.code);
@@ -873,6 +1265,19 @@ mod test_reject {
         assert_eq!(reject.triggered_by(), None);
     }
 
+    #[test]
+    fn test_display() {
+        assert_eq!(REJECT.to_string(), "F99 Some error");
+    }
+
+    #[test]
+    fn test_debug_invalid_message() {
+        let mut builder = REJECT_BUILDER.clone();
+        builder.message = b"Some \x81rror";
+        let reject = Reject::try_from(BytesMut::from(builder.build())).unwrap();
+        assert!(format!("{:?}", reject).contains("Some \u{fffd}rror"));
+    }
+
     #[test]
     fn test_data() {
         assert_eq!(REJECT.data(), fixtures::DATA);
@@ -885,6 +1290,16 @@ mod test_reject {
             BytesMut::from(REJECT.data()),
         );
     }
+
+    #[test]
+    fn test_ranges() {
+        let mut buffer = BytesMut::from(REJECT_BYTES);
+        buffer.extend_from_slice(&[0x11, 0x12, 0x13]);
+        let packet = Reject::try_from(buffer).unwrap();
+        assert_eq!(packet.content_range(), 4..REJECT_BYTES.len());
+        assert_eq!(&packet.as_ref()[packet.data_range()], fixtures::DATA);
+        assert_eq!(REJECT.data_range(), packet.data_range());
+    }
 }
 
 #[cfg(test)]
@@ -0,0 +1,128 @@
+//! Peer protocols (e.g. ILDCP) are carried by Prepares addressed to fixed
+//! `peer.*` destinations, with a fixed condition.
+
+use crate::{Addr, Packet, Prepare, ildcp};
+
+/// The fulfillment of every peer protocol Prepare (32 zero bytes).
+pub static PEER_PROTOCOL_FULFILLMENT: &[u8; 32] = &[0; 32];
+/// The SHA-256 of `PEER_PROTOCOL_FULFILLMENT`.
+pub static PEER_PROTOCOL_CONDITION: &[u8; 32] = b"\
+    \x66\x68\x7a\xad\xf8\x62\xbd\x77\x6c\x8f\xc1\x8b\x8e\x9f\x8e\x20\
+    \x08\x97\x14\x85\x6e\xe2\x33\xb3\x90\x2a\x59\x1d\x0d\x5f\x29\x25\
+";
+
+static ROUTE_CONTROL_DESTINATION: Addr<'static> = unsafe {
+    Addr::new_unchecked(b"peer.route.control")
+};
+static ROUTE_UPDATE_DESTINATION: Addr<'static> = unsafe {
+    Addr::new_unchecked(b"peer.route.update")
+};
+static SETTLEMENT_DESTINATION: Addr<'static> = unsafe {
+    Addr::new_unchecked(b"peer.settle")
+};
+
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub enum PeerProtocol {
+    /// `peer.config` (see the `ildcp` module).
+    Ildcp,
+    /// `peer.route.control`: CCP route control.
+    RouteControl,
+    /// `peer.route.update`: CCP route updates.
+    RouteUpdate,
+    /// `peer.settle`: settlement engine messages.
+    Settlement,
+}
+
+static PEER_PROTOCOLS: &[PeerProtocol] = &[
+    PeerProtocol::Ildcp,
+    PeerProtocol::RouteControl,
+    PeerProtocol::RouteUpdate,
+    PeerProtocol::Settlement,
+];
+
+impl PeerProtocol {
+    pub fn destination(self) -> Addr<'static> {
+        match self {
+            PeerProtocol::Ildcp => ildcp::DESTINATION,
+            PeerProtocol::RouteControl => ROUTE_CONTROL_DESTINATION,
+            PeerProtocol::RouteUpdate => ROUTE_UPDATE_DESTINATION,
+            PeerProtocol::Settlement => SETTLEMENT_DESTINATION,
+        }
+    }
+}
+
+impl Prepare {
+    /// The peer protocol that this Prepare belongs to, if any: its destination
+    /// must be the protocol's, and its condition `PEER_PROTOCOL_CONDITION`.
+    pub fn peer_protocol(&self) -> Option<PeerProtocol> {
+        if self.execution_condition() != PEER_PROTOCOL_CONDITION {
+            return None;
+        }
+        let destination = self.destination();
+        PEER_PROTOCOLS
+            .iter()
+            .find(|protocol| protocol.destination() == destination)
+            .cloned()
+    }
+}
+
+impl Packet {
+    /// Only a Prepare can belong to a peer protocol, since the responses
+    /// don't have a destination.
+    pub fn peer_protocol(&self) -> Option<PeerProtocol> {
+        match self {
+            Packet::Prepare(prepare) => prepare.peer_protocol(),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_peer_protocol {
+    use std::time::SystemTime;
+
+    use crate::PrepareBuilder;
+    use crate::fixtures::{FULFILL, PREPARE};
+    use super::*;
+
+    fn make_prepare(destination: &'static [u8], condition: &[u8; 32]) -> Prepare {
+        PrepareBuilder {
+            amount: 0,
+            expires_at: SystemTime::UNIX_EPOCH,
+            execution_condition: condition,
+            destination: Addr::new(destination),
+            data: b"",
+        }.build()
+    }
+
+    #[test]
+    fn test_peer_protocol() {
+        let tests: &[(&[u8], _)] = &[
+            (b"peer.config", Some(PeerProtocol::Ildcp)),
+            (b"peer.route.control", Some(PeerProtocol::RouteControl)),
+            (b"peer.route.update", Some(PeerProtocol::RouteUpdate)),
+            (b"peer.settle", Some(PeerProtocol::Settlement)),
+            (b"peer.config.child", None),
+            (b"peer.route", None),
+            (b"peer.other", None),
+            (b"example.config", None),
+        ];
+        for (destination, expect) in tests {
+            let prepare = make_prepare(destination, PEER_PROTOCOL_CONDITION);
+            assert_eq!(prepare.peer_protocol(), *expect);
+            assert_eq!(Packet::Prepare(prepare).peer_protocol(), *expect);
+        }
+
+        // The wrong condition:
+        assert_eq!(make_prepare(b"peer.config", &[0; 32]).peer_protocol(), None);
+        assert_eq!(PREPARE.peer_protocol(), None);
+        assert_eq!(Packet::Fulfill(FULFILL.clone()).peer_protocol(), None);
+    }
+
+    #[test]
+    fn test_ildcp_request() {
+        let prepare = ildcp::Request::new()
+            .to_prepare_expiring_at(SystemTime::UNIX_EPOCH);
+        assert_eq!(prepare.peer_protocol(), Some(PeerProtocol::Ildcp));
+    }
+}
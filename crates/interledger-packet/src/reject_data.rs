@@ -0,0 +1,208 @@
+//! Machine-readable Reject `data`, besides the `F08` amounts (see
+//! `MaxPacketAmountDetails`).
+
+use std::str;
+
+use byteorder::{BigEndian, ReadBytesExt};
+
+use crate::ParseError;
+use crate::oer::{BufOerExt, MutBufOerExt};
+
+/// The data of an `R01` (Insufficient Source Amount) Reject: the amount that
+/// was received, and the smallest amount that would have been forwarded (e.g.
+/// one that isn't rounded down to zero by an exchange).
+///
+/// The layout is the same as `MaxPacketAmountDetails`: two big-endian `u64`s.
+#[derive(Clone, Debug, PartialEq)]
+pub struct InsufficientAmountDetails {
+    amount_received: u64,
+    min_amount: u64,
+}
+
+impl InsufficientAmountDetails {
+    #[inline]
+    pub fn new(amount_received: u64, min_amount: u64) -> Self {
+        InsufficientAmountDetails {
+            amount_received,
+            min_amount,
+        }
+    }
+
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, std::io::Error> {
+        let amount_received = bytes.read_u64::<BigEndian>()?;
+        let min_amount = bytes.read_u64::<BigEndian>()?;
+        Ok(InsufficientAmountDetails::new(amount_received, min_amount))
+    }
+
+    pub fn to_bytes(&self) -> [u8; 16] {
+        use byteorder::ByteOrder;
+        let mut bytes = [0x00_u8; 16];
+        BigEndian::write_u64(&mut bytes[0..8], self.amount_received);
+        BigEndian::write_u64(&mut bytes[8..16], self.min_amount);
+        bytes
+    }
+
+    #[inline]
+    pub fn amount_received(&self) -> u64 {
+        self.amount_received
+    }
+
+    #[inline]
+    pub fn min_amount(&self) -> u64 {
+        self.min_amount
+    }
+}
+
+/// Key-value pairs that explain a Reject, e.g. `reason=no_route` and
+/// `destination=g.alice`. The entries keep their order, and a key may repeat.
+///
+/// Encoded as an OER `SEQUENCE OF` (key, value) pairs, each a UTF-8
+/// VarOctetString.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RejectDiagnostics {
+    entries: Vec<(String, String)>,
+}
+
+impl RejectDiagnostics {
+    #[inline]
+    pub fn new() -> Self {
+        RejectDiagnostics::default()
+    }
+
+    pub fn push(&mut self, key: &str, value: &str) {
+        self.entries.push((key.to_owned(), value.to_owned()));
+    }
+
+    /// The value of the first entry with the `key`.
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.iter()
+            .find(|(entry_key, _value)| *entry_key == key)
+            .map(|(_key, value)| value)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.entries
+            .iter()
+            .map(|(key, value)| (key.as_str(), value.as_str()))
+    }
+
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// Unlike packets, trailing bytes are an error, so that other data (e.g.
+    /// a reason ID) isn't mistaken for diagnostics.
+    pub fn from_bytes(mut bytes: &[u8]) -> Result<Self, ParseError> {
+        let entries = bytes.read_sequence_of(|reader| {
+            let key = str::from_utf8(reader.read_var_octet_string()?)?;
+            let value = str::from_utf8(reader.read_var_octet_string()?)?;
+            Ok::<_, ParseError>((key.to_owned(), value.to_owned()))
+        })?;
+        if !bytes.is_empty() {
+            return Err(ParseError::InvalidPacket({
+                "trailing bytes after diagnostics".to_owned()
+            }));
+        }
+        Ok(RejectDiagnostics { entries })
+    }
+
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut buffer = Vec::new();
+        buffer.put_sequence_of(&self.entries, |buffer, (key, value)| {
+            buffer.put_var_octet_string(key.as_bytes());
+            buffer.put_var_octet_string(value.as_bytes());
+        });
+        buffer
+    }
+}
+
+#[cfg(test)]
+mod test_insufficient_amount_details {
+    use super::*;
+
+    static DETAILS: InsufficientAmountDetails = InsufficientAmountDetails {
+        amount_received: 0x0102_0304_0506_0708,
+        min_amount: 0x0807_0605_0403_0201,
+    };
+
+    static BYTES: [u8; 16] = [
+        0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08,
+        0x08, 0x07, 0x06, 0x05, 0x04, 0x03, 0x02, 0x01,
+    ];
+
+    #[test]
+    fn test_from_bytes() {
+        assert_eq!(InsufficientAmountDetails::from_bytes(&BYTES).unwrap(), DETAILS);
+        assert!(InsufficientAmountDetails::from_bytes(&BYTES[..15]).is_err());
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        assert_eq!(DETAILS.to_bytes(), BYTES);
+    }
+
+    #[test]
+    fn test_accessors() {
+        assert_eq!(DETAILS.amount_received(), 0x0102_0304_0506_0708);
+        assert_eq!(DETAILS.min_amount(), 0x0807_0605_0403_0201);
+    }
+}
+
+#[cfg(test)]
+mod test_reject_diagnostics {
+    use super::*;
+
+    static BYTES: &[u8] = b"\
+        \x01\x02\
+        \x06reason\x08no_route\
+        \x0bdestination\x07g.alice\
+    ";
+
+    fn diagnostics() -> RejectDiagnostics {
+        let mut diagnostics = RejectDiagnostics::new();
+        diagnostics.push("reason", "no_route");
+        diagnostics.push("destination", "g.alice");
+        diagnostics
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        assert_eq!(diagnostics().to_bytes(), BYTES);
+        assert_eq!(RejectDiagnostics::new().to_bytes(), b"\x01\x00");
+    }
+
+    #[test]
+    fn test_from_bytes() {
+        assert_eq!(RejectDiagnostics::from_bytes(BYTES).unwrap(), diagnostics());
+        assert!(RejectDiagnostics::from_bytes(b"\x01\x00").unwrap().is_empty());
+
+        // A reason ID isn't diagnostics.
+        assert!(RejectDiagnostics::from_bytes(b"no_route").is_err());
+        assert!(RejectDiagnostics::from_bytes(b"").is_err());
+        // Truncated, or with trailing bytes:
+        assert!(RejectDiagnostics::from_bytes(&BYTES[..BYTES.len() - 1]).is_err());
+        assert!(RejectDiagnostics::from_bytes(&[BYTES, b"\x00"].concat()).is_err());
+        // Invalid UTF-8:
+        assert!(RejectDiagnostics::from_bytes(b"\x01\x01\x01\x81\x00").is_err());
+    }
+
+    #[test]
+    fn test_get() {
+        let mut diagnostics = diagnostics();
+        diagnostics.push("reason", "other");
+        assert_eq!(diagnostics.len(), 3);
+        assert_eq!(diagnostics.get("reason"), Some("no_route"));
+        assert_eq!(diagnostics.get("destination"), Some("g.alice"));
+        assert_eq!(diagnostics.get("account"), None);
+        assert_eq!(
+            diagnostics.iter().map(|(key, _value)| key).collect::<Vec<_>>(),
+            vec!["reason", "destination", "reason"],
+        );
+    }
+}
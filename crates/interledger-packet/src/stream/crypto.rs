@@ -0,0 +1,248 @@
+//! STREAM's connection-level cryptography: shared secrets, fulfillments, and
+//! the encryption of STREAM packets and connection tags (AES-256-GCM).
+
+use bytes::{BufMut, Bytes, BytesMut};
+use ring::{aead, digest, hmac};
+use ring::rand::{SecureRandom, SystemRandom};
+
+use crate::ParseError;
+
+pub const SHARED_SECRET_LEN: usize = 32;
+const NONCE_LEN: usize = 12;
+const AUTH_TAG_LEN: usize = 16;
+
+static CONNECTION_TAG_KEY_STRING: &[u8] = b"ilp_connection_tag_encryption";
+static ENCRYPTION_KEY_STRING: &[u8] = b"ilp_stream_encryption";
+static FULFILLMENT_GENERATION_STRING: &[u8] = b"ilp_stream_fulfillment";
+static SHARED_SECRET_GENERATION_STRING: &[u8] = b"ilp_stream_shared_secret";
+
+/// The shared secret of the connection identified by `token` (e.g. the
+/// connection tag of the receiver's address), so that a receiver doesn't have
+/// to store a secret per connection.
+pub fn generate_shared_secret(server_secret: &[u8], token: &[u8])
+    -> [u8; SHARED_SECRET_LEN]
+{
+    let generator = hmac_sha256(server_secret, SHARED_SECRET_GENERATION_STRING);
+    hmac_sha256(&generator, token)
+}
+
+/// The fulfillment of a Prepare whose data is `data` (the encrypted STREAM
+/// packet).
+pub fn generate_fulfillment(shared_secret: &[u8; SHARED_SECRET_LEN], data: &[u8])
+    -> [u8; 32]
+{
+    let key = hmac_sha256(shared_secret, FULFILLMENT_GENERATION_STRING);
+    hmac_sha256(&key, data)
+}
+
+/// The execution condition (the SHA-256 of the fulfillment) of a Prepare whose
+/// data is `data`.
+pub fn generate_condition(shared_secret: &[u8; SHARED_SECRET_LEN], data: &[u8])
+    -> [u8; 32]
+{
+    let fulfillment = generate_fulfillment(shared_secret, data);
+    let mut condition = [0; 32];
+    condition.copy_from_slice(
+        digest::digest(&digest::SHA256, &fulfillment).as_ref(),
+    );
+    condition
+}
+
+/// Encrypt with a random nonce.
+pub fn encrypt(shared_secret: &[u8; SHARED_SECRET_LEN], plaintext: &[u8])
+    -> Bytes
+{
+    let mut nonce = [0; NONCE_LEN];
+    SystemRandom::new()
+        .fill(&mut nonce)
+        .expect("failed to generate a random nonce");
+    encrypt_with_nonce(shared_secret, nonce, plaintext)
+}
+
+/// The ciphertext is the nonce, then the authentication tag, then the
+/// encrypted data. The `nonce` must never be reused with the same secret.
+pub fn encrypt_with_nonce(
+    shared_secret: &[u8; SHARED_SECRET_LEN],
+    nonce: [u8; NONCE_LEN],
+    plaintext: &[u8],
+) -> Bytes {
+    let mut data = plaintext.to_vec();
+    let tag = encryption_key(shared_secret)
+        .seal_in_place_separate_tag(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::empty(),
+            &mut data,
+        )
+        .expect("failed to encrypt STREAM packet");
+
+    let mut ciphertext =
+        BytesMut::with_capacity(NONCE_LEN + AUTH_TAG_LEN + data.len());
+    ciphertext.put_slice(&nonce);
+    ciphertext.put_slice(tag.as_ref());
+    ciphertext.put_slice(&data);
+    ciphertext.freeze()
+}
+
+pub fn decrypt(shared_secret: &[u8; SHARED_SECRET_LEN], ciphertext: &[u8])
+    -> Result<Vec<u8>, ParseError>
+{
+    if ciphertext.len() < NONCE_LEN + AUTH_TAG_LEN {
+        return Err(ParseError::InvalidPacket({
+            "STREAM ciphertext too short".to_owned()
+        }));
+    }
+    let mut nonce = [0; NONCE_LEN];
+    nonce.copy_from_slice(&ciphertext[..NONCE_LEN]);
+    let tag = &ciphertext[NONCE_LEN..NONCE_LEN + AUTH_TAG_LEN];
+    // `ring` expects the tag after the encrypted data.
+    let mut data = Vec::with_capacity(ciphertext.len() - NONCE_LEN);
+    data.extend_from_slice(&ciphertext[NONCE_LEN + AUTH_TAG_LEN..]);
+    data.extend_from_slice(tag);
+
+    let plaintext_len = encryption_key(shared_secret)
+        .open_in_place(
+            aead::Nonce::assume_unique_for_key(nonce),
+            aead::Aad::empty(),
+            &mut data,
+        )
+        .map_err(|_| ParseError::InvalidPacket({
+            "failed to decrypt STREAM packet".to_owned()
+        }))?
+        .len();
+    data.truncate(plaintext_len);
+    Ok(data)
+}
+
+/// Encrypts a connection tag (e.g. an invoice ID that the receiver shouldn't
+/// reveal to the connectors along the path), like the JS connector. The result
+/// is unpadded URL-safe base64, so it is itself a valid connection tag (see
+/// `Addr::with_connection_tag`).
+///
+/// The `key` is stretched with its own HMAC label, so it may be the receiver's
+/// server secret.
+pub fn encrypt_connection_tag(key: &[u8; SHARED_SECRET_LEN], tag: &[u8])
+    -> String
+{
+    let key = hmac_sha256(key, CONNECTION_TAG_KEY_STRING);
+    base64::encode_config(encrypt(&key, tag), base64::URL_SAFE_NO_PAD)
+}
+
+/// Decrypts a connection tag from `encrypt_connection_tag`.
+pub fn decrypt_connection_tag(key: &[u8; SHARED_SECRET_LEN], encrypted: &[u8])
+    -> Result<Vec<u8>, ParseError>
+{
+    let ciphertext = base64::decode_config(encrypted, base64::URL_SAFE_NO_PAD)
+        .map_err(|_| ParseError::InvalidPacket({
+            "invalid encrypted connection tag".to_owned()
+        }))?;
+    let key = hmac_sha256(key, CONNECTION_TAG_KEY_STRING);
+    decrypt(&key, &ciphertext)
+}
+
+fn encryption_key(shared_secret: &[u8; SHARED_SECRET_LEN]) -> aead::LessSafeKey {
+    let key = hmac_sha256(shared_secret, ENCRYPTION_KEY_STRING);
+    aead::LessSafeKey::new(
+        aead::UnboundKey::new(&aead::AES_256_GCM, &key)
+            .expect("invalid AES-256-GCM key length"),
+    )
+}
+
+fn hmac_sha256(key: &[u8], message: &[u8]) -> [u8; 32] {
+    let key = hmac::Key::new(hmac::HMAC_SHA256, key);
+    let mut output = [0; 32];
+    output.copy_from_slice(hmac::sign(&key, message).as_ref());
+    output
+}
+
+#[cfg(test)]
+mod test_crypto {
+    use super::*;
+
+    static SHARED_SECRET: [u8; 32] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07,
+        0x08, 0x09, 0x0a, 0x0b, 0x0c, 0x0d, 0x0e, 0x0f,
+        0x10, 0x11, 0x12, 0x13, 0x14, 0x15, 0x16, 0x17,
+        0x18, 0x19, 0x1a, 0x1b, 0x1c, 0x1d, 0x1e, 0x1f,
+    ];
+    static NONCE: [u8; NONCE_LEN] = [
+        0x00, 0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x08, 0x09, 0x0a, 0x0b,
+    ];
+
+    #[test]
+    fn test_generate_shared_secret() {
+        assert_eq!(
+            hex::encode(generate_shared_secret(&[0x42; 32], b"connection token")),
+            "2d72785dfee8fe385f95d12b1255e89fdf998a863bfa82203e68f7ceac49f627",
+        );
+    }
+
+    #[test]
+    fn test_generate_fulfillment() {
+        let data = b"stream packet data";
+        assert_eq!(
+            hex::encode(generate_fulfillment(&SHARED_SECRET, data)),
+            "88d1a756ca0cee7cf913119e01b04cb07bc8792cbf2a6787e18e9cc64ce903bd",
+        );
+        assert_eq!(
+            hex::encode(generate_condition(&SHARED_SECRET, data)),
+            "98e4303860e17209d68d24a8817aa313464ed49989f1a801a9c13868162c8743",
+        );
+    }
+
+    #[test]
+    fn test_encrypt_with_nonce() {
+        let ciphertext = encrypt_with_nonce(&SHARED_SECRET, NONCE, b"plaintext");
+        assert_eq!(
+            hex::encode(&ciphertext),
+            "000102030405060708090a0b32da48eaa470eae0fa651d4d588b22cc7942bde77e3e1e2156",
+        );
+        assert_eq!(
+            decrypt(&SHARED_SECRET, &ciphertext).unwrap(),
+            b"plaintext".to_vec(),
+        );
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let ciphertext_1 = encrypt(&SHARED_SECRET, b"plaintext");
+        let ciphertext_2 = encrypt(&SHARED_SECRET, b"plaintext");
+        // The nonces are random.
+        assert_ne!(ciphertext_1, ciphertext_2);
+        assert_eq!(
+            decrypt(&SHARED_SECRET, &ciphertext_1).unwrap(),
+            b"plaintext".to_vec(),
+        );
+        let empty = encrypt(&SHARED_SECRET, b"");
+        assert_eq!(decrypt(&SHARED_SECRET, &empty).unwrap(), Vec::<u8>::new());
+    }
+
+    #[test]
+    fn test_decrypt_invalid() {
+        let ciphertext = encrypt_with_nonce(&SHARED_SECRET, NONCE, b"plaintext");
+        // Wrong secret:
+        assert!(decrypt(&[0; 32], &ciphertext).is_err());
+        // Tampered:
+        let mut tampered = ciphertext.to_vec();
+        *tampered.last_mut().unwrap() ^= 1;
+        assert!(decrypt(&SHARED_SECRET, &tampered).is_err());
+        // Too short:
+        assert!(decrypt(&SHARED_SECRET, &ciphertext[..NONCE_LEN]).is_err());
+    }
+
+    #[test]
+    fn test_encrypt_connection_tag() {
+        let encrypted = encrypt_connection_tag(&SHARED_SECRET, b"invoice 5");
+        assert!(crate::is_valid_connection_tag(encrypted.as_bytes()));
+        assert_eq!(
+            decrypt_connection_tag(&SHARED_SECRET, encrypted.as_bytes()).unwrap(),
+            b"invoice 5".to_vec(),
+        );
+        // The tag key isn't the STREAM packet key.
+        let ciphertext = base64::decode_config(&encrypted, base64::URL_SAFE_NO_PAD)
+            .unwrap();
+        assert!(decrypt(&SHARED_SECRET, &ciphertext).is_err());
+        // Wrong key, or not base64:
+        assert!(decrypt_connection_tag(&[0; 32], encrypted.as_bytes()).is_err());
+        assert!(decrypt_connection_tag(&SHARED_SECRET, b"not.base64").is_err());
+    }
+}
@@ -0,0 +1,454 @@
+//! STREAM packets, which are carried (encrypted) in the data of ILP packets.
+//!
+//! A receiver that terminates STREAM payments decrypts each Prepare's data
+//! with the connection's shared secret, checks that the Prepare's condition
+//! is `crypto::generate_condition` of that data, and responds with an
+//! encrypted packet (in a Fulfill, with `crypto::generate_fulfillment`, or in
+//! a Reject).
+//!
+//! # References
+//!
+//!   * <https://github.com/interledger/rfcs/blob/master/0029-stream/0029-stream.md>
+//!
+
+pub mod crypto;
+
+use byteorder::ReadBytesExt;
+use bytes::{BufMut, Bytes, BytesMut};
+
+use crate::{Address, PacketType, ParseError};
+use crate::oer::{BufOerExt, MutBufOerExt};
+use self::crypto::SHARED_SECRET_LEN;
+
+const STREAM_VERSION: u8 = 1;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StreamPacket {
+    pub sequence: u64,
+    /// The type of the ILP packet that carries this STREAM packet.
+    pub ilp_packet_type: PacketType,
+    /// In a Prepare, the amount that the sender sent. In a Fulfill or Reject,
+    /// the amount that the receiver received.
+    pub prepare_amount: u64,
+    pub frames: Vec<Frame>,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Frame {
+    ConnectionClose {
+        code: ErrorCode,
+        message: String,
+    },
+    ConnectionNewAddress {
+        source_account: Address,
+    },
+    ConnectionMaxData {
+        max_offset: u64,
+    },
+    ConnectionDataBlocked {
+        max_offset: u64,
+    },
+    ConnectionMaxStreamId {
+        max_stream_id: u64,
+    },
+    ConnectionStreamIdBlocked {
+        max_stream_id: u64,
+    },
+    ConnectionAssetDetails {
+        source_asset_code: String,
+        source_asset_scale: u8,
+    },
+    StreamClose {
+        stream_id: u64,
+        code: ErrorCode,
+        message: String,
+    },
+    StreamMoney {
+        stream_id: u64,
+        shares: u64,
+    },
+    StreamMaxMoney {
+        stream_id: u64,
+        receive_max: u64,
+        total_received: u64,
+    },
+    StreamMoneyBlocked {
+        stream_id: u64,
+        send_max: u64,
+        total_sent: u64,
+    },
+    StreamData {
+        stream_id: u64,
+        offset: u64,
+        data: Bytes,
+    },
+    StreamMaxData {
+        stream_id: u64,
+        max_offset: u64,
+    },
+    StreamDataBlocked {
+        stream_id: u64,
+        max_offset: u64,
+    },
+    StreamReceipt {
+        stream_id: u64,
+        receipt: Bytes,
+    },
+}
+
+/// Why a connection or stream was closed.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct ErrorCode(u8);
+
+impl ErrorCode {
+    #[inline]
+    pub const fn new(code: u8) -> Self {
+        ErrorCode(code)
+    }
+
+    pub const NO_ERROR: Self = ErrorCode(0x01);
+    pub const INTERNAL_ERROR: Self = ErrorCode(0x02);
+    pub const ENDPOINT_BUSY: Self = ErrorCode(0x03);
+    pub const FLOW_CONTROL_ERROR: Self = ErrorCode(0x04);
+    pub const STREAM_ID_ERROR: Self = ErrorCode(0x05);
+    pub const STREAM_STATE_ERROR: Self = ErrorCode(0x06);
+    pub const FRAME_FORMAT_ERROR: Self = ErrorCode(0x07);
+    pub const PROTOCOL_VIOLATION: Self = ErrorCode(0x08);
+    pub const APPLICATION_ERROR: Self = ErrorCode(0x09);
+}
+
+impl From<ErrorCode> for u8 {
+    fn from(code: ErrorCode) -> Self {
+        code.0
+    }
+}
+
+impl StreamPacket {
+    /// Parse an unencrypted STREAM packet. Frames of unknown types (and any
+    /// data after the frames) are skipped.
+    pub fn try_from(mut buffer: &[u8]) -> Result<Self, ParseError> {
+        let version = buffer.read_u8()?;
+        if version != STREAM_VERSION {
+            return Err(ParseError::InvalidPacket({
+                format!("unsupported STREAM version: {}", version)
+            }));
+        }
+        let ilp_packet_type = PacketType::try_from(buffer.read_u8()?)?;
+        let sequence = buffer.read_var_uint()?;
+        let prepare_amount = buffer.read_var_uint()?;
+        let frames = buffer
+            .read_sequence_of(|reader| {
+                let frame_type = reader.read_u8()?;
+                let mut contents = reader.read_var_octet_string()?;
+                Frame::try_from(frame_type, &mut contents)
+            })?
+            .into_iter()
+            .flatten()
+            .collect();
+        Ok(StreamPacket { sequence, ilp_packet_type, prepare_amount, frames })
+    }
+
+    pub fn decrypt(
+        shared_secret: &[u8; SHARED_SECRET_LEN],
+        ciphertext: &[u8],
+    ) -> Result<Self, ParseError> {
+        StreamPacket::try_from(&crypto::decrypt(shared_secret, ciphertext)?)
+    }
+
+    pub fn to_bytes(&self) -> Bytes {
+        let mut buffer = BytesMut::new();
+        buffer.put_u8(STREAM_VERSION);
+        buffer.put_u8(self.ilp_packet_type as u8);
+        buffer.put_var_uint(self.sequence);
+        buffer.put_var_uint(self.prepare_amount);
+        buffer.put_sequence_of(&self.frames, |buffer, frame| {
+            buffer.put_u8(frame.frame_type());
+            buffer.put_var_octet_string(frame.contents().freeze());
+        });
+        buffer.freeze()
+    }
+
+    /// Encrypt the packet (with a random nonce), e.g. for the data of an ILP
+    /// packet.
+    pub fn encrypt(&self, shared_secret: &[u8; SHARED_SECRET_LEN]) -> Bytes {
+        crypto::encrypt(shared_secret, &self.to_bytes())
+    }
+}
+
+impl Frame {
+    /// Returns `None` for unknown frame types.
+    fn try_from(frame_type: u8, reader: &mut &[u8])
+        -> Result<Option<Self>, ParseError>
+    {
+        Ok(Some(match frame_type {
+            0x01 => Frame::ConnectionClose {
+                code: ErrorCode(reader.read_u8()?),
+                message: read_string(reader)?,
+            },
+            0x02 => Frame::ConnectionNewAddress {
+                source_account: Address::try_from({
+                    Bytes::copy_from_slice(reader.read_var_octet_string()?)
+                })?,
+            },
+            0x03 => Frame::ConnectionMaxData {
+                max_offset: reader.read_var_uint()?,
+            },
+            0x04 => Frame::ConnectionDataBlocked {
+                max_offset: reader.read_var_uint()?,
+            },
+            0x05 => Frame::ConnectionMaxStreamId {
+                max_stream_id: reader.read_var_uint()?,
+            },
+            0x06 => Frame::ConnectionStreamIdBlocked {
+                max_stream_id: reader.read_var_uint()?,
+            },
+            0x07 => Frame::ConnectionAssetDetails {
+                source_asset_code: read_string(reader)?,
+                source_asset_scale: reader.read_u8()?,
+            },
+            0x10 => Frame::StreamClose {
+                stream_id: reader.read_var_uint()?,
+                code: ErrorCode(reader.read_u8()?),
+                message: read_string(reader)?,
+            },
+            0x11 => Frame::StreamMoney {
+                stream_id: reader.read_var_uint()?,
+                shares: reader.read_var_uint()?,
+            },
+            0x12 => Frame::StreamMaxMoney {
+                stream_id: reader.read_var_uint()?,
+                receive_max: reader.read_var_uint()?,
+                total_received: reader.read_var_uint()?,
+            },
+            0x13 => Frame::StreamMoneyBlocked {
+                stream_id: reader.read_var_uint()?,
+                send_max: reader.read_var_uint()?,
+                total_sent: reader.read_var_uint()?,
+            },
+            0x14 => Frame::StreamData {
+                stream_id: reader.read_var_uint()?,
+                offset: reader.read_var_uint()?,
+                data: Bytes::copy_from_slice(reader.read_var_octet_string()?),
+            },
+            0x15 => Frame::StreamMaxData {
+                stream_id: reader.read_var_uint()?,
+                max_offset: reader.read_var_uint()?,
+            },
+            0x16 => Frame::StreamDataBlocked {
+                stream_id: reader.read_var_uint()?,
+                max_offset: reader.read_var_uint()?,
+            },
+            0x17 => Frame::StreamReceipt {
+                stream_id: reader.read_var_uint()?,
+                receipt: Bytes::copy_from_slice(reader.read_var_octet_string()?),
+            },
+            _ => return Ok(None),
+        }))
+    }
+
+    pub fn frame_type(&self) -> u8 {
+        match self {
+            Frame::ConnectionClose { .. } => 0x01,
+            Frame::ConnectionNewAddress { .. } => 0x02,
+            Frame::ConnectionMaxData { .. } => 0x03,
+            Frame::ConnectionDataBlocked { .. } => 0x04,
+            Frame::ConnectionMaxStreamId { .. } => 0x05,
+            Frame::ConnectionStreamIdBlocked { .. } => 0x06,
+            Frame::ConnectionAssetDetails { .. } => 0x07,
+            Frame::StreamClose { .. } => 0x10,
+            Frame::StreamMoney { .. } => 0x11,
+            Frame::StreamMaxMoney { .. } => 0x12,
+            Frame::StreamMoneyBlocked { .. } => 0x13,
+            Frame::StreamData { .. } => 0x14,
+            Frame::StreamMaxData { .. } => 0x15,
+            Frame::StreamDataBlocked { .. } => 0x16,
+            Frame::StreamReceipt { .. } => 0x17,
+        }
+    }
+
+    fn contents(&self) -> BytesMut {
+        let mut buffer = BytesMut::new();
+        match self {
+            Frame::ConnectionClose { code, message } => {
+                buffer.put_u8(code.0);
+                buffer.put_var_octet_string(message.as_bytes());
+            },
+            Frame::ConnectionNewAddress { source_account } => {
+                buffer.put_var_octet_string(source_account.as_ref());
+            },
+            Frame::ConnectionMaxData { max_offset }
+            | Frame::ConnectionDataBlocked { max_offset } => {
+                buffer.put_var_uint(*max_offset);
+            },
+            Frame::ConnectionMaxStreamId { max_stream_id }
+            | Frame::ConnectionStreamIdBlocked { max_stream_id } => {
+                buffer.put_var_uint(*max_stream_id);
+            },
+            Frame::ConnectionAssetDetails { source_asset_code, source_asset_scale } => {
+                buffer.put_var_octet_string(source_asset_code.as_bytes());
+                buffer.put_u8(*source_asset_scale);
+            },
+            Frame::StreamClose { stream_id, code, message } => {
+                buffer.put_var_uint(*stream_id);
+                buffer.put_u8(code.0);
+                buffer.put_var_octet_string(message.as_bytes());
+            },
+            Frame::StreamMoney { stream_id, shares } => {
+                buffer.put_var_uint(*stream_id);
+                buffer.put_var_uint(*shares);
+            },
+            Frame::StreamMaxMoney { stream_id, receive_max, total_received } => {
+                buffer.put_var_uint(*stream_id);
+                buffer.put_var_uint(*receive_max);
+                buffer.put_var_uint(*total_received);
+            },
+            Frame::StreamMoneyBlocked { stream_id, send_max, total_sent } => {
+                buffer.put_var_uint(*stream_id);
+                buffer.put_var_uint(*send_max);
+                buffer.put_var_uint(*total_sent);
+            },
+            Frame::StreamData { stream_id, offset, data } => {
+                buffer.put_var_uint(*stream_id);
+                buffer.put_var_uint(*offset);
+                buffer.put_var_octet_string(&data[..]);
+            },
+            Frame::StreamMaxData { stream_id, max_offset }
+            | Frame::StreamDataBlocked { stream_id, max_offset } => {
+                buffer.put_var_uint(*stream_id);
+                buffer.put_var_uint(*max_offset);
+            },
+            Frame::StreamReceipt { stream_id, receipt } => {
+                buffer.put_var_uint(*stream_id);
+                buffer.put_var_octet_string(&receipt[..]);
+            },
+        }
+        buffer
+    }
+}
+
+fn read_string(reader: &mut &[u8]) -> Result<String, ParseError> {
+    Ok(String::from_utf8(reader.read_var_octet_string()?.to_vec())?)
+}
+
+#[cfg(test)]
+mod test_stream_packet {
+    use lazy_static::lazy_static;
+
+    use super::*;
+
+    static PACKET_BYTES: &[u8] = b"\
+        \x01\x0c\x01\x01\x01\x0a\x01\x02\
+        \x11\x04\x01\x01\x01\x02\
+        \x14\x07\x01\x01\x01\x02\x02\x68\x69\
+    ";
+
+    lazy_static! {
+        static ref PACKET: StreamPacket = StreamPacket {
+            sequence: 1,
+            ilp_packet_type: PacketType::Prepare,
+            prepare_amount: 10,
+            frames: vec![
+                Frame::StreamMoney { stream_id: 1, shares: 2 },
+                Frame::StreamData {
+                    stream_id: 1,
+                    offset: 2,
+                    data: Bytes::from_static(b"hi"),
+                },
+            ],
+        };
+
+        static ref FRAMES: Vec<Frame> = vec![
+            Frame::ConnectionClose {
+                code: ErrorCode::NO_ERROR,
+                message: "done".to_owned(),
+            },
+            Frame::ConnectionNewAddress {
+                source_account: Address::new(b"example.alice"),
+            },
+            Frame::ConnectionMaxData { max_offset: 1_000 },
+            Frame::ConnectionDataBlocked { max_offset: 1_000 },
+            Frame::ConnectionMaxStreamId { max_stream_id: 20 },
+            Frame::ConnectionStreamIdBlocked { max_stream_id: 20 },
+            Frame::ConnectionAssetDetails {
+                source_asset_code: "XRP".to_owned(),
+                source_asset_scale: 9,
+            },
+            Frame::StreamClose {
+                stream_id: 1,
+                code: ErrorCode::APPLICATION_ERROR,
+                message: "oops".to_owned(),
+            },
+            Frame::StreamMoney { stream_id: 1, shares: 3 },
+            Frame::StreamMaxMoney {
+                stream_id: 1,
+                receive_max: u64::MAX,
+                total_received: 500,
+            },
+            Frame::StreamMoneyBlocked {
+                stream_id: 1,
+                send_max: 600,
+                total_sent: 500,
+            },
+            Frame::StreamData {
+                stream_id: 1,
+                offset: 0,
+                data: Bytes::from_static(b"hello"),
+            },
+            Frame::StreamMaxData { stream_id: 1, max_offset: 100 },
+            Frame::StreamDataBlocked { stream_id: 1, max_offset: 100 },
+            Frame::StreamReceipt {
+                stream_id: 1,
+                receipt: Bytes::from_static(&[0x01; 58]),
+            },
+        ];
+    }
+
+    #[test]
+    fn test_try_from() {
+        assert_eq!(StreamPacket::try_from(PACKET_BYTES).unwrap(), *PACKET);
+
+        // Unknown frame types and junk data are skipped:
+        let mut buffer = PACKET_BYTES.to_vec();
+        buffer[7] = 0x03;
+        buffer.extend_from_slice(b"\xff\x01\x00junk");
+        assert_eq!(StreamPacket::try_from(&buffer).unwrap(), *PACKET);
+
+        // Wrong version:
+        let mut buffer = PACKET_BYTES.to_vec();
+        buffer[0] = 0x02;
+        assert!(StreamPacket::try_from(&buffer).is_err());
+        // Wrong ILP packet type:
+        let mut buffer = PACKET_BYTES.to_vec();
+        buffer[1] = 0x0f;
+        assert!(StreamPacket::try_from(&buffer).is_err());
+        // Truncated:
+        assert!(StreamPacket::try_from(&PACKET_BYTES[..12]).is_err());
+    }
+
+    #[test]
+    fn test_to_bytes() {
+        assert_eq!(PACKET.to_bytes().as_ref(), PACKET_BYTES);
+
+        let packet = StreamPacket {
+            sequence: u64::MAX,
+            ilp_packet_type: PacketType::Reject,
+            prepare_amount: 0,
+            frames: FRAMES.clone(),
+        };
+        assert_eq!(
+            StreamPacket::try_from(&packet.to_bytes()).unwrap(),
+            packet,
+        );
+    }
+
+    #[test]
+    fn test_encrypt() {
+        let shared_secret = [0x07; SHARED_SECRET_LEN];
+        let ciphertext = PACKET.encrypt(&shared_secret);
+        assert_eq!(
+            StreamPacket::decrypt(&shared_secret, &ciphertext).unwrap(),
+            *PACKET,
+        );
+        assert!(StreamPacket::decrypt(&[0x08; 32], &ciphertext).is_err());
+    }
+}
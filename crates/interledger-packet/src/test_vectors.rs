@@ -0,0 +1,179 @@
+//! Validate parsing and serialization against the language-independent test
+//! vectors in `test-vectors/packets.json`.
+//!
+//! Each vector pairs the (hex) OER encoding of a packet with its fields. The
+//! JSON uses the field names of the JavaScript implementation (`ilp-packet`),
+//! so that the same file can be used to check both: amounts are decimal
+//! strings, binary fields are hex, and an empty `triggeredBy` means `None`.
+
+use std::time::SystemTime;
+
+use bytes::BytesMut;
+use chrono::DateTime;
+use serde::Deserialize;
+
+use super::*;
+
+static VECTORS: &str = include_str!("../test-vectors/packets.json");
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Vectors {
+    prepare: Vec<Vector<PrepareFields>>,
+    fulfill: Vec<Vector<FulfillFields>>,
+    reject: Vec<Vector<RejectFields>>,
+    invalid: Vec<InvalidVector>,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Vector<F> {
+    name: String,
+    binary: String,
+    json: F,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct PrepareFields {
+    amount: String,
+    expires_at: String,
+    execution_condition: String,
+    destination: String,
+    data: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct FulfillFields {
+    fulfillment: String,
+    data: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields, rename_all = "camelCase")]
+struct RejectFields {
+    code: String,
+    triggered_by: String,
+    message: String,
+    data: String,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct InvalidVector {
+    name: String,
+    binary: String,
+}
+
+lazy_static::lazy_static! {
+    static ref TEST_VECTORS: Vectors = serde_json::from_str(VECTORS).unwrap();
+}
+
+fn decode(hex: &str) -> Vec<u8> {
+    hex::decode(hex).unwrap()
+}
+
+fn decode_32(hex: &str) -> [u8; 32] {
+    let mut bytes = [0; 32];
+    bytes.copy_from_slice(&decode(hex));
+    bytes
+}
+
+fn parse_timestamp(timestamp: &str) -> SystemTime {
+    DateTime::parse_from_rfc3339(timestamp).unwrap().into()
+}
+
+#[test]
+fn test_prepare() {
+    for vector in &TEST_VECTORS.prepare {
+        let binary = decode(&vector.binary);
+        let fields = &vector.json;
+        let amount = fields.amount.parse::<u64>().unwrap();
+        let expires_at = parse_timestamp(&fields.expires_at);
+        let execution_condition = decode_32(&fields.execution_condition);
+        let data = decode(&fields.data);
+
+        let prepare = Prepare::try_from(BytesMut::from(&binary[..]))
+            .unwrap_or_else(|error| panic!("{}: {:?}", vector.name, error));
+        assert_eq!(prepare.amount(), amount, "{}", vector.name);
+        assert_eq!(prepare.expires_at(), expires_at, "{}", vector.name);
+        assert_eq!(prepare.execution_condition(), &execution_condition[..], "{}", vector.name);
+        assert_eq!(prepare.destination(), Addr::new(fields.destination.as_bytes()), "{}", vector.name);
+        assert_eq!(prepare.data(), &data[..], "{}", vector.name);
+
+        let built = PrepareBuilder {
+            amount,
+            expires_at,
+            execution_condition: &execution_condition,
+            destination: Addr::new(fields.destination.as_bytes()),
+            data: &data,
+        }.build();
+        assert_eq!(built.as_ref(), &binary[..], "{}", vector.name);
+    }
+}
+
+#[test]
+fn test_fulfill() {
+    for vector in &TEST_VECTORS.fulfill {
+        let binary = decode(&vector.binary);
+        let fields = &vector.json;
+        let fulfillment = decode_32(&fields.fulfillment);
+        let data = decode(&fields.data);
+
+        let fulfill = Fulfill::try_from(BytesMut::from(&binary[..]))
+            .unwrap_or_else(|error| panic!("{}: {:?}", vector.name, error));
+        assert_eq!(fulfill.fulfillment(), &fulfillment[..], "{}", vector.name);
+        assert_eq!(fulfill.data(), &data[..], "{}", vector.name);
+
+        let built = FulfillBuilder {
+            fulfillment: &fulfillment,
+            data: &data,
+        }.build();
+        assert_eq!(built.as_ref(), &binary[..], "{}", vector.name);
+    }
+}
+
+#[test]
+fn test_reject() {
+    for vector in &TEST_VECTORS.reject {
+        let binary = decode(&vector.binary);
+        let fields = &vector.json;
+        let mut code = [0; 3];
+        code.copy_from_slice(fields.code.as_bytes());
+        let code = ErrorCode::new(code);
+        let triggered_by = if fields.triggered_by.is_empty() {
+            None
+        } else {
+            Some(Addr::new(fields.triggered_by.as_bytes()))
+        };
+        let message = decode(&fields.message);
+        let data = decode(&fields.data);
+
+        let reject = Reject::try_from(BytesMut::from(&binary[..]))
+            .unwrap_or_else(|error| panic!("{}: {:?}", vector.name, error));
+        assert_eq!(reject.code(), code, "{}", vector.name);
+        assert_eq!(reject.triggered_by(), triggered_by, "{}", vector.name);
+        assert_eq!(reject.message(), &message[..], "{}", vector.name);
+        assert_eq!(reject.data(), &data[..], "{}", vector.name);
+
+        let built = RejectBuilder {
+            code,
+            message: &message,
+            triggered_by,
+            data: &data,
+        }.build();
+        assert_eq!(built.as_ref(), &binary[..], "{}", vector.name);
+    }
+}
+
+#[test]
+fn test_invalid() {
+    for vector in &TEST_VECTORS.invalid {
+        let binary = decode(&vector.binary);
+        assert!(
+            Packet::try_from(BytesMut::from(&binary[..])).is_err(),
+            "{}", vector.name,
+        );
+    }
+}
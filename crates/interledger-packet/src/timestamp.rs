@@ -0,0 +1,174 @@
+//! The fixed-length timestamp of a Prepare's `expiresAt`: 17 ASCII digits
+//! (`YYYYMMDDHHmmssfff`), in UTC, with millisecond precision.
+//!
+//! A `SystemTime` is more precise, and has a wider range, than the timestamp.
+//! Encoding a time truncates it to the millisecond and clamps it to the
+//! representable range; `normalize` returns the time that the encoded timestamp
+//! decodes to.
+
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use chrono::{DateTime, NaiveDate, Utc};
+
+use super::ParseError;
+
+pub const TIMESTAMP_LEN: usize = 17;
+
+static FORMAT: &str = "%Y%m%d%H%M%S%3f";
+
+/// The number of seconds from `0000-01-01T00:00:00Z` to the Unix epoch.
+const EARLIEST_BEFORE_EPOCH: u64 = 62_167_219_200;
+/// The number of milliseconds from the Unix epoch to `9999-12-31T23:59:59.999Z`.
+const LATEST_AFTER_EPOCH: u64 = 253_402_300_799_999;
+
+/// The earliest representable time: `0000-01-01T00:00:00.000Z`.
+pub fn earliest() -> SystemTime {
+    UNIX_EPOCH - Duration::from_secs(EARLIEST_BEFORE_EPOCH)
+}
+
+/// The latest representable time: `9999-12-31T23:59:59.999Z`.
+pub fn latest() -> SystemTime {
+    UNIX_EPOCH + Duration::from_millis(LATEST_AFTER_EPOCH)
+}
+
+/// Round `time` down (toward the past) to the millisecond.
+pub fn truncate(time: SystemTime) -> SystemTime {
+    match time.duration_since(UNIX_EPOCH) {
+        Ok(since_epoch) => {
+            let sub_milli = since_epoch.subsec_nanos() % 1_000_000;
+            time - Duration::from_nanos(u64::from(sub_milli))
+        },
+        Err(error) => {
+            let sub_milli = error.duration().subsec_nanos() % 1_000_000;
+            if sub_milli == 0 {
+                time
+            } else {
+                time - Duration::from_nanos(u64::from(1_000_000 - sub_milli))
+            }
+        },
+    }
+}
+
+/// Truncate `time` to the millisecond, and clamp it to the representable range.
+pub fn normalize(time: SystemTime) -> SystemTime {
+    if time < earliest() {
+        earliest()
+    } else if time > latest() {
+        latest()
+    } else {
+        truncate(time)
+    }
+}
+
+/// Encode the (normalized) time.
+pub fn encode(time: SystemTime) -> [u8; TIMESTAMP_LEN] {
+    let mut timestamp = [0; TIMESTAMP_LEN];
+    write!(
+        &mut timestamp[..],
+        "{}",
+        DateTime::<Utc>::from(normalize(time)).format(FORMAT),
+    ).expect("timestamp::encode error");
+    timestamp
+}
+
+/// Decode a timestamp. It must be exactly 17 digits, and a valid UTC time.
+pub fn decode(timestamp: &[u8]) -> Result<SystemTime, ParseError> {
+    let invalid = || ParseError::InvalidPacket(format!(
+        "invalid timestamp: {:?}",
+        String::from_utf8_lossy(timestamp),
+    ));
+    if timestamp.len() != TIMESTAMP_LEN
+        || !timestamp.iter().all(u8::is_ascii_digit)
+    {
+        return Err(invalid());
+    }
+    let field = |start: usize, end: usize| {
+        timestamp[start..end]
+            .iter()
+            .fold(0, |value, digit| value * 10 + u32::from(digit - b'0'))
+    };
+    let time = NaiveDate::from_ymd_opt(
+        field(0, 4) as i32, field(4, 6), field(6, 8),
+    ).and_then(|date| date.and_hms_milli_opt(
+        field(8, 10), field(10, 12), field(12, 14), field(14, 17),
+    )).ok_or_else(invalid)?;
+    let epoch = NaiveDate::from_ymd_opt(1970, 1, 1)
+        .and_then(|date| date.and_hms_opt(0, 0, 0))
+        .unwrap();
+
+    let millis = time.signed_duration_since(epoch).num_milliseconds();
+    Ok(if millis < 0 {
+        UNIX_EPOCH - Duration::from_millis((-millis) as u64)
+    } else {
+        UNIX_EPOCH + Duration::from_millis(millis as u64)
+    })
+}
+
+#[cfg(test)]
+mod test_timestamp {
+    use super::*;
+
+    #[test]
+    fn test_encode_decode() {
+        let tests: &[(SystemTime, &[u8])] = &[
+            (UNIX_EPOCH, b"19700101000000000"),
+            (
+                UNIX_EPOCH + Duration::from_millis(1_528_404_522_483),
+                b"20180607204842483",
+            ),
+            (earliest(), b"00000101000000000"),
+            (latest(), b"99991231235959999"),
+            (UNIX_EPOCH - Duration::from_millis(1), b"19691231235959999"),
+        ];
+        for (time, timestamp) in tests {
+            assert_eq!(&encode(*time)[..], *timestamp);
+            assert_eq!(decode(timestamp).unwrap(), *time);
+        }
+    }
+
+    #[test]
+    fn test_encode_normalizes() {
+        let time = UNIX_EPOCH + Duration::new(1, 999_999_999);
+        assert_eq!(&encode(time)[..], b"19700101000001999");
+        assert_eq!(
+            &encode(latest() + Duration::from_secs(1))[..],
+            b"99991231235959999",
+        );
+        assert_eq!(
+            &encode(earliest() - Duration::from_secs(1))[..],
+            b"00000101000000000",
+        );
+    }
+
+    #[test]
+    fn test_truncate() {
+        let ms = Duration::from_millis;
+        let time = UNIX_EPOCH + Duration::new(10, 123_456_789);
+        assert_eq!(truncate(time), UNIX_EPOCH + ms(10_123));
+        assert_eq!(truncate(UNIX_EPOCH + ms(5)), UNIX_EPOCH + ms(5));
+        // Before the epoch, truncation still rounds toward the past.
+        let time = UNIX_EPOCH - Duration::from_nanos(1_500_000);
+        assert_eq!(truncate(time), UNIX_EPOCH - ms(2));
+        assert_eq!(truncate(UNIX_EPOCH - ms(3)), UNIX_EPOCH - ms(3));
+    }
+
+    #[test]
+    fn test_decode_invalid() {
+        let tests: &[&[u8]] = &[
+            b"",
+            b"2018060720484248",
+            b"201806072048424830",
+            b"2018060720484248x",
+            b"+2018060720484248",
+            b"20181307204842483",
+            b"20180230204842483",
+            b"20180607244842483",
+            b"20180607206042483",
+            b"20180607204860483",
+        ];
+        for timestamp in tests {
+            assert!(decode(timestamp).is_err(), "{:?}", timestamp);
+        }
+    }
+}
@@ -0,0 +1,173 @@
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+
+use serde::Serialize;
+
+static COUNTERS: Counters = Counters::new();
+
+/// Wraps the system allocator, counting allocations and allocated bytes, so
+/// that memory pressure (e.g. from buffered packets or telemetry queues) and
+/// leaks show up in the metrics and the admin API.
+///
+/// It only takes effect when it is installed as the `#[global_allocator]` (as
+/// it is by `ilprelay`). Otherwise, `AllocatorStats::get` returns `None`.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct CountingAllocator;
+
+unsafe impl GlobalAlloc for CountingAllocator {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc(layout);
+        if !ptr.is_null() {
+            COUNTERS.allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = System.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            COUNTERS.allocated(layout.size());
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        System.dealloc(ptr, layout);
+        COUNTERS.deallocated(layout.size());
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize)
+        -> *mut u8
+    {
+        let new_ptr = System.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            COUNTERS.resized(layout.size(), new_size);
+        }
+        new_ptr
+    }
+}
+
+/// A snapshot of the `CountingAllocator`'s counters.
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct AllocatorStats {
+    /// The bytes that are currently allocated.
+    pub allocated_bytes: usize,
+    /// The most bytes that were allocated at once (since startup).
+    pub peak_allocated_bytes: usize,
+    pub allocations: u64,
+    pub deallocations: u64,
+}
+
+impl AllocatorStats {
+    /// Returns `None` when the `CountingAllocator` isn't the global allocator.
+    pub fn get() -> Option<Self> {
+        COUNTERS.stats()
+    }
+}
+
+#[derive(Debug)]
+struct Counters {
+    /// Set by the first allocation, i.e. when the allocator is installed.
+    installed: AtomicBool,
+    allocated_bytes: AtomicUsize,
+    peak_allocated_bytes: AtomicUsize,
+    allocations: AtomicU64,
+    deallocations: AtomicU64,
+}
+
+// The counters must not allocate.
+impl Counters {
+    const fn new() -> Self {
+        Counters {
+            installed: AtomicBool::new(false),
+            allocated_bytes: AtomicUsize::new(0),
+            peak_allocated_bytes: AtomicUsize::new(0),
+            allocations: AtomicU64::new(0),
+            deallocations: AtomicU64::new(0),
+        }
+    }
+
+    fn allocated(&self, size: usize) {
+        self.installed.store(true, Ordering::Relaxed);
+        self.allocations.fetch_add(1, Ordering::Relaxed);
+        let allocated = self.allocated_bytes
+            .fetch_add(size, Ordering::Relaxed)
+            .wrapping_add(size);
+        self.update_peak(allocated);
+    }
+
+    fn deallocated(&self, size: usize) {
+        self.deallocations.fetch_add(1, Ordering::Relaxed);
+        self.allocated_bytes.fetch_sub(size, Ordering::Relaxed);
+    }
+
+    fn resized(&self, old_size: usize, new_size: usize) {
+        if new_size >= old_size {
+            let growth = new_size - old_size;
+            let allocated = self.allocated_bytes
+                .fetch_add(growth, Ordering::Relaxed)
+                .wrapping_add(growth);
+            self.update_peak(allocated);
+        } else {
+            self.allocated_bytes
+                .fetch_sub(old_size - new_size, Ordering::Relaxed);
+        }
+    }
+
+    fn update_peak(&self, allocated: usize) {
+        let mut peak = self.peak_allocated_bytes.load(Ordering::Relaxed);
+        while allocated > peak {
+            match self.peak_allocated_bytes.compare_exchange_weak(
+                peak,
+                allocated,
+                Ordering::Relaxed,
+                Ordering::Relaxed,
+            ) {
+                Ok(_) => break,
+                Err(current_peak) => peak = current_peak,
+            }
+        }
+    }
+
+    fn stats(&self) -> Option<AllocatorStats> {
+        if !self.installed.load(Ordering::Relaxed) {
+            return None;
+        }
+        Some(AllocatorStats {
+            allocated_bytes: self.allocated_bytes.load(Ordering::Relaxed),
+            peak_allocated_bytes:
+                self.peak_allocated_bytes.load(Ordering::Relaxed),
+            allocations: self.allocations.load(Ordering::Relaxed),
+            deallocations: self.deallocations.load(Ordering::Relaxed),
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_counters {
+    use super::*;
+
+    #[test]
+    fn test_stats() {
+        let counters = Counters::new();
+        assert_eq!(counters.stats(), None);
+
+        counters.allocated(100);
+        counters.allocated(50);
+        counters.deallocated(100);
+        counters.resized(50, 80);
+        counters.resized(80, 10);
+        assert_eq!(
+            counters.stats(),
+            Some(AllocatorStats {
+                allocated_bytes: 10,
+                peak_allocated_bytes: 150,
+                allocations: 2,
+                deallocations: 1,
+            }),
+        );
+
+        counters.resized(10, 200);
+        assert_eq!(counters.stats().unwrap().peak_allocated_bytes, 200);
+    }
+}
@@ -0,0 +1,134 @@
+use std::cmp;
+use std::fmt;
+
+/// An amount of an asset, as an integer number of its smallest units: the
+/// amount is `value * 10^-scale`. For example, `AssetAmount::new(1_500, 3)` is
+/// 1.5 units.
+///
+/// Equality is structural: `1.5` at scale 3 and `1.50` at scale 2 are not equal.
+/// Convert both amounts to a common scale to compare them.
+#[derive(Clone, Copy, Debug, Eq, Hash, PartialEq)]
+pub struct AssetAmount {
+    value: u64,
+    scale: u8,
+}
+
+impl AssetAmount {
+    pub fn new(value: u64, scale: u8) -> Self {
+        AssetAmount { value, scale }
+    }
+
+    #[inline]
+    pub fn value(&self) -> u64 {
+        self.value
+    }
+
+    #[inline]
+    pub fn scale(&self) -> u8 {
+        self.scale
+    }
+
+    /// The same amount at a different scale. Returns `None` if the value
+    /// overflows, or if reducing the scale would lose precision.
+    pub fn to_scale(self, scale: u8) -> Option<Self> {
+        if scale < self.scale {
+            let divisor = pow10(self.scale - scale)?;
+            match self.value % divisor {
+                0 => Some(AssetAmount::new(self.value / divisor, scale)),
+                _remainder => None,
+            }
+        } else {
+            self.to_scale_floor(scale)
+        }
+    }
+
+    /// Like `to_scale`, but a smaller scale rounds the value down instead.
+    /// Returns `None` if the value overflows.
+    pub fn to_scale_floor(self, scale: u8) -> Option<Self> {
+        let value = if scale < self.scale {
+            // Dividing by more than `u64::MAX` always rounds down to zero.
+            pow10(self.scale - scale).map_or(0, |divisor| self.value / divisor)
+        } else {
+            self.value.checked_mul(pow10(scale - self.scale)?)?
+        };
+        Some(AssetAmount::new(value, scale))
+    }
+
+    /// The sum, at the larger of the two scales. Returns `None` on overflow.
+    pub fn checked_add(self, other: Self) -> Option<Self> {
+        let (a, b) = self.to_common_scale(other)?;
+        Some(AssetAmount::new(a.value.checked_add(b.value)?, a.scale))
+    }
+
+    /// The difference, at the larger of the two scales. Returns `None` on
+    /// overflow or underflow.
+    pub fn checked_sub(self, other: Self) -> Option<Self> {
+        let (a, b) = self.to_common_scale(other)?;
+        Some(AssetAmount::new(a.value.checked_sub(b.value)?, a.scale))
+    }
+
+    fn to_common_scale(self, other: Self) -> Option<(Self, Self)> {
+        let scale = cmp::max(self.scale, other.scale);
+        Some((self.to_scale(scale)?, other.to_scale(scale)?))
+    }
+}
+
+/// Formats the amount in units, keeping every digit of the scale (e.g.
+/// `1.500` for a value of 1500 at scale 3).
+impl fmt::Display for AssetAmount {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let scale = self.scale as usize;
+        if scale == 0 {
+            return write!(f, "{}", self.value);
+        }
+        let digits = format!("{:0width$}", self.value, width = scale + 1);
+        let (units, fraction) = digits.split_at(digits.len() - scale);
+        write!(f, "{}.{}", units, fraction)
+    }
+}
+
+fn pow10(exponent: u8) -> Option<u64> {
+    10_u64.checked_pow(u32::from(exponent))
+}
+
+#[cfg(test)]
+mod test_asset_amount {
+    use super::*;
+
+    #[test]
+    fn test_to_scale() {
+        let amount = AssetAmount::new(1_500, 3);
+        assert_eq!(amount.to_scale(5), Some(AssetAmount::new(150_000, 5)));
+        assert_eq!(amount.to_scale(2), Some(AssetAmount::new(150, 2)));
+        assert_eq!(amount.to_scale(0), None);
+        assert_eq!(amount.to_scale_floor(0), Some(AssetAmount::new(1, 0)));
+        // Overflow.
+        assert_eq!(AssetAmount::new(u64::MAX, 0).to_scale(1), None);
+        assert_eq!(amount.to_scale(30), None);
+        assert_eq!(
+            AssetAmount::new(u64::MAX, 30).to_scale_floor(0),
+            Some(AssetAmount::new(0, 0)),
+        );
+    }
+
+    #[test]
+    fn test_checked_add_sub() {
+        let a = AssetAmount::new(1_500, 3);
+        let b = AssetAmount::new(25, 2);
+        assert_eq!(a.checked_add(b), Some(AssetAmount::new(1_750, 3)));
+        assert_eq!(a.checked_sub(b), Some(AssetAmount::new(1_250, 3)));
+        assert_eq!(b.checked_sub(a), None);
+        assert_eq!(
+            AssetAmount::new(u64::MAX, 0).checked_add(AssetAmount::new(1, 0)),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(AssetAmount::new(1_500, 3).to_string(), "1.500");
+        assert_eq!(AssetAmount::new(123, 9).to_string(), "0.000000123");
+        assert_eq!(AssetAmount::new(0, 2).to_string(), "0.00");
+        assert_eq!(AssetAmount::new(42, 0).to_string(), "42");
+    }
+}
@@ -0,0 +1,161 @@
+use std::sync::{Arc, RwLock};
+
+use log::info;
+
+use ilp::ildcp;
+
+/// The connector's address and asset, from its root (the static config, or
+/// the parent's ILDCP response). Clones share them.
+///
+/// When a dynamic root's ILDCP config is refreshed, the response replaces the
+/// whole snapshot at once, so that no service sees the new address with the
+/// old asset (or vice versa).
+#[derive(Clone, Debug)]
+pub struct ConnectorInfo {
+    /// The address that the relatives' addresses were derived from at startup.
+    initial_address: ilp::Address,
+    snapshot: Arc<RwLock<Arc<Snapshot>>>,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Snapshot {
+    pub address: ilp::Address,
+    /// `None` when only the address is known, e.g. in the tests of services
+    /// that don't need the asset.
+    pub ildcp: Option<ildcp::Response>,
+}
+
+impl ConnectorInfo {
+    pub fn get(&self) -> Arc<Snapshot> {
+        Arc::clone(&self.snapshot.read().unwrap())
+    }
+
+    pub fn address(&self) -> ilp::Address {
+        self.snapshot.read().unwrap().address.clone()
+    }
+
+    pub fn asset_scale(&self) -> Option<u8> {
+        self.snapshot.read().unwrap()
+            .ildcp
+            .as_ref()
+            .map(ildcp::Response::asset_scale)
+    }
+
+    pub fn initial_address(&self) -> &ilp::Address {
+        &self.initial_address
+    }
+
+    /// Replace the snapshot with the (refreshed) ILDCP response.
+    pub fn set(&self, response: ildcp::Response) {
+        let new_snapshot = Arc::new(Snapshot::from(response));
+        let mut snapshot = self.snapshot.write().unwrap();
+        if *new_snapshot == **snapshot {
+            return;
+        }
+        if new_snapshot.address != snapshot.address {
+            info!(
+                "connector address changed: address={} new_address={}",
+                snapshot.address, new_snapshot.address,
+            );
+        }
+        let new_ildcp = new_snapshot.ildcp.as_ref().unwrap();
+        info!(
+            "connector config changed: asset_code={:?} asset_scale={}",
+            String::from_utf8_lossy(new_ildcp.asset_code()),
+            new_ildcp.asset_scale(),
+        );
+        *snapshot = new_snapshot;
+    }
+
+    /// Move an address that was derived from the connector's address at
+    /// startup (e.g. a child's) under the current address.
+    pub fn rebase(&self, address: &ilp::Address) -> ilp::Address {
+        let current = self.address();
+        if current == self.initial_address
+            || !address.starts_with_prefix(self.initial_address.as_ref())
+        {
+            return address.clone();
+        }
+        let segments = address
+            .segments()
+            .skip(self.initial_address.segments().count())
+            .collect::<Vec<_>>();
+        current
+            .try_with_suffix(&segments)
+            .unwrap_or_else(|_error| address.clone())
+    }
+}
+
+impl From<ilp::Address> for ConnectorInfo {
+    fn from(address: ilp::Address) -> Self {
+        ConnectorInfo {
+            initial_address: address.clone(),
+            snapshot: Arc::new(RwLock::new(Arc::new(Snapshot {
+                address,
+                ildcp: None,
+            }))),
+        }
+    }
+}
+
+impl From<ildcp::Response> for ConnectorInfo {
+    fn from(response: ildcp::Response) -> Self {
+        let snapshot = Snapshot::from(response);
+        ConnectorInfo {
+            initial_address: snapshot.address.clone(),
+            snapshot: Arc::new(RwLock::new(Arc::new(snapshot))),
+        }
+    }
+}
+
+impl From<ildcp::Response> for Snapshot {
+    fn from(response: ildcp::Response) -> Self {
+        Snapshot {
+            address: response.client_address().to_address(),
+            ildcp: Some(response),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_connector_info {
+    use super::*;
+
+    fn make_response(address: &'static [u8], asset_scale: u8) -> ildcp::Response {
+        ildcp::ResponseBuilder {
+            client_address: ilp::Addr::new(address),
+            asset_scale,
+            asset_code: b"XRP",
+        }.build()
+    }
+
+    #[test]
+    fn test_set() {
+        let info = ConnectorInfo::from(make_response(b"test.relay", 9));
+        let shared = info.clone();
+        let before = info.get();
+        shared.set(make_response(b"test.relay2", 6));
+        assert_eq!(info.address(), ilp::Address::new(b"test.relay2"));
+        assert_eq!(info.asset_scale(), Some(6));
+        // Earlier snapshots are unchanged.
+        assert_eq!(before.address, ilp::Address::new(b"test.relay"));
+        assert_eq!(before.ildcp.as_ref().unwrap().asset_scale(), 9);
+    }
+
+    #[test]
+    fn test_rebase() {
+        let info = ConnectorInfo::from(ilp::Address::new(b"test.relay"));
+        let child = ilp::Address::new(b"test.relay.child");
+        let other = ilp::Address::new(b"test.other.child");
+        assert_eq!(info.rebase(&child), child);
+
+        info.set(make_response(b"test.new.relay", 9));
+        assert_eq!(info.rebase(&child), ilp::Address::new(b"test.new.relay.child"));
+        assert_eq!(
+            info.rebase(&ilp::Address::new(b"test.relay")),
+            ilp::Address::new(b"test.new.relay"),
+        );
+        // Addresses that aren't under the initial address are left alone.
+        assert_eq!(info.rebase(&other), other);
+    }
+}
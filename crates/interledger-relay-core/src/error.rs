@@ -0,0 +1,77 @@
+use std::error;
+use std::fmt;
+use std::io;
+
+/// An error that keeps the connector (or one of its services) from starting.
+#[derive(Debug)]
+pub struct SetupError(ErrorKind);
+
+#[derive(Debug)]
+enum ErrorKind {
+    ParseError(ilp::ParseError),
+    Reject(ilp::Reject),
+    Io(io::Error),
+    InvalidConfig(String),
+    /// An error from the transport, e.g. while fetching a JWKS or a service
+    /// account's OAuth token.
+    Other(Box<dyn error::Error + Send + Sync>),
+}
+
+impl error::Error for SetupError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.0 {
+            ErrorKind::ParseError(inner) => Some(inner),
+            ErrorKind::Reject(_) => None,
+            ErrorKind::Io(inner) => Some(inner),
+            ErrorKind::InvalidConfig(_) => None,
+            ErrorKind::Other(inner) => Some(inner.as_ref()),
+        }
+    }
+}
+
+impl fmt::Display for SetupError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match &self.0 {
+            ErrorKind::ParseError(inner) => write!(f, "SetupError({})", inner),
+            ErrorKind::Reject(reject) => write!(f, "SetupError({:?})", reject),
+            ErrorKind::Io(inner) => write!(f, "SetupError({})", inner),
+            ErrorKind::InvalidConfig(message) =>
+                write!(f, "SetupError(invalid config: {})", message),
+            ErrorKind::Other(inner) => write!(f, "SetupError({})", inner),
+        }
+    }
+}
+
+impl SetupError {
+    pub fn invalid_config(message: impl Into<String>) -> Self {
+        SetupError(ErrorKind::InvalidConfig(message.into()))
+    }
+
+    pub fn other(inner: impl Into<Box<dyn error::Error + Send + Sync>>) -> Self {
+        SetupError(ErrorKind::Other(inner.into()))
+    }
+}
+
+impl From<ilp::ParseError> for SetupError {
+    fn from(inner: ilp::ParseError) -> Self {
+        SetupError(ErrorKind::ParseError(inner))
+    }
+}
+
+impl From<ilp::AddressError> for SetupError {
+    fn from(inner: ilp::AddressError) -> Self {
+        SetupError(ErrorKind::ParseError(inner.into()))
+    }
+}
+
+impl From<ilp::Reject> for SetupError {
+    fn from(reject: ilp::Reject) -> Self {
+        SetupError(ErrorKind::Reject(reject))
+    }
+}
+
+impl From<io::Error> for SetupError {
+    fn from(inner: io::Error) -> Self {
+        SetupError(ErrorKind::Io(inner))
+    }
+}
@@ -0,0 +1,113 @@
+//! In-process packet events, for embedders that want to observe every packet
+//! (e.g. a real-time dashboard or a risk engine) without a telemetry sink or a
+//! custom service. See `Config::start_with_events`.
+
+use std::sync::Arc;
+use std::time;
+
+use tokio::sync::broadcast;
+
+/// The outcome of a single Prepare that the connector handled.
+#[derive(Clone, Debug, PartialEq)]
+pub struct PacketEvent {
+    /// The account that sent the Prepare.
+    pub account: Arc<String>,
+    /// Not set when the packet had no route.
+    pub to_account: Option<Arc<String>>,
+    /// The destination, without its connection tag.
+    pub destination: ilp::Address,
+    pub amount: u64,
+    pub result: PacketResult,
+    /// How long the connector took to respond.
+    pub latency: time::Duration,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum PacketResult {
+    Fulfilled,
+    Rejected {
+        code: ilp::ErrorCode,
+        triggered_by: Option<ilp::Address>,
+    },
+}
+
+impl PacketResult {
+    pub fn new(response: &Result<ilp::Fulfill, ilp::Reject>) -> Self {
+        match response {
+            Ok(_) => PacketResult::Fulfilled,
+            Err(reject) => PacketResult::Rejected {
+                code: reject.code(),
+                triggered_by: reject.triggered_by().map(|addr| addr.to_address()),
+            },
+        }
+    }
+}
+
+/// A broadcast channel of `PacketEvent`s. Events are only buffered while there
+/// is at least one subscriber; a subscriber that falls more than `capacity`
+/// events behind skips the oldest ones (see `broadcast::Receiver::recv`).
+#[derive(Clone, Debug)]
+pub struct PacketEvents {
+    sender: broadcast::Sender<PacketEvent>,
+}
+
+impl PacketEvents {
+    pub fn new(capacity: usize) -> Self {
+        let (sender, _receiver) = broadcast::channel(capacity);
+        PacketEvents { sender }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<PacketEvent> {
+        self.sender.subscribe()
+    }
+
+    pub fn subscriber_count(&self) -> usize {
+        self.sender.receiver_count()
+    }
+
+    pub fn publish(&self, event: PacketEvent) {
+        // Sending only fails when there are no subscribers.
+        let _ = self.sender.send(event);
+    }
+}
+
+#[cfg(test)]
+mod test_packet_events {
+    use futures::executor::block_on;
+
+    use crate::testing::{FULFILL, REJECT};
+    use super::*;
+
+    fn make_event(result: PacketResult) -> PacketEvent {
+        PacketEvent {
+            account: Arc::new("alice".to_owned()),
+            to_account: None,
+            destination: ilp::Address::new(b"test.bob"),
+            amount: 123,
+            result,
+            latency: time::Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn test_publish() {
+        let events = PacketEvents::new(2);
+        // Without subscribers, events are dropped.
+        events.publish(make_event(PacketResult::Fulfilled));
+
+        let mut receiver_1 = events.subscribe();
+        let mut receiver_2 = events.subscribe();
+        let fulfilled = make_event(PacketResult::new(&Ok(FULFILL.clone())));
+        let rejected = make_event(PacketResult::new(&Err(REJECT.clone())));
+        events.publish(fulfilled.clone());
+        events.publish(rejected.clone());
+        for receiver in &mut [&mut receiver_1, &mut receiver_2] {
+            assert_eq!(block_on(receiver.recv()).unwrap(), fulfilled);
+            assert_eq!(block_on(receiver.recv()).unwrap(), rejected);
+        }
+        assert_eq!(rejected.result, PacketResult::Rejected {
+            code: REJECT.code(),
+            triggered_by: REJECT.triggered_by().map(|addr| addr.to_address()),
+        });
+    }
+}
@@ -0,0 +1,80 @@
+//! The transport-agnostic parts of the relay: the `Service` trait, the packet
+//! requests that services handle, the services themselves (including the
+//! router), and the infrastructure that they share (the connector's address,
+//! metrics, toggles, and packet events).
+//!
+//! This crate doesn't depend on hyper. The services reach the network through
+//! the traits in `transport`, which `interledger-relay` implements over HTTP
+//! (and BTP); the tests use the mocks in `testing` instead of sockets.
+
+// Declared first, so that `throttled_warn!` is available to the other modules.
+#[macro_use]
+#[doc(hidden)]
+pub mod log_throttle;
+mod allocator;
+mod amount;
+pub mod connector_info;
+mod error;
+pub mod events;
+pub mod metrics;
+mod packets;
+pub mod reject_reasons;
+pub mod serde;
+pub mod services;
+#[cfg(any(test, feature = "testing"))]
+pub mod testing;
+pub mod toggles;
+pub mod transport;
+
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
+use std::time;
+
+use futures::prelude::*;
+
+pub use self::allocator::{AllocatorStats, CountingAllocator};
+pub use self::amount::AssetAmount;
+pub use self::error::SetupError;
+pub use self::packets::{Request, RequestId, ResponseWithRoute, RequestWithDeadline, RequestWithFrom, RequestWithIdempotencyKey, RequestWithPeerName};
+pub use self::reject_reasons::{RejectReason, REJECT_REASONS};
+
+pub trait Service<Req: Request>: Clone {
+    type Future: 'static + Send
+        + Future<Output = Result<ilp::Fulfill, ilp::Reject>>;
+
+    fn setup(&mut self) {}
+    fn call(self, request: Req) -> Self::Future;
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Relation {
+    Child,
+    Peer,
+    Parent,
+}
+
+/// Allow closures as services when testing.
+#[cfg(any(test, feature = "testing"))]
+impl<F, Req, Res> Service<Req> for F
+where
+    F: Clone + Fn(Req) -> Res,
+    Req: Request,
+    Res: 'static + Send + Future<Output = Result<ilp::Fulfill, ilp::Reject>>,
+{
+    type Future = Res;
+
+    fn call(self, request: Req) -> Self::Future {
+        (self)(request)
+    }
+}
+
+/// A (non-cryptographic) random number in `[0.0, 1.0)`, used for jitter and
+/// sampling.
+pub fn random_fraction() -> f64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u32(time::SystemTime::now()
+        .duration_since(time::UNIX_EPOCH)
+        .map(|duration| duration.subsec_nanos())
+        .unwrap_or(0));
+    (hasher.finish() >> 11) as f64 / (1_u64 << 53) as f64
+}
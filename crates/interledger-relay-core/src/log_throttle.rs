@@ -0,0 +1,124 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time;
+
+/// Repeats of a throttled warning are logged at most this often.
+const THROTTLE_INTERVAL: time::Duration = time::Duration::from_secs(10);
+/// Stale keys are pruned once a kind has more keys than this.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+lazy_static::lazy_static! {
+    pub static ref LOG_THROTTLE: LogThrottle =
+        LogThrottle::new(THROTTLE_INTERVAL);
+}
+
+/// Log a warning, unless the same warning (i.e. format string) with the same
+/// `key` (e.g. a peer's account, or a next hop) was already logged within the
+/// last 10 seconds. Suppressed repeats are counted, and the count is included
+/// as `suppressed` in the next warning that is logged.
+///
+/// This keeps sustained failures (e.g. a next hop or telemetry sink that is
+/// down) from logging a warning per packet.
+#[macro_export]
+macro_rules! throttled_warn {
+    ($key:expr, $format:literal $(, $arg:expr)* $(,)?) => {
+        if let Some(suppressed) = $crate::log_throttle::LOG_THROTTLE
+            .check($format, $key, std::time::Instant::now())
+        {
+            log::warn!(concat!($format, " suppressed={}"), $($arg,)* suppressed);
+        }
+    };
+}
+
+#[derive(Debug)]
+pub struct LogThrottle {
+    interval: time::Duration,
+    kinds: Mutex<HashMap<&'static str, HashMap<String, Entry>>>,
+}
+
+#[derive(Debug)]
+struct Entry {
+    logged_at: time::Instant,
+    suppressed: u64,
+}
+
+impl LogThrottle {
+    fn new(interval: time::Duration) -> Self {
+        LogThrottle {
+            interval,
+            kinds: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the number of suppressed repeats when the warning should be
+    /// logged, or `None` when it should be suppressed.
+    pub fn check(&self, kind: &'static str, key: &str, now: time::Instant)
+        -> Option<u64>
+    {
+        let mut kinds = self.kinds.lock().unwrap();
+        let entries = kinds.entry(kind).or_default();
+        if let Some(entry) = entries.get_mut(key) {
+            if now.saturating_duration_since(entry.logged_at) < self.interval {
+                entry.suppressed += 1;
+                return None;
+            }
+            let suppressed = entry.suppressed;
+            entry.logged_at = now;
+            entry.suppressed = 0;
+            return Some(suppressed);
+        }
+
+        if entries.len() >= PRUNE_THRESHOLD {
+            let interval = self.interval;
+            entries.retain(|_key, entry| {
+                now.saturating_duration_since(entry.logged_at) < interval
+            });
+        }
+        entries.insert(key.to_owned(), Entry {
+            logged_at: now,
+            suppressed: 0,
+        });
+        Some(0)
+    }
+}
+
+#[cfg(test)]
+mod test_log_throttle {
+    use super::*;
+
+    const INTERVAL: time::Duration = time::Duration::from_secs(10);
+
+    #[test]
+    fn test_check() {
+        let throttle = LogThrottle::new(INTERVAL);
+        let start = time::Instant::now();
+        assert_eq!(throttle.check("error", "alice", start), Some(0));
+        assert_eq!(throttle.check("error", "alice", start), None);
+        assert_eq!(
+            throttle.check("error", "alice", start + INTERVAL / 2),
+            None,
+        );
+        // Other keys and kinds are throttled separately.
+        assert_eq!(throttle.check("error", "bob", start), Some(0));
+        assert_eq!(throttle.check("other error", "alice", start), Some(0));
+
+        assert_eq!(throttle.check("error", "alice", start + INTERVAL), Some(2));
+        assert_eq!(throttle.check("error", "alice", start + INTERVAL), None);
+        assert_eq!(
+            throttle.check("error", "alice", start + INTERVAL * 3),
+            Some(1),
+        );
+    }
+
+    #[test]
+    fn test_prune() {
+        let throttle = LogThrottle::new(INTERVAL);
+        let start = time::Instant::now();
+        for i in 0..PRUNE_THRESHOLD {
+            throttle.check("error", &i.to_string(), start);
+        }
+        throttle.check("error", "new", start + INTERVAL);
+        let kinds = throttle.kinds.lock().unwrap();
+        assert_eq!(kinds["error"].len(), 1);
+    }
+}
@@ -0,0 +1,247 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, RwLock};
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use crate::AllocatorStats;
+
+type Labels = Vec<(&'static str, String)>;
+
+static RECEIVED_BYTES: &str = "ilp_relay_received_bytes_total";
+static SENT_BYTES: &str = "ilp_relay_sent_bytes_total";
+static ALLOCATED_BYTES: &str = "ilp_relay_allocated_bytes";
+static PEAK_ALLOCATED_BYTES: &str = "ilp_relay_peak_allocated_bytes";
+static ALLOCATIONS: &str = "ilp_relay_allocations_total";
+static DEALLOCATIONS: &str = "ilp_relay_deallocations_total";
+
+/// A minimal registry of counters, rendered in the Prometheus text format.
+///
+/// The constant labels (e.g. the instance ID) are attached to every metric.
+/// When the `CountingAllocator` is installed, its stats are rendered too.
+#[derive(Debug, Default)]
+pub struct Metrics {
+    labels: Vec<(String, String)>,
+    counters: RwLock<BTreeMap<MetricKey, AtomicU64>>,
+}
+
+#[derive(Clone, Debug, Eq, Ord, PartialEq, PartialOrd)]
+struct MetricKey {
+    name: &'static str,
+    labels: Labels,
+}
+
+impl Metrics {
+    pub fn new<I>(labels: I) -> Self
+    where
+        I: IntoIterator<Item = (String, String)>,
+    {
+        Metrics {
+            labels: labels.into_iter().collect(),
+            counters: RwLock::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add `value` to the counter with the given name and labels.
+    pub fn increment(&self, name: &'static str, labels: Labels, value: u64) {
+        let key = MetricKey { name, labels };
+        {
+            let counters = self.counters.read().unwrap();
+            if let Some(counter) = counters.get(&key) {
+                counter.fetch_add(value, Ordering::Relaxed);
+                return;
+            }
+        }
+        let mut counters = self.counters.write().unwrap();
+        counters
+            .entry(key)
+            .or_insert_with(|| AtomicU64::new(0))
+            .fetch_add(value, Ordering::Relaxed);
+    }
+
+    /// Get the current value of a counter (`0` if it doesn't exist).
+    pub fn get(&self, name: &'static str, labels: Labels) -> u64 {
+        self.counters
+            .read()
+            .unwrap()
+            .get(&MetricKey { name, labels })
+            .map(|counter| counter.load(Ordering::Relaxed))
+            .unwrap_or(0)
+    }
+
+    /// Render all metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let mut output = String::new();
+        {
+            let counters = self.counters.read().unwrap();
+            let mut last_name = None;
+            for (key, counter) in counters.iter() {
+                if last_name != Some(key.name) {
+                    writeln!(output, "# TYPE {} counter", key.name).unwrap();
+                    last_name = Some(key.name);
+                }
+                let labels = key.labels
+                    .iter()
+                    .map(|(name, value)| (*name, value.as_str()));
+                self.write_sample(
+                    &mut output,
+                    key.name,
+                    labels,
+                    counter.load(Ordering::Relaxed),
+                );
+            }
+        }
+        if let Some(stats) = AllocatorStats::get() {
+            self.render_allocator_stats(&mut output, &stats);
+        }
+        output
+    }
+
+    fn render_allocator_stats(&self, output: &mut String, stats: &AllocatorStats) {
+        let samples = [
+            (ALLOCATED_BYTES, "gauge", stats.allocated_bytes as u64),
+            (PEAK_ALLOCATED_BYTES, "gauge", stats.peak_allocated_bytes as u64),
+            (ALLOCATIONS, "counter", stats.allocations),
+            (DEALLOCATIONS, "counter", stats.deallocations),
+        ];
+        for &(name, metric_type, value) in &samples {
+            writeln!(output, "# TYPE {} {}", name, metric_type).unwrap();
+            self.write_sample(output, name, std::iter::empty(), value);
+        }
+    }
+
+    /// Write a single sample, with the constant labels followed by `labels`.
+    fn write_sample<'a, I>(
+        &'a self,
+        output: &mut String,
+        name: &str,
+        labels: I,
+        value: u64,
+    )
+    where
+        I: Iterator<Item = (&'a str, &'a str)>,
+    {
+        output.push_str(name);
+        let labels = self.labels
+            .iter()
+            .map(|(name, value)| (name.as_str(), value.as_str()))
+            .chain(labels);
+        let mut has_labels = false;
+        for (i, (name, value)) in labels.enumerate() {
+            output.push(if i == 0 { '{' } else { ',' });
+            write!(output, "{}=\"{}\"", name, escape_label(value)).unwrap();
+            has_labels = true;
+        }
+        if has_labels {
+            output.push('}');
+        }
+        writeln!(output, " {}", value).unwrap();
+    }
+}
+
+/// Count the bytes received from and sent to a single peer (by account), for
+/// bandwidth-based peering agreements.
+#[derive(Clone, Debug)]
+pub struct PeerTraffic {
+    metrics: Arc<Metrics>,
+    account: Arc<String>,
+}
+
+impl PeerTraffic {
+    pub fn new(metrics: Arc<Metrics>, account: Arc<String>) -> Self {
+        PeerTraffic { metrics, account }
+    }
+
+    pub fn received(&self, bytes: usize) {
+        self.metrics.increment(RECEIVED_BYTES, self.labels(), bytes as u64);
+    }
+
+    pub fn sent(&self, bytes: usize) {
+        self.metrics.increment(SENT_BYTES, self.labels(), bytes as u64);
+    }
+
+    fn labels(&self) -> Labels {
+        vec![("account", self.account.as_ref().clone())]
+    }
+}
+
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+#[cfg(test)]
+mod test_metrics {
+    use super::*;
+
+    #[test]
+    fn test_increment() {
+        let metrics = Metrics::default();
+        let labels = || vec![("result", "fulfill".to_owned())];
+        metrics.increment("packets", labels(), 1);
+        metrics.increment("packets", labels(), 2);
+        assert_eq!(metrics.get("packets", labels()), 3);
+        assert_eq!(metrics.get("packets", vec![]), 0);
+    }
+
+    #[test]
+    fn test_render() {
+        let metrics = Metrics::new(vec![
+            ("instance".to_owned(), "relay-1".to_owned()),
+        ]);
+        metrics.increment("b_total", vec![("account", "a\"b".to_owned())], 5);
+        metrics.increment("a_total", vec![], 1);
+        assert_eq!(
+            metrics.render(),
+            "# TYPE a_total counter\n\
+             a_total{instance=\"relay-1\"} 1\n\
+             # TYPE b_total counter\n\
+             b_total{instance=\"relay-1\",account=\"a\\\"b\"} 5\n",
+        );
+
+        let metrics = Metrics::default();
+        metrics.increment("a_total", vec![], 1);
+        assert_eq!(metrics.render(), "# TYPE a_total counter\na_total 1\n");
+    }
+
+    #[test]
+    fn test_render_allocator_stats() {
+        let metrics = Metrics::new(vec![
+            ("instance".to_owned(), "relay-1".to_owned()),
+        ]);
+        let mut output = String::new();
+        metrics.render_allocator_stats(&mut output, &AllocatorStats {
+            allocated_bytes: 1000,
+            peak_allocated_bytes: 2000,
+            allocations: 30,
+            deallocations: 20,
+        });
+        assert_eq!(
+            output,
+            "# TYPE ilp_relay_allocated_bytes gauge\n\
+             ilp_relay_allocated_bytes{instance=\"relay-1\"} 1000\n\
+             # TYPE ilp_relay_peak_allocated_bytes gauge\n\
+             ilp_relay_peak_allocated_bytes{instance=\"relay-1\"} 2000\n\
+             # TYPE ilp_relay_allocations_total counter\n\
+             ilp_relay_allocations_total{instance=\"relay-1\"} 30\n\
+             # TYPE ilp_relay_deallocations_total counter\n\
+             ilp_relay_deallocations_total{instance=\"relay-1\"} 20\n",
+        );
+    }
+
+    #[test]
+    fn test_peer_traffic() {
+        let metrics = Arc::new(Metrics::default());
+        let traffic = PeerTraffic::new(
+            Arc::clone(&metrics),
+            Arc::new("alice".to_owned()),
+        );
+        traffic.received(100);
+        traffic.received(20);
+        traffic.sent(3);
+        let labels = || vec![("account", "alice".to_owned())];
+        assert_eq!(metrics.get(RECEIVED_BYTES, labels()), 120);
+        assert_eq!(metrics.get(SENT_BYTES, labels()), 3);
+    }
+}
@@ -0,0 +1,112 @@
+use std::borrow::{Borrow, BorrowMut};
+use std::fmt;
+use std::str;
+use std::sync::Arc;
+use std::time;
+
+use crate::Relation;
+use crate::services::RouteIndex;
+
+pub trait Request: Into<ilp::Prepare> + Borrow<ilp::Prepare> {
+    /// The ID used to correlate this packet's logs across services and hops.
+    fn request_id(&self) -> Option<&RequestId> {
+        None
+    }
+}
+impl Request for ilp::Prepare {}
+
+pub trait RequestWithPeerName: Request {
+    /// The value of the `ILP-Peer-Name` header.
+    fn peer_name(&self) -> Option<&[u8]>;
+}
+
+pub trait RequestWithIdempotencyKey: Request {
+    /// The value of the `Idempotency-Key` header, which identifies retries of
+    /// the same Prepare.
+    fn idempotency_key(&self) -> Option<&[u8]>;
+}
+
+pub trait RequestWithDeadline: Request + BorrowMut<ilp::Prepare> {
+    /// When the upstream connector will give up on the Prepare, if it is
+    /// earlier than the Prepare's expiry. Only trusted peers may set it.
+    fn deadline(&self) -> Option<time::SystemTime>;
+}
+
+impl RequestWithDeadline for ilp::Prepare {
+    fn deadline(&self) -> Option<time::SystemTime> {
+        None
+    }
+}
+
+pub trait RequestWithFrom: Request {
+    fn from_account(&self) -> &Arc<String>;
+    fn from_relation(&self) -> Relation;
+    fn from_address(&self) -> ilp::Addr<'_>;
+}
+
+/// The value of the `X-Request-Id` header, which is passed on to the next hop.
+#[derive(Clone, Debug, PartialEq)]
+pub struct RequestId(Arc<str>);
+
+impl RequestId {
+    const MAX_LENGTH: usize = 128;
+
+    pub fn generate() -> Self {
+        let id = uuid::Uuid::new_v4().to_hyphenated().to_string();
+        RequestId(Arc::from(id))
+    }
+
+    /// Incoming IDs are only honored when they are short and printable, since
+    /// they end up in the logs.
+    pub fn from_header(value: &[u8]) -> Option<Self> {
+        let is_valid = !value.is_empty()
+            && value.len() <= Self::MAX_LENGTH
+            && value.iter().all(|&byte| byte.is_ascii_graphic());
+        if is_valid {
+            str::from_utf8(value).ok().map(|id| RequestId(Arc::from(id)))
+        } else {
+            None
+        }
+    }
+
+    /// The request's ID, or a new one for requests that don't have one (e.g. a
+    /// bare Prepare).
+    pub fn of<Req: Request>(request: &Req) -> Self {
+        request.request_id().cloned().unwrap_or_else(RequestId::generate)
+    }
+
+    /// `from_header` and `generate` only accept visible ASCII, so this is
+    /// always a valid header value.
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for RequestId {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str(&self.0)
+    }
+}
+
+#[derive(Debug)]
+pub struct ResponseWithRoute {
+    pub packet: ResponsePacket,
+    /// The route which forwarded (outgoing) this response's corresponding
+    /// ILP-Prepare.
+    pub route: Option<RouteIndex>,
+    /// The amount of the Prepare that was sent to the route's next hop (after
+    /// any exchange), if it was sent.
+    pub amount: Option<u64>,
+}
+
+type ResponsePacket = Result<ilp::Fulfill, ilp::Reject>;
+
+impl From<ResponsePacket> for ResponseWithRoute {
+    fn from(packet: ResponsePacket) -> Self {
+        ResponseWithRoute {
+            packet,
+            route: None,
+            amount: None,
+        }
+    }
+}
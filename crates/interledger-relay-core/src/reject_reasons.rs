@@ -0,0 +1,449 @@
+use serde::{Serialize, Serializer};
+
+/// Why the relay rejected a packet itself (rather than passing on a peer's
+/// Reject).
+///
+/// The reason's `id` is the data of the Reject, so that Rejects with the same
+/// code (e.g. `F02` for "no route" and for "echo loop") can be told apart
+/// programmatically. The exceptions are `AMOUNT_TOO_LARGE`,
+/// `INSUFFICIENT_SOURCE_AMOUNT`, `SIMULATION`, and `STREAM_REJECTED`, whose
+/// data is already defined (see their descriptions), and Rejects with
+/// diagnostics (see `to_reject_with_diagnostics`).
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct RejectReason {
+    pub id: &'static str,
+    #[serde(serialize_with = "serialize_error_code")]
+    pub code: ilp::ErrorCode,
+    pub message: &'static str,
+    /// Operator documentation: what causes the Reject, and what to check.
+    pub description: &'static str,
+}
+
+impl RejectReason {
+    pub fn to_reject(self, triggered_by: ilp::Addr) -> ilp::Reject {
+        self.to_reject_with_data(triggered_by, self.id.as_bytes())
+    }
+
+    pub fn to_reject_with_data(
+        self,
+        triggered_by: ilp::Addr,
+        data: &[u8],
+    ) -> ilp::Reject {
+        ilp::RejectBuilder {
+            code: self.code,
+            message: self.message.as_bytes(),
+            triggered_by: Some(triggered_by),
+            data,
+        }.build()
+    }
+
+    /// The data is `ilp::RejectDiagnostics`: the `reason` (the ID), then the
+    /// `entries`.
+    pub fn to_reject_with_diagnostics(
+        self,
+        triggered_by: ilp::Addr,
+        entries: &[(&str, &str)],
+    ) -> ilp::Reject {
+        let mut diagnostics = ilp::RejectDiagnostics::new();
+        diagnostics.push("reason", self.id);
+        for (key, value) in entries {
+            diagnostics.push(key, value);
+        }
+        self.to_reject_with_data(triggered_by, &diagnostics.to_bytes())
+    }
+}
+
+// Incoming packets:
+
+pub const UNKNOWN_SOURCE: RejectReason = RejectReason {
+    id: "unknown_source",
+    code: ilp::ErrorCode::F00_BAD_REQUEST,
+    message: "could not determine packet source",
+    description: "The request was not authenticated as any peer. This shouldn't happen, since the auth middleware runs first.",
+};
+
+pub const CLIENT_CERTIFICATE_MISMATCH: RejectReason = RejectReason {
+    id: "client_certificate_mismatch",
+    code: ilp::ErrorCode::F00_BAD_REQUEST,
+    message: "client certificate mismatch",
+    description: "The peer's TLS client certificate doesn't match its configured `certificate`.",
+};
+
+pub const INSUFFICIENT_TIMEOUT: RejectReason = RejectReason {
+    id: "insufficient_timeout",
+    code: ilp::ErrorCode::R02_INSUFFICIENT_TIMEOUT,
+    message: "insufficient timeout",
+    description: "The Prepare had already expired when it was received. Check for clock skew or a slow upstream.",
+};
+
+pub const TIMED_OUT: RejectReason = RejectReason {
+    id: "timed_out",
+    code: ilp::ErrorCode::R00_TRANSFER_TIMED_OUT,
+    message: "request timed out",
+    description: "The Prepare expired (or the connector's 60 second cap elapsed) before the next hop responded.",
+};
+
+pub const RATE_LIMITED: RejectReason = RejectReason {
+    id: "rate_limited",
+    code: ilp::ErrorCode::T05_RATE_LIMITED,
+    message: "rate limit exceeded",
+    description: "The peer exceeded its configured `rate_limit`.",
+};
+
+pub const THROUGHPUT_LIMITED: RejectReason = RejectReason {
+    id: "throughput_limited",
+    code: ilp::ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+    message: "throughput limit exceeded",
+    description: "The total amount of the peer's recent Prepares exceeded its configured `throughput_limit`.",
+};
+
+pub const GREYLISTED: RejectReason = RejectReason {
+    id: "greylisted",
+    code: ilp::ErrorCode::T05_RATE_LIMITED,
+    message: "too many new destinations",
+    description: "The peer sent to more novel destination prefixes than the `greylist` allows, and its `action` is `throttle`.",
+};
+
+pub const CONNECTOR_BUSY: RejectReason = RejectReason {
+    id: "connector_busy",
+    code: ilp::ErrorCode::T03_CONNECTOR_BUSY,
+    message: "too many prepares in flight",
+    description: "The peer's `max_in_flight` limit, or the connector's global `max_in_flight` limit, was reached.",
+};
+
+// Local services:
+
+pub const INVALID_ECHO_REQUEST: RejectReason = RejectReason {
+    id: "invalid_echo_request",
+    code: ilp::ErrorCode::F01_INVALID_PACKET,
+    message: "invalid echo request",
+    description: "A Prepare addressed to the connector had data that isn't a valid echo request.",
+};
+
+pub const ECHO_LOOP: RejectReason = RejectReason {
+    id: "echo_loop",
+    code: ilp::ErrorCode::F02_UNREACHABLE,
+    message: "echo loop detected",
+    description: "An echo request with the same condition was already answered recently; the echo response is routed back to the connector.",
+};
+
+pub const INVALID_STREAM_PACKET: RejectReason = RejectReason {
+    id: "invalid_stream_packet",
+    code: ilp::ErrorCode::F06_UNEXPECTED_PAYMENT,
+    message: "unable to decrypt STREAM packet",
+    description: "A Prepare addressed to the local SPSP receiver doesn't carry a STREAM packet that was encrypted with the connection's shared secret.",
+};
+
+pub const STREAM_REJECTED: RejectReason = RejectReason {
+    id: "stream_rejected",
+    code: ilp::ErrorCode::F99_APPLICATION_ERROR,
+    message: "STREAM packet rejected",
+    description: "The local SPSP receiver can't fulfill the Prepare (its condition doesn't match, or its amount is less than the sender's minimum). The data is the encrypted STREAM response, not the reason ID.",
+};
+
+pub const ILDCP_NON_CHILD: RejectReason = RejectReason {
+    id: "ildcp_non_child",
+    code: ilp::ErrorCode::F00_BAD_REQUEST,
+    message: "ILDCP request from non-child peer",
+    description: "Only `Child` relations can request an ILDCP config.",
+};
+
+pub const ILDCP_MISSING_PEER_NAME: RejectReason = RejectReason {
+    id: "ildcp_missing_peer_name",
+    code: ilp::ErrorCode::F00_BAD_REQUEST,
+    message: "Missing ILP-Peer-Name header",
+    description: "ILDCP requests must include the child's name in the `ILP-Peer-Name` header.",
+};
+
+pub const ILDCP_INVALID_CLIENT_ADDRESS: RejectReason = RejectReason {
+    id: "ildcp_invalid_client_address",
+    code: ilp::ErrorCode::F00_BAD_REQUEST,
+    message: "Invalid generated client address",
+    description: "The child's name (from the `ILP-Peer-Name` header) isn't a valid address segment, or the resulting address is too long.",
+};
+
+pub const SETTLEMENT_NOT_CONFIGURED: RejectReason = RejectReason {
+    id: "settlement_not_configured",
+    code: ilp::ErrorCode::F00_BAD_REQUEST,
+    message: "settlement is not configured for this account",
+    description: "A `peer.settle` message was sent by an account that isn't one of the `settlement` accounts.",
+};
+
+pub const SETTLEMENT_ENGINE_ERROR: RejectReason = RejectReason {
+    id: "settlement_engine_error",
+    code: ilp::ErrorCode::T00_INTERNAL_ERROR,
+    message: "settlement engine error",
+    description: "The settlement engine couldn't be reached, or didn't accept a `peer.settle` message. The error is logged.",
+};
+
+pub const TELEMETRY_UNAVAILABLE: RejectReason = RejectReason {
+    id: "telemetry_unavailable",
+    code: ilp::ErrorCode::T03_CONNECTOR_BUSY,
+    message: "backend is unavailable",
+    description: "The telemetry sink is down (or its queues are full), and `on_unavailable` is `reject`.",
+};
+
+pub const MIDDLEWARE_ERROR: RejectReason = RejectReason {
+    id: "middleware_error",
+    code: ilp::ErrorCode::T00_INTERNAL_ERROR,
+    message: "middleware error",
+    description: "A `tower` middleware in an embedder's pipeline (see `FromTower`) failed with an error that isn't a Reject, e.g. a timeout. The error is logged.",
+};
+
+pub const WRONG_CONDITION: RejectReason = RejectReason {
+    id: "wrong_condition",
+    code: ilp::ErrorCode::F05_WRONG_CONDITION,
+    message: "fulfillment does not match condition",
+    description: "The next hop returned a Fulfill whose fulfillment doesn't hash to the Prepare's condition.",
+};
+
+// Routing:
+
+pub const NO_ROUTE: RejectReason = RejectReason {
+    id: "no_route",
+    code: ilp::ErrorCode::F02_UNREACHABLE,
+    message: "no route exists",
+    description: "No route's `target_prefix` matches the destination.",
+};
+
+pub const NO_HEALTHY_ROUTE: RejectReason = RejectReason {
+    id: "no_healthy_route",
+    code: ilp::ErrorCode::T01_PEER_UNREACHABLE,
+    message: "no healthy route found",
+    description: "Routes match the destination, but all of them are marked unavailable (e.g. by failover).",
+};
+
+pub const ROUTE_MAINTENANCE: RejectReason = RejectReason {
+    id: "route_maintenance",
+    code: ilp::ErrorCode::T01_PEER_UNREACHABLE,
+    message: "route is under maintenance",
+    description: "The route is in `maintenance` (from the config, or the admin API). Its `code` and `message` replace these defaults.",
+};
+
+pub const INVALID_ADDRESS_SEGMENT: RejectReason = RejectReason {
+    id: "invalid_address_segment",
+    code: ilp::ErrorCode::F02_UNREACHABLE,
+    message: "invalid address segment",
+    description: "The destination segment used for a dynamic route's next hop isn't valid in a URI.",
+};
+
+pub const INVALID_REWRITTEN_DESTINATION: RejectReason = RejectReason {
+    id: "invalid_rewritten_destination",
+    code: ilp::ErrorCode::F02_UNREACHABLE,
+    message: "invalid rewritten destination",
+    description: "Replacing the destination's `target_prefix` with the route's `rewrite_prefix` didn't produce a valid ILP address.",
+};
+
+pub const EXCHANGE_FAILED: RejectReason = RejectReason {
+    id: "exchange_failed",
+    code: ilp::ErrorCode::F03_INVALID_AMOUNT,
+    message: "amount can't be exchanged",
+    description: "The route's `exchange` can't convert the amount: it overflows in the next hop's asset, or the connector's asset scale isn't known.",
+};
+
+pub const EXCHANGE_RATE_UNAVAILABLE: RejectReason = RejectReason {
+    id: "exchange_rate_unavailable",
+    code: ilp::ErrorCode::T00_INTERNAL_ERROR,
+    message: "exchange rate unavailable",
+    description: "The route's `exchange` needs a rate for its `asset_code`, but the `exchange_rates` provider has none, or its rates are older than the `max_age`.",
+};
+
+pub const INSUFFICIENT_SOURCE_AMOUNT: RejectReason = RejectReason {
+    id: "insufficient_source_amount",
+    code: ilp::ErrorCode::R01_INSUFFICIENT_SOURCE_AMOUNT,
+    message: "amount too small to exchange",
+    description: "The route's `exchange` rounds the amount down to zero. The data is the received and minimum amounts (like F08's), not the reason ID.",
+};
+
+pub const AMOUNT_TOO_LARGE: RejectReason = RejectReason {
+    id: "amount_too_large",
+    code: ilp::ErrorCode::F08_AMOUNT_TOO_LARGE,
+    message: "packet amount too large",
+    description: "The amount exceeds the route's `max_packet_amount`. The data is the standard F08 amount details, not the reason ID.",
+};
+
+pub const SIMULATION: RejectReason = RejectReason {
+    id: "simulation",
+    code: ilp::ErrorCode::F02_UNREACHABLE,
+    message: "simulation mode: packet was not forwarded",
+    description: "Simulation mode is enabled. The data is the account of the route that would have been used, not the reason ID.",
+};
+
+pub const NEXT_HOP_BUSY: RejectReason = RejectReason {
+    id: "next_hop_busy",
+    code: ilp::ErrorCode::T03_CONNECTOR_BUSY,
+    message: "next hop is busy",
+    description: "The route's `concurrency` limit was reached, and the queued Prepare would have expired (within `min_expiry`) before a slot freed up.",
+};
+
+// Outgoing requests:
+
+pub const PEER_TIMED_OUT: RejectReason = RejectReason {
+    id: "peer_timed_out",
+    code: ilp::ErrorCode::R00_TRANSFER_TIMED_OUT,
+    message: "peer response timed out",
+    description: "The next hop didn't respond within the route's `response_timeout`.",
+};
+
+pub const PEER_CONNECTION_ERROR: RejectReason = RejectReason {
+    id: "peer_connection_error",
+    code: ilp::ErrorCode::T01_PEER_UNREACHABLE,
+    message: "peer connection error",
+    description: "The connection to the next hop failed (including retries).",
+};
+
+pub const PEER_BTP_ERROR: RejectReason = RejectReason {
+    id: "peer_btp_error",
+    code: ilp::ErrorCode::T01_PEER_UNREACHABLE,
+    message: "peer BTP error",
+    description: "The BTP connection to the next hop failed.",
+};
+
+pub const PEER_BAD_REQUEST: RejectReason = RejectReason {
+    id: "peer_bad_request",
+    code: ilp::ErrorCode::F00_BAD_REQUEST,
+    message: "bad request to peer",
+    description: "The next hop responded with a 4xx status. Check the route's `auth`.",
+};
+
+pub const PEER_INTERNAL_ERROR: RejectReason = RejectReason {
+    id: "peer_internal_error",
+    code: ilp::ErrorCode::T01_PEER_UNREACHABLE,
+    message: "peer internal error",
+    description: "The next hop responded with a 5xx status.",
+};
+
+pub const PEER_UNEXPECTED_STATUS: RejectReason = RejectReason {
+    id: "peer_unexpected_status",
+    code: ilp::ErrorCode::T00_INTERNAL_ERROR,
+    message: "unexpected response code from peer",
+    description: "The next hop responded with a status other than 200, 4xx, or 5xx.",
+};
+
+pub const INVALID_PEER_RESPONSE: RejectReason = RejectReason {
+    id: "invalid_peer_response",
+    code: ilp::ErrorCode::T00_INTERNAL_ERROR,
+    message: "invalid response body from peer",
+    description: "The next hop's response body isn't a valid Fulfill or Reject.",
+};
+
+pub const INVALID_HEADER_VALUE: RejectReason = RejectReason {
+    id: "invalid_header_value",
+    code: ilp::ErrorCode::F00_BAD_REQUEST,
+    message: "invalid header value",
+    description: "A header of the outgoing request (e.g. the route's `auth`) isn't a valid HTTP header value.",
+};
+
+pub const PEER_PACKET_TOO_LARGE: RejectReason = RejectReason {
+    id: "peer_packet_too_large",
+    code: ilp::ErrorCode::F00_BAD_REQUEST,
+    message: "packet too large for peer",
+    description: "The Prepare is larger than the `max_packet_size` that the next hop advertises (see peer discovery).",
+};
+
+/// Every reason, served by the admin API at `GET /admin/reject_reasons`.
+pub static REJECT_REASONS: &[RejectReason] = &[
+    UNKNOWN_SOURCE,
+    CLIENT_CERTIFICATE_MISMATCH,
+    INSUFFICIENT_TIMEOUT,
+    TIMED_OUT,
+    RATE_LIMITED,
+    THROUGHPUT_LIMITED,
+    GREYLISTED,
+    CONNECTOR_BUSY,
+    INVALID_ECHO_REQUEST,
+    ECHO_LOOP,
+    INVALID_STREAM_PACKET,
+    STREAM_REJECTED,
+    ILDCP_NON_CHILD,
+    ILDCP_MISSING_PEER_NAME,
+    ILDCP_INVALID_CLIENT_ADDRESS,
+    SETTLEMENT_NOT_CONFIGURED,
+    SETTLEMENT_ENGINE_ERROR,
+    TELEMETRY_UNAVAILABLE,
+    MIDDLEWARE_ERROR,
+    WRONG_CONDITION,
+    NO_ROUTE,
+    NO_HEALTHY_ROUTE,
+    ROUTE_MAINTENANCE,
+    INVALID_ADDRESS_SEGMENT,
+    INVALID_REWRITTEN_DESTINATION,
+    EXCHANGE_FAILED,
+    EXCHANGE_RATE_UNAVAILABLE,
+    INSUFFICIENT_SOURCE_AMOUNT,
+    AMOUNT_TOO_LARGE,
+    SIMULATION,
+    NEXT_HOP_BUSY,
+    PEER_TIMED_OUT,
+    PEER_CONNECTION_ERROR,
+    PEER_BTP_ERROR,
+    PEER_BAD_REQUEST,
+    PEER_INTERNAL_ERROR,
+    PEER_UNEXPECTED_STATUS,
+    INVALID_PEER_RESPONSE,
+    INVALID_HEADER_VALUE,
+    PEER_PACKET_TOO_LARGE,
+];
+
+fn serialize_error_code<S>(code: &ilp::ErrorCode, serializer: S)
+    -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(code)
+}
+
+#[cfg(test)]
+mod test_reject_reasons {
+    use std::collections::HashSet;
+
+    use super::*;
+
+    #[test]
+    fn test_unique_ids() {
+        let ids = REJECT_REASONS
+            .iter()
+            .map(|reason| reason.id)
+            .collect::<HashSet<_>>();
+        assert_eq!(ids.len(), REJECT_REASONS.len());
+    }
+
+    #[test]
+    fn test_to_reject() {
+        let address = ilp::Addr::new(b"example.relay");
+        let reject = NO_ROUTE.to_reject(address);
+        assert_eq!(reject.code(), ilp::ErrorCode::F02_UNREACHABLE);
+        assert_eq!(reject.message(), b"no route exists");
+        assert_eq!(reject.triggered_by(), Some(address));
+        assert_eq!(reject.data(), b"no_route");
+    }
+
+    #[test]
+    fn test_to_reject_with_diagnostics() {
+        let address = ilp::Addr::new(b"example.relay");
+        let reject = NO_ROUTE.to_reject_with_diagnostics(
+            address,
+            &[("destination", "example.bob")],
+        );
+        assert_eq!(reject.code(), ilp::ErrorCode::F02_UNREACHABLE);
+        let diagnostics =
+            ilp::RejectDiagnostics::from_bytes(reject.data()).unwrap();
+        assert_eq!(
+            diagnostics.iter().collect::<Vec<_>>(),
+            vec![("reason", "no_route"), ("destination", "example.bob")],
+        );
+    }
+
+    #[test]
+    fn test_serialize() {
+        assert_eq!(
+            serde_json::to_value(RATE_LIMITED).unwrap(),
+            serde_json::json!({
+                "id": "rate_limited",
+                "code": "T05",
+                "message": "rate limit exceeded",
+                "description": "The peer exceeded its configured `rate_limit`.",
+            }),
+        );
+    }
+}
@@ -0,0 +1,136 @@
+//! Deserializers for the config fields that are shared by the services.
+
+use std::time;
+
+use serde::de::{self, Deserialize, Deserializer};
+use serde::ser::Serializer;
+
+/// An ILP error code, e.g. `"F02"`.
+pub fn deserialize_error_code<'de, D>(deserializer: D)
+    -> Result<ilp::ErrorCode, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let code = <&str>::deserialize(deserializer)?;
+    let is_valid = code.len() == 3
+        && matches!(code.as_bytes()[0], b'F' | b'T' | b'R')
+        && code.as_bytes()[1..].iter().all(u8::is_ascii_alphanumeric);
+    if !is_valid {
+        return Err(de::Error::custom("invalid error code"));
+    }
+    let mut bytes = [0; 3];
+    bytes.copy_from_slice(code.as_bytes());
+    Ok(ilp::ErrorCode::new(bytes))
+}
+
+/// A list of ILP error codes (see `deserialize_error_code`).
+pub fn deserialize_error_codes<'de, D>(deserializer: D)
+    -> Result<Vec<ilp::ErrorCode>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    #[derive(serde::Deserialize)]
+    struct ErrorCode(
+        #[serde(deserialize_with = "deserialize_error_code")] ilp::ErrorCode,
+    );
+    Ok(Vec::<ErrorCode>::deserialize(deserializer)?
+        .into_iter()
+        .map(|code| code.0)
+        .collect())
+}
+
+pub fn serialize_error_code<S>(code: &ilp::ErrorCode, serializer: S)
+    -> Result<S::Ok, S::Error>
+where
+    S: Serializer,
+{
+    serializer.collect_str(code)
+}
+
+/// A base64-encoded 32-byte fulfillment.
+pub fn deserialize_fulfillment<'de, D>(deserializer: D)
+    -> Result<[u8; 32], D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let decoded = base64::decode(<&str>::deserialize(deserializer)?)
+        .map_err(de::Error::custom)?;
+    if decoded.len() != 32 {
+        return Err(de::Error::custom("fulfillment must be 32 bytes"));
+    }
+    let mut fulfillment = [0; 32];
+    fulfillment.copy_from_slice(&decoded);
+    Ok(fulfillment)
+}
+
+/// An optional RFC 3339 timestamp, e.g. `"2020-10-01T00:00:00Z"`.
+pub fn deserialize_timestamp<'de, D>(deserializer: D)
+    -> Result<Option<time::SystemTime>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    match Option::<String>::deserialize(deserializer)? {
+        Some(timestamp) => parse_timestamp(&timestamp).map(Some),
+        None => Ok(None),
+    }
+}
+
+/// An RFC 3339 timestamp, e.g. `"2020-10-01T00:00:00Z"`.
+pub fn deserialize_required_timestamp<'de, D>(deserializer: D)
+    -> Result<time::SystemTime, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    parse_timestamp(&String::deserialize(deserializer)?)
+}
+
+fn parse_timestamp<E: de::Error>(timestamp: &str)
+    -> Result<time::SystemTime, E>
+{
+    let timestamp = chrono::DateTime::parse_from_rfc3339(timestamp)
+        .map_err(de::Error::custom)?;
+    Ok(time::SystemTime::from(timestamp))
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[test]
+    fn test_deserialize_error_code() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct CodeData(
+            #[serde(deserialize_with = "deserialize_error_code")]
+            ilp::ErrorCode,
+        );
+
+        assert_eq!(
+            serde_json::from_str::<CodeData>(r#""F02""#).unwrap(),
+            CodeData(ilp::ErrorCode::F02_UNREACHABLE),
+        );
+        assert!(serde_json::from_str::<CodeData>(r#""F2""#).is_err());
+        assert!(serde_json::from_str::<CodeData>(r#""X02""#).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_fulfillment() {
+        #[derive(Debug, PartialEq, Deserialize)]
+        struct FulfillmentData(
+            #[serde(deserialize_with = "deserialize_fulfillment")]
+            [u8; 32],
+        );
+
+        assert_eq!(
+            serde_json::from_str::<FulfillmentData>(&format!(
+                "\"{}\"", base64::encode([7; 32]),
+            )).unwrap(),
+            FulfillmentData([7; 32]),
+        );
+        assert!(serde_json::from_str::<FulfillmentData>(&format!(
+            "\"{}\"", base64::encode([7; 31]),
+        )).is_err());
+        assert!(serde_json::from_str::<FulfillmentData>(r#""!""#).is_err());
+    }
+}
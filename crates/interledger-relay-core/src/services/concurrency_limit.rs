@@ -0,0 +1,217 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+use futures::future::err;
+use futures::prelude::*;
+
+use crate::{RequestId, RequestWithFrom, Service};
+use crate::connector_info::ConnectorInfo;
+use crate::reject_reasons::{self, RejectReason};
+
+/// Limit the number of Prepares that are in flight (i.e. forwarded, but not
+/// yet fulfilled or rejected) from each account, and in total. Prepares beyond
+/// either limit are rejected with `T03_CONNECTOR_BUSY`, so that a single noisy
+/// peer can't exhaust the connector's memory or outgoing connections.
+///
+/// Accounts without a configured limit are only subject to the global limit.
+#[derive(Clone, Debug)]
+pub struct ConcurrencyLimitService<S> {
+    connector: ConnectorInfo,
+    global: Option<Arc<Limit>>,
+    accounts: Arc<HashMap<Arc<String>, Arc<Limit>>>,
+    next: S,
+}
+
+#[derive(Debug)]
+struct Limit {
+    max_in_flight: usize,
+    in_flight: AtomicUsize,
+}
+
+/// Releases the slot when the response (or the dropped request) is done.
+#[derive(Debug)]
+struct Permit(Arc<Limit>);
+
+impl<S> ConcurrencyLimitService<S> {
+    pub fn new<I>(
+        connector: impl Into<ConnectorInfo>,
+        max_in_flight: Option<usize>,
+        limits: I,
+        next: S,
+    ) -> Self
+    where
+        I: IntoIterator<Item = (Arc<String>, usize)>,
+    {
+        ConcurrencyLimitService {
+            connector: connector.into(),
+            global: max_in_flight.map(|max_in_flight| {
+                Arc::new(Limit::new(max_in_flight))
+            }),
+            accounts: Arc::new({
+                limits
+                    .into_iter()
+                    .map(|(account, max_in_flight)| {
+                        (account, Arc::new(Limit::new(max_in_flight)))
+                    })
+                    .collect()
+            }),
+            next,
+        }
+    }
+
+    fn make_reject(&self, reason: &RejectReason) -> ilp::Reject {
+        reason.to_reject(self.connector.address().as_addr())
+    }
+}
+
+impl<S, Req> Service<Req> for ConcurrencyLimitService<S>
+where
+    S: 'static + Service<Req> + Send,
+    Req: RequestWithFrom,
+{
+    type Future = Pin<Box<
+        dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static,
+    >>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let account_limit = self.accounts.get(request.from_account());
+        let account_permit = match account_limit.map(Permit::acquire) {
+            Some(None) => {
+                throttled_warn!(
+                    request.from_account(),
+                    "account concurrency limit exceeded: request_id={} from_account={} from_address={}",
+                    RequestId::of(&request), request.from_account(), request.from_address(),
+                );
+                return Box::pin(err(self.make_reject(&reject_reasons::CONNECTOR_BUSY)));
+            },
+            Some(Some(permit)) => Some(permit),
+            None => None,
+        };
+        let global_permit = match self.global.as_ref().map(Permit::acquire) {
+            Some(None) => {
+                throttled_warn!(
+                    "global",
+                    "global concurrency limit exceeded: request_id={} from_account={}",
+                    RequestId::of(&request), request.from_account(),
+                );
+                return Box::pin(err(self.make_reject(&reject_reasons::CONNECTOR_BUSY)));
+            },
+            Some(Some(permit)) => Some(permit),
+            None => None,
+        };
+
+        Box::pin(self.next.call(request).map(move |response| {
+            std::mem::drop((account_permit, global_permit));
+            response
+        }))
+    }
+}
+
+impl Limit {
+    fn new(max_in_flight: usize) -> Self {
+        Limit {
+            max_in_flight,
+            in_flight: AtomicUsize::new(0),
+        }
+    }
+}
+
+impl Permit {
+    /// Returns `None` when the limit was reached.
+    fn acquire(limit: &Arc<Limit>) -> Option<Self> {
+        let in_flight = limit.in_flight.fetch_add(1, Ordering::AcqRel);
+        if in_flight < limit.max_in_flight {
+            Some(Permit(Arc::clone(limit)))
+        } else {
+            limit.in_flight.fetch_sub(1, Ordering::AcqRel);
+            None
+        }
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::AcqRel);
+    }
+}
+
+#[cfg(test)]
+mod test_concurrency_limit_service {
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+
+    use crate::Relation;
+    use crate::testing::{ADDRESS, FULFILL, MockRequest, PREPARE};
+    use super::*;
+
+    fn make_request(account: &str) -> MockRequest {
+        MockRequest::new(PREPARE.clone())
+            .from_account(account, Relation::Child)
+    }
+
+    /// Responds once the test sends the response.
+    #[derive(Clone)]
+    struct PendingService(Arc<std::sync::Mutex<Vec<oneshot::Sender<()>>>>);
+
+    impl Service<MockRequest> for PendingService {
+        type Future = Pin<Box<
+            dyn Future<
+                Output = Result<ilp::Fulfill, ilp::Reject>,
+            > + Send + 'static,
+        >>;
+
+        fn call(self, _request: MockRequest) -> Self::Future {
+            let (sender, receiver) = oneshot::channel();
+            self.0.lock().unwrap().push(sender);
+            Box::pin(receiver.map(|_| Ok(FULFILL.clone())))
+        }
+    }
+
+    #[test]
+    fn test_service() {
+        let pending = PendingService(Default::default());
+        let service = ConcurrencyLimitService::new(
+            ADDRESS.to_address(),
+            Some(3),
+            vec![(Arc::new("alice".to_owned()), 2)],
+            pending.clone(),
+        );
+        let busy = |response: Result<ilp::Fulfill, ilp::Reject>| {
+            let reject = response.unwrap_err();
+            assert_eq!(reject.code(), ilp::ErrorCode::T03_CONNECTOR_BUSY);
+            assert_eq!(reject.triggered_by(), Some(ADDRESS));
+        };
+
+        let alice_1 = service.clone().call(make_request("alice"));
+        let alice_2 = service.clone().call(make_request("alice"));
+        // Alice's limit.
+        busy(block_on(service.clone().call(make_request("alice"))));
+        let bob_1 = service.clone().call(make_request("bob"));
+        // The global limit.
+        busy(block_on(service.clone().call(make_request("bob"))));
+
+        // Responses release their slots.
+        for sender in pending.0.lock().unwrap().drain(..2) {
+            sender.send(()).unwrap();
+        }
+        assert!(block_on(alice_1).is_ok());
+        assert!(block_on(alice_2).is_ok());
+        // So do cancelled requests.
+        std::mem::drop(bob_1);
+
+        let alice_3 = service.clone().call(make_request("alice"));
+        let bob_2 = service.clone().call(make_request("bob"));
+        let bob_3 = service.clone().call(make_request("bob"));
+        busy(block_on(service.clone().call(make_request("bob"))));
+        for sender in pending.0.lock().unwrap().drain(..) {
+            let _ = sender.send(());
+        }
+        assert!(block_on(alice_3).is_ok());
+        assert!(block_on(bob_2).is_ok());
+        assert!(block_on(bob_3).is_ok());
+    }
+}
@@ -2,9 +2,10 @@ use std::pin::Pin;
 
 use futures::prelude::*;
 use log::{debug, warn};
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 
-use crate::{Request, Service};
+use crate::{Request, RequestId, Service};
+use crate::toggles::Toggle;
 
 /// These errors are more unusual, so they should be logged as warnings rather
 /// than just debug.
@@ -23,14 +24,17 @@ static WARNINGS: &[ilp::ErrorCode] = &[
 
 const ADDRESS_PREFIX_SIZE: usize = 64;
 
-/// Prints the requests and responses to stdout.
+/// Prints the requests and responses to stdout, rendered as JSON (see
+/// `ilp::Prepare::to_json`).
 #[derive(Clone, Debug)]
 pub struct DebugService<S> {
     options: DebugServiceOptions,
+    /// When disabled (through the admin API), nothing is logged.
+    enabled: Toggle,
     next: S,
 }
 
-#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
 #[serde(deny_unknown_fields)]
 pub struct DebugServiceOptions {
     pub log_prepare: bool,
@@ -44,7 +48,15 @@ impl<S> DebugService<S> {
         options: DebugServiceOptions,
         next: S,
     ) -> Self {
-        DebugService { options, next }
+        DebugService {
+            options,
+            enabled: Toggle::new(true),
+            next,
+        }
+    }
+
+    pub fn toggle(&self) -> &Toggle {
+        &self.enabled
     }
 }
 
@@ -60,9 +72,15 @@ where
     >>;
 
     fn call(self, request: Req) -> Self::Future {
+        if !self.enabled.is_enabled() {
+            return Box::pin(self.next.call(request));
+        }
         let options = self.options.clone();
         if options.log_prepare {
-            debug!("request: {:?}", request.borrow());
+            debug!(
+                "request: request_id={} {}",
+                RequestId::of(&request), request.borrow().to_json(),
+            );
         }
 
         // Store a fixed-length prefix of the destination address on the stack
@@ -74,6 +92,7 @@ where
             &destination.as_ref()[..len]
         });
 
+        let request_id = RequestId::of(&request);
         Box::pin(self.next.call(request)
             .inspect(move |response| {
                 let destination_prefix = std::str::from_utf8(&destination_prefix)
@@ -81,20 +100,23 @@ where
                 match response {
                     Ok(fulfill) => if options.log_fulfill {
                         debug!(
-                            "response: destination[..{}]={} {:?}",
-                            ADDRESS_PREFIX_SIZE, destination_prefix, fulfill,
+                            "response: request_id={} destination[..{}]={} {}",
+                            request_id, ADDRESS_PREFIX_SIZE, destination_prefix,
+                            fulfill.to_json(),
                         );
                     },
                     Err(reject) => if options.log_reject {
                         if WARNINGS.contains(&reject.code()) {
                             warn!(
-                                "response: destination[..{}]={} {:?}",
-                                ADDRESS_PREFIX_SIZE, destination_prefix, reject,
+                                "response: request_id={} destination[..{}]={} {}",
+                                request_id, ADDRESS_PREFIX_SIZE, destination_prefix,
+                                reject.to_json(),
                             );
                         } else {
                             debug!(
-                                "response: destination[..{}]={} {:?}",
-                                ADDRESS_PREFIX_SIZE, destination_prefix, reject,
+                                "response: request_id={} destination[..{}]={} {}",
+                                request_id, ADDRESS_PREFIX_SIZE, destination_prefix,
+                                reject.to_json(),
                             );
                         }
                     },
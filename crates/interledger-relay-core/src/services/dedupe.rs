@@ -0,0 +1,444 @@
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use bytes::Bytes;
+use futures::future::{Shared, ready};
+use futures::prelude::*;
+use log::debug;
+use serde::Deserialize;
+
+use crate::{RequestId, RequestWithFrom, RequestWithIdempotencyKey, Service};
+use crate::metrics::Metrics;
+
+static REPLAYS: &str = "ilp_relay_dedupe_replays_total";
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct DedupeConfig {
+    /// How long a response is remembered after its Prepare arrived.
+    pub ttl: time::Duration,
+    /// The maximum number of remembered responses. When it is reached, the
+    /// oldest are forgotten first.
+    #[serde(default = "default_max_entries")]
+    pub max_entries: usize,
+}
+
+fn default_max_entries() -> usize { 100_000 }
+
+/// Replay recent responses to retried Prepares, instead of forwarding the same
+/// packet twice (e.g. when the sender retries after a network blip).
+///
+/// A retry is a Prepare from the same account with the same condition,
+/// destination, and amount, and either the same expiry or the same
+/// `Idempotency-Key` header (so that a retry may extend its expiry).
+/// A retry of a Prepare that is still in flight waits for the same response.
+///
+/// Only Fulfills and final Rejects are remembered: a Prepare that was
+/// rejected with a temporary or relative error is forwarded again.
+#[derive(Clone, Debug)]
+pub struct DedupeService<S> {
+    config: Option<DedupeConfig>,
+    cache: Arc<Mutex<Cache>>,
+    metrics: Arc<Metrics>,
+    next: S,
+}
+
+type ResponsePacket = Result<ilp::Fulfill, ilp::Reject>;
+type SharedResponse = Shared<Pin<Box<
+    dyn Future<Output = ResponsePacket> + Send + 'static,
+>>>;
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+struct DedupeKey {
+    from_account: Arc<String>,
+    execution_condition: Bytes,
+    destination: ilp::Address,
+    amount: u64,
+    retry_id: RetryId,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+enum RetryId {
+    ExpiresAt(time::SystemTime),
+    IdempotencyKey(Bytes),
+}
+
+#[derive(Default)]
+struct Cache {
+    entries: HashMap<DedupeKey, Entry>,
+    /// The keys in the order that they were inserted (which is also the order
+    /// that they expire in). A key's entry may have been removed or replaced
+    /// since, in which case its `id` doesn't match.
+    order: VecDeque<(u64, time::Instant, DedupeKey)>,
+    next_id: u64,
+}
+
+struct Entry {
+    id: u64,
+    state: EntryState,
+}
+
+enum EntryState {
+    Pending(SharedResponse),
+    Done(ResponsePacket),
+}
+
+impl<S> DedupeService<S> {
+    pub fn new(
+        config: Option<DedupeConfig>,
+        metrics: Arc<Metrics>,
+        next: S,
+    ) -> Self {
+        DedupeService {
+            config,
+            cache: Arc::new(Mutex::new(Cache::default())),
+            metrics,
+            next,
+        }
+    }
+}
+
+impl<S, Req> Service<Req> for DedupeService<S>
+where
+    S: 'static + Service<Req> + Send,
+    Req: RequestWithFrom + RequestWithIdempotencyKey,
+{
+    type Future = Pin<Box<
+        dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static,
+    >>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let config = match self.config {
+            Some(config) => config,
+            None => return Box::pin(self.next.call(request)),
+        };
+        let key = DedupeKey::new(&request);
+        let now = time::Instant::now();
+
+        let mut cache = self.cache.lock().unwrap();
+        cache.purge(now, config.max_entries);
+        if let Some(entry) = cache.entries.get(&key) {
+            debug!(
+                "replaying response: request_id={} from_account={} is_pending={}",
+                RequestId::of(&request), request.from_account(),
+                matches!(entry.state, EntryState::Pending(_)),
+            );
+            self.metrics.increment(REPLAYS, vec![
+                ("from_account", request.from_account().as_ref().clone()),
+            ], 1);
+            return match &entry.state {
+                EntryState::Pending(response) => Box::pin(response.clone()),
+                EntryState::Done(response) => Box::pin(ready(response.clone())),
+            };
+        }
+
+        let id = cache.next_id;
+        cache.next_id += 1;
+        let response = {
+            let cache = Arc::clone(&self.cache);
+            let key = key.clone();
+            let response: Pin<Box<dyn Future<Output = _> + Send>> =
+                Box::pin(self.next.call(request).inspect(move |response| {
+                    cache.lock().unwrap().finish(&key, id, response);
+                }));
+            response.shared()
+        };
+        cache.order.push_back((id, now + config.ttl, key.clone()));
+        cache.entries.insert(key, Entry {
+            id,
+            state: EntryState::Pending(response.clone()),
+        });
+        Box::pin(response)
+    }
+}
+
+impl DedupeKey {
+    fn new<Req>(request: &Req) -> Self
+    where
+        Req: RequestWithFrom + RequestWithIdempotencyKey,
+    {
+        let prepare = request.borrow();
+        DedupeKey {
+            from_account: Arc::clone(request.from_account()),
+            execution_condition:
+                Bytes::copy_from_slice(prepare.execution_condition()),
+            destination: prepare.destination().to_address(),
+            amount: prepare.amount(),
+            retry_id: match request.idempotency_key() {
+                Some(key) => RetryId::IdempotencyKey(Bytes::copy_from_slice(key)),
+                None => RetryId::ExpiresAt(prepare.expires_at()),
+            },
+        }
+    }
+}
+
+impl Cache {
+    /// Forget the expired entries, and the oldest entries beyond
+    /// `max_entries` (leaving room for a new one).
+    fn purge(&mut self, now: time::Instant, max_entries: usize) {
+        while let Some((id, expires_at, _key)) = self.order.front() {
+            if *expires_at > now && self.order.len() < max_entries {
+                break;
+            }
+            let id = *id;
+            let (_id, _expires_at, key) = self.order.pop_front().unwrap();
+            let is_current = self.entries
+                .get(&key)
+                .map(|entry| entry.id == id)
+                .unwrap_or(false);
+            if is_current {
+                self.entries.remove(&key);
+            }
+        }
+    }
+
+    /// Remember the response if it is worth replaying, otherwise forget the
+    /// (pending) entry so that the next retry is forwarded.
+    fn finish(&mut self, key: &DedupeKey, id: u64, response: &ResponsePacket) {
+        let is_current = self.entries
+            .get(key)
+            .map(|entry| entry.id == id)
+            .unwrap_or(false);
+        if !is_current {
+            return;
+        }
+        let is_final = match response {
+            Ok(_) => true,
+            Err(reject) => reject.code().class() == ilp::ErrorClass::Final,
+        };
+        if is_final {
+            self.entries.insert(key.clone(), Entry {
+                id,
+                state: EntryState::Done(response.clone()),
+            });
+        } else {
+            self.entries.remove(key);
+        }
+    }
+}
+
+impl fmt::Debug for Cache {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("Cache")
+            .field("entries", &self.entries.len())
+            .field("next_id", &self.next_id)
+            .finish()
+    }
+}
+
+#[cfg(test)]
+mod test_dedupe_service {
+    use futures::channel::oneshot;
+    use futures::executor::block_on;
+
+    use crate::Relation;
+    use crate::testing::{FULFILL, MockRequest, PREPARE, REJECT};
+    use super::*;
+
+    static CONFIG: DedupeConfig = DedupeConfig {
+        ttl: time::Duration::from_secs(60),
+        max_entries: 3,
+    };
+
+    fn make_request(
+        account: &str,
+        prepare: ilp::Prepare,
+        idempotency_key: Option<&'static str>,
+    ) -> MockRequest {
+        let mut request = MockRequest::new(prepare)
+            .from_account(account, Relation::Child);
+        request.idempotency_key = idempotency_key
+            .map(|key| Bytes::from_static(key.as_bytes()));
+        request
+    }
+
+    fn with_amount(amount: u64) -> ilp::Prepare {
+        let prepare = PREPARE.clone();
+        ilp::PrepareBuilder {
+            amount,
+            expires_at: prepare.expires_at(),
+            execution_condition: &{
+                let mut condition = [0; 32];
+                condition.copy_from_slice(prepare.execution_condition());
+                condition
+            },
+            destination: prepare.destination(),
+            data: prepare.data(),
+        }.build()
+    }
+
+    fn with_expiry(expires_at: time::SystemTime) -> ilp::Prepare {
+        let mut prepare = PREPARE.clone();
+        prepare.set_expires_at(expires_at);
+        prepare
+    }
+
+    /// Responds once the test sends the response.
+    #[derive(Clone, Default)]
+    struct PendingService(Arc<Mutex<Vec<oneshot::Sender<ResponsePacket>>>>);
+
+    impl Service<MockRequest> for PendingService {
+        type Future = Pin<Box<
+            dyn Future<
+                Output = Result<ilp::Fulfill, ilp::Reject>,
+            > + Send + 'static,
+        >>;
+
+        fn call(self, _request: MockRequest) -> Self::Future {
+            let (sender, receiver) = oneshot::channel();
+            self.0.lock().unwrap().push(sender);
+            Box::pin(receiver.map(|response| response.unwrap()))
+        }
+    }
+
+    impl PendingService {
+        fn respond(&self, response: ResponsePacket) {
+            let senders = std::mem::take(&mut *self.0.lock().unwrap());
+            for sender in senders {
+                sender.send(response.clone()).unwrap();
+            }
+        }
+
+        fn calls(&self) -> usize {
+            self.0.lock().unwrap().len()
+        }
+    }
+
+    #[test]
+    fn test_replay() {
+        let next = PendingService::default();
+        let metrics = Arc::new(Metrics::default());
+        let service =
+            DedupeService::new(Some(CONFIG), Arc::clone(&metrics), next.clone());
+        let call = |request| service.clone().call(request);
+
+        // A retry while the first Prepare is in flight shares its response.
+        let first = call(make_request("alice", PREPARE.clone(), None));
+        let retry = call(make_request("alice", PREPARE.clone(), None));
+        // Another account's Prepare isn't a retry.
+        let bob = call(make_request("bob", PREPARE.clone(), None));
+        assert_eq!(next.calls(), 2);
+        next.respond(Ok(FULFILL.clone()));
+        assert_eq!(block_on(first), Ok(FULFILL.clone()));
+        assert_eq!(block_on(retry), Ok(FULFILL.clone()));
+        assert_eq!(block_on(bob), Ok(FULFILL.clone()));
+
+        // A later retry is answered from the cache.
+        let retry = call(make_request("alice", PREPARE.clone(), None));
+        assert_eq!(block_on(retry), Ok(FULFILL.clone()));
+        assert_eq!(next.calls(), 0);
+        assert_eq!(metrics.get(REPLAYS, vec![
+            ("from_account", "alice".to_owned()),
+        ]), 2);
+
+        // A different amount or expiry is a new Prepare.
+        std::mem::drop(call(make_request("alice", with_amount(1), None)));
+        let expires_at = PREPARE.expires_at() + time::Duration::from_secs(1);
+        std::mem::drop(call(make_request("alice", with_expiry(expires_at), None)));
+        assert_eq!(next.calls(), 2);
+    }
+
+    #[test]
+    fn test_idempotency_key() {
+        let next = PendingService::default();
+        let service = DedupeService::new(
+            Some(CONFIG),
+            Arc::new(Metrics::default()),
+            next.clone(),
+        );
+        let expires_at = PREPARE.expires_at() + time::Duration::from_secs(1);
+
+        let first = service.clone()
+            .call(make_request("alice", PREPARE.clone(), Some("abc")));
+        next.respond(Ok(FULFILL.clone()));
+        assert_eq!(block_on(first), Ok(FULFILL.clone()));
+
+        // The retry extends the expiry, but has the same key.
+        let retry = service.clone()
+            .call(make_request("alice", with_expiry(expires_at), Some("abc")));
+        assert_eq!(block_on(retry), Ok(FULFILL.clone()));
+        assert_eq!(next.calls(), 0);
+
+        std::mem::drop(service.clone()
+            .call(make_request("alice", PREPARE.clone(), Some("def"))));
+        assert_eq!(next.calls(), 1);
+    }
+
+    #[test]
+    fn test_temporary_reject() {
+        let next = PendingService::default();
+        let service = DedupeService::new(
+            Some(CONFIG),
+            Arc::new(Metrics::default()),
+            next.clone(),
+        );
+        let temporary = ilp::RejectBuilder {
+            code: ilp::ErrorCode::T04_INSUFFICIENT_LIQUIDITY,
+            message: b"",
+            triggered_by: None,
+            data: b"",
+        }.build();
+
+        let first = service.clone()
+            .call(make_request("alice", PREPARE.clone(), None));
+        next.respond(Err(temporary.clone()));
+        assert_eq!(block_on(first), Err(temporary));
+
+        // The retry is forwarded again.
+        let retry = service.clone()
+            .call(make_request("alice", PREPARE.clone(), None));
+        assert_eq!(next.calls(), 1);
+        next.respond(Err(REJECT.clone()));
+        assert_eq!(block_on(retry), Err(REJECT.clone()));
+
+        // But a final Reject is replayed.
+        let retry = service.clone()
+            .call(make_request("alice", PREPARE.clone(), None));
+        assert_eq!(block_on(retry), Err(REJECT.clone()));
+        assert_eq!(next.calls(), 0);
+    }
+
+    #[test]
+    fn test_purge() {
+        let mut cache = Cache::default();
+        let now = time::Instant::now();
+        let key = |amount| DedupeKey::new(
+            &make_request("alice", with_amount(amount), None),
+        );
+        for amount in 0..3 {
+            cache.order.push_back((
+                amount,
+                now + time::Duration::from_secs(amount),
+                key(amount),
+            ));
+            cache.entries.insert(key(amount), Entry {
+                id: amount,
+                state: EntryState::Done(Ok(FULFILL.clone())),
+            });
+        }
+        // The first entry expired.
+        cache.purge(now, 4);
+        assert!(!cache.entries.contains_key(&key(0)));
+        assert_eq!(cache.entries.len(), 2);
+        // Make room for another entry.
+        cache.purge(now, 2);
+        assert!(!cache.entries.contains_key(&key(1)));
+        assert_eq!(cache.entries.len(), 1);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let next = PendingService::default();
+        let service =
+            DedupeService::new(None, Arc::new(Metrics::default()), next.clone());
+        std::mem::drop(service.clone().call(make_request("alice", PREPARE.clone(), None)));
+        std::mem::drop(service.clone().call(make_request("alice", PREPARE.clone(), None)));
+        assert_eq!(next.calls(), 2);
+    }
+}
@@ -0,0 +1,440 @@
+use std::borrow::BorrowMut;
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use bytes::{BufMut, BytesMut};
+use futures::future::{Either, Ready, err};
+use serde::Deserialize;
+
+use crate::{RequestId, RequestWithFrom, Service};
+use crate::connector_info::ConnectorInfo;
+use crate::reject_reasons::{self, RejectReason};
+use crate::toggles::Toggle;
+use ilp::oer::{self, BufOerExt, MutBufOerExt};
+
+const MIN_MESSAGE_WINDOW: time::Duration = time::Duration::from_secs(1);
+
+/// An echo request whose execution condition was already answered within this
+/// window is treated as a loop (or a replay), and rejected.
+const LOOP_WINDOW: time::Duration = time::Duration::from_secs(30);
+/// The maximum number of remembered conditions. When it is reached, the oldest
+/// are forgotten first.
+const MAX_RECENT: usize = 10_000;
+
+static ECHO_REQUEST_PREFIX: &[u8] = b"ECHOECHOECHOECHO\x00";
+static ECHO_RESPONSE: &[u8] = b"ECHOECHOECHOECHO\x01";
+
+/// Respond to echo (ping) requests addressed to the connector by sending an
+/// echo response Prepare back to the request's source address.
+///
+/// The echo protocol has no hop count, so loops are detected by replay
+/// instead: a looping echo request comes back with the same execution
+/// condition, which is rejected if it was answered within the `LOOP_WINDOW`.
+#[derive(Clone, Debug)]
+pub struct EchoService<S> {
+    connector: ConnectorInfo,
+    /// Initially `EchoServiceOptions::enabled`.
+    enabled: Toggle,
+    /// Recently-answered execution conditions, for loop detection.
+    recent: Arc<Mutex<RecentConditions>>,
+    next: S,
+}
+
+#[derive(Debug, Default)]
+struct RecentConditions {
+    /// The id of each condition's latest entry in `order`.
+    entries: HashMap<[u8; 32], u64>,
+    /// The conditions in the order that they were answered (which is also the
+    /// order that they expire in). A condition that was answered again since
+    /// has a newer entry, in which case the `id` doesn't match.
+    order: VecDeque<(u64, time::Instant, [u8; 32])>,
+    next_id: u64,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EchoServiceOptions {
+    /// When disabled, echo requests are forwarded like any other Prepare. This
+    /// can be changed at runtime through the admin API.
+    pub enabled: bool,
+}
+
+impl<S> EchoService<S> {
+    pub fn new(
+        connector: impl Into<ConnectorInfo>,
+        options: EchoServiceOptions,
+        next: S,
+    ) -> Self {
+        EchoService {
+            connector: connector.into(),
+            enabled: Toggle::new(options.enabled),
+            recent: Arc::new(Mutex::new(RecentConditions::default())),
+            next,
+        }
+    }
+
+    pub fn toggle(&self) -> &Toggle {
+        &self.enabled
+    }
+
+    fn make_reject(&self, reason: &RejectReason) -> ilp::Reject {
+        reason.to_reject(self.connector.address().as_addr())
+    }
+
+    /// Returns `true` if the condition was already answered within the
+    /// `LOOP_WINDOW`.
+    fn is_loop(&self, execution_condition: [u8; 32], now: time::Instant) -> bool {
+        let mut recent = self.recent.lock().unwrap();
+        recent.purge(now);
+        recent.insert(execution_condition, now)
+    }
+}
+
+impl RecentConditions {
+    /// Forget the expired conditions, and the oldest conditions beyond
+    /// `MAX_RECENT` (leaving room for a new one).
+    fn purge(&mut self, now: time::Instant) {
+        while let Some((id, answered_at, _condition)) = self.order.front() {
+            let is_expired = now.saturating_duration_since(*answered_at) >= LOOP_WINDOW;
+            if !is_expired && self.order.len() < MAX_RECENT {
+                break;
+            }
+            let id = *id;
+            let (_id, _answered_at, condition) = self.order.pop_front().unwrap();
+            if self.entries.get(&condition) == Some(&id) {
+                self.entries.remove(&condition);
+            }
+        }
+    }
+
+    /// Returns `true` if the condition was already remembered.
+    fn insert(&mut self, condition: [u8; 32], now: time::Instant) -> bool {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.order.push_back((id, now, condition));
+        self.entries.insert(condition, id).is_some()
+    }
+}
+
+impl<S, Req> Service<Req> for EchoService<S>
+where
+    S: Service<Req>,
+    Req: RequestWithFrom + BorrowMut<ilp::Prepare>,
+{
+    type Future = Either<
+        Ready<Result<ilp::Fulfill, ilp::Reject>>,
+        S::Future,
+    >;
+
+    fn call(self, mut request: Req) -> Self::Future {
+        let incoming_prepare: &ilp::Prepare = request.borrow();
+        let address = self.connector.address();
+        if !self.enabled.is_enabled()
+            || address.as_addr() != incoming_prepare.destination()
+        {
+            return Either::Right(self.next.call(request));
+        }
+
+        let from_addr = deserialize_echo_request(incoming_prepare.data());
+        let from_addr = match from_addr {
+            Ok(addr) => addr,
+            Err(_) => return Either::Left(err(self.make_reject(&reject_reasons::INVALID_ECHO_REQUEST))),
+        };
+
+        let execution_condition = {
+            let mut cond = [0; 32];
+            cond.copy_from_slice(incoming_prepare.execution_condition());
+            cond
+        };
+
+        // Echoing to the connector's own address would just bounce the response
+        // back into this service.
+        let is_loop = from_addr == address.as_addr()
+            || self.is_loop(execution_condition, time::Instant::now());
+        if is_loop {
+            throttled_warn!(
+                request.from_account(),
+                "echo loop detected: request_id={} from_account={} source={}",
+                RequestId::of(&request), request.from_account(), from_addr,
+            );
+            return Either::Left(err(self.make_reject(&reject_reasons::ECHO_LOOP)));
+        }
+
+        let outgoing_prepare = ilp::PrepareBuilder {
+            amount: incoming_prepare.amount(),
+            expires_at: incoming_prepare.expires_at() - MIN_MESSAGE_WINDOW,
+            execution_condition: &execution_condition,
+            destination: from_addr,
+            data: ECHO_RESPONSE,
+        }.build();
+        *request.borrow_mut() = outgoing_prepare;
+        Either::Right(self.next.call(request))
+    }
+}
+
+fn deserialize_echo_request(mut reader: &[u8])
+    -> Result<ilp::Addr, ilp::ParseError>
+{
+    if reader.starts_with(ECHO_REQUEST_PREFIX) {
+        reader.skip(ECHO_REQUEST_PREFIX.len())?;
+        reader
+            .peek_var_octet_string()
+            .map_err(ilp::ParseError::from)
+            .and_then(|addr| Ok(ilp::Addr::try_from(addr)?))
+    } else {
+        Err(ilp::ParseError::from(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "not an echo request",
+        )))
+    }
+}
+
+/// The data of an echo request Prepare, asking for the echo response to be
+/// sent to `source_addr`.
+pub(crate) fn serialize_echo_request(source_addr: &[u8]) -> BytesMut {
+    let mut data = BytesMut::with_capacity({
+        ECHO_REQUEST_PREFIX.len()
+            + oer::predict_var_octet_string(source_addr.len())
+    });
+    data.put_slice(ECHO_REQUEST_PREFIX);
+    data.put_var_octet_string(source_addr);
+    data
+}
+
+#[cfg(test)]
+mod test_echo_service {
+    use futures::executor::block_on;
+    use lazy_static::lazy_static;
+
+    use crate::testing::{ADDRESS, FULFILL, MockRequest, MockService, PREPARE, PanicService};
+    use super::*;
+
+    static ENABLED: EchoServiceOptions = EchoServiceOptions { enabled: true };
+
+    lazy_static! {
+        static ref ECHO_PREPARE_DATA: BytesMut =
+            serialize_echo_request(b"test.origin");
+        static ref INVALID_ECHO_PREPARE_DATA: BytesMut =
+            serialize_echo_request(b"bad.address");
+        static ref SELF_ECHO_PREPARE_DATA: BytesMut =
+            serialize_echo_request(ADDRESS.as_ref());
+
+        static ref ECHO_PREPARE: ilp::PrepareBuilder<'static> =
+            ilp::PrepareBuilder {
+                amount: PREPARE.amount(),
+                expires_at: PREPARE.expires_at(),
+                execution_condition: &[0x11; 32],
+                destination: ADDRESS,
+                data: &ECHO_PREPARE_DATA,
+            };
+
+        static ref INVALID_ECHO_PREPARE: ilp::PrepareBuilder<'static> =
+            ilp::PrepareBuilder {
+                data: &INVALID_ECHO_PREPARE_DATA,
+                ..*ECHO_PREPARE
+            };
+
+        static ref SELF_ECHO_PREPARE: ilp::PrepareBuilder<'static> =
+            ilp::PrepareBuilder {
+                data: &SELF_ECHO_PREPARE_DATA,
+                ..*ECHO_PREPARE
+            };
+    }
+
+    #[test]
+    fn test_passthrough() {
+        let receiver = MockService::new(Ok(FULFILL.clone()));
+        let echo = EchoService::new(
+            ADDRESS.to_address(),
+            ENABLED.clone(),
+            receiver.clone(),
+        );
+
+        let fulfill = block_on(echo.call(make_request(PREPARE.clone()))).unwrap();
+        assert_eq!(fulfill, FULFILL.clone());
+        assert_eq!(
+            receiver
+                .prepares()
+                .collect::<Vec<_>>(),
+            vec![PREPARE.clone()],
+        );
+    }
+
+    #[test]
+    fn test_disabled() {
+        let receiver = MockService::new(Ok(FULFILL.clone()));
+        let echo = EchoService::new(
+            ADDRESS.to_address(),
+            EchoServiceOptions::default(),
+            receiver.clone(),
+        );
+
+        block_on(echo.call(make_request(ECHO_PREPARE.build()))).unwrap();
+        assert_eq!(
+            receiver
+                .prepares()
+                .collect::<Vec<_>>(),
+            vec![ECHO_PREPARE.build()],
+        );
+    }
+
+    #[test]
+    fn test_toggle() {
+        let receiver = MockService::new(Ok(FULFILL.clone()));
+        let echo = EchoService::new(
+            ADDRESS.to_address(),
+            ENABLED.clone(),
+            receiver.clone(),
+        );
+        echo.toggle().set(false);
+
+        block_on(echo.call(make_request(ECHO_PREPARE.build()))).unwrap();
+        assert_eq!(
+            receiver
+                .prepares()
+                .collect::<Vec<_>>(),
+            vec![ECHO_PREPARE.build()],
+        );
+    }
+
+    #[test]
+    fn test_valid_echo_request() {
+        let receiver = MockService::new(Ok(FULFILL.clone()));
+        let echo = EchoService::new(
+            ADDRESS.to_address(),
+            ENABLED.clone(),
+            receiver.clone(),
+        );
+
+        let fulfill = block_on(echo.call(make_request(ECHO_PREPARE.build())))
+            .unwrap();
+        assert_eq!(fulfill, FULFILL.clone());
+
+        let echo_response = receiver
+            .requests()
+            .next()
+            .unwrap();
+        assert_eq!(echo_response.from_account.as_str(), "alice");
+        assert_eq!(
+            echo_response.prepare,
+            ilp::PrepareBuilder {
+                expires_at: ECHO_PREPARE.expires_at - MIN_MESSAGE_WINDOW,
+                destination: ilp::Addr::new(b"test.origin"),
+                data: ECHO_RESPONSE,
+                ..*ECHO_PREPARE
+            }.build(),
+        );
+    }
+
+    #[test]
+    fn test_invalid_echo_request() {
+        let echo = EchoService::new(
+            ADDRESS.to_address(),
+            ENABLED.clone(),
+            PanicService,
+        );
+        let reject = block_on({
+            echo.call(make_request(INVALID_ECHO_PREPARE.build()))
+        }).unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F01_INVALID_PACKET);
+    }
+
+    #[test]
+    fn test_echo_to_self() {
+        let echo = EchoService::new(
+            ADDRESS.to_address(),
+            ENABLED.clone(),
+            PanicService,
+        );
+        let reject = block_on({
+            echo.call(make_request(SELF_ECHO_PREPARE.build()))
+        }).unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F02_UNREACHABLE);
+        assert_eq!(reject.message(), b"echo loop detected");
+    }
+
+    #[test]
+    fn test_echo_loop() {
+        let receiver = MockService::new(Ok(FULFILL.clone()));
+        let echo = EchoService::new(
+            ADDRESS.to_address(),
+            ENABLED.clone(),
+            receiver.clone(),
+        );
+
+        block_on(echo.clone().call(make_request(ECHO_PREPARE.build())))
+            .unwrap();
+        // The same echo request arrives a second time.
+        let reject = block_on(echo.call(make_request(ECHO_PREPARE.build())))
+            .unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F02_UNREACHABLE);
+        assert_eq!(receiver.requests().count(), 1);
+    }
+
+    #[test]
+    fn test_is_loop() {
+        let echo = EchoService::new(
+            ADDRESS.to_address(),
+            ENABLED.clone(),
+            PanicService,
+        );
+        let now = time::Instant::now();
+        assert!(!echo.is_loop([1; 32], now));
+        assert!(!echo.is_loop([2; 32], now));
+        assert!(echo.is_loop([1; 32], now));
+        // Old conditions are forgotten.
+        assert!(!echo.is_loop([1; 32], now + LOOP_WINDOW));
+        assert_eq!(echo.recent.lock().unwrap().entries.len(), 1);
+        assert_eq!(echo.recent.lock().unwrap().order.len(), 1);
+
+        // The oldest conditions are forgotten first when the cache is full.
+        let later = now + LOOP_WINDOW * 2;
+        for i in 0..MAX_RECENT as u32 {
+            let mut condition = [0; 32];
+            condition[..4].copy_from_slice(&i.to_be_bytes());
+            assert!(!echo.is_loop(condition, later));
+        }
+        // The first condition (`0`) was evicted to make room for itself, but
+        // the last one is still remembered.
+        assert!(!echo.is_loop([0; 32], later));
+        let mut last = [0; 32];
+        last[..4].copy_from_slice(&(MAX_RECENT as u32 - 1).to_be_bytes());
+        assert!(echo.is_loop(last, later));
+        assert!(echo.recent.lock().unwrap().order.len() <= MAX_RECENT);
+    }
+
+    #[test]
+    fn test_deserialize_echo_request() {
+        // Valid response.
+        let valid_request = serialize_echo_request(&b"example.address"[..]);
+        assert_eq!(
+            deserialize_echo_request(&valid_request).unwrap(),
+            ilp::Addr::new(b"example.address"),
+        );
+
+        // Empty.
+        assert!(deserialize_echo_request(&[]).is_err());
+
+        // Echo response.
+        let echo_response = {
+            let mut data = BytesMut::with_capacity(256);
+            data.put_slice(ECHO_RESPONSE);
+            data.put_var_octet_string(&b"example.address"[..]);
+            data
+        };
+        assert!(deserialize_echo_request(&echo_response).is_err());
+
+        // Missing source address.
+        assert!(deserialize_echo_request(ECHO_REQUEST_PREFIX).is_err());
+
+        // Invalid source address.
+        let with_invalid_address = serialize_echo_request(&b"bad.address"[..]);
+        assert!(deserialize_echo_request(&with_invalid_address).is_err());
+    }
+
+    fn make_request(prepare: ilp::Prepare) -> MockRequest {
+        MockRequest::new(prepare)
+    }
+}
@@ -5,41 +5,50 @@ use std::time;
 use futures::future::err;
 use futures::prelude::*;
 
-use crate::{Request, Service};
+use crate::{RequestWithDeadline, Service};
+use crate::connector_info::ConnectorInfo;
+use crate::reject_reasons::{self, RejectReason};
 
 /// Reject expired Prepares, and time out requests that take too long.
+///
+/// When a trusted peer sends a deadline that is earlier than the Prepare's
+/// expiry, the Prepare's expiry is moved up to the deadline. Since the next hop
+/// sees the earlier expiry, the deadline is propagated along the path.
 #[derive(Clone, Debug)]
 pub struct ExpiryService<S> {
-    address: ilp::Address,
+    connector: ConnectorInfo,
     max_timeout: time::Duration,
     next: S,
 }
 
 impl<S> ExpiryService<S> {
     pub fn new(
-        address: ilp::Address,
+        connector: impl Into<ConnectorInfo>,
         max_timeout: time::Duration,
         next: S,
     ) -> Self {
-        ExpiryService { address, max_timeout, next }
+        ExpiryService { connector: connector.into(), max_timeout, next }
     }
 
-    fn make_reject(&self, code: ilp::ErrorCode, message: &[u8])
-        -> ilp::Reject
-    {
-        ilp::RejectBuilder {
-            code,
-            message,
-            triggered_by: Some(self.address.as_addr()),
-            data: &[],
-        }.build()
+    /// The `key` is e.g. `expired_ms`: the diagnostics are durations in
+    /// milliseconds.
+    fn make_reject(
+        &self,
+        reason: &RejectReason,
+        key: &str,
+        duration: time::Duration,
+    ) -> ilp::Reject {
+        reason.to_reject_with_diagnostics(
+            self.connector.address().as_addr(),
+            &[(key, &duration.as_millis().to_string())],
+        )
     }
 }
 
 impl<S, Req> Service<Req> for ExpiryService<S>
 where
     S: Service<Req> + Send + 'static,
-    Req: Request + Send + 'static,
+    Req: RequestWithDeadline + Send + 'static,
 {
     type Future = Pin<Box<
         dyn Future<
@@ -47,29 +56,37 @@ where
         > + Send + 'static,
     >>;
 
-    fn call(self, request: Req) -> Self::Future {
-        let prepare = request.borrow();
+    fn call(self, mut request: Req) -> Self::Future {
+        let deadline = request.deadline();
+        let prepare = request.borrow_mut();
+        if let Some(deadline) = deadline {
+            if deadline < prepare.expires_at() {
+                prepare.set_expires_at(deadline);
+            }
+        }
         let expires_at = prepare.expires_at();
         let expires_in = expires_at.duration_since(time::SystemTime::now());
 
         let expires_in = match expires_in {
             Ok(expires_in) => expires_in,
-            Err(_) => return Box::pin(err(self.make_reject(
-                ilp::ErrorCode::R02_INSUFFICIENT_TIMEOUT,
-                b"insufficient timeout",
+            Err(error) => return Box::pin(err(self.make_reject(
+                &reject_reasons::INSUFFICIENT_TIMEOUT,
+                "expired_ms",
+                error.duration(),
             ))),
         };
 
         let next = self.next.clone();
+        let timeout = cmp::min(self.max_timeout, expires_in);
         Box::pin(async move {
             // TODO use Result::flatten once it stabilizes.
-            tokio::time::timeout(
-                cmp::min(self.max_timeout, expires_in),
-                next.call(request),
-            ).await.map_err(move |_error| self.make_reject(
-                ilp::ErrorCode::R00_TRANSFER_TIMED_OUT,
-                b"request timed out",
-            ))?
+            tokio::time::timeout(timeout, next.call(request))
+                .await
+                .map_err(move |_error| self.make_reject(
+                    &reject_reasons::TIMED_OUT,
+                    "timeout_ms",
+                    timeout,
+                ))?
         })
     }
 }
@@ -80,7 +97,7 @@ mod test_expiry_service {
 
     use lazy_static::lazy_static;
 
-    use crate::testing::{DelayService, FULFILL, MockService, PanicService, PREPARE};
+    use crate::testing::{DelayService, FULFILL, MockRequest, MockService, PanicService, PREPARE};
     use super::*;
 
     lazy_static! {
@@ -122,6 +139,10 @@ mod test_expiry_service {
                     let reject = response.expect_err("expected Reject");
                     assert_eq!(reject.code(), ilp::ErrorCode::R02_INSUFFICIENT_TIMEOUT);
                     assert_eq!(reject.message(), b"insufficient timeout");
+                    let diagnostics =
+                        ilp::RejectDiagnostics::from_bytes(reject.data()).unwrap();
+                    assert_eq!(diagnostics.get("reason"), Some("insufficient_timeout"));
+                    assert!(diagnostics.get("expired_ms").is_some());
                 })
         })
     }
@@ -161,10 +182,55 @@ mod test_expiry_service {
                     let reject = response.expect_err("expected Reject");
                     assert_eq!(reject.code(), ilp::ErrorCode::R00_TRANSFER_TIMED_OUT);
                     assert_eq!(reject.message(), b"request timed out");
+                    let diagnostics =
+                        ilp::RejectDiagnostics::from_bytes(reject.data()).unwrap();
+                    assert_eq!(
+                        diagnostics.iter().collect::<Vec<_>>(),
+                        vec![("reason", "timed_out"), ("timeout_ms", "15")],
+                    );
                 })
         })
     }
 
+    #[test]
+    fn test_deadline() {
+        let now = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .unwrap()
+            .as_secs();
+        let deadline = time::UNIX_EPOCH + time::Duration::from_secs(now + 5);
+        let mut prepare = PREPARE.clone();
+        prepare.set_expires_at(deadline + time::Duration::from_secs(10));
+
+        // An earlier deadline moves up the expiry, and a later one is ignored.
+        let later = prepare.expires_at() + time::Duration::from_secs(10);
+        let past = time::UNIX_EPOCH + time::Duration::from_secs(now - 5);
+        let tests = &[
+            (deadline, Some(deadline)),
+            (later, Some(prepare.expires_at())),
+            // A past deadline rejects the Prepare.
+            (past, None),
+        ];
+        for (deadline, expires_at) in tests {
+            let receiver = MockService::new(Ok(FULFILL.clone()));
+            let expiry = ExpiryService::new(ADDRESS.clone(), MAX_TIMEOUT, receiver.clone());
+            let mut request = MockRequest::new(prepare.clone());
+            request.deadline = Some(*deadline);
+            let is_ok = expires_at.is_some();
+            tokio_run(move || {
+                expiry.call(request).map(move |response| {
+                    assert_eq!(response.is_ok(), is_ok);
+                })
+            });
+            if let Some(expires_at) = expires_at {
+                assert_eq!(
+                    receiver.prepares().next().unwrap().expires_at(),
+                    *expires_at,
+                );
+            }
+        }
+    }
+
     fn tokio_run<T, F>(test: T)
     where
         T: FnOnce() -> F,
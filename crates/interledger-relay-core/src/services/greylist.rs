@@ -0,0 +1,320 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use bytes::Bytes;
+use futures::future::{Either, Ready, err};
+use serde::Deserialize;
+
+use crate::{RequestId, RequestWithFrom, Service};
+use crate::connector_info::ConnectorInfo;
+use crate::metrics::Metrics;
+use crate::reject_reasons;
+
+static FLAGGED: &str = "ilp_relay_greylist_flagged_total";
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GreylistConfig {
+    /// The number of address segments that make up a destination prefix,
+    /// e.g. `3` for `g.us.alice`.
+    #[serde(default = "default_segments")]
+    pub segments: usize,
+    /// Novel prefixes are counted over windows of this duration.
+    pub window: time::Duration,
+    /// A peer that sends to more novel prefixes within one window is flagged.
+    pub max_novel_prefixes: u32,
+    /// For this long after a peer's first Prepare, its prefixes are learned
+    /// without being counted.
+    #[serde(default)]
+    pub learning_period: time::Duration,
+    #[serde(default)]
+    pub action: GreylistAction,
+    /// The maximum number of prefixes remembered per peer. Beyond it, novel
+    /// prefixes are still counted, but not remembered.
+    #[serde(default = "default_max_known_prefixes")]
+    pub max_known_prefixes: usize,
+}
+
+fn default_segments() -> usize { 3 }
+
+fn default_max_known_prefixes() -> usize { 10_000 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum GreylistAction {
+    /// Log a warning and count the Prepare, but forward it.
+    Alert,
+    /// Reject the Prepare with `T05_RATE_LIMITED`. Prepares to known prefixes
+    /// are still forwarded.
+    Throttle,
+}
+
+// `#[default]` variants need a newer compiler.
+#[allow(clippy::derivable_impls)]
+impl Default for GreylistAction {
+    fn default() -> Self {
+        GreylistAction::Alert
+    }
+}
+
+/// Flag peers that suddenly send to many previously unseen destination
+/// prefixes, as an early sign that their credentials were compromised or
+/// that they are scanning the address space.
+#[derive(Clone, Debug)]
+pub struct GreylistService<S> {
+    connector: ConnectorInfo,
+    config: Option<GreylistConfig>,
+    peers: Arc<Mutex<HashMap<Arc<String>, PeerPrefixes>>>,
+    metrics: Arc<Metrics>,
+    next: S,
+}
+
+#[derive(Debug)]
+struct PeerPrefixes {
+    first_seen: time::Instant,
+    known: HashSet<Bytes>,
+    window_start: time::Instant,
+    novel: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Verdict {
+    Known,
+    Novel,
+    Flagged,
+}
+
+impl<S> GreylistService<S> {
+    pub fn new(
+        connector: impl Into<ConnectorInfo>,
+        config: Option<GreylistConfig>,
+        metrics: Arc<Metrics>,
+        next: S,
+    ) -> Self {
+        GreylistService {
+            connector: connector.into(),
+            config,
+            peers: Arc::new(Mutex::new(HashMap::new())),
+            metrics,
+            next,
+        }
+    }
+}
+
+impl<S, Req> Service<Req> for GreylistService<S>
+where
+    S: Service<Req>,
+    Req: RequestWithFrom,
+{
+    type Future = Either<
+        Ready<Result<ilp::Fulfill, ilp::Reject>>,
+        S::Future,
+    >;
+
+    fn call(self, request: Req) -> Self::Future {
+        let config = match self.config {
+            Some(config) => config,
+            None => return Either::Right(self.next.call(request)),
+        };
+        let destination = request.borrow().destination();
+        let prefix = destination_prefix(destination.as_ref(), config.segments);
+        let now = time::Instant::now();
+        let verdict = self.peers
+            .lock()
+            .unwrap()
+            .entry(Arc::clone(request.from_account()))
+            .or_insert_with(|| PeerPrefixes::new(now))
+            .check(&config, prefix, now);
+        if verdict != Verdict::Flagged {
+            return Either::Right(self.next.call(request));
+        }
+
+        self.metrics.increment(FLAGGED, vec![
+            ("from_account", request.from_account().as_ref().clone()),
+        ], 1);
+        throttled_warn!(
+            request.from_account(),
+            "too many novel destination prefixes: request_id={} from_account={} destination=\"{}\" action={:?}",
+            RequestId::of(&request), request.from_account(),
+            destination, config.action,
+        );
+        match config.action {
+            GreylistAction::Alert => Either::Right(self.next.call(request)),
+            GreylistAction::Throttle => Either::Left(err({
+                reject_reasons::GREYLISTED.to_reject(self.connector.address().as_addr())
+            })),
+        }
+    }
+}
+
+impl PeerPrefixes {
+    fn new(now: time::Instant) -> Self {
+        PeerPrefixes {
+            first_seen: now,
+            known: HashSet::new(),
+            window_start: now,
+            novel: 0,
+        }
+    }
+
+    fn check(&mut self, config: &GreylistConfig, prefix: &[u8], now: time::Instant)
+        -> Verdict
+    {
+        if self.known.contains(prefix) {
+            return Verdict::Known;
+        }
+        if now.duration_since(self.first_seen) < config.learning_period {
+            self.learn(config, prefix);
+            return Verdict::Known;
+        }
+        if now.duration_since(self.window_start) >= config.window {
+            self.window_start = now;
+            self.novel = 0;
+        }
+        if self.novel >= config.max_novel_prefixes {
+            // Under `Alert`, the Prepare is forwarded, so the prefix isn't
+            // novel anymore. A throttled prefix stays novel.
+            if config.action == GreylistAction::Alert {
+                self.learn(config, prefix);
+            }
+            return Verdict::Flagged;
+        }
+        self.novel += 1;
+        self.learn(config, prefix);
+        Verdict::Novel
+    }
+
+    fn learn(&mut self, config: &GreylistConfig, prefix: &[u8]) {
+        if self.known.len() < config.max_known_prefixes {
+            self.known.insert(Bytes::copy_from_slice(prefix));
+        }
+    }
+}
+
+/// The first `segments` segments of the destination (or all of it, if it has
+/// fewer).
+fn destination_prefix(destination: &[u8], segments: usize) -> &[u8] {
+    let end = destination
+        .iter()
+        .enumerate()
+        .filter(|(_i, &byte)| byte == b'.')
+        .map(|(i, _byte)| i)
+        .nth(segments.saturating_sub(1))
+        .unwrap_or(destination.len());
+    &destination[..end]
+}
+
+#[cfg(test)]
+mod test_greylist_service {
+    use futures::executor::block_on;
+
+    use crate::Relation;
+    use crate::testing::{ADDRESS, FULFILL, MockRequest, MockService, PREPARE};
+    use super::*;
+
+    const CONFIG: GreylistConfig = GreylistConfig {
+        segments: 3,
+        window: time::Duration::from_secs(60),
+        max_novel_prefixes: 2,
+        learning_period: time::Duration::from_secs(0),
+        action: GreylistAction::Throttle,
+        max_known_prefixes: 10,
+    };
+
+    fn make_request(account: &str, destination: &'static [u8]) -> MockRequest {
+        let prepare = ilp::PrepareBuilder {
+            amount: PREPARE.amount(),
+            expires_at: PREPARE.expires_at(),
+            execution_condition: &[0; 32],
+            destination: ilp::Addr::new(destination),
+            data: b"",
+        }.build();
+        MockRequest::new(prepare)
+            .from_account(account, Relation::Child)
+    }
+
+    #[test]
+    fn test_service() {
+        let metrics = Arc::new(Metrics::new(vec![]));
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let service = GreylistService::new(
+            ADDRESS.to_address(),
+            Some(CONFIG),
+            Arc::clone(&metrics),
+            next.clone(),
+        );
+        let call = |account, destination| {
+            block_on(service.clone().call(make_request(account, destination)))
+        };
+
+        assert!(call("alice", b"test.bob.x.1").is_ok());
+        assert!(call("alice", b"test.carl.x.2").is_ok());
+        let reject = call("alice", b"test.dave.x.3").unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::T05_RATE_LIMITED);
+        assert_eq!(reject.triggered_by(), Some(ADDRESS));
+        assert_eq!(reject.data(), reject_reasons::GREYLISTED.id.as_bytes());
+        // Known prefixes, and other peers, aren't affected.
+        assert!(call("alice", b"test.bob.x.4").is_ok());
+        assert!(call("bob", b"test.dave.x.5").is_ok());
+        assert_eq!(next.requests().count(), 4);
+        assert_eq!(metrics.get(FLAGGED, vec![
+            ("from_account", "alice".to_owned()),
+        ]), 1);
+    }
+
+    #[test]
+    fn test_alert() {
+        let metrics = Arc::new(Metrics::new(vec![]));
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let service = GreylistService::new(
+            ADDRESS.to_address(),
+            Some(GreylistConfig {
+                action: GreylistAction::Alert,
+                max_novel_prefixes: 0,
+                ..CONFIG
+            }),
+            Arc::clone(&metrics),
+            next.clone(),
+        );
+        for _i in 0..2 {
+            let request = make_request("alice", b"test.bob.x");
+            assert!(block_on(service.clone().call(request)).is_ok());
+        }
+        assert_eq!(next.requests().count(), 2);
+        // The prefix is learned after it is first flagged.
+        assert_eq!(metrics.get(FLAGGED, vec![
+            ("from_account", "alice".to_owned()),
+        ]), 1);
+    }
+
+    #[test]
+    fn test_check() {
+        let start = time::Instant::now();
+        let secs = time::Duration::from_secs;
+        let config = GreylistConfig {
+            learning_period: secs(10),
+            ..CONFIG
+        };
+        let mut peer = PeerPrefixes::new(start);
+        // Learning:
+        assert_eq!(peer.check(&config, b"test.a", start), Verdict::Known);
+        assert_eq!(peer.check(&config, b"test.b", start + secs(5)), Verdict::Known);
+        assert_eq!(peer.check(&config, b"test.c", start + secs(10)), Verdict::Novel);
+        assert_eq!(peer.check(&config, b"test.d", start + secs(10)), Verdict::Novel);
+        assert_eq!(peer.check(&config, b"test.e", start + secs(10)), Verdict::Flagged);
+        assert_eq!(peer.check(&config, b"test.a", start + secs(10)), Verdict::Known);
+        assert_eq!(peer.check(&config, b"test.e", start + secs(59)), Verdict::Flagged);
+        // A new window:
+        assert_eq!(peer.check(&config, b"test.e", start + secs(60)), Verdict::Novel);
+        assert_eq!(peer.check(&config, b"test.e", start + secs(60)), Verdict::Known);
+    }
+
+    #[test]
+    fn test_destination_prefix() {
+        assert_eq!(destination_prefix(b"g.us.alice.bob", 3), b"g.us.alice");
+        assert_eq!(destination_prefix(b"g.us.alice", 3), b"g.us.alice");
+        assert_eq!(destination_prefix(b"g.us", 3), b"g.us");
+        assert_eq!(destination_prefix(b"g.us.alice", 1), b"g");
+    }
+}
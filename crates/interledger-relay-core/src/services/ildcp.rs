@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::future::{Either, Ready, err, ok};
+use log::warn;
+
+use crate::{Relation, RequestId, RequestWithFrom, RequestWithPeerName, Service};
+use crate::connector_info::{ConnectorInfo, Snapshot};
+use crate::reject_reasons::{self, RejectReason};
+use crate::toggles::Toggle;
+use ilp::ildcp;
+
+/// Responses are reused for repeated requests from the same child within this
+/// window, since some plugins re-query their configuration aggressively.
+const CACHE_TTL: time::Duration = time::Duration::from_secs(10);
+
+/// The child's account and `ILP-Peer-Name`.
+type CacheKey = (Arc<String>, Vec<u8>);
+
+/// Respond to ILDCP requests from children, with the connector's current
+/// asset, and an address under its current address.
+#[derive(Clone, Debug)]
+pub struct ConfigService<S> {
+    /// Replaced as a whole when the parent's ILDCP response is refreshed.
+    connector: ConnectorInfo,
+    /// When disabled (through the admin API), ILDCP requests are forwarded
+    /// like any other Prepare.
+    enabled: Toggle,
+    cache: Arc<Mutex<HashMap<CacheKey, CacheEntry>>>,
+    next: S,
+}
+
+/// A response, and the snapshot of the connector's config that it was
+/// generated from. It is only reused while that snapshot is current.
+type CacheEntry = (time::Instant, Arc<Snapshot>, ilp::Fulfill);
+
+impl<S> ConfigService<S> {
+    pub fn new(connector: impl Into<ConnectorInfo>, next: S) -> Self {
+        ConfigService {
+            connector: connector.into(),
+            enabled: Toggle::new(true),
+            cache: Arc::new(Mutex::new(HashMap::new())),
+            next,
+        }
+    }
+
+    pub fn toggle(&self) -> &Toggle {
+        &self.enabled
+    }
+
+    /// Get the response for `key` if it was generated within the `CACHE_TTL`,
+    /// from the current `snapshot`.
+    fn get_cached(&self, key: &CacheKey, snapshot: &Arc<Snapshot>, now: time::Instant)
+        -> Option<ilp::Fulfill>
+    {
+        self.cache.lock().unwrap()
+            .get(key)
+            .filter(|(created_at, created_from, _)| {
+                now.duration_since(*created_at) < CACHE_TTL
+                    && Arc::ptr_eq(created_from, snapshot)
+            })
+            .map(|(_, _, fulfill)| fulfill.clone())
+    }
+
+    fn insert_cached(
+        &self,
+        key: CacheKey,
+        snapshot: Arc<Snapshot>,
+        now: time::Instant,
+        fulfill: ilp::Fulfill,
+    ) {
+        let mut cache = self.cache.lock().unwrap();
+        cache.retain(|_key, (created_at, _, _)| {
+            now.duration_since(*created_at) < CACHE_TTL
+        });
+        cache.insert(key, (now, snapshot, fulfill));
+    }
+
+    fn make_reject(&self, reason: &RejectReason) -> ilp::Reject {
+        reason.to_reject(self.connector.address().as_addr())
+    }
+}
+
+impl<S, Req> Service<Req> for ConfigService<S>
+where
+    S: Service<Req>,
+    Req: RequestWithPeerName + RequestWithFrom,
+{
+    type Future = Either<
+        Ready<Result<ilp::Fulfill, ilp::Reject>>,
+        S::Future,
+    >;
+
+    fn call(self, request: Req) -> Self::Future {
+        let prepare = request.borrow();
+        let is_ildcp = prepare.peer_protocol() == Some(ilp::PeerProtocol::Ildcp);
+        if !is_ildcp || !self.enabled.is_enabled() {
+            return Either::Right(self.next.call(request));
+        }
+
+        if !matches!(request.from_relation(), Relation::Child) {
+            warn!(
+                "ildcp request from non-child peer: request_id={} relation={:?} from_address={:?}",
+                RequestId::of(&request), request.from_relation(), request.from_address(),
+            );
+            return Either::Left(err(self.make_reject(&reject_reasons::ILDCP_NON_CHILD)))
+        }
+
+        let peer_name = match request.peer_name() {
+            Some(peer_name) => peer_name,
+            None => {
+                warn!(
+                    "ildcp request missing ILP-Peer-Name: request_id={} from_address={:?}",
+                    RequestId::of(&request), request.from_address(),
+                );
+                return Either::Left(err(self.make_reject(&reject_reasons::ILDCP_MISSING_PEER_NAME)))
+            },
+        };
+
+        // The asset is known, except in the tests of other services.
+        let snapshot = self.connector.get();
+        let config = match &snapshot.ildcp {
+            Some(config) => config,
+            None => return Either::Right(self.next.call(request)),
+        };
+
+        let now = time::Instant::now();
+        let key = (Arc::clone(request.from_account()), peer_name.to_vec());
+        if let Some(fulfill) = self.get_cached(&key, &snapshot, now) {
+            return Either::Left(ok(fulfill));
+        }
+
+        // If the generated address is invalid it is probably too long or the
+        // `ILP-Peer-Name` was invalid. The child's address was already moved
+        // under the current address by the `FromPeerService`.
+        let client_address = request.from_address().with_suffix(peer_name);
+        let client_address = match client_address {
+            Ok(addr) => addr,
+            Err(_) => return Either::Left(err(self.make_reject(&reject_reasons::ILDCP_INVALID_CLIENT_ADDRESS))),
+        };
+
+        debug_assert!({
+            client_address.starts_with_prefix(config.client_address().as_ref())
+        });
+
+        let fulfill = ilp::Fulfill::from(ildcp::ResponseBuilder {
+            client_address: client_address.as_addr(),
+            asset_scale: config.asset_scale(),
+            asset_code: config.asset_code(),
+        }.build());
+        self.insert_cached(key, Arc::clone(&snapshot), now, fulfill.clone());
+        Either::Left(ok(fulfill))
+    }
+}
+
+#[cfg(test)]
+mod test_config_service {
+    use std::borrow::Borrow;
+
+    use futures::executor::block_on;
+    use lazy_static::lazy_static;
+
+    use crate::Request;
+    use crate::testing::{FULFILL, MockService, PREPARE, PanicService};
+    use super::*;
+
+    static ILDCP_RESPONSE: ildcp::ResponseBuilder<'static> =
+        ildcp::ResponseBuilder {
+            client_address: unsafe { ilp::Addr::new_unchecked(b"test.carl") },
+            asset_scale: 9,
+            asset_code: b"XRP",
+        };
+
+    lazy_static! {
+        static ref CONFIG: ConfigService<MockService<TestRequest>> =
+            ConfigService::new(
+                ILDCP_RESPONSE.build(),
+                MockService::new(Ok(FULFILL.clone())),
+            );
+
+        static ref REQUEST_PREPARE: TestRequest = TestRequest {
+            prepare: PREPARE.clone(),
+            peer_name: None,
+            from_account: Arc::new("account_1".to_owned()),
+            from_relation: Relation::Child,
+            from_address: ilp::Address::new(b"test.carl.child.123"),
+        };
+
+        static ref REQUEST_ILDCP: TestRequest = TestRequest {
+            prepare: ilp::Prepare::from(ildcp::Request::new()),
+            peer_name: Some(b"bob"),
+            from_account: Arc::new("account_2".to_owned()),
+            from_relation: Relation::Child,
+            from_address:  ilp::Address::new(b"test.carl.child.123"),
+        };
+    }
+
+    #[test]
+    fn test_passthrough() {
+        assert_eq!(
+            block_on(CONFIG.clone().call(REQUEST_PREPARE.clone()))
+                .unwrap(),
+            *FULFILL,
+        );
+
+        // `peer.config`, but without the peer protocol condition:
+        let request = {
+            let mut request = REQUEST_ILDCP.clone();
+            request.prepare = ilp::PrepareBuilder {
+                destination: ildcp::DESTINATION,
+                amount: 0,
+                expires_at: PREPARE.expires_at(),
+                execution_condition: &[0; 32],
+                data: b"",
+            }.build();
+            request
+        };
+        assert_eq!(block_on(CONFIG.clone().call(request)).unwrap(), *FULFILL);
+    }
+
+    #[test]
+    fn test_toggle() {
+        let service = ConfigService::new(
+            ILDCP_RESPONSE.build(),
+            MockService::new(Ok(FULFILL.clone())),
+        );
+        service.toggle().set(false);
+        assert_eq!(
+            block_on(service.call(REQUEST_ILDCP.clone())).unwrap(),
+            *FULFILL,
+        );
+    }
+
+    #[test]
+    fn test_ildcp_missing_peer_name() {
+        let request = {
+            let mut request = REQUEST_ILDCP.clone();
+            request.peer_name = None;
+            request
+        };
+        assert_eq!(
+            block_on(CONFIG.clone().call(request))
+                .unwrap_err()
+                .code(),
+            ilp::ErrorCode::F00_BAD_REQUEST,
+        );
+    }
+
+    #[test]
+    fn test_ildcp_from_parent() {
+        let request = {
+            let mut request = REQUEST_ILDCP.clone();
+            request.from_relation = Relation::Parent;
+            request
+        };
+        assert_eq!(
+            block_on(CONFIG.clone().call(request))
+                .unwrap_err()
+                .code(),
+            ilp::ErrorCode::F00_BAD_REQUEST,
+        );
+    }
+
+    #[test]
+    fn test_ildcp_response() {
+        let fulfill = block_on({
+            CONFIG.clone().call(REQUEST_ILDCP.clone())
+        }).unwrap();
+        let response = ildcp::Response::try_from(fulfill).unwrap();
+        assert_eq!(
+            response.client_address(),
+            ilp::Addr::new(b"test.carl.child.123.bob"),
+        );
+        assert_eq!(response.asset_scale(), 9);
+        assert_eq!(response.asset_code(), b"XRP");
+    }
+
+    #[test]
+    fn test_refreshed_config() {
+        let connector = ConnectorInfo::from(ILDCP_RESPONSE.build());
+        let service = ConfigService::new(
+            connector.clone(),
+            MockService::new(Ok(FULFILL.clone())),
+        );
+        let get_response = || {
+            // The `FromPeerService` moves the child under the current address.
+            let mut request = REQUEST_ILDCP.clone();
+            request.from_address = connector.rebase(&request.from_address);
+            let fulfill = block_on(service.clone().call(request)).unwrap();
+            ildcp::Response::try_from(fulfill).unwrap()
+        };
+        assert_eq!(get_response().asset_code(), b"XRP");
+
+        // The asset is updated (and the cached response discarded).
+        connector.set(ildcp::ResponseBuilder {
+            asset_code: b"USD",
+            asset_scale: 2,
+            ..ILDCP_RESPONSE
+        }.build());
+        let response = get_response();
+        assert_eq!(response.asset_code(), b"USD");
+        assert_eq!(response.asset_scale(), 2);
+
+        // So is the address, along with the asset.
+        connector.set(ildcp::ResponseBuilder {
+            client_address: ilp::Addr::new(b"test.dave"),
+            asset_code: b"EUR",
+            ..ILDCP_RESPONSE
+        }.build());
+        let response = get_response();
+        assert_eq!(
+            response.client_address(),
+            ilp::Addr::new(b"test.dave.child.123.bob"),
+        );
+        assert_eq!(response.asset_code(), b"EUR");
+        assert_eq!(response.asset_scale(), 9);
+    }
+
+    #[test]
+    fn test_ildcp_cache() {
+        let service = ConfigService::new(
+            ILDCP_RESPONSE.build(),
+            MockService::new(Ok(FULFILL.clone())),
+        );
+        let fulfill = block_on({
+            service.clone().call(REQUEST_ILDCP.clone())
+        }).unwrap();
+        assert_eq!(
+            block_on(service.clone().call(REQUEST_ILDCP.clone())).unwrap(),
+            fulfill,
+        );
+        assert_eq!(service.cache.lock().unwrap().len(), 1);
+
+        // A different peer name gets its own response.
+        let request = {
+            let mut request = REQUEST_ILDCP.clone();
+            request.peer_name = Some(b"carl");
+            request
+        };
+        let response = ildcp::Response::try_from({
+            block_on(service.clone().call(request)).unwrap()
+        }).unwrap();
+        assert_eq!(
+            response.client_address(),
+            ilp::Addr::new(b"test.carl.child.123.carl"),
+        );
+        assert_eq!(service.cache.lock().unwrap().len(), 2);
+    }
+
+    #[test]
+    fn test_cache_ttl() {
+        let service = ConfigService::new(ILDCP_RESPONSE.build(), PanicService);
+        let key = (Arc::new("account".to_owned()), b"bob".to_vec());
+        let snapshot = service.connector.get();
+        let now = time::Instant::now();
+        assert_eq!(service.get_cached(&key, &snapshot, now), None);
+        service.insert_cached(key.clone(), Arc::clone(&snapshot), now, FULFILL.clone());
+        assert_eq!(service.get_cached(&key, &snapshot, now), Some(FULFILL.clone()));
+        // Expired responses are ignored, then removed.
+        assert_eq!(service.get_cached(&key, &snapshot, now + CACHE_TTL), None);
+        let other_key = (Arc::new("account".to_owned()), b"carl".to_vec());
+        service.insert_cached(other_key, snapshot, now + CACHE_TTL, FULFILL.clone());
+        assert_eq!(service.cache.lock().unwrap().len(), 1);
+    }
+
+    #[derive(Clone, Debug)]
+    struct TestRequest {
+        prepare: ilp::Prepare,
+        peer_name: Option<&'static [u8]>,
+        from_account: Arc<String>,
+        from_relation: Relation,
+        from_address: ilp::Address,
+    }
+
+    impl Request for TestRequest {}
+
+    impl Borrow<ilp::Prepare> for TestRequest {
+        fn borrow(&self) -> &ilp::Prepare {
+            &self.prepare
+        }
+    }
+
+    impl Into<ilp::Prepare> for TestRequest {
+        fn into(self) -> ilp::Prepare {
+            self.prepare
+        }
+    }
+
+    impl RequestWithPeerName for TestRequest {
+        fn peer_name(&self) -> Option<&[u8]> {
+            self.peer_name
+        }
+    }
+
+    impl RequestWithFrom for TestRequest {
+        fn from_account(&self) -> &Arc<String> {
+            &self.from_account
+        }
+
+        fn from_relation(&self) -> Relation {
+            self.from_relation
+        }
+
+        fn from_address(&self) -> ilp::Addr {
+            self.from_address.as_addr()
+        }
+    }
+}
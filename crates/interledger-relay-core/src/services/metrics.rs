@@ -0,0 +1,83 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::prelude::*;
+
+use crate::{RequestWithFrom, Service};
+use crate::metrics::Metrics;
+
+/// Count the incoming Prepares by source account and result (`"fulfill"` or
+/// the reject code).
+#[derive(Clone, Debug)]
+pub struct MetricsService<S> {
+    metrics: Arc<Metrics>,
+    next: S,
+}
+
+impl<S> MetricsService<S> {
+    pub fn new(metrics: Arc<Metrics>, next: S) -> Self {
+        MetricsService { metrics, next }
+    }
+}
+
+impl<S, Req> Service<Req> for MetricsService<S>
+where
+    S: 'static + Service<Req> + Send,
+    Req: RequestWithFrom,
+{
+    type Future = Pin<Box<
+        dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static,
+    >>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let from_account = request.from_account().as_ref().clone();
+        let metrics = self.metrics;
+        Box::pin(self.next.call(request)
+            .inspect(move |response| {
+                let result = match response {
+                    Ok(_) => "fulfill".to_owned(),
+                    Err(reject) => reject.code().to_string(),
+                };
+                metrics.increment("ilp_relay_prepares_total", vec![
+                    ("from_account", from_account),
+                    ("result", result),
+                ], 1);
+            }))
+    }
+}
+
+#[cfg(test)]
+mod test_metrics_service {
+    use futures::executor::block_on;
+
+    use crate::testing::{FULFILL, MockRequest, MockService, PREPARE, REJECT};
+    use super::*;
+
+    #[test]
+    fn test_count() {
+        let metrics = Arc::new(Metrics::default());
+        let request = MockRequest::new(PREPARE.clone());
+        let labels = |result: &str| vec![
+            ("from_account", "alice".to_owned()),
+            ("result", result.to_owned()),
+        ];
+
+        let service = MetricsService::new(
+            Arc::clone(&metrics),
+            MockService::new(Ok(FULFILL.clone())),
+        );
+        block_on(service.clone().call(request.clone())).unwrap();
+        block_on(service.call(request.clone())).unwrap();
+        assert_eq!(metrics.get("ilp_relay_prepares_total", labels("fulfill")), 2);
+
+        let service = MetricsService::new(
+            Arc::clone(&metrics),
+            MockService::new(Err(REJECT.clone())),
+        );
+        block_on(service.call(request)).unwrap_err();
+        let code = REJECT.code().to_string();
+        assert_eq!(metrics.get("ilp_relay_prepares_total", labels(&code)), 1);
+    }
+}
@@ -0,0 +1,33 @@
+mod concurrency_limit;
+mod debug;
+mod dedupe;
+mod echo;
+mod expiry;
+mod greylist;
+mod ildcp;
+mod metrics;
+mod rate_limit;
+mod reject_jitter;
+mod router;
+mod settlement;
+mod spsp;
+mod telemetry;
+mod throughput_limit;
+mod validate_fulfillment;
+
+pub use self::concurrency_limit::ConcurrencyLimitService;
+pub use self::debug::{DebugService, DebugServiceOptions};
+pub use self::dedupe::{DedupeConfig, DedupeService};
+pub use self::echo::{EchoService, EchoServiceOptions};
+pub use self::expiry::ExpiryService;
+pub use self::greylist::{GreylistAction, GreylistConfig, GreylistService};
+pub use self::ildcp::ConfigService;
+pub use self::metrics::MetricsService;
+pub use self::rate_limit::{RateLimitConfig, RateLimitService};
+pub use self::reject_jitter::{RejectJitterConfig, RejectJitterService};
+pub use self::router::*;
+pub use self::settlement::{Quantity, ReceiveError, Settlement, SettlementAccountConfig, SettlementConfig, SettlementService};
+pub use self::spsp::{ServerSecret, SpspConfig, SpspReceiver, SpspService};
+pub use self::telemetry::{BigQueryConfig, FileConfig, KafkaCompression, KafkaConfig, PubSubConfig, RowColumn, SinkConfig, SpillConfig, TelemetryService, TelemetryServiceConfig, UnavailablePolicy};
+pub use self::throughput_limit::{ThroughputLimitConfig, ThroughputLimitService};
+pub use self::validate_fulfillment::ValidateFulfillmentService;
@@ -0,0 +1,214 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::future::{Either, Ready, err};
+use serde::Deserialize;
+
+use crate::{RequestId, RequestWithFrom, Service};
+use crate::connector_info::ConnectorInfo;
+use crate::reject_reasons::{self, RejectReason};
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RateLimitConfig {
+    /// The rate at which the bucket refills.
+    pub packets_per_second: f64,
+    /// The capacity of the bucket: the maximum number of packets that can be
+    /// sent at once after a quiet period.
+    pub burst: u32,
+}
+
+/// Limit the rate of incoming Prepares from each account with a token bucket.
+/// Prepares from accounts without a configured limit are passed through.
+#[derive(Clone, Debug)]
+pub struct RateLimitService<S> {
+    connector: ConnectorInfo,
+    buckets: Arc<HashMap<Arc<String>, Mutex<TokenBucket>>>,
+    next: S,
+}
+
+/// A bucket of `capacity` tokens, which refills continuously. Each take costs
+/// one token per packet here, or one per unit of the amount in the
+/// `ThroughputLimitService`.
+#[derive(Debug)]
+pub(crate) struct TokenBucket {
+    capacity: f64,
+    /// Tokens per second.
+    refill_rate: f64,
+    tokens: f64,
+    updated_at: time::Instant,
+}
+
+impl<S> RateLimitService<S> {
+    pub fn new<I>(connector: impl Into<ConnectorInfo>, limits: I, next: S) -> Self
+    where
+        I: IntoIterator<Item = (Arc<String>, RateLimitConfig)>,
+    {
+        let now = time::Instant::now();
+        RateLimitService {
+            connector: connector.into(),
+            buckets: Arc::new({
+                limits
+                    .into_iter()
+                    .map(|(account, config)| {
+                        let bucket = TokenBucket::new(
+                            f64::from(config.burst),
+                            config.packets_per_second,
+                            now,
+                        );
+                        (account, Mutex::new(bucket))
+                    })
+                    .collect()
+            }),
+            next,
+        }
+    }
+
+    fn make_reject(&self, reason: &RejectReason) -> ilp::Reject {
+        reason.to_reject(self.connector.address().as_addr())
+    }
+}
+
+impl<S, Req> Service<Req> for RateLimitService<S>
+where
+    S: Service<Req>,
+    Req: RequestWithFrom,
+{
+    type Future = Either<
+        Ready<Result<ilp::Fulfill, ilp::Reject>>,
+        S::Future,
+    >;
+
+    fn call(self, request: Req) -> Self::Future {
+        let bucket = match self.buckets.get(request.from_account()) {
+            Some(bucket) => bucket,
+            None => return Either::Right(self.next.call(request)),
+        };
+
+        let is_allowed = bucket
+            .lock()
+            .unwrap()
+            .take(1.0, time::Instant::now());
+        if is_allowed {
+            Either::Right(self.next.call(request))
+        } else {
+            throttled_warn!(
+                request.from_account(),
+                "rate limit exceeded: request_id={} from_account={} from_address={}",
+                RequestId::of(&request), request.from_account(), request.from_address(),
+            );
+            Either::Left(err(self.make_reject(&reject_reasons::RATE_LIMITED)))
+        }
+    }
+}
+
+impl TokenBucket {
+    /// The bucket starts out full.
+    pub(crate) fn new(capacity: f64, refill_rate: f64, now: time::Instant) -> Self {
+        TokenBucket {
+            capacity,
+            refill_rate,
+            tokens: capacity,
+            updated_at: now,
+        }
+    }
+
+    /// Returns `true` if `cost` tokens were available. When they aren't,
+    /// nothing is taken, so that a cheaper take may still fit.
+    pub(crate) fn take(&mut self, cost: f64, now: time::Instant) -> bool {
+        self.refill(now);
+        if self.tokens >= cost {
+            self.tokens -= cost;
+            true
+        } else {
+            false
+        }
+    }
+
+    /// Return tokens that were taken (e.g. for a Prepare that was rejected).
+    pub(crate) fn refund(&mut self, cost: f64, now: time::Instant) {
+        self.refill(now);
+        self.tokens = f64::min(self.capacity, self.tokens + cost);
+    }
+
+    fn refill(&mut self, now: time::Instant) {
+        let elapsed = now
+            .checked_duration_since(self.updated_at)
+            .unwrap_or_default();
+        self.updated_at = std::cmp::max(self.updated_at, now);
+        self.tokens = f64::min(
+            self.capacity,
+            self.tokens + elapsed.as_secs_f64() * self.refill_rate,
+        );
+    }
+}
+
+#[cfg(test)]
+mod test_rate_limit_service {
+    use futures::executor::block_on;
+
+    use crate::Relation;
+    use crate::testing::{ADDRESS, FULFILL, MockRequest, MockService, PREPARE};
+    use super::*;
+
+    static CONFIG: RateLimitConfig = RateLimitConfig {
+        packets_per_second: 10.0,
+        burst: 2,
+    };
+
+    fn make_request(account: &str) -> MockRequest {
+        MockRequest::new(PREPARE.clone())
+            .from_account(account, Relation::Child)
+    }
+
+    #[test]
+    fn test_service() {
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let service = RateLimitService::new(
+            ADDRESS.to_address(),
+            vec![(Arc::new("alice".to_owned()), CONFIG)],
+            next.clone(),
+        );
+
+        assert!(block_on(service.clone().call(make_request("alice"))).is_ok());
+        assert!(block_on(service.clone().call(make_request("alice"))).is_ok());
+        let reject = block_on(service.clone().call(make_request("alice")))
+            .unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::T05_RATE_LIMITED);
+        assert_eq!(reject.triggered_by(), Some(ADDRESS));
+        assert_eq!(next.requests().count(), 2);
+
+        // Accounts without a limit are not limited.
+        for _ in 0..5 {
+            assert!(block_on(service.clone().call(make_request("bob"))).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_token_bucket() {
+        let start = time::Instant::now();
+        let mut bucket = TokenBucket::new(2.0, 10.0, start);
+        assert!(bucket.take(1.0, start));
+        assert!(bucket.take(1.0, start));
+        assert!(!bucket.take(1.0, start));
+
+        // 10 packets per second: a token is added every 100ms.
+        let ms = time::Duration::from_millis;
+        assert!(!bucket.take(1.0, start + ms(50)));
+        assert!(bucket.take(1.0, start + ms(100)));
+        assert!(!bucket.take(1.0, start + ms(100)));
+
+        // The bucket never holds more than `burst` tokens.
+        let later = start + time::Duration::from_secs(60);
+        assert!(bucket.take(1.0, later));
+        assert!(bucket.take(1.0, later));
+        assert!(!bucket.take(1.0, later));
+
+        // Refunds are capped at the capacity too.
+        bucket.refund(1.0, later);
+        bucket.refund(5.0, later);
+        assert!(bucket.take(2.0, later));
+        assert!(!bucket.take(1.0, later));
+    }
+}
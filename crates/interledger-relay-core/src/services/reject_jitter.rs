@@ -0,0 +1,157 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time;
+
+use futures::prelude::*;
+use serde::Deserialize;
+
+use crate::{Request, Service};
+use crate::random_fraction;
+use crate::serde::deserialize_error_codes;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RejectJitterConfig {
+    /// Each delay is picked at random, up to this duration.
+    pub max_delay: time::Duration,
+    /// The reject codes that are delayed (default: `["F02"]`).
+    #[serde(
+        default = "default_codes",
+        deserialize_with = "deserialize_error_codes",
+    )]
+    pub codes: Vec<ilp::ErrorCode>,
+}
+
+fn default_codes() -> Vec<ilp::ErrorCode> {
+    vec![ilp::ErrorCode::F02_UNREACHABLE]
+}
+
+impl RejectJitterConfig {
+    /// A random delay, up to `max_delay`.
+    pub fn random_delay(&self) -> time::Duration {
+        self.max_delay.mul_f64(random_fraction())
+    }
+}
+
+/// Delay Rejects with one of the configured codes (by default, `F02`) by a
+/// random duration, to slow down peers that scan the address space. Other
+/// responses aren't delayed.
+///
+/// The delay is capped at half of the time left before the Prepare expires,
+/// so that the Reject still arrives in time.
+#[derive(Clone, Debug)]
+pub struct RejectJitterService<S> {
+    config: Option<Arc<RejectJitterConfig>>,
+    next: S,
+}
+
+impl<S> RejectJitterService<S> {
+    pub fn new(config: Option<RejectJitterConfig>, next: S) -> Self {
+        RejectJitterService {
+            config: config.map(Arc::new),
+            next,
+        }
+    }
+}
+
+impl<S, Req> Service<Req> for RejectJitterService<S>
+where
+    S: 'static + Service<Req> + Send,
+    Req: Request,
+{
+    type Future = Pin<Box<
+        dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static,
+    >>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let config = match self.config {
+            Some(config) => config,
+            None => return Box::pin(self.next.call(request)),
+        };
+        let expires_at = request.borrow().expires_at();
+        Box::pin(self.next.call(request).then(move |result| async move {
+            let code = match &result {
+                Ok(_) => return result,
+                Err(reject) => reject.code(),
+            };
+            if !config.codes.contains(&code) {
+                return result;
+            }
+            let expires_in = expires_at
+                .duration_since(time::SystemTime::now())
+                .unwrap_or_default();
+            let delay = config.random_delay().min(expires_in / 2);
+            tokio::time::delay_for(delay).await;
+            result
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test_reject_jitter_service {
+    use crate::testing::{FULFILL, MockService, PREPARE, REJECT};
+    use super::*;
+
+    const MAX_DELAY: time::Duration = time::Duration::from_millis(100);
+
+    fn make_config(codes: Vec<ilp::ErrorCode>) -> Option<RejectJitterConfig> {
+        Some(RejectJitterConfig {
+            max_delay: MAX_DELAY,
+            codes,
+        })
+    }
+
+    fn run(service: RejectJitterService<MockService<ilp::Prepare>>)
+        -> (Result<ilp::Fulfill, ilp::Reject>, time::Duration)
+    {
+        let mut runtime = tokio::runtime::Runtime::new().unwrap();
+        runtime.block_on(async move {
+            let start = time::Instant::now();
+            let result = service.call(PREPARE.clone()).await;
+            (result, start.elapsed())
+        })
+    }
+
+    #[test]
+    fn test_delay() {
+        let mut delayed = 0;
+        for _i in 0..5 {
+            let (result, elapsed) = run(RejectJitterService::new(
+                make_config(vec![REJECT.code()]),
+                MockService::new(Err(REJECT.clone())),
+            ));
+            assert_eq!(result.unwrap_err(), *REJECT);
+            // Leave some room for a slow timer.
+            assert!(elapsed < MAX_DELAY * 2);
+            if elapsed > time::Duration::from_millis(1) {
+                delayed += 1;
+            }
+        }
+        // The delays are random, but (almost certainly) not all ~0.
+        assert!(delayed > 0);
+    }
+
+    #[test]
+    fn test_no_delay() {
+        let services = vec![
+            // Another code:
+            RejectJitterService::new(
+                make_config(vec![ilp::ErrorCode::T04_INSUFFICIENT_LIQUIDITY]),
+                MockService::new(Err(REJECT.clone())),
+            ),
+            // A Fulfill:
+            RejectJitterService::new(
+                make_config(vec![REJECT.code()]),
+                MockService::new(Ok(FULFILL.clone())),
+            ),
+            // Disabled:
+            RejectJitterService::new(None, MockService::new(Err(REJECT.clone()))),
+        ];
+        for service in services {
+            let (_result, elapsed) = run(service);
+            assert!(elapsed < time::Duration::from_millis(5));
+        }
+    }
+}
@@ -0,0 +1,116 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use log::warn;
+use serde::Deserialize;
+
+use crate::metrics::Metrics;
+
+static CATCH_ALL_PREPARES: &str = "ilp_relay_catch_all_prepares_total";
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CatchAllWarningConfig {
+    /// The number of Prepares (from a single account, within `window`) routed
+    /// to the catch-all route that triggers a warning.
+    pub max_packets: u64,
+    pub window: time::Duration,
+}
+
+/// Count the Prepares routed to the catch-all route (the route with an empty
+/// `target_prefix`) per source account, and warn when an account sends too
+/// many of them. A surge usually means that a route is missing, or that a peer
+/// is misconfigured.
+#[derive(Debug)]
+pub struct CatchAllMonitor {
+    config: Option<CatchAllWarningConfig>,
+    metrics: Arc<Metrics>,
+    accounts: Mutex<HashMap<Arc<String>, AccountState>>,
+}
+
+#[derive(Debug)]
+struct AccountState {
+    packets: u64,
+    window_start: time::Instant,
+}
+
+impl CatchAllMonitor {
+    pub fn new(
+        config: Option<CatchAllWarningConfig>,
+        metrics: Arc<Metrics>,
+    ) -> Self {
+        CatchAllMonitor {
+            config,
+            metrics,
+            accounts: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn record(&self, from_account: &Arc<String>, now: time::Instant) {
+        self.metrics.increment(CATCH_ALL_PREPARES, vec![
+            ("from_account", from_account.as_ref().clone()),
+        ], 1);
+        let config = match &self.config {
+            Some(config) => config,
+            None => return,
+        };
+
+        // The accounts are all configured peers, so there is nothing to prune.
+        let mut accounts = self.accounts.lock().unwrap();
+        let state = accounts
+            .entry(Arc::clone(from_account))
+            .or_insert(AccountState {
+                packets: 0,
+                window_start: now,
+            });
+        if state.window_start + config.window <= now {
+            state.packets = 0;
+            state.window_start = now;
+        }
+        state.packets += 1;
+        // Warn once per window.
+        if state.packets == config.max_packets + 1 {
+            warn!(
+                "catch-all route rate exceeded: from_account={} max_packets={} window={:?}",
+                from_account, config.max_packets, config.window,
+            );
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_catch_all_monitor {
+    use super::*;
+
+    #[test]
+    fn test_record() {
+        let metrics = Arc::new(Metrics::default());
+        let monitor = CatchAllMonitor::new(
+            Some(CatchAllWarningConfig {
+                max_packets: 2,
+                window: time::Duration::from_secs(60),
+            }),
+            Arc::clone(&metrics),
+        );
+        let alice = Arc::new("alice".to_owned());
+        let bob = Arc::new("bob".to_owned());
+        let now = time::Instant::now();
+        for _i in 0..3 {
+            monitor.record(&alice, now);
+        }
+        monitor.record(&bob, now);
+
+        let labels = |account: &str| vec![("from_account", account.to_owned())];
+        assert_eq!(metrics.get(CATCH_ALL_PREPARES, labels("alice")), 3);
+        assert_eq!(metrics.get(CATCH_ALL_PREPARES, labels("bob")), 1);
+
+        let packets = |account: &Arc<String>| {
+            monitor.accounts.lock().unwrap()[account].packets
+        };
+        assert_eq!(packets(&alice), 3);
+        // The window resets.
+        monitor.record(&alice, now + time::Duration::from_secs(60));
+        assert_eq!(packets(&alice), 1);
+    }
+}
@@ -2,20 +2,36 @@ use std::sync;
 use std::time;
 
 use log::{info, warn};
+use tokio::sync::Semaphore;
 
-use super::StaticRoute;
+use super::{RouteMaintenance, StaticRoute};
 
 const MAX_WINDOW_DURATION: time::Duration =
     time::Duration::from_secs(5 * 60);
 
+/// The weight of each new sample in the latency's moving average.
+const LATENCY_SMOOTHING: f64 = 0.1;
+
 /// A dynamic route's availability changes according to the health of its endpoint.
 #[derive(Debug)]
 pub struct DynamicRoute {
     pub config: StaticRoute,
     /// The whole routing table is in a `RwLock`, but wrapping each `status` in
     /// an independent lock ensures that e.g. routing table lookups don't interfere
-    /// with health updates.
-    pub status: sync::RwLock<RouteStatus>,
+    /// with health updates. Routes in the same `peer_pool` share a `status`.
+    pub status: sync::Arc<sync::RwLock<RouteStatus>>,
+    /// The slots for outgoing requests, when the route has a `concurrency`
+    /// limit.
+    pub in_flight: Option<sync::Arc<Semaphore>>,
+    /// Starts out as the config's `maintenance`, and is changed at runtime
+    /// through the admin API.
+    pub maintenance: sync::RwLock<Option<RouteMaintenance>>,
+    /// The exponentially weighted moving average of the next hop's response
+    /// time, in milliseconds. It is only tracked when partitioning by
+    /// `Latency`.
+    pub latency: sync::RwLock<Option<f64>>,
+    /// When the last `health_check` probe was started.
+    probed_at: sync::Mutex<Option<time::Instant>>,
 }
 
 #[derive(Clone, Debug, PartialEq)]
@@ -34,22 +50,39 @@ pub enum RouteStatus {
 
 impl DynamicRoute {
     pub fn new(config: StaticRoute) -> Self {
-        let status = sync::RwLock::new(match &config.failover {
+        let status = sync::Arc::new(sync::RwLock::new(match &config.failover {
             None => RouteStatus::Infallible,
             Some(failover) => RouteStatus::Healthy {
                 remaining: failover.window_size,
                 failures: 0,
                 updated_at: time::Instant::now(),
             },
-        });
-        DynamicRoute { config, status }
+        }));
+        let in_flight = config.concurrency
+            .as_ref()
+            .map(|limit| sync::Arc::new(Semaphore::new(limit.max_in_flight)));
+        let maintenance = sync::RwLock::new(config.maintenance.clone());
+        let latency = sync::RwLock::new(None);
+        let probed_at = sync::Mutex::new(None);
+        DynamicRoute {
+            config,
+            status,
+            in_flight,
+            maintenance,
+            latency,
+            probed_at,
+        }
     }
 
     #[cfg(test)]
     pub fn with_status(config: StaticRoute, status: RouteStatus) -> Self {
         DynamicRoute {
+            maintenance: sync::RwLock::new(config.maintenance.clone()),
             config,
-            status: sync::RwLock::new(status),
+            status: sync::Arc::new(sync::RwLock::new(status)),
+            in_flight: None,
+            latency: sync::RwLock::new(None),
+            probed_at: sync::Mutex::new(None),
         }
     }
 
@@ -61,6 +94,86 @@ impl DynamicRoute {
         }
     }
 
+    pub fn is_in_maintenance(&self) -> bool {
+        self.maintenance.read().unwrap().is_some()
+    }
+
+    /// The Reject for Prepares routed to the route, if it is in maintenance.
+    pub(crate) fn maintenance_reject(&self, connector_addr: ilp::Addr)
+        -> Option<ilp::Reject>
+    {
+        self.maintenance
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|maintenance| maintenance.to_reject(connector_addr))
+    }
+
+    pub fn latency(&self) -> Option<f64> {
+        *self.latency.read().unwrap()
+    }
+
+    /// Add a response time to the moving average.
+    pub fn record_latency(&self, latency: time::Duration) {
+        let sample = latency.as_secs_f64() * 1_000.0;
+        let mut average = self.latency.write().unwrap();
+        *average = Some(match *average {
+            Some(average) =>
+                LATENCY_SMOOTHING * sample + (1.0 - LATENCY_SMOOTHING) * average,
+            None => sample,
+        });
+    }
+
+    /// Whether the route's `health_check` is due at `now`. If so, the probe is
+    /// claimed, so the caller must send it.
+    pub(crate) fn claim_probe(&self, now: time::Instant) -> bool {
+        let interval = match &self.config.health_check {
+            Some(health_check) => health_check.interval,
+            None => return false,
+        };
+        let mut probed_at = self.probed_at.lock().unwrap();
+        let is_due = match *probed_at {
+            Some(probed_at) => probed_at + interval <= now,
+            None => true,
+        };
+        if is_due {
+            *probed_at = Some(now);
+        }
+        is_due
+    }
+
+    /// Apply the result of a `health_check` probe. Unlike `update`, a probe
+    /// doesn't wait for an unhealthy route's `fail_duration` to pass: a
+    /// success marks it healthy, and a failure keeps it unhealthy.
+    pub fn probe(&self, is_success: bool) {
+        self.probe_with_now(is_success, time::Instant::now());
+    }
+
+    fn probe_with_now(&self, is_success: bool, now: time::Instant) {
+        {
+            let mut status = self.status.write().unwrap();
+            if let RouteStatus::Unhealthy { until } = &mut *status {
+                let failover = self.config.failover.as_ref().unwrap();
+                if is_success {
+                    info!(
+                        "marking route healthy (probe succeeded): target_prefix={:?} next_hop={:?}",
+                        self.config.target_prefix,
+                        self.config.next_hop,
+                    );
+                    *status = RouteStatus::Healthy {
+                        remaining: failover.window_size,
+                        failures: 0,
+                        updated_at: now,
+                    };
+                } else {
+                    *until = (*until).max(now + failover.fail_duration);
+                }
+                return;
+            }
+        }
+        self.update_with_now(is_success, now);
+    }
+
     pub fn update(&self, is_success: bool) {
         self.update_with_now(is_success, time::Instant::now());
     }
@@ -132,7 +245,8 @@ mod test_dynamic_route {
     use bytes::Bytes;
     use lazy_static::lazy_static;
 
-    use crate::RouteFailover;
+    use crate::services::{HealthCheck, HealthProbe, RouteFailover};
+    use crate::transport::{HttpVersion, RetryPolicy};
     use crate::testing;
     use super::*;
 
@@ -149,9 +263,30 @@ mod test_dynamic_route {
                 fail_duration: 2 * SECOND,
             }),
             partition: 1.0,
+            max_packet_amount: None,
+            concurrency: None,
+            retry: std::sync::Arc::new(RetryPolicy::default()),
+            http_version: HttpVersion::Adaptive,
+            hedging: None,
+            response_timeout: None,
+            maintenance: None,
+            health_check: None,
+            peer_pool: None,
+            rewrite_prefix: None,
+            exchange: None,
         };
     }
 
+    #[test]
+    fn test_record_latency() {
+        let route = DynamicRoute::new(ROUTE.clone());
+        assert_eq!(route.latency(), None);
+        route.record_latency(time::Duration::from_millis(100));
+        assert_eq!(route.latency(), Some(100.0));
+        route.record_latency(time::Duration::from_millis(200));
+        assert!((route.latency().unwrap() - 110.0).abs() < 1e-9);
+    }
+
     #[test]
     fn test_is_available() {
         let now = time::Instant::now();
@@ -288,4 +423,66 @@ mod test_dynamic_route {
             assert_eq!(route, route_after, "index={:?}", i);
         }
     }
+
+    #[test]
+    fn test_probe() {
+        let now = time::Instant::now();
+        let probe = |before: RouteStatus, success: bool| {
+            let route = DynamicRoute::with_status(ROUTE.clone(), before);
+            route.probe_with_now(success, now);
+            sync::Arc::try_unwrap(route.status).unwrap().into_inner().unwrap()
+        };
+        let healthy = RouteStatus::Healthy {
+            remaining: 20,
+            failures: 0,
+            updated_at: now,
+        };
+
+        // A success restores an unhealthy route before `until`.
+        assert_eq!(
+            probe(RouteStatus::Unhealthy { until: now + 5 * SECOND }, true),
+            healthy,
+        );
+        // A failure keeps it unhealthy, even once `until` passes.
+        assert_eq!(
+            probe(RouteStatus::Unhealthy { until: now + 5 * SECOND }, false),
+            RouteStatus::Unhealthy { until: now + 5 * SECOND },
+        );
+        assert_eq!(
+            probe(RouteStatus::Unhealthy { until: now - SECOND }, false),
+            RouteStatus::Unhealthy { until: now + 2 * SECOND },
+        );
+        // Otherwise, it is counted like any other response.
+        assert_eq!(
+            probe(RouteStatus::Healthy {
+                remaining: 10,
+                failures: 1,
+                updated_at: now,
+            }, false),
+            RouteStatus::Unhealthy { until: now + 2 * SECOND },
+        );
+        assert_eq!(
+            probe(RouteStatus::Infallible, false),
+            RouteStatus::Infallible,
+        );
+    }
+
+    #[test]
+    fn test_claim_probe() {
+        let now = time::Instant::now();
+        assert!(!DynamicRoute::new(ROUTE.clone()).claim_probe(now));
+
+        let route = DynamicRoute::new(StaticRoute {
+            health_check: Some(HealthCheck {
+                interval: 10 * SECOND,
+                timeout: SECOND,
+                probe: HealthProbe::Head,
+            }),
+            ..ROUTE.clone()
+        });
+        assert!(route.claim_probe(now));
+        assert!(!route.claim_probe(now));
+        assert!(!route.claim_probe(now + 9 * SECOND));
+        assert!(route.claim_probe(now + 10 * SECOND));
+    }
 }
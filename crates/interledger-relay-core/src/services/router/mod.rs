@@ -0,0 +1,20 @@
+mod catch_all;
+mod dynamic_route;
+mod partition;
+mod prefix_trie;
+mod rates;
+mod schedule;
+mod serde;
+mod service;
+mod static_route;
+mod table;
+
+pub use self::catch_all::{CatchAllMonitor, CatchAllWarningConfig};
+pub use self::dynamic_route::{DynamicRoute, RouteStatus};
+pub use self::partition::RoutingPartition;
+pub use self::rates::{ExchangeRates, ExchangeRatesConfig, RateProvider, RatesError};
+pub use self::schedule::{RollbackPolicy, ScheduleState, ScheduleStatus, ScheduledRoutes};
+pub use self::serde::RoutingTableData;
+pub use self::service::RouterService;
+pub use self::static_route::{ConcurrencyLimit, HealthCheck, HealthProbe, HedgingPolicy, NextHop, RouteExchange, RouteFailover, RouteMaintenance, StaticResponse, StaticRoute};
+pub use self::table::{RouteIndex, RoutingError, RoutingTable};
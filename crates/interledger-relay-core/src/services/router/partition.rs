@@ -6,6 +6,10 @@ pub enum RoutingPartition {
     /// When partitioning by `ExecutionCondition`, packets of a STREAM connection
     /// are split over multiple routes.
     ExecutionCondition,
+    /// Like `ExecutionCondition`, but each route's partition is scaled by how
+    /// fast its next hop responds (relative to the other routes that share its
+    /// target prefix), so that traffic shifts away from a slow next hop.
+    Latency,
 }
 
 impl RoutingPartition {
@@ -13,7 +17,8 @@ impl RoutingPartition {
         let destination = prepare.destination();
         hash(match self {
             Self::Destination => destination.as_ref(),
-            Self::ExecutionCondition => prepare.execution_condition(),
+            Self::ExecutionCondition | Self::Latency =>
+                prepare.execution_condition(),
         })
     }
 }
@@ -47,6 +52,10 @@ mod test_routing_partition {
             RoutingPartition::ExecutionCondition.find(&testing::PREPARE),
             hash(testing::PREPARE.execution_condition().as_ref()),
         );
+        assert_eq!(
+            RoutingPartition::Latency.find(&testing::PREPARE),
+            hash(testing::PREPARE.execution_condition()),
+        );
     }
 
     #[test]
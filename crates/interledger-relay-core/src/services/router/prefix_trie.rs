@@ -0,0 +1,105 @@
+use std::collections::BTreeMap;
+
+/// Map target prefixes to values, for longest-prefix matching.
+///
+/// Lookups take `O(len(address))`, regardless of the number of prefixes.
+#[derive(Debug)]
+pub(crate) struct PrefixTrie<T> {
+    root: Node<T>,
+}
+
+#[derive(Debug)]
+struct Node<T> {
+    value: Option<T>,
+    children: BTreeMap<u8, Node<T>>,
+}
+
+impl<T> PrefixTrie<T> {
+    pub(crate) fn new() -> Self {
+        PrefixTrie { root: Node::new() }
+    }
+
+    /// Insert the value for `prefix`, replacing the existing one (if any).
+    pub(crate) fn insert(&mut self, prefix: &[u8], value: T) {
+        let mut node = &mut self.root;
+        for byte in prefix {
+            node = node.children.entry(*byte).or_insert_with(Node::new);
+        }
+        node.value = Some(value);
+    }
+
+    pub(crate) fn get(&self, prefix: &[u8]) -> Option<&T> {
+        let mut node = &self.root;
+        for byte in prefix {
+            node = node.children.get(byte)?;
+        }
+        node.value.as_ref()
+    }
+
+    /// Return the value of the longest prefix of `address`.
+    pub(crate) fn longest_match(&self, address: &[u8]) -> Option<&T> {
+        let mut node = &self.root;
+        let mut longest = node.value.as_ref();
+        for byte in address {
+            node = match node.children.get(byte) {
+                Some(child) => child,
+                None => break,
+            };
+            if let Some(value) = &node.value {
+                longest = Some(value);
+            }
+        }
+        longest
+    }
+}
+
+impl<T> Node<T> {
+    fn new() -> Self {
+        Node { value: None, children: BTreeMap::new() }
+    }
+}
+
+#[cfg(test)]
+mod test_prefix_trie {
+    use super::*;
+
+    #[test]
+    fn test_longest_match() {
+        let mut trie = PrefixTrie::new();
+        trie.insert(b"test.", 1);
+        trie.insert(b"test.one", 2);
+        trie.insert(b"test.one.alice.", 3);
+        trie.insert(b"example.", 4);
+
+        let tests: &[(&[u8], Option<i32>)] = &[
+            (b"test.one", Some(2)),
+            (b"test.one.alice", Some(2)),
+            (b"test.one.alice.bob", Some(3)),
+            (b"test.onetwo", Some(2)),
+            (b"test.two", Some(1)),
+            (b"test.", Some(1)),
+            (b"test", None),
+            (b"example.test.one", Some(4)),
+            (b"g.alice", None),
+            (b"", None),
+        ];
+        for (address, expect) in tests {
+            assert_eq!(trie.longest_match(address).copied(), *expect);
+        }
+
+        // The empty prefix matches everything.
+        trie.insert(b"", 0);
+        assert_eq!(trie.longest_match(b"g.alice"), Some(&0));
+        assert_eq!(trie.longest_match(b"test.two"), Some(&1));
+    }
+
+    #[test]
+    fn test_get() {
+        let mut trie = PrefixTrie::new();
+        trie.insert(b"test.one", 1);
+        trie.insert(b"test.one", 2);
+        assert_eq!(trie.get(b"test.one"), Some(&2));
+        assert_eq!(trie.get(b"test."), None);
+        assert_eq!(trie.get(b"test.one.alice"), None);
+    }
+}
@@ -0,0 +1,358 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::io;
+use std::process::Stdio;
+use std::sync::{Arc, RwLock};
+use std::time;
+
+use log::{debug, warn};
+use serde::Deserialize;
+
+use crate::transport::{Endpoint, HttpClient, HttpError, HttpRequest};
+
+/// Larger responses (or process outputs) are rejected.
+const MAX_RATES_SIZE: usize = 1 << 20;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ExchangeRatesConfig {
+    pub provider: RateProvider,
+    /// Polled rates that haven't been refreshed for longer are stale. Prepares
+    /// that need them are rejected with `T00_INTERNAL_ERROR`.
+    #[serde(default)]
+    pub max_age: Option<time::Duration>,
+}
+
+/// Where the rates (units of each asset, by asset code, per unit of the
+/// connector's asset) come from. The `Http` and `Process` providers return a
+/// JSON object, e.g. `{"EUR": 0.92, "JPY": 151.3}`.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum RateProvider {
+    Static {
+        rates: HashMap<String, f64>,
+    },
+    /// `GET` the rates from the `url` every `interval`.
+    Http {
+        url: Endpoint,
+        interval: time::Duration,
+    },
+    /// Run the `command` every `interval`, and read the rates from its
+    /// standard output. A command that runs for longer than the `interval`
+    /// is killed.
+    Process {
+        command: String,
+        #[serde(default)]
+        args: Vec<String>,
+        interval: time::Duration,
+    },
+}
+
+/// The latest exchange rates, shared by the router's routes.
+#[derive(Clone, Debug)]
+pub struct ExchangeRates {
+    config: Arc<ExchangeRatesConfig>,
+    table: Arc<RwLock<RateTable>>,
+}
+
+#[derive(Debug, Default)]
+struct RateTable {
+    rates: HashMap<String, f64>,
+    /// When the rates were last polled. `None` for `Static` rates (which never
+    /// go stale), and until the first poll succeeds.
+    updated_at: Option<time::Instant>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum RateError {
+    /// The provider has no rate for the asset.
+    Missing,
+    /// The rates are older than the `max_age`.
+    Stale,
+}
+
+#[derive(Debug)]
+pub enum RatesError {
+    Http(HttpError),
+    Status(u16),
+    Io(io::Error),
+    ExitStatus(std::process::ExitStatus),
+    TimedOut,
+    TooLarge,
+    Json(serde_json::Error),
+    InvalidRate(String),
+}
+
+impl ExchangeRates {
+    /// A provider's rates are unavailable until `spawn_refresh` polls them.
+    pub fn new(config: ExchangeRatesConfig) -> Self {
+        let rates = match &config.provider {
+            RateProvider::Static { rates } => rates.clone(),
+            _ => HashMap::new(),
+        };
+        ExchangeRates {
+            config: Arc::new(config),
+            table: Arc::new(RwLock::new(RateTable {
+                rates,
+                updated_at: None,
+            })),
+        }
+    }
+
+    /// Check that the `Static` rates are positive.
+    pub fn validate(&self) -> Result<(), RatesError> {
+        validate_rates(&self.table.read().unwrap().rates)
+    }
+
+    pub(crate) fn get(&self, asset_code: &str, now: time::Instant)
+        -> Result<f64, RateError>
+    {
+        let table = self.table.read().unwrap();
+        if !matches!(self.config.provider, RateProvider::Static { .. }) {
+            let is_stale = match (table.updated_at, self.config.max_age) {
+                (None, _) => true,
+                (Some(updated_at), Some(max_age)) =>
+                    now.saturating_duration_since(updated_at) > max_age,
+                (Some(_updated_at), None) => false,
+            };
+            if is_stale {
+                return Err(RateError::Stale);
+            }
+        }
+        table.rates.get(asset_code).copied().ok_or(RateError::Missing)
+    }
+
+    /// Poll an `Http` or `Process` provider every `interval` (starting right
+    /// away), with the `client` for an `Http` provider. When a poll fails, the
+    /// previous rates are kept.
+    pub fn spawn_refresh(&self, client: Arc<dyn HttpClient>) {
+        let interval = match &self.config.provider {
+            RateProvider::Static { .. } => return,
+            RateProvider::Http { interval, .. } => *interval,
+            RateProvider::Process { interval, .. } => *interval,
+        };
+        let rates = self.clone();
+        tokio::spawn(async move {
+            loop {
+                match rates.fetch(client.as_ref(), interval).await {
+                    Ok(new_rates) => {
+                        debug!("refreshed exchange rates: rates={}", new_rates.len());
+                        let mut table = rates.table.write().unwrap();
+                        table.rates = new_rates;
+                        table.updated_at = Some(time::Instant::now());
+                    },
+                    Err(error) => {
+                        warn!("error refreshing exchange rates: error={}", error);
+                    },
+                }
+                tokio::time::delay_for(interval).await;
+            }
+        });
+    }
+
+    async fn fetch(&self, client: &dyn HttpClient, timeout: time::Duration)
+        -> Result<HashMap<String, f64>, RatesError>
+    {
+        let output = match &self.config.provider {
+            RateProvider::Static { rates } => return Ok(rates.clone()),
+            RateProvider::Http { url, .. } =>
+                tokio::time::timeout(timeout, fetch_http(client, url)).await,
+            RateProvider::Process { command, args, .. } =>
+                tokio::time::timeout(timeout, fetch_process(command, args)).await,
+        };
+        let rates = serde_json::from_slice(&output.map_err(|_| RatesError::TimedOut)??)?;
+        validate_rates(&rates)?;
+        Ok(rates)
+    }
+}
+
+fn validate_rates(rates: &HashMap<String, f64>) -> Result<(), RatesError> {
+    for (asset_code, rate) in rates {
+        if !rate.is_finite() || *rate <= 0.0 {
+            return Err(RatesError::InvalidRate(asset_code.clone()));
+        }
+    }
+    Ok(())
+}
+
+async fn fetch_http(client: &dyn HttpClient, url: &Endpoint)
+    -> Result<Vec<u8>, RatesError>
+{
+    let response = client
+        .request(HttpRequest::get(url.as_str(), MAX_RATES_SIZE))
+        .await
+        .map_err(|error| match error {
+            HttpError::ResponseTooLarge => RatesError::TooLarge,
+            error => RatesError::Http(error),
+        })?;
+    if !response.is_success() {
+        return Err(RatesError::Status(response.status));
+    }
+    Ok(response.body.to_vec())
+}
+
+async fn fetch_process(command: &str, args: &[String])
+    -> Result<Vec<u8>, RatesError>
+{
+    let output = tokio::process::Command::new(command)
+        .args(args)
+        .stdin(Stdio::null())
+        .stderr(Stdio::inherit())
+        // If the poll times out, the command is killed.
+        .kill_on_drop(true)
+        .output()
+        .await?;
+    if !output.status.success() {
+        return Err(RatesError::ExitStatus(output.status));
+    }
+    if output.stdout.len() > MAX_RATES_SIZE {
+        return Err(RatesError::TooLarge);
+    }
+    Ok(output.stdout)
+}
+
+impl fmt::Display for RatesError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RatesError::Http(inner) => write!(f, "RatesError({})", inner),
+            RatesError::Status(status) =>
+                write!(f, "RatesError(unexpected status: {})", status),
+            RatesError::Io(inner) => write!(f, "RatesError({})", inner),
+            RatesError::ExitStatus(status) =>
+                write!(f, "RatesError(command failed: {})", status),
+            RatesError::TimedOut => f.write_str("RatesError(timed out)"),
+            RatesError::TooLarge => f.write_str("RatesError(response too large)"),
+            RatesError::Json(inner) => write!(f, "RatesError({})", inner),
+            RatesError::InvalidRate(asset_code) =>
+                write!(f, "RatesError(invalid rate: {})", asset_code),
+        }
+    }
+}
+
+impl From<io::Error> for RatesError {
+    fn from(inner: io::Error) -> Self {
+        RatesError::Io(inner)
+    }
+}
+
+impl From<serde_json::Error> for RatesError {
+    fn from(inner: serde_json::Error) -> Self {
+        RatesError::Json(inner)
+    }
+}
+
+#[cfg(test)]
+mod test_exchange_rates {
+    use crate::testing::{MockHttpClient, RECEIVER_ORIGIN};
+    use crate::transport::{HttpMethod, HttpResponse};
+    use super::*;
+
+    lazy_static::lazy_static! {
+        /// For the providers that don't make any requests.
+        static ref NO_CLIENT: MockHttpClient = MockHttpClient::new(|request| {
+            panic!("unexpected request: {:?}", request)
+        });
+    }
+
+    fn make_rates(provider: RateProvider, max_age: Option<time::Duration>)
+        -> ExchangeRates
+    {
+        ExchangeRates::new(ExchangeRatesConfig { provider, max_age })
+    }
+
+    #[test]
+    fn test_static() {
+        let rates = make_rates(RateProvider::Static {
+            rates: vec![("EUR".to_owned(), 0.5)].into_iter().collect(),
+        }, Some(time::Duration::from_secs(1)));
+        assert!(rates.validate().is_ok());
+        let later = time::Instant::now() + time::Duration::from_secs(60);
+        assert_eq!(rates.get("EUR", later), Ok(0.5));
+        assert_eq!(rates.get("JPY", later), Err(RateError::Missing));
+
+        let rates = make_rates(RateProvider::Static {
+            rates: vec![("EUR".to_owned(), 0.0)].into_iter().collect(),
+        }, None);
+        assert!(rates.validate().is_err());
+    }
+
+    #[test]
+    fn test_stale() {
+        let max_age = time::Duration::from_secs(10);
+        let rates = make_rates(RateProvider::Process {
+            command: "true".to_owned(),
+            args: vec![],
+            interval: time::Duration::from_secs(1),
+        }, Some(max_age));
+        let now = time::Instant::now();
+        // Not polled yet:
+        assert_eq!(rates.get("EUR", now), Err(RateError::Stale));
+
+        {
+            let mut table = rates.table.write().unwrap();
+            table.rates.insert("EUR".to_owned(), 0.5);
+            table.updated_at = Some(now);
+        }
+        assert_eq!(rates.get("EUR", now + max_age), Ok(0.5));
+        assert_eq!(rates.get("EUR", now + max_age * 2), Err(RateError::Stale));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_process() {
+        let interval = time::Duration::from_secs(5);
+        let rates = make_rates(RateProvider::Process {
+            command: "echo".to_owned(),
+            args: vec![r#"{"EUR": 0.5}"#.to_owned()],
+            interval,
+        }, None);
+        let fetched = rates.fetch(&*NO_CLIENT, interval).await.unwrap();
+        assert_eq!(fetched.get("EUR"), Some(&0.5));
+
+        let rates = make_rates(RateProvider::Process {
+            command: "false".to_owned(),
+            args: vec![],
+            interval,
+        }, None);
+        assert!(rates.fetch(&*NO_CLIENT, interval).await.is_err());
+
+        let rates = make_rates(RateProvider::Process {
+            command: "sleep".to_owned(),
+            args: vec!["10".to_owned()],
+            interval,
+        }, None);
+        let error = rates.fetch(&*NO_CLIENT, time::Duration::from_millis(10)).await.unwrap_err();
+        assert!(matches!(error, RatesError::TimedOut));
+    }
+
+    #[tokio::test]
+    async fn test_fetch_http() {
+        let interval = time::Duration::from_secs(5);
+        let rates = make_rates(RateProvider::Http {
+            url: format!("{}/rates", RECEIVER_ORIGIN).parse().unwrap(),
+            interval,
+        }, None);
+        let client = MockHttpClient::with_response(200, r#"{"EUR": 0.5}"#);
+        let fetched = rates.fetch(&client, interval).await.unwrap();
+        assert_eq!(fetched.get("EUR"), Some(&0.5));
+        let requests = client.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, HttpMethod::Get);
+        assert_eq!(requests[0].uri, format!("{}/rates", RECEIVER_ORIGIN));
+
+        let client = MockHttpClient::with_response(200, r#"{"EUR": 0.5, "JPY": -1}"#);
+        let error = rates.fetch(&client, interval).await.unwrap_err();
+        assert!(matches!(error, RatesError::InvalidRate(_)));
+
+        let client = MockHttpClient::with_response(503, "");
+        let error = rates.fetch(&client, interval).await.unwrap_err();
+        assert!(matches!(error, RatesError::Status(503)));
+
+        let client = MockHttpClient::new(|_request| Ok(HttpResponse {
+            status: 200,
+            body: vec![b' '; MAX_RATES_SIZE + 1].into(),
+        }));
+        let error = rates.fetch(&client, interval).await.unwrap_err();
+        assert!(matches!(error, RatesError::TooLarge));
+    }
+}
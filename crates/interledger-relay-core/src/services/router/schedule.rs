@@ -0,0 +1,191 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::time;
+
+use serde::{Deserialize, Serialize};
+
+use crate::serde::deserialize_required_timestamp;
+use super::{RoutingTable, RoutingTableData};
+
+/// A routing table that replaces the configured one at `activate_at`, so that
+/// a cutover can be prepared ahead of time.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ScheduledRoutes {
+    #[serde(deserialize_with = "deserialize_required_timestamp")]
+    pub activate_at: time::SystemTime,
+    pub routes: RoutingTableData,
+    /// Restore the previous routes if the new ones cause too many errors.
+    #[serde(default)]
+    pub rollback: Option<RollbackPolicy>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RollbackPolicy {
+    /// The maximum fraction (between `0` and `1`) of Prepares that may be
+    /// rejected by the connector itself (e.g. no route, an unreachable next
+    /// hop, or a timeout).
+    pub max_error_rate: f64,
+    /// How long after the activation the error rate is watched. After that,
+    /// the new routes are kept.
+    pub window: time::Duration,
+    /// The minimum number of Prepares before the error rate is checked.
+    #[serde(default = "default_min_prepares")]
+    pub min_prepares: u64,
+}
+
+fn default_min_prepares() -> u64 { 100 }
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub enum ScheduleState {
+    /// Waiting for `activate_at`.
+    Pending,
+    /// Activated, and watching the error rate.
+    Watching,
+    /// Activated, and kept.
+    Committed,
+    /// Activated, and then replaced by the previous routes.
+    RolledBack,
+    /// Cancelled before the activation.
+    Cancelled,
+}
+
+/// The state of the scheduled routes, as served by the admin API.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ScheduleStatus {
+    pub state: ScheduleState,
+    /// An RFC 3339 timestamp.
+    pub activate_at: String,
+    /// The Prepares that were forwarded while the error rate was watched.
+    pub prepares: u64,
+    /// The watched Prepares that were rejected by the connector itself.
+    pub errors: u64,
+}
+
+#[derive(Debug)]
+pub(crate) struct Schedule {
+    pub(crate) activate_at: time::SystemTime,
+    pub(crate) state: ScheduleState,
+    /// When pending, the scheduled routes. When watching, the previous routes,
+    /// for a rollback.
+    pub(crate) routes: Option<RoutingTable>,
+    pub(crate) watch: Option<Arc<ErrorWatch>>,
+}
+
+/// Count the Prepares (and errors) after the activation.
+#[derive(Debug)]
+pub(crate) struct ErrorWatch {
+    policy: RollbackPolicy,
+    prepares: AtomicU64,
+    errors: AtomicU64,
+}
+
+impl Schedule {
+    pub(crate) fn new(activate_at: time::SystemTime, routes: RoutingTable)
+        -> Self
+    {
+        Schedule {
+            activate_at,
+            state: ScheduleState::Pending,
+            routes: Some(routes),
+            watch: None,
+        }
+    }
+
+    pub(crate) fn status(&self) -> ScheduleStatus {
+        let (prepares, errors) = self.watch
+            .as_ref()
+            .map(|watch| watch.counts())
+            .unwrap_or((0, 0));
+        ScheduleStatus {
+            state: self.state,
+            activate_at: chrono::DateTime::<chrono::Utc>::from(self.activate_at)
+                .to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            prepares,
+            errors,
+        }
+    }
+}
+
+impl ErrorWatch {
+    pub(crate) fn new(policy: RollbackPolicy) -> Self {
+        ErrorWatch {
+            policy,
+            prepares: AtomicU64::new(0),
+            errors: AtomicU64::new(0),
+        }
+    }
+
+    /// Count a response. Returns `true` when the error rate exceeds the
+    /// policy's `max_error_rate`.
+    pub(crate) fn record(
+        &self,
+        connector_address: ilp::Addr,
+        response: &Result<ilp::Fulfill, ilp::Reject>,
+    ) -> bool {
+        let is_error = match response {
+            Ok(_) => false,
+            Err(reject) => reject.triggered_by() == Some(connector_address),
+        };
+        let prepares = self.prepares.fetch_add(1, Ordering::Relaxed) + 1;
+        let errors = if is_error {
+            self.errors.fetch_add(1, Ordering::Relaxed) + 1
+        } else {
+            self.errors.load(Ordering::Relaxed)
+        };
+        prepares >= self.policy.min_prepares
+            && errors as f64 > self.policy.max_error_rate * prepares as f64
+    }
+
+    pub(crate) fn counts(&self) -> (u64, u64) {
+        (
+            self.prepares.load(Ordering::Relaxed),
+            self.errors.load(Ordering::Relaxed),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_schedule {
+    use crate::testing::{ADDRESS, FULFILL, REJECT};
+    use crate::reject_reasons;
+    use super::*;
+
+    #[test]
+    fn test_deserialize() {
+        let scheduled = serde_json::from_str::<ScheduledRoutes>(r#"
+            { "activate_at": "2020-10-01T02:00:00Z"
+            , "routes": {}
+            , "rollback": { "max_error_rate": 0.1, "window": { "secs": 600, "nanos": 0 } }
+            }
+        "#).unwrap();
+        assert_eq!(
+            scheduled.activate_at,
+            time::UNIX_EPOCH + time::Duration::from_secs(1_601_517_600),
+        );
+        assert_eq!(scheduled.routes, RoutingTableData(vec![]));
+        assert_eq!(scheduled.rollback, Some(RollbackPolicy {
+            max_error_rate: 0.1,
+            window: time::Duration::from_secs(600),
+            min_prepares: 100,
+        }));
+    }
+
+    #[test]
+    fn test_error_watch() {
+        let watch = ErrorWatch::new(RollbackPolicy {
+            max_error_rate: 0.4,
+            window: time::Duration::from_secs(60),
+            min_prepares: 3,
+        });
+        let error = Err(reject_reasons::NO_ROUTE.to_reject(ADDRESS));
+        // Rejects from other connectors aren't errors.
+        assert!(!watch.record(ADDRESS, &Err(REJECT.clone())));
+        // Too few Prepares to tell.
+        assert!(!watch.record(ADDRESS, &error));
+        assert!(!watch.record(ADDRESS, &Ok(FULFILL.clone())));
+        assert!(watch.record(ADDRESS, &error));
+        assert_eq!(watch.counts(), (4, 2));
+    }
+}
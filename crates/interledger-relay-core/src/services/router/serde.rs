@@ -0,0 +1,111 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+use std::time;
+
+use bytes::Bytes;
+use serde::de::{Deserialize, Deserializer};
+
+use crate::transport::{HttpVersion, RetryPolicy};
+use super::{ConcurrencyLimit, HealthCheck, HedgingPolicy, NextHop, RouteExchange, RouteFailover, RouteMaintenance, StaticRoute};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RoutingTableData(pub Vec<StaticRoute>);
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+struct RouteMap(HashMap<String, Vec<RouteData>>);
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+struct RouteData {
+    pub next_hop: NextHop,
+    /// The destination "account" -- tagged as `to_account` in telemetry rows.
+    pub account: Arc<String>,
+    pub failover: Option<RouteFailover>,
+    #[serde(default = "default_partition")]
+    pub partition: f64,
+    #[serde(default)]
+    pub max_packet_amount: Option<u64>,
+    #[serde(default)]
+    pub concurrency: Option<ConcurrencyLimit>,
+    #[serde(default)]
+    pub retry: Arc<RetryPolicy>,
+    #[serde(default = "default_http_version")]
+    pub http_version: HttpVersion,
+    #[serde(default)]
+    pub hedging: Option<HedgingPolicy>,
+    #[serde(default)]
+    pub response_timeout: Option<time::Duration>,
+    #[serde(default)]
+    pub maintenance: Option<RouteMaintenance>,
+    #[serde(default)]
+    pub health_check: Option<HealthCheck>,
+    #[serde(default)]
+    pub peer_pool: Option<Arc<String>>,
+    #[serde(default)]
+    pub rewrite_prefix: Option<String>,
+    #[serde(default)]
+    pub exchange: Option<RouteExchange>,
+}
+
+fn default_partition() -> f64 { 1.0 }
+
+fn default_http_version() -> HttpVersion { HttpVersion::Adaptive }
+
+impl<'de> Deserialize<'de> for RoutingTableData {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let mut routes_by_prefix = RouteMap::deserialize(deserializer)?.0;
+        let mut prefixes = routes_by_prefix
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        // Order prefixes from longest-to-shortest. The routing table matches the
+        // longest prefix regardless, but this keeps the group order (and the
+        // logs) stable. For same-length prefixes, use alphabetical order to make
+        // it deterministic.
+        prefixes.sort_unstable_by(|prefix_1, prefix_2| {
+            prefix_1.len()
+                .cmp(&prefix_2.len())
+                .reverse()
+                .then_with(|| prefix_1.cmp(prefix_2))
+        });
+
+        let mut routes = Vec::new();
+        for prefix in prefixes {
+            let route_datas = routes_by_prefix
+                .remove(&prefix)
+                .unwrap();
+            let prefix = Bytes::from(prefix);
+            for route_data in route_datas {
+                routes.push(StaticRoute {
+                    target_prefix: prefix.clone(),
+                    next_hop: route_data.next_hop,
+                    account: route_data.account,
+                    failover: route_data.failover,
+                    partition: route_data.partition,
+                    max_packet_amount: route_data.max_packet_amount,
+                    concurrency: route_data.concurrency,
+                    retry: route_data.retry,
+                    http_version: route_data.http_version,
+                    hedging: route_data.hedging,
+                    response_timeout: route_data.response_timeout,
+                    maintenance: route_data.maintenance,
+                    health_check: route_data.health_check,
+                    peer_pool: route_data.peer_pool,
+                    rewrite_prefix: route_data.rewrite_prefix.map(Bytes::from),
+                    exchange: route_data.exchange,
+                });
+            }
+        }
+        Ok(RoutingTableData(routes))
+    }
+}
+
+impl Into<Vec<StaticRoute>> for RoutingTableData {
+    #[inline]
+    fn into(self) -> Vec<StaticRoute> {
+        self.0
+    }
+}
@@ -0,0 +1,1469 @@
+use std::collections::{BTreeMap, HashMap};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::time;
+
+use futures::future::Either;
+use futures::prelude::*;
+use log::{debug, info, warn};
+use tokio::sync::{OwnedSemaphorePermit, Semaphore};
+
+use crate::{Service, Request, RequestId, ResponseWithRoute};
+use crate::connector_info::ConnectorInfo;
+use crate::reject_reasons::{self, RejectReason};
+use crate::services::Settlement;
+use crate::services::echo::serialize_echo_request;
+use crate::toggles::Toggle;
+use crate::transport::{Endpoint, NextHopClient, NextHopError, NextHopRequest, ProbeRequest, RetryPolicy};
+use super::{DynamicRoute, ExchangeRates, HealthProbe, NextHop, RouteExchange, RouteIndex, RouteMaintenance, RoutingError, RoutingTable, StaticRoute};
+use super::{RollbackPolicy, ScheduleState, ScheduleStatus};
+use super::schedule::{ErrorWatch, Schedule};
+
+/// How often the routing table is checked for due `health_check` probes.
+const PROBE_TICK: time::Duration = time::Duration::from_secs(1);
+
+#[derive(Clone, Debug)]
+pub struct RouterService {
+    data: Arc<ServiceData>,
+    client: Arc<dyn NextHopClient>,
+}
+
+#[derive(Debug)]
+struct ServiceData {
+    connector: ConnectorInfo,
+    routes: RwLock<RoutingTable>,
+    /// When enabled, packets are routed (and the decision logged) but never
+    /// forwarded.
+    simulation: Toggle,
+    /// The maintenance changes made through the admin API, by account. These
+    /// are applied to new routing tables too.
+    maintenance: Mutex<HashMap<String, Option<RouteMaintenance>>>,
+    schedule: Mutex<Option<Schedule>>,
+    /// Set while the error rate of the scheduled routes is watched.
+    watch: RwLock<Option<Arc<ErrorWatch>>>,
+    /// The rates for routes whose `exchange` has an `asset_code`.
+    exchange_rates: RwLock<Option<ExchangeRates>>,
+    /// Tracks the routes' accounts' payable balances.
+    settlement: RwLock<Option<Settlement>>,
+}
+
+impl<Req> Service<Req> for RouterService
+where
+    Req: Request,
+{
+    type Future = Pin<Box<
+        dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static,
+    >>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let request_id = RequestId::of(&request);
+        let watch = self.data.watch
+            .read()
+            .unwrap()
+            .as_ref()
+            .map(|watch| (Arc::clone(watch), self.clone()));
+        Box::pin({
+            self.forward(request.into(), request_id)
+                .map(move |response| {
+                    if let Some((watch, router)) = watch {
+                        let address = router.data.connector.address();
+                        if watch.record(address.as_addr(), &response.packet) {
+                            router.rollback_schedule();
+                        }
+                    }
+                    response.packet
+                })
+        })
+    }
+}
+
+impl RouterService {
+    pub fn new(
+        connector: impl Into<ConnectorInfo>,
+        client: Arc<dyn NextHopClient>,
+        routes: RoutingTable,
+        simulation_mode: bool,
+    ) -> Self {
+        RouterService {
+            data: Arc::new(ServiceData {
+                connector: connector.into(),
+                routes: RwLock::new(routes),
+                simulation: Toggle::new(simulation_mode),
+                maintenance: Mutex::new(HashMap::new()),
+                schedule: Mutex::new(None),
+                watch: RwLock::new(None),
+                exchange_rates: RwLock::new(None),
+                settlement: RwLock::new(None),
+            }),
+            client,
+        }
+    }
+
+    pub fn simulation_toggle(&self) -> &Toggle {
+        &self.data.simulation
+    }
+
+    /// Set the rates for routes whose `exchange` has an `asset_code`.
+    pub fn set_exchange_rates(&self, exchange_rates: ExchangeRates) {
+        *self.data.exchange_rates.write().unwrap() = Some(exchange_rates);
+    }
+
+    /// Add the amounts that are forwarded (and fulfilled) to the settled
+    /// routes' accounts' payable balances.
+    pub fn set_settlement(&self, settlement: Settlement) {
+        *self.data.settlement.write().unwrap() = Some(settlement);
+    }
+
+    /// Replace the routing table.
+    pub fn set_routes(&self, new_routes: RoutingTable) {
+        self.replace_routes(new_routes);
+    }
+
+    fn replace_routes(&self, new_routes: RoutingTable) -> RoutingTable {
+        let maintenance = self.data.maintenance.lock().unwrap();
+        for (account, maintenance) in maintenance.iter() {
+            new_routes.set_maintenance(account, maintenance.as_ref());
+        }
+        let mut routes = self.data.routes.write().unwrap();
+        std::mem::replace(&mut *routes, new_routes)
+    }
+
+    /// Replace the routing table with `routes` at `activate_at` (or right
+    /// away, if it has passed). With a `rollback` policy, the previous table is
+    /// restored if the new one's error rate exceeds the policy's limit within
+    /// its window.
+    pub fn schedule_routes(
+        &self,
+        activate_at: time::SystemTime,
+        routes: RoutingTable,
+        rollback: Option<RollbackPolicy>,
+    ) {
+        *self.data.schedule.lock().unwrap() =
+            Some(Schedule::new(activate_at, routes));
+        let activate_now = activate_at <= time::SystemTime::now();
+        let window = if activate_now {
+            self.activate_schedule(rollback)
+        } else {
+            None
+        };
+        let router = self.clone();
+        tokio::spawn(async move {
+            let window = if activate_now {
+                window
+            } else {
+                delay_until(activate_at).await;
+                router.activate_schedule(rollback)
+            };
+            if let Some(window) = window {
+                tokio::time::delay_for(window).await;
+                router.commit_schedule();
+            }
+        });
+    }
+
+    /// The state of the scheduled routes, if any.
+    pub fn schedule(&self) -> Option<ScheduleStatus> {
+        self.data.schedule
+            .lock()
+            .unwrap()
+            .as_ref()
+            .map(Schedule::status)
+    }
+
+    /// Cancel the scheduled routes, unless they were already activated.
+    pub fn cancel_schedule(&self) -> Option<ScheduleStatus> {
+        let mut schedule = self.data.schedule.lock().unwrap();
+        let schedule = schedule.as_mut()?;
+        if schedule.state == ScheduleState::Pending {
+            info!("cancelled scheduled routes");
+            schedule.state = ScheduleState::Cancelled;
+            schedule.routes = None;
+        }
+        Some(schedule.status())
+    }
+
+    /// Returns the rollback window, if the new routes are watched.
+    fn activate_schedule(&self, rollback: Option<RollbackPolicy>)
+        -> Option<time::Duration>
+    {
+        let mut schedule = self.data.schedule.lock().unwrap();
+        let schedule = match schedule.as_mut() {
+            Some(schedule) if schedule.state == ScheduleState::Pending => schedule,
+            _ => return None,
+        };
+        let new_routes = schedule.routes.take()
+            .expect("pending schedule without routes");
+        let old_routes = self.replace_routes(new_routes);
+        info!("activated scheduled routes: rollback={:?}", rollback);
+        let policy = match rollback {
+            Some(policy) => policy,
+            None => {
+                schedule.state = ScheduleState::Committed;
+                return None;
+            },
+        };
+        let watch = Arc::new(ErrorWatch::new(policy));
+        schedule.state = ScheduleState::Watching;
+        schedule.routes = Some(old_routes);
+        schedule.watch = Some(Arc::clone(&watch));
+        *self.data.watch.write().unwrap() = Some(watch);
+        Some(policy.window)
+    }
+
+    fn commit_schedule(&self) {
+        let mut schedule = self.data.schedule.lock().unwrap();
+        if let Some(schedule) = schedule.as_mut() {
+            if schedule.state == ScheduleState::Watching {
+                let status = schedule.status();
+                info!(
+                    "committed scheduled routes: prepares={} errors={}",
+                    status.prepares, status.errors,
+                );
+                schedule.state = ScheduleState::Committed;
+                schedule.routes = None;
+                *self.data.watch.write().unwrap() = None;
+            }
+        }
+    }
+
+    fn rollback_schedule(&self) {
+        let mut schedule = self.data.schedule.lock().unwrap();
+        if let Some(schedule) = schedule.as_mut() {
+            if schedule.state == ScheduleState::Watching {
+                let status = schedule.status();
+                warn!(
+                    "rolling back scheduled routes: prepares={} errors={}",
+                    status.prepares, status.errors,
+                );
+                let old_routes = schedule.routes.take()
+                    .expect("watched schedule without previous routes");
+                self.replace_routes(old_routes);
+                schedule.state = ScheduleState::RolledBack;
+                *self.data.watch.write().unwrap() = None;
+            }
+        }
+    }
+
+    /// Send the routes' `health_check` probes in the background. The current
+    /// routing table is checked every `PROBE_TICK`, so this also covers routes
+    /// that are added later.
+    pub fn spawn_health_checks(&self) {
+        let router = self.clone();
+        tokio::spawn(async move {
+            loop {
+                router.send_probes(time::Instant::now());
+                tokio::time::delay_for(PROBE_TICK).await;
+            }
+        });
+    }
+
+    fn send_probes(&self, now: time::Instant) {
+        let routes = self.data.routes.read().unwrap();
+        for (index, route) in routes.claim_probes(now) {
+            let probe = match self.probe(&route.config) {
+                Some(probe) => probe,
+                None => continue,
+            };
+            let config = route.config.clone();
+            let service_data = Arc::clone(&self.data);
+            tokio::spawn(async move {
+                let is_success = probe.await;
+                debug!(
+                    "route probed: account={} target_prefix={:?} is_success={}",
+                    config.account, config.target_prefix, is_success,
+                );
+                service_data.routes
+                    .read()
+                    .unwrap()
+                    .probe(index, &config, is_success);
+            });
+        }
+    }
+
+    /// Send the route's `health_check` probe, which resolves to whether the
+    /// next hop answered. Returns `None` when the probe can't be sent to the
+    /// route's next hop.
+    fn probe(&self, config: &StaticRoute)
+        -> Option<impl Future<Output = bool> + Send + 'static>
+    {
+        let health_check = config.health_check.as_ref()?;
+        let auth = config.auth().cloned();
+        let request = match (&health_check.probe, &config.next_hop) {
+            (_, NextHop::Static { .. }) | (_, NextHop::Reject { .. }) =>
+                return None,
+            (HealthProbe::Head, NextHop::Bilateral { endpoint, .. }) => self.client
+                .probe(ProbeRequest {
+                    endpoint: endpoint.clone(),
+                    auth,
+                    headers: config.headers().cloned(),
+                    http_version: config.http_version,
+                })
+                .left_future(),
+            (HealthProbe::Head, _) => return None,
+            (HealthProbe::Echo { destination }, _) => {
+                let address = self.data.connector.address();
+                let endpoint = config
+                    .endpoint(address.as_addr(), destination.as_addr())
+                    .ok()?;
+                let data = serialize_echo_request(address.as_ref());
+                let prepare = ilp::PrepareBuilder {
+                    amount: 0,
+                    expires_at: time::SystemTime::now() + health_check.timeout,
+                    // The next hop's echo service rejects repeated conditions
+                    // as loops.
+                    execution_condition: &random_condition(),
+                    destination: destination.as_addr(),
+                    data: &data,
+                }.build();
+                let request = self.client.send(NextHopRequest {
+                    endpoint,
+                    is_btp: config.is_btp(),
+                    auth,
+                    headers: config.headers().cloned(),
+                    retry: Arc::new(RetryPolicy {
+                        max_attempts: 1,
+                        ..RetryPolicy::default()
+                    }),
+                    http_version: config.http_version,
+                    response_timeout: None,
+                    request_id: RequestId::generate(),
+                    prepare,
+                });
+                request.map_ok(|_response| ()).right_future()
+            },
+        };
+        let timeout = health_check.timeout;
+        Some(async move {
+            match tokio::time::timeout(timeout, request).await {
+                Ok(result) => result.is_ok(),
+                Err(_elapsed) => false,
+            }
+        })
+    }
+
+    /// Put the routes with the `account` in maintenance (or take them out of
+    /// it, overriding their config). Returns `false` when there is no such
+    /// route.
+    pub fn set_maintenance(
+        &self,
+        account: &str,
+        maintenance: Option<RouteMaintenance>,
+    ) -> bool {
+        let mut overrides = self.data.maintenance.lock().unwrap();
+        let routes = self.data.routes.read().unwrap();
+        if !routes.set_maintenance(account, maintenance.as_ref()) {
+            return false;
+        }
+        info!(
+            "route maintenance: account={} maintenance={:?}",
+            account, maintenance,
+        );
+        overrides.insert(account.to_owned(), maintenance);
+        true
+    }
+
+    /// The routes that are in maintenance, by account.
+    pub fn maintenance(&self) -> BTreeMap<String, RouteMaintenance> {
+        self.data.routes.read().unwrap().maintenance()
+    }
+
+    pub(crate) fn get_account(&self, route_index: RouteIndex) -> Arc<String> {
+        let routes = self.data.routes.read().unwrap();
+        Arc::clone(&routes[route_index].config.account)
+    }
+
+    /// Add the amount that was forwarded to the route (and fulfilled, with a
+    /// valid fulfillment) to its account's payable balance.
+    pub(crate) fn record_fulfill(&self, route_index: RouteIndex, amount: u64) {
+        let settlement = self.data.settlement.read().unwrap();
+        if let Some(settlement) = settlement.as_ref() {
+            settlement.record_payable(&self.get_account(route_index), amount);
+        }
+    }
+
+    /// Whether the route is the catch-all route (its `target_prefix` is empty).
+    pub(crate) fn is_catch_all(&self, route_index: RouteIndex) -> bool {
+        let routes = self.data.routes.read().unwrap();
+        routes[route_index].config.target_prefix.is_empty()
+    }
+
+    pub(crate) fn forward(self, prepare: ilp::Prepare, request_id: RequestId)
+        //-> impl Future<Output = Result<ilp::Fulfill, ilp::Reject>>
+        -> impl Future<Output = ResponseWithRoute>
+    {
+        fn fail(reject: ilp::Reject) -> future::Ready<ResponseWithRoute> {
+            future::ready(ResponseWithRoute::from(Err(reject)))
+        }
+
+        let routes = self.data.routes.read().unwrap();
+        let (route_index, route) = match routes.resolve(&prepare) {
+            Ok((i, route)) => (i, route),
+            Err(RoutingError::NoRoute) => {
+                debug!(
+                    "no route exists: request_id={} destination=\"{}\"",
+                    request_id, prepare.destination(),
+                );
+                return Either::Right(fail(self.make_destination_reject(
+                    &reject_reasons::NO_ROUTE,
+                    prepare.destination(),
+                )));
+            },
+            Err(RoutingError::NoHealthyRoute) => {
+                debug!(
+                    "no healthy route found: request_id={} destination=\"{}\"",
+                    request_id, prepare.destination(),
+                );
+                return Either::Right(fail(self.make_destination_reject(
+                    &reject_reasons::NO_HEALTHY_ROUTE,
+                    prepare.destination(),
+                )));
+            },
+        };
+        if let Some(reject) =
+            route.maintenance_reject(self.data.connector.address().as_addr())
+        {
+            debug!(
+                "route in maintenance: request_id={} destination=\"{}\" account={}",
+                request_id, prepare.destination(), route.config.account,
+            );
+            return Either::Right(future::ready(ResponseWithRoute {
+                packet: Err(reject),
+                route: Some(route_index),
+                amount: None,
+            }));
+        }
+        if let Some(max_amount) = route.config.max_packet_amount {
+            if prepare.amount() > max_amount {
+                debug!(
+                    "amount too large: request_id={} destination=\"{}\" amount={} max_amount={}",
+                    request_id, prepare.destination(), prepare.amount(), max_amount,
+                );
+                let details = ilp::MaxPacketAmountDetails::new(
+                    prepare.amount(),
+                    max_amount,
+                );
+                return Either::Right(fail({
+                    reject_reasons::AMOUNT_TOO_LARGE.to_reject_with_data(
+                        self.data.connector.address().as_addr(),
+                        &details.to_bytes(),
+                    )
+                }));
+            }
+        }
+
+        if let Some(packet) =
+            route.config.static_response(self.data.connector.address().as_addr())
+        {
+            debug!(
+                "static response: request_id={} destination=\"{}\" account={} is_fulfill={}",
+                request_id, prepare.destination(), route.config.account, packet.is_ok(),
+            );
+            return Either::Right(future::ready(ResponseWithRoute {
+                packet,
+                route: Some(route_index),
+                amount: None,
+            }));
+        }
+
+        let next_hop = route.config.endpoint(
+            self.data.connector.address().as_addr(),
+            prepare.destination(),
+        );
+        let next_hop = match next_hop {
+            Ok(endpoint) => endpoint,
+            Err(error) => {
+                warn!(
+                    "error generating endpoint: request_id={} error={}",
+                    request_id, error,
+                );
+                return Either::Right(fail(self.make_reject(&reject_reasons::INVALID_ADDRESS_SEGMENT)));
+            },
+        };
+
+        if self.data.simulation.is_enabled() {
+            info!(
+                "simulation: request_id={} destination=\"{}\" amount={} account={} next_hop={}",
+                request_id, prepare.destination(), prepare.amount(),
+                route.config.account, next_hop,
+            );
+            return Either::Right(future::ready(ResponseWithRoute {
+                packet: Err(reject_reasons::SIMULATION.to_reject_with_data(
+                    self.data.connector.address().as_addr(),
+                    route.config.account.as_bytes(),
+                )),
+                route: Some(route_index),
+                amount: None,
+            }));
+        }
+
+        let hedge = route.config.hedging.as_ref().and_then(|hedging| {
+            let (alternate_index, alternate) =
+                routes.resolve_alternate(route_index)?;
+            if let Some(max_amount) = alternate.config.max_packet_amount {
+                if prepare.amount() > max_amount {
+                    return None;
+                }
+            }
+            let alternate_hop = alternate.config.endpoint(
+                self.data.connector.address().as_addr(),
+                prepare.destination(),
+            ).ok()?;
+            let alternate_prepare =
+                alternate.config.rewrite_destination(prepare.clone()).ok()?;
+            let alternate_prepare =
+                self.exchange(&alternate.config, alternate_prepare).ok()?;
+            Some((hedging.delay, self.request(
+                alternate_index,
+                alternate,
+                alternate_hop,
+                request_id.clone(),
+                alternate_prepare,
+            )))
+        });
+        // The endpoint (and telemetry) use the original destination; only the
+        // forwarded Prepare is rewritten.
+        let prepare = match route.config.rewrite_destination(prepare) {
+            Ok(prepare) => prepare,
+            Err(error) => {
+                warn!(
+                    "error rewriting destination: request_id={} account={} error={}",
+                    request_id, route.config.account, error,
+                );
+                return Either::Right(fail(self.make_reject(&reject_reasons::INVALID_REWRITTEN_DESTINATION)));
+            },
+        };
+        let source_amount = prepare.amount();
+        let prepare = match self.exchange(&route.config, prepare) {
+            Ok(prepare) => prepare,
+            Err(reason) if *reason == reject_reasons::INSUFFICIENT_SOURCE_AMOUNT => {
+                debug!(
+                    "amount too small to exchange: request_id={} account={} amount={}",
+                    request_id, route.config.account, source_amount,
+                );
+                return Either::Right(fail({
+                    self.insufficient_amount_reject(&route.config, source_amount)
+                }));
+            },
+            Err(reason) => {
+                warn!(
+                    "error converting amount: request_id={} account={} reason={}",
+                    request_id, route.config.account, reason.id,
+                );
+                return Either::Right(fail(self.make_reject(reason)));
+            },
+        };
+        let do_request =
+            self.request(route_index, route, next_hop, request_id, prepare);
+        // Don't hold onto the table mutex during the HTTP request.
+        std::mem::drop(routes);
+
+        Either::Left(match hedge {
+            None => do_request.left_future(),
+            Some((delay, alternate)) => hedge_request(
+                self.data.connector.address(),
+                delay,
+                do_request,
+                alternate,
+            ).right_future(),
+        })
+    }
+
+    /// Send the Prepare to the route's next hop. If the route has `failover`,
+    /// its health is updated with the response.
+    fn request(
+        &self,
+        route_index: RouteIndex,
+        route: &DynamicRoute,
+        next_hop: Endpoint,
+        request_id: RequestId,
+        prepare: ilp::Prepare,
+    ) -> impl Future<Output = ResponseWithRoute> {
+        let has_failover = route.config.failover.is_some();
+        let exchange = route.config.exchange.clone();
+        let slots = match (&route.in_flight, &route.config.concurrency) {
+            (Some(slots), Some(limit)) => Some((
+                Arc::clone(slots),
+                limit.min_expiry,
+                Arc::clone(&route.config.account),
+            )),
+            _ => None,
+        };
+        let expires_at = prepare.expires_at();
+        let amount = prepare.amount();
+        let service_data = Arc::clone(&self.data);
+        let client = Arc::clone(&self.client);
+        let next_hop_request = NextHopRequest {
+            endpoint: next_hop,
+            is_btp: route.config.is_btp(),
+            auth: route.config.auth().cloned(),
+            headers: route.config.headers().cloned(),
+            retry: Arc::clone(&route.config.retry),
+            http_version: route.config.http_version,
+            response_timeout: route.config.response_timeout,
+            request_id: request_id.clone(),
+            prepare,
+        };
+        let do_request = {
+            let service_data = Arc::clone(&service_data);
+            async move {
+                // The slot is held until the response is received.
+                let _slot = match slots {
+                    None => None,
+                    Some((slots, min_expiry, account)) => {
+                        let slot =
+                            wait_for_slot(slots, expires_at, min_expiry).await;
+                        if slot.is_none() {
+                            debug!(
+                                "next hop is busy: request_id={} account={} min_expiry={:?}",
+                                request_id, account, min_expiry,
+                            );
+                            // The next hop wasn't contacted, so this isn't a
+                            // `NextHopError` (and doesn't affect its health).
+                            return Ok(Err(reject_reasons::NEXT_HOP_BUSY
+                                .to_reject(service_data.connector.address().as_addr())));
+                        }
+                        slot
+                    },
+                };
+                let start = time::Instant::now();
+                let result = client.send(next_hop_request).await;
+                if result.is_ok() {
+                    service_data.routes
+                        .read()
+                        .unwrap()
+                        .record_latency(route_index, start.elapsed());
+                }
+                result
+            }
+        };
+        do_request
+            .map(move |result: Result<_, NextHopError>| {
+                if has_failover {
+                    let is_success = match &result {
+                        Ok(_response) => true,
+                        Err(error) => !error.is_peer_unreachable(),
+                    };
+                    service_data.routes
+                        .read()
+                        .unwrap()
+                        .update(route_index, is_success)
+                }
+                let packet = result.unwrap_or_else(|error| {
+                    Err(error.to_reject(service_data.connector.address().as_addr()))
+                });
+                ResponseWithRoute {
+                    packet: match &exchange {
+                        Some(exchange) => packet.map_err(|reject| {
+                            service_data.exchange_reject(exchange, reject)
+                        }),
+                        None => packet,
+                    },
+                    route: Some(route_index),
+                    amount: Some(amount),
+                }
+            })
+    }
+
+    /// Convert the Prepare's amount to the route's asset, if it has an
+    /// `exchange`. Returns the original amount if it can't be converted.
+    fn exchange(&self, route: &StaticRoute, mut prepare: ilp::Prepare)
+        -> Result<ilp::Prepare, &'static RejectReason>
+    {
+        let exchange = match &route.exchange {
+            Some(exchange) => exchange,
+            None => return Ok(prepare),
+        };
+        let (asset_scale, rate) = self.data.exchange_rate(exchange)?;
+        let new_amount = exchange
+            .convert(prepare.amount(), asset_scale, rate)
+            .ok_or(&reject_reasons::EXCHANGE_FAILED)?;
+        // Don't forward value as a zero-amount Prepare.
+        if new_amount == 0 && prepare.amount() != 0 {
+            return Err(&reject_reasons::INSUFFICIENT_SOURCE_AMOUNT);
+        }
+        prepare.set_amount(new_amount);
+        Ok(prepare)
+    }
+
+    /// An `R01` Reject with the smallest amount that the route's `exchange`
+    /// doesn't round down to zero.
+    fn insufficient_amount_reject(&self, route: &StaticRoute, amount: u64)
+        -> ilp::Reject
+    {
+        let min_amount = route.exchange.as_ref()
+            .and_then(|exchange| {
+                let (asset_scale, rate) =
+                    self.data.exchange_rate(exchange).ok()?;
+                // `convert_back` rounds down, so it may be one short.
+                let min_amount = exchange.convert_back(1, asset_scale, rate)?;
+                match exchange.convert(min_amount, asset_scale, rate)? {
+                    0 => min_amount.checked_add(1),
+                    _ => Some(min_amount),
+                }
+            })
+            .unwrap_or_else(|| amount.saturating_add(1));
+        let details = ilp::InsufficientAmountDetails::new(amount, min_amount);
+        reject_reasons::INSUFFICIENT_SOURCE_AMOUNT.to_reject_with_data(
+            self.data.connector.address().as_addr(),
+            &details.to_bytes(),
+        )
+    }
+
+    fn make_reject(&self, reason: &RejectReason) -> ilp::Reject {
+        reason.to_reject(self.data.connector.address().as_addr())
+    }
+
+    fn make_destination_reject(
+        &self,
+        reason: &RejectReason,
+        destination: ilp::Addr,
+    ) -> ilp::Reject {
+        reason.to_reject_with_diagnostics(
+            self.data.connector.address().as_addr(),
+            &[("destination", &destination.to_string())],
+        )
+    }
+}
+
+impl ServiceData {
+    /// The connector's (ILDCP) asset scale, and the rate of the route's
+    /// `exchange`. Until the asset scale is known, Prepares to routes with an
+    /// `exchange` are rejected.
+    fn exchange_rate(&self, exchange: &RouteExchange)
+        -> Result<(u8, f64), &'static RejectReason>
+    {
+        let asset_scale = self.connector
+            .asset_scale()
+            .ok_or(&reject_reasons::EXCHANGE_FAILED)?;
+        let asset_code = match &exchange.asset_code {
+            Some(asset_code) => asset_code,
+            None => return Ok((asset_scale, exchange.rate)),
+        };
+        let rates = self.exchange_rates.read().unwrap();
+        let rate = rates.as_ref()
+            .ok_or(&reject_reasons::EXCHANGE_RATE_UNAVAILABLE)?
+            .get(asset_code, time::Instant::now())
+            .map_err(|_error| &reject_reasons::EXCHANGE_RATE_UNAVAILABLE)?;
+        Ok((asset_scale, rate))
+    }
+
+    /// Convert the amounts of the next hop's `F08` Reject back to the
+    /// connector's asset, so that the sender can adjust its packet size.
+    fn exchange_reject(&self, exchange: &RouteExchange, reject: ilp::Reject)
+        -> ilp::Reject
+    {
+        if reject.code() != ilp::ErrorCode::F08_AMOUNT_TOO_LARGE {
+            return reject;
+        }
+        let details = match ilp::MaxPacketAmountDetails::from_bytes(reject.data()) {
+            Ok(details) => details,
+            Err(_error) => return reject,
+        };
+        let (asset_scale, rate) = match self.exchange_rate(exchange) {
+            Ok(scale_and_rate) => scale_and_rate,
+            Err(_reason) => return reject,
+        };
+        let convert_back = |amount| {
+            exchange.convert_back(amount, asset_scale, rate).unwrap_or(u64::MAX)
+        };
+        let details = ilp::MaxPacketAmountDetails::new(
+            convert_back(details.amount_received()),
+            convert_back(details.max_amount()),
+        );
+        ilp::RejectBuilder {
+            code: reject.code(),
+            message: reject.message(),
+            triggered_by: reject.triggered_by(),
+            data: &details.to_bytes(),
+        }.build()
+    }
+}
+
+fn random_condition() -> [u8; 32] {
+    use ring::rand::SecureRandom;
+    let mut condition = [0; 32];
+    ring::rand::SystemRandom::new()
+        .fill(&mut condition)
+        .expect("probe condition generation error");
+    condition
+}
+
+/// Wait until the (wall clock) time. The timer measures monotonic time (and
+/// can't wait for years), so this wakes up at least hourly to check the clock.
+async fn delay_until(time: time::SystemTime) {
+    let max_delay = time::Duration::from_secs(3600);
+    while let Ok(remaining) = time.duration_since(time::SystemTime::now()) {
+        if remaining == time::Duration::from_secs(0) {
+            break;
+        }
+        tokio::time::delay_for(remaining.min(max_delay)).await;
+    }
+}
+
+/// Wait (in FIFO order) for a slot to send a request to the next hop. Gives up
+/// once the Prepare would expire within `min_expiry`, though a slot that is
+/// free right away is always taken.
+async fn wait_for_slot(
+    slots: Arc<Semaphore>,
+    expires_at: time::SystemTime,
+    min_expiry: time::Duration,
+) -> Option<OwnedSemaphorePermit> {
+    let max_wait = expires_at
+        .duration_since(time::SystemTime::now())
+        .ok()
+        .and_then(|expires_in| expires_in.checked_sub(min_expiry))
+        .unwrap_or_default();
+    tokio::time::timeout(max_wait, slots.acquire_owned()).await.ok()
+}
+
+/// Send the `primary` request, and if it hasn't been answered after `delay`,
+/// the `alternate` request too. The first response is used, unless it is from
+/// an unreachable next hop, in which case the other response is awaited.
+async fn hedge_request<F>(
+    connector_address: ilp::Address,
+    delay: time::Duration,
+    primary: F,
+    alternate: F,
+) -> ResponseWithRoute
+where
+    F: Future<Output = ResponseWithRoute>,
+{
+    futures::pin_mut!(primary);
+    let primary = match future::select(primary, tokio::time::delay_for(delay)).await {
+        Either::Left((response, _delay)) => return response,
+        Either::Right(((), primary)) => primary,
+    };
+    debug!("hedging request: delay={:?}", delay);
+    futures::pin_mut!(alternate);
+    let (response, other) = future::select(primary, alternate)
+        .await
+        .factor_first();
+    if response_is_ok(connector_address.as_addr(), &response.packet) {
+        response
+    } else {
+        other.await
+    }
+}
+
+fn response_is_ok(
+    connector_address: ilp::Addr,
+    response: &Result<ilp::Fulfill, ilp::Reject>,
+) -> bool {
+    let is_unhealthy = match response {
+        Ok(_) => false,
+        Err(reject) => {
+            // Corresponds to a 5xx error and connection errors.
+            reject.code() == ilp::ErrorCode::T01_PEER_UNREACHABLE
+                && reject.triggered_by() == Some(connector_address)
+        },
+    };
+    !is_unhealthy
+}
+
+#[cfg(test)]
+mod test_router_service {
+    use bytes::Bytes;
+
+    use crate::services::{HealthCheck, HedgingPolicy, NextHop, RouteExchange, RouteFailover, RoutingPartition, StaticResponse, StaticRoute};
+    use crate::services::RouteStatus;
+    use crate::testing::{self, ADDRESS, MockClient, RECEIVER_ORIGIN, ROUTES};
+    use crate::transport::{AuthToken, Headers};
+    use super::super::table::RouteIndex;
+    use super::*;
+
+    fn make_router(client: &MockClient, routes: Vec<StaticRoute>) -> RouterService {
+        RouterService::new(
+            ADDRESS.to_address(),
+            Arc::new(client.clone()),
+            RoutingTable::new(routes, RoutingPartition::default()),
+            false,
+        )
+    }
+
+    fn fulfill_client() -> MockClient {
+        MockClient::with_response(Ok(testing::FULFILL.clone()))
+    }
+
+    /// For the tests where the next hop must not be contacted.
+    fn no_client() -> MockClient {
+        MockClient::new(|request| panic!("unexpected request: {:?}", request))
+    }
+
+    /// The next hop responded with a 5xx.
+    fn unreachable_error() -> NextHopError {
+        NextHopError::new(
+            &reject_reasons::PEER_INTERNAL_ERROR,
+            true,
+            "unexpected status: 500",
+        )
+    }
+
+    #[tokio::test]
+    async fn test_outgoing_request_bilateral() {
+        let client = fulfill_client();
+        let router = make_router(&client, ROUTES.clone());
+        let result = router.call(testing::PREPARE.clone()).await;
+        assert_eq!(result.unwrap(), *testing::FULFILL);
+
+        let requests = client.requests();
+        assert_eq!(requests.len(), 1);
+        let request = &requests[0];
+        assert_eq!(request.endpoint.as_str(), format!("{}/alice", RECEIVER_ORIGIN));
+        assert!(!request.is_btp);
+        assert_eq!(request.auth, Some(AuthToken::new("alice_auth")));
+        assert_eq!(request.prepare, *testing::PREPARE);
+    }
+
+    #[tokio::test]
+    async fn test_outgoing_request_headers() {
+        let mut headers = Headers::default();
+        headers.insert("x-api-key", "api_secret").unwrap();
+        let client = fulfill_client();
+        let router = make_router(&client, vec![
+            StaticRoute {
+                next_hop: NextHop::Bilateral {
+                    endpoint: format!("{}/alice", RECEIVER_ORIGIN).parse().unwrap(),
+                    auth: None,
+                    headers,
+                },
+                ..ROUTES[0].clone()
+            },
+        ]);
+        let result = router.call(testing::PREPARE.clone()).await;
+        assert_eq!(result.unwrap(), *testing::FULFILL);
+
+        let request = &client.requests()[0];
+        assert_eq!(request.auth, None);
+        let headers = request.headers.as_ref().unwrap();
+        assert_eq!(headers.get("X-Api-Key").unwrap().as_ref(), b"api_secret");
+    }
+
+    #[tokio::test]
+    async fn test_mark_as_unhealthy() {
+        let client = MockClient::new(|_request| Some(Err(unreachable_error())));
+        let router = make_router(&client, vec![
+            StaticRoute {
+                failover: Some(RouteFailover {
+                    window_size: 20,
+                    fail_ratio: 0.01,
+                    fail_duration: std::time::Duration::from_secs(5),
+                }),
+                ..ROUTES[0].clone()
+            },
+        ]);
+        let reject = router.clone()
+            .call(testing::PREPARE.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(reject, reject_reasons::PEER_INTERNAL_ERROR.to_reject(ADDRESS));
+        let table = router.data.routes.read().unwrap();
+        let route = &table[RouteIndex {
+            group_index: 0,
+            route_index: 0,
+        }];
+        assert_eq!(route.is_available(), false);
+    }
+
+    #[tokio::test]
+    async fn test_outgoing_request_multilateral() {
+        let client = fulfill_client();
+        let router = make_router(&client, ROUTES.clone());
+        let result = router.call(testing::PREPARE_MULTILATERAL.clone()).await;
+        assert_eq!(result.unwrap(), *testing::FULFILL);
+
+        let request = &client.requests()[0];
+        assert_eq!(
+            request.endpoint.as_str(),
+            format!("{}/bob/1234/ilp", RECEIVER_ORIGIN),
+        );
+        assert_eq!(request.auth, Some(AuthToken::new("bob_auth")));
+    }
+
+    #[tokio::test]
+    async fn test_rewrite_destination() {
+        let client = fulfill_client();
+        let router = make_router(&client, vec![
+            StaticRoute {
+                rewrite_prefix: Some(Bytes::from("test.new.alice.")),
+                ..ROUTES[0].clone()
+            },
+        ]);
+        let result = router.call(testing::PREPARE.clone()).await;
+        assert_eq!(result.unwrap(), *testing::FULFILL);
+
+        // The endpoint uses the original destination.
+        let request = &client.requests()[0];
+        assert_eq!(request.endpoint.as_str(), format!("{}/alice", RECEIVER_ORIGIN));
+        let prepare = &request.prepare;
+        assert_eq!(
+            prepare.destination(),
+            ilp::Addr::new(b"test.new.alice.1234"),
+        );
+        assert_eq!(prepare.amount(), testing::PREPARE.amount());
+        assert_eq!(prepare.data(), testing::PREPARE.data());
+    }
+
+    fn make_ildcp_response(asset_scale: u8) -> ilp::ildcp::Response {
+        ilp::ildcp::ResponseBuilder {
+            client_address: ADDRESS,
+            asset_scale,
+            asset_code: b"XRP",
+        }.build()
+    }
+
+    #[tokio::test]
+    async fn test_exchange() {
+        let details = ilp::MaxPacketAmountDetails::new(123 * 200, 1_000);
+        let reject = ilp::RejectBuilder {
+            code: ilp::ErrorCode::F08_AMOUNT_TOO_LARGE,
+            message: b"too much",
+            triggered_by: Some(ilp::Addr::new(b"test.alice")),
+            data: &details.to_bytes(),
+        }.build();
+        let client = MockClient::with_response(Err(reject));
+        // The router shares the snapshot of the connector's config.
+        let connector = ConnectorInfo::from(ADDRESS.to_address());
+        let router = RouterService::new(
+            connector.clone(),
+            Arc::new(client.clone()),
+            RoutingTable::new(vec![
+                StaticRoute {
+                    exchange: Some(RouteExchange {
+                        asset_scale: 2,
+                        asset_code: None,
+                        rate: 2.0,
+                        spread: 0.0,
+                    }),
+                    ..ROUTES[0].clone()
+                },
+            ], RoutingPartition::default()),
+            false,
+        );
+        // Without the connector's asset scale, the amount can't be converted.
+        let reject = router.clone()
+            .call(testing::PREPARE.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(reject, reject_reasons::EXCHANGE_FAILED.to_reject(ADDRESS));
+        assert!(client.requests().is_empty());
+
+        connector.set(make_ildcp_response(0));
+        let reject = router
+            .call(testing::PREPARE.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(client.requests()[0].prepare.amount(), 123 * 200);
+        assert_eq!(reject.code(), ilp::ErrorCode::F08_AMOUNT_TOO_LARGE);
+        assert_eq!(reject.message(), b"too much");
+        assert_eq!(reject.triggered_by(), Some(ilp::Addr::new(b"test.alice")));
+        // The amounts are converted back to the connector's asset.
+        let details =
+            ilp::MaxPacketAmountDetails::from_bytes(reject.data()).unwrap();
+        assert_eq!(details.amount_received(), 123);
+        assert_eq!(details.max_amount(), 5);
+    }
+
+    #[tokio::test]
+    async fn test_exchange_insufficient_amount() {
+        let connector = ConnectorInfo::from(ADDRESS.to_address());
+        let router = RouterService::new(
+            connector.clone(),
+            Arc::new(no_client()),
+            RoutingTable::new(vec![
+                StaticRoute {
+                    exchange: Some(RouteExchange {
+                        asset_scale: 2,
+                        asset_code: None,
+                        rate: 0.25,
+                        spread: 0.0,
+                    }),
+                    ..ROUTES[0].clone()
+                },
+            ], RoutingPartition::default()),
+            false,
+        );
+        connector.set(make_ildcp_response(2));
+        let mut prepare = testing::PREPARE.clone();
+        prepare.set_amount(3);
+        let reject = router.call(prepare).await.unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::R01_INSUFFICIENT_SOURCE_AMOUNT);
+        let details =
+            ilp::InsufficientAmountDetails::from_bytes(reject.data()).unwrap();
+        assert_eq!(details.amount_received(), 3);
+        assert_eq!(details.min_amount(), 4);
+    }
+
+    #[tokio::test]
+    async fn test_no_route_exists() {
+        let expect_reject = reject_reasons::NO_ROUTE.to_reject_with_diagnostics(
+            ADDRESS,
+            &[("destination", &testing::PREPARE.destination().to_string())],
+        );
+        let router = make_router(&no_client(), vec![ROUTES[1].clone()]);
+        let result = router.call(testing::PREPARE.clone()).await;
+        assert_eq!(result.unwrap_err(), expect_reject);
+    }
+
+    #[tokio::test]
+    async fn test_max_packet_amount() {
+        let router = make_router(&no_client(), vec![
+            StaticRoute {
+                max_packet_amount: Some(testing::PREPARE.amount() - 1),
+                concurrency: None,
+                ..ROUTES[0].clone()
+            },
+        ]);
+        let reject = router
+            .call(testing::PREPARE.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F08_AMOUNT_TOO_LARGE);
+        assert_eq!(reject.triggered_by(), Some(ADDRESS));
+        let details =
+            ilp::MaxPacketAmountDetails::from_bytes(reject.data()).unwrap();
+        assert_eq!(details.amount_received(), testing::PREPARE.amount());
+        assert_eq!(details.max_amount(), testing::PREPARE.amount() - 1);
+    }
+
+    #[tokio::test]
+    async fn test_max_packet_amount_equal() {
+        let client = fulfill_client();
+        let router = make_router(&client, vec![
+            StaticRoute {
+                max_packet_amount: Some(testing::PREPARE.amount()),
+                concurrency: None,
+                ..ROUTES[0].clone()
+            },
+        ]);
+        let result = router.call(testing::PREPARE.clone()).await;
+        assert_eq!(result.unwrap(), *testing::FULFILL);
+        assert_eq!(client.requests().len(), 1);
+    }
+
+    #[test]
+    fn test_static_response() {
+        let mut fulfillment = [0; 32];
+        fulfillment.copy_from_slice(testing::FULFILL.fulfillment());
+        let router = make_router(&no_client(), vec![
+            StaticRoute::new(
+                Bytes::from("test.alice."),
+                "alice",
+                NextHop::Static {
+                    response: StaticResponse::Fulfill { fulfillment },
+                    data: Bytes::from("fulfill data"),
+                },
+            ),
+        ]);
+        let response =
+            futures::executor::block_on(router.forward(testing::PREPARE.clone(), RequestId::generate()));
+        assert_eq!(response.packet.unwrap(), *testing::FULFILL);
+        assert!(response.route.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_simulation_mode() {
+        let client = fulfill_client();
+        let router = RouterService::new(
+            ADDRESS.to_address(),
+            Arc::new(client.clone()),
+            RoutingTable::new(ROUTES.clone(), RoutingPartition::default()),
+            true,
+        );
+        let response = router.clone()
+            .forward(testing::PREPARE.clone(), RequestId::generate())
+            .await;
+        assert!(response.route.is_some());
+        let reject = response.packet.unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F02_UNREACHABLE);
+        assert_eq!(reject.message(), reject_reasons::SIMULATION.message.as_bytes());
+        assert_eq!(reject.triggered_by(), Some(ADDRESS));
+        assert_eq!(reject.data(), b"alice");
+        assert!(client.requests().is_empty());
+
+        // Go live:
+        router.simulation_toggle().set(false);
+        let result = router.call(testing::PREPARE.clone()).await;
+        assert_eq!(result.unwrap(), *testing::FULFILL);
+        assert_eq!(client.requests().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_maintenance() {
+        let maintenance = RouteMaintenance {
+            code: ilp::ErrorCode::T00_INTERNAL_ERROR,
+            message: "back soon".to_owned(),
+        };
+        let route = StaticRoute {
+            maintenance: Some(maintenance.clone()),
+            ..ROUTES[0].clone()
+        };
+        let client = fulfill_client();
+        let router = make_router(&client, vec![route.clone()]);
+        assert_eq!(
+            router.maintenance().into_iter().collect::<Vec<_>>(),
+            vec![("alice".to_owned(), maintenance)],
+        );
+        assert!(!router.set_maintenance("bob", None));
+
+        // The next hop isn't contacted.
+        let reject = router.clone()
+            .call(testing::PREPARE.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::T00_INTERNAL_ERROR);
+        assert_eq!(reject.message(), b"back soon");
+        assert_eq!(reject.triggered_by(), Some(ADDRESS));
+        assert_eq!(reject.data(), b"route_maintenance");
+        assert!(client.requests().is_empty());
+
+        // Taking the route out of maintenance outlasts a new routing table
+        // (with the same config).
+        assert!(router.set_maintenance("alice", None));
+        router.set_routes(RoutingTable::new(
+            vec![route],
+            RoutingPartition::default(),
+        ));
+        assert!(router.maintenance().is_empty());
+        let fulfill = router.clone()
+            .call(testing::PREPARE.clone())
+            .await
+            .unwrap();
+        assert_eq!(fulfill, *testing::FULFILL);
+    }
+
+    #[tokio::test]
+    async fn test_schedule_routes_rollback() {
+        let router = make_router(&fulfill_client(), vec![ROUTES[0].clone()]);
+        // The scheduled routes have no route to the Prepare's destination.
+        router.schedule_routes(
+            time::SystemTime::now(),
+            RoutingTable::new(vec![ROUTES[1].clone()], RoutingPartition::default()),
+            Some(RollbackPolicy {
+                max_error_rate: 0.5,
+                window: time::Duration::from_secs(60),
+                min_prepares: 2,
+            }),
+        );
+        let status = router.schedule().unwrap();
+        assert_eq!(status.state, ScheduleState::Watching);
+
+        let expect_reject = reject_reasons::NO_ROUTE
+            .to_reject_with_diagnostics(ADDRESS, &[(
+                "destination",
+                &testing::PREPARE.destination().to_string(),
+            )]);
+        for _ in 0..2 {
+            assert_eq!(
+                router.clone().call(testing::PREPARE.clone()).await,
+                Err(expect_reject.clone()),
+            );
+        }
+        let status = router.schedule().unwrap();
+        assert_eq!(status.state, ScheduleState::RolledBack);
+        assert_eq!((status.prepares, status.errors), (2, 2));
+
+        // The previous routes are restored.
+        assert_eq!(
+            router.clone().call(testing::PREPARE.clone()).await,
+            Ok(testing::FULFILL.clone()),
+        );
+        assert_eq!(router.schedule().unwrap().prepares, 2);
+    }
+
+    #[tokio::test]
+    async fn test_set_routes() {
+        let client = fulfill_client();
+        let router = make_router(&client, ROUTES.clone());
+        router.set_routes(RoutingTable::new(vec![
+            StaticRoute::new(
+                Bytes::from("test.alice."),
+                "alice",
+                NextHop::Bilateral {
+                    endpoint: format!("{}/new_alice", RECEIVER_ORIGIN).parse().unwrap(),
+                    auth: None,
+                    headers: Headers::default(),
+                },
+            ),
+        ], RoutingPartition::default()));
+        let result = router.call(testing::PREPARE.clone()).await;
+        assert_eq!(result.unwrap(), *testing::FULFILL);
+        assert_eq!(
+            client.requests()[0].endpoint.as_str(),
+            format!("{}/new_alice", RECEIVER_ORIGIN),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_hedging() {
+        let slow_endpoint = format!("{}/slow", RECEIVER_ORIGIN);
+        // The primary next hop never responds.
+        let client = MockClient::new({
+            let slow_endpoint = slow_endpoint.clone();
+            move |request| {
+                if request.endpoint.as_str() == slow_endpoint {
+                    None
+                } else {
+                    Some(Ok(Ok(testing::FULFILL.clone())))
+                }
+            }
+        });
+        let router = make_router(&client, vec![
+            StaticRoute {
+                next_hop: NextHop::Bilateral {
+                    endpoint: slow_endpoint.parse().unwrap(),
+                    auth: None,
+                    headers: Headers::default(),
+                },
+                hedging: Some(HedgingPolicy {
+                    delay: time::Duration::from_millis(10),
+                }),
+                ..ROUTES[0].clone()
+            },
+            StaticRoute {
+                partition: 0.0,
+                ..ROUTES[0].clone()
+            },
+        ]);
+        let response = router.forward(testing::PREPARE.clone(), RequestId::generate()).await;
+        assert_eq!(response.packet.unwrap(), *testing::FULFILL);
+        assert_eq!(response.route, Some(RouteIndex::new(0, 1)));
+        let endpoints = client.requests()
+            .into_iter()
+            .map(|request| request.endpoint.as_str().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(endpoints, vec![
+            slow_endpoint,
+            format!("{}/alice", RECEIVER_ORIGIN),
+        ]);
+    }
+
+    fn make_probed_route(probe: HealthProbe) -> StaticRoute {
+        StaticRoute {
+            failover: Some(RouteFailover {
+                window_size: 20,
+                fail_ratio: 0.1,
+                fail_duration: time::Duration::from_secs(60),
+            }),
+            health_check: Some(HealthCheck {
+                interval: time::Duration::from_secs(10),
+                timeout: time::Duration::from_secs(1),
+                probe,
+            }),
+            ..ROUTES[0].clone()
+        }
+    }
+
+    #[tokio::test]
+    async fn test_health_check_head() {
+        let client = no_client();
+        let router = make_router(&client, vec![make_probed_route(HealthProbe::Head)]);
+        let until = time::Instant::now() + time::Duration::from_secs(60);
+        *router.data.routes.read().unwrap()[(0, 0)].status.write().unwrap() =
+            RouteStatus::Unhealthy { until };
+
+        // The successful probe restores the route right away.
+        router.send_probes(time::Instant::now());
+        tokio::time::delay_for(time::Duration::from_millis(100)).await;
+        assert!(router.data.routes.read().unwrap()[(0, 0)].is_available());
+
+        let probes = client.probes();
+        assert_eq!(probes.len(), 1);
+        assert_eq!(probes[0].endpoint.as_str(), format!("{}/alice", RECEIVER_ORIGIN));
+        assert_eq!(probes[0].auth, Some(AuthToken::new("alice_auth")));
+    }
+
+    #[tokio::test]
+    async fn test_health_check_echo() {
+        // The next hop rejects the echo Prepare, but `127.0.0.1:1` is down.
+        let client = MockClient::new(|request| {
+            if request.endpoint.as_str().starts_with("http://127.0.0.1:1/") {
+                Some(Err(NextHopError::new(
+                    &reject_reasons::PEER_CONNECTION_ERROR,
+                    true,
+                    "connection refused",
+                )))
+            } else {
+                Some(Ok(Err(testing::REJECT.clone())))
+            }
+        });
+        let echo = HealthProbe::Echo {
+            destination: ilp::Address::new(b"test.alice.probe"),
+        };
+        let router = make_router(&client, vec![make_probed_route(echo.clone())]);
+        // A Reject counts as a success.
+        let route = make_probed_route(echo.clone());
+        assert!(router.probe(&route).unwrap().await);
+        let request = &client.requests()[0];
+        assert_eq!(request.endpoint.as_str(), format!("{}/alice", RECEIVER_ORIGIN));
+        assert_eq!(request.retry.max_attempts, 1);
+        let prepare = &request.prepare;
+        assert_eq!(prepare.amount(), 0);
+        assert_eq!(
+            prepare.destination(),
+            ilp::Addr::new(b"test.alice.probe"),
+        );
+        assert_eq!(
+            prepare.data(),
+            serialize_echo_request(ADDRESS.as_ref()).as_ref(),
+        );
+
+        // An unreachable next hop doesn't.
+        let route = StaticRoute {
+            next_hop: NextHop::Bilateral {
+                endpoint: "http://127.0.0.1:1/".parse().unwrap(),
+                auth: None,
+                headers: Headers::default(),
+            },
+            ..make_probed_route(echo)
+        };
+        assert!(!router.probe(&route).unwrap().await);
+
+        // Static routes aren't probed.
+        let route = StaticRoute {
+            next_hop: NextHop::Static {
+                response: StaticResponse::Reject {
+                    code: ilp::ErrorCode::F02_UNREACHABLE,
+                    message: String::new(),
+                },
+                data: Bytes::new(),
+            },
+            ..make_probed_route(HealthProbe::Head)
+        };
+        assert!(router.probe(&route).is_none());
+        assert!(client.probes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn test_wait_for_slot() {
+        let slots = Arc::new(Semaphore::new(1));
+        let expires_at =
+            time::SystemTime::now() + time::Duration::from_millis(200);
+
+        // A free slot is taken, even within the `min_expiry`.
+        let slot = wait_for_slot(
+            Arc::clone(&slots),
+            expires_at,
+            time::Duration::from_secs(1),
+        ).await;
+        assert!(slot.is_some());
+
+        // Give up on a busy slot once the Prepare would expire within the
+        // `min_expiry`.
+        let start = time::Instant::now();
+        assert!(wait_for_slot(
+            Arc::clone(&slots),
+            expires_at,
+            time::Duration::from_millis(150),
+        ).await.is_none());
+        assert!(start.elapsed() < time::Duration::from_millis(150));
+
+        std::mem::drop(slot);
+        assert!(wait_for_slot(
+            slots,
+            expires_at,
+            time::Duration::from_millis(150),
+        ).await.is_some());
+    }
+}
@@ -0,0 +1,833 @@
+use std::error;
+use std::fmt;
+use std::sync::Arc;
+use std::time;
+
+use bytes::{BufMut, Bytes, BytesMut};
+use serde::{Deserialize, Serialize};
+
+use crate::AssetAmount;
+use crate::reject_reasons::ROUTE_MAINTENANCE;
+use crate::serde::{deserialize_error_code, deserialize_fulfillment, serialize_error_code};
+use crate::transport::{AuthToken, Endpoint, Headers, HttpVersion, InvalidEndpoint, RetryPolicy};
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct StaticRoute {
+    pub target_prefix: Bytes,
+    pub next_hop: NextHop,
+    /// A label for the route. This is tagged as the `to_account` when a packet
+    /// is logged by the telemetry service.
+    pub account: Arc<String>,
+    pub failover: Option<RouteFailover>,
+    /// Positive shares of the packets. For example, given the following routes
+    /// to a destination.
+    /// - *A*: `partition: 2.0`
+    /// - *B*: `partition: 1.0`
+    /// - *C*: `partition: 1.0`
+    /// Assuming all three routes are available:
+    /// - Route *A* receives 50% of all Prepares.
+    /// - Route *B* receives 25% of all Prepares.
+    /// - Route *C* receives the remaining 25% of all prepares.
+    ///
+    /// If the partitions of all hops to a destination sum to `1.0`, the individual
+    /// partition values can be interpreted as the fraction of packets assigned.
+    pub partition: f64,
+    /// Prepares with a larger amount are rejected with `F08_AMOUNT_TOO_LARGE`.
+    pub max_packet_amount: Option<u64>,
+    pub concurrency: Option<ConcurrencyLimit>,
+    pub retry: Arc<RetryPolicy>,
+    pub http_version: HttpVersion,
+    pub hedging: Option<HedgingPolicy>,
+    /// Outgoing HTTP requests (including retries) that take longer are
+    /// cancelled, and the Prepare is rejected with `R00_TRANSFER_TIMED_OUT`.
+    pub response_timeout: Option<time::Duration>,
+    /// The route starts out in maintenance.
+    pub maintenance: Option<RouteMaintenance>,
+    pub health_check: Option<HealthCheck>,
+    /// Routes (even with different target prefixes) that name the same peer
+    /// pool share their failover status, so that when one of them marks the
+    /// pool's peer unhealthy, the others fail over too.
+    pub peer_pool: Option<Arc<String>>,
+    /// Replaces the `target_prefix` of forwarded Prepares' destinations, e.g.
+    /// to map `test.legacy.alice.*` onto `test.new.alice.*`.
+    pub rewrite_prefix: Option<Bytes>,
+    /// Converts the amounts of forwarded Prepares to the peer's asset.
+    pub exchange: Option<RouteExchange>,
+}
+
+/// Explanation of multilateral mode:
+/// <https://forum.interledger.org/t/describe-multilateral-mode-in-ilp-plugin-http/456/2>
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum NextHop {
+    Bilateral {
+        endpoint: Endpoint,
+        auth: Option<AuthToken>,
+        /// Extra headers for the outgoing requests, e.g. for hosted endpoints
+        /// that need an API key.
+        #[serde(default)]
+        headers: Headers,
+    },
+    Multilateral {
+        endpoint_prefix: Bytes,
+        endpoint_suffix: Bytes,
+        auth: Option<AuthToken>,
+        #[serde(default)]
+        headers: Headers,
+    },
+    /// A BTP/2.0 peer. The `endpoint` is a `ws://` or `wss://` URI.
+    Btp {
+        endpoint: Endpoint,
+        auth: Option<AuthToken>,
+    },
+    /// Answer matching Prepares locally with a configured response, without
+    /// any outgoing request (e.g. for sinkholes, test prefixes, or
+    /// deprecation notices).
+    Static {
+        response: StaticResponse,
+        /// The data of the Fulfill or Reject.
+        #[serde(default)]
+        data: Bytes,
+    },
+    /// Reject matching Prepares right away (e.g. to block a prefix during an
+    /// incident). This is shorthand for a `Static` next hop with a `Reject`
+    /// response and no data.
+    Reject {
+        #[serde(deserialize_with = "deserialize_error_code")]
+        code: ilp::ErrorCode,
+        #[serde(default)]
+        message: String,
+    },
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum StaticResponse {
+    /// The Fulfill is only passed back if the (base64) `fulfillment` matches
+    /// the Prepare's condition, so this is only useful for test prefixes.
+    Fulfill {
+        #[serde(deserialize_with = "deserialize_fulfillment")]
+        fulfillment: [u8; 32],
+    },
+    Reject {
+        #[serde(deserialize_with = "deserialize_error_code")]
+        code: ilp::ErrorCode,
+        #[serde(default)]
+        message: String,
+    },
+}
+
+/// A route's planned downtime: every Prepare that is routed to it is rejected
+/// right away with this `code` and `message` (instead of being forwarded, and
+/// failing over or timing out).
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteMaintenance {
+    #[serde(
+        default = "default_maintenance_code",
+        deserialize_with = "deserialize_error_code",
+        serialize_with = "serialize_error_code",
+    )]
+    pub code: ilp::ErrorCode,
+    #[serde(default = "default_maintenance_message")]
+    pub message: String,
+}
+
+fn default_maintenance_code() -> ilp::ErrorCode { ROUTE_MAINTENANCE.code }
+
+fn default_maintenance_message() -> String {
+    ROUTE_MAINTENANCE.message.to_owned()
+}
+
+impl Default for RouteMaintenance {
+    fn default() -> Self {
+        RouteMaintenance {
+            code: default_maintenance_code(),
+            message: default_maintenance_message(),
+        }
+    }
+}
+
+impl RouteMaintenance {
+    /// The data is the `route_maintenance` reason's id, regardless of the
+    /// code and message.
+    pub(crate) fn to_reject(&self, connector_addr: ilp::Addr) -> ilp::Reject {
+        ilp::RejectBuilder {
+            code: self.code,
+            message: self.message.as_bytes(),
+            triggered_by: Some(connector_addr),
+            data: ROUTE_MAINTENANCE.id.as_bytes(),
+        }.build()
+    }
+
+    /// Check that the message fits in a Reject.
+    pub fn validate(&self) -> Result<(), ilp::BuildError> {
+        validate_reject(self.code, &self.message, ROUTE_MAINTENANCE.id.as_bytes())
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteFailover {
+    pub window_size: usize,
+    /// A route is marked as unhealthy when
+    /// `fail_ratio <= number of failures per window / window_size`.
+    pub fail_ratio: f64,
+    // <https://docs.serde.rs/serde/de/trait.Deserialize.html#impl-Deserialize%3C%27de%3E-for-Duration>
+    pub fail_duration: time::Duration,
+}
+
+/// The next hop's asset, when it differs from the connector's (ILDCP) asset.
+/// A forwarded Prepare's amount is converted from the connector's scale to
+/// `asset_scale`, and multiplied by `rate * (1 - spread)`, rounding down.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct RouteExchange {
+    pub asset_scale: u8,
+    /// When set, the rate is looked up by this code in the connector's
+    /// `exchange_rates`, instead of the static `rate`.
+    #[serde(default)]
+    pub asset_code: Option<String>,
+    /// Units of the next hop's asset per unit of the connector's asset.
+    #[serde(default = "default_rate")]
+    pub rate: f64,
+    /// The fraction of each amount that the connector keeps, from `0.0` up to
+    /// (but excluding) `1.0`.
+    #[serde(default)]
+    pub spread: f64,
+}
+
+fn default_rate() -> f64 { 1.0 }
+
+impl RouteExchange {
+    /// Convert an amount of the connector's asset to the next hop's asset, at
+    /// the given `rate` (either the static `rate`, or the provider's).
+    /// Returns `None` on overflow.
+    pub(crate) fn convert(&self, amount: u64, connector_scale: u8, rate: f64)
+        -> Option<u64>
+    {
+        let multiplier = self.multiplier(rate);
+        if is_one(multiplier) {
+            return AssetAmount::new(amount, connector_scale)
+                .to_scale_floor(self.asset_scale)
+                .map(|amount| amount.value());
+        }
+        let scale = i32::from(self.asset_scale) - i32::from(connector_scale);
+        to_u64(amount as f64 * multiplier * 10_f64.powi(scale))
+    }
+
+    /// The inverse of `convert`: an amount of the next hop's asset (e.g. from
+    /// its `F08` Reject) in the connector's asset.
+    pub(crate) fn convert_back(&self, amount: u64, connector_scale: u8, rate: f64)
+        -> Option<u64>
+    {
+        let multiplier = self.multiplier(rate);
+        if is_one(multiplier) {
+            return AssetAmount::new(amount, self.asset_scale)
+                .to_scale_floor(connector_scale)
+                .map(|amount| amount.value());
+        }
+        let scale = i32::from(connector_scale) - i32::from(self.asset_scale);
+        to_u64(amount as f64 / multiplier * 10_f64.powi(scale))
+    }
+
+    fn multiplier(&self, rate: f64) -> f64 {
+        rate * (1.0 - self.spread)
+    }
+}
+
+/// Without a rate or spread, the conversion is exact integer arithmetic.
+#[allow(clippy::float_cmp)]
+fn is_one(multiplier: f64) -> bool {
+    multiplier == 1.0
+}
+
+/// Round down to an integer amount. Returns `None` if it doesn't fit a `u64`.
+fn to_u64(value: f64) -> Option<u64> {
+    let value = value.floor();
+    // `u64::MAX as f64` rounds up to 2^64.
+    if value.is_finite() && value >= 0.0 && value < u64::MAX as f64 {
+        Some(value as u64)
+    } else {
+        None
+    }
+}
+
+/// Limit the number of concurrent outgoing requests to a route's next hop.
+/// Prepares over the limit wait in a (FIFO) queue.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConcurrencyLimit {
+    pub max_in_flight: usize,
+    /// Queued Prepares are rejected with `T03_CONNECTOR_BUSY` once they would
+    /// expire within this duration, since they are unlikely to be fulfilled in
+    /// time.
+    #[serde(default = "default_min_expiry")]
+    pub min_expiry: time::Duration,
+}
+
+fn default_min_expiry() -> time::Duration { time::Duration::from_secs(1) }
+
+/// When a forwarded Prepare hasn't been answered within `delay`, send a
+/// duplicate request to another available route in the same group, and use
+/// whichever response arrives first.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HedgingPolicy {
+    pub delay: time::Duration,
+}
+
+/// Probe the route's next hop every `interval`, and feed the results into its
+/// `failover` health. A probe that succeeds restores an unhealthy route right
+/// away, instead of once its `fail_duration` has passed and a Prepare has been
+/// risked on it.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct HealthCheck {
+    pub interval: time::Duration,
+    /// A probe that hasn't been answered within this duration fails.
+    #[serde(default = "default_probe_timeout")]
+    pub timeout: time::Duration,
+    pub probe: HealthProbe,
+}
+
+fn default_probe_timeout() -> time::Duration { time::Duration::from_secs(5) }
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+#[serde(tag = "type")]
+pub enum HealthProbe {
+    /// A `HEAD` request to a `Bilateral` endpoint. Any response other than a
+    /// 5xx succeeds, since the endpoint may not allow the method.
+    Head,
+    /// An echo (ping) Prepare to `destination`, e.g. the next hop's own
+    /// address. Any Fulfill or Reject succeeds.
+    Echo { destination: ilp::Address },
+}
+
+impl StaticRoute {
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new(target_prefix: Bytes, account: &str, next_hop: NextHop) -> Self {
+        Self::new_with_partition(target_prefix, account, next_hop, 1.0)
+    }
+
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new_with_partition(
+        target_prefix: Bytes,
+        account: &str,
+        next_hop: NextHop,
+        partition: f64,
+    ) -> Self {
+        StaticRoute {
+            target_prefix,
+            account: Arc::new(account.to_owned()), // XXX
+            next_hop,
+            failover: None,
+            partition,
+            max_packet_amount: None,
+            concurrency: None,
+            retry: Arc::new(RetryPolicy::default()),
+            http_version: HttpVersion::Adaptive,
+            hedging: None,
+            response_timeout: None,
+            maintenance: None,
+            health_check: None,
+            peer_pool: None,
+            rewrite_prefix: None,
+            exchange: None,
+        }
+    }
+
+    pub(crate) fn endpoint(
+        &self,
+        connector_addr: ilp::Addr,
+        destination_addr: ilp::Addr,
+    ) -> Result<Endpoint, RouterError> {
+        match &self.next_hop {
+            // `Endpoint` is built from `bytes::Bytes`, so this clone doesn't
+            // actually allocate.
+            NextHop::Bilateral { endpoint, .. } => Ok(endpoint.clone()),
+            NextHop::Btp { endpoint, .. } => Ok(endpoint.clone()),
+            NextHop::Static { .. } | NextHop::Reject { .. } =>
+                Err(RouterError(ErrorKind::NoEndpoint)),
+            NextHop::Multilateral { endpoint_prefix, endpoint_suffix, .. } => {
+                debug_assert!({
+                    destination_addr.starts_with_prefix(connector_addr.as_ref())
+                        && destination_addr != connector_addr
+                });
+                // TODO or use the route's target prefix instead of the connector address?
+                let destination_segment = match parse_address_segment(
+                    &self.target_prefix,
+                    destination_addr.as_ref(),
+                ) {
+                    Some(segment) => segment,
+                    None => return Err(RouterError(ErrorKind::InvalidDestination)),
+                };
+
+                // TODO dont allocate every time (maybe have a cache of segment => uri)
+                let mut uri = BytesMut::with_capacity({
+                    endpoint_prefix.len()
+                    + destination_segment.len()
+                    + endpoint_suffix.len()
+                });
+                uri.put_slice(endpoint_prefix);
+                uri.put_slice(destination_segment);
+                uri.put_slice(endpoint_suffix);
+                Ok(Endpoint::try_from(uri.freeze())?)
+            },
+        }
+    }
+
+    #[inline]
+    pub(crate) fn auth(&self) -> Option<&AuthToken> {
+        match &self.next_hop {
+            NextHop::Bilateral { auth, .. } => auth.as_ref(),
+            NextHop::Multilateral { auth, .. } => auth.as_ref(),
+            NextHop::Btp { auth, .. } => auth.as_ref(),
+            NextHop::Static { .. } | NextHop::Reject { .. } => None,
+        }
+    }
+
+    /// The extra headers of the outgoing HTTP requests, if there are any.
+    #[inline]
+    pub(crate) fn headers(&self) -> Option<&Headers> {
+        match &self.next_hop {
+            NextHop::Bilateral { headers, .. } => Some(headers),
+            NextHop::Multilateral { headers, .. } => Some(headers),
+            NextHop::Btp { .. }
+                | NextHop::Static { .. }
+                | NextHop::Reject { .. } => None,
+        }.filter(|headers| !headers.is_empty())
+    }
+
+    /// The response to a Prepare, if the route's next hop is `Static` (or
+    /// `Reject`).
+    pub(crate) fn static_response(&self, connector_addr: ilp::Addr)
+        -> Option<Result<ilp::Fulfill, ilp::Reject>>
+    {
+        let (response, data) = match &self.next_hop {
+            NextHop::Static { response, data } => (response, data),
+            NextHop::Reject { code, message } =>
+                return Some(Err(ilp::RejectBuilder {
+                    code: *code,
+                    message: message.as_bytes(),
+                    triggered_by: Some(connector_addr),
+                    data: b"",
+                }.build())),
+            _ => return None,
+        };
+        Some(match response {
+            StaticResponse::Fulfill { fulfillment } => Ok(ilp::FulfillBuilder {
+                fulfillment,
+                data,
+            }.build()),
+            StaticResponse::Reject { code, message } => Err(ilp::RejectBuilder {
+                code: *code,
+                message: message.as_bytes(),
+                triggered_by: Some(connector_addr),
+                data,
+            }.build()),
+        })
+    }
+
+    /// Check that the `Static` (or `Reject`) response and the `maintenance`
+    /// Reject fit in a packet, e.g. that the `data` isn't too long.
+    pub(crate) fn validate_responses(&self) -> Result<(), ilp::BuildError> {
+        if let Some(maintenance) = &self.maintenance {
+            maintenance.validate()?;
+        }
+        match &self.next_hop {
+            NextHop::Static {
+                response: StaticResponse::Fulfill { fulfillment },
+                data,
+            } => ilp::FulfillBuilder { fulfillment, data }
+                .try_build()
+                .map(|_| ()),
+            NextHop::Static {
+                response: StaticResponse::Reject { code, message },
+                data,
+            } => validate_reject(*code, message, data),
+            NextHop::Reject { code, message } =>
+                validate_reject(*code, message, b""),
+            _ => Ok(()),
+        }
+    }
+
+    /// Replace the Prepare's `target_prefix` with the route's `rewrite_prefix`,
+    /// if it has one.
+    pub(crate) fn rewrite_destination(&self, prepare: ilp::Prepare)
+        -> Result<ilp::Prepare, RouterError>
+    {
+        let rewrite_prefix = match &self.rewrite_prefix {
+            Some(rewrite_prefix) => rewrite_prefix,
+            None => return Ok(prepare),
+        };
+        let destination = prepare.destination();
+        let suffix = destination.as_ref()
+            .get(self.target_prefix.len()..)
+            .ok_or(RouterError(ErrorKind::InvalidDestination))?;
+        let mut new_destination =
+            BytesMut::with_capacity(rewrite_prefix.len() + suffix.len());
+        new_destination.put_slice(rewrite_prefix);
+        new_destination.put_slice(suffix);
+        let new_destination = ilp::Addr::try_from(new_destination.as_ref())
+            .map_err(|_error| RouterError(ErrorKind::InvalidDestination))?;
+
+        let mut execution_condition = [0; 32];
+        execution_condition.copy_from_slice(prepare.execution_condition());
+        Ok(ilp::PrepareBuilder {
+            amount: prepare.amount(),
+            expires_at: prepare.expires_at(),
+            execution_condition: &execution_condition,
+            destination: new_destination,
+            data: prepare.data(),
+        }.build())
+    }
+
+    #[inline]
+    pub(crate) fn is_btp(&self) -> bool {
+        matches!(self.next_hop, NextHop::Btp { .. })
+    }
+}
+
+#[derive(Debug)]
+pub struct RouterError(ErrorKind);
+
+#[derive(Debug)]
+enum ErrorKind {
+    InvalidDestination,
+    NoEndpoint,
+    InvalidEndpoint(InvalidEndpoint),
+}
+
+impl error::Error for RouterError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match &self.0 {
+            ErrorKind::InvalidDestination => None,
+            ErrorKind::NoEndpoint => None,
+            ErrorKind::InvalidEndpoint(inner) => Some(inner),
+        }
+    }
+}
+
+impl fmt::Display for RouterError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match self.0 {
+            ErrorKind::InvalidDestination => "InvalidDestination",
+            ErrorKind::NoEndpoint => "NoEndpoint",
+            ErrorKind::InvalidEndpoint(_) => "InvalidEndpoint",
+        })
+    }
+}
+
+impl From<InvalidEndpoint> for RouterError {
+    fn from(inner: InvalidEndpoint) -> Self {
+        RouterError(ErrorKind::InvalidEndpoint(inner))
+    }
+}
+
+fn parse_address_segment<'a>(target_prefix: &[u8], destination: &'a [u8])
+    -> Option<&'a [u8]>
+{
+    debug_assert!(destination.starts_with(target_prefix));
+    let segment_offset = target_prefix.len();
+    destination[segment_offset..]
+        .split(|&byte| byte == b'.')
+        .next()
+        .filter(|&segment| validate_address_segment(segment))
+}
+
+fn validate_reject(code: ilp::ErrorCode, message: &str, data: &[u8])
+    -> Result<(), ilp::BuildError>
+{
+    ilp::RejectBuilder {
+        code,
+        message: message.as_bytes(),
+        triggered_by: None,
+        data,
+    }.try_build().map(|_| ())
+}
+
+fn validate_address_segment(segment: &[u8]) -> bool {
+    !segment.is_empty() && segment.iter().all(|&byte| {
+        byte == b'-' || byte == b'_'
+            || (b'A' <= byte && byte <= b'Z')
+            || (b'a' <= byte && byte <= b'z')
+            || (b'0' <= byte && byte <= b'9')
+    })
+}
+
+#[cfg(test)]
+mod test_static_route {
+    use lazy_static::lazy_static;
+
+    use super::*;
+
+    lazy_static! {
+        static ref BI_URI: Endpoint =
+            "http://example.com/alice".parse::<Endpoint>().unwrap();
+
+        static ref BI: StaticRoute = StaticRoute::new(
+            Bytes::from("test.alice."),
+            "account1",
+            NextHop::Bilateral {
+                endpoint: BI_URI.clone(),
+                auth: Some(AuthToken::new("alice_auth")),
+                headers: Headers::default(),
+            },
+        );
+
+        static ref MULTI: StaticRoute = StaticRoute::new(
+            Bytes::from("test.relay."),
+            "account2",
+            NextHop::Multilateral {
+                endpoint_prefix: Bytes::from("http://example.com/bob/"),
+                endpoint_suffix: Bytes::from("/ilp"),
+                auth: Some(AuthToken::new("bob_auth")),
+                headers: Headers::default(),
+            },
+        );
+
+        static ref BTP_URI: Endpoint =
+            "ws://example.com/btp".parse::<Endpoint>().unwrap();
+
+        static ref BTP: StaticRoute = StaticRoute::new(
+            Bytes::from("test.carl."),
+            "account3",
+            NextHop::Btp {
+                endpoint: BTP_URI.clone(),
+                auth: Some(AuthToken::new("carl_auth")),
+            },
+        );
+
+        static ref REJECT: StaticRoute = StaticRoute::new(
+            Bytes::from("test.blocked."),
+            "account5",
+            NextHop::Reject {
+                code: ilp::ErrorCode::F02_UNREACHABLE,
+                message: "blocked".to_owned(),
+            },
+        );
+
+        static ref STATIC: StaticRoute = StaticRoute::new(
+            Bytes::from("test.deprecated."),
+            "account4",
+            NextHop::Static {
+                response: StaticResponse::Reject {
+                    code: ilp::ErrorCode::F02_UNREACHABLE,
+                    message: "deprecated".to_owned(),
+                },
+                data: Bytes::from("see example.com"),
+            },
+        );
+    }
+
+    #[test]
+    fn test_endpoint() {
+        assert_eq!(
+            BI.endpoint(
+                ilp::Addr::new(b"test.relay"),
+                ilp::Addr::new(b"test.whatever.123"),
+            ).unwrap(),
+            *BI_URI,
+        );
+        assert_eq!(
+            MULTI.endpoint(
+                ilp::Addr::new(b"test.relay"),
+                ilp::Addr::new(b"test.relay.123.456"),
+            ).unwrap(),
+            "http://example.com/bob/123/ilp".parse::<Endpoint>().unwrap(),
+        );
+        assert!(MULTI.endpoint(
+            ilp::Addr::new(b"test.relay"),
+            ilp::Addr::new(b"test.relay.123~.456"),
+        ).is_err());
+        assert_eq!(
+            BTP.endpoint(
+                ilp::Addr::new(b"test.relay"),
+                ilp::Addr::new(b"test.carl.123"),
+            ).unwrap(),
+            *BTP_URI,
+        );
+        assert!(STATIC.endpoint(
+            ilp::Addr::new(b"test.relay"),
+            ilp::Addr::new(b"test.deprecated.123"),
+        ).is_err());
+        assert!(REJECT.endpoint(
+            ilp::Addr::new(b"test.relay"),
+            ilp::Addr::new(b"test.blocked.123"),
+        ).is_err());
+    }
+
+    #[test]
+    fn test_auth() {
+        assert_eq!(BI.auth(), Some(&AuthToken::new("alice_auth")));
+        assert_eq!(MULTI.auth(), Some(&AuthToken::new("bob_auth")));
+        assert_eq!(BTP.auth(), Some(&AuthToken::new("carl_auth")));
+        assert_eq!(STATIC.auth(), None);
+        assert_eq!(REJECT.auth(), None);
+    }
+
+    #[test]
+    fn test_is_btp() {
+        assert!(!BI.is_btp());
+        assert!(!MULTI.is_btp());
+        assert!(BTP.is_btp());
+    }
+
+    #[test]
+    fn test_deserialize_btp() {
+        let next_hop = serde_json::from_str::<NextHop>(r#"
+            { "type": "Btp"
+            , "endpoint": "ws://example.com/btp"
+            , "auth": "carl_auth"
+            }
+        "#).unwrap();
+        assert_eq!(next_hop, BTP.next_hop);
+    }
+
+    #[test]
+    fn test_deserialize_static() {
+        let next_hop = serde_json::from_str::<NextHop>(r#"
+            { "type": "Static"
+            , "response":
+              { "type": "Reject"
+              , "code": "F02"
+              , "message": "deprecated"
+              }
+            , "data": "see example.com"
+            }
+        "#).unwrap();
+        assert_eq!(next_hop, STATIC.next_hop);
+
+        let next_hop = serde_json::from_str::<NextHop>(r#"
+            { "type": "Static"
+            , "response":
+              { "type": "Fulfill"
+              , "fulfillment": "AAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAAA="
+              }
+            }
+        "#).unwrap();
+        assert_eq!(next_hop, NextHop::Static {
+            response: StaticResponse::Fulfill { fulfillment: [0; 32] },
+            data: Bytes::new(),
+        });
+    }
+
+    #[test]
+    fn test_deserialize_reject() {
+        let next_hop = serde_json::from_str::<NextHop>(r#"
+            { "type": "Reject"
+            , "code": "F02"
+            , "message": "blocked"
+            }
+        "#).unwrap();
+        assert_eq!(next_hop, REJECT.next_hop);
+
+        let next_hop = serde_json::from_str::<NextHop>(r#"
+            { "type": "Reject", "code": "T00" }
+        "#).unwrap();
+        assert_eq!(next_hop, NextHop::Reject {
+            code: ilp::ErrorCode::T00_INTERNAL_ERROR,
+            message: String::new(),
+        });
+    }
+
+    #[test]
+    fn test_static_response() {
+        let address = ilp::Addr::new(b"test.relay");
+        assert!(BI.static_response(address).is_none());
+        let reject = STATIC.static_response(address).unwrap().unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F02_UNREACHABLE);
+        assert_eq!(reject.message(), b"deprecated");
+        assert_eq!(reject.triggered_by(), Some(address));
+        assert_eq!(reject.data(), b"see example.com");
+
+        let reject = REJECT.static_response(address).unwrap().unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F02_UNREACHABLE);
+        assert_eq!(reject.message(), b"blocked");
+        assert_eq!(reject.triggered_by(), Some(address));
+        assert_eq!(reject.data(), b"");
+    }
+
+    #[test]
+    fn test_rewrite_destination() {
+        let prepare = ilp::PrepareBuilder {
+            amount: 123,
+            expires_at: time::SystemTime::now(),
+            execution_condition: &[1; 32],
+            destination: ilp::Addr::new(b"test.alice.1234"),
+            data: b"prepare data",
+        }.build();
+        assert_eq!(BI.rewrite_destination(prepare.clone()).unwrap(), prepare);
+
+        let rewrite = |rewrite_prefix: &'static str| StaticRoute {
+            rewrite_prefix: Some(Bytes::from(rewrite_prefix)),
+            ..BI.clone()
+        }.rewrite_destination(prepare.clone());
+        let rewritten = rewrite("test.new.alice.").unwrap();
+        assert_eq!(rewritten.destination(), ilp::Addr::new(b"test.new.alice.1234"));
+        assert_eq!(rewritten.amount(), prepare.amount());
+        assert_eq!(rewritten.expires_at(), prepare.expires_at());
+        assert_eq!(rewritten.execution_condition(), prepare.execution_condition());
+        assert_eq!(rewritten.data(), prepare.data());
+        // Stripping the whole prefix leaves an invalid address.
+        assert!(rewrite("").is_err());
+    }
+
+    #[test]
+    fn test_exchange() {
+        let exchange = |asset_scale, spread| RouteExchange {
+            asset_scale,
+            asset_code: None,
+            rate: 1.0,
+            spread,
+        };
+        // Only the scale:
+        assert_eq!(exchange(6, 0.0).convert(1_234_567, 9, 1.0), Some(1_234));
+        assert_eq!(exchange(6, 0.0).convert_back(1_234, 9, 1.0), Some(1_234_000));
+        assert_eq!(exchange(9, 0.0).convert(u64::MAX, 0, 1.0), None);
+        // With a rate and spread:
+        assert_eq!(exchange(2, 0.0).convert(123, 0, 2.0), Some(24_600));
+        assert_eq!(exchange(2, 0.0).convert_back(24_600, 0, 2.0), Some(123));
+        assert_eq!(exchange(0, 0.5).convert(1_000, 0, 1.5), Some(750));
+        assert_eq!(exchange(0, 0.5).convert_back(750, 0, 1.5), Some(1_000));
+        assert_eq!(exchange(0, 0.0).convert(u64::MAX, 0, 2.0), None);
+    }
+}
+
+#[cfg(test)]
+mod test_helpers {
+    use super::*;
+
+    #[test]
+    fn test_parse_address_segment() {
+        assert_eq!(
+            parse_address_segment(b"test.cx.", b"test.cx.alice"),
+            Some(&b"alice"[..]),
+        );
+        assert_eq!(
+            parse_address_segment(b"test.cx.", b"test.cx.alice.bob"),
+            Some(&b"alice"[..]),
+        );
+        assert_eq!(
+            parse_address_segment(b"test.cx.", b"test.cx.alice!"),
+            None,
+        );
+    }
+
+    #[test]
+    fn test_validate_address_segment() {
+        let valid = &["test", "Test_1-2-3"];
+        let invalid = &["", "peer.config", "test!", "test?"];
+
+        for segment in valid {
+            assert_eq!(validate_address_segment(segment.as_bytes()), true);
+        }
+        for segment in invalid {
+            assert_eq!(validate_address_segment(segment.as_bytes()), false);
+        }
+    }
+}
@@ -0,0 +1,890 @@
+use std::collections::{BTreeMap, HashMap};
+use std::sync::{Arc, RwLock};
+
+use bytes::BytesMut;
+use log::warn;
+
+use crate::SetupError;
+use super::{DynamicRoute, HealthProbe, NextHop, RouteFailover, RouteMaintenance, RouteStatus, RoutingPartition, StaticRoute};
+use super::prefix_trie::PrefixTrie;
+
+/// Latencies (in milliseconds) below this are treated as equal, so that a very
+/// fast next hop doesn't take all of the traffic.
+const MIN_LATENCY_MS: f64 = 1.0;
+
+/// A simple static routing table.
+///
+/// Resolution picks the group with the longest matching target prefix, so the
+/// order of the routes (other than within a group) doesn't matter.
+#[derive(Debug)]
+pub struct RoutingTable {
+    partition_by: RoutingPartition,
+    groups: Vec<RouteGroup>,
+    /// Map each group's target prefix to its index in `groups`.
+    prefixes: PrefixTrie<usize>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RoutingError {
+    NoRoute,
+    /// One or more routes to the destination exist in the table, but all are
+    /// unhealthy.
+    NoHealthyRoute,
+}
+
+/// A set of routes that share a target prefix.
+#[derive(Debug)]
+struct RouteGroup {
+    routes: Vec<DynamicRoute>,
+}
+
+/// Uniquely identify a route within a `RoutingTable`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct RouteIndex {
+    /// Index within `RoutingTable.groups`.
+    pub(crate) group_index: usize,
+    /// Index within `RouteGroup.routes`.
+    pub(crate) route_index: usize,
+}
+
+impl RoutingTable {
+    pub fn new(routes: Vec<StaticRoute>, partition_by: RoutingPartition) -> Self {
+        let mut groups = Vec::<RouteGroup>::new();
+        let mut prefixes = PrefixTrie::<usize>::new();
+        let mut pools = HashMap::<Arc<String>, Arc<RwLock<RouteStatus>>>::new();
+        for route in routes {
+            let target_prefix = route.target_prefix.clone();
+            let mut route = DynamicRoute::new(route);
+            if let Some(pool) = &route.config.peer_pool {
+                match pools.get(pool) {
+                    Some(status) => route.status = Arc::clone(status),
+                    None => {
+                        pools.insert(Arc::clone(pool), Arc::clone(&route.status));
+                    },
+                }
+            }
+            let existing_group = prefixes.get(&target_prefix).copied();
+            if let Some(group_index) = existing_group {
+                groups[group_index].routes.push(route);
+            } else {
+                prefixes.insert(&target_prefix, groups.len());
+                groups.push(RouteGroup { routes: vec![route] });
+            }
+        }
+        RoutingTable { groups, partition_by, prefixes }
+    }
+
+    /// Check that every target prefix is a valid ILP address prefix. Warn
+    /// about prefixes without a trailing `"."`, and about sub-routes that can
+    /// never be used.
+    ///
+    /// Since the longest prefix always wins, a route can't be shadowed by a
+    /// broader one. But a sub-route with a `partition` of `0` is only used
+    /// when the other sub-routes of its group are unavailable (or for
+    /// hedging), so without `failover` (or `hedging`) it is unreachable.
+    ///
+    /// Also check that each `health_check` can be sent, and has a `failover`
+    /// to feed its results into, and that the routes of each `peer_pool` agree
+    /// on the `failover` that their shared status follows. Each `rewrite_prefix`
+    /// must be a valid address prefix, and each `exchange` must have a positive
+    /// `rate` and a `spread` below `1.0`. The `Static` and `Reject` responses
+    /// and the `maintenance` Rejects must fit in a packet.
+    pub fn validate(&self) -> Result<(), SetupError> {
+        let mut pools = HashMap::new();
+        for route in self.routes() {
+            validate_health_check(&route.config)?;
+            if let Err(error) = route.config.validate_responses() {
+                return Err(SetupError::invalid_config(format!(
+                    "invalid route response: {}: target_prefix={:?} account={}",
+                    error,
+                    String::from_utf8_lossy(&route.config.target_prefix),
+                    route.config.account,
+                )));
+            }
+            if let Some(pool) = &route.config.peer_pool {
+                validate_peer_pool(&mut pools, pool, &route.config)?;
+            }
+            if let Some(rewrite_prefix) = &route.config.rewrite_prefix {
+                if !is_valid_prefix(rewrite_prefix) {
+                    return Err(SetupError::invalid_config(format!(
+                        "route rewrite_prefix is not a valid ILP address prefix: {:?}",
+                        String::from_utf8_lossy(rewrite_prefix),
+                    )));
+                }
+            }
+            if let Some(exchange) = &route.config.exchange {
+                let is_valid = exchange.rate.is_finite() && exchange.rate > 0.0
+                    && (0.0..1.0).contains(&exchange.spread);
+                if !is_valid {
+                    return Err(SetupError::invalid_config(format!(
+                        "invalid route exchange: account={} rate={} spread={}",
+                        route.config.account, exchange.rate, exchange.spread,
+                    )));
+                }
+            }
+        }
+        for group in &self.groups {
+            let prefix = &group.routes[0].config.target_prefix;
+            if !is_valid_prefix(prefix) {
+                return Err(SetupError::invalid_config(format!(
+                    "route target_prefix is not a valid ILP address prefix: {:?}",
+                    String::from_utf8_lossy(prefix),
+                )));
+            }
+            if !prefix.is_empty() && !prefix.ends_with(b".") {
+                warn!(
+                    "route target_prefix has no trailing \".\", so it also matches longer segments: target_prefix={:?}",
+                    String::from_utf8_lossy(prefix),
+                );
+            }
+
+            let has_fallback = group.routes.iter().any(|route| {
+                route.config.failover.is_some() || route.config.hedging.is_some()
+            });
+            let has_partition = group.routes
+                .iter()
+                .any(|route| route.config.partition > 0.0);
+            if has_fallback || !has_partition {
+                continue;
+            }
+            for route in &group.routes {
+                if route.config.partition == 0.0 {
+                    warn!(
+                        "route is unreachable: it has no partition, and no other route with its target_prefix has a failover: target_prefix={:?} account={}",
+                        String::from_utf8_lossy(prefix),
+                        route.config.account,
+                    );
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Return a healthy route (and its index) with the longest target prefix
+    /// that matches the destination.
+    ///
+    /// If a route with prefix `"foo.bar."` matches (even if it is unhealthy),
+    /// then only routes with the same prefix are considered (this is used for
+    /// fallback routes).
+    pub(crate) fn resolve(&self, prepare: &ilp::Prepare)
+        -> Result<(RouteIndex, &DynamicRoute), RoutingError>
+    {
+        let (group_index, group) = self
+            .resolve_group(prepare.destination())
+            .ok_or(RoutingError::NoRoute)?;
+        let latency_factors = self.latency_factors(group);
+        let mut available_routes = group.routes
+            .iter()
+            .enumerate()
+            .filter(|(_i, route)| route.is_available())
+            .map(|(i, route)| {
+                let factor = latency_factors.get(i).copied().unwrap_or(1.0);
+                (i, route, route.config.partition * factor)
+            })
+            .collect::<Vec<_>>()
+            .into_iter()
+            .peekable();
+        // Recompute the total partitions every `resolve` so that it only includes
+        // available routes.
+        let total_partitions = available_routes
+            .clone()
+            .map(|(_i, _route, weight)| weight)
+            .sum::<f64>();
+
+        let mut position = if group.routes.len() > 1 {
+            self.partition_by.find(prepare)
+        } else {
+            // Don't bother to compute the hash unnecessarily.
+            0.0
+        };
+
+        while let Some((route_index, route, weight)) = available_routes.next() {
+            let fraction = weight / total_partitions;
+            if position <= fraction || available_routes.peek().is_none() {
+                // The last matching available route is always used as a catch-all.
+                return Ok((RouteIndex { group_index, route_index }, route));
+            }
+            // Shift `position` down so that it fits in the upcoming partitions.
+            position -= fraction;
+        }
+
+        Err(RoutingError::NoHealthyRoute)
+    }
+
+    /// When partitioning by `Latency`, the factor that each route's
+    /// `partition` is scaled by: the group's mean latency over the route's
+    /// latency. Routes without a latency (yet) aren't scaled. Otherwise, this is
+    /// empty.
+    fn latency_factors(&self, group: &RouteGroup) -> Vec<f64> {
+        if self.partition_by != RoutingPartition::Latency {
+            return Vec::new();
+        }
+        let latencies = group.routes
+            .iter()
+            .map(|route| {
+                route.latency().map(|latency| latency.max(MIN_LATENCY_MS))
+            })
+            .collect::<Vec<_>>();
+        let samples = latencies.iter().flatten().count();
+        if samples == 0 {
+            return Vec::new();
+        }
+        let mean = latencies.iter().flatten().sum::<f64>() / samples as f64;
+        latencies
+            .into_iter()
+            .map(|latency| latency.map_or(1.0, |latency| mean / latency))
+            .collect()
+    }
+
+    /// Record the response time of the route's next hop, when partitioning by
+    /// `Latency`. The table may have been replaced since the route was
+    /// resolved, in which case the sample is dropped.
+    pub(crate) fn record_latency(
+        &self,
+        index: RouteIndex,
+        latency: std::time::Duration,
+    ) {
+        if self.partition_by != RoutingPartition::Latency {
+            return;
+        }
+        let route = self.groups
+            .get(index.group_index)
+            .and_then(|group| group.routes.get(index.route_index));
+        if let Some(route) = route {
+            route.record_latency(latency);
+        }
+    }
+
+    /// Return another available route in the same group as `index`, for
+    /// hedging a request to it. The search starts after `index`, so that the
+    /// hedged requests of a group are spread across its routes.
+    pub(crate) fn resolve_alternate(&self, index: RouteIndex)
+        -> Option<(RouteIndex, &DynamicRoute)>
+    {
+        let routes = &self.groups[index.group_index].routes;
+        (1..routes.len())
+            .map(|offset| (index.route_index + offset) % routes.len())
+            .map(|route_index| (
+                RouteIndex { group_index: index.group_index, route_index },
+                &routes[route_index],
+            ))
+            .find(|(_index, route)| {
+                route.is_available()
+                    && !route.is_in_maintenance()
+                    && !matches!(
+                        route.config.next_hop,
+                        NextHop::Static { .. } | NextHop::Reject { .. }
+                    )
+            })
+    }
+
+    fn resolve_group(&self, destination: ilp::Addr)
+        -> Option<(usize, &RouteGroup)>
+    {
+        let group_index = *self.prefixes.longest_match(destination.as_ref())?;
+        Some((group_index, &self.groups[group_index]))
+    }
+
+    /// Put every route with the `account` in (or take it out of) maintenance.
+    /// Returns `false` when there is no such route.
+    pub(crate) fn set_maintenance(
+        &self,
+        account: &str,
+        maintenance: Option<&RouteMaintenance>,
+    ) -> bool {
+        let mut found = false;
+        for route in self.routes() {
+            if route.config.account.as_str() == account {
+                *route.maintenance.write().unwrap() = maintenance.cloned();
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// The routes that are in maintenance, by account.
+    pub(crate) fn maintenance(&self) -> BTreeMap<String, RouteMaintenance> {
+        self.routes()
+            .filter_map(|route| {
+                let maintenance = route.maintenance.read().unwrap().clone()?;
+                Some((route.config.account.as_ref().clone(), maintenance))
+            })
+            .collect()
+    }
+
+    fn routes(&self) -> impl Iterator<Item = &DynamicRoute> {
+        self.groups.iter().flat_map(|group| group.routes.iter())
+    }
+
+    /// The routes whose `health_check` is due at `now`. Each one is claimed,
+    /// so the caller must probe them all.
+    pub(crate) fn claim_probes(&self, now: std::time::Instant)
+        -> Vec<(RouteIndex, &DynamicRoute)>
+    {
+        self.groups
+            .iter()
+            .enumerate()
+            .flat_map(|(group_index, group)| {
+                group.routes
+                    .iter()
+                    .enumerate()
+                    .map(move |(route_index, route)| {
+                        (RouteIndex { group_index, route_index }, route)
+                    })
+            })
+            .filter(|(_index, route)| route.claim_probe(now))
+            .collect()
+    }
+
+    /// Apply a probe's result to the route. The table may have been replaced
+    /// since the probe was sent, so the result is dropped unless the route at
+    /// `index` still has the same `config`.
+    pub(crate) fn probe(
+        &self,
+        index: RouteIndex,
+        config: &StaticRoute,
+        is_success: bool,
+    ) {
+        let route = self.groups
+            .get(index.group_index)
+            .and_then(|group| group.routes.get(index.route_index))
+            .filter(|route| route.config == *config);
+        if let Some(route) = route {
+            route.probe(is_success);
+        }
+    }
+
+    pub(crate) fn update(&self, index: RouteIndex, is_success: bool) {
+        self.groups[index.group_index]
+            .routes[index.route_index]
+            .update(is_success)
+    }
+}
+
+fn validate_health_check(route: &StaticRoute) -> Result<(), SetupError> {
+    let health_check = match &route.health_check {
+        Some(health_check) => health_check,
+        None => return Ok(()),
+    };
+    let error = if route.failover.is_none() {
+        "it has no failover"
+    } else {
+        match (&health_check.probe, &route.next_hop) {
+            (_, NextHop::Static { .. }) => "its next hop is Static",
+            (_, NextHop::Reject { .. }) => "its next hop is Reject",
+            (HealthProbe::Head, NextHop::Bilateral { .. }) => return Ok(()),
+            (HealthProbe::Head, _) => "Head probes need a Bilateral next hop",
+            (HealthProbe::Echo { destination }, _) => {
+                let destination: &[u8] = destination.as_ref();
+                if destination.starts_with(&route.target_prefix) {
+                    return Ok(());
+                }
+                "the Echo destination doesn't match the target_prefix"
+            },
+        }
+    };
+    Err(SetupError::invalid_config(format!(
+        "invalid route health_check: {}: target_prefix={:?} account={}",
+        error,
+        String::from_utf8_lossy(&route.target_prefix),
+        route.account,
+    )))
+}
+
+/// `pools` maps each peer pool to the `failover` of its first route.
+fn validate_peer_pool<'a>(
+    pools: &mut HashMap<&'a str, &'a RouteFailover>,
+    pool: &'a str,
+    route: &'a StaticRoute,
+) -> Result<(), SetupError> {
+    let error = match &route.failover {
+        None => "it has no failover",
+        Some(failover) => match pools.get(pool) {
+            None => {
+                pools.insert(pool, failover);
+                return Ok(());
+            },
+            Some(pool_failover) if *pool_failover == failover =>
+                return Ok(()),
+            Some(_) => "its failover differs from the pool's other routes",
+        },
+    };
+    Err(SetupError::invalid_config(format!(
+        "invalid route peer_pool: {}: peer_pool={:?} target_prefix={:?} account={}",
+        error,
+        pool,
+        String::from_utf8_lossy(&route.target_prefix),
+        route.account,
+    )))
+}
+
+/// Whether some valid ILP address starts with the `prefix`, which must end
+/// with a complete (or partial) segment (the scheme, in particular, can't be
+/// partial). The empty prefix (the catch-all route) is valid.
+fn is_valid_prefix(prefix: &[u8]) -> bool {
+    if prefix.is_empty() {
+        return true;
+    }
+    let mut address = BytesMut::from(prefix);
+    if !prefix.ends_with(b".") {
+        address.extend_from_slice(b".");
+    }
+    address.extend_from_slice(b"a");
+    ilp::Addr::try_from(address.as_ref()).is_ok()
+}
+
+#[cfg(test)]
+impl RouteIndex {
+    pub const fn new(group_index: usize, route_index: usize) -> Self {
+        RouteIndex { group_index, route_index }
+    }
+}
+
+impl std::ops::Index<RouteIndex> for RoutingTable {
+    type Output = DynamicRoute;
+    fn index(&self, index: RouteIndex) -> &Self::Output {
+        &self.groups[index.group_index].routes[index.route_index]
+    }
+}
+
+#[cfg(test)]
+impl std::ops::Index<(usize, usize)> for RoutingTable {
+    type Output = DynamicRoute;
+    fn index(&self, (group_index, route_index): (usize, usize)) -> &Self::Output {
+        &self.groups[group_index].routes[route_index]
+    }
+}
+
+#[cfg(test)]
+mod test_routing_table {
+    use std::time;
+
+    use bytes::Bytes;
+    use lazy_static::lazy_static;
+
+    use crate::services::{HealthCheck, HealthProbe, NextHop, RouteExchange, RouteFailover, StaticResponse};
+    use crate::services::RouteStatus;
+    use crate::testing::ROUTES;
+    use super::*;
+
+    lazy_static! {
+        static ref HOP_0: NextHop = ROUTES[0].next_hop.clone();
+        static ref HOP_1: NextHop = ROUTES[1].next_hop.clone();
+        static ref HOP_2: NextHop = ROUTES[2].next_hop.clone();
+    }
+
+    #[test]
+    fn test_resolve() {
+        let table = RoutingTable::new(vec![
+            StaticRoute::new(Bytes::from("test.one"), "one", HOP_0.clone()),
+            StaticRoute::new(Bytes::from("test.two"), "two", HOP_1.clone()),
+            StaticRoute::new(Bytes::from("test."), "default", HOP_2.clone()),
+        ], RoutingPartition::default());
+
+        let tests = &[
+            // Exact match.
+            ("test.one", Ok(RouteIndex::new(0, 0))),
+            // Prefix match.
+            ("test.one.alice", Ok(RouteIndex::new(0, 0))),
+            ("test.two.bob", Ok(RouteIndex::new(1, 0))),
+            ("test.three", Ok(RouteIndex::new(2, 0))),
+            // Dot separator isn't necessary.
+            ("test.two__", Ok(RouteIndex::new(1, 0))),
+            // Unhealthy
+            // No matching prefix.
+            ("example.test.one", Err(RoutingError::NoRoute)),
+            ("g.alice", Err(RoutingError::NoRoute)),
+        ];
+
+        for (addr, index) in tests {
+            let addr = addr.as_bytes();
+            let expect = index.map(|index| (index, &table[index]));
+            assert_eq!(table.resolve(&make_prepare(addr)), expect);
+        }
+    }
+
+    #[test]
+    fn test_resolve_longest_prefix() {
+        // The more general routes come first, but the longest prefix wins.
+        let table = RoutingTable::new(vec![
+            StaticRoute::new(Bytes::from(""), "default", HOP_0.clone()),
+            StaticRoute::new(Bytes::from("test."), "test", HOP_1.clone()),
+            StaticRoute::new(Bytes::from("test.one."), "one", HOP_2.clone()),
+            StaticRoute::new(Bytes::from("test.one.alice."), "alice", HOP_0.clone()),
+            StaticRoute::new_with_partition(Bytes::from("test."), "test_2", HOP_2.clone(), 0.0),
+        ], RoutingPartition::default());
+
+        let tests = &[
+            ("test.one.alice.x", RouteIndex::new(3, 0)),
+            ("test.one.alice", RouteIndex::new(2, 0)),
+            ("test.one.bob", RouteIndex::new(2, 0)),
+            ("test.two", RouteIndex::new(1, 0)),
+            ("example.test.one.alice.x", RouteIndex::new(0, 0)),
+        ];
+        for (addr, index) in tests {
+            assert_eq!(
+                table.resolve(&make_prepare(addr.as_bytes())),
+                Ok((*index, &table[*index])),
+            );
+        }
+
+        // A matching but unhealthy group doesn't fall back to a shorter prefix.
+        *table[(2, 0)].status.write().unwrap() = RouteStatus::Unhealthy {
+            until: time::Instant::now() + time::Duration::from_secs(1),
+        };
+        assert_eq!(
+            table.resolve(&make_prepare(b"test.one.bob")),
+            Err(RoutingError::NoHealthyRoute),
+        );
+    }
+
+    #[test]
+    fn test_validate() {
+        let table = RoutingTable::new(vec![
+            StaticRoute::new(Bytes::from("test.one."), "one", HOP_0.clone()),
+            StaticRoute::new(Bytes::from("test.two"), "two", HOP_1.clone()),
+            StaticRoute::new_with_partition(Bytes::from("test.two"), "three", HOP_2.clone(), 0.0),
+            StaticRoute::new(Bytes::from(""), "default", HOP_2.clone()),
+        ], RoutingPartition::default());
+        // Only warnings.
+        assert!(table.validate().is_ok());
+
+        let too_long = format!("test.{}", "a.".repeat(600));
+        for prefix in &["test..", ".test.", "foo.", "test.\u{e9}", &too_long] {
+            let table = RoutingTable::new(vec![
+                StaticRoute::new(Bytes::from("test.one."), "one", HOP_0.clone()),
+                StaticRoute::new(Bytes::from(prefix.to_string()), "two", HOP_1.clone()),
+            ], RoutingPartition::default());
+            assert!(table.validate().is_err(), "prefix={:?}", prefix);
+        }
+
+        for &(rewrite_prefix, is_ok) in &[("test.new.", true), ("", true), ("foo.", false)] {
+            let mut route =
+                StaticRoute::new(Bytes::from("test.one."), "one", HOP_0.clone());
+            route.rewrite_prefix = Some(Bytes::from(rewrite_prefix));
+            let table = RoutingTable::new(vec![route], RoutingPartition::default());
+            assert_eq!(table.validate().is_ok(), is_ok, "rewrite_prefix={:?}", rewrite_prefix);
+        }
+
+        for &(rate, spread, is_ok) in &[
+            (1.0, 0.0, true),
+            (0.5, 0.99, true),
+            (0.0, 0.0, false),
+            (f64::INFINITY, 0.0, false),
+            (1.0, 1.0, false),
+            (1.0, -0.1, false),
+        ] {
+            let mut route =
+                StaticRoute::new(Bytes::from("test.one."), "one", HOP_0.clone());
+            route.exchange = Some(RouteExchange {
+                asset_scale: 2,
+                asset_code: None,
+                rate,
+                spread,
+            });
+            let table = RoutingTable::new(vec![route], RoutingPartition::default());
+            assert_eq!(table.validate().is_ok(), is_ok, "rate={} spread={}", rate, spread);
+        }
+
+        let long_message = "a".repeat(8192);
+        let long_data = Bytes::from(vec![0; 32768]);
+        let reject = |message: &str, data: &Bytes| NextHop::Static {
+            response: StaticResponse::Reject {
+                code: ilp::ErrorCode::F02_UNREACHABLE,
+                message: message.to_owned(),
+            },
+            data: data.clone(),
+        };
+        for (next_hop, is_ok) in &[
+            (reject("unreachable", &Bytes::from("data")), true),
+            (reject(&long_message, &Bytes::new()), false),
+            (reject("", &long_data), false),
+            (NextHop::Static {
+                response: StaticResponse::Fulfill { fulfillment: [0; 32] },
+                data: long_data.clone(),
+            }, false),
+            (NextHop::Reject {
+                code: ilp::ErrorCode::F02_UNREACHABLE,
+                message: long_message.clone(),
+            }, false),
+        ] {
+            let route =
+                StaticRoute::new(Bytes::from("test.one."), "one", next_hop.clone());
+            let table = RoutingTable::new(vec![route], RoutingPartition::default());
+            assert_eq!(table.validate().is_ok(), *is_ok);
+        }
+
+        let mut route =
+            StaticRoute::new(Bytes::from("test.one."), "one", HOP_0.clone());
+        route.maintenance = Some(RouteMaintenance {
+            message: long_message,
+            ..RouteMaintenance::default()
+        });
+        let table = RoutingTable::new(vec![route], RoutingPartition::default());
+        assert!(table.validate().is_err());
+    }
+
+    #[test]
+    fn test_validate_health_check() {
+        let validate = |next_hop: &NextHop, has_failover: bool, probe: HealthProbe| {
+            let mut route =
+                StaticRoute::new(Bytes::from("test.one."), "one", next_hop.clone());
+            if has_failover {
+                route.failover = Some(RouteFailover {
+                    window_size: 10,
+                    fail_ratio: 0.5,
+                    fail_duration: time::Duration::from_secs(10),
+                });
+            }
+            route.health_check = Some(HealthCheck {
+                interval: time::Duration::from_secs(10),
+                timeout: time::Duration::from_secs(1),
+                probe,
+            });
+            RoutingTable::new(vec![route], RoutingPartition::default())
+                .validate()
+                .is_ok()
+        };
+        let echo = |destination: &'static [u8]| HealthProbe::Echo {
+            destination: ilp::Address::new(destination),
+        };
+
+        assert!(validate(&HOP_0, true, HealthProbe::Head));
+        assert!(validate(&HOP_1, true, echo(b"test.one.bob")));
+        assert!(!validate(&HOP_0, false, HealthProbe::Head));
+        assert!(!validate(&HOP_1, true, HealthProbe::Head));
+        assert!(!validate(&HOP_0, true, echo(b"test.two")));
+        assert!(!validate(&NextHop::Static {
+            response: StaticResponse::Reject {
+                code: ilp::ErrorCode::F02_UNREACHABLE,
+                message: String::new(),
+            },
+            data: Bytes::new(),
+        }, true, echo(b"test.one.bob")));
+    }
+
+    fn make_pool_route(prefix: &'static str, fail_ratio: Option<f64>) -> StaticRoute {
+        let mut route =
+            StaticRoute::new(Bytes::from(prefix), "peer", HOP_0.clone());
+        route.failover = fail_ratio.map(|fail_ratio| RouteFailover {
+            window_size: 10,
+            fail_ratio,
+            fail_duration: time::Duration::from_secs(10),
+        });
+        route.peer_pool = Some(Arc::new("pool".to_owned()));
+        route
+    }
+
+    #[test]
+    fn test_peer_pool() {
+        let mut other_route = make_pool_route("test.c.", Some(0.5));
+        other_route.peer_pool = Some(Arc::new("other_pool".to_owned()));
+        let table = RoutingTable::new(vec![
+            make_pool_route("test.a.", Some(0.5)),
+            make_pool_route("test.b.", Some(0.5)),
+            other_route,
+        ], RoutingPartition::default());
+        assert!(table.validate().is_ok());
+
+        for _i in 0..5 {
+            table[(0, 0)].update(false);
+        }
+        // The other route to the same peer pool fails over too.
+        assert!(!table[(0, 0)].is_available());
+        assert!(!table[(1, 0)].is_available());
+        assert!(table[(2, 0)].is_available());
+        assert_eq!(
+            table.resolve(&make_prepare(b"test.b.bob")),
+            Err(RoutingError::NoHealthyRoute),
+        );
+    }
+
+    #[test]
+    fn test_validate_peer_pool() {
+        let validate = |routes: Vec<StaticRoute>| {
+            RoutingTable::new(routes, RoutingPartition::default())
+                .validate()
+                .is_ok()
+        };
+        assert!(validate(vec![make_pool_route("test.a.", Some(0.5))]));
+        assert!(!validate(vec![make_pool_route("test.a.", None)]));
+        assert!(!validate(vec![
+            make_pool_route("test.a.", Some(0.5)),
+            make_pool_route("test.b.", Some(0.2)),
+        ]));
+    }
+
+    #[test]
+    fn test_is_valid_prefix() {
+        for prefix in &["", "g.", "test.", "test", "test.one", "test.one.", "private.a-b_c~d."] {
+            assert!(is_valid_prefix(prefix.as_bytes()), "prefix={:?}", prefix);
+        }
+        for prefix in &[".", "..", "te", "foo.", "test..", "test.one..", "test.one two"] {
+            assert!(!is_valid_prefix(prefix.as_bytes()), "prefix={:?}", prefix);
+        }
+    }
+
+    #[test]
+    fn test_resolve_unhealthy() {
+        let table = RoutingTable::new(vec![
+            StaticRoute::new(Bytes::from("test.one"), "one", HOP_0.clone()),
+            StaticRoute::new_with_partition(Bytes::from("test.one"), "two", HOP_2.clone(), 0.0),
+        ], RoutingPartition::default());
+        assert_eq!(
+            table.resolve(&make_prepare(b"test.one.a")),
+            Ok((RouteIndex::new(0, 0), &table[(0, 0)])),
+        );
+
+        *table[(0, 0)].status.write().unwrap() = RouteStatus::Unhealthy {
+            until: time::Instant::now() + time::Duration::from_secs(1),
+        };
+        assert_eq!(
+            table.resolve(&make_prepare(b"test.one.a")),
+            Ok((RouteIndex::new(0, 1), &table[(0, 1)])),
+        );
+
+        *table[(0, 1)].status.write().unwrap() = RouteStatus::Unhealthy {
+            until: time::Instant::now() + time::Duration::from_secs(1),
+        };
+        assert_eq!(
+            table.resolve(&make_prepare(b"test.one.a")),
+            Err(RoutingError::NoHealthyRoute),
+        );
+    }
+
+    #[test]
+    fn test_resolve_alternate() {
+        let table = RoutingTable::new(vec![
+            StaticRoute::new(Bytes::from("test.one"), "one", HOP_0.clone()),
+            StaticRoute::new(Bytes::from("test.one"), "two", HOP_1.clone()),
+            StaticRoute::new(Bytes::from("test.one"), "three", HOP_2.clone()),
+            StaticRoute::new(Bytes::from("test.two"), "four", HOP_0.clone()),
+        ], RoutingPartition::default());
+        assert_eq!(
+            table.resolve_alternate(RouteIndex::new(0, 0)),
+            Some((RouteIndex::new(0, 1), &table[(0, 1)])),
+        );
+        assert_eq!(
+            table.resolve_alternate(RouteIndex::new(0, 2)),
+            Some((RouteIndex::new(0, 0), &table[(0, 0)])),
+        );
+
+        *table[(0, 1)].status.write().unwrap() = RouteStatus::Unhealthy {
+            until: time::Instant::now() + time::Duration::from_secs(1),
+        };
+        assert_eq!(
+            table.resolve_alternate(RouteIndex::new(0, 0)),
+            Some((RouteIndex::new(0, 2), &table[(0, 2)])),
+        );
+        // A route without any others in its group.
+        assert_eq!(table.resolve_alternate(RouteIndex::new(1, 0)), None);
+    }
+
+    #[test]
+    fn test_resolve_catch_all() {
+        let table = RoutingTable::new(vec![
+            StaticRoute::new(Bytes::from("test.one"), "one", HOP_0.clone()),
+            StaticRoute::new(Bytes::from("test.two"), "two", HOP_1.clone()),
+            StaticRoute::new(Bytes::from(""), "default", HOP_2.clone()),
+        ], RoutingPartition::default());
+        assert_eq!(
+            table.resolve(&make_prepare(b"example.test.one")),
+            Ok((RouteIndex::new(2, 0), &table[(2, 0)])),
+        );
+    }
+
+    #[test]
+    fn test_resolve_partition() {
+        let table = RoutingTable::new(vec![
+            StaticRoute::new_with_partition(Bytes::from("test.one."), "one", HOP_0.clone(), 0.50),
+            StaticRoute::new_with_partition(Bytes::from("test.one."), "two", HOP_1.clone(), 0.25),
+            StaticRoute::new_with_partition(Bytes::from("test.one."), "three", HOP_1.clone(), 0.25),
+        ], RoutingPartition::Destination);
+
+        let mut counts = [0_i32; 3];
+        for i in 0..10_000 {
+            let (index, _route) =
+                table.resolve(&make_prepare(&alice(i))).unwrap();
+            counts[index.route_index] += 1;
+        }
+        // Ensure that the partitions are (mostly) balanced.
+        assert!((counts[0] - 5_000).abs() < 100);
+        assert!((counts[1] - 2_500).abs() < 100);
+        assert!((counts[2] - 2_500).abs() < 100);
+
+        // When the first route is down, all traffic is routed to the remaining route.
+        *table[(0, 0)].status.write().unwrap() = RouteStatus::Unhealthy {
+            until: time::Instant::now() + time::Duration::from_secs(1),
+        };
+
+        let mut counts = [0_i32; 3];
+        for i in 0..10_000 {
+            let (index, _route) =
+                table.resolve(&make_prepare(&alice(i))).unwrap();
+            counts[index.route_index] += 1;
+        }
+        assert_eq!(counts[0], 0);
+        assert!((counts[1] - 5_000).abs() < 100);
+        assert!((counts[2] - 5_000).abs() < 100);
+    }
+
+    #[test]
+    fn test_resolve_latency() {
+        let table = RoutingTable::new(vec![
+            StaticRoute::new_with_partition(Bytes::from("test.one."), "one", HOP_0.clone(), 0.50),
+            StaticRoute::new_with_partition(Bytes::from("test.one."), "two", HOP_1.clone(), 0.50),
+        ], RoutingPartition::Latency);
+
+        // Without any samples, the partitions are used as-is.
+        let counts = count_latency_routes(&table);
+        assert!((counts[0] - 5_000).abs() < 200);
+        assert!((counts[1] - 5_000).abs() < 200);
+
+        // The faster route takes proportionally more of the traffic.
+        table.record_latency(RouteIndex::new(0, 0), time::Duration::from_millis(10));
+        table.record_latency(RouteIndex::new(0, 1), time::Duration::from_millis(30));
+        let counts = count_latency_routes(&table);
+        assert!((counts[0] - 7_500).abs() < 200);
+        assert!((counts[1] - 2_500).abs() < 200);
+
+        // Stale indices are ignored.
+        table.record_latency(RouteIndex::new(1, 0), time::Duration::from_millis(10));
+    }
+
+    fn count_latency_routes(table: &RoutingTable) -> [i32; 2] {
+        let mut counts = [0_i32; 2];
+        for i in 0..10_000_u32 {
+            let mut condition = [0; 32];
+            condition[..4].copy_from_slice(&i.to_be_bytes());
+            let prepare = ilp::PrepareBuilder {
+                execution_condition: &condition,
+                ..make_prepare_builder(b"test.one.alice")
+            }.build();
+            let (index, _route) = table.resolve(&prepare).unwrap();
+            counts[index.route_index] += 1;
+        }
+        counts
+    }
+
+    fn make_prepare(address: &[u8]) -> ilp::Prepare {
+        make_prepare_builder(address).build()
+    }
+
+    fn make_prepare_builder(address: &[u8]) -> ilp::PrepareBuilder<'_> {
+        ilp::PrepareBuilder {
+            amount: 123,
+            expires_at: std::time::SystemTime::now()
+                + std::time::Duration::from_secs(20),
+            execution_condition: b"\
+                \x11\x7b\x43\x4f\x1a\x54\xe9\x04\x4f\x4f\x54\x92\x3b\x2c\xff\x9e\
+                \x4a\x6d\x42\x0a\xe2\x81\xd5\x02\x5d\x7b\xb0\x40\xc4\xb4\xc0\x4a\
+            ",
+            destination: ilp::Addr::try_from(address).unwrap(),
+            data: b"prepare data",
+        }
+    }
+
+    fn alice(n: usize) -> Vec<u8> {
+        format!("test.one.alice.{}", n).into_bytes()
+    }
+}
@@ -0,0 +1,841 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::fmt;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use bytes::Bytes;
+use futures::future::Either;
+use futures::prelude::*;
+use log::{debug, info, warn};
+use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
+use serde::{Deserialize, Serialize};
+
+use crate::{RequestId, RequestWithFrom, Service};
+use crate::SetupError;
+use crate::connector_info::ConnectorInfo;
+use crate::metrics::Metrics;
+use crate::reject_reasons;
+use crate::transport::{Endpoint, HttpClient, HttpError, HttpRequest};
+
+static SETTLEMENTS: &str = "ilp_relay_settlements_total";
+
+/// Account names are percent-encoded in the engine's paths.
+const PATH_CHARS: &percent_encoding::AsciiSet = &NON_ALPHANUMERIC.remove(b'_');
+
+/// The settlement engine must respond within this duration.
+const ENGINE_TIMEOUT: time::Duration = time::Duration::from_secs(30);
+
+/// A `peer.settle` response must fit in the Fulfill's data.
+const MAX_MESSAGE_SIZE: usize = 32_767;
+
+/// Remember this many incoming settlements' Idempotency-Keys.
+const MAX_RECEIVED_KEYS: usize = 4_096;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettlementConfig {
+    /// The settlement engine's base URL, e.g. `http://127.0.0.1:3000`. It
+    /// implements the Interledger settlement engine API.
+    pub engine_url: String,
+    /// The settled accounts, by account (the peers' and routes' `account`).
+    pub accounts: HashMap<String, SettlementAccountConfig>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SettlementAccountConfig {
+    /// Settle once the payable balance (owed to the peer for the packets that
+    /// it fulfilled) reaches this amount.
+    pub settle_threshold: u64,
+    /// Settle the payable balance down to this amount.
+    #[serde(default)]
+    pub settle_to: u64,
+    /// The scale of the amounts that the peer is paid in, for the engine
+    /// (default: the connector's `asset_scale`). This differs when the
+    /// account's route has an `exchange`.
+    #[serde(default)]
+    pub asset_scale: Option<u8>,
+}
+
+/// An account's balances, in its own units.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize)]
+pub struct Balance {
+    /// Owed to the peer, for the Prepares sent to it that it fulfilled.
+    pub payable: u64,
+    /// Owed by the peer, for the Prepares it sent that were fulfilled.
+    pub receivable: u64,
+    /// Whether a settlement is waiting for the engine's response. While it is,
+    /// another isn't started.
+    pub is_settling: bool,
+    /// The amount of the settlement that the engine hasn't confirmed yet.
+    pub pending: u64,
+}
+
+#[derive(Debug, Default)]
+struct AccountState {
+    balance: Balance,
+    /// The unconfirmed settlement. Its Idempotency-Key is kept, so that when
+    /// it is retried, the engine can tell that it may have already paid it.
+    pending: Option<PendingSettlement>,
+}
+
+#[derive(Clone, Debug)]
+struct PendingSettlement {
+    amount: u64,
+    idempotency_key: String,
+}
+
+/// Track each settled account's payable and receivable balances, and ask the
+/// settlement engine to settle the payable balance once it crosses the
+/// account's `settle_threshold`. The receivable balance is reduced by the
+/// incoming settlements that the engine reports (through the admin API).
+#[derive(Clone, Debug)]
+pub struct Settlement {
+    engine_url: Arc<String>,
+    accounts: Arc<HashMap<String, SettlementAccountConfig>>,
+    /// For the connector's asset scale, which may change when its ILDCP config
+    /// is refreshed.
+    connector: ConnectorInfo,
+    balances: Arc<Mutex<HashMap<String, AccountState>>>,
+    client: Arc<dyn HttpClient>,
+    received: Arc<Mutex<ReceivedSettlements>>,
+    metrics: Arc<Metrics>,
+}
+
+/// An amount, and the scale that it is in. This is the body of the engine's
+/// `POST /accounts/{account}/settlements`, and of the connector's (through
+/// the admin API).
+#[derive(Clone, Debug, PartialEq, Deserialize, Serialize)]
+pub struct Quantity {
+    /// An unsigned integer, as a string.
+    pub amount: String,
+    pub scale: u8,
+}
+
+/// The recently-received settlements, by Idempotency-Key, so that the
+/// engine's retries aren't credited twice.
+#[derive(Debug, Default)]
+struct ReceivedSettlements {
+    /// The account, the request's quantity, and the response's.
+    responses: HashMap<String, (String, Quantity, Quantity)>,
+    /// The keys, oldest first.
+    order: VecDeque<String>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ReceiveError {
+    /// The account isn't settled.
+    UnknownAccount,
+    /// The amount isn't an unsigned integer, or overflows once it is scaled.
+    InvalidAmount,
+    /// The Idempotency-Key was already used with another account or amount.
+    KeyReused,
+}
+
+#[derive(Debug)]
+pub(crate) enum EngineError {
+    Http(HttpError),
+    Status(u16),
+    TimedOut,
+    TooLarge,
+}
+
+/// Answer `peer.settle` messages from the settled accounts by passing them to
+/// their settlement engine, and fulfilling with its response. Other Prepares
+/// are forwarded, and when they are fulfilled, their amounts are added to the
+/// sender's receivable balance. (The router adds the forwarded amounts to the
+/// payable balances, since only it knows the next hop.)
+#[derive(Clone, Debug)]
+pub struct SettlementService<S> {
+    connector: ConnectorInfo,
+    settlement: Option<Settlement>,
+    next: S,
+}
+
+impl Settlement {
+    pub fn new(
+        config: SettlementConfig,
+        connector: impl Into<ConnectorInfo>,
+        metrics: Arc<Metrics>,
+        client: Arc<dyn HttpClient>,
+    ) -> Result<Self, SetupError> {
+        let engine_url = config.engine_url.trim_end_matches('/').to_owned();
+        if engine_url.parse::<Endpoint>().is_err() {
+            return Err(SetupError::invalid_config({
+                "settlement.engine_url must be a valid URL"
+            }));
+        }
+        for (account, account_config) in &config.accounts {
+            if account_config.settle_threshold < account_config.settle_to {
+                return Err(SetupError::invalid_config(format!(
+                    "settlement account {:?}: settle_to exceeds settle_threshold",
+                    account,
+                )));
+            }
+        }
+        let balances = config.accounts
+            .keys()
+            .map(|account| (account.clone(), AccountState::default()))
+            .collect::<HashMap<_, _>>();
+        Ok(Settlement {
+            engine_url: Arc::new(engine_url),
+            accounts: Arc::new(config.accounts),
+            connector: connector.into(),
+            balances: Arc::new(Mutex::new(balances)),
+            client,
+            received: Arc::new(Mutex::new(ReceivedSettlements::default())),
+            metrics,
+        })
+    }
+
+    /// Every settled account's balances.
+    pub fn balances(&self) -> BTreeMap<String, Balance> {
+        self.balances.lock().unwrap()
+            .iter()
+            .map(|(account, state)| (account.clone(), state.balance))
+            .collect()
+    }
+
+    /// Add the amount of a fulfilled Prepare from the account to its
+    /// receivable balance.
+    pub fn record_receivable(&self, from_account: &str, amount: u64) {
+        let mut balances = self.balances.lock().unwrap();
+        if let Some(state) = balances.get_mut(from_account) {
+            state.balance.receivable =
+                state.balance.receivable.saturating_add(amount);
+        }
+    }
+
+    /// Add the amount of a fulfilled Prepare that was forwarded to the account
+    /// to its payable balance. A settlement is started if the payable balance
+    /// crosses the threshold, or an unconfirmed one is retried.
+    pub(crate) fn record_payable(&self, to_account: &str, to_amount: u64) {
+        let mut balances = self.balances.lock().unwrap();
+        let (state, config) = match (
+            balances.get_mut(to_account),
+            self.accounts.get(to_account),
+        ) {
+            (Some(state), Some(config)) => (state, config),
+            _ => return,
+        };
+        let balance = &mut state.balance;
+        balance.payable = balance.payable.saturating_add(to_amount);
+        if balance.is_settling {
+            return;
+        }
+        let pending = match &state.pending {
+            // The amount and key must match the failed attempt's.
+            Some(pending) => pending.clone(),
+            None if balance.payable >= config.settle_threshold => {
+                let pending = PendingSettlement {
+                    amount: balance.payable - config.settle_to,
+                    idempotency_key: uuid::Uuid::new_v4().to_string(),
+                };
+                balance.payable = config.settle_to;
+                balance.pending = pending.amount;
+                state.pending = Some(pending.clone());
+                pending
+            },
+            None => return,
+        };
+        balance.is_settling = true;
+        std::mem::drop(balances);
+        tokio::spawn(self.clone().settle(to_account.to_owned(), pending));
+    }
+
+    /// Reduce the account's receivable balance by an incoming settlement, and
+    /// return the amount that was credited, in the account's scale. Precision
+    /// that the account's scale can't represent isn't credited; the engine is
+    /// expected to keep it. A retry (with the same `idempotency_key`) returns
+    /// the original response, without crediting the amount again.
+    pub fn receive_settlement(
+        &self,
+        account: &str,
+        idempotency_key: &str,
+        quantity: Quantity,
+    ) -> Result<Quantity, ReceiveError> {
+        if !self.accounts.contains_key(account) {
+            return Err(ReceiveError::UnknownAccount);
+        }
+        let mut received = self.received.lock().unwrap();
+        if let Some((key_account, request, response)) =
+            received.responses.get(idempotency_key)
+        {
+            if key_account != account || *request != quantity {
+                return Err(ReceiveError::KeyReused);
+            }
+            return Ok(response.clone());
+        }
+
+        let asset_scale = self.asset_scale(account);
+        let amount = quantity.amount
+            .parse::<u64>()
+            .ok()
+            .and_then(|amount| scale_amount(amount, quantity.scale, asset_scale))
+            .ok_or(ReceiveError::InvalidAmount)?;
+        let mut balances = self.balances.lock().unwrap();
+        let balance = &mut balances.get_mut(account)
+            .expect("settled account has no balance")
+            .balance;
+        balance.receivable = balance.receivable.saturating_sub(amount);
+        info!(
+            "received settlement: account={} amount={} receivable={}",
+            account, amount, balance.receivable,
+        );
+        std::mem::drop(balances);
+
+        let response = Quantity {
+            amount: amount.to_string(),
+            scale: asset_scale,
+        };
+        received.insert(
+            idempotency_key.to_owned(),
+            (account.to_owned(), quantity, response.clone()),
+        );
+        Ok(response)
+    }
+
+    /// The scale of the account's amounts, for the engine.
+    fn asset_scale(&self, account: &str) -> u8 {
+        self.accounts
+            .get(account)
+            .and_then(|config| config.asset_scale)
+            .or_else(|| self.connector.asset_scale())
+            .unwrap_or(0)
+    }
+
+    /// Ask the engine to settle the pending amount. On failure, it stays
+    /// pending, and is retried (with the same Idempotency-Key) after the next
+    /// fulfill.
+    async fn settle(self, account: String, pending: PendingSettlement) {
+        let amount = pending.amount;
+        let body = serde_json::to_vec(&Quantity {
+            amount: amount.to_string(),
+            scale: self.asset_scale(&account),
+        }).expect("settlement serialization error");
+        let result = self.post(
+            &account,
+            "settlements",
+            &pending.idempotency_key,
+            body,
+            "application/json",
+        ).await;
+        let mut balances = self.balances.lock().unwrap();
+        let state = balances.get_mut(&account)
+            .expect("settled account has no balance");
+        state.balance.is_settling = false;
+        let result_label = match result {
+            Ok(_body) => {
+                debug!("settlement sent: account={} amount={}", account, amount);
+                state.balance.pending = 0;
+                state.pending = None;
+                "ok"
+            },
+            Err(error) => {
+                warn!(
+                    "settlement error: account={} amount={} error={}",
+                    account, amount, error,
+                );
+                "error"
+            },
+        };
+        self.metrics.increment(SETTLEMENTS, vec![
+            ("account", account),
+            ("result", result_label.to_owned()),
+        ], 1);
+    }
+
+    /// Pass a `peer.settle` message from the account to the engine, and
+    /// return its response.
+    async fn send_message(self, account: &str, message: Bytes)
+        -> Result<Bytes, EngineError>
+    {
+        // Each message is new, so it has its own key.
+        let idempotency_key = uuid::Uuid::new_v4().to_string();
+        self.post(
+            account,
+            "messages",
+            &idempotency_key,
+            message,
+            "application/octet-stream",
+        ).await
+    }
+
+    async fn post(
+        &self,
+        account: &str,
+        resource: &str,
+        idempotency_key: &str,
+        body: impl Into<Bytes>,
+        content_type: &'static str,
+    ) -> Result<Bytes, EngineError> {
+        let uri = format!(
+            "{}/accounts/{}/{}",
+            self.engine_url,
+            percent_encode(account.as_bytes(), PATH_CHARS),
+            resource,
+        );
+        let request = HttpRequest::post(uri, body, MAX_MESSAGE_SIZE)
+            .header("Content-Type", content_type)
+            .header("Idempotency-Key", idempotency_key);
+        let response = tokio::time::timeout(
+            ENGINE_TIMEOUT,
+            self.client.request(request),
+        )
+            .await
+            .map_err(|_elapsed| EngineError::TimedOut)??;
+        if !response.is_success() {
+            return Err(EngineError::Status(response.status));
+        }
+        Ok(response.body)
+    }
+}
+
+impl ReceivedSettlements {
+    fn insert(&mut self, key: String, entry: (String, Quantity, Quantity)) {
+        if self.order.len() >= MAX_RECEIVED_KEYS {
+            if let Some(oldest) = self.order.pop_front() {
+                self.responses.remove(&oldest);
+            }
+        }
+        self.order.push_back(key.clone());
+        self.responses.insert(key, entry);
+    }
+}
+
+/// Convert the amount from one scale to another, rounding down. Returns `None`
+/// if it overflows.
+fn scale_amount(amount: u64, from_scale: u8, to_scale: u8) -> Option<u64> {
+    if to_scale >= from_scale {
+        10_u64
+            .checked_pow(u32::from(to_scale - from_scale))
+            .and_then(|factor| amount.checked_mul(factor))
+            .or(if amount == 0 { Some(0) } else { None })
+    } else {
+        Some(10_u64
+            .checked_pow(u32::from(from_scale - to_scale))
+            .map_or(0, |divisor| amount / divisor))
+    }
+}
+
+impl<S> SettlementService<S> {
+    pub fn new(
+        connector: impl Into<ConnectorInfo>,
+        settlement: Option<Settlement>,
+        next: S,
+    ) -> Self {
+        SettlementService { connector: connector.into(), settlement, next }
+    }
+}
+
+impl<S, Req> Service<Req> for SettlementService<S>
+where
+    S: Service<Req>,
+    Req: RequestWithFrom,
+{
+    type Future = Either<
+        Pin<Box<dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static>>,
+        S::Future,
+    >;
+
+    fn call(self, request: Req) -> Self::Future {
+        let prepare = request.borrow();
+        if prepare.peer_protocol() != Some(ilp::PeerProtocol::Settlement) {
+            let settlement = self.settlement
+                .filter(|settlement| {
+                    settlement.accounts.contains_key(&**request.from_account())
+                });
+            return match settlement {
+                Some(settlement) => {
+                    let from_account = Arc::clone(request.from_account());
+                    let amount = prepare.amount();
+                    Either::Left(Box::pin({
+                        self.next.call(request).inspect_ok(move |_fulfill| {
+                            settlement.record_receivable(&from_account, amount);
+                        })
+                    }))
+                },
+                None => Either::Right(self.next.call(request)),
+            };
+        }
+
+        let connector = self.connector;
+        let from_account = Arc::clone(request.from_account());
+        let settlement = self.settlement
+            .filter(|settlement| settlement.accounts.contains_key(&*from_account));
+        let settlement = match settlement {
+            Some(settlement) => settlement,
+            None => {
+                warn!(
+                    "settlement message from unsettled account: request_id={} from_account={}",
+                    RequestId::of(&request), from_account,
+                );
+                return Either::Left(Box::pin(future::err({
+                    reject_reasons::SETTLEMENT_NOT_CONFIGURED
+                        .to_reject(connector.address().as_addr())
+                })));
+            },
+        };
+        let request_id = RequestId::of(&request);
+        let message = Bytes::copy_from_slice(prepare.data());
+        Either::Left(Box::pin(async move {
+            let response = settlement.send_message(&from_account, message).await;
+            match response {
+                Ok(data) => Ok(ilp::FulfillBuilder {
+                    fulfillment: ilp::PEER_PROTOCOL_FULFILLMENT,
+                    data: &data,
+                }.build()),
+                Err(error) => {
+                    warn!(
+                        "settlement engine message error: request_id={} from_account={} error={}",
+                        request_id, from_account, error,
+                    );
+                    Err(reject_reasons::SETTLEMENT_ENGINE_ERROR
+                        .to_reject(connector.address().as_addr()))
+                },
+            }
+        }))
+    }
+}
+
+impl fmt::Display for EngineError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            EngineError::Http(inner) => write!(f, "EngineError({})", inner),
+            EngineError::Status(status) =>
+                write!(f, "EngineError(unexpected status: {})", status),
+            EngineError::TimedOut => f.write_str("EngineError(timed out)"),
+            EngineError::TooLarge => f.write_str("EngineError(response too large)"),
+        }
+    }
+}
+
+impl From<HttpError> for EngineError {
+    fn from(inner: HttpError) -> Self {
+        match inner {
+            HttpError::ResponseTooLarge => EngineError::TooLarge,
+            inner => EngineError::Http(inner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_settlement {
+    use futures::executor::block_on;
+
+    use crate::Relation;
+    use crate::testing::{self, FULFILL, ILDCP_RESPONSE, MockHttpClient, MockRequest, MockService, PREPARE, REJECT};
+    use crate::transport::HttpMethod;
+    use super::*;
+
+    fn make_config() -> SettlementConfig {
+        let mut accounts = HashMap::new();
+        accounts.insert("alice".to_owned(), SettlementAccountConfig {
+            settle_threshold: 100,
+            settle_to: 10,
+            asset_scale: Some(6),
+        });
+        accounts.insert("bob".to_owned(), SettlementAccountConfig {
+            settle_threshold: 1_000,
+            settle_to: 0,
+            asset_scale: None,
+        });
+        SettlementConfig {
+            engine_url: format!("{}/", testing::RECEIVER_ORIGIN),
+            accounts,
+        }
+    }
+
+    fn make_settlement(engine: &MockHttpClient) -> Settlement {
+        Settlement::new(
+            make_config(),
+            ILDCP_RESPONSE.clone(),
+            Arc::new(Metrics::default()),
+            Arc::new(engine.clone()),
+        ).unwrap()
+    }
+
+    /// For the tests where the engine must not be contacted.
+    fn no_engine() -> MockHttpClient {
+        MockHttpClient::new(|request| panic!("unexpected request: {:?}", request))
+    }
+
+    fn make_request(account: &str, prepare: ilp::Prepare) -> MockRequest {
+        MockRequest::new(prepare).from_account(account, Relation::Peer)
+    }
+
+    async fn wait_for_settlement(settlement: &Settlement, account: &str) {
+        while settlement.balances()[account].is_settling {
+            tokio::time::delay_for(time::Duration::from_millis(1)).await;
+        }
+    }
+
+    fn make_message() -> ilp::Prepare {
+        ilp::PrepareBuilder {
+            destination: ilp::PeerProtocol::Settlement.destination(),
+            amount: 0,
+            expires_at: PREPARE.expires_at(),
+            execution_condition: ilp::PEER_PROTOCOL_CONDITION,
+            data: b"MESSAGE",
+        }.build()
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let config = serde_json::from_str::<SettlementConfig>(r#"{
+            "engine_url": "http://127.0.0.1:3000",
+            "accounts": {
+                "alice": { "settle_threshold": 100, "settle_to": 10 }
+            }
+        }"#).unwrap();
+        assert_eq!(config.accounts["alice"], SettlementAccountConfig {
+            settle_threshold: 100,
+            settle_to: 10,
+            asset_scale: None,
+        });
+    }
+
+    #[test]
+    fn test_new_invalid() {
+        let mut config = make_config();
+        config.accounts.get_mut("alice").unwrap().settle_to = 101;
+        let metrics = Arc::new(Metrics::default());
+        let engine = Arc::new(no_engine());
+        assert!(Settlement::new(config, ILDCP_RESPONSE.clone(), metrics.clone(), engine.clone()).is_err());
+
+        let mut config = make_config();
+        config.engine_url = "127.0.0.1:3000".to_owned();
+        assert!(Settlement::new(config, ILDCP_RESPONSE.clone(), metrics, engine).is_err());
+    }
+
+    #[test]
+    fn test_record_below_threshold() {
+        let settlement = make_settlement(&no_engine());
+        settlement.record_receivable("alice", 50);
+        settlement.record_payable("bob", 40);
+        settlement.record_receivable("carl", 50);
+        settlement.record_payable("alice", 40);
+        settlement.record_receivable("bob", 5);
+        let balances = settlement.balances();
+        assert_eq!(balances["alice"], Balance {
+            payable: 40,
+            receivable: 50,
+            is_settling: false,
+            pending: 0,
+        });
+        assert_eq!(balances["bob"], Balance {
+            payable: 40,
+            receivable: 5,
+            is_settling: false,
+            pending: 0,
+        });
+        assert!(!balances.contains_key("carl"));
+    }
+
+    #[test]
+    fn test_receive_settlement() {
+        let settlement = make_settlement(&no_engine());
+        let quantity = |amount: &str, scale| Quantity {
+            amount: amount.to_owned(),
+            scale,
+        };
+        let receivable = || settlement.balances()["alice"].receivable;
+        settlement.record_receivable("alice", 50);
+        // alice's amounts have a scale of 6.
+        assert_eq!(
+            settlement.receive_settlement("alice", "key1", quantity("2099", 8)),
+            Ok(quantity("20", 6)),
+        );
+        assert_eq!(receivable(), 30);
+        // A retry isn't credited again.
+        assert_eq!(
+            settlement.receive_settlement("alice", "key1", quantity("2099", 8)),
+            Ok(quantity("20", 6)),
+        );
+        assert_eq!(receivable(), 30);
+        assert_eq!(
+            settlement.receive_settlement("alice", "key1", quantity("1", 6)),
+            Err(ReceiveError::KeyReused),
+        );
+        assert_eq!(
+            settlement.receive_settlement("alice", "key2", quantity("4", 5)),
+            Ok(quantity("40", 6)),
+        );
+        assert_eq!(receivable(), 0);
+
+        assert_eq!(
+            settlement.receive_settlement("alice", "key3", quantity("x", 6)),
+            Err(ReceiveError::InvalidAmount),
+        );
+        assert_eq!(
+            settlement.receive_settlement("alice", "key3", quantity("100", 0)),
+            Ok(quantity("100000000", 6)),
+        );
+        assert_eq!(
+            settlement.receive_settlement("alice", "key4", quantity(&u64::MAX.to_string(), 0)),
+            Err(ReceiveError::InvalidAmount),
+        );
+        assert_eq!(
+            settlement.receive_settlement("carl", "key5", quantity("1", 6)),
+            Err(ReceiveError::UnknownAccount),
+        );
+    }
+
+    #[test]
+    fn test_scale_amount() {
+        assert_eq!(scale_amount(123, 2, 2), Some(123));
+        assert_eq!(scale_amount(123, 2, 4), Some(12_300));
+        assert_eq!(scale_amount(123, 4, 2), Some(1));
+        assert_eq!(scale_amount(123, 255, 0), Some(0));
+        assert_eq!(scale_amount(0, 0, 255), Some(0));
+        assert_eq!(scale_amount(2, 0, 255), None);
+        assert_eq!(scale_amount(u64::MAX, 0, 1), None);
+    }
+
+    #[tokio::test]
+    async fn test_settle() {
+        let engine = MockHttpClient::with_response(201, r#"{"amount":"110","scale":6}"#);
+        let settlement = make_settlement(&engine);
+        settlement.record_payable("alice", 60);
+        settlement.record_payable("alice", 60);
+        let balance = settlement.balances()["alice"];
+        assert_eq!(balance.payable, 10);
+        assert_eq!(balance.pending, 110);
+        assert!(balance.is_settling);
+        // While the settlement is in flight, another isn't started.
+        settlement.record_payable("alice", 100);
+        wait_for_settlement(&settlement, "alice").await;
+
+        assert_eq!(settlement.balances()["alice"].payable, 110);
+        assert_eq!(settlement.balances()["alice"].pending, 0);
+        assert_eq!(settlement.metrics.get(SETTLEMENTS, vec![
+            ("account", "alice".to_owned()),
+            ("result", "ok".to_owned()),
+        ]), 1);
+        let requests = engine.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, HttpMethod::Post);
+        assert_eq!(
+            requests[0].uri,
+            format!("{}/accounts/alice/settlements", testing::RECEIVER_ORIGIN),
+        );
+        assert!(requests[0].headers.iter().any(|(name, _)| *name == "Idempotency-Key"));
+        assert_eq!(requests[0].body.as_ref(), br#"{"amount":"110","scale":6}"#);
+    }
+
+    #[tokio::test]
+    async fn test_settle_error() {
+        let engine = MockHttpClient::with_response(500, "");
+        let settlement = make_settlement(&engine);
+        settlement.record_payable("alice", 150);
+        wait_for_settlement(&settlement, "alice").await;
+        // The next fulfill retries the same settlement, even though it doesn't
+        // cross the threshold.
+        settlement.record_payable("alice", 5);
+        wait_for_settlement(&settlement, "alice").await;
+
+        let balance = settlement.balances()["alice"];
+        assert_eq!(balance.payable, 15);
+        assert_eq!(balance.pending, 140);
+        let requests = engine.requests();
+        assert_eq!(requests.len(), 2);
+        let key = |request: &HttpRequest| request.headers
+            .iter()
+            .find(|(name, _)| *name == "Idempotency-Key")
+            .map(|(_, key)| key.clone())
+            .unwrap();
+        assert_eq!(key(&requests[0]), key(&requests[1]));
+        for request in &requests {
+            assert_eq!(request.body.as_ref(), br#"{"amount":"140","scale":6}"#);
+        }
+        assert_eq!(settlement.metrics.get(SETTLEMENTS, vec![
+            ("account", "alice".to_owned()),
+            ("result", "error".to_owned()),
+        ]), 2);
+    }
+
+    #[test]
+    fn test_service_passthrough() {
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let settlement = make_settlement(&no_engine());
+        let service = SettlementService::new(
+            testing::ADDRESS.to_address(),
+            Some(settlement.clone()),
+            next.clone(),
+        );
+        let request = make_request("alice", PREPARE.clone());
+        assert_eq!(block_on(service.call(request)), Ok(FULFILL.clone()));
+        assert_eq!(next.prepares().collect::<Vec<_>>(), vec![PREPARE.clone()]);
+        assert_eq!(settlement.balances()["alice"].receivable, PREPARE.amount());
+
+        let service = SettlementService::new(
+            testing::ADDRESS.to_address(),
+            Some(settlement.clone()),
+            MockService::new(Err(REJECT.clone())),
+        );
+        let request = make_request("alice", PREPARE.clone());
+        assert_eq!(block_on(service.call(request)), Err(REJECT.clone()));
+        // Rejected Prepares aren't owed.
+        assert_eq!(settlement.balances()["alice"].receivable, PREPARE.amount());
+    }
+
+    #[test]
+    fn test_service_unsettled_account() {
+        let service = SettlementService::new(
+            testing::ADDRESS.to_address(),
+            Some(make_settlement(&no_engine())),
+            testing::PanicService,
+        );
+        let reject = block_on(service.call(make_request("carl", make_message())))
+            .unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F00_BAD_REQUEST);
+
+        let service = SettlementService::new(
+            testing::ADDRESS.to_address(),
+            None,
+            testing::PanicService,
+        );
+        let reject = block_on(service.call(make_request("alice", make_message())))
+            .unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F00_BAD_REQUEST);
+    }
+
+    #[tokio::test]
+    async fn test_service_message() {
+        let engine = MockHttpClient::with_response(200, "RESPONSE");
+        let service = SettlementService::new(
+            testing::ADDRESS.to_address(),
+            Some(make_settlement(&engine)),
+            testing::PanicService,
+        );
+        let fulfill = service
+            .call(make_request("alice", make_message()))
+            .await
+            .unwrap();
+        assert_eq!(fulfill.fulfillment(), ilp::PEER_PROTOCOL_FULFILLMENT);
+        assert_eq!(fulfill.data(), b"RESPONSE");
+        let requests = engine.requests();
+        assert_eq!(
+            requests[0].uri,
+            format!("{}/accounts/alice/messages", testing::RECEIVER_ORIGIN),
+        );
+        assert_eq!(requests[0].body.as_ref(), b"MESSAGE");
+    }
+
+    #[tokio::test]
+    async fn test_service_message_error() {
+        let service = SettlementService::new(
+            testing::ADDRESS.to_address(),
+            Some(make_settlement(&MockHttpClient::with_response(404, ""))),
+            testing::PanicService,
+        );
+        let reject = service
+            .call(make_request("alice", make_message()))
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::T00_INTERNAL_ERROR);
+    }
+}
@@ -0,0 +1,420 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, Mutex};
+
+use bytes::Bytes;
+use futures::future::{Either, Ready, err, ok};
+use log::debug;
+use ring::rand::{SecureRandom, SystemRandom};
+use serde::{Deserialize, Deserializer, Serialize};
+
+use crate::{Request, RequestId, Service};
+use crate::SetupError;
+use crate::connector_info::ConnectorInfo;
+use crate::reject_reasons;
+use ilp::stream::{Frame, StreamPacket, crypto};
+
+/// Connection tokens are this many random bytes (before base64 encoding).
+const TOKEN_LEN: usize = 18;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpspConfig {
+    /// The receiver's addresses are `{connector address}.{segment}.{token}`.
+    #[serde(default = "default_segment")]
+    pub segment: String,
+    /// Each connection's shared secret is derived from this secret and the
+    /// connection's token, so that they don't need to be stored. Changing it
+    /// breaks existing connections.
+    pub server_secret: ServerSecret,
+}
+
+fn default_segment() -> String {
+    "spsp".to_owned()
+}
+
+#[derive(Clone, PartialEq)]
+pub struct ServerSecret(Bytes);
+
+impl ServerSecret {
+    pub fn new(secret: Bytes) -> Self {
+        ServerSecret(secret)
+    }
+}
+
+// Don't log the secret.
+impl fmt::Debug for ServerSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("ServerSecret(..)")
+    }
+}
+
+impl<'de> Deserialize<'de> for ServerSecret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let secret = String::deserialize(deserializer)?;
+        Ok(ServerSecret(Bytes::from(secret)))
+    }
+}
+
+/// A STREAM receiver for Prepares addressed to the connector's SPSP
+/// `segment`, which credits the amounts that it receives to an in-memory
+/// ledger, by connection.
+#[derive(Debug)]
+pub struct SpspReceiver {
+    /// The connector's address and asset, which may change when its ILDCP
+    /// config is refreshed.
+    connector: ConnectorInfo,
+    segment: String,
+    server_secret: ServerSecret,
+    received: Mutex<HashMap<String, u64>>,
+}
+
+/// The response to an SPSP query.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct SpspResponse {
+    pub destination_account: String,
+    pub shared_secret: String,
+}
+
+impl SpspReceiver {
+    pub fn new(config: SpspConfig, ildcp: impl Into<ConnectorInfo>)
+        -> Result<Self, SetupError>
+    {
+        let connector = ildcp.into();
+        if config.segment.is_empty() || config.segment.contains('.') {
+            return Err(SetupError::invalid_config({
+                "spsp.segment must be a single address segment"
+            }));
+        }
+        if config.server_secret.0.len() < 32 {
+            return Err(SetupError::invalid_config({
+                "spsp.server_secret must be at least 32 bytes"
+            }));
+        }
+        if connector.get().ildcp.is_none() {
+            return Err(SetupError::invalid_config({
+                "spsp requires the connector's asset"
+            }));
+        }
+        connector.address().with_suffix(config.segment.as_bytes())?;
+        Ok(SpspReceiver {
+            connector,
+            segment: config.segment,
+            server_secret: config.server_secret,
+            received: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Set up a new connection.
+    pub fn query(&self) -> SpspResponse {
+        let mut token = [0; TOKEN_LEN];
+        SystemRandom::new()
+            .fill(&mut token)
+            .expect("failed to generate a random token");
+        let token = base64::encode_config(token, base64::URL_SAFE_NO_PAD);
+        let shared_secret = self.shared_secret(token.as_bytes());
+        SpspResponse {
+            destination_account: format!(
+                "{}.{}.{}", self.connector.address(), self.segment, token,
+            ),
+            shared_secret: base64::encode(shared_secret),
+        }
+    }
+
+    /// The total amount received through the connection with the `token`.
+    pub fn received(&self, token: &str) -> u64 {
+        self.received
+            .lock()
+            .unwrap()
+            .get(token)
+            .copied()
+            .unwrap_or(0)
+    }
+
+    /// Returns `None` if the Prepare isn't addressed to the receiver.
+    fn receive(&self, prepare: &ilp::Prepare)
+        -> Option<Result<ilp::Fulfill, ilp::Reject>>
+    {
+        // The address and asset are read once, so that a concurrent ILDCP
+        // refresh can't mix the old and the new.
+        let connector = self.connector.get();
+        let address = connector.address.as_addr();
+        let destination = prepare.destination();
+        let token = self.connection_token(address, destination)?;
+        let shared_secret = self.shared_secret(token);
+        let request = match StreamPacket::decrypt(&shared_secret, prepare.data()) {
+            Ok(request) if request.ilp_packet_type == ilp::PacketType::Prepare =>
+                request,
+            _ => return Some(Err({
+                reject_reasons::INVALID_STREAM_PACKET
+                    .to_reject(address)
+            })),
+        };
+
+        let condition = crypto::generate_condition(&shared_secret, prepare.data());
+        let is_fulfillable = prepare.execution_condition() == &condition[..]
+            && prepare.amount() >= request.prepare_amount;
+        let asks_for_asset_details = request.frames
+            .iter()
+            .any(|frame| matches!(frame, Frame::ConnectionNewAddress { .. }));
+        let frames = match (asks_for_asset_details, &connector.ildcp) {
+            (true, Some(ildcp)) => vec![Frame::ConnectionAssetDetails {
+                source_asset_code: String::from_utf8_lossy(ildcp.asset_code())
+                    .into_owned(),
+                source_asset_scale: ildcp.asset_scale(),
+            }],
+            _ => Vec::new(),
+        };
+        let response = |ilp_packet_type| StreamPacket {
+            sequence: request.sequence,
+            ilp_packet_type,
+            prepare_amount: prepare.amount(),
+            frames: frames.clone(),
+        }.encrypt(&shared_secret);
+
+        if !is_fulfillable {
+            return Some(Err(reject_reasons::STREAM_REJECTED.to_reject_with_data(
+                address,
+                &response(ilp::PacketType::Reject),
+            )));
+        }
+
+        // Tokens are base64 (URL-safe), so they are always valid UTF-8.
+        let token = String::from_utf8_lossy(token).into_owned();
+        let mut received = self.received.lock().unwrap();
+        let total = received.entry(token).or_insert(0);
+        *total = total.saturating_add(prepare.amount());
+        drop(received);
+        Some(Ok(ilp::FulfillBuilder {
+            fulfillment: &crypto::generate_fulfillment(&shared_secret, prepare.data()),
+            data: &response(ilp::PacketType::Fulfill),
+        }.build()))
+    }
+
+    /// The segment after `{address}.{segment}`, if the destination is under
+    /// it.
+    fn connection_token<'a>(&self, address: ilp::Addr, destination: ilp::Addr<'a>)
+        -> Option<&'a [u8]>
+    {
+        let mut segments = destination.segments();
+        let is_match = destination.starts_with_prefix(address.as_ref())
+            && segments.nth(address.segments().count())
+                == Some(self.segment.as_bytes());
+        if !is_match {
+            return None;
+        }
+        segments.next()
+    }
+
+    fn shared_secret(&self, token: &[u8]) -> [u8; crypto::SHARED_SECRET_LEN] {
+        crypto::generate_shared_secret(&self.server_secret.0, token)
+    }
+}
+
+/// Fulfill (or reject) Prepares that are addressed to the `SpspReceiver`.
+/// Other Prepares are passed on.
+#[derive(Clone, Debug)]
+pub struct SpspService<S> {
+    receiver: Option<Arc<SpspReceiver>>,
+    next: S,
+}
+
+impl<S> SpspService<S> {
+    pub fn new(receiver: Option<Arc<SpspReceiver>>, next: S) -> Self {
+        SpspService { receiver, next }
+    }
+}
+
+impl<S, Req> Service<Req> for SpspService<S>
+where
+    S: Service<Req>,
+    Req: Request,
+{
+    type Future = Either<
+        Ready<Result<ilp::Fulfill, ilp::Reject>>,
+        S::Future,
+    >;
+
+    fn call(self, request: Req) -> Self::Future {
+        let receiver = match &self.receiver {
+            Some(receiver) => receiver,
+            None => return Either::Right(self.next.call(request)),
+        };
+        match receiver.receive(request.borrow()) {
+            Some(Ok(fulfill)) => {
+                debug!(
+                    "received STREAM packet: request_id={} amount={}",
+                    RequestId::of(&request), request.borrow().amount(),
+                );
+                Either::Left(ok(fulfill))
+            },
+            Some(Err(reject)) => Either::Left(err(reject)),
+            None => Either::Right(self.next.call(request)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_spsp_service {
+    use futures::executor::block_on;
+    use lazy_static::lazy_static;
+
+    use ilp::ildcp;
+
+    use crate::testing::{FULFILL, MockService, PREPARE};
+    use super::*;
+
+    lazy_static! {
+        static ref ILDCP: ildcp::Response = ildcp::ResponseBuilder {
+            client_address: ilp::Addr::new(b"example.connector"),
+            asset_scale: 9,
+            asset_code: b"XRP",
+        }.build();
+    }
+
+    fn make_receiver() -> Arc<SpspReceiver> {
+        Arc::new(SpspReceiver::new(SpspConfig {
+            segment: "spsp".to_owned(),
+            server_secret: ServerSecret::new(Bytes::from_static(&[0x42; 32])),
+        }, ILDCP.clone()).unwrap())
+    }
+
+    /// A Prepare (and its fulfillment) that pays `amount` through the
+    /// connection set up by `response`.
+    fn make_prepare(
+        response: &SpspResponse,
+        amount: u64,
+        min_amount: u64,
+        frames: Vec<Frame>,
+    ) -> (ilp::Prepare, [u8; 32]) {
+        let mut shared_secret = [0; 32];
+        shared_secret.copy_from_slice(&base64::decode(&response.shared_secret).unwrap());
+        let data = StreamPacket {
+            sequence: 1,
+            ilp_packet_type: ilp::PacketType::Prepare,
+            prepare_amount: min_amount,
+            frames,
+        }.encrypt(&shared_secret);
+        let prepare = ilp::PrepareBuilder {
+            amount,
+            expires_at: PREPARE.expires_at(),
+            execution_condition: &crypto::generate_condition(&shared_secret, &data),
+            destination: ilp::Address::try_from(Bytes::from(
+                response.destination_account.clone(),
+            )).unwrap().as_addr(),
+            data: &data,
+        }.build();
+        (prepare, crypto::generate_fulfillment(&shared_secret, &data))
+    }
+
+    #[test]
+    fn test_query() {
+        let receiver = make_receiver();
+        let response_1 = receiver.query();
+        let response_2 = receiver.query();
+        assert!(response_1.destination_account.starts_with("example.connector.spsp."));
+        assert_ne!(response_1.destination_account, response_2.destination_account);
+        assert_ne!(response_1.shared_secret, response_2.shared_secret);
+        assert_eq!(base64::decode(&response_1.shared_secret).unwrap().len(), 32);
+    }
+
+    #[test]
+    fn test_receive() {
+        let receiver = make_receiver();
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let service = SpspService::new(Some(Arc::clone(&receiver)), next.clone());
+        let response = receiver.query();
+        let token = response.destination_account
+            .rsplit('.')
+            .next()
+            .unwrap()
+            .to_owned();
+        let mut shared_secret = [0; 32];
+        shared_secret.copy_from_slice(&base64::decode(&response.shared_secret).unwrap());
+
+        let (prepare, fulfillment) = make_prepare(&response, 100, 90, vec![
+            Frame::ConnectionNewAddress {
+                source_account: ilp::Address::new(b"example.sender"),
+            },
+        ]);
+        let fulfill = block_on(service.clone().call(prepare)).unwrap();
+        assert_eq!(fulfill.fulfillment(), &fulfillment);
+        let stream_response = StreamPacket::decrypt(&shared_secret, fulfill.data()).unwrap();
+        assert_eq!(stream_response, StreamPacket {
+            sequence: 1,
+            ilp_packet_type: ilp::PacketType::Fulfill,
+            prepare_amount: 100,
+            frames: vec![Frame::ConnectionAssetDetails {
+                source_asset_code: "XRP".to_owned(),
+                source_asset_scale: 9,
+            }],
+        });
+        let (prepare, _fulfillment) = make_prepare(&response, 50, 0, vec![]);
+        assert!(block_on(service.clone().call(prepare)).is_ok());
+        assert_eq!(receiver.received(&token), 150);
+
+        // Less than the sender's minimum:
+        let (prepare, _fulfillment) = make_prepare(&response, 50, 51, vec![]);
+        let reject = block_on(service.clone().call(prepare)).unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F99_APPLICATION_ERROR);
+        assert_eq!(reject.triggered_by(), Some(ILDCP.client_address()));
+        let stream_response = StreamPacket::decrypt(&shared_secret, reject.data()).unwrap();
+        assert_eq!(stream_response.ilp_packet_type, ilp::PacketType::Reject);
+        assert_eq!(stream_response.prepare_amount, 50);
+        assert_eq!(receiver.received(&token), 150);
+
+        // Not a STREAM packet:
+        let mut prepare = make_prepare(&response, 50, 0, vec![]).0;
+        prepare = ilp::PrepareBuilder {
+            amount: prepare.amount(),
+            expires_at: prepare.expires_at(),
+            execution_condition: &[0; 32],
+            destination: prepare.destination(),
+            data: b"not encrypted",
+        }.build();
+        let reject = block_on(service.clone().call(prepare)).unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F06_UNEXPECTED_PAYMENT);
+        assert_eq!(reject.data(), reject_reasons::INVALID_STREAM_PACKET.id.as_bytes());
+
+        // Other destinations are passed on:
+        assert_eq!(next.requests().count(), 0);
+        assert!(block_on(service.call(PREPARE.clone())).is_ok());
+        assert_eq!(next.requests().count(), 1);
+    }
+
+    #[test]
+    fn test_connection_token() {
+        let receiver = make_receiver();
+        let token = |destination| {
+            receiver.connection_token(ILDCP.client_address(), ilp::Addr::new(destination))
+        };
+        assert_eq!(token(b"example.connector.spsp.abc"), Some(&b"abc"[..]));
+        assert_eq!(
+            token(b"example.connector.spsp.abc.def"),
+            Some(&b"abc"[..]),
+        );
+        assert_eq!(token(b"example.connector.spsp"), None);
+        assert_eq!(token(b"example.connector.spspx.abc"), None);
+        assert_eq!(token(b"example.connector.alice"), None);
+    }
+
+    #[test]
+    fn test_new() {
+        let config = SpspConfig {
+            segment: "spsp".to_owned(),
+            server_secret: ServerSecret::new(Bytes::from_static(&[0x42; 32])),
+        };
+        assert!(SpspReceiver::new(config.clone(), ILDCP.clone()).is_ok());
+        assert!(SpspReceiver::new(SpspConfig {
+            segment: "a.b".to_owned(),
+            ..config.clone()
+        }, ILDCP.clone()).is_err());
+        assert!(SpspReceiver::new(SpspConfig {
+            server_secret: ServerSecret::new(Bytes::from_static(b"short")),
+            ..config
+        }, ILDCP.clone()).is_err());
+    }
+}
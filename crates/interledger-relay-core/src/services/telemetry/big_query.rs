@@ -0,0 +1,284 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time;
+
+use futures::prelude::*;
+use log::trace;
+
+use crate::transport::HttpClient;
+use super::{ClientError, Row, Sink, SinkError};
+use super::client::post_json;
+
+/// Stream rows into a BigQuery table.
+///
+/// See: <https://cloud.google.com/bigquery/docs/reference/rest/>
+#[derive(Clone, Debug)]
+pub struct BigQuerySink {
+    client: Arc<dyn HttpClient>,
+    //get_table_uri: String,
+    insert_all_uri: String,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct BigQueryConfig {
+    #[serde(default = "default_origin")]
+    pub origin: String,
+    pub project_id: String,
+    pub dataset_id: String,
+    pub table_id: String,
+    /// <https://docs.rs/yup-oauth2/4.1.2/yup_oauth2/struct.ServiceAccountKey.html>
+    pub service_account_key_file: Option<std::path::PathBuf>,
+    //pub queue_capacity: usize,
+}
+
+fn default_origin() -> String { "https://bigquery.googleapis.com".to_owned() }
+
+impl BigQuerySink {
+    pub const SCOPES: &'static [&'static str] =
+        &["https://www.googleapis.com/auth/bigquery"];
+
+    pub fn new(
+        config: &BigQueryConfig,
+        client: Arc<dyn HttpClient>,
+    ) -> Self {
+        BigQuerySink {
+            client,
+            //get_table_uri: config.get_table_uri(),
+            insert_all_uri: config.insert_all_uri(),
+        }
+    }
+
+    /*
+    pub async fn exists(&self) -> Result<bool, ClientError> {
+        let request = HttpRequest::get(&self.get_table_uri, usize::MAX)
+            .header("Accept", "application/json");
+        self.client
+            .request(request)
+            .map_ok(|response| response.is_success())
+            .map_err(ClientError::Http)
+            .await
+    }
+    */
+}
+
+/*
+/// <https://cloud.google.com/bigquery/docs/reference/rest/v2/tables/get>
+#[derive(Debug, serde::Deserialize)]
+struct GetTableResponse {
+    id: String,
+    // And many more... see <https://cloud.google.com/bigquery/docs/reference/rest/v2/tables#Table>
+}
+*/
+
+/// <https://cloud.google.com/bigquery/docs/reference/rest/v2/tabledata/insertAll#request-body>
+#[derive(Debug, PartialEq, serde::Serialize)]
+pub(super) struct InsertAllRequest<'a, D> {
+    pub rows: &'a [Row<D>]
+}
+
+/// <https://cloud.google.com/bigquery/docs/reference/rest/v2/tabledata/insertAll#response-body>
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub(super) struct InsertAllResponse {
+    #[serde(default)]
+    pub insert_errors: Vec<InsertError>,
+}
+
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub(super) struct InsertError {
+    pub index: u32,
+    pub errors: Vec<ErrorProto>,
+}
+
+/// <https://cloud.google.com/bigquery/docs/reference/rest/v2/ErrorProto>
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+pub(super) struct ErrorProto {
+    pub reason: String,
+    //location: String,
+    //debug_info: String,
+    pub message: String,
+}
+
+macro_rules! try_insert_all {
+    ($rows:expr, $future:expr) => {
+        match $future {
+            Ok(ok) => ok,
+            Err(error) => return Err({
+                SinkError::new($rows, error)
+            }),
+        }
+    };
+}
+
+impl BigQuerySink {
+    /// See:
+    ///
+    ///   * <https://cloud.google.com/bigquery/docs/reference/rest/v2/tabledata/insertAll>
+    ///   * <https://github.com/googleapis/nodejs-bigquery/blob/ea3d7afe18f8f22c6541043c92c26625ae9e0e85/src/table.ts#L1905>
+    ///
+    pub async fn insert_all<D>(self, rows: Vec<Row<D>>)
+        -> Result<(), SinkError<D>>
+    where
+        D: serde::Serialize + Clone + Send + Sync + 'static,
+    {
+        trace!("insert_all begin: rows={}", rows.len());
+        let json = try_insert_all!(rows,
+            serde_json::to_string(&InsertAllRequest { rows: &rows })
+                .map_err(ClientError::Serde));
+        let start = time::Instant::now();
+
+        let response_result = post_json::<InsertAllResponse>(
+            self.client.as_ref(),
+            &self.insert_all_uri,
+            json,
+        ).await;
+
+        let elapsed = time::Instant::now() - start;
+        let response = match response_result {
+            Ok(response) => response,
+            Err(error) => {
+                throttled_warn!(
+                    "",
+                    "insert_all error: elapsed={:?} error={:?} rows={}",
+                    elapsed, error, rows.len(),
+                );
+                return Err(SinkError::new(rows, error));
+            },
+        };
+        if response.insert_errors.is_empty() {
+            trace!(
+                "insert_all success: elapsed={:?} rows={:?}",
+                elapsed, rows.len(),
+            );
+            return Ok(());
+        }
+
+        throttled_warn!(
+            "",
+            "insert_all partial error: elapsed={:?} errors={} errors[0]={:?}",
+            elapsed,
+            response.insert_errors.len(),
+            &response.insert_errors[0],
+        );
+        let mut retries = Vec::with_capacity(response.insert_errors.len());
+        retries.extend({
+            response.insert_errors
+                .iter()
+                .map(|error| rows[error.index as usize].clone())
+        });
+        Err(SinkError::new(retries, ClientError::PartialError))
+    }
+}
+
+impl<D> Sink<D> for BigQuerySink
+where
+    D: serde::Serialize + Clone + Send + Sync + 'static,
+{
+    fn write_batch(&self, rows: Vec<Row<D>>) -> Pin<Box<
+        dyn Future<Output = Result<(), SinkError<D>>> + Send + 'static,
+    >> {
+        Box::pin(self.clone().insert_all(rows))
+    }
+}
+
+impl BigQueryConfig {
+    /*
+    pub(crate) fn get_table_uri(&self) -> String {
+        format!(
+            "{}/bigquery/v2/projects/{}/datasets/{}/tables/{}",
+            self.origin,
+            self.project_id,
+            self.dataset_id,
+            self.table_id,
+        )
+    }
+    */
+
+    pub(crate) fn insert_all_uri(&self) -> String {
+        use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
+        const CHARS: &percent_encoding::AsciiSet = &NON_ALPHANUMERIC.remove(b'_');
+        format!(
+            "{}/bigquery/v2/projects/{}/datasets/{}/tables/{}/insertAll",
+            self.origin,
+            percent_encode(self.project_id.as_bytes(), CHARS),
+            percent_encode(self.dataset_id.as_bytes(), CHARS),
+            percent_encode(self.table_id.as_bytes(), CHARS),
+        )
+    }
+}
+
+#[cfg(test)]
+mod test_big_query_sink {
+    use lazy_static::lazy_static;
+
+    use crate::testing::{self, MockHttpClient};
+    use crate::transport::HttpMethod;
+    use super::*;
+
+    lazy_static! {
+        static ref CONFIG: BigQueryConfig = BigQueryConfig {
+            origin: testing::RECEIVER_ORIGIN.to_owned(),
+            project_id: "PROJECT_ID".to_owned(),
+            dataset_id: "DATASET_ID".to_owned(),
+            table_id: "TABLE_ID".to_owned(),
+            service_account_key_file: None,
+            //batch_capacity: 3,
+            //queue_capacity: 6,
+        };
+
+        static ref ROWS: Vec<Row<i32>> =
+            vec![Row::new(1), Row::new(2), Row::new(3)];
+    }
+
+    fn make_response(insert_errors: Vec<InsertError>) -> MockHttpClient {
+        MockHttpClient::with_response(200, {
+            serde_json::to_vec(&InsertAllResponse { insert_errors }).unwrap()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_insert_all_ok() {
+        let client = make_response(vec![]);
+        let sink = BigQuerySink::new(&CONFIG, Arc::new(client.clone()));
+        sink.insert_all(ROWS.clone()).await.unwrap();
+
+        let requests = client.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, HttpMethod::Post);
+        assert_eq!(
+            requests[0].uri,
+            format!(
+                "{}/bigquery/v2/projects/PROJECT_ID/datasets/DATASET_ID/tables/TABLE_ID/insertAll",
+                testing::RECEIVER_ORIGIN,
+            ),
+        );
+        assert_eq!(
+            requests[0].body.as_ref(),
+            serde_json::to_vec(&InsertAllRequest { rows: &ROWS })
+                .unwrap()
+                .as_slice(),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_insert_all_partial_error() {
+        let client = make_response(vec![
+            InsertError { index: 1, errors: vec![] },
+        ]);
+        let sink = BigQuerySink::new(&CONFIG, Arc::new(client));
+        assert_eq!(
+            sink.insert_all(ROWS.clone()).await.unwrap_err().retries,
+            vec![ROWS[1].clone()],
+        );
+    }
+
+    #[tokio::test]
+    async fn test_insert_all_total_error() {
+        let client = MockHttpClient::with_response(500, "");
+        let sink = BigQuerySink::new(&CONFIG, Arc::new(client));
+        let error = sink.insert_all(ROWS.clone()).await.unwrap_err();
+        assert_eq!(error.retries, ROWS.clone());
+        assert!(matches!(error.error, ClientError::StatusCode(500)));
+    }
+}
@@ -0,0 +1,46 @@
+use log::debug;
+
+use crate::transport::{HttpClient, HttpError, HttpRequest};
+
+#[derive(Debug)]
+pub enum ClientError {
+    Http(HttpError),
+    StatusCode(u16),
+    ResponseTooLarge,
+    Serde(serde_json::Error),
+    PartialError,
+    Io(std::io::Error),
+    Timeout,
+    /// A Kafka error code.
+    Kafka(i16),
+    InvalidResponse,
+}
+
+/// POST the JSON request to a Google Cloud API, and parse its JSON response.
+pub async fn post_json<Resp>(client: &dyn HttpClient, uri: &str, json: String)
+    -> Result<Resp, ClientError>
+where
+    Resp: for<'q> serde::Deserialize<'q> + Send + 'static,
+{
+    let request = HttpRequest::post(uri, json, usize::MAX)
+        .header("Accept", "application/json")
+        .header("Content-Type", "application/json");
+    let response = client
+        .request(request)
+        .await
+        .map_err(|error| match error {
+            HttpError::ResponseTooLarge => ClientError::ResponseTooLarge,
+            error => ClientError::Http(error),
+        })?;
+
+    if response.status != 200 {
+        debug!(
+            "response error: status={} body='{:?}'",
+            response.status, response.body,
+        );
+        return Err(ClientError::StatusCode(response.status));
+    }
+
+    serde_json::from_slice::<Resp>(&response.body)
+        .map_err(ClientError::Serde)
+}
@@ -0,0 +1,245 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+
+use futures::prelude::*;
+use log::{info, trace};
+
+use super::{ClientError, Row, Sink, SinkError};
+
+/// Append each row as a line of JSON (NDJSON) to a local file, or to stdout.
+/// Files are rotated by size: `file` is renamed to `file.1`, `file.1` to
+/// `file.2`, and so on, up to `max_files`.
+///
+/// Writing is all-or-nothing per batch, so on error every row is retried. A
+/// partially written batch may therefore be duplicated.
+#[derive(Clone, Debug)]
+pub struct FileSink {
+    config: Arc<FileConfig>,
+    state: Arc<Mutex<FileState>>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct FileConfig {
+    /// The file to append rows to, or `"-"` for stdout.
+    pub file: PathBuf,
+    /// Rotate the file before it would grow past this many bytes. Files are
+    /// never rotated when this is unset, or when writing to stdout.
+    #[serde(default)]
+    pub max_file_size: Option<u64>,
+    /// The number of rotated files to keep.
+    #[serde(default = "default_max_files")]
+    pub max_files: usize,
+}
+
+fn default_max_files() -> usize { 5 }
+
+#[derive(Debug, Default)]
+struct FileState {
+    /// Opened on the first write, and re-opened after an error or rotation.
+    file: Option<fs::File>,
+    size: u64,
+}
+
+impl FileSink {
+    pub fn new(config: &FileConfig) -> Self {
+        FileSink {
+            config: Arc::new(config.clone()),
+            state: Arc::new(Mutex::new(FileState::default())),
+        }
+    }
+
+    pub async fn append<D>(self, rows: Vec<Row<D>>)
+        -> Result<(), SinkError<D>>
+    where
+        D: serde::Serialize + Clone + Send + Sync + 'static,
+    {
+        trace!("append begin: rows={}", rows.len());
+        let lines = match make_lines(&rows) {
+            Ok(lines) => lines,
+            Err(error) => return Err(SinkError::new(rows, error)),
+        };
+
+        let result = tokio::task::spawn_blocking(move || {
+            self.write_lines(&lines)
+        }).await;
+        let result = match result {
+            Ok(result) => result,
+            // The write panicked, or was cancelled by a runtime shutdown.
+            Err(join_error) =>
+                Err(io::Error::new(io::ErrorKind::Interrupted, join_error)),
+        };
+
+        match result {
+            Ok(()) => {
+                trace!("append success: rows={}", rows.len());
+                Ok(())
+            },
+            Err(error) => {
+                throttled_warn!("", "append error: error={:?} rows={}", error, rows.len());
+                Err(SinkError::new(rows, ClientError::Io(error)))
+            },
+        }
+    }
+
+    fn write_lines(&self, lines: &[u8]) -> io::Result<()> {
+        if self.config.file == Path::new("-") {
+            let stdout = io::stdout();
+            let mut stdout = stdout.lock();
+            stdout.write_all(lines)?;
+            return stdout.flush();
+        }
+
+        let mut state = self.state.lock().unwrap();
+        let result = self.write_file(&mut state, lines);
+        if result.is_err() {
+            // Start over with a fresh file handle.
+            *state = FileState::default();
+        }
+        result
+    }
+
+    fn write_file(&self, state: &mut FileState, lines: &[u8]) -> io::Result<()> {
+        if state.file.is_none() {
+            self.open(state)?;
+        }
+        if let Some(max_file_size) = self.config.max_file_size {
+            if state.size > 0 && state.size + lines.len() as u64 > max_file_size {
+                // Close the file before renaming it.
+                *state = FileState::default();
+                self.rotate()?;
+                self.open(state)?;
+            }
+        }
+
+        let file = state.file.as_mut().unwrap();
+        file.write_all(lines)?;
+        file.flush()?;
+        state.size += lines.len() as u64;
+        Ok(())
+    }
+
+    fn open(&self, state: &mut FileState) -> io::Result<()> {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&self.config.file)?;
+        state.size = file.metadata()?.len();
+        state.file = Some(file);
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        info!("rotating file: file={:?}", self.config.file);
+        if self.config.max_files == 0 {
+            return fs::remove_file(&self.config.file);
+        }
+        for index in (1..self.config.max_files).rev() {
+            let from = self.rotated_path(index);
+            if from.exists() {
+                fs::rename(from, self.rotated_path(index + 1))?;
+            }
+        }
+        fs::rename(&self.config.file, self.rotated_path(1))
+    }
+
+    fn rotated_path(&self, index: usize) -> PathBuf {
+        let mut path = self.config.file.clone().into_os_string();
+        path.push(format!(".{}", index));
+        PathBuf::from(path)
+    }
+}
+
+impl<D> Sink<D> for FileSink
+where
+    D: serde::Serialize + Clone + Send + Sync + 'static,
+{
+    fn write_batch(&self, rows: Vec<Row<D>>) -> Pin<Box<
+        dyn Future<Output = Result<(), SinkError<D>>> + Send + 'static,
+    >> {
+        Box::pin(self.clone().append(rows))
+    }
+}
+
+fn make_lines<D>(rows: &[Row<D>]) -> Result<Vec<u8>, ClientError>
+where
+    D: serde::Serialize,
+{
+    let mut lines = Vec::new();
+    for row in rows {
+        serde_json::to_writer(&mut lines, &row.json)
+            .map_err(ClientError::Serde)?;
+        lines.push(b'\n');
+    }
+    Ok(lines)
+}
+
+#[cfg(test)]
+mod test_file_sink {
+    use super::*;
+
+    fn make_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir()
+            .join(format!("ilp-relay-{}-{}", name, uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        dir
+    }
+
+    fn make_rows(values: &[i32]) -> Vec<Row<i32>> {
+        values.iter().cloned().map(Row::new).collect()
+    }
+
+    #[tokio::test]
+    async fn test_append() {
+        let dir = make_dir("append");
+        let path = dir.join("rows.json");
+        fs::write(&path, "0\n").unwrap();
+        let sink = FileSink::new(&FileConfig {
+            file: path.clone(),
+            max_file_size: None,
+            max_files: 1,
+        });
+        sink.clone().append(make_rows(&[1, 2])).await.unwrap();
+        sink.clone().append(make_rows(&[3])).await.unwrap();
+        assert_eq!(fs::read_to_string(&path).unwrap(), "0\n1\n2\n3\n");
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_rotate() {
+        let dir = make_dir("rotate");
+        let path = dir.join("rows.json");
+        let sink = FileSink::new(&FileConfig {
+            file: path.clone(),
+            max_file_size: Some(4),
+            max_files: 2,
+        });
+        for values in &[[1, 2], [3, 4], [5, 6], [7, 8]] {
+            sink.clone().append(make_rows(values)).await.unwrap();
+        }
+        let read = |path: PathBuf| fs::read_to_string(path).unwrap();
+        assert_eq!(read(path.clone()), "7\n8\n");
+        assert_eq!(read(dir.join("rows.json.1")), "5\n6\n");
+        assert_eq!(read(dir.join("rows.json.2")), "3\n4\n");
+        assert!(!dir.join("rows.json.3").exists());
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[tokio::test]
+    async fn test_append_error() {
+        let dir = make_dir("error");
+        let sink = FileSink::new(&FileConfig {
+            file: dir.join("missing").join("rows.json"),
+            max_file_size: None,
+            max_files: 1,
+        });
+        let rows = make_rows(&[1, 2]);
+        let error = sink.append(rows.clone()).await.unwrap_err();
+        assert_eq!(error.retries, rows);
+        assert!(matches!(error.error, ClientError::Io(_)));
+        fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -0,0 +1,324 @@
+//! A minimal LZ4 frame encoder (greedy, single-pass), for compressing Kafka
+//! record batches.
+//!
+//! # References
+//!
+//!   * <https://github.com/lz4/lz4/blob/dev/doc/lz4_Frame_format.md>
+//!   * <https://github.com/lz4/lz4/blob/dev/doc/lz4_Block_format.md>
+//!
+
+static MAGIC: [u8; 4] = [0x04, 0x22, 0x4d, 0x18];
+/// Version `01`, independent blocks, no checksums or content size.
+const FLAGS: u8 = 0b0110_0000;
+/// 64 KB maximum block size (Kafka's default).
+const BLOCK_DESCRIPTOR: u8 = 0b0100_0000;
+const BLOCK_SIZE: usize = 64 * 1024;
+/// A block with this bit set in its size is stored uncompressed.
+const UNCOMPRESSED_BIT: u32 = 1 << 31;
+
+const MIN_MATCH: usize = 4;
+/// The last match must start at least this many bytes before the end.
+const MF_LIMIT: usize = 12;
+/// The last bytes of a block are always literals.
+const LAST_LITERALS: usize = 5;
+const MAX_OFFSET: usize = 0xffff;
+const HASH_LOG: u32 = 12;
+
+pub fn compress(input: &[u8]) -> Vec<u8> {
+    let mut output = Vec::with_capacity(input.len() / 2 + 16);
+    output.extend_from_slice(&MAGIC);
+    output.push(FLAGS);
+    output.push(BLOCK_DESCRIPTOR);
+    output.push((xxh32(&[FLAGS, BLOCK_DESCRIPTOR], 0) >> 8) as u8);
+
+    let mut block = Vec::with_capacity(BLOCK_SIZE);
+    for chunk in input.chunks(BLOCK_SIZE) {
+        block.clear();
+        compress_block(chunk, &mut block);
+        if block.len() < chunk.len() {
+            output.extend_from_slice(&(block.len() as u32).to_le_bytes());
+            output.extend_from_slice(&block);
+        } else {
+            let size = chunk.len() as u32 | UNCOMPRESSED_BIT;
+            output.extend_from_slice(&size.to_le_bytes());
+            output.extend_from_slice(chunk);
+        }
+    }
+    // End mark:
+    output.extend_from_slice(&[0; 4]);
+    output
+}
+
+fn compress_block(input: &[u8], output: &mut Vec<u8>) {
+    let mut table = vec![usize::MAX; 1 << HASH_LOG];
+    let mut anchor = 0;
+    let mut position = 0;
+    if input.len() > MF_LIMIT {
+        let match_limit = input.len() - MF_LIMIT;
+        while position < match_limit {
+            let sequence = read_u32(input, position);
+            let hash = (sequence.wrapping_mul(2_654_435_761) >> (32 - HASH_LOG)) as usize;
+            let candidate = std::mem::replace(&mut table[hash], position);
+            let is_match = candidate != usize::MAX
+                && position - candidate <= MAX_OFFSET
+                && read_u32(input, candidate) == sequence;
+            if !is_match {
+                position += 1;
+                continue;
+            }
+
+            let max_len = input.len() - LAST_LITERALS - position;
+            let mut len = MIN_MATCH;
+            while len < max_len && input[candidate + len] == input[position + len] {
+                len += 1;
+            }
+            write_sequence(
+                output,
+                &input[anchor..position],
+                Some((position - candidate, len)),
+            );
+            position += len;
+            anchor = position;
+        }
+    }
+    write_sequence(output, &input[anchor..], None);
+}
+
+/// Write the literals, followed by the match's `(offset, length)` (the last
+/// sequence of a block has no match).
+fn write_sequence(
+    output: &mut Vec<u8>,
+    literals: &[u8],
+    match_: Option<(usize, usize)>,
+) {
+    let match_len = match_.map(|(_offset, len)| len - MIN_MATCH).unwrap_or(0);
+    let token = (std::cmp::min(literals.len(), 15) << 4)
+        | std::cmp::min(match_len, 15);
+    output.push(token as u8);
+    if literals.len() >= 15 {
+        write_length(output, literals.len() - 15);
+    }
+    output.extend_from_slice(literals);
+
+    if let Some((offset, _len)) = match_ {
+        output.extend_from_slice(&(offset as u16).to_le_bytes());
+        if match_len >= 15 {
+            write_length(output, match_len - 15);
+        }
+    }
+}
+
+fn write_length(output: &mut Vec<u8>, mut length: usize) {
+    while length >= 255 {
+        output.push(255);
+        length -= 255;
+    }
+    output.push(length as u8);
+}
+
+fn read_u32(buffer: &[u8], offset: usize) -> u32 {
+    let mut bytes = [0; 4];
+    bytes.copy_from_slice(&buffer[offset..offset + 4]);
+    u32::from_le_bytes(bytes)
+}
+
+/// <https://github.com/Cyan4973/xxHash/blob/dev/doc/xxhash_spec.md>
+fn xxh32(input: &[u8], seed: u32) -> u32 {
+    const PRIME_1: u32 = 2_654_435_761;
+    const PRIME_2: u32 = 2_246_822_519;
+    const PRIME_3: u32 = 3_266_489_917;
+    const PRIME_4: u32 = 668_265_263;
+    const PRIME_5: u32 = 374_761_393;
+
+    let round = |acc: u32, lane: u32| {
+        acc.wrapping_add(lane.wrapping_mul(PRIME_2))
+            .rotate_left(13)
+            .wrapping_mul(PRIME_1)
+    };
+
+    let mut stripes = input.chunks_exact(16);
+    let mut hash = if input.len() >= 16 {
+        let mut acc = [
+            seed.wrapping_add(PRIME_1).wrapping_add(PRIME_2),
+            seed.wrapping_add(PRIME_2),
+            seed,
+            seed.wrapping_sub(PRIME_1),
+        ];
+        for stripe in &mut stripes {
+            for (i, acc) in acc.iter_mut().enumerate() {
+                *acc = round(*acc, read_u32(stripe, i * 4));
+            }
+        }
+        acc[0].rotate_left(1)
+            .wrapping_add(acc[1].rotate_left(7))
+            .wrapping_add(acc[2].rotate_left(12))
+            .wrapping_add(acc[3].rotate_left(18))
+    } else {
+        seed.wrapping_add(PRIME_5)
+    };
+    hash = hash.wrapping_add(input.len() as u32);
+
+    let mut remainder = stripes.remainder();
+    while remainder.len() >= 4 {
+        hash = hash
+            .wrapping_add(read_u32(remainder, 0).wrapping_mul(PRIME_3))
+            .rotate_left(17)
+            .wrapping_mul(PRIME_4);
+        remainder = &remainder[4..];
+    }
+    for byte in remainder {
+        hash = hash
+            .wrapping_add(u32::from(*byte).wrapping_mul(PRIME_5))
+            .rotate_left(11)
+            .wrapping_mul(PRIME_1);
+    }
+
+    hash ^= hash >> 15;
+    hash = hash.wrapping_mul(PRIME_2);
+    hash ^= hash >> 13;
+    hash = hash.wrapping_mul(PRIME_3);
+    hash ^= hash >> 16;
+    hash
+}
+
+#[cfg(test)]
+mod test_lz4 {
+    use super::*;
+
+    #[test]
+    fn test_xxh32() {
+        assert_eq!(xxh32(b"", 0), 0x02cc_5d05);
+        assert_eq!(xxh32(b"a", 0), 0x550d_7456);
+        assert_eq!(xxh32(b"Nobody inspects the spammish repetition", 0), 0xe229_3b2f);
+    }
+
+    #[test]
+    fn test_frame_header() {
+        assert_eq!(
+            &compress(b"")[..],
+            &[0x04, 0x22, 0x4d, 0x18, 0x60, 0x40, 0x82, 0, 0, 0, 0][..],
+        );
+    }
+
+    /// The frames that the reference implementation (`lz4 -B4 -BI
+    /// --no-frame-crc`, v1.9.4) produces for the same inputs, which it also
+    /// decompresses back to the inputs. Its fast mode uses the same greedy
+    /// matching as this encoder (without acceleration, which only kicks in on
+    /// incompressible input), so the output is identical.
+    #[test]
+    fn test_reference_vectors() {
+        let vectors: &[(&[u8], &[u8])] = &[
+            (b"short", &[
+                0x04, 0x22, 0x4d, 0x18, 0x60, 0x40, 0x82,
+                0x05, 0x00, 0x00, 0x80, b's', b'h', b'o', b'r', b't',
+                0, 0, 0, 0,
+            ]),
+            (b"abcabcabcabcabcabcabcabcabcabcabcabc", &[
+                0x04, 0x22, 0x4d, 0x18, 0x60, 0x40, 0x82,
+                0x0d, 0x00, 0x00, 0x00,
+                0x3f, b'a', b'b', b'c', 0x03, 0x00, 0x09,
+                0x50, b'b', b'c', b'a', b'b', b'c',
+                0, 0, 0, 0,
+            ]),
+            (&[0; 300], &[
+                0x04, 0x22, 0x4d, 0x18, 0x60, 0x40, 0x82,
+                0x0c, 0x00, 0x00, 0x00,
+                0x1f, 0x00, 0x01, 0x00, 0xff, 0x14,
+                0x50, 0x00, 0x00, 0x00, 0x00, 0x00,
+                0, 0, 0, 0,
+            ]),
+        ];
+        for (input, frame) in vectors {
+            assert_eq!(&compress(input)[..], *frame);
+        }
+
+        let json = b"{\"account\":\"alice\",\"amount\":123}".repeat(8);
+        assert_eq!(compress(&json), &[
+            0x04, 0x22, 0x4d, 0x18, 0x60, 0x40, 0x82,
+            0x28, 0x00, 0x00, 0x00,
+            0xf2, 0x07, 0x7b, 0x22, 0x61, 0x63, 0x63, 0x6f, 0x75, 0x6e, 0x74,
+            0x22, 0x3a, 0x22, 0x61, 0x6c, 0x69, 0x63, 0x65, 0x22, 0x2c, 0x22,
+            0x61, 0x6d, 0x11, 0x00, 0x4f, 0x31, 0x32, 0x33, 0x7d, 0x20, 0x00,
+            0xc8, 0x50, 0x3a, 0x31, 0x32, 0x33, 0x7d,
+            0, 0, 0, 0,
+        ][..]);
+
+        // Two blocks (the first one full); too long to inline.
+        let repetitive = b"{\"account\":\"alice\",\"amount\":123}".repeat(3000);
+        let frame = compress(&repetitive);
+        assert_eq!(frame.len(), 474);
+        assert_eq!(xxh32(&frame, 0), 0x7a3d_394a);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let repetitive = b"{\"account\":\"alice\",\"amount\":123}".repeat(1000);
+        let random = (0..100_000_u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 13) as u8)
+            .collect::<Vec<_>>();
+        let inputs: &[&[u8]] = &[
+            b"",
+            b"short",
+            b"abcabcabcabcabcabcabcabcabcabcabcabc",
+            &repetitive,
+            &random,
+        ];
+        for input in inputs {
+            let compressed = compress(input);
+            assert_eq!(decompress(&compressed), *input);
+        }
+        assert!(compress(&repetitive).len() < repetitive.len() / 10);
+    }
+
+    fn decompress(frame: &[u8]) -> Vec<u8> {
+        assert_eq!(&frame[..7], &compress(b"")[..7]);
+        let mut input = &frame[7..];
+        let mut output = Vec::new();
+        loop {
+            let size = read_u32(input, 0);
+            input = &input[4..];
+            if size == 0 { break; }
+            let len = (size & !UNCOMPRESSED_BIT) as usize;
+            let block = &input[..len];
+            input = &input[len..];
+            if size & UNCOMPRESSED_BIT != 0 {
+                output.extend_from_slice(block);
+            } else {
+                decompress_block(block, &mut output);
+            }
+        }
+        assert!(input.is_empty());
+        output
+    }
+
+    fn decompress_block(mut block: &[u8], output: &mut Vec<u8>) {
+        let block_start = output.len();
+        let read_length = |block: &mut &[u8], mut length: usize| {
+            if length == 15 {
+                loop {
+                    let byte = block[0];
+                    *block = &block[1..];
+                    length += byte as usize;
+                    if byte != 255 { break; }
+                }
+            }
+            length
+        };
+        loop {
+            let token = block[0];
+            block = &block[1..];
+            let literals = read_length(&mut block, (token >> 4) as usize);
+            output.extend_from_slice(&block[..literals]);
+            block = &block[literals..];
+            if block.is_empty() { break; }
+
+            let offset = u16::from_le_bytes([block[0], block[1]]) as usize;
+            block = &block[2..];
+            let len = read_length(&mut block, (token & 15) as usize) + MIN_MATCH;
+            assert!(offset <= output.len() - block_start);
+            for _i in 0..len {
+                output.push(output[output.len() - offset]);
+            }
+        }
+    }
+}
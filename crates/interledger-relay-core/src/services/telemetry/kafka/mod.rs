@@ -0,0 +1,532 @@
+mod lz4;
+mod protocol;
+
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicI32, AtomicUsize, Ordering};
+use std::time;
+
+use futures::prelude::*;
+use log::trace;
+use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+use crate::transport::{Connection, SinkTransport};
+use super::{ClientError, Row, Sink, SinkError};
+use self::protocol::{Record, TopicMetadata};
+
+/// Keep at most this many idle connections to each broker.
+const MAX_IDLE_CONNECTIONS: usize = 4;
+
+/// Produce each row as a JSON record to a Kafka topic. The row's insert ID is
+/// included as the `insert_id` record header, so that consumers can
+/// deduplicate retried rows.
+///
+/// Each batch is written to a single partition; partitions are chosen
+/// round-robin. The partition leaders are looked up from the configured
+/// brokers, and looked up again after any error.
+///
+/// Connections to the brokers are kept open and reused. Each one carries a
+/// single request at a time, so concurrent batches open more of them.
+#[derive(Clone, Debug)]
+pub struct KafkaSink {
+    config: Arc<KafkaConfig>,
+    transport: Arc<dyn SinkTransport>,
+    metadata: Arc<Mutex<Option<Arc<TopicMetadata>>>>,
+    /// The idle connections, by broker address.
+    connections: Arc<Mutex<HashMap<String, Vec<Box<dyn Connection>>>>>,
+    next_partition: Arc<AtomicUsize>,
+    correlation_id: Arc<AtomicI32>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct KafkaConfig {
+    /// The bootstrap brokers (`host:port`).
+    pub brokers: Vec<String>,
+    pub topic: String,
+    #[serde(default = "default_compression")]
+    pub compression: KafkaCompression,
+    /// `0` (don't wait for an acknowledgement), `1` (the leader), or `-1`
+    /// (all in-sync replicas).
+    #[serde(default = "default_acks")]
+    pub acks: i16,
+    /// The limit on each produce, including the metadata lookup (if any).
+    #[serde(default = "default_request_timeout")]
+    pub request_timeout: time::Duration,
+    #[serde(default = "default_client_id")]
+    pub client_id: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum KafkaCompression {
+    None,
+    Lz4,
+}
+
+fn default_compression() -> KafkaCompression { KafkaCompression::None }
+fn default_acks() -> i16 { 1 }
+fn default_request_timeout() -> time::Duration { time::Duration::from_secs(10) }
+fn default_client_id() -> String { "interledger-relay".to_owned() }
+
+impl KafkaSink {
+    pub fn new(config: &KafkaConfig, transport: Arc<dyn SinkTransport>) -> Self {
+        KafkaSink {
+            config: Arc::new(config.clone()),
+            transport,
+            metadata: Arc::new(Mutex::new(None)),
+            connections: Arc::new(Mutex::new(HashMap::new())),
+            next_partition: Arc::new(AtomicUsize::new(0)),
+            correlation_id: Arc::new(AtomicI32::new(0)),
+        }
+    }
+
+    /// Producing is all-or-nothing, so on error every row is retried.
+    pub async fn produce<D>(self, rows: Vec<Row<D>>)
+        -> Result<(), SinkError<D>>
+    where
+        D: serde::Serialize + Clone + Send + Sync + 'static,
+    {
+        trace!("produce begin: rows={}", rows.len());
+        let records = match make_records(&rows) {
+            Ok(records) => records,
+            Err(error) => return Err(SinkError::new(rows, error)),
+        };
+        let start = time::Instant::now();
+
+        let result = tokio::time::timeout(
+            self.config.request_timeout,
+            self.produce_records(&records),
+        )
+            .await
+            .unwrap_or(Err(ClientError::Timeout));
+
+        let elapsed = time::Instant::now() - start;
+        match result {
+            Ok(()) => {
+                trace!("produce success: elapsed={:?} rows={}", elapsed, rows.len());
+                Ok(())
+            },
+            Err(error) => {
+                throttled_warn!(
+                    "",
+                    "produce error: elapsed={:?} error={:?} rows={}",
+                    elapsed, error, rows.len(),
+                );
+                // The leader may have moved.
+                self.metadata.lock().unwrap().take();
+                Err(SinkError::new(rows, error))
+            },
+        }
+    }
+
+    async fn produce_records(&self, records: &[Record])
+        -> Result<(), ClientError>
+    {
+        let metadata = self.topic_metadata().await?;
+        let index = self.next_partition.fetch_add(1, Ordering::Relaxed)
+            % metadata.partitions.len();
+        let (partition, leader) = &metadata.partitions[index];
+        let timestamp_ms = time::SystemTime::now()
+            .duration_since(time::UNIX_EPOCH)
+            .map(|since_epoch| since_epoch.as_millis() as i64)
+            .unwrap_or(0);
+        let record_batch = protocol::encode_record_batch(
+            records,
+            self.config.compression,
+            timestamp_ms,
+        );
+        let body = protocol::encode_produce_request(
+            self.config.acks,
+            self.config.request_timeout.as_millis() as i32,
+            &self.config.topic,
+            *partition,
+            &record_batch,
+        );
+        // With `acks=0` the broker doesn't respond at all.
+        let expect_response = self.config.acks != 0;
+        let response =
+            self.request(leader, protocol::PRODUCE, &body, expect_response).await?;
+        if expect_response {
+            protocol::decode_produce_response(&response)?;
+        }
+        Ok(())
+    }
+
+    async fn topic_metadata(&self) -> Result<Arc<TopicMetadata>, ClientError> {
+        let cached = self.metadata.lock().unwrap().clone();
+        if let Some(metadata) = cached {
+            return Ok(metadata);
+        }
+
+        let body = protocol::encode_metadata_request(&self.config.topic);
+        let mut last_error = ClientError::InvalidResponse;
+        for broker in &self.config.brokers {
+            let result = self
+                .request(broker, protocol::METADATA, &body, true)
+                .await
+                .and_then(|response| {
+                    protocol::decode_metadata_response(
+                        &response,
+                        &self.config.topic,
+                    )
+                });
+            match result {
+                Ok(metadata) => {
+                    let metadata = Arc::new(metadata);
+                    *self.metadata.lock().unwrap() = Some(Arc::clone(&metadata));
+                    return Ok(metadata);
+                },
+                Err(error) => {
+                    throttled_warn!(
+                        broker,
+                        "metadata error: broker={} error={:?}",
+                        broker, error,
+                    );
+                    last_error = error;
+                },
+            }
+        }
+        Err(last_error)
+    }
+
+    /// Send the request on an idle connection to the broker (or a new one),
+    /// and return the response body.
+    async fn request(
+        &self,
+        address: &str,
+        api_key: i16,
+        body: &[u8],
+        expect_response: bool,
+    ) -> Result<Vec<u8>, ClientError> {
+        let correlation_id =
+            self.correlation_id.fetch_add(1, Ordering::Relaxed);
+        let request = protocol::encode_request(
+            api_key,
+            correlation_id,
+            &self.config.client_id,
+            body,
+        );
+        let idle = self.connections
+            .lock()
+            .unwrap()
+            .get_mut(address)
+            .and_then(Vec::pop);
+        let reused = match idle {
+            Some(mut stream) => exchange(&mut stream, &request, expect_response)
+                .await
+                .ok()
+                .map(|response| (stream, response)),
+            None => None,
+        };
+        let (stream, response) = match reused {
+            Some(reused) => reused,
+            // There was no idle connection, or the broker had closed it. (If
+            // the broker did get the request, its rows are deduplicated by
+            // their `insert_id`.)
+            None => {
+                let mut stream = self.transport
+                    .connect(address)
+                    .await
+                    .map_err(ClientError::Io)?;
+                let response =
+                    exchange(&mut stream, &request, expect_response).await?;
+                (stream, response)
+            },
+        };
+        let response = match response {
+            Some(response) => response,
+            None => {
+                self.release(address, stream);
+                return Ok(Vec::new());
+            },
+        };
+        // A connection is only reused once its response has been read
+        // completely, and matched to the request.
+        let response = protocol::decode_response(&response, correlation_id)?
+            .to_vec();
+        self.release(address, stream);
+        Ok(response)
+    }
+
+    /// Return the connection to the idle pool.
+    fn release(&self, address: &str, stream: Box<dyn Connection>) {
+        let mut connections = self.connections.lock().unwrap();
+        let idle = connections.entry(address.to_owned()).or_default();
+        if idle.len() < MAX_IDLE_CONNECTIONS {
+            idle.push(stream);
+        }
+    }
+}
+
+/// Write the request, and read the response (if one is expected).
+async fn exchange(
+    stream: &mut Box<dyn Connection>,
+    request: &[u8],
+    expect_response: bool,
+) -> Result<Option<Vec<u8>>, ClientError> {
+    stream.write_all(request).await.map_err(ClientError::Io)?;
+    if !expect_response {
+        return Ok(None);
+    }
+
+    let size = stream.read_i32().await.map_err(ClientError::Io)?;
+    if size < 0 {
+        return Err(ClientError::InvalidResponse);
+    }
+    if size as usize > protocol::MAX_RESPONSE_SIZE {
+        return Err(ClientError::ResponseTooLarge);
+    }
+    let mut response = vec![0; size as usize];
+    stream.read_exact(&mut response).await.map_err(ClientError::Io)?;
+    Ok(Some(response))
+}
+
+impl<D> Sink<D> for KafkaSink
+where
+    D: serde::Serialize + Clone + Send + Sync + 'static,
+{
+    fn write_batch(&self, rows: Vec<Row<D>>) -> Pin<Box<
+        dyn Future<Output = Result<(), SinkError<D>>> + Send + 'static,
+    >> {
+        Box::pin(self.clone().produce(rows))
+    }
+}
+
+fn make_records<D>(rows: &[Row<D>]) -> Result<Vec<Record>, ClientError>
+where
+    D: serde::Serialize,
+{
+    rows
+        .iter()
+        .map(|row| Ok(Record {
+            value: serde_json::to_vec(&row.json)?,
+            insert_id: row.insert_id,
+        }))
+        .collect::<Result<Vec<_>, serde_json::Error>>()
+        .map_err(ClientError::Serde)
+}
+
+#[cfg(test)]
+mod test_kafka_sink {
+    use bytes::{Buf, BufMut};
+    use tokio::sync::mpsc;
+
+    use crate::testing::{MockSinkTransport, Pipe};
+    use super::*;
+
+    /// The mock broker's address. It isn't a real socket: the sink's
+    /// connections to it are `Pipe`s.
+    const BROKER_HOST: &str = "127.0.0.1";
+    const BROKER_PORT: u16 = 9092;
+
+    fn make_config(address: String, acks: i16) -> KafkaConfig {
+        KafkaConfig {
+            brokers: vec!["127.0.0.1:1".to_owned(), address],
+            topic: "packets".to_owned(),
+            compression: KafkaCompression::Lz4,
+            acks,
+            request_timeout: time::Duration::from_secs(5),
+            client_id: default_client_id(),
+        }
+    }
+
+    fn make_rows() -> Vec<Row<i32>> {
+        vec![Row::new(1), Row::new(2), Row::new(3)]
+    }
+
+    /// A single broker that leads partitions 0 and 1 of the `packets` topic.
+    /// It serves the expected number of requests, one connection at a time,
+    /// and returns the API key and partition (if any) of each, and the number
+    /// of connections. Unless `keep_alive` is set, each connection is closed
+    /// after its first response.
+    async fn mock_broker(
+        mut listener: mpsc::UnboundedReceiver<Pipe>,
+        requests: usize,
+        error_code: i16,
+        keep_alive: bool,
+    ) -> (Vec<(i16, Option<i32>)>, usize) {
+        let mut received = Vec::new();
+        let mut connections = 0;
+        let mut connection: Option<Pipe> = None;
+        while received.len() < requests {
+            if connection.is_none() {
+                connection = Some(listener.recv().await.unwrap());
+                connections += 1;
+            }
+            let stream = connection.as_mut().unwrap();
+            let size = match stream.read_i32().await {
+                Ok(size) => size,
+                Err(_error) => {
+                    // The client closed the connection.
+                    connection = None;
+                    continue;
+                },
+            };
+            let mut request = vec![0; size as usize];
+            stream.read_exact(&mut request).await.unwrap();
+            let mut reader = &request[..];
+            let api_key = reader.get_i16();
+            let _api_version = reader.get_i16();
+            let correlation_id = reader.get_i32();
+            let client_id_len = reader.get_i16() as usize;
+            assert_eq!(&reader[..client_id_len], b"interledger-relay");
+            reader.advance(client_id_len);
+
+            let mut response = Vec::new();
+            response.put_i32(correlation_id);
+            let partition = match api_key {
+                protocol::METADATA => {
+                    response.put_i32(1); // brokers
+                    response.put_i32(1);
+                    response.put_i16(BROKER_HOST.len() as i16);
+                    response.put_slice(BROKER_HOST.as_bytes());
+                    response.put_i32(i32::from(BROKER_PORT));
+                    response.put_i16(-1);
+                    response.put_i32(1); // controller
+                    response.put_i32(1); // topics
+                    response.put_i16(0);
+                    response.put_i16(7);
+                    response.put_slice(b"packets");
+                    response.put_i8(0);
+                    response.put_i32(2); // partitions
+                    for partition in 0..2 {
+                        response.put_i16(0);
+                        response.put_i32(partition);
+                        response.put_i32(1); // leader
+                        response.put_i32(0);
+                        response.put_i32(0);
+                    }
+                    None
+                },
+                protocol::PRODUCE => {
+                    assert_eq!(reader.get_i16(), -1); // transactional id
+                    let acks = reader.get_i16();
+                    let _timeout = reader.get_i32();
+                    assert_eq!(reader.get_i32(), 1);
+                    assert_eq!(reader.get_i16(), 7);
+                    assert_eq!(&reader[..7], b"packets");
+                    reader.advance(7);
+                    assert_eq!(reader.get_i32(), 1);
+                    let partition = reader.get_i32();
+                    let batch_len = reader.get_i32() as usize;
+                    assert_eq!(reader.len(), batch_len);
+                    // Record count:
+                    assert_eq!(&reader[57..61], &3_i32.to_be_bytes());
+                    if acks == 0 {
+                        received.push((api_key, Some(partition)));
+                        continue;
+                    }
+                    response.put_i32(1);
+                    response.put_i16(7);
+                    response.put_slice(b"packets");
+                    response.put_i32(1);
+                    response.put_i32(partition);
+                    response.put_i16(error_code);
+                    response.put_i64(0);
+                    response.put_i64(-1);
+                    response.put_i32(0);
+                    Some(partition)
+                },
+                _ => panic!("unexpected api_key={}", api_key),
+            };
+            received.push((api_key, partition));
+            stream.write_i32(response.len() as i32).await.unwrap();
+            stream.write_all(&response).await.unwrap();
+            if !keep_alive {
+                connection = None;
+            }
+        }
+        (received, connections)
+    }
+
+    fn bind() -> (Arc<dyn SinkTransport>, mpsc::UnboundedReceiver<Pipe>, String) {
+        let transport = MockSinkTransport::default();
+        let address = format!("{}:{}", BROKER_HOST, BROKER_PORT);
+        let listener = transport.listen(&address);
+        (Arc::new(transport), listener, address)
+    }
+
+    #[tokio::test]
+    async fn test_produce_ok() {
+        let (transport, listener, address) = bind();
+        let broker = tokio::spawn(mock_broker(listener, 3, 0, true));
+        let sink = KafkaSink::new(&make_config(address, 1), transport);
+        sink.clone().produce(make_rows()).await.unwrap();
+        sink.clone().produce(make_rows()).await.unwrap();
+        assert_eq!(
+            broker.await.unwrap(),
+            (vec![
+                (protocol::METADATA, None),
+                (protocol::PRODUCE, Some(0)),
+                (protocol::PRODUCE, Some(1)),
+            ], 1),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_produce_reconnect() {
+        let (transport, listener, address) = bind();
+        let broker = tokio::spawn(mock_broker(listener, 3, 0, false));
+        let sink = KafkaSink::new(&make_config(address, 1), transport);
+        // The idle connections were closed by the broker, so each request
+        // is sent again on a new one.
+        sink.clone().produce(make_rows()).await.unwrap();
+        sink.clone().produce(make_rows()).await.unwrap();
+        assert_eq!(
+            broker.await.unwrap(),
+            (vec![
+                (protocol::METADATA, None),
+                (protocol::PRODUCE, Some(0)),
+                (protocol::PRODUCE, Some(1)),
+            ], 3),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_produce_no_acks() {
+        let (transport, listener, address) = bind();
+        let broker = tokio::spawn(mock_broker(listener, 2, 0, true));
+        let sink = KafkaSink::new(&make_config(address, 0), transport);
+        sink.produce(make_rows()).await.unwrap();
+        assert_eq!(
+            broker.await.unwrap(),
+            (vec![(protocol::METADATA, None), (protocol::PRODUCE, Some(0))], 1),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_produce_error() {
+        let (transport, listener, address) = bind();
+        let broker = tokio::spawn(mock_broker(listener, 4, 6, true));
+        let sink = KafkaSink::new(&make_config(address, -1), transport);
+        let rows = make_rows();
+        let error = sink.clone().produce(rows.clone()).await.unwrap_err();
+        assert_eq!(error.retries, rows);
+        assert!(matches!(error.error, ClientError::Kafka(6)));
+        // The metadata is looked up again.
+        assert!(sink.clone().produce(rows).await.is_err());
+        assert_eq!(
+            broker.await.unwrap(),
+            (vec![
+                (protocol::METADATA, None),
+                (protocol::PRODUCE, Some(0)),
+                (protocol::METADATA, None),
+                (protocol::PRODUCE, Some(1)),
+            ], 1),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_produce_unavailable() {
+        let mut config = make_config("127.0.0.1:1".to_owned(), 1);
+        config.brokers.truncate(1);
+        let error = KafkaSink::new(&config, Arc::new(MockSinkTransport::default()))
+            .produce(make_rows())
+            .await
+            .unwrap_err();
+        assert_eq!(error.retries.len(), 3);
+        assert!(matches!(error.error, ClientError::Io(_)));
+    }
+}
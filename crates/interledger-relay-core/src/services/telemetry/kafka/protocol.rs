@@ -0,0 +1,482 @@
+//! Encoding and decoding for the subset of the Kafka protocol that the sink
+//! uses: `Metadata` (v1) and `Produce` (v3, with v2 record batches).
+//!
+//! # References
+//!
+//!   * <https://kafka.apache.org/protocol>
+//!   * <https://kafka.apache.org/documentation/#recordbatch>
+//!
+
+use bytes::{Buf, BufMut};
+
+use super::super::ClientError;
+use super::{KafkaCompression, lz4};
+
+pub const PRODUCE: i16 = 0;
+pub const METADATA: i16 = 3;
+const PRODUCE_VERSION: i16 = 3;
+const METADATA_VERSION: i16 = 1;
+
+/// <https://kafka.apache.org/protocol#protocol_error_codes>
+pub const LEADER_NOT_AVAILABLE: i16 = 5;
+/// Responses larger than this are rejected (the sink only expects small ones).
+pub const MAX_RESPONSE_SIZE: usize = 1024 * 1024;
+
+const MAGIC: i8 = 2;
+const LZ4_ATTRIBUTE: i16 = 3;
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct Record {
+    pub value: Vec<u8>,
+    /// Included as the `insert_id` header, so that consumers can deduplicate
+    /// retried records.
+    pub insert_id: uuid::Uuid,
+}
+
+/// The leader of each partition of a topic.
+#[derive(Clone, Debug, PartialEq)]
+pub struct TopicMetadata {
+    /// Pairs of `(partition, leader address)`.
+    pub partitions: Vec<(i32, String)>,
+}
+
+/// Frame a request: the size, the (v1) request header, and the body.
+pub fn encode_request(
+    api_key: i16,
+    correlation_id: i32,
+    client_id: &str,
+    body: &[u8],
+) -> Vec<u8> {
+    let api_version = match api_key {
+        PRODUCE => PRODUCE_VERSION,
+        _ => METADATA_VERSION,
+    };
+    let mut buffer = Vec::with_capacity(14 + client_id.len() + body.len());
+    buffer.put_i32(0); // Size (see below).
+    buffer.put_i16(api_key);
+    buffer.put_i16(api_version);
+    buffer.put_i32(correlation_id);
+    put_string(&mut buffer, client_id);
+    buffer.put_slice(body);
+    let size = (buffer.len() - 4) as i32;
+    buffer[..4].copy_from_slice(&size.to_be_bytes());
+    buffer
+}
+
+/// Strip the (v0) response header, checking the correlation ID.
+pub fn decode_response(mut response: &[u8], correlation_id: i32)
+    -> Result<&[u8], ClientError>
+{
+    if read_i32(&mut response)? != correlation_id {
+        return Err(ClientError::InvalidResponse);
+    }
+    Ok(response)
+}
+
+pub fn encode_metadata_request(topic: &str) -> Vec<u8> {
+    let mut buffer = Vec::new();
+    buffer.put_i32(1);
+    put_string(&mut buffer, topic);
+    buffer
+}
+
+pub fn decode_metadata_response(mut response: &[u8], topic: &str)
+    -> Result<TopicMetadata, ClientError>
+{
+    let buffer = &mut response;
+    let mut brokers = Vec::new();
+    for _i in 0..read_array_len(buffer)? {
+        let node_id = read_i32(buffer)?;
+        let host = read_string(buffer)?.to_owned();
+        let port = read_i32(buffer)?;
+        let _rack = read_nullable_string(buffer)?;
+        brokers.push((node_id, format!("{}:{}", host, port)));
+    }
+    let _controller_id = read_i32(buffer)?;
+
+    for _i in 0..read_array_len(buffer)? {
+        let error_code = read_i16(buffer)?;
+        let name = read_string(buffer)?.to_owned();
+        let _is_internal = read_i8(buffer)?;
+        let mut partitions = Vec::new();
+        for _j in 0..read_array_len(buffer)? {
+            let _error_code = read_i16(buffer)?;
+            let partition = read_i32(buffer)?;
+            let leader = read_i32(buffer)?;
+            let _replicas = read_i32_array(buffer)?;
+            let _isr = read_i32_array(buffer)?;
+            let leader = brokers
+                .iter()
+                .find(|(node_id, _address)| *node_id == leader);
+            // Partitions without a leader can't be written to.
+            if let Some((_node_id, address)) = leader {
+                partitions.push((partition, address.clone()));
+            }
+        }
+        if name != topic {
+            continue;
+        }
+        if error_code != 0 {
+            return Err(ClientError::Kafka(error_code));
+        }
+        if partitions.is_empty() {
+            return Err(ClientError::Kafka(LEADER_NOT_AVAILABLE));
+        }
+        partitions.sort();
+        return Ok(TopicMetadata { partitions });
+    }
+    Err(ClientError::InvalidResponse)
+}
+
+pub fn encode_produce_request(
+    acks: i16,
+    timeout_ms: i32,
+    topic: &str,
+    partition: i32,
+    record_batch: &[u8],
+) -> Vec<u8> {
+    let mut buffer = Vec::with_capacity(32 + topic.len() + record_batch.len());
+    buffer.put_i16(-1); // `transactional_id`: null
+    buffer.put_i16(acks);
+    buffer.put_i32(timeout_ms);
+    buffer.put_i32(1);
+    put_string(&mut buffer, topic);
+    buffer.put_i32(1);
+    buffer.put_i32(partition);
+    buffer.put_i32(record_batch.len() as i32);
+    buffer.put_slice(record_batch);
+    buffer
+}
+
+/// Returns the first error code in the response, if any.
+pub fn decode_produce_response(mut response: &[u8])
+    -> Result<(), ClientError>
+{
+    let buffer = &mut response;
+    for _i in 0..read_array_len(buffer)? {
+        let _topic = read_string(buffer)?;
+        for _j in 0..read_array_len(buffer)? {
+            let _partition = read_i32(buffer)?;
+            let error_code = read_i16(buffer)?;
+            let _base_offset = read_i64(buffer)?;
+            let _log_append_time = read_i64(buffer)?;
+            if error_code != 0 {
+                return Err(ClientError::Kafka(error_code));
+            }
+        }
+    }
+    Ok(())
+}
+
+pub fn encode_record_batch(
+    records: &[Record],
+    compression: KafkaCompression,
+    timestamp_ms: i64,
+) -> Vec<u8> {
+    let mut encoded_records = Vec::new();
+    for (offset_delta, record) in records.iter().enumerate() {
+        let insert_id = record.insert_id.to_string();
+        let mut body = Vec::with_capacity(64 + record.value.len());
+        body.put_i8(0); // attributes
+        put_varint(&mut body, 0); // timestamp delta
+        put_varint(&mut body, offset_delta as i64);
+        put_varint(&mut body, -1); // key: null
+        put_varint(&mut body, record.value.len() as i64);
+        body.put_slice(&record.value);
+        put_varint(&mut body, 1); // header count
+        put_varint(&mut body, "insert_id".len() as i64);
+        body.put_slice(b"insert_id");
+        put_varint(&mut body, insert_id.len() as i64);
+        body.put_slice(insert_id.as_bytes());
+
+        put_varint(&mut encoded_records, body.len() as i64);
+        encoded_records.put_slice(&body);
+    }
+    let (attributes, encoded_records) = match compression {
+        KafkaCompression::None => (0, encoded_records),
+        KafkaCompression::Lz4 =>
+            (LZ4_ATTRIBUTE, lz4::compress(&encoded_records)),
+    };
+
+    let mut batch = Vec::with_capacity(61 + encoded_records.len());
+    batch.put_i64(0); // base offset
+    batch.put_i32(0); // batch length (see below)
+    batch.put_i32(-1); // partition leader epoch
+    batch.put_i8(MAGIC);
+    batch.put_u32(0); // CRC (see below)
+    let crc_offset = batch.len();
+    batch.put_i16(attributes);
+    batch.put_i32(records.len() as i32 - 1); // last offset delta
+    batch.put_i64(timestamp_ms); // first timestamp
+    batch.put_i64(timestamp_ms); // max timestamp
+    batch.put_i64(-1); // producer ID
+    batch.put_i16(-1); // producer epoch
+    batch.put_i32(-1); // base sequence
+    batch.put_i32(records.len() as i32);
+    batch.put_slice(&encoded_records);
+
+    let crc = crc32c(&batch[crc_offset..]);
+    batch[crc_offset - 4..crc_offset].copy_from_slice(&crc.to_be_bytes());
+    let length = (batch.len() - 12) as i32;
+    batch[8..12].copy_from_slice(&length.to_be_bytes());
+    batch
+}
+
+/// CRC-32C (Castagnoli), as used by record batches.
+fn crc32c(data: &[u8]) -> u32 {
+    const POLYNOMIAL: u32 = 0x82f6_3b78;
+    let mut crc = !0_u32;
+    for byte in data {
+        crc ^= u32::from(*byte);
+        for _i in 0..8 {
+            let mask = (crc & 1).wrapping_neg();
+            crc = (crc >> 1) ^ (POLYNOMIAL & mask);
+        }
+    }
+    !crc
+}
+
+fn put_string(buffer: &mut Vec<u8>, string: &str) {
+    buffer.put_i16(string.len() as i16);
+    buffer.put_slice(string.as_bytes());
+}
+
+/// A zig-zag encoded varint.
+fn put_varint(buffer: &mut Vec<u8>, value: i64) {
+    let mut value = ((value << 1) ^ (value >> 63)) as u64;
+    while value >= 0x80 {
+        buffer.put_u8(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buffer.put_u8(value as u8);
+}
+
+fn read_i8(buffer: &mut &[u8]) -> Result<i8, ClientError> {
+    check_remaining(buffer, 1)?;
+    Ok(buffer.get_i8())
+}
+
+fn read_i16(buffer: &mut &[u8]) -> Result<i16, ClientError> {
+    check_remaining(buffer, 2)?;
+    Ok(buffer.get_i16())
+}
+
+fn read_i32(buffer: &mut &[u8]) -> Result<i32, ClientError> {
+    check_remaining(buffer, 4)?;
+    Ok(buffer.get_i32())
+}
+
+fn read_i64(buffer: &mut &[u8]) -> Result<i64, ClientError> {
+    check_remaining(buffer, 8)?;
+    Ok(buffer.get_i64())
+}
+
+/// A null array is treated as empty.
+fn read_array_len(buffer: &mut &[u8]) -> Result<usize, ClientError> {
+    Ok(std::cmp::max(read_i32(buffer)?, 0) as usize)
+}
+
+fn read_i32_array(buffer: &mut &[u8]) -> Result<Vec<i32>, ClientError> {
+    (0..read_array_len(buffer)?)
+        .map(|_i| read_i32(buffer))
+        .collect()
+}
+
+fn read_string<'a>(buffer: &mut &'a [u8]) -> Result<&'a str, ClientError> {
+    read_nullable_string(buffer)?
+        .ok_or(ClientError::InvalidResponse)
+}
+
+fn read_nullable_string<'a>(buffer: &mut &'a [u8])
+    -> Result<Option<&'a str>, ClientError>
+{
+    let len = read_i16(buffer)?;
+    if len < 0 {
+        return Ok(None);
+    }
+    let len = len as usize;
+    check_remaining(buffer, len)?;
+    let (string, rest) = buffer.split_at(len);
+    *buffer = rest;
+    std::str::from_utf8(string)
+        .map(Some)
+        .map_err(|_error| ClientError::InvalidResponse)
+}
+
+fn check_remaining(buffer: &[u8], size: usize) -> Result<(), ClientError> {
+    if buffer.len() < size {
+        Err(ClientError::InvalidResponse)
+    } else {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_protocol {
+    use super::*;
+
+    static INSERT_ID: uuid::Uuid = uuid::Uuid::from_bytes([0x11; 16]);
+
+    #[test]
+    fn test_crc32c() {
+        assert_eq!(crc32c(b"123456789"), 0xe306_9283);
+    }
+
+    #[test]
+    fn test_put_varint() {
+        let tests: &[(i64, &[u8])] = &[
+            (0, &[0x00]),
+            (-1, &[0x01]),
+            (1, &[0x02]),
+            (63, &[0x7e]),
+            (-64, &[0x7f]),
+            (64, &[0x80, 0x01]),
+            (300, &[0xd8, 0x04]),
+        ];
+        for (value, expect) in tests {
+            let mut buffer = Vec::new();
+            put_varint(&mut buffer, *value);
+            assert_eq!(&buffer[..], *expect, "value={}", value);
+        }
+    }
+
+    #[test]
+    fn test_encode_request() {
+        assert_eq!(
+            encode_request(METADATA, 7, "relay", &encode_metadata_request("t")),
+            &[
+                0, 0, 0, 22, // size
+                0, 3, // api key
+                0, 1, // api version
+                0, 0, 0, 7, // correlation id
+                0, 5, b'r', b'e', b'l', b'a', b'y', // client id
+                0, 0, 0, 1, // topic count
+                0, 1, b't',
+            ][..],
+        );
+    }
+
+    #[test]
+    fn test_decode_response() {
+        assert_eq!(
+            decode_response(&[0, 0, 0, 7, 1, 2], 7).unwrap(),
+            &[1, 2],
+        );
+        assert!(decode_response(&[0, 0, 0, 8, 1, 2], 7).is_err());
+        assert!(decode_response(&[0, 0], 7).is_err());
+    }
+
+    #[test]
+    fn test_decode_metadata_response() {
+        let mut response = Vec::new();
+        response.put_i32(2); // brokers
+        for (node_id, host) in &[(1, "kafka-1"), (2, "kafka-2")] {
+            response.put_i32(*node_id);
+            put_string(&mut response, host);
+            response.put_i32(9092);
+            response.put_i16(-1); // rack
+        }
+        response.put_i32(1); // controller id
+        response.put_i32(1); // topics
+        response.put_i16(0);
+        put_string(&mut response, "packets");
+        response.put_i8(0);
+        response.put_i32(3); // partitions
+        for (partition, leader) in &[(1, 1), (0, 2), (2, -1)] {
+            response.put_i16(0);
+            response.put_i32(*partition);
+            response.put_i32(*leader);
+            response.put_i32(0); // replicas
+            response.put_i32(0); // isr
+        }
+
+        assert_eq!(
+            decode_metadata_response(&response, "packets").unwrap(),
+            TopicMetadata {
+                partitions: vec![
+                    (0, "kafka-2:9092".to_owned()),
+                    (1, "kafka-1:9092".to_owned()),
+                ],
+            },
+        );
+        assert!(decode_metadata_response(&response, "other").is_err());
+        assert!(decode_metadata_response(&response[..40], "packets").is_err());
+    }
+
+    #[test]
+    fn test_decode_produce_response() {
+        let make_response = |error_code: i16| {
+            let mut response = Vec::new();
+            response.put_i32(1);
+            put_string(&mut response, "packets");
+            response.put_i32(1);
+            response.put_i32(0);
+            response.put_i16(error_code);
+            response.put_i64(123);
+            response.put_i64(-1);
+            response.put_i32(0); // throttle time
+            response
+        };
+        assert!(decode_produce_response(&make_response(0)).is_ok());
+        assert!(matches!(
+            decode_produce_response(&make_response(LEADER_NOT_AVAILABLE)),
+            Err(ClientError::Kafka(LEADER_NOT_AVAILABLE)),
+        ));
+    }
+
+    #[test]
+    fn test_encode_record_batch() {
+        let records = vec![Record {
+            value: b"{}".to_vec(),
+            insert_id: INSERT_ID,
+        }];
+        let batch = encode_record_batch(&records, KafkaCompression::None, 1000);
+        let mut reader = &batch[..];
+        assert_eq!(reader.get_i64(), 0); // base offset
+        assert_eq!(reader.get_i32() as usize, batch.len() - 12);
+        assert_eq!(reader.get_i32(), -1);
+        assert_eq!(reader.get_i8(), MAGIC);
+        assert_eq!(reader.get_u32(), crc32c(&batch[21..]));
+        assert_eq!(reader.get_i16(), 0); // attributes
+        assert_eq!(reader.get_i32(), 0); // last offset delta
+        assert_eq!(reader.get_i64(), 1000);
+        assert_eq!(reader.get_i64(), 1000);
+        assert_eq!(reader.get_i64(), -1);
+        assert_eq!(reader.get_i16(), -1);
+        assert_eq!(reader.get_i32(), -1);
+        assert_eq!(reader.get_i32(), 1); // record count
+
+        let insert_id = INSERT_ID.to_string();
+        let mut expect = vec![
+            // length (zig-zag): 10 + "insert_id" + insert ID
+            (10 + 9 + insert_id.len() as u8) * 2,
+            0, // attributes
+            0, // timestamp delta
+            0, // offset delta
+            1, // key: null (-1)
+            4, b'{', b'}', // value
+            2, // header count
+            18,
+        ];
+        expect.extend_from_slice(b"insert_id");
+        expect.push(insert_id.len() as u8 * 2);
+        expect.extend_from_slice(insert_id.as_bytes());
+        assert_eq!(reader, &expect[..]);
+    }
+
+    #[test]
+    fn test_encode_record_batch_lz4() {
+        let records = vec![
+            Record { value: b"{\"a\":1}".to_vec(), insert_id: INSERT_ID },
+            Record { value: b"{\"a\":2}".to_vec(), insert_id: INSERT_ID },
+        ];
+        let plain = encode_record_batch(&records, KafkaCompression::None, 0);
+        let batch = encode_record_batch(&records, KafkaCompression::Lz4, 0);
+        assert_eq!(&batch[21..23], &LZ4_ATTRIBUTE.to_be_bytes());
+        assert_eq!(&batch[23..27], &1_i32.to_be_bytes()); // last offset delta
+        assert_eq!(&batch[57..61], &2_i32.to_be_bytes()); // record count
+        assert_eq!(&batch[61..65], &[0x04, 0x22, 0x4d, 0x18]);
+        assert_eq!(&batch[17..21], &crc32c(&batch[21..]).to_be_bytes());
+        assert_ne!(batch, plain);
+    }
+}
@@ -0,0 +1,530 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use log::info;
+
+use crate::SetupError;
+use crate::transport::SinkTransport;
+use super::{DeadLetter, FileConfig, FileSink, LoggerQueue, MeteredSink, Row, RowColumn, Sink, SinkConfig, SinkMetrics, Spill, SpillConfig};
+
+#[derive(Debug)]
+pub struct Logger<D> {
+    queues: Vec<LoggerQueue<D>>,
+    /// The overflow is only used when `is_available` returns `true` before the
+    /// write, but all of the sub-queues refuse the row, so it needs somewhere to go.
+    /// This also holds the rows sampled by `UnavailablePolicy::Sample` while
+    /// the sink is down, so it is bounded by `max_overflow`.
+    overflow: Mutex<Vec<Row<D>>>,
+    /// Rows written while the overflow is this long are dropped.
+    max_overflow: usize,
+    /// When configured, the overflow is moved to disk (by `clean`) instead of
+    /// piling up in memory, and replayed once the queues have room again.
+    spill: Option<Spill>,
+    metrics: Option<SinkMetrics>,
+}
+
+// Unknown fields are rejected by the `SinkConfig` (`deny_unknown_fields` doesn't
+// work with a flattened untagged enum).
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+pub struct LoggerConfig {
+    pub queue_count: usize,
+    /// 500 rows/request recommended in
+    /// <https://cloud.google.com/bigquery/quotas#streaming_inserts>.
+    #[serde(default = "default_batch_capacity")]
+    pub batch_capacity: usize,
+    /// A queue is also flushed once its rows' JSON reaches this many bytes.
+    /// BigQuery limits `insertAll` requests to 10MB, so the default leaves
+    /// room for the request's overhead and the last row.
+    #[serde(default = "default_max_batch_bytes")]
+    pub max_batch_bytes: usize,
+    /// When set, a queue is flushed once its oldest row is this old, even if
+    /// the (staggered) flush timer hasn't reached it yet.
+    #[serde(default)]
+    pub max_row_age: Option<time::Duration>,
+    /// After a flush in which every row failed, the queue waits this long
+    /// before it is flushed again, doubling with each consecutive failure up
+    /// to `max_retry_backoff`.
+    #[serde(default = "default_retry_backoff")]
+    pub retry_backoff: time::Duration,
+    #[serde(default = "default_max_retry_backoff")]
+    pub max_retry_backoff: time::Duration,
+    /// When set, rows that have failed this many writes are dead-lettered
+    /// instead of retried again. Otherwise they are retried forever.
+    #[serde(default)]
+    pub max_attempts: Option<u32>,
+    /// The file that dead-lettered rows are appended to. Without it, they
+    /// are logged.
+    #[serde(default)]
+    pub dead_letter: Option<FileConfig>,
+    #[serde(default = "default_flush_interval")]
+    pub flush_interval: time::Duration,
+    #[serde(flatten)]
+    pub sink: SinkConfig,
+    /// When set, rejected packets are also logged to this sink, e.g. a
+    /// separate table. It uses the same queue settings as the main sink.
+    #[serde(default)]
+    pub reject_sink: Option<SinkConfig>,
+    /// When set, fulfilled packets are logged to this sink too ("dual-write"),
+    /// e.g. to validate a new pipeline before switching to it. It uses the
+    /// same queue settings as the main sink.
+    #[serde(default)]
+    pub shadow_sink: Option<SinkConfig>,
+    /// Whether the `shadow_sink` starts out as the authoritative sink (see
+    /// `TelemetryService`). This can be toggled at runtime.
+    #[serde(default)]
+    pub cutover: bool,
+    /// The optional columns to add to the fulfill and reject rows.
+    #[serde(default)]
+    pub extra_columns: Vec<RowColumn>,
+    /// The fraction (between `0.0` and `1.0`) of fulfilled packets that are
+    /// logged. A packet is sampled by its execution condition, so that the
+    /// main and shadow sinks (and other relays with the same rate) log the
+    /// same packets.
+    #[serde(default = "default_sample_rate")]
+    pub sample_rate: f64,
+    /// Overrides of the `sample_rate` by the account that sent the packet.
+    #[serde(default)]
+    pub account_sample_rates: HashMap<String, f64>,
+    /// Buffer rows on disk while the sink is unavailable.
+    #[serde(default)]
+    pub spill: Option<SpillConfig>,
+    #[serde(default = "default_on_unavailable")]
+    pub on_unavailable: UnavailablePolicy,
+    /// The fraction (between `0.0` and `1.0`) of packets that are logged while
+    /// the sink is unavailable, with `UnavailablePolicy::Sample`.
+    #[serde(default = "default_unavailable_sample_rate")]
+    pub unavailable_sample_rate: f64,
+}
+
+/// What to do with Prepares while the sink is unavailable (every queue is
+/// busy, and the spill, if any, is full).
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum UnavailablePolicy {
+    /// Reject them with `T03`.
+    Reject,
+    /// Forward them without logging them.
+    Forward,
+    /// Forward them, and log a sample (`unavailable_sample_rate`) of them.
+    /// The sampled rows are held in the overflow until the sink recovers.
+    Sample,
+}
+
+fn default_batch_capacity() -> usize { 500 }
+fn default_max_batch_bytes() -> usize { 9_000_000 }
+fn default_retry_backoff() -> time::Duration { time::Duration::from_secs(1) }
+fn default_max_retry_backoff() -> time::Duration { time::Duration::from_secs(60) }
+//fn default_retry_interval() -> time::Duration { time::Duration::from_secs(5) }
+fn default_flush_interval() -> time::Duration { time::Duration::from_secs(1) }
+fn default_sample_rate() -> f64 { 1.0 }
+fn default_on_unavailable() -> UnavailablePolicy { UnavailablePolicy::Reject }
+fn default_unavailable_sample_rate() -> f64 { 0.1 }
+
+impl LoggerConfig {
+    pub(super) fn validate(&self) -> Result<(), SetupError> {
+        let is_valid_rate = |rate: f64| (0.0..=1.0).contains(&rate);
+        if !is_valid_rate(self.sample_rate)
+            || !self.account_sample_rates.values().all(|&rate| is_valid_rate(rate))
+            || !is_valid_rate(self.unavailable_sample_rate)
+        {
+            return Err(SetupError::invalid_config({
+                "telemetry sample rates must be between 0.0 and 1.0"
+            }));
+        }
+        Ok(())
+    }
+}
+
+impl<D> Logger<D>
+where
+    D: 'static + Clone + Send + Sync + serde::Serialize + serde::de::DeserializeOwned,
+{
+    pub async fn new(
+        config: LoggerConfig,
+        metrics: SinkMetrics,
+        transport: &Arc<dyn SinkTransport>,
+    ) -> Result<Self, SetupError> {
+        debug_assert_ne!(config.queue_count, 0);
+
+        let sink = config.sink.build(transport).await?;
+        let sink: Arc<dyn Sink<D>> =
+            Arc::new(MeteredSink::new(sink, metrics.clone()));
+        let spill = config.spill
+            .as_ref()
+            .map(Spill::open)
+            .transpose()?;
+        let dead_letter_sink = config.dead_letter
+            .as_ref()
+            .map(|config| -> Arc<dyn Sink<D>> { Arc::new(FileSink::new(config)) });
+        let dead_letter = DeadLetter::new(dead_letter_sink, metrics.clone());
+        let max_overflow = config.queue_count * config.batch_capacity;
+        let config = Arc::new(config);
+        let queues = (0..config.queue_count)
+            .map(|_i| LoggerQueue::new(
+                config.clone(),
+                Arc::clone(&sink),
+                dead_letter.clone(),
+            ))
+            .collect::<Vec<_>>();
+        Ok(Logger {
+            queues,
+            overflow: Mutex::new(Vec::new()),
+            max_overflow,
+            spill,
+            metrics: Some(metrics),
+        })
+    }
+
+    pub fn queues(&self) -> &[LoggerQueue<D>] {
+        &self.queues
+    }
+
+    #[cfg(test)]
+    pub(super) fn overflow_len(&self) -> usize {
+        self.overflow.lock().unwrap().len()
+    }
+
+    pub fn is_dummy(&self) -> bool {
+        self.queues.is_empty()
+    }
+
+    pub fn is_available(&self) -> bool {
+        if self.is_dummy() { return true; }
+        let is_spill_available = match &self.spill {
+            Some(spill) => !spill.is_full(),
+            None => false,
+        };
+        is_spill_available || self.queues
+            .iter()
+            .any(LoggerQueue::is_ready)
+    }
+
+    pub fn write(&self, row: Row<D>) {
+        if self.is_dummy() { return; }
+        if let Err(row) = self.try_write(row) {
+            let mut overflow = self.overflow.lock().unwrap();
+            if overflow.len() < self.max_overflow {
+                overflow.push(row);
+                return;
+            }
+            drop(overflow);
+            throttled_warn!("", "overflow is full, dropping row: len={}", self.max_overflow);
+            if let Some(metrics) = &self.metrics {
+                metrics.dropped(1);
+            }
+        }
+    }
+
+    /// Write the row on a best-effort basis: it is dropped (and counted) if
+    /// the sink is unavailable. Returns whether the row was written.
+    pub fn write_or_drop(&self, row: Row<D>) -> bool {
+        if !self.is_available() {
+            if let Some(metrics) = &self.metrics {
+                metrics.dropped(1);
+            }
+            return false;
+        }
+        self.write(row);
+        true
+    }
+
+    /// Move as many rows as possible from the overflow to queues. Then, if
+    /// there is a spill, move the rest of the overflow to it, or (if the
+    /// overflow is empty) replay spilled rows to the queues.
+    pub fn clean(&self) {
+        let mut overflow = self.overflow.lock().unwrap();
+        while let Some(row) = overflow.pop() {
+            match self.try_write(row) {
+                Ok(()) => continue,
+                Err(row) => {
+                    overflow.push(row);
+                    break;
+                },
+            }
+        }
+        if let Some(spill) = &self.spill {
+            if !overflow.is_empty() {
+                self.write_spill(spill, &mut overflow);
+            } else if !spill.is_empty() {
+                if let Err(error) = spill.replay(|row| self.try_write(row)) {
+                    throttled_warn!("", "spill replay error: error={}", error);
+                }
+            }
+        }
+        if !overflow.is_empty() {
+            info!("non-empty overflow: len={}", overflow.len());
+        }
+    }
+
+    /// Move every unlogged row that isn't currently being written by a queue
+    /// to the spill, so that it is replayed after a restart.
+    pub fn persist(&self) {
+        let spill = match &self.spill {
+            Some(spill) => spill,
+            None => return,
+        };
+        let mut overflow = self.overflow.lock().unwrap();
+        for queue in &self.queues {
+            overflow.extend(queue.take_rows());
+        }
+        if !overflow.is_empty() {
+            info!("persisting unlogged rows: len={}", overflow.len());
+            self.write_spill(spill, &mut overflow);
+        }
+    }
+
+    fn write_spill(&self, spill: &Spill, rows: &mut Vec<Row<D>>) {
+        match spill.write(rows) {
+            Ok(true) => rows.clear(),
+            Ok(false) => throttled_warn!("", "spill is full: len={}", rows.len()),
+            Err(error) => throttled_warn!(
+                "",
+                "spill write error: error={} len={}",
+                error, rows.len(),
+            ),
+        }
+    }
+
+    fn try_write(&self, mut row: Row<D>) -> Result<(), Row<D>> {
+        for queue in &self.queues {
+            let result = queue.try_write(row);
+            match result {
+                Ok(_) => return Ok(()),
+                Err(row2) => row = row2,
+            }
+        }
+        Err(row)
+    }
+}
+
+impl<D> Default for Logger<D> {
+    fn default() -> Self {
+        Logger {
+            queues: Vec::new(),
+            overflow: Mutex::new(Vec::new()),
+            max_overflow: 0,
+            spill: None,
+            metrics: None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_logger {
+    use futures::executor::block_on;
+    use lazy_static::lazy_static;
+
+    use crate::metrics::Metrics;
+    use crate::testing::{self, MockSinkTransport};
+    use super::*;
+    use super::super::BigQueryConfig;
+
+    lazy_static! {
+        static ref CONFIG: LoggerConfig = LoggerConfig {
+            queue_count: 2,
+            batch_capacity: 3,
+            max_batch_bytes: 9_000_000,
+            max_row_age: None,
+            retry_backoff: time::Duration::from_secs(1),
+            max_retry_backoff: time::Duration::from_secs(60),
+            max_attempts: None,
+            dead_letter: None,
+            flush_interval: time::Duration::from_secs(1),
+            sink: SinkConfig::BigQuery(BigQueryConfig {
+                origin: testing::RECEIVER_ORIGIN.to_owned(),
+                project_id: "PROJECT_ID".to_owned(),
+                dataset_id: "DATASET_ID".to_owned(),
+                table_id: "TABLE_ID".to_owned(),
+                service_account_key_file: None,
+            }),
+            reject_sink: None,
+            shadow_sink: None,
+            cutover: false,
+            extra_columns: Vec::new(),
+            sample_rate: 1.0,
+            account_sample_rates: HashMap::new(),
+            spill: None,
+            on_unavailable: UnavailablePolicy::Reject,
+            unavailable_sample_rate: 0.1,
+        };
+
+        static ref ROWS: Vec<Row<i32>> = (0..7)
+            .map(|i| Row::new(i))
+            .collect::<Vec<_>>();
+    }
+
+    fn make_metrics() -> SinkMetrics {
+        SinkMetrics::new(Arc::new(Metrics::default()), "sink")
+    }
+
+    /// The sinks' requests (if any) fail the test.
+    fn make_transport() -> Arc<dyn SinkTransport> {
+        Arc::new(MockSinkTransport::default())
+    }
+
+    #[test]
+    fn test_deserialize() {
+        let config = serde_json::from_str::<LoggerConfig>(r#"{
+            "queue_count": 2,
+            "project_id": "PROJECT_ID",
+            "topic_id": "TOPIC_ID"
+        }"#).unwrap();
+        assert_eq!(config.batch_capacity, default_batch_capacity());
+        assert_eq!(config.max_batch_bytes, default_max_batch_bytes());
+        assert_eq!(config.max_row_age, None);
+        assert_eq!(config.retry_backoff, default_retry_backoff());
+        assert_eq!(config.max_attempts, None);
+        assert_eq!(config.sink.name(), "PubSub");
+        assert_eq!(config.reject_sink, None);
+        assert_eq!(config.on_unavailable, UnavailablePolicy::Reject);
+        assert_eq!(config.sample_rate, 1.0);
+        assert!(config.account_sample_rates.is_empty());
+
+        let config = serde_json::from_str::<LoggerConfig>(r#"{
+            "queue_count": 2,
+            "project_id": "PROJECT_ID",
+            "topic_id": "TOPIC_ID",
+            "reject_sink": { "file": "rejects.json" },
+            "on_unavailable": "sample",
+            "unavailable_sample_rate": 0.5,
+            "sample_rate": 0.1,
+            "account_sample_rates": { "alice": 1.0 },
+            "max_attempts": 5,
+            "dead_letter": { "file": "dead_letter.json" }
+        }"#).unwrap();
+        assert_eq!(config.max_attempts, Some(5));
+        assert_eq!(config.dead_letter.unwrap().file.to_str(), Some("dead_letter.json"));
+        assert_eq!(config.reject_sink.unwrap().name(), "File");
+        assert_eq!(config.sample_rate, 0.1);
+        assert_eq!(config.account_sample_rates["alice"], 1.0);
+        assert_eq!(config.on_unavailable, UnavailablePolicy::Sample);
+        assert_eq!(config.unavailable_sample_rate, 0.5);
+
+        assert!(serde_json::from_str::<LoggerConfig>(r#"{
+            "queue_count": 2,
+            "project_id": "PROJECT_ID",
+            "topic_id": "TOPIC_ID",
+            "unknown": true
+        }"#).is_err());
+    }
+
+    #[test]
+    fn test_validate() {
+        assert!(CONFIG.validate().is_ok());
+        assert!(LoggerConfig {
+            sample_rate: 0.0,
+            account_sample_rates: vec![("alice".to_owned(), 1.0)]
+                .into_iter()
+                .collect(),
+            ..CONFIG.clone()
+        }.validate().is_ok());
+
+        for &rate in &[-0.1, 1.1, f64::NAN, f64::INFINITY] {
+            assert!(LoggerConfig {
+                sample_rate: rate,
+                ..CONFIG.clone()
+            }.validate().is_err());
+            assert!(LoggerConfig {
+                unavailable_sample_rate: rate,
+                ..CONFIG.clone()
+            }.validate().is_err());
+            assert!(LoggerConfig {
+                account_sample_rates: vec![("alice".to_owned(), rate)]
+                    .into_iter()
+                    .collect(),
+                ..CONFIG.clone()
+            }.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn test_default() {
+        let logger = Logger::default();
+        assert!(logger.is_dummy());
+        assert!(logger.is_available());
+        logger.write(ROWS[0].clone());
+        assert!(logger.overflow.lock().unwrap().is_empty());
+        logger.clean();
+    }
+
+    #[test]
+    fn test_new() {
+        let logger = block_on(Logger::new(CONFIG.clone(), make_metrics(), &make_transport())).unwrap();
+        assert!(!logger.is_dummy());
+        assert!(logger.is_available());
+        assert_eq!(logger.queues.len(), CONFIG.queue_count);
+        logger.write(ROWS[0].clone());
+        assert!(logger.overflow.lock().unwrap().is_empty());
+        logger.clean();
+    }
+
+    #[test]
+    fn test_write() {
+        let logger = block_on(Logger::new(CONFIG.clone(), make_metrics(), &make_transport())).unwrap();
+        logger.write(ROWS[0].clone());
+        logger.write(ROWS[1].clone());
+        assert_eq!(logger.queues[0].len(), 2);
+        assert_eq!(logger.queues[1].len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_write_overflow() {
+        // The queues' flushes fail, and aren't retried during the test.
+        let logger = Logger::new(LoggerConfig {
+            retry_backoff: time::Duration::from_secs(3600),
+            sink: SinkConfig::File(FileConfig {
+                file: std::env::temp_dir()
+                    .join(format!("ilp-relay-logger-{}", uuid::Uuid::new_v4()))
+                    .join("rows.json"),
+                max_file_size: None,
+                max_files: 5,
+            }),
+            ..CONFIG.clone()
+        }, make_metrics(), &make_transport()).await.unwrap();
+        let max_overflow = CONFIG.queue_count * CONFIG.batch_capacity;
+        for _ in 0..(max_overflow * 3) {
+            logger.write(ROWS[0].clone());
+        }
+        assert!(!logger.is_available());
+        assert_eq!(logger.overflow_len(), max_overflow);
+    }
+
+    #[test]
+    fn test_clean() {
+        let logger = block_on(Logger::new(CONFIG.clone(), make_metrics(), &make_transport())).unwrap();
+        logger.overflow
+            .lock()
+            .unwrap()
+            .push(ROWS[0].clone());
+        logger.clean();
+        assert!(logger.overflow.lock().unwrap().is_empty());
+        assert_eq!(logger.queues[0].len(), 1);
+        assert_eq!(logger.queues[1].len(), 0);
+    }
+
+    #[test]
+    fn test_spill() {
+        let directory = std::env::temp_dir()
+            .join(format!("ilp-relay-logger-{}", uuid::Uuid::new_v4()));
+        let logger = block_on(Logger::new(LoggerConfig {
+            spill: Some(SpillConfig {
+                directory: directory.clone(),
+                segment_size: 1024,
+                max_size: 1024,
+            }),
+            ..CONFIG.clone()
+        }, make_metrics(), &make_transport())).unwrap();
+        logger.write(ROWS[0].clone());
+        logger.write(ROWS[1].clone());
+        logger.persist();
+        assert_eq!(logger.queues[0].len(), 0);
+        assert!(!logger.spill.as_ref().unwrap().is_empty());
+
+        // The spilled rows are replayed once the overflow is empty.
+        logger.clean();
+        assert!(logger.spill.as_ref().unwrap().is_empty());
+        assert_eq!(logger.queues[0].len(), 2);
+        std::fs::remove_dir_all(directory).unwrap();
+    }
+}
@@ -0,0 +1,554 @@
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use log::{trace, warn};
+
+use super::{LoggerConfig, Row, Sink, SinkMetrics};
+
+#[derive(Clone, Debug)]
+pub struct LoggerQueue<D> {
+    config: Arc<LoggerConfig>,
+    sink: Arc<dyn Sink<D>>,
+    dead_letter: DeadLetter<D>,
+    data: Arc<Mutex<LoggerData<D>>>,
+}
+
+/// Where rows go once they have failed `max_attempts` writes: the
+/// `dead_letter` file, if any, or else the log.
+#[derive(Clone, Debug)]
+pub struct DeadLetter<D> {
+    sink: Option<Arc<dyn Sink<D>>>,
+    metrics: Option<SinkMetrics>,
+}
+
+/// There is a hard maximum of 10,000 rows-per-request.
+///
+/// See: <https://cloud.google.com/bigquery/quotas#streaming_inserts>
+const MAXIMUM_BATCH_CAPACITY: usize = 10_000;
+
+#[derive(Debug)]
+struct LoggerData<D> {
+    queue: Vec<Row<D>>,
+    /// The (estimated) size of the queued rows' JSON.
+    queue_bytes: usize,
+    /// When the oldest queued row was queued.
+    queued_at: Option<time::Instant>,
+    insert: Option<tokio::task::JoinHandle<()>>,
+    /// The number of consecutive flushes in which every row failed.
+    failures: u32,
+    /// After a failed flush, the queue isn't flushed until this time. It still
+    /// takes rows, until it is full.
+    retry_at: Option<time::Instant>,
+}
+
+impl<D> LoggerQueue<D>
+where
+    D: 'static + Clone + Send + Sync + serde::Serialize,
+{
+    pub fn new(
+        config: Arc<LoggerConfig>,
+        sink: Arc<dyn Sink<D>>,
+        dead_letter: DeadLetter<D>,
+    ) -> Self {
+        debug_assert!(config.batch_capacity <= MAXIMUM_BATCH_CAPACITY);
+        let queue = Vec::with_capacity(config.batch_capacity);
+        LoggerQueue {
+            config,
+            sink,
+            dead_letter,
+            data: Arc::new(Mutex::new(LoggerData {
+                queue,
+                queue_bytes: 0,
+                queued_at: None,
+                insert: None,
+                failures: 0,
+                retry_at: None,
+            })),
+        }
+    }
+
+    pub fn is_ready(&self) -> bool {
+        self.data.try_lock()
+            .map(|data| data.insert.is_none() && !self.is_queue_full(&data))
+            .unwrap_or(false)
+    }
+
+    /// Returns an error when the queue is busy.
+    pub fn try_write(&self, row: Row<D>) -> Result<(), Row<D>> {
+        let mut data = match self.data.try_lock() {
+            Ok(data) => data,
+            Err(_error) => return Err(row),
+        };
+        if data.insert.is_some() {
+            return Err(row);
+        }
+        // A full queue is only left unflushed while backing off.
+        if self.is_queue_full(&data) {
+            if !data.is_backing_off() {
+                self.spawn_flush(&mut data);
+            }
+            return Err(row);
+        }
+
+        data.queue_bytes += row_size(&row);
+        data.queued_at.get_or_insert_with(time::Instant::now);
+        data.queue.push(row);
+        if self.is_queue_full(&data) && !data.is_backing_off() {
+            self.spawn_flush(&mut data);
+        }
+        Ok(())
+    }
+
+    pub fn flush_now(self) {
+        let mut data = self.data.lock().unwrap();
+        if data.insert.is_some() || data.is_backing_off() { return; }
+        if data.queue.is_empty() { return; }
+        self.spawn_flush(&mut data);
+    }
+
+    /// Flush the queue if its oldest row has been queued for at least
+    /// `max_row_age`, so that a trickle of rows isn't held back by the
+    /// staggered flush timer.
+    pub fn flush_if_stale(&self) {
+        let max_row_age = match self.config.max_row_age {
+            Some(max_row_age) => max_row_age,
+            None => return,
+        };
+        let mut data = match self.data.try_lock() {
+            Ok(data) => data,
+            Err(_error) => return,
+        };
+        if data.insert.is_some() || data.is_backing_off() { return; }
+        let is_stale = match data.queued_at {
+            Some(queued_at) => max_row_age <= queued_at.elapsed(),
+            None => false,
+        };
+        if is_stale {
+            self.spawn_flush(&mut data);
+        }
+    }
+
+    fn spawn_flush(&self, data: &mut LoggerData<D>) {
+        let rows = std::mem::take(&mut data.queue);
+        data.queue_bytes = 0;
+        data.queued_at = None;
+        data.insert = Some(tokio::spawn(self.clone().flush(rows)));
+    }
+
+    async fn flush(self, rows: Vec<Row<D>>) {
+        let count = rows.len();
+        trace!("flush start: total_rows={}", count);
+        let result = self.sink.write_batch(rows).await;
+        let mut retries = match result {
+            Ok(()) => Vec::new(),
+            Err(error) => {
+                throttled_warn!(
+                    "",
+                    "flush write_batch error: error={:?} retries={} total_rows={}",
+                    error.error, error.retries.len(), count,
+                );
+                debug_assert!(!error.retries.is_empty());
+                error.retries
+            },
+        };
+        let is_total_failure = retries.len() == count;
+        for row in &mut retries {
+            row.attempts += 1;
+        }
+        if let Some(max_attempts) = self.config.max_attempts {
+            let (dead, live) = retries
+                .into_iter()
+                .partition::<Vec<_>, _>(|row| max_attempts <= row.attempts);
+            retries = live;
+            if !dead.is_empty() {
+                self.dead_letter.write(dead).await;
+            }
+        }
+
+        let mut data = self.data.lock().unwrap();
+        debug_assert!(data.queue.is_empty());
+        data.insert = None;
+        if is_total_failure {
+            data.failures += 1;
+            let backoff = self.backoff(data.failures);
+            data.retry_at = Some(time::Instant::now() + backoff);
+        } else {
+            data.failures = 0;
+            data.retry_at = None;
+        }
+        if !retries.is_empty() {
+            data.queue_bytes = retries.iter().map(row_size).sum();
+            data.queued_at = Some(time::Instant::now());
+            data.queue = retries;
+        }
+    }
+
+    /// The delay after the `failures`th consecutive failed flush, doubling
+    /// from `retry_backoff` up to `max_retry_backoff`.
+    fn backoff(&self, failures: u32) -> time::Duration {
+        let exponent = failures.saturating_sub(1).min(31);
+        self.config.retry_backoff
+            .checked_mul(1 << exponent)
+            .unwrap_or(self.config.max_retry_backoff)
+            .min(self.config.max_retry_backoff)
+    }
+
+    fn is_queue_full(&self, data: &LoggerData<D>) -> bool {
+        self.config.batch_capacity <= data.queue.len()
+            || self.config.max_batch_bytes <= data.queue_bytes
+    }
+
+    #[cfg(test)]
+    pub fn len(&self) -> usize {
+        self.data
+            .lock()
+            .unwrap()
+            .queue
+            .len()
+    }
+
+    /// Take the queued rows. Rows that are currently being written aren't
+    /// included (they are returned to the queue if the write fails).
+    pub fn take_rows(&self) -> Vec<Row<D>> {
+        let mut data = self.data.lock().unwrap();
+        data.queue_bytes = 0;
+        data.queued_at = None;
+        std::mem::take(&mut data.queue)
+    }
+
+    pub fn is_idle(&self) -> bool {
+        let data = self.data.lock().unwrap();
+        data.queue.is_empty() && data.insert.is_none()
+    }
+}
+
+impl<D> LoggerData<D> {
+    fn is_backing_off(&self) -> bool {
+        match self.retry_at {
+            Some(retry_at) => time::Instant::now() < retry_at,
+            None => false,
+        }
+    }
+}
+
+impl<D> DeadLetter<D>
+where
+    D: 'static + Clone + Send + Sync + serde::Serialize,
+{
+    pub fn new(sink: Option<Arc<dyn Sink<D>>>, metrics: SinkMetrics) -> Self {
+        DeadLetter { sink, metrics: Some(metrics) }
+    }
+
+    async fn write(&self, rows: Vec<Row<D>>) {
+        if let Some(metrics) = &self.metrics {
+            metrics.dead_lettered(rows.len());
+        }
+        let rows = match &self.sink {
+            Some(sink) => match sink.write_batch(rows).await {
+                Ok(()) => return,
+                Err(error) => {
+                    warn!("dead letter write error: error={:?}", error.error);
+                    error.retries
+                },
+            },
+            None => rows,
+        };
+        for row in rows {
+            warn!(
+                "dead-lettered telemetry row: insert_id={} attempts={} json={}",
+                row.insert_id, row.attempts,
+                serde_json::to_string(&row.json).unwrap_or_default(),
+            );
+        }
+    }
+}
+
+impl<D> Default for DeadLetter<D> {
+    fn default() -> Self {
+        DeadLetter { sink: None, metrics: None }
+    }
+}
+
+/// The length of the row's JSON, without buffering it.
+fn row_size<D: serde::Serialize>(row: &Row<D>) -> usize {
+    let mut counter = ByteCounter(0);
+    serde_json::to_writer(&mut counter, row)
+        .expect("row serialization error");
+    counter.0
+}
+
+struct ByteCounter(usize);
+
+impl io::Write for ByteCounter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0 += buf.len();
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test_logger_queue {
+    use std::collections::HashMap;
+    use std::time;
+
+    use lazy_static::lazy_static;
+
+    use crate::metrics::Metrics;
+    use crate::testing::{self, MockHttpClient};
+    use super::*;
+    use super::super::{BigQueryConfig, BigQuerySink, FileConfig, FileSink, SinkConfig, UnavailablePolicy};
+    use super::super::big_query::{InsertAllRequest, InsertAllResponse, InsertError};
+
+    lazy_static! {
+        static ref CONFIG: Arc<LoggerConfig> = Arc::new(LoggerConfig {
+            queue_count: 2,
+            batch_capacity: 3,
+            max_batch_bytes: 9_000_000,
+            max_row_age: None,
+            retry_backoff: time::Duration::from_secs(1),
+            max_retry_backoff: time::Duration::from_secs(60),
+            max_attempts: None,
+            dead_letter: None,
+            flush_interval: time::Duration::from_secs(1),
+            sink: SinkConfig::BigQuery(BIG_QUERY.clone()),
+            reject_sink: None,
+            shadow_sink: None,
+            cutover: false,
+            extra_columns: Vec::new(),
+            sample_rate: 1.0,
+            account_sample_rates: HashMap::new(),
+            spill: None,
+            on_unavailable: UnavailablePolicy::Reject,
+            unavailable_sample_rate: 0.1,
+        });
+
+        static ref BIG_QUERY: BigQueryConfig = BigQueryConfig {
+            origin: testing::RECEIVER_ORIGIN.to_owned(),
+            project_id: "PROJECT_ID".to_owned(),
+            dataset_id: "DATASET_ID".to_owned(),
+            table_id: "TABLE_ID".to_owned(),
+            service_account_key_file: None,
+        };
+
+        /// For the tests that don't flush.
+        static ref SINK: Arc<BigQuerySink> = Arc::new(BigQuerySink::new(
+            &BIG_QUERY,
+            Arc::new(MockHttpClient::new(|request| {
+                panic!("unexpected request: {:?}", request)
+            })),
+        ));
+
+        static ref ROWS: Vec<Row<i32>> = (0..7)
+            .map(|i| Row::new(i))
+            .collect::<Vec<_>>();
+    }
+
+    #[test]
+    fn test_is_ready() {
+        let queue = LoggerQueue::<i32>::new(
+            CONFIG.clone(),
+            SINK.clone(),
+            DeadLetter::default(),
+        );
+        assert!(queue.is_ready());
+    }
+
+    #[tokio::test]
+    async fn test_flush_no_retries() {
+        let client = make_client(&[]);
+        let queue = LoggerQueue::<i32>::new(
+            CONFIG.clone(),
+            make_sink(&client),
+            DeadLetter::default(),
+        );
+        for i in 0..3 {
+            queue.try_write(ROWS[i].clone()).unwrap();
+        }
+        assert!(!queue.is_ready());
+        assert_eq!(
+            queue.try_write(ROWS[3].clone()).unwrap_err(),
+            ROWS[3].clone(),
+        );
+        take_insert(&queue).await.unwrap();
+        test_requests(&client, &[0, 1, 2]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_max_batch_bytes() {
+        let row_size = row_size(&ROWS[0]);
+        let client = make_client(&[]);
+        let queue = LoggerQueue::<i32>::new(Arc::new(LoggerConfig {
+            max_batch_bytes: row_size * 2,
+            ..CONFIG.as_ref().clone()
+        }), make_sink(&client), DeadLetter::default());
+        queue.try_write(ROWS[0].clone()).unwrap();
+        assert!(queue.is_ready());
+        queue.try_write(ROWS[1].clone()).unwrap();
+        assert!(!queue.is_ready());
+        take_insert(&queue).await.unwrap();
+        test_requests(&client, &[0, 1]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_if_stale() {
+        let client = make_client(&[]);
+        let queue = LoggerQueue::<i32>::new(Arc::new(LoggerConfig {
+            max_row_age: Some(time::Duration::from_millis(10)),
+            ..CONFIG.as_ref().clone()
+        }), make_sink(&client), DeadLetter::default());
+        queue.try_write(ROWS[0].clone()).unwrap();
+        queue.flush_if_stale();
+        assert!(queue.is_ready());
+        std::thread::sleep(time::Duration::from_millis(10));
+        queue.flush_if_stale();
+        assert!(!queue.is_ready());
+        assert_eq!(queue.len(), 0);
+        take_insert(&queue).await.unwrap();
+        test_requests(&client, &[0]);
+    }
+
+    #[tokio::test]
+    async fn test_flush_with_retries() {
+        let client = make_client(&[1]);
+        let queue = LoggerQueue::<i32>::new(
+            CONFIG.clone(),
+            make_sink(&client),
+            DeadLetter::default(),
+        );
+        for i in 0..3 {
+            queue.try_write(ROWS[i].clone()).unwrap();
+        }
+        take_insert(&queue).await.unwrap();
+        test_requests(&client, &[0, 1, 2]);
+        assert!(queue.is_ready());
+        let data = queue.data.lock().unwrap();
+        assert_eq!(data.queue.len(), 1);
+        assert_eq!(data.queue[0].attempts, 1);
+        assert!(data.insert.is_none());
+        assert_eq!(data.failures, 0);
+    }
+
+    #[tokio::test]
+    async fn test_flush_backoff() {
+        let client = make_client(&[0, 1, 2]);
+        let queue = LoggerQueue::<i32>::new(Arc::new(LoggerConfig {
+            batch_capacity: 4,
+            ..CONFIG.as_ref().clone()
+        }), make_sink(&client), DeadLetter::default());
+        for i in 0..3 {
+            queue.try_write(ROWS[i].clone()).unwrap();
+        }
+        queue.clone().flush_now();
+        take_insert(&queue).await.unwrap();
+        test_requests(&client, &[0, 1, 2]);
+        // Every row failed, so the queue backs off, but it still
+        // takes rows until it is full.
+        assert!(queue.is_ready());
+        queue.try_write(ROWS[3].clone()).unwrap();
+        assert!(!queue.is_ready());
+        assert_eq!(
+            queue.try_write(ROWS[4].clone()).unwrap_err(),
+            ROWS[4].clone(),
+        );
+        // The full queue isn't flushed until the backoff is over.
+        queue.clone().flush_now();
+        let data = queue.data.lock().unwrap();
+        assert!(data.insert.is_none());
+        assert_eq!(data.queue.len(), 4);
+        assert_eq!(data.failures, 1);
+    }
+
+    #[test]
+    fn test_backoff() {
+        let queue = LoggerQueue::<i32>::new(Arc::new(LoggerConfig {
+            retry_backoff: time::Duration::from_secs(1),
+            max_retry_backoff: time::Duration::from_secs(5),
+            ..CONFIG.as_ref().clone()
+        }), SINK.clone(), DeadLetter::default());
+        assert_eq!(queue.backoff(1), time::Duration::from_secs(1));
+        assert_eq!(queue.backoff(2), time::Duration::from_secs(2));
+        assert_eq!(queue.backoff(3), time::Duration::from_secs(4));
+        assert_eq!(queue.backoff(4), time::Duration::from_secs(5));
+        assert_eq!(queue.backoff(100), time::Duration::from_secs(5));
+    }
+
+    #[tokio::test]
+    async fn test_flush_dead_letter() {
+        let path = std::env::temp_dir()
+            .join(format!("ilp-relay-dead-letter-{}", uuid::Uuid::new_v4()));
+        let metrics = Arc::new(Metrics::default());
+        let dead_letter = DeadLetter::new(
+            Some(Arc::new(FileSink::new(&FileConfig {
+                file: path.clone(),
+                max_file_size: None,
+                max_files: 5,
+            }))),
+            SinkMetrics::new(Arc::clone(&metrics), "sink"),
+        );
+        let client = make_client(&[1]);
+        let queue = LoggerQueue::<i32>::new(Arc::new(LoggerConfig {
+            max_attempts: Some(1),
+            ..CONFIG.as_ref().clone()
+        }), make_sink(&client), dead_letter);
+        for i in 0..3 {
+            queue.try_write(ROWS[i].clone()).unwrap();
+        }
+        take_insert(&queue).await.unwrap();
+        test_requests(&client, &[0, 1, 2]);
+        assert!(queue.is_ready());
+        assert!(queue.is_idle());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), "1\n");
+        assert_eq!(
+            metrics.get(
+                "ilp_relay_telemetry_rows_dead_lettered_total",
+                vec![("sink", "sink".to_owned())],
+            ),
+            1,
+        );
+        std::fs::remove_file(path).unwrap();
+    }
+
+    fn take_insert(queue: &LoggerQueue<i32>) -> tokio::task::JoinHandle<()> {
+        queue.data.lock().unwrap().insert.take().unwrap()
+    }
+
+    /// The sink received a single batch of the `rows`.
+    fn test_requests(client: &MockHttpClient, rows: &[usize]) {
+        let requests = client.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(
+            requests[0].body.as_ref(),
+            serde_json::to_vec(&InsertAllRequest {
+                rows: rows.iter()
+                    .map(|index| ROWS[*index].clone())
+                    .collect::<Vec<_>>()
+                    .as_slice(),
+            }).unwrap().as_slice(),
+        );
+    }
+
+    /// The rows at the `retries` indices fail to insert.
+    fn make_client(retries: &[u32]) -> MockHttpClient {
+        MockHttpClient::with_response(200, {
+            serde_json::to_vec(&InsertAllResponse {
+                insert_errors: retries
+                    .iter()
+                    .copied()
+                    .map(|index| InsertError {
+                        index,
+                        errors: Vec::new(),
+                    })
+                    .collect::<Vec<_>>(),
+            }).unwrap()
+        })
+    }
+
+    fn make_sink(client: &MockHttpClient) -> Arc<BigQuerySink> {
+        Arc::new(BigQuerySink::new(&BIG_QUERY, Arc::new(client.clone())))
+    }
+}
@@ -0,0 +1,1027 @@
+mod big_query;
+mod client;
+mod file;
+mod kafka;
+mod logger;
+mod logger_queue;
+mod pub_sub;
+mod sink;
+mod spill;
+
+use std::collections::HashMap;
+use std::convert::TryInto;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time;
+
+use futures::prelude::*;
+use log::{debug, error, warn};
+
+pub use self::big_query::BigQueryConfig;
+pub use self::file::FileConfig;
+pub use self::kafka::{KafkaCompression, KafkaConfig};
+pub use self::pub_sub::PubSubConfig;
+pub use self::sink::SinkConfig;
+pub use self::spill::SpillConfig;
+use crate::{RequestId, RequestWithFrom, Service};
+use crate::SetupError;
+use crate::random_fraction;
+use crate::connector_info::ConnectorInfo;
+use crate::events::{PacketEvent, PacketEvents, PacketResult};
+use crate::metrics::Metrics;
+use crate::reject_reasons;
+use crate::services::{CatchAllMonitor, RouteIndex, RouterService, ValidateFulfillmentService};
+use crate::toggles::Toggle;
+use crate::transport::SinkTransport;
+use self::big_query::BigQuerySink;
+use self::client::ClientError;
+use self::file::FileSink;
+use self::kafka::KafkaSink;
+pub use self::logger::UnavailablePolicy;
+use self::logger::{Logger, LoggerConfig};
+use self::logger_queue::{DeadLetter, LoggerQueue};
+use self::pub_sub::PubSubSink;
+use self::sink::{MeteredSink, Row, Sink, SinkError, SinkMetrics};
+use self::spill::Spill;
+
+pub type TelemetryServiceConfig = LoggerConfig;
+
+// TODO move to Logger?
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct RowData {
+    /// Only included when the connector is configured with an instance ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<Arc<String>>,
+    pub account: Arc<String>,
+    pub to_account: Arc<String>,
+    pub destination: ilp::Address,
+    pub amount: u64,
+    #[serde(
+        serialize_with = "serialize_timestamp",
+        deserialize_with = "deserialize_timestamp",
+    )]
+    pub fulfill_time: time::SystemTime,
+    #[serde(flatten)]
+    pub extras: RowExtras,
+}
+
+/// A rejected packet, logged to the `reject_sink`.
+#[derive(Clone, Debug, serde::Deserialize, serde::Serialize)]
+pub struct RejectRowData {
+    /// Only included when the connector is configured with an instance ID.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub instance_id: Option<Arc<String>>,
+    pub account: Arc<String>,
+    /// Not set when the packet had no route.
+    pub to_account: Option<Arc<String>>,
+    pub destination: ilp::Address,
+    pub amount: u64,
+    pub code: String,
+    pub triggered_by: Option<ilp::Address>,
+    /// Invalid UTF-8 is replaced.
+    pub message: String,
+    #[serde(
+        serialize_with = "serialize_timestamp",
+        deserialize_with = "deserialize_timestamp",
+    )]
+    pub reject_time: time::SystemTime,
+    #[serde(flatten)]
+    pub extras: RowExtras,
+}
+
+/// An optional column of the fulfill and reject rows, selected by the
+/// `extra_columns` of the `LoggerConfig`. The table needs a nullable column
+/// for each one that is selected.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum RowColumn {
+    /// When the Prepare was received (a `TIMESTAMP`).
+    PrepareTime,
+    /// The expiry of the Prepare (a `TIMESTAMP`).
+    ExpiresAt,
+    /// The milliseconds between receiving the Prepare and its response (an
+    /// `INTEGER`), including the next hop's round trip.
+    LatencyMs,
+    /// Whether the destination has a STREAM connection tag (a `BOOLEAN`). The
+    /// `destination` column never includes the tag.
+    HasConnectionTag,
+}
+
+/// The `extra_columns` of a row. Columns that weren't selected are `None`, and
+/// aren't serialized.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize, serde::Serialize)]
+pub struct RowExtras {
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_timestamp",
+        deserialize_with = "deserialize_optional_timestamp",
+    )]
+    pub prepare_time: Option<time::SystemTime>,
+    #[serde(
+        default,
+        skip_serializing_if = "Option::is_none",
+        serialize_with = "serialize_optional_timestamp",
+        deserialize_with = "deserialize_optional_timestamp",
+    )]
+    pub expires_at: Option<time::SystemTime>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub latency_ms: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub has_connection_tag: Option<bool>,
+}
+
+/// This service logs batches of fulfilled packets to a sink (BigQuery,
+/// Pub/Sub, Kafka, or a local file), and optionally rejected packets to a
+/// second sink. It will cease to route packets when it detects that the
+/// (fulfill) sink is unavailable.
+///
+/// While migrating between sinks, fulfilled packets can be logged to a shadow
+/// sink too. Until the cutover toggle is enabled, the shadow sink is written on
+/// a best-effort basis; after it, the roles are swapped.
+///
+/// Prepares that are routed to the catch-all route are counted whether or not
+/// a sink is configured.
+///
+/// Every packet's outcome is also published to the in-process `PacketEvents`
+/// channel, while it has subscribers.
+#[derive(Clone, Debug)]
+pub struct TelemetryService {
+    connector: ConnectorInfo,
+    instance_id: Option<Arc<String>>,
+    next: ValidateFulfillmentService<RouterService>,
+    catch_all: Arc<CatchAllMonitor>,
+    flush_interval: time::Duration,
+    on_unavailable: UnavailablePolicy,
+    unavailable_sample_rate: f64,
+    extra_columns: Arc<Vec<RowColumn>>,
+    sample_rate: f64,
+    account_sample_rates: Arc<HashMap<String, f64>>,
+    logger: Arc<Logger<RowData>>,
+    shadow_logger: Arc<Logger<RowData>>,
+    reject_logger: Arc<Logger<RejectRowData>>,
+    /// When enabled (and there is a shadow sink), the shadow sink is the
+    /// authoritative one.
+    cutover: Toggle,
+    events: PacketEvents,
+}
+
+impl TelemetryService {
+    #[inline]
+    #[allow(clippy::too_many_arguments)]
+    pub async fn new(
+        connector: impl Into<ConnectorInfo>,
+        instance_id: Option<Arc<String>>,
+        config: Option<LoggerConfig>,
+        transport: Arc<dyn SinkTransport>,
+        catch_all: Arc<CatchAllMonitor>,
+        metrics: Arc<Metrics>,
+        events: PacketEvents,
+        next: ValidateFulfillmentService<RouterService>,
+    ) -> Result<Self, SetupError> {
+        if let Some(config) = &config {
+            config.validate()?;
+        }
+        let has_config = config.is_some();
+        let flush_interval = config
+            .as_ref()
+            .map(|config| config.flush_interval)
+            .unwrap_or_default();
+        let on_unavailable = config
+            .as_ref()
+            .map(|config| config.on_unavailable)
+            .unwrap_or(UnavailablePolicy::Reject);
+        let unavailable_sample_rate = config
+            .as_ref()
+            .map(|config| config.unavailable_sample_rate)
+            .unwrap_or_default();
+        let cutover = config
+            .as_ref()
+            .map(|config| config.cutover)
+            .unwrap_or(false);
+        let extra_columns = config
+            .as_ref()
+            .map(|config| config.extra_columns.clone())
+            .unwrap_or_default();
+        let sample_rate = config
+            .as_ref()
+            .map(|config| config.sample_rate)
+            .unwrap_or(1.0);
+        let account_sample_rates = config
+            .as_ref()
+            .map(|config| config.account_sample_rates.clone())
+            .unwrap_or_default();
+        let reject_config = config
+            .as_ref()
+            .and_then(|config| Some(LoggerConfig {
+                sink: config.reject_sink.clone()?,
+                reject_sink: None,
+                shadow_sink: None,
+                // Rejects are logged on a best-effort basis.
+                spill: None,
+                dead_letter: None,
+                ..config.clone()
+            }));
+        let shadow_config = config
+            .as_ref()
+            .and_then(|config| Some(LoggerConfig {
+                sink: config.shadow_sink.clone()?,
+                reject_sink: None,
+                shadow_sink: None,
+                // The spill and dead letter file belong to the main sink.
+                spill: None,
+                dead_letter: None,
+                ..config.clone()
+            }));
+        let logger = match config {
+            Some(config) => Logger::new(
+                config,
+                SinkMetrics::new(Arc::clone(&metrics), "sink"),
+                &transport,
+            ).await?,
+            None => Logger::default(),
+        };
+        let shadow_logger = match shadow_config {
+            Some(config) => Logger::new(
+                config,
+                SinkMetrics::new(Arc::clone(&metrics), "shadow_sink"),
+                &transport,
+            ).await?,
+            None => Logger::default(),
+        };
+        let reject_logger = match reject_config {
+            Some(config) => Logger::new(
+                config,
+                SinkMetrics::new(metrics, "reject_sink"),
+                &transport,
+            ).await?,
+            None => Logger::default(),
+        };
+        let mut service = TelemetryService {
+            connector: connector.into(),
+            instance_id,
+            next,
+            catch_all,
+            flush_interval,
+            on_unavailable,
+            unavailable_sample_rate,
+            extra_columns: Arc::new(extra_columns),
+            sample_rate,
+            account_sample_rates: Arc::new(account_sample_rates),
+            logger: Arc::new(logger),
+            shadow_logger: Arc::new(shadow_logger),
+            reject_logger: Arc::new(reject_logger),
+            cutover: Toggle::new(cutover),
+            events,
+        };
+        if has_config {
+            service.setup();
+        }
+        Ok(service)
+    }
+
+    /// Whether the shadow sink is the authoritative sink.
+    pub fn cutover_toggle(&self) -> &Toggle {
+        &self.cutover
+    }
+
+    pub async fn stop(self) {
+        debug!("stopping logger");
+        self.logger.clean();
+        self.shadow_logger.clean();
+        self.reject_logger.clean();
+        for queue in self.logger.queues() {
+            queue.clone().flush_now();
+        }
+        for queue in self.shadow_logger.queues() {
+            queue.clone().flush_now();
+        }
+        for queue in self.reject_logger.queues() {
+            queue.clone().flush_now();
+        }
+
+        const ATTEMPTS: usize = 100;
+        for _i in 0..ATTEMPTS {
+            let is_stopped = self.logger
+                .queues()
+                .iter()
+                .all(LoggerQueue::is_idle)
+                && self.shadow_logger
+                    .queues()
+                    .iter()
+                    .all(LoggerQueue::is_idle)
+                && self.reject_logger
+                    .queues()
+                    .iter()
+                    .all(LoggerQueue::is_idle);
+            if is_stopped {
+                debug!("stopped with no unlogged rows");
+                return;
+            }
+            tokio::time::delay_for(time::Duration::from_millis(250)).await;
+        }
+        warn!("stopped logger with unlogged rows");
+        self.logger.persist();
+    }
+
+    fn setup(&mut self) {
+        // TODO verify table.exists()?
+        spawn_flush(Arc::clone(&self.logger), self.flush_interval);
+        if !self.shadow_logger.is_dummy() {
+            spawn_flush(Arc::clone(&self.shadow_logger), self.flush_interval);
+        }
+        if !self.reject_logger.is_dummy() {
+            spawn_flush(Arc::clone(&self.reject_logger), self.flush_interval);
+        }
+    }
+
+    /// The authoritative logger, followed by the best-effort one.
+    fn fulfill_loggers(&self) -> (&Logger<RowData>, &Logger<RowData>) {
+        if self.cutover.is_enabled() && !self.shadow_logger.is_dummy() {
+            (&self.shadow_logger, &self.logger)
+        } else {
+            (&self.logger, &self.shadow_logger)
+        }
+    }
+
+    /// The `value` of the column, if it is one of the `extra_columns`.
+    fn select<T>(&self, column: RowColumn, value: impl FnOnce() -> T)
+        -> Option<T>
+    {
+        if self.extra_columns.contains(&column) {
+            Some(value())
+        } else {
+            None
+        }
+    }
+
+    /// The `extra_columns` that are known when the Prepare is received.
+    fn row_extras(&self, prepare: &ilp::Prepare) -> RowExtras {
+        RowExtras {
+            prepare_time: self.select(RowColumn::PrepareTime, time::SystemTime::now),
+            expires_at: self.select(RowColumn::ExpiresAt, || prepare.expires_at()),
+            // Set once the response is received.
+            latency_ms: None,
+            has_connection_tag: self.select(RowColumn::HasConnectionTag, || {
+                prepare.destination().connection_tag().is_some()
+            }),
+        }
+    }
+
+    /// Whether a fulfill of the Prepare is in the sample that is logged.
+    fn is_sampled(&self, from_account: &str, prepare: &ilp::Prepare) -> bool {
+        let sample_rate = self.account_sample_rates
+            .get(from_account)
+            .copied()
+            .unwrap_or(self.sample_rate);
+        is_in_sample(prepare.execution_condition(), sample_rate)
+    }
+
+    fn record_route(
+        &self,
+        from_account: &Arc<String>,
+        route_index: Option<RouteIndex>,
+    ) {
+        if let Some(route_index) = route_index {
+            if self.next.is_catch_all(route_index) {
+                self.catch_all.record(from_account, time::Instant::now());
+            }
+        }
+    }
+
+    fn publish_event(
+        &self,
+        from_account: &Arc<String>,
+        route_index: Option<RouteIndex>,
+        destination: &ilp::Address,
+        amount: u64,
+        response: &Result<ilp::Fulfill, ilp::Reject>,
+        started_at: time::Instant,
+    ) {
+        if self.events.subscriber_count() == 0 {
+            return;
+        }
+        self.events.publish(PacketEvent {
+            account: Arc::clone(from_account),
+            to_account: route_index.map(|route| self.next.get_account(route)),
+            destination: destination.clone(),
+            amount,
+            result: PacketResult::new(response),
+            latency: started_at.elapsed(),
+        });
+    }
+
+    /// Rejects are logged on a best-effort basis: unlike fulfills, they are
+    /// dropped while the reject sink is unavailable.
+    #[allow(clippy::too_many_arguments)]
+    fn log_reject(
+        &self,
+        from_account: Arc<String>,
+        to_account: Option<Arc<String>>,
+        destination: ilp::Address,
+        amount: u64,
+        reject: &ilp::Reject,
+        extras: RowExtras,
+    ) {
+        if self.reject_logger.is_dummy() {
+            return;
+        }
+        let is_written = self.reject_logger.write_or_drop(Row::new(RejectRowData {
+            instance_id: self.instance_id.clone(),
+            account: Arc::clone(&from_account),
+            to_account,
+            destination: destination.clone(),
+            amount,
+            code: reject.code().to_string(),
+            triggered_by: reject.triggered_by().map(|addr| addr.to_address()),
+            message: String::from_utf8_lossy(reject.message()).into_owned(),
+            reject_time: time::SystemTime::now(),
+            extras,
+        }));
+        if !is_written {
+            debug!(
+                "reject sink unavailable, dropping row: from_account={} destination={}",
+                from_account, destination,
+            );
+        }
+    }
+}
+
+/// The condition is already a hash, so its prefix is uniformly distributed.
+fn is_in_sample(condition: &[u8], sample_rate: f64) -> bool {
+    if sample_rate >= 1.0 {
+        return true;
+    }
+    let prefix = u64::from_be_bytes(condition[..8].try_into().unwrap());
+    (prefix as f64 / u64::MAX as f64) < sample_rate
+}
+
+/// Stagger the logger flushes to avoid latency spikes.
+fn spawn_flush<D>(logger: Arc<Logger<D>>, flush_interval: time::Duration)
+where
+    D: 'static + Clone + Send + Sync + serde::Serialize + serde::de::DeserializeOwned,
+{
+    tokio::spawn(async move {
+        let queues = logger.queues();
+        let flush_interval = flush_interval / queues.len() as u32;
+        let mut index = 0;
+        loop {
+            if index == 0 {
+                logger.clean();
+            }
+            tokio::time::delay_for(flush_interval).await;
+            for queue in queues {
+                queue.flush_if_stale();
+            }
+            let queue = &queues[index];
+            queue.clone().flush_now();
+            index = (index + 1) % queues.len();
+        }
+    });
+}
+
+impl<Req> Service<Req> for TelemetryService
+where
+    Req: RequestWithFrom + Send + 'static,
+{
+    type Future = Pin<Box<
+        dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static,
+    >>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let prepare = request.borrow();
+        let from_account = Arc::clone(request.from_account());
+        let destination = prepare.destination()
+            .without_connection_tag()
+            .to_address();
+        let amount = prepare.amount();
+        let mut extras = self.row_extras(prepare);
+        let is_sampled = self.is_sampled(&from_account, prepare);
+        let request_id = RequestId::of(&request);
+        let started_at = time::Instant::now();
+
+        Box::pin(async move {
+            if self.logger.is_dummy() {
+                let response = self.next.clone()
+                    .forward(request.into(), request_id)
+                    .await;
+                self.record_route(&from_account, response.route);
+                self.publish_event(
+                    &from_account, response.route, &destination, amount,
+                    &response.packet, started_at,
+                );
+                return response.packet;
+            }
+
+            // Whether to log the packet if it is fulfilled.
+            let mut is_logged = is_sampled;
+            if is_logged && !self.fulfill_loggers().0.is_available() {
+                match self.on_unavailable {
+                    UnavailablePolicy::Reject => {
+                        throttled_warn!(
+                            &from_account,
+                            "telemetry sink unavailable, dropping packet: request_id={} from_account={} destination={} amount={}",
+                            request_id, from_account, destination, amount,
+                        );
+                        let response = Err(reject_reasons::TELEMETRY_UNAVAILABLE
+                            .to_reject(self.connector.address().as_addr()));
+                        self.publish_event(
+                            &from_account, None, &destination, amount,
+                            &response, started_at,
+                        );
+                        return response;
+                    },
+                    UnavailablePolicy::Forward => is_logged = false,
+                    UnavailablePolicy::Sample => {
+                        is_logged =
+                            random_fraction() < self.unavailable_sample_rate;
+                    },
+                }
+                if !is_logged {
+                    throttled_warn!(
+                        &from_account,
+                        "telemetry sink unavailable, forwarding packet without logging: request_id={} from_account={} destination={} amount={}",
+                        request_id, from_account, destination, amount,
+                    );
+                }
+            }
+
+            let response = self.next.clone()
+                .forward(request.into(), request_id)
+                .await;
+            let route_index = response.route;
+            self.record_route(&from_account, route_index);
+            self.publish_event(
+                &from_account, route_index, &destination, amount,
+                &response.packet, started_at,
+            );
+            extras.latency_ms = self.select(RowColumn::LatencyMs, || {
+                started_at.elapsed().as_millis() as u64
+            });
+            let fulfill = match response.packet {
+                Ok(fulfill) => fulfill,
+                Err(reject) => {
+                    let to_account = route_index
+                        .map(|route| self.next.get_account(route));
+                    self.log_reject(
+                        from_account, to_account, destination, amount, &reject,
+                        extras,
+                    );
+                    return Err(reject);
+                },
+            };
+            if !is_logged {
+                return Ok(fulfill);
+            }
+            let to_account = route_index
+                .map(|route| self.next.get_account(route))
+                .unwrap_or_else(|| {
+                    // This branch should be unreachable, but just to be safe:
+                    error!(
+                        "could not determine to_account: destination={} route={:?}",
+                        &destination, route_index,
+                    );
+                    Arc::new("unknown".to_owned())
+                });
+            let row = Row::new(RowData {
+                instance_id: self.instance_id.clone(),
+                account: from_account,
+                to_account,
+                destination,
+                amount,
+                fulfill_time: time::SystemTime::now(),
+                extras,
+            });
+            let (logger, shadow_logger) = self.fulfill_loggers();
+            if !shadow_logger.is_dummy() {
+                // The same `insert_id`, so that the sinks' rows can be compared.
+                shadow_logger.write_or_drop(row.clone());
+            }
+            logger.write(row);
+            Ok(fulfill)
+        })
+    }
+}
+
+/// Serialize a `SystemTime` to a BigQuery `TIMESTAMP`.
+///
+/// <https://cloud.google.com/bigquery/docs/reference/standard-sql/data-types#timestamp_type>
+fn serialize_timestamp<S>(time: &time::SystemTime, serializer: S)
+    -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    let datetime = chrono::DateTime::<chrono::Utc>::from(*time);
+
+    serializer.collect_str({
+        &datetime.format("%Y-%m-%dT%H:%M:%S.%6fZ")
+    })
+}
+
+/// Deserialize a BigQuery `TIMESTAMP` (as serialized by `serialize_timestamp`)
+/// to a `SystemTime`, e.g. when replaying spilled rows.
+fn deserialize_timestamp<'de, D>(deserializer: D)
+    -> Result<time::SystemTime, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    use serde::Deserialize;
+    let timestamp = String::deserialize(deserializer)?;
+    chrono::DateTime::parse_from_rfc3339(&timestamp)
+        .map(time::SystemTime::from)
+        .map_err(serde::de::Error::custom)
+}
+
+fn serialize_optional_timestamp<S>(time: &Option<time::SystemTime>, serializer: S)
+    -> Result<S::Ok, S::Error>
+where
+    S: serde::Serializer,
+{
+    match time {
+        Some(time) => serialize_timestamp(time, serializer),
+        None => serializer.serialize_none(),
+    }
+}
+
+fn deserialize_optional_timestamp<'de, D>(deserializer: D)
+    -> Result<Option<time::SystemTime>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    deserialize_timestamp(deserializer).map(Some)
+}
+
+#[cfg(test)]
+mod test_telemetry_service {
+    use chrono::TimeZone;
+    use lazy_static::lazy_static;
+
+    use crate::Relation;
+    use crate::services::{RoutingPartition, RoutingTable};
+    use crate::testing::{self, ADDRESS, FULFILL, ILDCP_RESPONSE, MockClient, MockRequest, MockSinkTransport, PREPARE, REJECT, ROUTES};
+    use crate::transport::PeerResponse;
+    use super::*;
+
+    lazy_static! {
+        /// The sink is never flushed during a test, so the rows stay queued.
+        static ref CONFIG: LoggerConfig = LoggerConfig {
+            queue_count: 1,
+            batch_capacity: 10,
+            max_batch_bytes: 9_000_000,
+            max_row_age: None,
+            retry_backoff: time::Duration::from_secs(3600),
+            max_retry_backoff: time::Duration::from_secs(3600),
+            max_attempts: None,
+            dead_letter: None,
+            flush_interval: time::Duration::from_secs(3600),
+            sink: SinkConfig::File(FileConfig {
+                file: "-".into(),
+                max_file_size: None,
+                max_files: 5,
+            }),
+            reject_sink: None,
+            shadow_sink: None,
+            cutover: false,
+            extra_columns: Vec::new(),
+            sample_rate: 1.0,
+            account_sample_rates: HashMap::new(),
+            spill: None,
+            on_unavailable: UnavailablePolicy::Reject,
+            unavailable_sample_rate: 0.1,
+        };
+    }
+
+    /// The next hop responds with the `response`.
+    fn make_router(response: PeerResponse) -> RouterService {
+        RouterService::new(
+            ADDRESS.to_address(),
+            Arc::new(MockClient::with_response(response)),
+            RoutingTable::new(ROUTES.clone(), RoutingPartition::default()),
+            false,
+        )
+    }
+
+    async fn make_service(config: LoggerConfig, response: PeerResponse)
+        -> TelemetryService
+    {
+        let metrics = Arc::new(Metrics::default());
+        let router = make_router(response);
+        TelemetryService::new(
+            ILDCP_RESPONSE.clone(),
+            None,
+            Some(config),
+            Arc::new(MockSinkTransport::default()),
+            Arc::new(CatchAllMonitor::new(None, Arc::clone(&metrics))),
+            metrics,
+            PacketEvents::new(16),
+            ValidateFulfillmentService::new(ADDRESS.to_address(), router),
+        ).await.unwrap()
+    }
+
+    fn make_request() -> MockRequest {
+        let mut request = MockRequest::new(PREPARE.clone())
+            .from_account("bob", Relation::Child);
+        request.from_address = ilp::Address::new(b"example.connie.bob");
+        request
+    }
+
+    #[tokio::test]
+    async fn test_log_fulfill_latency() {
+        let service = make_service(LoggerConfig {
+            extra_columns: vec![RowColumn::LatencyMs],
+            ..CONFIG.clone()
+        }, Ok(FULFILL.clone())).await;
+        assert_eq!(
+            service.clone().call(make_request()).await,
+            Ok(FULFILL.clone()),
+        );
+        let rows = service.logger.queues()[0].take_rows();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].json.extras.latency_ms.is_some());
+        let json = serde_json::to_value(&rows[0].json).unwrap();
+        assert!(json["latency_ms"].is_u64());
+    }
+
+    #[tokio::test]
+    async fn test_log_reject_latency() {
+        let service = make_service(LoggerConfig {
+            reject_sink: Some(CONFIG.sink.clone()),
+            extra_columns: vec![RowColumn::LatencyMs],
+            ..CONFIG.clone()
+        }, Err(REJECT.clone())).await;
+        assert_eq!(
+            service.clone().call(make_request()).await,
+            Err(REJECT.clone()),
+        );
+        let rows = service.reject_logger.queues()[0].take_rows();
+        assert_eq!(rows.len(), 1);
+        assert!(rows[0].json.extras.latency_ms.is_some());
+    }
+
+    #[tokio::test]
+    async fn test_log_without_latency() {
+        let service = make_service(CONFIG.clone(), Ok(FULFILL.clone())).await;
+        service.clone().call(make_request()).await.unwrap();
+        let rows = service.logger.queues()[0].take_rows();
+        assert_eq!(rows[0].json.extras.latency_ms, None);
+        let json = serde_json::to_value(&rows[0].json).unwrap();
+        assert!(json.get("latency_ms").is_none());
+    }
+
+    /// A service whose sink is down: its only queue is full, and its flush
+    /// fails (and isn't retried during the test).
+    async fn make_unavailable_service(
+        on_unavailable: UnavailablePolicy,
+        unavailable_sample_rate: f64,
+    ) -> TelemetryService {
+        // Only the forwarded Prepares reach the next hop.
+        let service = make_service(LoggerConfig {
+            batch_capacity: 1,
+            sink: SinkConfig::File(FileConfig {
+                file: std::env::temp_dir()
+                    .join(format!("ilp-relay-telemetry-{}", uuid::Uuid::new_v4()))
+                    .join("rows.json"),
+                max_file_size: None,
+                max_files: 5,
+            }),
+            on_unavailable,
+            unavailable_sample_rate,
+            ..CONFIG.clone()
+        }, Ok(FULFILL.clone())).await;
+        service.logger.write(Row::new(RowData {
+            instance_id: None,
+            account: Arc::new("ACCOUNT".to_owned()),
+            to_account: Arc::new("TO_ACCOUNT".to_owned()),
+            destination: testing::ADDRESS.to_address(),
+            amount: 123,
+            fulfill_time: time::SystemTime::now(),
+            extras: RowExtras::default(),
+        }));
+        assert!(!service.logger.is_available());
+        service
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_reject() {
+        let service =
+            make_unavailable_service(UnavailablePolicy::Reject, 0.1).await;
+        // The Prepare isn't forwarded (there is no next hop to receive it).
+        let reject = service.clone().call(make_request()).await.unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::T03_CONNECTOR_BUSY);
+        assert_eq!(reject.triggered_by(), Some(ILDCP_RESPONSE.client_address()));
+        assert_eq!(service.logger.overflow_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_forward() {
+        let service =
+            make_unavailable_service(UnavailablePolicy::Forward, 1.0).await;
+        assert_eq!(
+            service.clone().call(make_request()).await,
+            Ok(FULFILL.clone()),
+        );
+        assert_eq!(service.logger.overflow_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_unavailable_sample() {
+        // Every packet is sampled, and held until the sink recovers,
+        // up to a batch per queue; the second row is dropped.
+        let service =
+            make_unavailable_service(UnavailablePolicy::Sample, 1.0).await;
+        for _ in 0..2 {
+            assert_eq!(
+                service.clone().call(make_request()).await,
+                Ok(FULFILL.clone()),
+            );
+        }
+        assert_eq!(service.logger.overflow_len(), 1);
+
+        // No packets are sampled, but they are still forwarded.
+        let service =
+            make_unavailable_service(UnavailablePolicy::Sample, 0.0).await;
+        assert_eq!(
+            service.clone().call(make_request()).await,
+            Ok(FULFILL.clone()),
+        );
+        assert_eq!(service.logger.overflow_len(), 0);
+    }
+
+    #[tokio::test]
+    async fn test_invalid_unavailable_sample_rate() {
+        let metrics = Arc::new(Metrics::default());
+        let router = make_router(Ok(FULFILL.clone()));
+        let result = TelemetryService::new(
+            ILDCP_RESPONSE.clone(),
+            None,
+            Some(LoggerConfig {
+                on_unavailable: UnavailablePolicy::Sample,
+                unavailable_sample_rate: 1.5,
+                ..CONFIG.clone()
+            }),
+            Arc::new(MockSinkTransport::default()),
+            Arc::new(CatchAllMonitor::new(None, Arc::clone(&metrics))),
+            metrics,
+            PacketEvents::new(16),
+            ValidateFulfillmentService::new(ADDRESS.to_address(), router),
+        ).await;
+        assert!(result.is_err());
+    }
+
+    #[test]
+    fn test_serialize_row_data() {
+        const EXPECT: &str = r#"{
+  "account": "ACCOUNT",
+  "to_account": "TO_ACCOUNT",
+  "destination": "test.relay",
+  "amount": 123,
+  "fulfill_time": "2020-05-06T07:08:09.000000Z"
+}"#;
+        let fulfill_time = time::SystemTime::from({
+            chrono::Utc.ymd(2020, 05, 06).and_hms(07, 08, 09)
+        });
+        assert_eq!(
+            serde_json::to_string_pretty(&RowData {
+                instance_id: None,
+                account: Arc::new("ACCOUNT".to_owned()),
+                to_account: Arc::new("TO_ACCOUNT".to_owned()),
+                destination: testing::ADDRESS.to_address(),
+                amount:  123,
+                fulfill_time,
+                extras: RowExtras::default(),
+            }).unwrap(),
+            EXPECT,
+        );
+    }
+
+    #[test]
+    fn test_serialize_row_data_with_instance_id() {
+        let row = serde_json::to_value(&RowData {
+            instance_id: Some(Arc::new("relay-1".to_owned())),
+            account: Arc::new("ACCOUNT".to_owned()),
+            to_account: Arc::new("TO_ACCOUNT".to_owned()),
+            destination: testing::ADDRESS.to_address(),
+            amount:  123,
+            fulfill_time: time::SystemTime::now(),
+            extras: RowExtras::default(),
+        }).unwrap();
+        assert_eq!(row["instance_id"], "relay-1");
+    }
+
+    #[test]
+    fn test_deserialize_row_data() {
+        // 2020-05-06T07:08:09.123456Z
+        let fulfill_time = time::UNIX_EPOCH
+            + time::Duration::from_micros(1_588_748_889_123_456);
+        let row = RowData {
+            instance_id: None,
+            account: Arc::new("ACCOUNT".to_owned()),
+            to_account: Arc::new("TO_ACCOUNT".to_owned()),
+            destination: testing::ADDRESS.to_address(),
+            amount:  123,
+            fulfill_time,
+            extras: RowExtras::default(),
+        };
+        let json = serde_json::to_string(&row).unwrap();
+        let row = serde_json::from_str::<RowData>(&json).unwrap();
+        assert_eq!(row.instance_id, None);
+        assert_eq!(row.destination, testing::ADDRESS.to_address());
+        assert_eq!(row.fulfill_time, fulfill_time);
+    }
+
+    #[test]
+    fn test_serialize_reject_row_data() {
+        const EXPECT: &str = r#"{
+  "account": "ACCOUNT",
+  "to_account": null,
+  "destination": "test.relay",
+  "amount": 123,
+  "code": "F02",
+  "triggered_by": "test.relay",
+  "message": "no route exists",
+  "reject_time": "2020-05-06T07:08:09.000000Z"
+}"#;
+        // 2020-05-06T07:08:09Z
+        let reject_time =
+            time::UNIX_EPOCH + time::Duration::from_secs(1_588_748_889);
+        let reject = ilp::RejectBuilder {
+            code: ilp::ErrorCode::F02_UNREACHABLE,
+            message: b"no route exists",
+            triggered_by: Some(testing::ADDRESS),
+            data: b"",
+        }.build();
+        assert_eq!(
+            serde_json::to_string_pretty(&RejectRowData {
+                instance_id: None,
+                account: Arc::new("ACCOUNT".to_owned()),
+                to_account: None,
+                destination: testing::ADDRESS.to_address(),
+                amount: 123,
+                code: reject.code().to_string(),
+                triggered_by: reject.triggered_by().map(|addr| addr.to_address()),
+                message: String::from_utf8_lossy(reject.message()).into_owned(),
+                reject_time,
+                extras: RowExtras::default(),
+            }).unwrap(),
+            EXPECT,
+        );
+    }
+
+    #[test]
+    fn test_is_in_sample() {
+        let mut low = [0x00; 32];
+        low[7] = 0x01;
+        let high = [0xff; 32];
+        assert!(is_in_sample(&low, 1.0));
+        assert!(is_in_sample(&high, 1.0));
+        assert!(is_in_sample(&low, 0.1));
+        assert!(!is_in_sample(&high, 0.1));
+        assert!(!is_in_sample(&low, 0.0));
+
+        // Roughly `sample_rate` of the (uniform) conditions are sampled.
+        let count = (0..1_000_u32)
+            .filter(|i| {
+                let condition = ring::digest::digest(
+                    &ring::digest::SHA256,
+                    &i.to_be_bytes(),
+                );
+                is_in_sample(condition.as_ref(), 0.1)
+            })
+            .count();
+        assert!((50..150).contains(&count), "count={}", count);
+    }
+
+    #[test]
+    fn test_serialize_row_extras() {
+        // 2020-05-06T07:08:09Z
+        let time = time::UNIX_EPOCH + time::Duration::from_secs(1_588_748_889);
+        let row = RowData {
+            instance_id: None,
+            account: Arc::new("ACCOUNT".to_owned()),
+            to_account: Arc::new("TO_ACCOUNT".to_owned()),
+            destination: testing::ADDRESS.to_address(),
+            amount:  123,
+            fulfill_time: time,
+            extras: RowExtras {
+                prepare_time: Some(time),
+                expires_at: None,
+                latency_ms: Some(25),
+                has_connection_tag: Some(false),
+            },
+        };
+        let json = serde_json::to_value(&row).unwrap();
+        assert_eq!(json["prepare_time"], "2020-05-06T07:08:09.000000Z");
+        assert_eq!(json["latency_ms"], 25);
+        assert_eq!(json["has_connection_tag"], false);
+        // Only the selected columns are serialized.
+        assert!(json.get("expires_at").is_none());
+
+        let json = serde_json::to_string(&row).unwrap();
+        let row = serde_json::from_str::<RowData>(&json).unwrap();
+        assert_eq!(row.extras.prepare_time, Some(time));
+        assert_eq!(row.extras.expires_at, None);
+        assert_eq!(row.extras.latency_ms, Some(25));
+    }
+}
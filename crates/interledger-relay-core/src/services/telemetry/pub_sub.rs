@@ -0,0 +1,252 @@
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time;
+
+use futures::prelude::*;
+use log::trace;
+
+use crate::SetupError;
+use crate::transport::{Endpoint, HttpClient, InvalidEndpoint};
+use super::{ClientError, Row, Sink, SinkError};
+use super::client::post_json;
+
+/// Publish each row as a JSON message to a Pub/Sub topic. The row's insert ID
+/// is included as the `insert_id` attribute, so that subscribers can
+/// deduplicate retried rows.
+///
+/// See: <https://cloud.google.com/pubsub/docs/reference/rest/>
+#[derive(Clone, Debug)]
+pub struct PubSubSink {
+    client: Arc<dyn HttpClient>,
+    publish_uri: String,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct PubSubConfig {
+    #[serde(default = "default_origin")]
+    pub origin: String,
+    pub project_id: String,
+    pub topic_id: String,
+    /// <https://docs.rs/yup-oauth2/4.1.2/yup_oauth2/struct.ServiceAccountKey.html>
+    pub service_account_key_file: Option<std::path::PathBuf>,
+}
+
+fn default_origin() -> String { "https://pubsub.googleapis.com".to_owned() }
+
+/// <https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.topics/publish#request-body>
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct PublishRequest {
+    messages: Vec<PubSubMessage>,
+}
+
+/// <https://cloud.google.com/pubsub/docs/reference/rest/v1/PubsubMessage>
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct PubSubMessage {
+    /// Base64-encoded JSON.
+    data: String,
+    attributes: MessageAttributes,
+}
+
+#[derive(Debug, PartialEq, serde::Serialize)]
+struct MessageAttributes {
+    insert_id: String,
+}
+
+/// <https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.topics/publish#response-body>
+#[derive(Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+struct PublishResponse {
+    #[serde(default)]
+    message_ids: Vec<String>,
+}
+
+impl PubSubSink {
+    pub const SCOPES: &'static [&'static str] =
+        &["https://www.googleapis.com/auth/pubsub"];
+
+    pub fn new(config: &PubSubConfig, client: Arc<dyn HttpClient>)
+        -> Result<Self, SetupError>
+    {
+        let publish_uri = config.publish_uri().map_err(|error| {
+            SetupError::invalid_config(format!("invalid Pub/Sub topic: {}", error))
+        })?;
+        Ok(PubSubSink { client, publish_uri })
+    }
+
+    /// Publishing is all-or-nothing, so on error every row is retried.
+    ///
+    /// See: <https://cloud.google.com/pubsub/docs/reference/rest/v1/projects.topics/publish>
+    pub async fn publish<D>(self, rows: Vec<Row<D>>)
+        -> Result<(), SinkError<D>>
+    where
+        D: serde::Serialize + Clone + Send + Sync + 'static,
+    {
+        trace!("publish begin: rows={}", rows.len());
+        let json = match make_publish_request(&rows) {
+            Ok(json) => json,
+            Err(error) => return Err(SinkError::new(rows, error)),
+        };
+        let start = time::Instant::now();
+
+        let response_result = post_json::<PublishResponse>(
+            self.client.as_ref(),
+            &self.publish_uri,
+            json,
+        ).await;
+
+        let elapsed = time::Instant::now() - start;
+        match response_result {
+            Ok(response) if response.message_ids.len() == rows.len() => {
+                trace!("publish success: elapsed={:?} rows={}", elapsed, rows.len());
+                Ok(())
+            },
+            Ok(response) => {
+                throttled_warn!(
+                    "",
+                    "publish partial error: elapsed={:?} message_ids={} rows={}",
+                    elapsed, response.message_ids.len(), rows.len(),
+                );
+                Err(SinkError::new(rows, ClientError::PartialError))
+            },
+            Err(error) => {
+                throttled_warn!(
+                    "",
+                    "publish error: elapsed={:?} error={:?} rows={}",
+                    elapsed, error, rows.len(),
+                );
+                Err(SinkError::new(rows, error))
+            },
+        }
+    }
+}
+
+impl<D> Sink<D> for PubSubSink
+where
+    D: serde::Serialize + Clone + Send + Sync + 'static,
+{
+    fn write_batch(&self, rows: Vec<Row<D>>) -> Pin<Box<
+        dyn Future<Output = Result<(), SinkError<D>>> + Send + 'static,
+    >> {
+        Box::pin(self.clone().publish(rows))
+    }
+}
+
+impl PubSubConfig {
+    pub(crate) fn publish_uri(&self) -> Result<String, InvalidEndpoint> {
+        use percent_encoding::{NON_ALPHANUMERIC, percent_encode};
+        const CHARS: &percent_encoding::AsciiSet = &NON_ALPHANUMERIC.remove(b'_');
+        format!(
+            "{}/v1/projects/{}/topics/{}:publish",
+            self.origin,
+            percent_encode(self.project_id.as_bytes(), CHARS),
+            percent_encode(self.topic_id.as_bytes(), CHARS),
+        ).parse::<Endpoint>().map(|uri| uri.as_str().to_owned())
+    }
+}
+
+fn make_publish_request<D>(rows: &[Row<D>]) -> Result<String, ClientError>
+where
+    D: serde::Serialize,
+{
+    let messages = rows
+        .iter()
+        .map(|row| Ok(PubSubMessage {
+            data: base64::encode(serde_json::to_vec(&row.json)?),
+            attributes: MessageAttributes {
+                insert_id: row.insert_id.to_string(),
+            },
+        }))
+        .collect::<Result<Vec<_>, serde_json::Error>>()
+        .map_err(ClientError::Serde)?;
+    serde_json::to_string(&PublishRequest { messages })
+        .map_err(ClientError::Serde)
+}
+
+#[cfg(test)]
+mod test_pub_sub_sink {
+    use lazy_static::lazy_static;
+
+    use crate::testing::{self, MockHttpClient};
+    use crate::transport::HttpMethod;
+    use super::*;
+
+    lazy_static! {
+        static ref CONFIG: PubSubConfig = PubSubConfig {
+            origin: testing::RECEIVER_ORIGIN.to_owned(),
+            project_id: "PROJECT_ID".to_owned(),
+            topic_id: "TOPIC_ID".to_owned(),
+            service_account_key_file: None,
+        };
+
+        static ref ROWS: Vec<Row<i32>> =
+            vec![Row::new(1), Row::new(2), Row::new(3)];
+    }
+
+    fn make_sink(client: &MockHttpClient) -> PubSubSink {
+        PubSubSink::new(&CONFIG, Arc::new(client.clone())).unwrap()
+    }
+
+    fn make_response(message_ids: usize) -> MockHttpClient {
+        MockHttpClient::with_response(200, {
+            serde_json::to_vec(&PublishResponse {
+                message_ids: (0..message_ids)
+                    .map(|id| id.to_string())
+                    .collect(),
+            }).unwrap()
+        })
+    }
+
+    #[tokio::test]
+    async fn test_publish_ok() {
+        let client = make_response(3);
+        make_sink(&client).publish(ROWS.clone()).await.unwrap();
+
+        let requests = client.requests();
+        assert_eq!(requests.len(), 1);
+        assert_eq!(requests[0].method, HttpMethod::Post);
+        assert_eq!(
+            requests[0].uri,
+            format!(
+                "{}/v1/projects/PROJECT_ID/topics/TOPIC_ID:publish",
+                testing::RECEIVER_ORIGIN,
+            ),
+        );
+        let request =
+            serde_json::from_slice::<serde_json::Value>(&requests[0].body).unwrap();
+        let message = &request["messages"][1];
+        assert_eq!(message["data"], base64::encode(b"2"));
+        assert_eq!(
+            message["attributes"]["insert_id"],
+            ROWS[1].insert_id.to_string(),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_partial_error() {
+        let sink = make_sink(&make_response(1));
+        assert_eq!(
+            sink.publish(ROWS.clone()).await.unwrap_err().retries,
+            ROWS.clone(),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_publish_error() {
+        let sink = make_sink(&MockHttpClient::with_response(500, ""));
+        assert_eq!(
+            sink.publish(ROWS.clone()).await.unwrap_err().retries,
+            ROWS.clone(),
+        );
+    }
+
+    #[test]
+    fn test_new_invalid_origin() {
+        let client = Arc::new(make_response(0));
+        let config = PubSubConfig {
+            origin: "not a uri".to_owned(),
+            ..CONFIG.clone()
+        };
+        assert!(PubSubSink::new(&config, client).is_err());
+    }
+}
@@ -0,0 +1,302 @@
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use crate::SetupError;
+use crate::metrics::Metrics;
+use crate::transport::SinkTransport;
+use super::{BigQueryConfig, BigQuerySink, ClientError, FileConfig, FileSink};
+use super::{KafkaConfig, KafkaSink, PubSubConfig, PubSubSink};
+
+/// A destination for batches of rows, e.g. a BigQuery table.
+pub trait Sink<D>: fmt::Debug + Send + Sync {
+    /// Write the rows. On error, the rows that should be retried are returned.
+    fn write_batch(&self, rows: Vec<Row<D>>) -> Pin<Box<
+        dyn Future<Output = Result<(), SinkError<D>>> + Send + 'static,
+    >>;
+}
+
+/// The sink is chosen by which fields are set:
+///
+/// * `BigQuery`: `dataset_id` and `table_id`
+/// * `PubSub`: `topic_id`
+/// * `Kafka`: `brokers` and `topic`
+/// * `File`: `file`
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(untagged)]
+pub enum SinkConfig {
+    BigQuery(BigQueryConfig),
+    PubSub(PubSubConfig),
+    Kafka(KafkaConfig),
+    File(FileConfig),
+}
+
+static ROWS_WRITTEN: &str = "ilp_relay_telemetry_rows_written_total";
+static ROWS_FAILED: &str = "ilp_relay_telemetry_rows_failed_total";
+static ROWS_DROPPED: &str = "ilp_relay_telemetry_rows_dropped_total";
+static ROWS_DEAD_LETTERED: &str = "ilp_relay_telemetry_rows_dead_lettered_total";
+
+/// Count the rows that a sink wrote, failed to write (they are retried),
+/// dropped, or gave up on after `max_attempts` (dead-lettered), labeled by the sink's role: `"sink"`, `"shadow_sink"`, or
+/// `"reject_sink"`.
+#[derive(Clone, Debug)]
+pub struct SinkMetrics {
+    metrics: Arc<Metrics>,
+    sink: &'static str,
+}
+
+/// Wrap a sink to count the rows that it writes.
+pub struct MeteredSink<D> {
+    inner: Arc<dyn Sink<D>>,
+    metrics: SinkMetrics,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize, serde::Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct Row<D> {
+    /// Used by the sink to deduplicate retried rows.
+    pub insert_id: uuid::Uuid,
+    pub json: D,
+    /// The number of failed writes. This isn't sent to the sink (or spilled).
+    #[serde(skip)]
+    pub attempts: u32,
+}
+
+#[derive(Debug)]
+pub struct SinkError<D> {
+    pub retries: Vec<Row<D>>,
+    pub error: ClientError,
+}
+
+impl SinkConfig {
+    pub async fn build<D>(&self, transport: &Arc<dyn SinkTransport>)
+        -> Result<Arc<dyn Sink<D>>, SetupError>
+    where
+        D: 'static + Clone + Send + Sync + serde::Serialize,
+    {
+        Ok(match self {
+            SinkConfig::BigQuery(config) => {
+                let client = transport.google_client(
+                    config.service_account_key_file.clone(),
+                    BigQuerySink::SCOPES,
+                ).await?;
+                Arc::new(BigQuerySink::new(config, client))
+            },
+            SinkConfig::PubSub(config) => {
+                let client = transport.google_client(
+                    config.service_account_key_file.clone(),
+                    PubSubSink::SCOPES,
+                ).await?;
+                Arc::new(PubSubSink::new(config, client)?)
+            },
+            SinkConfig::Kafka(config) =>
+                Arc::new(KafkaSink::new(config, Arc::clone(transport))),
+            SinkConfig::File(config) => Arc::new(FileSink::new(config)),
+        })
+    }
+
+    /// The name of the sink, for the config summary.
+    pub fn name(&self) -> &'static str {
+        match self {
+            SinkConfig::BigQuery(_) => "BigQuery",
+            SinkConfig::PubSub(_) => "PubSub",
+            SinkConfig::Kafka(_) => "Kafka",
+            SinkConfig::File(_) => "File",
+        }
+    }
+
+    /// The Google Cloud project, if any.
+    pub fn project_id(&self) -> Option<&str> {
+        match self {
+            SinkConfig::BigQuery(config) => Some(&config.project_id),
+            SinkConfig::PubSub(config) => Some(&config.project_id),
+            SinkConfig::Kafka(_) | SinkConfig::File(_) => None,
+        }
+    }
+
+    /// The table (`dataset_id.table_id`), topic, or file that rows are
+    /// written to.
+    pub fn destination(&self) -> String {
+        match self {
+            SinkConfig::BigQuery(config) =>
+                format!("{}.{}", config.dataset_id, config.table_id),
+            SinkConfig::PubSub(config) => config.topic_id.clone(),
+            SinkConfig::Kafka(config) => config.topic.clone(),
+            SinkConfig::File(config) => config.file.display().to_string(),
+        }
+    }
+}
+
+impl SinkMetrics {
+    pub fn new(metrics: Arc<Metrics>, sink: &'static str) -> Self {
+        SinkMetrics { metrics, sink }
+    }
+
+    pub fn dropped(&self, rows: usize) {
+        self.increment(ROWS_DROPPED, rows);
+    }
+
+    pub fn dead_lettered(&self, rows: usize) {
+        self.increment(ROWS_DEAD_LETTERED, rows);
+    }
+
+    fn increment(&self, name: &'static str, rows: usize) {
+        if rows != 0 {
+            self.metrics.increment(name, vec![
+                ("sink", self.sink.to_owned()),
+            ], rows as u64);
+        }
+    }
+}
+
+impl<D> MeteredSink<D> {
+    pub fn new(inner: Arc<dyn Sink<D>>, metrics: SinkMetrics) -> Self {
+        MeteredSink { inner, metrics }
+    }
+}
+
+impl<D> Sink<D> for MeteredSink<D>
+where
+    D: 'static + Send,
+{
+    fn write_batch(&self, rows: Vec<Row<D>>) -> Pin<Box<
+        dyn Future<Output = Result<(), SinkError<D>>> + Send + 'static,
+    >> {
+        let count = rows.len();
+        let metrics = self.metrics.clone();
+        Box::pin(self.inner.write_batch(rows).inspect(move |result| {
+            let failed = match result {
+                Ok(()) => 0,
+                Err(error) => error.retries.len(),
+            };
+            metrics.increment(ROWS_WRITTEN, count.saturating_sub(failed));
+            metrics.increment(ROWS_FAILED, failed);
+        }))
+    }
+}
+
+impl<D> fmt::Debug for MeteredSink<D> {
+    fn fmt(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter
+            .debug_struct("MeteredSink")
+            .field("inner", &self.inner)
+            .field("sink", &self.metrics.sink)
+            .finish()
+    }
+}
+
+impl<D> SinkError<D> {
+    pub fn new(retries: Vec<Row<D>>, error: ClientError) -> Self {
+        SinkError { retries, error }
+    }
+}
+
+impl<D> Row<D> {
+    pub fn new(json: D) -> Self {
+        Row { insert_id: uuid::Uuid::new_v4(), json, attempts: 0 }
+    }
+}
+
+#[cfg(test)]
+mod test_sink_config {
+    use super::*;
+    use super::super::KafkaCompression;
+
+    #[test]
+    fn test_deserialize() {
+        let config = serde_json::from_str::<SinkConfig>(r#"{
+            "project_id": "PROJECT_ID",
+            "dataset_id": "DATASET_ID",
+            "table_id": "TABLE_ID"
+        }"#).unwrap();
+        assert_eq!(config.name(), "BigQuery");
+        assert_eq!(config.project_id(), Some("PROJECT_ID"));
+        assert_eq!(config.destination(), "DATASET_ID.TABLE_ID");
+
+        let config = serde_json::from_str::<SinkConfig>(r#"{
+            "project_id": "PROJECT_ID",
+            "topic_id": "TOPIC_ID"
+        }"#).unwrap();
+        assert_eq!(config.name(), "PubSub");
+        assert_eq!(config.destination(), "TOPIC_ID");
+
+        let config = serde_json::from_str::<SinkConfig>(r#"{
+            "brokers": ["kafka-1:9092", "kafka-2:9092"],
+            "topic": "packets",
+            "compression": "lz4"
+        }"#).unwrap();
+        assert_eq!(config.name(), "Kafka");
+        assert_eq!(config.project_id(), None);
+        assert_eq!(config.destination(), "packets");
+        match config {
+            SinkConfig::Kafka(config) => {
+                assert_eq!(config.compression, KafkaCompression::Lz4);
+                assert_eq!(config.acks, 1);
+            },
+            _ => panic!("expected Kafka"),
+        }
+
+        let config = serde_json::from_str::<SinkConfig>(r#"{
+            "file": "-"
+        }"#).unwrap();
+        assert_eq!(config.name(), "File");
+        assert_eq!(config.project_id(), None);
+        assert_eq!(config.destination(), "-");
+
+        // Ambiguous:
+        assert!(serde_json::from_str::<SinkConfig>(r#"{
+            "project_id": "PROJECT_ID",
+            "table_id": "TABLE_ID",
+            "topic_id": "TOPIC_ID"
+        }"#).is_err());
+    }
+}
+
+#[cfg(test)]
+mod test_metered_sink {
+    use futures::executor::block_on;
+
+    use super::*;
+
+    /// Ask for every other row to be retried.
+    #[derive(Debug)]
+    struct FlakySink;
+
+    impl Sink<i32> for FlakySink {
+        fn write_batch(&self, rows: Vec<Row<i32>>) -> Pin<Box<
+            dyn Future<Output = Result<(), SinkError<i32>>> + Send + 'static,
+        >> {
+            let retries = rows
+                .into_iter()
+                .step_by(2)
+                .collect::<Vec<_>>();
+            Box::pin(future::ready(if retries.is_empty() {
+                Ok(())
+            } else {
+                Err(SinkError::new(retries, ClientError::PartialError))
+            }))
+        }
+    }
+
+    #[test]
+    fn test_write_batch() {
+        let metrics = Arc::new(Metrics::default());
+        let sink = MeteredSink::new(
+            Arc::new(FlakySink),
+            SinkMetrics::new(Arc::clone(&metrics), "shadow_sink"),
+        );
+        let rows = (0..5).map(Row::new).collect::<Vec<_>>();
+        let error = block_on(sink.write_batch(rows)).unwrap_err();
+        assert_eq!(error.retries.len(), 3);
+        block_on(sink.write_batch(vec![])).unwrap();
+        sink.metrics.dropped(4);
+        sink.metrics.dead_lettered(1);
+
+        let labels = || vec![("sink", "shadow_sink".to_owned())];
+        assert_eq!(metrics.get(ROWS_WRITTEN, labels()), 2);
+        assert_eq!(metrics.get(ROWS_FAILED, labels()), 3);
+        assert_eq!(metrics.get(ROWS_DROPPED, labels()), 4);
+        assert_eq!(metrics.get(ROWS_DEAD_LETTERED, labels()), 1);
+    }
+}
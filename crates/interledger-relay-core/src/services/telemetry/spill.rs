@@ -0,0 +1,299 @@
+use std::collections::VecDeque;
+use std::fs;
+use std::io::{self, BufRead, Seek, SeekFrom, Write};
+use std::path::PathBuf;
+use std::sync::Mutex;
+
+use log::{info, warn};
+
+use super::Row;
+
+const SEGMENT_EXTENSION: &str = "ndjson";
+
+/// A bounded, disk-backed queue of rows that couldn't be written to the sink's
+/// queues. Rows are appended (as lines of JSON) to numbered segment files in
+/// `directory`, and replayed oldest-first once the sink recovers. Segments are
+/// deleted once they are fully replayed.
+///
+/// The replay position is only kept in memory, so after a restart the oldest
+/// segment is replayed from the beginning. Sinks deduplicate the repeated rows
+/// by their `insert_id`.
+#[derive(Debug)]
+pub struct Spill {
+    config: SpillConfig,
+    state: Mutex<SpillState>,
+}
+
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct SpillConfig {
+    /// The directory that segments are written to. It is created if it doesn't
+    /// exist, and must not be shared with another connector.
+    pub directory: PathBuf,
+    /// Start a new segment once the current one is at least this many bytes.
+    #[serde(default = "default_segment_size")]
+    pub segment_size: u64,
+    /// Refuse rows once the segments add up to this many bytes.
+    pub max_size: u64,
+}
+
+fn default_segment_size() -> u64 { 16 * 1024 * 1024 }
+
+#[derive(Debug, Default)]
+struct SpillState {
+    /// Oldest first. Rows are appended to the last segment.
+    segments: VecDeque<Segment>,
+    next_index: u64,
+    /// The total size of the segments.
+    size: u64,
+    /// The number of bytes of the oldest segment that have been replayed.
+    read_offset: u64,
+    /// The last segment, opened for appending.
+    writer: Option<fs::File>,
+}
+
+#[derive(Debug)]
+struct Segment {
+    index: u64,
+    size: u64,
+}
+
+impl Spill {
+    /// Open the directory, picking up any segments left by a previous process.
+    pub fn open(config: &SpillConfig) -> io::Result<Self> {
+        fs::create_dir_all(&config.directory)?;
+        let mut segments = Vec::new();
+        for entry in fs::read_dir(&config.directory)? {
+            let path = entry?.path();
+            if path.extension().and_then(|ext| ext.to_str()) != Some(SEGMENT_EXTENSION) {
+                continue;
+            }
+            let index = path.file_stem()
+                .and_then(|stem| stem.to_str())
+                .and_then(|stem| stem.parse::<u64>().ok());
+            if let Some(index) = index {
+                let size = fs::metadata(&path)?.len();
+                segments.push(Segment { index, size });
+            }
+        }
+        segments.sort_by_key(|segment| segment.index);
+
+        let size = segments.iter().map(|segment| segment.size).sum();
+        if !segments.is_empty() {
+            info!(
+                "found spilled rows: directory={:?} segments={} size={}",
+                config.directory, segments.len(), size,
+            );
+        }
+        Ok(Spill {
+            config: config.clone(),
+            state: Mutex::new(SpillState {
+                next_index: segments.last().map_or(0, |segment| segment.index + 1),
+                segments: segments.into(),
+                size,
+                read_offset: 0,
+                writer: None,
+            }),
+        })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.state.lock().unwrap().segments.is_empty()
+    }
+
+    pub fn is_full(&self) -> bool {
+        self.config.max_size <= self.state.lock().unwrap().size
+    }
+
+    /// Append the rows. Returns `false` (and writes nothing) when there isn't
+    /// enough room left for all of them.
+    pub fn write<D>(&self, rows: &[Row<D>]) -> io::Result<bool>
+    where
+        D: serde::Serialize,
+    {
+        let mut lines = Vec::new();
+        for row in rows {
+            serde_json::to_writer(&mut lines, row)?;
+            lines.push(b'\n');
+        }
+        let lines_size = lines.len() as u64;
+
+        let mut state = self.state.lock().unwrap();
+        if self.config.max_size < state.size + lines_size {
+            return Ok(false);
+        }
+        let is_segment_full = match state.segments.back() {
+            Some(segment) => self.config.segment_size <= segment.size,
+            None => true,
+        };
+        if state.writer.is_none() || is_segment_full {
+            let index = state.next_index;
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(self.segment_path(index))?;
+            let size = file.metadata()?.len();
+            state.next_index += 1;
+            state.segments.push_back(Segment { index, size });
+            state.writer = Some(file);
+        }
+
+        let result = {
+            let writer = state.writer.as_mut().unwrap();
+            writer.write_all(&lines).and_then(|_| writer.flush())
+        };
+        if let Err(error) = result {
+            // The segment may end with a partial line, which is skipped when it
+            // is replayed. Start a new segment for the next write.
+            state.writer = None;
+            return Err(error);
+        }
+        state.segments.back_mut().unwrap().size += lines_size;
+        state.size += lines_size;
+        Ok(true)
+    }
+
+    /// Pass the spilled rows (oldest first) to `write`, until it returns an
+    /// error (the row is kept), or the spill is empty.
+    pub fn replay<D, F>(&self, mut write: F) -> io::Result<()>
+    where
+        D: serde::de::DeserializeOwned,
+        F: FnMut(Row<D>) -> Result<(), Row<D>>,
+    {
+        let mut state = self.state.lock().unwrap();
+        while let Some(segment) = state.segments.front() {
+            let path = self.segment_path(segment.index);
+            let mut reader = io::BufReader::new(fs::File::open(&path)?);
+            reader.seek(SeekFrom::Start(state.read_offset))?;
+
+            let mut line = Vec::new();
+            loop {
+                line.clear();
+                let line_size = reader.read_until(b'\n', &mut line)?;
+                if line_size == 0 { break; }
+                match serde_json::from_slice::<Row<D>>(&line) {
+                    Ok(row) => if write(row).is_err() {
+                        return Ok(());
+                    },
+                    Err(error) => warn!(
+                        "skipping invalid spilled row: file={:?} offset={} error={}",
+                        path, state.read_offset, error,
+                    ),
+                }
+                state.read_offset += line_size as u64;
+            }
+
+            // The segment has been fully replayed.
+            if state.segments.len() == 1 {
+                state.writer = None;
+            }
+            fs::remove_file(&path)?;
+            let segment = state.segments.pop_front().unwrap();
+            state.size -= segment.size;
+            state.read_offset = 0;
+        }
+        Ok(())
+    }
+
+    fn segment_path(&self, index: u64) -> PathBuf {
+        self.config.directory
+            .join(format!("{:020}.{}", index, SEGMENT_EXTENSION))
+    }
+}
+
+#[cfg(test)]
+mod test_spill {
+    use super::*;
+
+    fn make_config(name: &str, max_size: u64) -> SpillConfig {
+        SpillConfig {
+            directory: std::env::temp_dir()
+                .join(format!("ilp-relay-spill-{}-{}", name, uuid::Uuid::new_v4())),
+            segment_size: 64,
+            max_size,
+        }
+    }
+
+    fn make_rows(values: &[i32]) -> Vec<Row<i32>> {
+        values.iter().cloned().map(Row::new).collect()
+    }
+
+    fn replay_all(spill: &Spill) -> Vec<Row<i32>> {
+        let mut rows = Vec::new();
+        spill.replay(|row| {
+            rows.push(row);
+            Ok(())
+        }).unwrap();
+        rows
+    }
+
+    #[test]
+    fn test_write_and_replay() {
+        let config = make_config("replay", 10_000);
+        let spill = Spill::open(&config).unwrap();
+        assert!(spill.is_empty());
+        let rows = make_rows(&[1, 2, 3, 4]);
+        assert!(spill.write(&rows[..2]).unwrap());
+        assert!(spill.write(&rows[2..]).unwrap());
+        assert!(!spill.is_empty());
+        // The first write filled the first segment.
+        assert_eq!(spill.state.lock().unwrap().segments.len(), 2);
+
+        // Stop at the first refused row.
+        let mut replayed = Vec::new();
+        spill.replay(|row| {
+            if replayed.len() == 3 { return Err(row); }
+            replayed.push(row);
+            Ok(())
+        }).unwrap();
+        assert_eq!(replayed, rows[..3].to_vec());
+        assert_eq!(spill.state.lock().unwrap().segments.len(), 1);
+
+        assert_eq!(replay_all(&spill), rows[3..].to_vec());
+        assert!(spill.is_empty());
+        assert_eq!(spill.state.lock().unwrap().size, 0);
+        assert_eq!(fs::read_dir(&config.directory).unwrap().count(), 0);
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_reopen() {
+        let config = make_config("reopen", 10_000);
+        let rows = make_rows(&[1, 2, 3]);
+        {
+            let spill = Spill::open(&config).unwrap();
+            assert!(spill.write(&rows[..2]).unwrap());
+            assert!(spill.write(&rows[2..]).unwrap());
+        }
+        // A partially written line is skipped.
+        fs::OpenOptions::new()
+            .append(true)
+            .open(config.directory.join(format!("{:020}.ndjson", 1)))
+            .unwrap()
+            .write_all(b"{\"insertId\":")
+            .unwrap();
+
+        let spill = Spill::open(&config).unwrap();
+        assert_eq!(spill.state.lock().unwrap().next_index, 2);
+        assert!(spill.write(&make_rows(&[4])).unwrap());
+        let replayed = replay_all(&spill);
+        assert_eq!(replayed[..3], rows[..]);
+        assert_eq!(
+            replayed.iter().map(|row| row.json).collect::<Vec<_>>(),
+            vec![1, 2, 3, 4],
+        );
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+
+    #[test]
+    fn test_full() {
+        let config = make_config("full", 100);
+        let spill = Spill::open(&config).unwrap();
+        let rows = make_rows(&[1]);
+        assert!(spill.write(&rows).unwrap());
+        assert!(!spill.is_full());
+        assert!(!spill.write(&rows).unwrap());
+        assert_eq!(spill.state.lock().unwrap().segments.len(), 1);
+        fs::remove_dir_all(&config.directory).unwrap();
+    }
+}
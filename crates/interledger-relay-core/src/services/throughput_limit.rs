@@ -0,0 +1,176 @@
+use std::collections::HashMap;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::future::err;
+use futures::prelude::*;
+use serde::Deserialize;
+
+use crate::{RequestId, RequestWithFrom, Service};
+use crate::connector_info::ConnectorInfo;
+use crate::reject_reasons::{self, RejectReason};
+use super::rate_limit::TokenBucket;
+
+#[derive(Clone, Copy, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ThroughputLimitConfig {
+    /// The maximum total amount of the Prepares that may be sent per
+    /// `interval`. This is also the capacity of the bucket.
+    pub amount: u64,
+    /// The bucket refills by `amount` over this duration.
+    pub interval: time::Duration,
+}
+
+/// Limit the total amount of incoming Prepares from each account with a token
+/// bucket whose tokens are units of the account's asset. Unlike the
+/// `RateLimitService`, which counts packets, this bounds the value that a peer
+/// can send through the connector per interval. Prepares from accounts without
+/// a configured limit are passed through.
+///
+/// The amount of a Prepare that is rejected (by this connector or a later hop)
+/// is refunded, so only fulfilled Prepares count against the limit.
+#[derive(Clone, Debug)]
+pub struct ThroughputLimitService<S> {
+    connector: ConnectorInfo,
+    buckets: Arc<HashMap<Arc<String>, Mutex<TokenBucket>>>,
+    next: S,
+}
+
+impl<S> ThroughputLimitService<S> {
+    pub fn new<I>(connector: impl Into<ConnectorInfo>, limits: I, next: S) -> Self
+    where
+        I: IntoIterator<Item = (Arc<String>, ThroughputLimitConfig)>,
+    {
+        let now = time::Instant::now();
+        ThroughputLimitService {
+            connector: connector.into(),
+            buckets: Arc::new({
+                limits
+                    .into_iter()
+                    .map(|(account, config)| {
+                        let capacity = config.amount as f64;
+                        let bucket = TokenBucket::new(
+                            capacity,
+                            capacity / config.interval.as_secs_f64(),
+                            now,
+                        );
+                        (account, Mutex::new(bucket))
+                    })
+                    .collect()
+            }),
+            next,
+        }
+    }
+
+    fn make_reject(&self, reason: &RejectReason) -> ilp::Reject {
+        reason.to_reject(self.connector.address().as_addr())
+    }
+}
+
+impl<S, Req> Service<Req> for ThroughputLimitService<S>
+where
+    S: 'static + Service<Req> + Send,
+    Req: RequestWithFrom,
+{
+    type Future = Pin<Box<
+        dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static,
+    >>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let bucket = match self.buckets.get(request.from_account()) {
+            Some(bucket) => bucket,
+            None => return Box::pin(self.next.call(request)),
+        };
+
+        let amount = request.borrow().amount();
+        let is_allowed = bucket
+            .lock()
+            .unwrap()
+            .take(amount as f64, time::Instant::now());
+        if !is_allowed {
+            throttled_warn!(
+                request.from_account(),
+                "throughput limit exceeded: request_id={} from_account={} from_address={} amount={}",
+                RequestId::of(&request), request.from_account(), request.from_address(), amount,
+            );
+            return Box::pin(err(self.make_reject(&reject_reasons::THROUGHPUT_LIMITED)));
+        }
+
+        let buckets = Arc::clone(&self.buckets);
+        let account = Arc::clone(request.from_account());
+        Box::pin(self.next.call(request).inspect(move |response| {
+            if response.is_err() {
+                buckets[&account]
+                    .lock()
+                    .unwrap()
+                    .refund(amount as f64, time::Instant::now());
+            }
+        }))
+    }
+}
+
+#[cfg(test)]
+mod test_throughput_limit_service {
+    use futures::executor::block_on;
+
+    use crate::Relation;
+    use crate::testing::{ADDRESS, FULFILL, MockRequest, MockService, PREPARE, REJECT};
+    use super::*;
+
+    static CONFIG: ThroughputLimitConfig = ThroughputLimitConfig {
+        amount: 1_000,
+        interval: time::Duration::from_secs(1),
+    };
+
+    fn make_request(account: &str, amount: u64) -> MockRequest {
+        let mut prepare = PREPARE.clone();
+        prepare.set_amount(amount);
+        MockRequest::new(prepare)
+            .from_account(account, Relation::Child)
+    }
+
+    #[test]
+    fn test_service() {
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let service = ThroughputLimitService::new(
+            ADDRESS.to_address(),
+            vec![(Arc::new("alice".to_owned()), CONFIG)],
+            next.clone(),
+        );
+
+        assert!(block_on(service.clone().call(make_request("alice", 600))).is_ok());
+        let reject = block_on(service.clone().call(make_request("alice", 600)))
+            .unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::T04_INSUFFICIENT_LIQUIDITY);
+        assert_eq!(reject.triggered_by(), Some(ADDRESS));
+        // A smaller Prepare still fits in what's left.
+        assert!(block_on(service.clone().call(make_request("alice", 400))).is_ok());
+        assert_eq!(next.requests().count(), 2);
+
+        // Accounts without a limit are not limited.
+        for _ in 0..5 {
+            assert!(block_on(service.clone().call(make_request("bob", 600))).is_ok());
+        }
+    }
+
+    #[test]
+    fn test_refund_rejected() {
+        let next = MockService::new(Err(REJECT.clone()));
+        let service = ThroughputLimitService::new(
+            ADDRESS.to_address(),
+            vec![(Arc::new("alice".to_owned()), CONFIG)],
+            next.clone(),
+        );
+
+        // Each rejected Prepare's amount is refunded, so the next one fits.
+        for _ in 0..3 {
+            let reject = block_on(service.clone().call(make_request("alice", 600)))
+                .unwrap_err();
+            assert_eq!(reject, *REJECT);
+        }
+        assert_eq!(next.requests().count(), 3);
+    }
+}
@@ -0,0 +1,186 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use log::warn;
+use ring::digest::{SHA256, digest};
+
+use crate::{Request, RequestId, ResponseWithRoute, Service};
+use crate::connector_info::ConnectorInfo;
+use crate::reject_reasons;
+use crate::services::{RouteIndex, RouterService};
+
+/// Reject Fulfills whose fulfillment doesn't hash (SHA-256) to the Prepare's
+/// execution condition, so that a misbehaving peer can't pass an invalid
+/// fulfillment upstream.
+#[derive(Clone, Debug)]
+pub struct ValidateFulfillmentService<S> {
+    connector: ConnectorInfo,
+    next: S,
+}
+
+impl<S> ValidateFulfillmentService<S> {
+    pub fn new(connector: impl Into<ConnectorInfo>, next: S) -> Self {
+        ValidateFulfillmentService { connector: connector.into(), next }
+    }
+}
+
+impl<S, Req> Service<Req> for ValidateFulfillmentService<S>
+where
+    S: Service<Req> + Send + 'static,
+    Req: Request + Send + 'static,
+{
+    type Future = Pin<Box<
+        dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static,
+    >>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let condition = copy_condition(request.borrow());
+        let connector = self.connector;
+        let request_id = RequestId::of(&request);
+        Box::pin({
+            self.next.call(request).map(move |result| {
+                validate(&connector, &request_id, &condition, result)
+            })
+        })
+    }
+}
+
+impl ValidateFulfillmentService<RouterService> {
+    pub(crate) fn get_account(&self, route_index: RouteIndex) -> Arc<String> {
+        self.next.get_account(route_index)
+    }
+
+    pub(crate) fn is_catch_all(&self, route_index: RouteIndex) -> bool {
+        self.next.is_catch_all(route_index)
+    }
+
+    pub(crate) fn forward(self, prepare: ilp::Prepare, request_id: RequestId)
+        -> impl Future<Output = ResponseWithRoute>
+    {
+        let condition = copy_condition(&prepare);
+        let connector = self.connector;
+        let router = self.next.clone();
+        self.next.forward(prepare, request_id.clone())
+            .map(move |response| {
+                let packet = validate(
+                    &connector,
+                    &request_id,
+                    &condition,
+                    response.packet,
+                );
+                if let (Ok(_), Some(route), Some(amount)) =
+                    (&packet, response.route, response.amount)
+                {
+                    router.record_fulfill(route, amount);
+                }
+                ResponseWithRoute {
+                    packet,
+                    route: response.route,
+                    amount: response.amount,
+                }
+            })
+    }
+}
+
+fn copy_condition(prepare: &ilp::Prepare) -> [u8; 32] {
+    let mut condition = [0; 32];
+    condition.copy_from_slice(prepare.execution_condition());
+    condition
+}
+
+fn validate(
+    connector: &ConnectorInfo,
+    request_id: &RequestId,
+    condition: &[u8; 32],
+    result: Result<ilp::Fulfill, ilp::Reject>,
+) -> Result<ilp::Fulfill, ilp::Reject> {
+    let fulfill = result?;
+    let fulfillment = fulfill.fulfillment();
+    if digest(&SHA256, fulfillment).as_ref() == condition {
+        return Ok(fulfill);
+    }
+
+    warn!(
+        "fulfillment does not match condition: request_id={} fulfillment={:?} condition={:?}",
+        request_id, fulfillment, condition,
+    );
+    Err(reject_reasons::WRONG_CONDITION.to_reject(connector.address().as_addr()))
+}
+
+#[cfg(test)]
+mod test_validate_fulfillment_service {
+    use crate::services::{RoutingPartition, RoutingTable};
+    use crate::metrics::Metrics;
+    use crate::services::{Settlement, SettlementAccountConfig, SettlementConfig};
+    use crate::testing::{self, ADDRESS, FULFILL, ILDCP_RESPONSE, MockClient, MockHttpClient, MockService, PREPARE, REJECT, ROUTES};
+    use super::*;
+
+    fn make_service(result: Result<ilp::Fulfill, ilp::Reject>)
+        -> ValidateFulfillmentService<MockService<ilp::Prepare>>
+    {
+        ValidateFulfillmentService::new(
+            ADDRESS.to_address(),
+            MockService::new(result),
+        )
+    }
+
+    #[tokio::test]
+    async fn test_valid_fulfillment() {
+        let service = make_service(Ok(FULFILL.clone()));
+        assert_eq!(
+            service.call(PREPARE.clone()).await,
+            Ok(FULFILL.clone()),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_wrong_fulfillment() {
+        let fulfill = ilp::FulfillBuilder {
+            fulfillment: &[0; 32],
+            data: b"fulfill data",
+        }.build();
+        let service = make_service(Ok(fulfill));
+        let reject = service.call(PREPARE.clone()).await.unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F05_WRONG_CONDITION);
+        assert_eq!(reject.triggered_by(), Some(ADDRESS));
+    }
+
+    #[tokio::test]
+    async fn test_reject() {
+        let service = make_service(Err(REJECT.clone()));
+        assert_eq!(
+            service.call(PREPARE.clone()).await,
+            Err(REJECT.clone()),
+        );
+    }
+
+    #[tokio::test]
+    async fn test_forward_records_payable() {
+        let settlement = Settlement::new(SettlementConfig {
+            engine_url: testing::RECEIVER_ORIGIN.to_owned(),
+            accounts: vec![("alice".to_owned(), SettlementAccountConfig {
+                settle_threshold: 1_000_000,
+                settle_to: 0,
+                asset_scale: None,
+            })].into_iter().collect(),
+        }, ILDCP_RESPONSE.clone(), Arc::new(Metrics::default()), Arc::new({
+            MockHttpClient::new(|request| panic!("unexpected request: {:?}", request))
+        })).unwrap();
+        let router = RouterService::new(
+            ADDRESS.to_address(),
+            Arc::new(MockClient::with_response(Ok(FULFILL.clone()))),
+            RoutingTable::new(ROUTES.clone(), RoutingPartition::default()),
+            false,
+        );
+        router.set_settlement(settlement.clone());
+        let service = ValidateFulfillmentService::new(ADDRESS.to_address(), router);
+        let response = service
+            .forward(PREPARE.clone(), RequestId::generate())
+            .await;
+        assert_eq!(response.packet, Ok(FULFILL.clone()));
+        assert_eq!(settlement.balances()["alice"].payable, PREPARE.amount());
+    }
+}
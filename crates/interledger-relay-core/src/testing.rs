@@ -0,0 +1,617 @@
+//! Test helpers, mocks, and fixtures.
+
+use std::borrow::{Borrow, BorrowMut};
+use std::collections::{HashMap, VecDeque};
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, RwLock};
+use std::task::{Context, Poll, Waker};
+use std::time::{Duration, SystemTime};
+
+use bytes::Bytes;
+use futures::prelude::*;
+use lazy_static::lazy_static;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::sync::mpsc;
+
+use crate::{Relation, Request, RequestId, RequestWithDeadline, RequestWithFrom, RequestWithIdempotencyKey, RequestWithPeerName, Service};
+use crate::services::{NextHop, StaticRoute};
+use crate::transport::{AuthToken, ConnectFuture, Connection, GoogleClientFuture, Headers, HttpClient, HttpError, HttpRequest, HttpResponse, HttpVersion, NextHopClient, NextHopError, NextHopRequest, PeerResponse, ProbeRequest, RetryPolicy, SinkTransport};
+
+const EXPIRES_IN: Duration = Duration::from_secs(20);
+
+pub static ADDRESS: ilp::Addr<'static> = unsafe {
+    ilp::Addr::new_unchecked(b"test.relay")
+};
+
+/// The origin of the `ROUTES`' next hops.
+pub static RECEIVER_ORIGIN: &str = "http://127.0.0.1:3001";
+
+lazy_static! {
+    pub static ref PREPARE: ilp::Prepare = ilp::PrepareBuilder {
+        amount: 123,
+        expires_at: SystemTime::now() + EXPIRES_IN,
+        execution_condition: b"\
+            \x22\xbd\x80\xd3\x15\xf6\x10\x3c\xb7\x42\xac\xac\x4a\xa2\x32\x01\
+            \xf2\x70\xad\x74\x54\x74\x70\xd7\xac\x44\x4c\x5e\xf7\xe8\xb8\x85\
+        ",
+        destination: ilp::Addr::new(b"test.alice.1234"),
+        data: b"prepare data",
+    }.build();
+
+    pub static ref PREPARE_MULTILATERAL: ilp::Prepare = ilp::PrepareBuilder {
+        amount: 123,
+        expires_at: SystemTime::now() + EXPIRES_IN,
+        execution_condition: b"\
+            \x22\xbd\x80\xd3\x15\xf6\x10\x3c\xb7\x42\xac\xac\x4a\xa2\x32\x01\
+            \xf2\x70\xad\x74\x54\x74\x70\xd7\xac\x44\x4c\x5e\xf7\xe8\xb8\x85\
+        ",
+        destination: ilp::Addr::new(b"test.relay.1234.5678"),
+        data: b"prepare data",
+    }.build();
+
+    pub static ref FULFILL: ilp::Fulfill = ilp::FulfillBuilder {
+        fulfillment: b"\
+            \x11\x7b\x43\x4f\x1a\x54\xe9\x04\x4f\x4f\x54\x92\x3b\x2c\xff\x9e\
+            \x4a\x6d\x42\x0a\xe2\x81\xd5\x02\x5d\x7b\xb0\x40\xc4\xb4\xc0\x4a\
+        ",
+        data: b"fulfill data",
+    }.build();
+
+    /// The connector's config, for the services that need its asset too.
+    pub static ref ILDCP_RESPONSE: ilp::ildcp::Response = ilp::ildcp::ResponseBuilder {
+        client_address: ADDRESS,
+        asset_scale: 9,
+        asset_code: b"XRP",
+    }.build();
+
+    pub static ref REJECT: ilp::Reject = ilp::RejectBuilder {
+        code: ilp::ErrorCode::F99_APPLICATION_ERROR,
+        message: b"Some error",
+        triggered_by: Some(ilp::Addr::new(b"example.connector")),
+        data: b"reject data",
+    }.build();
+
+    pub static ref ROUTES: Vec<StaticRoute> = vec![
+        StaticRoute {
+            target_prefix: Bytes::from("test.alice."),
+            account: Arc::new("alice".to_owned()),
+            next_hop: NextHop::Bilateral {
+                endpoint: format!("{}/alice", RECEIVER_ORIGIN).parse().unwrap(),
+                auth: Some(AuthToken::new("alice_auth")),
+                headers: Headers::default(),
+            },
+            failover: None,
+            partition: 1.0,
+            max_packet_amount: None,
+            concurrency: None,
+            retry: Arc::new(RetryPolicy::default()),
+            http_version: HttpVersion::Adaptive,
+            hedging: None,
+            response_timeout: None,
+            maintenance: None,
+            health_check: None,
+            peer_pool: None,
+            rewrite_prefix: None,
+            exchange: None,
+        },
+        StaticRoute {
+            target_prefix: Bytes::from("test.relay."),
+            account: Arc::new("bob".to_owned()),
+            next_hop: NextHop::Multilateral {
+                endpoint_prefix: Bytes::from(format!("{}/bob/", RECEIVER_ORIGIN)),
+                endpoint_suffix: Bytes::from("/ilp"),
+                auth: Some(AuthToken::new("bob_auth")),
+                headers: Headers::default(),
+            },
+            failover: None,
+            partition: 1.0,
+            max_packet_amount: None,
+            concurrency: None,
+            retry: Arc::new(RetryPolicy::default()),
+            http_version: HttpVersion::Adaptive,
+            hedging: None,
+            response_timeout: None,
+            maintenance: None,
+            health_check: None,
+            peer_pool: None,
+            rewrite_prefix: None,
+            exchange: None,
+        },
+        StaticRoute {
+            target_prefix: Bytes::from(""),
+            account: Arc::new("default".to_owned()),
+            next_hop: NextHop::Bilateral {
+                endpoint: format!("{}/default", RECEIVER_ORIGIN).parse().unwrap(),
+                auth: Some(AuthToken::new("default_auth")),
+                headers: Headers::default(),
+            },
+            failover: None,
+            partition: 1.0,
+            max_packet_amount: None,
+            concurrency: None,
+            retry: Arc::new(RetryPolicy::default()),
+            http_version: HttpVersion::Adaptive,
+            hedging: None,
+            response_timeout: None,
+            maintenance: None,
+            health_check: None,
+            peer_pool: None,
+            rewrite_prefix: None,
+            exchange: None,
+        },
+    ];
+}
+
+pub type IlpResult = Result<ilp::Fulfill, ilp::Reject>;
+
+#[derive(Clone, Debug)]
+pub struct MockService<Req> {
+    requests: Arc<RwLock<Vec<Req>>>,
+    response: Arc<IlpResult>,
+}
+
+impl<Req> MockService<Req>
+where
+    Req: Request + Clone,
+{
+    pub fn new(response: IlpResult) -> Self {
+        MockService {
+            requests: Default::default(),
+            response: Arc::new(response),
+        }
+    }
+
+    pub fn requests(&self) -> impl Iterator<Item = Req> {
+        self.requests
+            .read()
+            .unwrap()
+            .clone()
+            .into_iter()
+    }
+
+    pub fn prepares(&self) -> impl Iterator<Item = ilp::Prepare> {
+        self.requests()
+            .map(|request| request.borrow().clone())
+    }
+}
+
+impl<Req> Service<Req> for MockService<Req>
+where
+    Req: Request + Clone,
+{
+    type Future = future::Ready<Result<ilp::Fulfill, ilp::Reject>>;
+
+    fn call(self, request: Req) -> Self::Future {
+        self.requests
+            .write()
+            .unwrap()
+            .push(request);
+        future::ready(self.response.as_ref().clone())
+    }
+}
+
+/// Waits before responding.
+#[derive(Clone, Debug)]
+pub struct DelayService<S> {
+    delay: Duration,
+    next: S,
+}
+
+impl<S> DelayService<S> {
+    pub fn new(delay: Duration, next: S) -> Self {
+        DelayService { delay, next }
+    }
+}
+
+impl<S, Req> Service<Req> for DelayService<S>
+where
+    S: Service<Req> + Send + 'static,
+    Req: Request + Send + 'static,
+{
+    type Future = Pin<Box<dyn Future<
+        Output = Result<ilp::Fulfill, ilp::Reject>,
+    > + 'static + Send>>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let future = tokio::time::delay_for(self.delay)
+            .then(move |_| self.next.call(request));
+        Box::pin(future)
+    }
+}
+
+/// Dummy service to verify that no prepares arrive.
+#[derive(Clone, Debug)]
+pub struct PanicService;
+
+impl<Req: Request> Service<Req> for PanicService {
+    type Future = Pin<Box<dyn Future<
+        Output = Result<ilp::Fulfill, ilp::Reject>,
+    > + Send + 'static>>;
+
+    fn call(self, request: Req) -> Self::Future {
+        panic!("PanicService received prepare={:?}", request.borrow());
+    }
+}
+
+/// A request from a peer, with the fields that the transport would take from
+/// its headers.
+#[derive(Clone, Debug, PartialEq)]
+pub struct MockRequest {
+    pub prepare: ilp::Prepare,
+    pub from_account: Arc<String>,
+    pub from_relation: Relation,
+    pub from_address: ilp::Address,
+    pub peer_name: Option<Bytes>,
+    pub idempotency_key: Option<Bytes>,
+    pub deadline: Option<SystemTime>,
+    pub request_id: RequestId,
+}
+
+impl MockRequest {
+    /// A request from the child `alice` (`test.relay.alice`).
+    pub fn new(prepare: ilp::Prepare) -> Self {
+        MockRequest {
+            prepare,
+            from_account: Arc::new("alice".to_owned()),
+            from_relation: Relation::Child,
+            from_address: ilp::Address::new(b"test.relay.alice"),
+            peer_name: None,
+            idempotency_key: None,
+            deadline: None,
+            request_id: RequestId::generate(),
+        }
+    }
+
+    pub fn from_account(mut self, account: &str, relation: Relation) -> Self {
+        self.from_account = Arc::new(account.to_owned());
+        self.from_relation = relation;
+        self
+    }
+}
+
+impl Request for MockRequest {
+    fn request_id(&self) -> Option<&RequestId> {
+        Some(&self.request_id)
+    }
+}
+
+impl From<MockRequest> for ilp::Prepare {
+    fn from(request: MockRequest) -> Self {
+        request.prepare
+    }
+}
+
+impl Borrow<ilp::Prepare> for MockRequest {
+    fn borrow(&self) -> &ilp::Prepare {
+        &self.prepare
+    }
+}
+
+impl BorrowMut<ilp::Prepare> for MockRequest {
+    fn borrow_mut(&mut self) -> &mut ilp::Prepare {
+        &mut self.prepare
+    }
+}
+
+impl RequestWithPeerName for MockRequest {
+    fn peer_name(&self) -> Option<&[u8]> {
+        self.peer_name.as_deref()
+    }
+}
+
+impl RequestWithIdempotencyKey for MockRequest {
+    fn idempotency_key(&self) -> Option<&[u8]> {
+        self.idempotency_key.as_deref()
+    }
+}
+
+impl RequestWithDeadline for MockRequest {
+    fn deadline(&self) -> Option<SystemTime> {
+        self.deadline
+    }
+}
+
+impl RequestWithFrom for MockRequest {
+    fn from_account(&self) -> &Arc<String> {
+        &self.from_account
+    }
+
+    fn from_relation(&self) -> Relation {
+        self.from_relation
+    }
+
+    fn from_address(&self) -> ilp::Addr<'_> {
+        self.from_address.as_addr()
+    }
+}
+
+type Respond<Req, Res> = Arc<dyn Fn(&Req) -> Option<Res> + Send + Sync>;
+type HttpRespond = Arc<
+    dyn Fn(&HttpRequest) -> Result<HttpResponse, HttpError> + Send + Sync,
+>;
+
+/// A `NextHopClient` that records its requests, and answers them with
+/// `respond`. When `respond` returns `None`, the request never completes (like
+/// a next hop that doesn't respond).
+#[derive(Clone)]
+pub struct MockClient {
+    respond: Respond<NextHopRequest, Result<PeerResponse, NextHopError>>,
+    probe: Respond<ProbeRequest, Result<(), NextHopError>>,
+    requests: Arc<Mutex<Vec<NextHopRequest>>>,
+    probes: Arc<Mutex<Vec<ProbeRequest>>>,
+}
+
+impl MockClient {
+    /// Every probe succeeds.
+    pub fn new<F>(respond: F) -> Self
+    where
+        F: 'static + Fn(&NextHopRequest) -> Option<Result<PeerResponse, NextHopError>>
+            + Send + Sync,
+    {
+        MockClient {
+            respond: Arc::new(respond),
+            probe: Arc::new(|_request| Some(Ok(()))),
+            requests: Arc::default(),
+            probes: Arc::default(),
+        }
+    }
+
+    /// Every request gets the same response.
+    pub fn with_response(response: PeerResponse) -> Self {
+        MockClient::new(move |_request| Some(Ok(response.clone())))
+    }
+
+    pub fn with_probe<F>(mut self, probe: F) -> Self
+    where
+        F: 'static + Fn(&ProbeRequest) -> Option<Result<(), NextHopError>>
+            + Send + Sync,
+    {
+        self.probe = Arc::new(probe);
+        self
+    }
+
+    pub fn requests(&self) -> Vec<NextHopRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+
+    pub fn probes(&self) -> Vec<ProbeRequest> {
+        self.probes.lock().unwrap().clone()
+    }
+}
+
+impl NextHopClient for MockClient {
+    fn send(&self, request: NextHopRequest) -> Pin<Box<
+        dyn Future<Output = Result<PeerResponse, NextHopError>> + Send + 'static,
+    >> {
+        let response = (self.respond)(&request);
+        self.requests.lock().unwrap().push(request);
+        match response {
+            Some(response) => Box::pin(future::ready(response)),
+            None => Box::pin(future::pending()),
+        }
+    }
+
+    fn probe(&self, request: ProbeRequest) -> Pin<Box<
+        dyn Future<Output = Result<(), NextHopError>> + Send + 'static,
+    >> {
+        let response = (self.probe)(&request);
+        self.probes.lock().unwrap().push(request);
+        match response {
+            Some(response) => Box::pin(future::ready(response)),
+            None => Box::pin(future::pending()),
+        }
+    }
+}
+
+impl std::fmt::Debug for MockClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MockClient")
+            .field("requests", &self.requests)
+            .finish()
+    }
+}
+
+/// An `HttpClient` that records its requests, and answers them with
+/// `respond`.
+#[derive(Clone)]
+pub struct MockHttpClient {
+    respond: HttpRespond,
+    requests: Arc<Mutex<Vec<HttpRequest>>>,
+}
+
+impl MockHttpClient {
+    pub fn new<F>(respond: F) -> Self
+    where
+        F: 'static + Fn(&HttpRequest) -> Result<HttpResponse, HttpError>
+            + Send + Sync,
+    {
+        MockHttpClient {
+            respond: Arc::new(respond),
+            requests: Arc::default(),
+        }
+    }
+
+    /// Every request gets a response with the `status` and `body`.
+    pub fn with_response(status: u16, body: impl Into<Bytes>) -> Self {
+        let body = body.into();
+        MockHttpClient::new(move |_request| Ok(HttpResponse {
+            status,
+            body: body.clone(),
+        }))
+    }
+
+    pub fn requests(&self) -> Vec<HttpRequest> {
+        self.requests.lock().unwrap().clone()
+    }
+}
+
+impl HttpClient for MockHttpClient {
+    fn request(&self, request: HttpRequest) -> Pin<Box<
+        dyn Future<Output = Result<HttpResponse, HttpError>> + Send + 'static,
+    >> {
+        let response = (self.respond)(&request).and_then(|response| {
+            if response.body.len() > request.max_response_size {
+                Err(HttpError::ResponseTooLarge)
+            } else {
+                Ok(response)
+            }
+        });
+        self.requests.lock().unwrap().push(request);
+        Box::pin(future::ready(response))
+    }
+}
+
+impl std::fmt::Debug for MockHttpClient {
+    fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+        f.debug_struct("MockHttpClient")
+            .field("requests", &self.requests)
+            .finish()
+    }
+}
+
+/// A `SinkTransport` whose Google client is a `MockHttpClient`, and whose
+/// connections are in-memory `Pipe`s to the `listen`ers.
+#[derive(Clone, Debug)]
+pub struct MockSinkTransport {
+    google_client: MockHttpClient,
+    listeners: Arc<Mutex<HashMap<String, mpsc::UnboundedSender<Pipe>>>>,
+}
+
+impl MockSinkTransport {
+    pub fn new(google_client: MockHttpClient) -> Self {
+        MockSinkTransport {
+            google_client,
+            listeners: Arc::default(),
+        }
+    }
+
+    /// Accept the connections to `address`. Connections to other addresses
+    /// are refused.
+    pub fn listen(&self, address: &str) -> mpsc::UnboundedReceiver<Pipe> {
+        let (sender, receiver) = mpsc::unbounded_channel();
+        self.listeners.lock().unwrap().insert(address.to_owned(), sender);
+        receiver
+    }
+}
+
+impl Default for MockSinkTransport {
+    fn default() -> Self {
+        MockSinkTransport::new(MockHttpClient::new(|request| {
+            panic!("unexpected request: {:?}", request)
+        }))
+    }
+}
+
+impl SinkTransport for MockSinkTransport {
+    fn google_client(
+        &self,
+        _service_account_key_file: Option<PathBuf>,
+        _scopes: &'static [&'static str],
+    ) -> GoogleClientFuture {
+        let client: Arc<dyn HttpClient> = Arc::new(self.google_client.clone());
+        Box::pin(future::ok(client))
+    }
+
+    fn connect(&self, address: &str) -> ConnectFuture {
+        let listeners = self.listeners.lock().unwrap();
+        let result = match listeners.get(address) {
+            Some(listener) => {
+                let (client, server) = pipe();
+                listener
+                    .send(server)
+                    .map(|()| Box::new(client) as Box<dyn Connection>)
+                    .map_err(|_| io::ErrorKind::ConnectionRefused.into())
+            },
+            None => Err(io::ErrorKind::ConnectionRefused.into()),
+        };
+        Box::pin(future::ready(result))
+    }
+}
+
+/// One end of an in-memory byte stream. Dropping (or shutting down) an end
+/// closes the stream in both directions.
+#[derive(Debug)]
+pub struct Pipe {
+    read: Arc<Mutex<PipeBuffer>>,
+    write: Arc<Mutex<PipeBuffer>>,
+}
+
+#[derive(Debug, Default)]
+struct PipeBuffer {
+    data: VecDeque<u8>,
+    is_closed: bool,
+    reader: Option<Waker>,
+}
+
+pub fn pipe() -> (Pipe, Pipe) {
+    let a = Arc::new(Mutex::new(PipeBuffer::default()));
+    let b = Arc::new(Mutex::new(PipeBuffer::default()));
+    (
+        Pipe { read: Arc::clone(&a), write: Arc::clone(&b) },
+        Pipe { read: b, write: a },
+    )
+}
+
+impl PipeBuffer {
+    fn close(&mut self) {
+        self.is_closed = true;
+        if let Some(reader) = self.reader.take() {
+            reader.wake();
+        }
+    }
+}
+
+impl AsyncRead for Pipe {
+    fn poll_read(self: Pin<&mut Self>, cx: &mut Context, buf: &mut [u8])
+        -> Poll<io::Result<usize>>
+    {
+        let mut buffer = self.read.lock().unwrap();
+        if buffer.data.is_empty() && !buffer.is_closed {
+            buffer.reader = Some(cx.waker().clone());
+            return Poll::Pending;
+        }
+        let size = buf.len().min(buffer.data.len());
+        for (byte, data) in buf.iter_mut().zip(buffer.data.drain(..size)) {
+            *byte = data;
+        }
+        Poll::Ready(Ok(size))
+    }
+}
+
+impl AsyncWrite for Pipe {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context, buf: &[u8])
+        -> Poll<io::Result<usize>>
+    {
+        let mut buffer = self.write.lock().unwrap();
+        if buffer.is_closed {
+            return Poll::Ready(Err(io::ErrorKind::BrokenPipe.into()));
+        }
+        buffer.data.extend(buf);
+        if let Some(reader) = buffer.reader.take() {
+            reader.wake();
+        }
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context)
+        -> Poll<io::Result<()>>
+    {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context)
+        -> Poll<io::Result<()>>
+    {
+        self.write.lock().unwrap().close();
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl Drop for Pipe {
+    fn drop(&mut self) {
+        self.read.lock().unwrap().close();
+        self.write.lock().unwrap().close();
+    }
+}
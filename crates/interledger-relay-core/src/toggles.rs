@@ -0,0 +1,92 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, Ordering};
+
+use serde::Serialize;
+
+/// A flag to enable or disable a service at runtime (through the admin API),
+/// without a restart. Clones share the flag.
+#[derive(Clone, Debug)]
+pub struct Toggle(Arc<AtomicBool>);
+
+/// The services that can be toggled at runtime.
+#[derive(Clone, Debug, Serialize)]
+pub struct ServiceToggles {
+    /// When disabled, echo requests are routed like any other Prepare.
+    pub echo: Toggle,
+    /// When disabled, ILDCP requests are routed like any other Prepare.
+    pub ildcp: Toggle,
+    /// When disabled, nothing is logged by the `DebugService`.
+    pub debug: Toggle,
+    /// When enabled, routable packets are rejected instead of forwarded.
+    pub simulation: Toggle,
+    /// When enabled, the telemetry shadow sink is the authoritative sink.
+    pub telemetry_cutover: Toggle,
+}
+
+impl Toggle {
+    pub fn new(enabled: bool) -> Self {
+        Toggle(Arc::new(AtomicBool::new(enabled)))
+    }
+
+    #[inline]
+    pub fn is_enabled(&self) -> bool {
+        self.0.load(Ordering::Relaxed)
+    }
+
+    pub fn set(&self, enabled: bool) {
+        self.0.store(enabled, Ordering::Relaxed);
+    }
+}
+
+impl Serialize for Toggle {
+    fn serialize<S>(&self, serializer: S) -> Result<S::Ok, S::Error>
+    where
+        S: serde::Serializer,
+    {
+        serializer.serialize_bool(self.is_enabled())
+    }
+}
+
+impl ServiceToggles {
+    pub fn get(&self, name: &str) -> Option<&Toggle> {
+        match name {
+            "echo" => Some(&self.echo),
+            "ildcp" => Some(&self.ildcp),
+            "debug" => Some(&self.debug),
+            "simulation" => Some(&self.simulation),
+            "telemetry_cutover" => Some(&self.telemetry_cutover),
+            _ => None,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_toggles {
+    use super::*;
+
+    #[test]
+    fn test_toggle() {
+        let toggle = Toggle::new(true);
+        let clone = toggle.clone();
+        assert!(clone.is_enabled());
+        toggle.set(false);
+        assert!(!clone.is_enabled());
+    }
+
+    #[test]
+    fn test_service_toggles() {
+        let toggles = ServiceToggles {
+            echo: Toggle::new(false),
+            ildcp: Toggle::new(true),
+            debug: Toggle::new(true),
+            simulation: Toggle::new(false),
+            telemetry_cutover: Toggle::new(false),
+        };
+        toggles.get("debug").unwrap().set(false);
+        assert!(toggles.get("unknown").is_none());
+        assert_eq!(
+            serde_json::to_string(&toggles).unwrap(),
+            r#"{"echo":false,"ildcp":true,"debug":false,"simulation":false,"telemetry_cutover":false}"#,
+        );
+    }
+}
@@ -0,0 +1,708 @@
+//! What the services need from the network: a client that sends Prepares to
+//! the routes' next hops, plain HTTP requests (for exchange rates and the
+//! settlement engine), and the telemetry sinks' connections.
+//!
+//! `interledger-relay` implements these with hyper; the tests use the mocks
+//! in `testing`.
+
+use std::borrow::Borrow;
+use std::collections::BTreeMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::str;
+use std::sync::Arc;
+use std::time;
+
+use bytes::Bytes;
+use futures::prelude::*;
+use serde::de::{self, Deserialize, Deserializer};
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{RequestId, SetupError};
+use crate::reject_reasons::RejectReason;
+
+/// The next hop's response to a Prepare: its Fulfill or Reject.
+pub type PeerResponse = Result<ilp::Fulfill, ilp::Reject>;
+
+/// Sends the router's Prepares to the routes' next hops.
+pub trait NextHopClient: fmt::Debug + Send + Sync {
+    /// Failures (e.g. connection errors) are returned as a `NextHopError`, so
+    /// that the caller can tell them apart from the next hop's Rejects.
+    fn send(&self, request: NextHopRequest) -> Pin<Box<
+        dyn Future<Output = Result<PeerResponse, NextHopError>> + Send + 'static,
+    >>;
+
+    /// Check that the next hop is up, for the route's `health_check`.
+    fn probe(&self, request: ProbeRequest) -> Pin<Box<
+        dyn Future<Output = Result<(), NextHopError>> + Send + 'static,
+    >>;
+}
+
+#[derive(Clone, Debug)]
+pub struct NextHopRequest {
+    pub endpoint: Endpoint,
+    /// Send the Prepare over a BTP connection to the (`ws://` or `wss://`)
+    /// endpoint, instead of in an HTTP request.
+    pub is_btp: bool,
+    pub auth: Option<AuthToken>,
+    /// Extra headers, from the route's next hop.
+    pub headers: Option<Headers>,
+    pub retry: Arc<RetryPolicy>,
+    pub http_version: HttpVersion,
+    /// Give up on the request (including any retries) after this long, and
+    /// fail with `PEER_TIMED_OUT`.
+    pub response_timeout: Option<time::Duration>,
+    /// Sent to the next hop (as the `X-Request-Id` header), and included in
+    /// the logs.
+    pub request_id: RequestId,
+    pub prepare: ilp::Prepare,
+}
+
+#[derive(Clone, Debug)]
+pub struct ProbeRequest {
+    pub endpoint: Endpoint,
+    pub auth: Option<AuthToken>,
+    pub headers: Option<Headers>,
+    pub http_version: HttpVersion,
+}
+
+/// Why a request failed to get a Fulfill or Reject from the next hop.
+///
+/// Callers that need to tell failures apart (e.g. failover) use this, and
+/// convert it to a Reject (`to_reject`) at the edge.
+#[derive(Debug)]
+pub struct NextHopError {
+    reason: &'static RejectReason,
+    is_peer_unreachable: bool,
+    inner: Box<dyn error::Error + Send + Sync>,
+}
+
+impl NextHopError {
+    pub fn new(
+        reason: &'static RejectReason,
+        is_peer_unreachable: bool,
+        inner: impl Into<Box<dyn error::Error + Send + Sync>>,
+    ) -> Self {
+        NextHopError {
+            reason,
+            is_peer_unreachable,
+            inner: inner.into(),
+        }
+    }
+
+    /// Whether the next hop appears to be down (e.g. a connection error or a
+    /// 5xx), as opposed to e.g. a misconfigured route.
+    pub fn is_peer_unreachable(&self) -> bool {
+        self.is_peer_unreachable
+    }
+
+    pub fn reject_reason(&self) -> &'static RejectReason {
+        self.reason
+    }
+
+    pub fn to_reject(&self, connector_address: ilp::Addr) -> ilp::Reject {
+        self.reason.to_reject(connector_address)
+    }
+}
+
+impl error::Error for NextHopError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        Some(self.inner.as_ref())
+    }
+}
+
+impl fmt::Display for NextHopError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(&self.inner, f)
+    }
+}
+
+/// Sends plain HTTP requests, e.g. to poll exchange rates, or to talk to the
+/// settlement engine.
+pub trait HttpClient: fmt::Debug + Send + Sync {
+    fn request(&self, request: HttpRequest) -> Pin<Box<
+        dyn Future<Output = Result<HttpResponse, HttpError>> + Send + 'static,
+    >>;
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpRequest {
+    pub method: HttpMethod,
+    pub uri: String,
+    pub headers: Vec<(&'static str, String)>,
+    pub body: Bytes,
+    /// Larger response bodies fail with `HttpError::ResponseTooLarge`.
+    pub max_response_size: usize,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum HttpMethod {
+    Get,
+    Post,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct HttpResponse {
+    pub status: u16,
+    pub body: Bytes,
+}
+
+#[derive(Debug)]
+pub enum HttpError {
+    /// The response body exceeded the request's `max_response_size`.
+    ResponseTooLarge,
+    /// The request failed, e.g. with a connection error.
+    Other(Box<dyn error::Error + Send + Sync>),
+}
+
+impl HttpRequest {
+    pub fn get(uri: impl Into<String>, max_response_size: usize) -> Self {
+        HttpRequest {
+            method: HttpMethod::Get,
+            uri: uri.into(),
+            headers: Vec::new(),
+            body: Bytes::new(),
+            max_response_size,
+        }
+    }
+
+    pub fn post(
+        uri: impl Into<String>,
+        body: impl Into<Bytes>,
+        max_response_size: usize,
+    ) -> Self {
+        HttpRequest {
+            method: HttpMethod::Post,
+            uri: uri.into(),
+            headers: Vec::new(),
+            body: body.into(),
+            max_response_size,
+        }
+    }
+
+    pub fn header(mut self, name: &'static str, value: impl Into<String>) -> Self {
+        self.headers.push((name, value.into()));
+        self
+    }
+}
+
+impl HttpResponse {
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+}
+
+impl HttpError {
+    pub fn other(inner: impl Into<Box<dyn error::Error + Send + Sync>>) -> Self {
+        HttpError::Other(inner.into())
+    }
+}
+
+impl error::Error for HttpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            HttpError::ResponseTooLarge => None,
+            HttpError::Other(inner) => Some(inner.as_ref()),
+        }
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HttpError::ResponseTooLarge => f.write_str("response too large"),
+            HttpError::Other(inner) => fmt::Display::fmt(inner, f),
+        }
+    }
+}
+
+pub type GoogleClientFuture = Pin<Box<
+    dyn Future<Output = Result<Arc<dyn HttpClient>, SetupError>> + Send + 'static,
+>>;
+
+pub type ConnectFuture = Pin<Box<
+    dyn Future<Output = io::Result<Box<dyn Connection>>> + Send + 'static,
+>>;
+
+/// Opens the telemetry sinks' connections.
+pub trait SinkTransport: fmt::Debug + Send + Sync {
+    /// An `HttpClient` for the Google Cloud APIs, which authorizes its
+    /// requests with the service account key (if any).
+    fn google_client(
+        &self,
+        service_account_key_file: Option<PathBuf>,
+        scopes: &'static [&'static str],
+    ) -> GoogleClientFuture;
+
+    /// Connect to a Kafka broker (`host:port`).
+    fn connect(&self, address: &str) -> ConnectFuture;
+}
+
+/// A byte stream, e.g. a TCP connection.
+pub trait Connection: AsyncRead + AsyncWrite + fmt::Debug + Send + Unpin {}
+
+impl<T> Connection for T
+where
+    T: AsyncRead + AsyncWrite + fmt::Debug + Send + Unpin,
+{}
+
+/// The HTTP version of outgoing requests to a route's next hop.
+#[derive(Clone, Copy, Debug, PartialEq, serde::Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HttpVersion {
+    /// HTTP/1.1, or HTTP/2 if the connection negotiates it.
+    Adaptive,
+    /// Never use HTTP/2.
+    Http1Only,
+    /// HTTP/2 with prior knowledge (without an upgrade or ALPN), e.g. for
+    /// `h2c` peers.
+    Http2Only,
+}
+
+/// When and how often to retry a failed outgoing request.
+///
+/// The default policy retries once, immediately, when the first attempt
+/// failed with a 502. The 502 is probably caused by the hidden request/
+/// connection limit described in <https://github.com/interledgerjs/ilp-plugin-http/pull/3>.
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct RetryPolicy {
+    /// The maximum number of attempts, including the first one.
+    pub max_attempts: u32,
+    /// The delay before the first retry. The delay doubles after every retry.
+    // <https://docs.serde.rs/serde/de/trait.Deserialize.html#impl-Deserialize%3C%27de%3E-for-Duration>
+    pub backoff_base: time::Duration,
+    /// The fraction (between `0.0` and `1.0`) of each delay that is randomized.
+    pub jitter: f64,
+    /// The HTTP response statuses that are retried.
+    pub status_codes: Vec<u16>,
+    /// Whether to retry when the connection fails or is aborted.
+    pub connection_errors: bool,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        RetryPolicy {
+            max_attempts: 2,
+            backoff_base: time::Duration::from_secs(0),
+            jitter: 0.0,
+            // Bad Gateway.
+            status_codes: vec![502],
+            connection_errors: false,
+        }
+    }
+}
+
+impl RetryPolicy {
+    pub fn is_retryable_status(&self, status: u16) -> bool {
+        self.status_codes.contains(&status)
+    }
+
+    /// The delay after the `attempt`th attempt. `random` is in `[0.0, 1.0)`.
+    pub fn backoff(&self, attempt: u32, random: f64) -> time::Duration {
+        let factor = 1_u32
+            .checked_shl(attempt.saturating_sub(1))
+            .unwrap_or(u32::MAX);
+        let delay = self.backoff_base
+            .checked_mul(factor)
+            .unwrap_or_else(|| time::Duration::from_secs(u64::MAX));
+        // This also maps `NaN` to `0.0`.
+        let jitter = if self.jitter > 1.0 {
+            1.0
+        } else if self.jitter > 0.0 {
+            self.jitter
+        } else {
+            0.0
+        };
+        delay.mul_f64(1.0 - jitter * random)
+    }
+}
+
+/// An absolute URI, e.g. `https://example.com/ilp` or `wss://example.com/btp`.
+///
+/// It must have a scheme and a host, and only the characters that RFC 3986
+/// allows, so that the transport can always send a request to it.
+#[derive(Clone, Eq, Hash, PartialEq)]
+pub struct Endpoint(Bytes);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidEndpoint(());
+
+impl Endpoint {
+    pub fn try_from(bytes: Bytes) -> Result<Self, InvalidEndpoint> {
+        let uri = str::from_utf8(&bytes).map_err(|_| InvalidEndpoint(()))?;
+        let scheme_end = uri.find("://").ok_or(InvalidEndpoint(()))?;
+        let scheme = &uri[..scheme_end];
+        let is_valid_scheme = matches!(
+            scheme.bytes().next(),
+            Some(byte) if byte.is_ascii_alphabetic()
+        )
+            && scheme.bytes().all(|byte| {
+                byte.is_ascii_alphanumeric() || b"+-.".contains(&byte)
+            });
+        let rest = &uri[scheme_end + 3..];
+        let authority = &rest[..rest.find(&['/', '?', '#'][..]).unwrap_or(rest.len())];
+        let host = match authority.rfind(':') {
+            // An IPv6 host (e.g. `[::1]`) has colons, but no port.
+            Some(colon) if !authority[colon..].contains(']') => {
+                let port = &authority[colon + 1..];
+                if !port.bytes().all(|byte| byte.is_ascii_digit()) {
+                    return Err(InvalidEndpoint(()));
+                }
+                &authority[..colon]
+            },
+            _ => authority,
+        };
+        let is_valid = is_valid_scheme
+            && !host.is_empty()
+            && uri.bytes().all(is_uri_char);
+        if is_valid {
+            Ok(Endpoint(bytes))
+        } else {
+            Err(InvalidEndpoint(()))
+        }
+    }
+
+    pub fn as_str(&self) -> &str {
+        // `try_from` checked that it is ASCII.
+        str::from_utf8(&self.0).expect("invalid endpoint")
+    }
+
+    /// E.g. `https`.
+    pub fn scheme(&self) -> &str {
+        let uri = self.as_str();
+        &uri[..uri.find("://").expect("invalid endpoint")]
+    }
+
+    /// E.g. `example.com:8443`.
+    pub fn authority(&self) -> &str {
+        let rest = &self.as_str()[self.scheme().len() + 3..];
+        &rest[..rest.find(&['/', '?', '#'][..]).unwrap_or(rest.len())]
+    }
+}
+
+/// The unreserved, reserved, and percent-encoding characters of RFC 3986.
+fn is_uri_char(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || b"-._~:/?#[]@!$&'()*+,;=%".contains(&byte)
+}
+
+impl str::FromStr for Endpoint {
+    type Err = InvalidEndpoint;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        Endpoint::try_from(Bytes::copy_from_slice(uri.as_bytes()))
+    }
+}
+
+impl fmt::Debug for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(self.as_str(), f)
+    }
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl<'de> Deserialize<'de> for Endpoint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        <&str>::deserialize(deserializer)?
+            .parse::<Endpoint>()
+            .map_err(de::Error::custom)
+    }
+}
+
+impl error::Error for InvalidEndpoint {}
+
+impl fmt::Display for InvalidEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid endpoint URI")
+    }
+}
+
+/// `AuthToken`s must be valid HTTP header values.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct AuthToken(Bytes);
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct InvalidHeaderValue(());
+
+impl AuthToken {
+    /// # Panics
+    ///
+    /// Panics if the string is not a valid auth token.
+    #[cfg(any(test, feature = "testing"))]
+    pub fn new(string: &'static str) -> Self {
+        AuthToken::try_from(Bytes::from(string))
+            .expect("invalid auth token")
+    }
+
+    pub fn try_from(bytes: Bytes) -> Result<Self, InvalidHeaderValue> {
+        // Verify that the `AuthToken` can be used an an HTTP header value.
+        check_header_value(&bytes)?;
+        Ok(AuthToken(bytes))
+    }
+
+    pub fn as_bytes(&self) -> Bytes {
+        self.0.clone()
+    }
+}
+
+/// The same bytes that `http::HeaderValue` accepts: visible ASCII, spaces,
+/// tabs, and `obs-text`.
+fn check_header_value(value: &[u8]) -> Result<(), InvalidHeaderValue> {
+    let is_valid = value
+        .iter()
+        .all(|&byte| (byte >= 32 && byte != 127) || byte == b'\t');
+    if is_valid {
+        Ok(())
+    } else {
+        Err(InvalidHeaderValue(()))
+    }
+}
+
+impl Borrow<[u8]> for AuthToken {
+    fn borrow(&self) -> &[u8] {
+        self.0.borrow()
+    }
+}
+
+impl From<AuthToken> for Bytes {
+    fn from(token: AuthToken) -> Self {
+        token.0
+    }
+}
+
+impl<'de> Deserialize<'de> for AuthToken {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let token_str = <&str>::deserialize(deserializer)?;
+        AuthToken::try_from(Bytes::copy_from_slice(token_str.as_bytes()))
+            .map_err(de::Error::custom)
+    }
+}
+
+impl error::Error for InvalidHeaderValue {}
+
+impl fmt::Display for InvalidHeaderValue {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("failed to parse header value")
+    }
+}
+
+/// Headers that the connector sets itself, so they can't be configured as
+/// extra `headers`.
+static RESERVED_HEADERS: &[&str] = &[
+    "authorization",
+    "content-length",
+    "content-type",
+    "host",
+    "ilp-deadline",
+    "ilp-peer-name",
+    "x-request-id",
+];
+
+/// Extra HTTP headers for a next hop, deserialized from an object of names to
+/// values. The names are lowercase. The values are redacted from `Debug`, so
+/// they aren't logged.
+#[derive(Clone, Default, PartialEq)]
+pub struct Headers(Vec<(String, Bytes)>);
+
+impl Headers {
+    /// `name` must be a valid (and not reserved) header name, and `value` a
+    /// valid header value.
+    pub fn insert(&mut self, name: &str, value: &str) -> Result<(), String> {
+        let is_valid_name = !name.is_empty()
+            && name.bytes().all(|byte| {
+                byte.is_ascii_alphanumeric()
+                    || b"!#$%&'*+-.^_`|~".contains(&byte)
+            });
+        if !is_valid_name {
+            return Err(format!("invalid HTTP header name: {:?}", name));
+        }
+        let name = name.to_ascii_lowercase();
+        if RESERVED_HEADERS.contains(&name.as_str()) {
+            return Err(format!("reserved header: {}", name));
+        }
+        check_header_value(value.as_bytes())
+            .map_err(|error| error.to_string())?;
+        let value = Bytes::copy_from_slice(value.as_bytes());
+        match self.0.iter_mut().find(|(other, _)| *other == name) {
+            Some(header) => header.1 = value,
+            None => self.0.push((name, value)),
+        }
+        Ok(())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Bytes)> {
+        self.0.iter().map(|(name, value)| (name.as_str(), value))
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Bytes> {
+        self.iter()
+            .find(|(other, _)| other.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.0.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl fmt::Debug for Headers {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_map()
+            .entries(self.0.iter().map(|(name, _)| (name, "Sensitive")))
+            .finish()
+    }
+}
+
+impl<'de> Deserialize<'de> for Headers {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let map = BTreeMap::<String, String>::deserialize(deserializer)?;
+        let mut headers = Headers::default();
+        for (name, value) in map {
+            headers.insert(&name, &value).map_err(de::Error::custom)?;
+        }
+        Ok(headers)
+    }
+}
+
+#[cfg(test)]
+mod test_transport {
+    use super::*;
+
+    #[test]
+    fn test_endpoint() {
+        for valid in &[
+            "http://example.com",
+            "https://example.com:8443/ilp?x=1",
+            "http://127.0.0.1:3001/alice",
+            "http://[::1]:3001/alice",
+            "wss://example.com/btp",
+            "http://example.com/bob/test.relay.1234/ilp",
+        ] {
+            assert_eq!(valid.parse::<Endpoint>().unwrap().as_str(), *valid);
+        }
+        for invalid in &[
+            "",
+            "not a uri",
+            "/relative",
+            "http://",
+            "http:///path",
+            "http://example.com:port/",
+            "1http://example.com",
+            "http://example.com/a b",
+            "http://example.com/\n",
+        ] {
+            assert!(invalid.parse::<Endpoint>().is_err(), "uri={:?}", invalid);
+        }
+
+        let endpoint = "https://example.com:8443/ilp".parse::<Endpoint>().unwrap();
+        assert_eq!(endpoint.scheme(), "https");
+        assert_eq!(endpoint.authority(), "example.com:8443");
+        assert_eq!(format!("{:?}", endpoint), "\"https://example.com:8443/ilp\"");
+    }
+
+    #[test]
+    fn test_auth_token_try_from() {
+        let valid_bytes = Bytes::from("test_token");
+        let invalid_bytes = Bytes::from("test\ntoken");
+
+        assert_eq!(
+            AuthToken::try_from(valid_bytes.clone()).unwrap(),
+            AuthToken(valid_bytes),
+        );
+        assert!(AuthToken::try_from(invalid_bytes).is_err());
+    }
+
+    #[test]
+    fn test_deserialize_headers() {
+        let headers = serde_json::from_str::<Headers>(r#"{
+            "X-Api-Key": "secret",
+            "x-tenant": "example"
+        }"#).unwrap();
+        assert_eq!(headers.len(), 2);
+        assert_eq!(headers.get("x-api-key").unwrap(), "secret");
+        assert_eq!(headers.get("X-Tenant").unwrap(), "example");
+        assert_eq!(
+            format!("{:?}", headers),
+            r#"{"x-api-key": "Sensitive", "x-tenant": "Sensitive"}"#,
+        );
+
+        for invalid in &[
+            r#"{ "Authorization": "secret" }"#,
+            r#"{ "x request": "1" }"#,
+            r#"{ "x-api-key": "new
+line" }"#,
+            r#"["x-api-key"]"#,
+        ] {
+            assert!(
+                serde_json::from_str::<Headers>(invalid).is_err(),
+                "headers={}", invalid,
+            );
+        }
+    }
+
+    #[test]
+    fn test_retry_backoff() {
+        let ms = time::Duration::from_millis;
+        let policy = RetryPolicy {
+            max_attempts: 5,
+            backoff_base: ms(100),
+            jitter: 0.5,
+            ..RetryPolicy::default()
+        };
+        assert_eq!(policy.backoff(1, 0.0), ms(100));
+        assert_eq!(policy.backoff(2, 0.0), ms(200));
+        assert_eq!(policy.backoff(3, 0.0), ms(400));
+        assert_eq!(policy.backoff(3, 0.5), ms(300));
+        assert_eq!(RetryPolicy::default().backoff(1, 0.5), ms(0));
+        // Large attempt numbers don't overflow.
+        assert!(policy.backoff(100, 0.0) > ms(100));
+
+        for _ in 0..100 {
+            let random = crate::random_fraction();
+            assert!((0.0..1.0).contains(&random));
+        }
+    }
+
+    #[test]
+    fn test_deserialize_retry_policy() {
+        assert_eq!(
+            serde_json::from_str::<RetryPolicy>(r#"{
+                "max_attempts": 3,
+                "backoff_base": { "secs": 0, "nanos": 50000000 },
+                "status_codes": [502, 503]
+            }"#).unwrap(),
+            RetryPolicy {
+                max_attempts: 3,
+                backoff_base: time::Duration::from_millis(50),
+                jitter: 0.0,
+                status_codes: vec![502, 503],
+                connection_errors: false,
+            },
+        );
+        assert_eq!(
+            serde_json::from_str::<RetryPolicy>("{}").unwrap(),
+            RetryPolicy::default(),
+        );
+    }
+}
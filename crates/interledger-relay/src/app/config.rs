@@ -1,21 +1,30 @@
-use std::collections::HashSet;
-use std::error;
-use std::fmt;
+use std::cmp;
+use std::collections::{BTreeMap, HashSet};
 use std::sync::Arc;
+use std::time;
 
 use bytes::{Bytes, BytesMut};
 use futures::future::{Either, ok};
 use futures::prelude::*;
 use hyper::Uri;
+use log::{debug, warn};
 use serde::Deserialize;
 
-use crate::{AuthToken, Client, Relation};
+use crate::{AuthToken, CertificateBinding, Client, HttpVersion, IpNetwork, PeerAuthToken, RateLimitConfig, Relation, RequestId, RetryPolicy, SigningSecret, ThroughputLimitConfig};
 use crate::client::RequestOptions;
+use crate::middlewares::JwksError;
 use crate::serde::deserialize_uri;
-use crate::services::ConnectorPeer;
+use crate::peers::ConnectorPeer;
 use ilp::ildcp;
 
-#[derive(Debug, PartialEq, Deserialize)]
+pub use interledger_relay_core::SetupError;
+
+/// The delays between attempts to fetch a `Dynamic` root's config at startup
+/// double from the minimum, up to the maximum.
+const FETCH_RETRY_MIN_DELAY: time::Duration = time::Duration::from_secs(1);
+const FETCH_RETRY_MAX_DELAY: time::Duration = time::Duration::from_secs(60);
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 pub enum ConnectorRoot {
@@ -30,32 +39,126 @@ pub enum ConnectorRoot {
         parent_auth: AuthToken,
         // TODO should "name" be optional?
         name: String,
+        /// Parents that are tried, in order, when the ILDCP request to
+        /// `parent_endpoint` fails.
+        #[serde(default)]
+        fallback_parents: Vec<ParentEndpoint>,
+        /// Re-fetch the config from the parent this often. Changes to the
+        /// asset are applied; changes to the address require a restart.
+        #[serde(default)]
+        refresh_interval: Option<time::Duration>,
     },
 }
 
-/// The `auth` token lists are valid incoming authentication tokens.
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ParentEndpoint {
+    #[serde(deserialize_with = "deserialize_uri")]
+    pub endpoint: Uri,
+    pub auth: AuthToken,
+}
+
+/// The `auth` token lists are valid incoming authentication tokens (see
+/// `PeerAuthToken` for rotating them). They may
+/// be empty when peers authenticate with a JWT (see `jwt_auth`) or by signing
+/// their requests with their `signing_secret` instead.
 /// `account` is an account's unique identifier. It is primarily used for
-/// logging in BigQuery.
+/// telemetry (BigQuery or Pub/Sub).
 #[derive(Clone, Debug, PartialEq, Deserialize)]
 #[serde(deny_unknown_fields)]
 #[serde(tag = "type")]
 pub enum RelationConfig {
     Child {
-        auth: Vec<AuthToken>,
+        #[serde(default)]
+        auth: Vec<PeerAuthToken>,
         account: Arc<String>,
         /// The suffix must be an ILP address segment.
         suffix: String,
+        #[serde(default)]
+        rate_limit: Option<RateLimitConfig>,
+        /// Limit the total amount of the peer's Prepares per interval.
+        #[serde(default)]
+        throughput_limit: Option<ThroughputLimitConfig>,
+        /// The maximum number of the peer's Prepares that may be in flight.
+        #[serde(default)]
+        max_in_flight: Option<usize>,
+        #[serde(default)]
+        certificate: Option<CertificateBinding>,
+        #[serde(default)]
+        signing_secret: Option<SigningSecret>,
+        /// When set, the peer's requests must come from one of these networks.
+        #[serde(default)]
+        allowed_ips: Vec<IpNetwork>,
+        /// Honor the child's `ILP-Deadline` (or `X-Request-Deadline`) header,
+        /// e.g. when the child is another relay.
+        #[serde(default)]
+        trust_deadline: bool,
     },
     Peer {
-        auth: Vec<AuthToken>,
+        #[serde(default)]
+        auth: Vec<PeerAuthToken>,
         account: Arc<String>,
+        #[serde(default)]
+        rate_limit: Option<RateLimitConfig>,
+        /// Limit the total amount of the peer's Prepares per interval.
+        #[serde(default)]
+        throughput_limit: Option<ThroughputLimitConfig>,
+        /// The maximum number of the peer's Prepares that may be in flight.
+        #[serde(default)]
+        max_in_flight: Option<usize>,
+        #[serde(default)]
+        certificate: Option<CertificateBinding>,
+        #[serde(default)]
+        signing_secret: Option<SigningSecret>,
+        /// When set, the peer's requests must come from one of these networks.
+        #[serde(default)]
+        allowed_ips: Vec<IpNetwork>,
     },
     Parent {
-        auth: Vec<AuthToken>,
+        #[serde(default)]
+        auth: Vec<PeerAuthToken>,
         account: Arc<String>,
+        #[serde(default)]
+        rate_limit: Option<RateLimitConfig>,
+        /// Limit the total amount of the peer's Prepares per interval.
+        #[serde(default)]
+        throughput_limit: Option<ThroughputLimitConfig>,
+        /// The maximum number of the peer's Prepares that may be in flight.
+        #[serde(default)]
+        max_in_flight: Option<usize>,
+        #[serde(default)]
+        certificate: Option<CertificateBinding>,
+        #[serde(default)]
+        signing_secret: Option<SigningSecret>,
+        /// When set, the peer's requests must come from one of these networks.
+        #[serde(default)]
+        allowed_ips: Vec<IpNetwork>,
     },
 }
 
+/// Identifies a single replica in a multi-instance deployment. The `id` and
+/// `labels` are attached to logs, metrics, telemetry rows, and responses.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct InstanceConfig {
+    /// The `id` must be a valid HTTP header value.
+    pub id: Option<Arc<String>>,
+    #[serde(default)]
+    pub labels: BTreeMap<String, String>,
+}
+
+impl InstanceConfig {
+    /// The constant labels for metrics: the `id` (as `instance`) followed by the
+    /// configured `labels`.
+    pub(crate) fn metric_labels(&self) -> Vec<(String, String)> {
+        self.id
+            .iter()
+            .map(|id| ("instance".to_owned(), id.as_ref().clone()))
+            .chain(self.labels.clone())
+            .collect()
+    }
+}
+
 impl ConnectorRoot {
     pub(crate) fn load_config(&self)
         -> impl Future<Output = Result<ildcp::Response, SetupError>>
@@ -74,13 +177,98 @@ impl ConnectorRoot {
                 parent_endpoint,
                 parent_auth,
                 name,
-            } => Either::Right(fetch_ildcp(
-                parent_endpoint,
-                parent_auth.as_bytes(),
-                name.as_bytes(),
-            )),
+                fallback_parents,
+                ..
+            } => {
+                let primary = ParentEndpoint {
+                    endpoint: parent_endpoint.clone(),
+                    auth: parent_auth.clone(),
+                };
+                let parents = std::iter::once(primary)
+                    .chain(fallback_parents.iter().cloned())
+                    .collect();
+                Either::Right(fetch_ildcp_with_failover(parents, name.clone()))
+            },
+        }
+    }
+
+    /// Like `load_config`, but retry (with exponential backoff) until the
+    /// parent of a `Dynamic` root responds, rather than failing startup.
+    pub(crate) async fn load_config_with_retry(&self) -> ildcp::Response {
+        let mut delay = FETCH_RETRY_MIN_DELAY;
+        loop {
+            match self.load_config().await {
+                Ok(response) => return response,
+                Err(error) => {
+                    warn!(
+                        "error fetching ildcp config: error={} retry_delay={:?}",
+                        error, delay,
+                    );
+                    tokio::time::delay_for(delay).await;
+                    delay = cmp::min(delay * 2, FETCH_RETRY_MAX_DELAY);
+                },
+            }
+        }
+    }
+
+    /// Re-fetch the config of a `Dynamic` root every `refresh_interval` (if
+    /// set), and pass it to `update`. Failed fetches are retried at the next
+    /// interval.
+    pub(crate) fn spawn_refresh<F>(&self, update: F)
+    where
+        F: Fn(ildcp::Response) + Send + 'static,
+    {
+        let refresh_interval = match self {
+            ConnectorRoot::Dynamic {
+                refresh_interval: Some(refresh_interval),
+                ..
+            } => *refresh_interval,
+            _ => return,
+        };
+        let root = self.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::delay_for(refresh_interval).await;
+                match root.load_config().await {
+                    Ok(response) => {
+                        debug!("refreshed ildcp config: response={:?}", response);
+                        update(response);
+                    },
+                    Err(error) => {
+                        warn!("error refreshing ildcp config: error={}", error);
+                    },
+                }
+            }
+        });
+    }
+}
+
+/// Request the config from each parent in turn, until one responds.
+async fn fetch_ildcp_with_failover(parents: Vec<ParentEndpoint>, name: String)
+    -> Result<ildcp::Response, SetupError>
+{
+    let mut last_error = None;
+    for (index, parent) in parents.iter().enumerate() {
+        let result = fetch_ildcp(
+            &parent.endpoint,
+            parent.auth.as_bytes(),
+            name.as_bytes(),
+        ).await;
+        match result {
+            Ok(response) => return Ok(response),
+            Err(error) => {
+                if index + 1 < parents.len() {
+                    warn!(
+                        "ildcp request failed, trying next parent: parent_index={} error={}",
+                        index, error,
+                    );
+                }
+                last_error = Some(error);
+            },
         }
     }
+    // There is always at least one parent.
+    Err(last_error.unwrap())
 }
 
 fn fetch_ildcp(endpoint: &Uri, auth: Bytes, peer_name: &[u8])
@@ -96,6 +284,11 @@ fn fetch_ildcp(endpoint: &Uri, auth: Bytes, peer_name: &[u8])
             uri: endpoint.clone(),
             auth: Some(auth),
             peer_name: Some(BytesMut::from(peer_name).freeze()),
+            headers: None,
+            retry: Arc::new(RetryPolicy::default()),
+            http_version: HttpVersion::Adaptive,
+            response_timeout: None,
+            request_id: RequestId::generate(),
         }, prepare)
         .err_into()
         .and_then(|fulfill| {
@@ -113,7 +306,7 @@ impl RelationConfig {
         }
     }
 
-    pub(crate) fn auth_tokens(&self) -> &[AuthToken] {
+    pub(crate) fn auth_tokens(&self) -> &[PeerAuthToken] {
         match self {
             RelationConfig::Child { auth, .. } => auth,
             RelationConfig::Peer { auth, .. } => auth,
@@ -130,6 +323,67 @@ impl RelationConfig {
         }
     }
 
+    pub(crate) fn rate_limit(&self) -> Option<RateLimitConfig> {
+        match self {
+            RelationConfig::Child { rate_limit, .. }
+                | RelationConfig::Peer { rate_limit, .. }
+                | RelationConfig::Parent { rate_limit, .. }
+                => *rate_limit,
+        }
+    }
+
+    pub(crate) fn throughput_limit(&self) -> Option<ThroughputLimitConfig> {
+        match self {
+            RelationConfig::Child { throughput_limit, .. }
+                | RelationConfig::Peer { throughput_limit, .. }
+                | RelationConfig::Parent { throughput_limit, .. }
+                => *throughput_limit,
+        }
+    }
+
+    pub(crate) fn max_in_flight(&self) -> Option<usize> {
+        match self {
+            RelationConfig::Child { max_in_flight, .. }
+                | RelationConfig::Peer { max_in_flight, .. }
+                | RelationConfig::Parent { max_in_flight, .. }
+                => *max_in_flight,
+        }
+    }
+
+    pub(crate) fn certificate(&self) -> Option<&CertificateBinding> {
+        match self {
+            RelationConfig::Child { certificate, .. }
+                | RelationConfig::Peer { certificate, .. }
+                | RelationConfig::Parent { certificate, .. }
+                => certificate.as_ref(),
+        }
+    }
+
+    pub(crate) fn signing_secret(&self) -> Option<&SigningSecret> {
+        match self {
+            RelationConfig::Child { signing_secret, .. }
+                | RelationConfig::Peer { signing_secret, .. }
+                | RelationConfig::Parent { signing_secret, .. }
+                => signing_secret.as_ref(),
+        }
+    }
+
+    pub(crate) fn allowed_ips(&self) -> &[IpNetwork] {
+        match self {
+            RelationConfig::Child { allowed_ips, .. }
+                | RelationConfig::Peer { allowed_ips, .. }
+                | RelationConfig::Parent { allowed_ips, .. }
+                => allowed_ips,
+        }
+    }
+
+    pub(crate) fn trust_deadline(&self) -> bool {
+        match self {
+            RelationConfig::Child { trust_deadline, .. } => *trust_deadline,
+            RelationConfig::Peer { .. } | RelationConfig::Parent { .. } => false,
+        }
+    }
+
     pub(crate) fn with_parent(&self, parent_address: &ilp::Address)
         -> Result<ConnectorPeer, SetupError>
     {
@@ -152,61 +406,16 @@ impl RelationConfig {
                 .iter()
                 .cloned()
                 .collect::<HashSet<_>>(),
+            certificate: self.certificate().cloned(),
+            signing_secret: self.signing_secret().cloned(),
+            trust_deadline: self.trust_deadline(),
         })
     }
 }
 
-#[derive(Debug)]
-pub struct SetupError(ErrorKind);
-
-#[derive(Debug)]
-enum ErrorKind {
-    ParseError(ilp::ParseError),
-    Reject(ilp::Reject),
-    OAuth(yup_oauth2::Error),
-}
-
-impl error::Error for SetupError {
-    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
-        match &self.0 {
-            ErrorKind::ParseError(inner) => Some(inner),
-            ErrorKind::Reject(_) => None,
-            ErrorKind::OAuth(inner) => Some(inner),
-        }
-    }
-}
-
-impl fmt::Display for SetupError {
-    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        match &self.0 {
-            ErrorKind::ParseError(inner) => write!(f, "SetupError({})", inner),
-            ErrorKind::Reject(reject) => write!(f, "SetupError({:?})", reject),
-            ErrorKind::OAuth(inner) => write!(f, "SetupError({:?})", inner),
-        }
-    }
-}
-
-impl From<ilp::ParseError> for SetupError {
-    fn from(inner: ilp::ParseError) -> Self {
-        SetupError(ErrorKind::ParseError(inner))
-    }
-}
-
-impl From<ilp::AddressError> for SetupError {
-    fn from(inner: ilp::AddressError) -> Self {
-        SetupError(ErrorKind::ParseError(inner.into()))
-    }
-}
-
-impl From<ilp::Reject> for SetupError {
-    fn from(reject: ilp::Reject) -> Self {
-        SetupError(ErrorKind::Reject(reject))
-    }
-}
-
-impl From<yup_oauth2::Error> for SetupError {
-    fn from(inner: yup_oauth2::Error) -> Self {
-        SetupError(ErrorKind::OAuth(inner))
+impl From<JwksError> for SetupError {
+    fn from(inner: JwksError) -> Self {
+        SetupError::other(inner)
     }
 }
 
@@ -240,6 +449,8 @@ mod test_connector_root {
             parent_endpoint: RECEIVER_ORIGIN.parse().unwrap(),
             parent_auth: AuthToken::new("parent_secret"),
             name: "carl".to_owned(),
+            fallback_parents: vec![],
+            refresh_interval: None,
         };
 
         static PARENT_RESPONSE: ildcp::ResponseBuilder<'static> =
@@ -286,4 +497,49 @@ mod test_connector_root {
             })
             .run(load_config);
     }
+    #[test]
+    fn test_dynamic_failover() {
+        let root = ConnectorRoot::Dynamic {
+            // Nothing is listening on this port.
+            parent_endpoint: "http://127.0.0.1:1".parse().unwrap(),
+            parent_auth: AuthToken::new("unreachable_secret"),
+            name: "carl".to_owned(),
+            fallback_parents: vec![ParentEndpoint {
+                endpoint: RECEIVER_ORIGIN.parse().unwrap(),
+                auth: AuthToken::new("parent_secret"),
+            }],
+            refresh_interval: None,
+        };
+
+        static PARENT_RESPONSE: ildcp::ResponseBuilder<'static> =
+            ildcp::ResponseBuilder {
+                client_address: unsafe {
+                    ilp::Addr::new_unchecked(b"test.parent.carl")
+                },
+                asset_scale: 9,
+                asset_code: b"XRP",
+            };
+
+        let load_config = root.load_config()
+            .map(|response_result| {
+                let response = response_result.unwrap();
+                assert_eq!(response, PARENT_RESPONSE.build());
+            });
+
+        testing::MockServer::new()
+            .test_request(|req| {
+                assert_eq!(
+                    req.headers().get("Authorization").unwrap(),
+                    "parent_secret",
+                );
+            })
+            .with_response(|| {
+                let fulfill = ilp::Fulfill::from(PARENT_RESPONSE.build());
+                hyper::Response::builder()
+                    .status(200)
+                    .body(hyper::Body::from(BytesMut::from(fulfill).freeze()))
+                    .unwrap()
+            })
+            .run(load_config);
+    }
 }
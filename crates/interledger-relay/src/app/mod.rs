@@ -1,103 +1,498 @@
 mod config;
+mod summary;
 
+use std::sync::Arc;
 use std::time;
 
-use log::debug;
+use bytes::Bytes;
+use log::{debug, info};
 
-pub use self::config::{ConnectorRoot, RelationConfig, SetupError};
-use crate::{Client, RoutingPartition, RoutingTable, RoutingTableData};
-use crate::middlewares::{AuthTokenFilter, HealthCheckFilter, MethodFilter, PreStopFilter, Receiver};
-use crate::services::{BigQueryService, BigQueryServiceConfig};
-use crate::services::{ConfigService, DebugService, DebugServiceOptions};
-use crate::services::{ExpiryService, FromPeerService, RouterService};
+pub use self::config::{ConnectorRoot, InstanceConfig, ParentEndpoint, RelationConfig, SetupError};
+pub use self::summary::{ConfigSummary, Limits, RelationCounts, TelemetrySummary};
+use crate::{AccessLogConfig, AdminApiConfig, AuthHeader, Client, ClientPoolConfig, IpNetwork, JwtAuthConfig, TlsConfig, RoutingPartition, RoutingTable, RoutingTableData, ScheduledRoutes};
+use crate::btp::BtpReceiver;
+use crate::connector_info::ConnectorInfo;
+use crate::events::PacketEvents;
+use crate::https_client::HttpsClient;
+use crate::middlewares::{AccessLog, AccessLogFilter, AdminFilter, AuthLockout, AuthLockoutConfig, AuthTokenFilter, CapabilitiesFilter, HealthCheckFilter, IpAllowlist, IpAllowlistFilter, JwtVerifier, MethodFilter, PreStopFilter, Receiver, RegistrationFilter, SignatureFilter, SpspFilter};
+use crate::services::{CatchAllMonitor, CatchAllWarningConfig, ConcurrencyLimitService, ConfigService, DebugService, DebugServiceOptions};
+use crate::services::{DedupeConfig, DedupeService, GreylistConfig, GreylistService};
+use crate::services::{EchoService, EchoServiceOptions, SpspConfig, SpspReceiver, SpspService};
+use crate::services::{Settlement, SettlementConfig, SettlementService, ThroughputLimitService};
+use crate::metrics::Metrics;
+use crate::toggles::{ServiceToggles, Toggle};
+use crate::services::{ExpiryService, MetricsService};
+use crate::peers::{ChildRegistrationConfig, ChildRegistry, FromPeerService, PeerIndex};
+use crate::services::{RateLimitService, RejectJitterConfig, RejectJitterService, RouterService, ValidateFulfillmentService};
+use crate::services::{ExchangeRates, ExchangeRatesConfig, TelemetryService, TelemetryServiceConfig};
+use crate::sink_transport::TcpSinkTransport;
+use crate::transport::HttpClient;
 use ilp::ildcp;
 
 /// The maximum duration that the outgoing HTTP client will wait for a response,
 /// even if the Prepare's expiry is longer.
 const DEFAULT_MAX_TIMEOUT: time::Duration = time::Duration::from_secs(60);
 
+/// The capacity of the `PacketEvents` channel (e.g. for the admin API's packet
+/// tap), unless the embedder passes its own.
+const DEFAULT_EVENTS_CAPACITY: usize = 1024;
+
+/// The maximum duration that an incoming request's body may take to arrive.
+const DEFAULT_REQUEST_READ_TIMEOUT: time::Duration =
+    time::Duration::from_secs(10);
+
 #[derive(Debug, PartialEq, serde::Deserialize)]
 #[serde(deny_unknown_fields)]
 pub struct Config {
     pub root: ConnectorRoot,
     pub relatives: Vec<RelationConfig>,
     pub routes: RoutingTableData,
+    /// Routes that replace `routes` at a scheduled time.
+    #[serde(default)]
+    pub scheduled_routes: Option<ScheduledRoutes>,
+    /// The rates for routes whose `exchange` has an `asset_code`.
+    #[serde(default)]
+    pub exchange_rates: Option<ExchangeRatesConfig>,
     #[serde(default)]
     pub pre_stop_path: Option<String>,
+    /// How long requests are still served after a pre-stop request, before
+    /// they are rejected with `503`.
+    #[serde(default)]
+    pub pre_stop_grace_period: Option<time::Duration>,
+    /// On `SIGTERM` or `SIGINT`, how long to wait for in-flight requests before
+    /// exiting. This is used by the `ilprelay` binary, not by the `Connector`
+    /// itself.
+    #[serde(default)]
+    pub drain_timeout: Option<time::Duration>,
+    /// How long an incoming request's body may take to arrive, before the
+    /// request is answered with `408` (default: 10 seconds).
+    #[serde(default)]
+    pub request_read_timeout: Option<time::Duration>,
+    /// Accept BTP connections (WebSocket upgrades) on this path.
+    #[serde(default)]
+    pub btp_path: Option<String>,
+    /// The listener's TLS config. This is used by the `ilprelay` binary, not by
+    /// the `Connector` itself.
+    #[serde(default)]
+    pub tls: Option<TlsConfig>,
     #[serde(default)]
     pub routing_partition: RoutingPartition,
+    /// Authenticate and route packets, but reject them instead of forwarding
+    /// them to the next hop. This can be toggled at runtime.
+    #[serde(default)]
+    pub simulation_mode: bool,
+    /// The maximum number of Prepares (from all peers) that may be in flight.
+    #[serde(default)]
+    pub max_in_flight: Option<usize>,
+    /// Replay recent responses to retried Prepares, instead of forwarding
+    /// them again.
+    #[serde(default)]
+    pub dedupe: Option<DedupeConfig>,
+    /// Warn when an account sends too many packets to the catch-all route.
+    #[serde(default)]
+    pub catch_all_warning: Option<CatchAllWarningConfig>,
+    /// Connection pool settings for outgoing HTTP requests.
+    #[serde(default)]
+    pub client_pool: ClientPoolConfig,
+    /// Fetch the capabilities of each HTTP next hop, and adapt the outgoing
+    /// requests to them.
+    #[serde(default)]
+    pub peer_discovery: bool,
     #[serde(default)]
     pub debug_service: DebugServiceOptions,
     #[serde(default)]
-    pub big_query_service: Option<BigQueryServiceConfig>,
+    pub echo_service: EchoServiceOptions,
+    /// Receive STREAM payments locally, at `{address}.{segment}.*`, and serve
+    /// SPSP queries for them.
+    #[serde(default)]
+    pub spsp: Option<SpspConfig>,
+    /// Log fulfilled packets to BigQuery or Pub/Sub. `big_query_service` is
+    /// accepted for backwards compatibility.
+    #[serde(default, alias = "big_query_service")]
+    pub telemetry_service: Option<TelemetryServiceConfig>,
+    /// Track the balances of the settled accounts, and settle them through a
+    /// settlement engine.
+    #[serde(default)]
+    pub settlement: Option<SettlementConfig>,
+    #[serde(default)]
+    pub auth_header: AuthHeader,
+    #[serde(default)]
+    pub auth_lockout: Option<AuthLockoutConfig>,
+    /// Delay some Rejects (and `401` responses) by a random duration, to slow
+    /// down address scanning and credential probing.
+    #[serde(default)]
+    pub reject_jitter: Option<RejectJitterConfig>,
+    /// Flag peers that suddenly send to many novel destination prefixes.
+    #[serde(default)]
+    pub greylist: Option<GreylistConfig>,
+    /// Proxies (e.g. load balancers) whose `X-Forwarded-For` header is trusted
+    /// to identify the client, for the relations' `allowed_ips`.
+    #[serde(default)]
+    pub trusted_proxies: Vec<IpNetwork>,
+    /// Accept JWTs (in the auth header) from peers, in addition to their
+    /// static `auth` tokens.
+    #[serde(default)]
+    pub jwt_auth: Option<JwtAuthConfig>,
+    /// Allow children to be registered at runtime, through the admin API.
+    #[serde(default)]
+    pub child_registration: Option<ChildRegistrationConfig>,
+    #[serde(default)]
+    pub admin_api: Option<AdminApiConfig>,
+    /// Write a JSON line per HTTP request.
+    #[serde(default)]
+    pub access_log: Option<AccessLogConfig>,
+    #[serde(default)]
+    pub instance: InstanceConfig,
 }
 
 // TODO This should be an existential type once they are stable.
 pub type Connector =
     // HTTP Middlewares:
-    PreStopFilter<HealthCheckFilter<MethodFilter<AuthTokenFilter<
-        Receiver<
-            // ILP Services:
-            DebugService<ExpiryService<FromPeerService<
-                // RequestWithFrom:
-                ConfigService<BigQueryService>
-            >>>
-        >
+    PreStopFilter<AccessLogFilter<AdminFilter<RegistrationFilter<SpspFilter<
+        HealthCheckFilter<BtpReceiver<
+            PacketService,
+            CapabilitiesFilter<MethodFilter<SignatureFilter<AuthTokenFilter<
+                IpAllowlistFilter<Receiver<PacketService>>,
+            >>>>,
+        >>,
+    >>>>>;
+
+/// The ILP services, shared by the HTTP and BTP receivers.
+pub type PacketService =
+    DebugService<ExpiryService<RejectJitterService<FromPeerService<
+        // RequestWithFrom:
+        MetricsService<DedupeService<ConcurrencyLimitService<RateLimitService<
+            ThroughputLimitService<GreylistService<ConfigService<SettlementService<
+                SpspService<EchoService<TelemetryService>>,
+            >>>>
+        >>>>
     >>>>;
 
 impl Config {
     pub async fn start(self) -> Result<Connector, SetupError> {
-        let ildcp = self.root.load_config().await?;
+        let ildcp = self.root.load_config_with_retry().await;
         debug!("starting with ildcp_response={:?}", ildcp);
         self.start_with_ildcp(ildcp).await
     }
 
+    /// Start the connector, publishing every packet's outcome to `events`.
+    pub async fn start_with_events(self, events: PacketEvents)
+        -> Result<Connector, SetupError>
+    {
+        let ildcp = self.root.load_config_with_retry().await;
+        debug!("starting with ildcp_response={:?}", ildcp);
+        self.start_inner(ildcp, events).await
+    }
+
     // Used by benchmarks.
     #[doc(hidden)]
     pub async fn start_with_ildcp(self, ildcp: ildcp::Response)
         -> Result<Connector, SetupError>
     {
+        self.start_inner(ildcp, PacketEvents::new(DEFAULT_EVENTS_CAPACITY)).await
+    }
+
+    async fn start_inner(
+        self,
+        ildcp: ildcp::Response,
+        events: PacketEvents,
+    ) -> Result<Connector, SetupError> {
+        let summary = ConfigSummary::new(&self, &ildcp);
+        info!("starting connector: config={}", summary);
+
+        let instance_header = self.instance.id
+            .as_ref()
+            .map(|id| hyper::header::HeaderValue::from_str(id))
+            .transpose()
+            .map_err(|_| SetupError::invalid_config({
+                "instance.id must be a valid HTTP header value"
+            }))?;
+        let metrics = Arc::new(Metrics::new(self.instance.metric_labels()));
+        let access_log = self.access_log
+            .as_ref()
+            .map(AccessLog::open)
+            .transpose()?
+            .map(Arc::new);
+
+        // Shared by the services, so that a refreshed ILDCP config is applied
+        // to all of them at once.
+        let connector = ConnectorInfo::from(ildcp.clone());
         let address = ildcp.client_address().to_address();
-        let auth_tokens = self.relatives
-            .iter()
-            .flat_map(|relation| relation.auth_tokens().iter())
-            .cloned();
         let peers = self.relatives
             .iter()
             .map(|relation| {
                 relation.with_parent(&address)
             })
             .collect::<Result<Vec<_>, _>>()?;
+        let mut peers = PeerIndex::new(self.auth_header, peers);
+        if let Some(jwt_auth) = self.jwt_auth {
+            let verifier = JwtVerifier::new(jwt_auth).await?;
+            verifier.spawn_refresh();
+            peers = peers.with_jwt(Arc::new(verifier));
+        }
+        if let Some(child_registration) = self.child_registration {
+            let registry = ChildRegistry::new(child_registration, connector.clone());
+            registry.restore()?;
+            peers = peers.with_registry(Arc::new(registry));
+        }
+        let peers = Arc::new(peers);
+        let rate_limits = self.relatives
+            .iter()
+            .filter_map(|relation| {
+                relation.rate_limit().map(|limit| (relation.account(), limit))
+            })
+            .collect::<Vec<_>>();
+        let is_valid_rate = |rate: f64| rate.is_finite() && rate > 0.0;
+        if rate_limits.iter().any(|(_, limit)| !is_valid_rate(limit.packets_per_second)) {
+            return Err(SetupError::invalid_config({
+                "a relation's rate_limit.packets_per_second must be finite and positive"
+            }));
+        }
+        // A bucket without room for a token would reject every packet.
+        if rate_limits.iter().any(|(_, limit)| limit.burst == 0) {
+            return Err(SetupError::invalid_config({
+                "a relation's rate_limit.burst must be at least 1"
+            }));
+        }
+        let throughput_limits = self.relatives
+            .iter()
+            .filter_map(|relation| {
+                relation.throughput_limit().map(|limit| (relation.account(), limit))
+            })
+            .collect::<Vec<_>>();
+        if throughput_limits.iter().any(|(_, limit)| limit.interval.as_nanos() == 0) {
+            return Err(SetupError::invalid_config({
+                "a relation's throughput_limit.interval must not be zero"
+            }));
+        }
+        let concurrency_limits = self.relatives
+            .iter()
+            .filter_map(|relation| {
+                relation.max_in_flight().map(|limit| (relation.account(), limit))
+            })
+            .collect::<Vec<_>>();
+        let ip_allowlist = Arc::new(IpAllowlist::new(
+            self.relatives.iter().map(|relation| (
+                relation.account().as_ref().clone(),
+                relation.allowed_ips().to_vec(),
+            )),
+            self.trusted_proxies,
+        ));
 
-        let client = Client::new(address.clone());
+        let mut client = Client::new_with_pool(
+            connector.clone(),
+            &self.client_pool,
+            Some(Arc::clone(&metrics)),
+        );
+        if self.peer_discovery {
+            client = client.with_peer_discovery();
+        }
+        let pool = Arc::clone(client.pool());
+        // For the exchange rates and the settlement engine.
+        let https: Arc<dyn HttpClient> = Arc::new(HttpsClient::new());
         // ILP packet services:
-        let router_svc = RouterService::new(client, RoutingTable::new(
-            self.routes.into(),
-            self.routing_partition,
+        let needs_exchange_rates = self.routes.0
+            .iter()
+            .chain(self.scheduled_routes
+                .iter()
+                .flat_map(|scheduled| &scheduled.routes.0))
+            .any(|route| {
+                route.exchange
+                    .as_ref()
+                    .and_then(|exchange| exchange.asset_code.as_ref())
+                    .is_some()
+            });
+        let routes = RoutingTable::new(self.routes.into(), self.routing_partition);
+        routes.validate()?;
+        let scheduled_routes = match self.scheduled_routes {
+            Some(scheduled) => {
+                let routes = RoutingTable::new(
+                    scheduled.routes.into(),
+                    self.routing_partition,
+                );
+                routes.validate()?;
+                Some((scheduled.activate_at, routes, scheduled.rollback))
+            },
+            None => None,
+        };
+        let router_svc = RouterService::new(
+            connector.clone(),
+            Arc::new(client),
+            routes,
+            self.simulation_mode,
+        );
+        if let Some((activate_at, routes, rollback)) = scheduled_routes {
+            router_svc.schedule_routes(activate_at, routes, rollback);
+        }
+        if let Some(exchange_rates) = self.exchange_rates {
+            let exchange_rates = ExchangeRates::new(exchange_rates);
+            exchange_rates.validate().map_err(|error| {
+                SetupError::invalid_config(format!("invalid exchange_rates: {}", error))
+            })?;
+            exchange_rates.spawn_refresh(Arc::clone(&https));
+            router_svc.set_exchange_rates(exchange_rates);
+        } else if needs_exchange_rates {
+            return Err(SetupError::invalid_config(
+                "a route's exchange has an asset_code, but there are no exchange_rates",
+            ));
+        }
+        router_svc.spawn_health_checks();
+        let simulation_toggle = router_svc.simulation_toggle().clone();
+        let router = router_svc.clone();
+        let validate_svc =
+            ValidateFulfillmentService::new(connector.clone(), router_svc);
+        let catch_all = Arc::new(CatchAllMonitor::new(
+            self.catch_all_warning,
+            Arc::clone(&metrics),
         ));
-        let big_query_svc = BigQueryService::new(
-            address.clone(),
-            self.big_query_service,
-            router_svc,
+        let settlement = self.settlement
+            .map(|config| {
+                Settlement::new(
+                    config,
+                    connector.clone(),
+                    Arc::clone(&metrics),
+                    Arc::clone(&https),
+                )
+            })
+            .transpose()?;
+        if let Some(settlement) = &settlement {
+            router.set_settlement(settlement.clone());
+        }
+        let telemetry_svc = TelemetryService::new(
+            connector.clone(),
+            self.instance.id,
+            self.telemetry_service,
+            Arc::new(TcpSinkTransport),
+            catch_all,
+            Arc::clone(&metrics),
+            events.clone(),
+            validate_svc,
         ).await?;
-        //let echo_svc = EchoService::new(address.clone(), big_query_svc.clone());
+        let echo_svc = EchoService::new(
+            connector.clone(),
+            self.echo_service,
+            telemetry_svc.clone(),
+        );
 
-        let ildcp_svc = ConfigService::new(ildcp, big_query_svc.clone());
+        let echo_toggle = echo_svc.toggle().clone();
+        let spsp_receiver = self.spsp
+            .map(|spsp| SpspReceiver::new(spsp, connector.clone()))
+            .transpose()?
+            .map(Arc::new);
+        let spsp_svc = SpspService::new(spsp_receiver.clone(), echo_svc);
+        let settlement_svc = SettlementService::new(
+            connector.clone(),
+            settlement.clone(),
+            spsp_svc,
+        );
+        let ildcp_svc = ConfigService::new(connector.clone(), settlement_svc);
+        let ildcp_toggle = ildcp_svc.toggle().clone();
+        self.root.spawn_refresh({
+            let connector = connector.clone();
+            move |response| connector.set(response)
+        });
+        let greylist_svc = GreylistService::new(
+            connector.clone(),
+            self.greylist,
+            Arc::clone(&metrics),
+            ildcp_svc,
+        );
+        let throughput_limit_svc = ThroughputLimitService::new(
+            connector.clone(),
+            throughput_limits,
+            greylist_svc,
+        );
+        let rate_limit_svc = RateLimitService::new(
+            connector.clone(),
+            rate_limits,
+            throughput_limit_svc,
+        );
+        let concurrency_limit_svc = ConcurrencyLimitService::new(
+            connector.clone(),
+            self.max_in_flight,
+            concurrency_limits,
+            rate_limit_svc,
+        );
+        let dedupe_svc = DedupeService::new(
+            self.dedupe,
+            Arc::clone(&metrics),
+            concurrency_limit_svc,
+        );
+        let metrics_svc =
+            MetricsService::new(Arc::clone(&metrics), dedupe_svc);
         let from_peer_svc =
-            FromPeerService::new(address.clone(), peers, ildcp_svc);
+            FromPeerService::new(connector.clone(), Arc::clone(&peers), metrics_svc);
+        let reject_jitter_svc =
+            RejectJitterService::new(self.reject_jitter.clone(), from_peer_svc);
         let expiry_svc =
-            ExpiryService::new(address, DEFAULT_MAX_TIMEOUT, from_peer_svc);
+            ExpiryService::new(connector, DEFAULT_MAX_TIMEOUT, reject_jitter_svc);
         let debug_svc = DebugService::new(self.debug_service, expiry_svc);
+        let toggles = ServiceToggles {
+            echo: echo_toggle,
+            ildcp: ildcp_toggle,
+            debug: debug_svc.toggle().clone(),
+            simulation: simulation_toggle,
+            telemetry_cutover: telemetry_svc.cutover_toggle().clone(),
+        };
 
         // Middlewares:
-        let receiver = Receiver::new(debug_svc);
-        let auth_filter = AuthTokenFilter::new(auth_tokens, receiver);
-        let method_filter = MethodFilter::new(hyper::Method::POST, auth_filter);
-        let health_filter = HealthCheckFilter::new(method_filter);
+        let receiver = Receiver::new(
+            instance_header,
+            Some(self.request_read_timeout.unwrap_or(DEFAULT_REQUEST_READ_TIMEOUT)),
+            Arc::clone(&metrics),
+            debug_svc.clone(),
+        );
+        let auth_lockout = self.auth_lockout.map(|config| {
+            Arc::new(AuthLockout::new(config, Arc::clone(&metrics)))
+        });
+        let ip_allowlist_filter =
+            IpAllowlistFilter::new(Arc::clone(&ip_allowlist), receiver);
+        let mut auth_filter = AuthTokenFilter::new(
+            Arc::clone(&peers),
+            auth_lockout,
+            ip_allowlist_filter,
+        );
+        if let Some(reject_jitter) = self.reject_jitter {
+            auth_filter = auth_filter.with_reject_jitter(reject_jitter);
+        }
+        let signature_filter =
+            SignatureFilter::new(Arc::clone(&peers), auth_filter);
+        let method_filter =
+            MethodFilter::new(hyper::Method::POST, signature_filter);
+        let capabilities_filter = CapabilitiesFilter::new(method_filter);
+        let btp_receiver = BtpReceiver::new(
+            self.btp_path,
+            Arc::clone(&peers),
+            ip_allowlist,
+            Arc::clone(&metrics),
+            debug_svc,
+            capabilities_filter,
+        );
+        let draining = Toggle::new(false);
+        let health_filter =
+            HealthCheckFilter::new(draining.clone(), btp_receiver);
+        let spsp_filter = SpspFilter::new(spsp_receiver, health_filter);
+        let registration_filter =
+            RegistrationFilter::new(Arc::clone(&peers), spsp_filter);
+        let admin_filter = AdminFilter::new(
+            self.admin_api,
+            Bytes::from(summary.to_string()),
+            metrics,
+            pool,
+            router,
+            toggles,
+            Arc::clone(&peers),
+            events,
+            settlement,
+            registration_filter,
+        );
+        let access_log_filter = AccessLogFilter::new(access_log, admin_filter);
         let pre_stop_filter = PreStopFilter::new(
             self.pre_stop_path,
-            Box::new(move || Box::pin(big_query_svc.clone().stop())),
-            health_filter,
+            Box::new(move || Box::pin(telemetry_svc.clone().stop())),
+            self.pre_stop_grace_period,
+            draining,
+            access_log_filter,
         );
         Ok(pre_stop_filter)
     }
@@ -111,7 +506,7 @@ mod test_config {
     use hyper::service::Service;
     use lazy_static::lazy_static;
 
-    use crate::AuthToken;
+    use crate::{PeerAuthToken, RateLimitConfig};
     use crate::combinators;
     use crate::testing::{self, FULFILL, PREPARE};
     use super::*;
@@ -122,12 +517,25 @@ mod test_config {
         static ref PEERS: Vec<RelationConfig> = vec![
             RelationConfig::Child {
                 account: Arc::new("child_account".to_owned()),
-                auth: vec![AuthToken::new("secret_child")],
+                auth: vec![PeerAuthToken::new("secret_child")],
                 suffix: "child".to_owned(),
+                rate_limit: None,
+                throughput_limit: None,
+                max_in_flight: None,
+                certificate: None,
+                signing_secret: None,
+                allowed_ips: vec![],
+                trust_deadline: false,
             },
             RelationConfig::Parent {
                 account: Arc::new("parent_account".to_owned()),
-                auth: vec![AuthToken::new("secret_parent")],
+                auth: vec![PeerAuthToken::new("secret_parent")],
+                rate_limit: None,
+                throughput_limit: None,
+                max_in_flight: None,
+                certificate: None,
+                signing_secret: None,
+                allowed_ips: vec![],
             },
         ];
     }
@@ -142,14 +550,42 @@ mod test_config {
             },
             relatives: PEERS.clone(),
             routes: RoutingTableData(testing::ROUTES.clone()),
+            scheduled_routes: None,
             debug_service: DebugServiceOptions::default(),
-            big_query_service: None,
+            echo_service: EchoServiceOptions::default(),
+            spsp: None,
+            telemetry_service: None,
+            settlement: None,
             pre_stop_path: None,
+            pre_stop_grace_period: None,
+            drain_timeout: None,
+            request_read_timeout: None,
+            btp_path: None,
+            tls: None,
             routing_partition: RoutingPartition::Destination,
+            simulation_mode: false,
+            max_in_flight: None,
+            dedupe: None,
+            catch_all_warning: None,
+            client_pool: ClientPoolConfig::default(),
+            peer_discovery: false,
+            auth_header: AuthHeader::default(),
+            auth_lockout: None,
+            reject_jitter: None,
+            greylist: None,
+            exchange_rates: None,
+            trusted_proxies: vec![],
+            jwt_auth: None,
+            child_registration: None,
+            admin_api: None,
+            access_log: None,
+            instance: InstanceConfig::default(),
         };
 
+        let events = PacketEvents::new(16);
+        let mut receiver = events.subscribe();
         let future = connector
-            .start()
+            .start_with_events(events)
             .then(|connector_result| {
                 connector_result.unwrap().call({
                     hyper::Request::post("http://127.0.0.1:3002/ilp")
@@ -158,8 +594,14 @@ mod test_config {
                         .unwrap()
                 })
             })
-            .map(|response| {
+            .map(move |response| {
                 assert_eq!(response.unwrap().status(), 200);
+                let event = receiver.try_recv().unwrap();
+                assert_eq!(event.account.as_str(), "child_account");
+                assert_eq!(event.to_account.unwrap().as_str(), "alice");
+                assert_eq!(event.destination, PREPARE.destination().to_address());
+                assert_eq!(event.amount, PREPARE.amount());
+                assert_eq!(event.result, crate::PacketResult::Fulfilled);
             });
 
         testing::MockServer::new()
@@ -179,6 +621,82 @@ mod test_config {
             .run(future);
     }
 
+    #[tokio::test]
+    async fn test_invalid_rate_limit() {
+        async fn start(rate_limit: RateLimitConfig) -> Result<Connector, SetupError> {
+            let mut relatives = PEERS.clone();
+            if let RelationConfig::Child { rate_limit: limit, .. } = &mut relatives[0] {
+                *limit = Some(rate_limit);
+            }
+            let config = Config {
+                relatives,
+                ..static_config()
+            };
+            config.start_with_ildcp(testing::ILDCP_RESPONSE.clone()).await
+        }
+
+        for packets_per_second in &[0.0, -1.0, f64::NAN, f64::INFINITY] {
+            let error = start(RateLimitConfig {
+                packets_per_second: *packets_per_second,
+                burst: 10,
+            }).await.err().expect("invalid packets_per_second");
+            assert!(error.to_string().contains("rate_limit.packets_per_second"));
+        }
+
+        let error = start(RateLimitConfig {
+            packets_per_second: 10.0,
+            burst: 0,
+        }).await.err().expect("invalid burst");
+        assert!(error.to_string().contains("rate_limit.burst"));
+
+        assert!(start(RateLimitConfig {
+            packets_per_second: 10.0,
+            burst: 1,
+        }).await.is_ok());
+    }
+
+    fn static_config() -> Config {
+        Config {
+            root: ConnectorRoot::Static {
+                address: ilp::Address::new(b"example.alice"),
+                asset_scale: 9,
+                asset_code: "XRP".to_owned(),
+            },
+            relatives: PEERS.clone(),
+            routes: RoutingTableData(testing::ROUTES.clone()),
+            scheduled_routes: None,
+            debug_service: DebugServiceOptions::default(),
+            echo_service: EchoServiceOptions::default(),
+            spsp: None,
+            telemetry_service: None,
+            settlement: None,
+            pre_stop_path: None,
+            pre_stop_grace_period: None,
+            drain_timeout: None,
+            request_read_timeout: None,
+            btp_path: None,
+            tls: None,
+            routing_partition: RoutingPartition::Destination,
+            simulation_mode: false,
+            max_in_flight: None,
+            dedupe: None,
+            catch_all_warning: None,
+            client_pool: ClientPoolConfig::default(),
+            peer_discovery: false,
+            auth_header: AuthHeader::default(),
+            auth_lockout: None,
+            reject_jitter: None,
+            greylist: None,
+            exchange_rates: None,
+            trusted_proxies: vec![],
+            jwt_auth: None,
+            child_registration: None,
+            admin_api: None,
+            access_log: None,
+            instance: InstanceConfig::default(),
+        }
+    }
+
 /*
     #[test]
     fn test_dynamic() {
@@ -236,10 +754,36 @@ mod test_config {
             },
             relatives: PEERS.clone(),
             routes: RoutingTableData(testing::ROUTES.clone()),
+            scheduled_routes: None,
             debug_service: DebugServiceOptions::default(),
-            big_query_service: None,
+            echo_service: EchoServiceOptions::default(),
+            spsp: None,
+            telemetry_service: None,
+            settlement: None,
             pre_stop_path: None,
+            pre_stop_grace_period: None,
+            drain_timeout: None,
+            request_read_timeout: None,
+            btp_path: None,
+            tls: None,
             routing_partition: RoutingPartition::Destination,
+            simulation_mode: false,
+            max_in_flight: None,
+            dedupe: None,
+            catch_all_warning: None,
+            client_pool: ClientPoolConfig::default(),
+            peer_discovery: false,
+            auth_header: AuthHeader::default(),
+            auth_lockout: None,
+            reject_jitter: None,
+            greylist: None,
+            exchange_rates: None,
+            trusted_proxies: vec![],
+            jwt_auth: None,
+            child_registration: None,
+            admin_api: None,
+            access_log: None,
+            instance: InstanceConfig::default(),
         }.start();
 
         let request = hyper::Client::new()
@@ -0,0 +1,356 @@
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use serde::Serialize;
+
+use crate::{DebugServiceOptions, RowColumn, SinkConfig, UnavailablePolicy};
+use crate::client::MAX_RESPONSE_SIZE;
+use crate::middlewares::MAX_REQUEST_SIZE;
+use super::{Config, ConnectorRoot, DEFAULT_MAX_TIMEOUT, DEFAULT_REQUEST_READ_TIMEOUT, RelationConfig};
+use ilp::ildcp;
+
+/// A redacted summary of the effective configuration: it never includes auth
+/// tokens, endpoints, or credential file paths.
+///
+/// It is logged on startup and served from `GET /admin/config`.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct ConfigSummary {
+    pub instance_id: Option<String>,
+    pub instance_labels: BTreeMap<String, String>,
+    pub address: String,
+    pub asset_code: String,
+    pub asset_scale: u8,
+    /// Either `"Static"` or `"Dynamic"`.
+    pub root: &'static str,
+    pub relations: RelationCounts,
+    pub route_prefixes: usize,
+    pub routes: usize,
+    pub routing_partition: String,
+    /// Whether routes are scheduled to replace `routes`.
+    pub scheduled_routes: bool,
+    /// Whether packets are rejected instead of forwarded (at startup).
+    pub simulation_mode: bool,
+    /// Whether the next hops' capabilities are discovered.
+    pub peer_discovery: bool,
+    pub catch_all_warning: bool,
+    /// Whether retried Prepares are deduplicated.
+    pub dedupe: bool,
+    pub debug_service: DebugServiceOptions,
+    pub echo_service: bool,
+    pub spsp: bool,
+    /// The number of accounts settled through the settlement engine.
+    pub settled_accounts: usize,
+    pub telemetry_service: Option<TelemetrySummary>,
+    pub pre_stop_path: Option<String>,
+    pub btp_path: Option<String>,
+    pub tls: bool,
+    /// Whether clients must present a certificate.
+    pub mutual_tls: bool,
+    /// The name of the header that incoming tokens are read from.
+    pub auth_header: String,
+    pub auth_lockout: bool,
+    pub reject_jitter: bool,
+    pub greylist: bool,
+    pub exchange_rates: bool,
+    pub trusted_proxies: usize,
+    pub jwt_auth: bool,
+    /// Whether children may be registered at runtime.
+    pub child_registration: bool,
+    pub admin_api: bool,
+    pub access_log: bool,
+    pub limits: Limits,
+}
+
+#[derive(Clone, Debug, Default, PartialEq, Serialize)]
+pub struct RelationCounts {
+    pub child: usize,
+    pub peer: usize,
+    pub parent: usize,
+    /// The number of relations with a `rate_limit`.
+    pub rate_limited: usize,
+    /// The number of relations with a `throughput_limit`.
+    pub throughput_limited: usize,
+    /// The number of relations with a `max_in_flight`.
+    pub concurrency_limited: usize,
+    /// The number of relations with `allowed_ips`.
+    pub ip_restricted: usize,
+    /// The number of children whose `ILP-Deadline` header is honored.
+    pub deadline_trusted: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct TelemetrySummary {
+    /// Either `"BigQuery"`, `"PubSub"`, `"Kafka"`, or `"File"`.
+    pub sink: &'static str,
+    /// Not set for Kafka or files.
+    pub project_id: Option<String>,
+    /// The BigQuery table (`dataset_id.table_id`), the Pub/Sub or Kafka topic,
+    /// or the file (`"-"` for stdout).
+    pub destination: String,
+    pub queue_count: usize,
+    pub batch_capacity: usize,
+    pub flush_interval_ms: u64,
+    pub on_unavailable: UnavailablePolicy,
+    /// The sink and destination that rejected packets are logged to, if any.
+    pub reject_sink: Option<&'static str>,
+    pub reject_destination: Option<String>,
+    /// The sink and destination that fulfilled packets are also logged to
+    /// (while migrating), if any.
+    pub shadow_sink: Option<&'static str>,
+    pub shadow_destination: Option<String>,
+    /// Whether the shadow sink starts out as the authoritative sink.
+    pub cutover: bool,
+    pub extra_columns: Vec<RowColumn>,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Limits {
+    pub max_timeout_ms: u64,
+    pub max_request_size: usize,
+    pub request_read_timeout_ms: u64,
+    pub max_response_size: usize,
+    /// The global `max_in_flight`.
+    pub max_in_flight: Option<usize>,
+}
+
+impl ConfigSummary {
+    pub fn new(config: &Config, ildcp: &ildcp::Response) -> Self {
+        let mut relations = RelationCounts::default();
+        for relation in &config.relatives {
+            match relation {
+                RelationConfig::Child { .. } => relations.child += 1,
+                RelationConfig::Peer { .. } => relations.peer += 1,
+                RelationConfig::Parent { .. } => relations.parent += 1,
+            }
+            if relation.rate_limit().is_some() {
+                relations.rate_limited += 1;
+            }
+            if relation.throughput_limit().is_some() {
+                relations.throughput_limited += 1;
+            }
+            if relation.max_in_flight().is_some() {
+                relations.concurrency_limited += 1;
+            }
+            if !relation.allowed_ips().is_empty() {
+                relations.ip_restricted += 1;
+            }
+            if relation.trust_deadline() {
+                relations.deadline_trusted += 1;
+            }
+        }
+
+        let route_prefixes = config.routes.0
+            .iter()
+            .map(|route| &route.target_prefix)
+            .collect::<HashSet<_>>()
+            .len();
+
+        ConfigSummary {
+            instance_id: config.instance.id
+                .as_ref()
+                .map(|id| id.as_ref().clone()),
+            instance_labels: config.instance.labels.clone(),
+            address: ildcp.client_address().to_string(),
+            asset_code: String::from_utf8_lossy(ildcp.asset_code()).into_owned(),
+            asset_scale: ildcp.asset_scale(),
+            root: match config.root {
+                ConnectorRoot::Static { .. } => "Static",
+                ConnectorRoot::Dynamic { .. } => "Dynamic",
+            },
+            relations,
+            route_prefixes,
+            routes: config.routes.0.len(),
+            routing_partition: format!("{:?}", config.routing_partition),
+            scheduled_routes: config.scheduled_routes.is_some(),
+            simulation_mode: config.simulation_mode,
+            peer_discovery: config.peer_discovery,
+            catch_all_warning: config.catch_all_warning.is_some(),
+            dedupe: config.dedupe.is_some(),
+            debug_service: config.debug_service.clone(),
+            echo_service: config.echo_service.enabled,
+            spsp: config.spsp.is_some(),
+            settled_accounts: config.settlement
+                .as_ref()
+                .map_or(0, |settlement| settlement.accounts.len()),
+            telemetry_service: config.telemetry_service
+                .as_ref()
+                .map(|telemetry| TelemetrySummary {
+                    sink: telemetry.sink.name(),
+                    project_id: telemetry.sink.project_id().map(str::to_owned),
+                    destination: telemetry.sink.destination(),
+                    queue_count: telemetry.queue_count,
+                    batch_capacity: telemetry.batch_capacity,
+                    flush_interval_ms: telemetry.flush_interval.as_millis() as u64,
+                    on_unavailable: telemetry.on_unavailable,
+                    reject_sink: telemetry.reject_sink
+                        .as_ref()
+                        .map(SinkConfig::name),
+                    reject_destination: telemetry.reject_sink
+                        .as_ref()
+                        .map(SinkConfig::destination),
+                    shadow_sink: telemetry.shadow_sink
+                        .as_ref()
+                        .map(SinkConfig::name),
+                    shadow_destination: telemetry.shadow_sink
+                        .as_ref()
+                        .map(SinkConfig::destination),
+                    cutover: telemetry.cutover,
+                    extra_columns: telemetry.extra_columns.clone(),
+                }),
+            pre_stop_path: config.pre_stop_path.clone(),
+            btp_path: config.btp_path.clone(),
+            tls: config.tls.is_some(),
+            mutual_tls: config.tls
+                .as_ref()
+                .and_then(|tls| tls.client_ca_file.as_ref())
+                .is_some(),
+            auth_header: config.auth_header.name().to_string(),
+            auth_lockout: config.auth_lockout.is_some(),
+            reject_jitter: config.reject_jitter.is_some(),
+            greylist: config.greylist.is_some(),
+            exchange_rates: config.exchange_rates.is_some(),
+            trusted_proxies: config.trusted_proxies.len(),
+            jwt_auth: config.jwt_auth.is_some(),
+            child_registration: config.child_registration.is_some(),
+            admin_api: config.admin_api.is_some(),
+            access_log: config.access_log.is_some(),
+            limits: Limits {
+                max_timeout_ms: DEFAULT_MAX_TIMEOUT.as_millis() as u64,
+                max_request_size: MAX_REQUEST_SIZE,
+                request_read_timeout_ms: config.request_read_timeout
+                    .unwrap_or(DEFAULT_REQUEST_READ_TIMEOUT)
+                    .as_millis() as u64,
+                max_response_size: MAX_RESPONSE_SIZE,
+                max_in_flight: config.max_in_flight,
+            },
+        }
+    }
+}
+
+impl fmt::Display for ConfigSummary {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let json = serde_json::to_string(self).map_err(|_| fmt::Error)?;
+        f.write_str(&json)
+    }
+}
+
+#[cfg(test)]
+mod test_config_summary {
+    use std::sync::Arc;
+
+    use crate::{AuthHeader, ClientPoolConfig, EchoServiceOptions, PeerAuthToken, RoutingPartition, RoutingTableData};
+    use crate::app::InstanceConfig;
+    use crate::testing::ROUTES;
+    use super::*;
+
+    #[test]
+    fn test_new() {
+        let config = Config {
+            root: ConnectorRoot::Static {
+                address: ilp::Address::new(b"example.alice"),
+                asset_scale: 9,
+                asset_code: "XRP".to_owned(),
+            },
+            relatives: vec![
+                RelationConfig::Child {
+                    account: Arc::new("child_account".to_owned()),
+                    auth: vec![PeerAuthToken::new("secret_child")],
+                    suffix: "child".to_owned(),
+                    rate_limit: None,
+                    throughput_limit: None,
+                    max_in_flight: None,
+                    certificate: None,
+                    signing_secret: None,
+                    allowed_ips: vec!["10.0.0.0/8".parse().unwrap()],
+                    trust_deadline: true,
+                },
+                RelationConfig::Parent {
+                    account: Arc::new("parent_account".to_owned()),
+                    auth: vec![PeerAuthToken::new("secret_parent")],
+                    rate_limit: None,
+                    throughput_limit: None,
+                    max_in_flight: None,
+                    certificate: None,
+                    signing_secret: None,
+                    allowed_ips: vec![],
+                },
+            ],
+            routes: RoutingTableData(ROUTES.clone()),
+            scheduled_routes: None,
+            debug_service: DebugServiceOptions::default(),
+            echo_service: EchoServiceOptions { enabled: true },
+            spsp: None,
+            telemetry_service: None,
+            settlement: None,
+            pre_stop_path: None,
+            pre_stop_grace_period: None,
+            drain_timeout: None,
+            request_read_timeout: None,
+            btp_path: None,
+            tls: None,
+            routing_partition: RoutingPartition::Destination,
+            simulation_mode: false,
+            max_in_flight: None,
+            dedupe: None,
+            catch_all_warning: None,
+            client_pool: ClientPoolConfig::default(),
+            peer_discovery: false,
+            auth_header: AuthHeader::default(),
+            auth_lockout: None,
+            reject_jitter: None,
+            greylist: None,
+            exchange_rates: None,
+            trusted_proxies: vec![],
+            jwt_auth: None,
+            child_registration: None,
+            admin_api: None,
+            access_log: None,
+            instance: InstanceConfig {
+                id: Some(Arc::new("relay-1".to_owned())),
+                labels: BTreeMap::new(),
+            },
+        };
+        let ildcp = ildcp::ResponseBuilder {
+            client_address: ilp::Addr::new(b"example.alice"),
+            asset_scale: 9,
+            asset_code: b"XRP",
+        }.build();
+
+        let summary = ConfigSummary::new(&config, &ildcp);
+        assert_eq!(summary.instance_id, Some("relay-1".to_owned()));
+        assert_eq!(summary.address, "example.alice");
+        assert_eq!(summary.root, "Static");
+        assert_eq!(summary.relations, RelationCounts {
+            child: 1,
+            peer: 0,
+            parent: 1,
+            rate_limited: 0,
+            throughput_limited: 0,
+            concurrency_limited: 0,
+            ip_restricted: 1,
+            deadline_trusted: 1,
+        });
+        assert_eq!(summary.routes, 3);
+        assert_eq!(summary.route_prefixes, 3);
+        assert_eq!(summary.routing_partition, "Destination");
+        assert!(!summary.scheduled_routes);
+        assert!(!summary.simulation_mode);
+        assert!(!summary.peer_discovery);
+        assert!(!summary.dedupe);
+        assert!(summary.echo_service);
+        assert!(!summary.spsp);
+        assert_eq!(summary.settled_accounts, 0);
+        assert_eq!(summary.auth_header, "authorization");
+        assert!(!summary.jwt_auth);
+        assert!(!summary.child_registration);
+        assert!(!summary.reject_jitter);
+        assert!(!summary.greylist);
+        assert!(!summary.exchange_rates);
+        assert!(!summary.admin_api);
+        assert!(!summary.access_log);
+
+        let json = summary.to_string();
+        assert!(!json.contains("secret"));
+        assert!(json.contains("\"address\":\"example.alice\""));
+    }
+}
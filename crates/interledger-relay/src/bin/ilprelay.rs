@@ -1,27 +1,41 @@
+use std::convert::Infallible;
 use std::env;
-use std::io::Write;
+use std::fs;
+use std::io::{self, Read, Write};
 use std::net::SocketAddr;
 use std::process;
+use std::sync::Arc;
+use std::time;
 
+use futures::channel::oneshot;
+use futures::future::Either;
 use futures::prelude::*;
-use log::{error, info};
+use hyper::server::conn::AddrStream;
+use hyper::service::Service;
+use log::{error, info, warn};
+use tokio::net::{TcpListener, TcpStream};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
 
-use interledger_relay::app;
+use interledger_relay::{ClientCertificate, CountingAllocator, app, inspect_packet};
+
+type HTTPRequest = hyper::Request<hyper::Body>;
+
+const TLS_HANDSHAKE_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+const MAX_TLS_HANDSHAKES: usize = 256;
+const DEFAULT_DRAIN_TIMEOUT: time::Duration = time::Duration::from_secs(20);
+
+// Count allocations, for the memory metrics.
+#[global_allocator]
+static ALLOCATOR: CountingAllocator = CountingAllocator;
 
 // TODO filter path?
 
 fn main() {
-    env_logger::builder()
-        .format(|fmt, record| {
-            writeln!(
-                fmt, "{} {} {} {}",
-                fmt.timestamp_micros(),
-                record.target(),
-                record.level(),
-                record.args(),
-            )
-        })
-        .init();
+    // `ilprelay inspect [FILE]` decodes packets instead of running the relay.
+    if env::args().nth(1).as_deref() == Some("inspect") {
+        process::exit(inspect(env::args().nth(2)));
+    }
 
     let bind_addr = env::var("RELAY_BIND")
         .unwrap_or_else(|_| {
@@ -45,22 +59,42 @@ fn main() {
             process::exit(1);
         });
 
+    // Include the instance ID (if any) in every log line.
+    let instance_id = config.instance.id
+        .as_ref()
+        .map(|id| format!(" instance={}", id))
+        .unwrap_or_default();
+    env_logger::builder()
+        .format(move |fmt, record| {
+            writeln!(
+                fmt, "{}{} {} {} {}",
+                fmt.timestamp_micros(),
+                instance_id,
+                record.target(),
+                record.level(),
+                record.args(),
+            )
+        })
+        .init();
+
+    let tls_acceptor = config.tls
+        .as_ref()
+        .map(|tls| tls.server_config())
+        .transpose()
+        .unwrap_or_else(|error| {
+            error!("invalid TLS config: {}", error);
+            process::exit(1);
+        })
+        .map(|server_config| TlsAcceptor::from(Arc::new(server_config)));
+
+    let drain_timeout = config.drain_timeout.unwrap_or(DEFAULT_DRAIN_TIMEOUT);
     let run_server = config
         .start()
         .map_err(|error| {
             error!("error starting connector: {}", error);
         })
         .and_then(move |connector| {
-            info!("listening at: addr={}", bind_addr);
-            hyper::Server::bind(&bind_addr)
-                // This never actually returns an error, so the closure needs a
-                // semi-explicit return type.
-                .serve(hyper::service::make_service_fn(move |_socket| {
-                    future::ok::<_, std::convert::Infallible>(connector.clone())
-                }))
-                .map_err(|error| {
-                    error!("server error: {}", error);
-                })
+            run(bind_addr, tls_acceptor, drain_timeout, connector)
         });
 
     tokio::runtime::Builder::new()
@@ -71,3 +105,191 @@ fn main() {
         .block_on(run_server)
         .unwrap();
 }
+
+/// Decode the packets (one per line, hex or base64) in `path`, or stdin, and
+/// print them as JSON. Returns the exit code.
+fn inspect(path: Option<String>) -> i32 {
+    let mut input = String::new();
+    let read = match &path {
+        Some(path) => fs::read_to_string(path).map(|file| input = file),
+        None => io::stdin().read_to_string(&mut input).map(|_| ()),
+    };
+    if let Err(error) = read {
+        eprintln!("error reading input: {}", error);
+        return 1;
+    }
+
+    let mut code = 0;
+    let lines = input.lines()
+        .enumerate()
+        .filter(|(_, line)| !line.trim().is_empty());
+    for (index, line) in lines {
+        match inspect_packet(line) {
+            Ok(fields) => println!(
+                "{}",
+                serde_json::to_string_pretty(&fields)
+                    .expect("failed to serialize packet"),
+            ),
+            Err(error) => {
+                eprintln!("invalid packet: line={} error={}", index + 1, error);
+                code = 1;
+            },
+        }
+    }
+    code
+}
+
+/// Serve until `SIGTERM` or `SIGINT`. Then stop accepting connections, wait (up
+/// to `drain_timeout`) for in-flight requests, and flush the telemetry queues.
+async fn run(
+    bind_addr: SocketAddr,
+    tls_acceptor: Option<TlsAcceptor>,
+    drain_timeout: time::Duration,
+    connector: app::Connector,
+) -> Result<(), ()> {
+    let (drain_tx, drain_rx) = oneshot::channel::<()>();
+    let drain = drain_rx.map(|_| ());
+    let server = match tls_acceptor {
+        Some(acceptor) => Either::Left(serve_tls(
+            bind_addr,
+            acceptor,
+            connector.clone(),
+            drain,
+        )),
+        None => Either::Right(serve(bind_addr, connector.clone(), drain)),
+    };
+    futures::pin_mut!(server);
+
+    let signal = shutdown_signal();
+    futures::pin_mut!(signal);
+    let server = match future::select(server, signal).await {
+        Either::Left((result, _signal)) => return result,
+        Either::Right(((), server)) => server,
+    };
+    info!("draining connections: drain_timeout={:?}", drain_timeout);
+    let start = time::Instant::now();
+    let _ = drain_tx.send(());
+    match tokio::time::timeout(drain_timeout, server).await {
+        Ok(result) => result?,
+        Err(_elapsed) => warn!("drain timed out; dropping in-flight requests"),
+    }
+    connector.shutdown().await;
+    info!("relay stopped: duration={:?}", time::Instant::now() - start);
+    Ok(())
+}
+
+#[cfg(unix)]
+async fn shutdown_signal() {
+    use tokio::signal::unix::{SignalKind, signal};
+    let mut sigterm = signal(SignalKind::terminate())
+        .expect("error listening for SIGTERM");
+    let mut sigint = signal(SignalKind::interrupt())
+        .expect("error listening for SIGINT");
+    let signal = future::select(sigterm.recv().boxed(), sigint.recv().boxed())
+        .await;
+    info!("received {}", match signal {
+        Either::Left(_) => "SIGTERM",
+        Either::Right(_) => "SIGINT",
+    });
+}
+
+#[cfg(not(unix))]
+async fn shutdown_signal() {
+    tokio::signal::ctrl_c()
+        .await
+        .expect("error listening for ctrl-c");
+    info!("received ctrl-c");
+}
+
+fn serve(
+    bind_addr: SocketAddr,
+    connector: app::Connector,
+    drain: impl Future<Output = ()>,
+) -> impl Future<Output = Result<(), ()>> {
+    info!("listening at: addr={}", bind_addr);
+    hyper::Server::bind(&bind_addr)
+        // This never actually returns an error, so the closure needs a
+        // semi-explicit return type.
+        .serve(hyper::service::make_service_fn(move |socket: &AddrStream| {
+            // Attach the peer's address to each request (e.g. for the
+            // auth lockout).
+            let remote_addr = socket.remote_addr();
+            let mut connector = connector.clone();
+            future::ok::<_, Infallible>({
+                hyper::service::service_fn(move |mut request: HTTPRequest| {
+                    request.extensions_mut().insert(remote_addr);
+                    connector.call(request)
+                })
+            })
+        }))
+        .with_graceful_shutdown(drain)
+        .map_err(|error| {
+            error!("server error: {}", error);
+        })
+}
+
+async fn serve_tls(
+    bind_addr: SocketAddr,
+    acceptor: TlsAcceptor,
+    connector: app::Connector,
+    drain: impl Future<Output = ()>,
+) -> Result<(), ()> {
+    let mut listener = TcpListener::bind(&bind_addr).await
+        .map_err(|error| {
+            error!("error binding listener: {}", error);
+        })?;
+    info!("listening at: addr={} tls=true", bind_addr);
+
+    // Handshakes run concurrently, so that a slow client doesn't block the
+    // others. Failed handshakes are logged and dropped.
+    let streams = listener
+        .incoming()
+        .filter_map(|tcp| future::ready(match tcp {
+            Ok(tcp) => Some(tcp),
+            Err(error) => {
+                warn!("error accepting connection: error={}", error);
+                None
+            },
+        }))
+        .map(move |tcp| {
+            tokio::time::timeout(TLS_HANDSHAKE_TIMEOUT, acceptor.accept(tcp))
+        })
+        .buffer_unordered(MAX_TLS_HANDSHAKES)
+        .filter_map(|result| future::ready(match result {
+            Ok(Ok(stream)) => Some(Ok::<_, io::Error>(stream)),
+            Ok(Err(error)) => {
+                warn!("TLS handshake error: error={}", error);
+                None
+            },
+            Err(_elapsed) => {
+                warn!("TLS handshake timed out");
+                None
+            },
+        }));
+
+    hyper::Server::builder(hyper::server::accept::from_stream(streams))
+        .serve(hyper::service::make_service_fn(move |stream: &TlsStream<TcpStream>| {
+            // Attach the peer's address and certificate to each request.
+            let (tcp, session) = stream.get_ref();
+            let remote_addr = tcp.peer_addr().ok();
+            let certificate = ClientCertificate::from_session(session)
+                .map(Arc::new);
+            let mut connector = connector.clone();
+            future::ok::<_, Infallible>({
+                hyper::service::service_fn(move |mut request: HTTPRequest| {
+                    if let Some(remote_addr) = remote_addr {
+                        request.extensions_mut().insert(remote_addr);
+                    }
+                    if let Some(certificate) = &certificate {
+                        request.extensions_mut().insert(Arc::clone(certificate));
+                    }
+                    connector.call(request)
+                })
+            })
+        }))
+        .with_graceful_shutdown(drain)
+        .await
+        .map_err(|error| {
+            error!("server error: {}", error);
+        })
+}
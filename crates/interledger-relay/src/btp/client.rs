@@ -0,0 +1,409 @@
+use std::collections::HashMap;
+use std::error;
+use std::fmt;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time;
+
+use bytes::Bytes;
+use futures::channel::{mpsc, oneshot};
+use futures::prelude::*;
+use hyper::Uri;
+use ilp::btp;
+use log::debug;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+use tokio_tungstenite::tungstenite::{self, Message};
+
+use super::{make_error, read_packet, send, websocket_config};
+
+/// The maximum duration to connect and authenticate.
+const CONNECT_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+/// Outgoing BTP connections, one per endpoint. The first request to an
+/// endpoint opens (and authenticates) its connection; it is reopened by the
+/// next request after it closes.
+///
+/// Incoming requests on outgoing connections are rejected.
+#[derive(Clone, Debug, Default)]
+pub(crate) struct BtpClient {
+    connections: Arc<Mutex<HashMap<Uri, Arc<Connection>>>>,
+}
+
+#[derive(Debug)]
+pub(crate) enum BtpError {
+    /// The endpoint isn't a `ws://` or `wss://` URI.
+    InvalidUri,
+    ConnectTimeout,
+    Io(io::Error),
+    Tls(native_tls::Error),
+    WebSocket(tungstenite::Error),
+    /// The connection closed before the response arrived.
+    Closed,
+    /// The peer responded with a BTP Error.
+    Remote(btp::ErrorDetails),
+    /// The peer's response didn't include any ILP data.
+    InvalidResponse,
+}
+
+#[derive(Debug)]
+struct Connection {
+    sender: mpsc::UnboundedSender<Message>,
+    pending: Mutex<HashMap<u32, oneshot::Sender<btp::Packet>>>,
+    next_request_id: AtomicU32,
+}
+
+/// Removes a request's entry from `pending` when the request is dropped
+/// (e.g. because it timed out).
+struct PendingGuard<'a> {
+    pending: &'a Mutex<HashMap<u32, oneshot::Sender<btp::Packet>>>,
+    request_id: u32,
+}
+
+impl BtpClient {
+    /// Send `ilp_data` (a Prepare) to the endpoint, and return the ILP data
+    /// (a Fulfill or Reject) of the response.
+    pub(crate) async fn request(
+        &self,
+        uri: &Uri,
+        auth: Option<Bytes>,
+        ilp_data: Bytes,
+    ) -> Result<Bytes, BtpError> {
+        let connection = self.connection(uri, auth).await?;
+        let response = connection.request(vec![btp::ProtocolData::new(
+            btp::PROTOCOL_ILP,
+            btp::ContentType::OctetStream,
+            ilp_data,
+        )]).await?;
+        match response {
+            btp::Packet::Response { .. } => response
+                .protocol(btp::PROTOCOL_ILP)
+                .map(|ilp_data| ilp_data.data.clone())
+                .ok_or(BtpError::InvalidResponse),
+            btp::Packet::Error { error, .. } => Err(BtpError::Remote(error)),
+            _ => Err(BtpError::InvalidResponse),
+        }
+    }
+
+    async fn connection(&self, uri: &Uri, auth: Option<Bytes>)
+        -> Result<Arc<Connection>, BtpError>
+    {
+        {
+            let connections = self.connections.lock().unwrap();
+            if let Some(connection) = connections.get(uri) {
+                if connection.is_open() {
+                    return Ok(Arc::clone(connection));
+                }
+            }
+        }
+
+        let connection = tokio::time::timeout(
+            CONNECT_TIMEOUT,
+            Connection::connect(uri, auth),
+        ).await.map_err(|_elapsed| BtpError::ConnectTimeout)??;
+        self.connections
+            .lock()
+            .unwrap()
+            .insert(uri.clone(), Arc::clone(&connection));
+        Ok(connection)
+    }
+}
+
+impl Connection {
+    async fn connect(uri: &Uri, auth: Option<Bytes>)
+        -> Result<Arc<Self>, BtpError>
+    {
+        let host = uri.host().ok_or(BtpError::InvalidUri)?;
+        let connection = match uri.scheme_str() {
+            Some("ws") => {
+                let port = uri.port_u16().unwrap_or(80);
+                let tcp = TcpStream::connect((host, port)).await?;
+                Connection::handshake(uri, tcp).await?
+            },
+            Some("wss") => {
+                let port = uri.port_u16().unwrap_or(443);
+                let tcp = TcpStream::connect((host, port)).await?;
+                let tls = tokio_tls::TlsConnector::from(native_tls::TlsConnector::new()?)
+                    .connect(host, tcp)
+                    .await?;
+                Connection::handshake(uri, tls).await?
+            },
+            _ => return Err(BtpError::InvalidUri),
+        };
+        connection.authenticate(auth.unwrap_or_default()).await?;
+        Ok(connection)
+    }
+
+    async fn handshake<S>(uri: &Uri, stream: S) -> Result<Arc<Self>, BtpError>
+    where
+        S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+    {
+        let (socket, _response) = tokio_tungstenite::client_async_with_config(
+            uri.clone(),
+            stream,
+            Some(websocket_config()),
+        ).await?;
+        let (sink, mut stream) = socket.split();
+        let (sender, receiver) = mpsc::unbounded();
+        tokio::spawn(receiver.map(Ok).forward(sink).map(|result| {
+            if let Err(error) = result {
+                debug!("BTP write error: error={}", error);
+            }
+        }));
+
+        let connection = Arc::new(Connection {
+            sender,
+            pending: Mutex::new(HashMap::new()),
+            next_request_id: AtomicU32::new(1),
+        });
+        let reader = Arc::clone(&connection);
+        tokio::spawn(async move {
+            while let Some(packet) = read_packet(&mut stream).await {
+                reader.dispatch(packet);
+            }
+            // Fail the pending requests.
+            reader.sender.close_channel();
+            reader.pending.lock().unwrap().clear();
+        });
+        Ok(connection)
+    }
+
+    async fn authenticate(&self, token: Bytes) -> Result<(), BtpError> {
+        let response = self.request(vec![
+            btp::ProtocolData::new(
+                btp::PROTOCOL_AUTH,
+                btp::ContentType::OctetStream,
+                Bytes::new(),
+            ),
+            btp::ProtocolData::new(
+                btp::PROTOCOL_AUTH_USERNAME,
+                btp::ContentType::TextPlainUtf8,
+                Bytes::new(),
+            ),
+            btp::ProtocolData::new(
+                btp::PROTOCOL_AUTH_TOKEN,
+                btp::ContentType::TextPlainUtf8,
+                token,
+            ),
+        ]).await?;
+        match response {
+            btp::Packet::Response { .. } => Ok(()),
+            btp::Packet::Error { error, .. } => Err(BtpError::Remote(error)),
+            _ => Err(BtpError::InvalidResponse),
+        }
+    }
+
+    fn is_open(&self) -> bool {
+        !self.sender.is_closed()
+    }
+
+    async fn request(&self, protocol_data: Vec<btp::ProtocolData>)
+        -> Result<btp::Packet, BtpError>
+    {
+        let request_id = self.next_request_id.fetch_add(1, Ordering::Relaxed);
+        let (response_sender, response) = oneshot::channel();
+        self.pending
+            .lock()
+            .unwrap()
+            .insert(request_id, response_sender);
+        let _guard = PendingGuard {
+            pending: &self.pending,
+            request_id,
+        };
+        if !self.is_open() {
+            return Err(BtpError::Closed);
+        }
+
+        send(&self.sender, &btp::Packet::Message { request_id, protocol_data });
+        response.await.map_err(|_canceled| BtpError::Closed)
+    }
+
+    fn dispatch(&self, packet: btp::Packet) {
+        let request_id = packet.request_id();
+        match packet {
+            btp::Packet::Response { .. } | btp::Packet::Error { .. } => {
+                let response_sender = self.pending
+                    .lock()
+                    .unwrap()
+                    .remove(&request_id);
+                match response_sender {
+                    Some(response_sender) => {
+                        // The request may have been dropped already.
+                        let _ = response_sender.send(packet);
+                    },
+                    None => debug!(
+                        "unexpected BTP response: request_id={}",
+                        request_id,
+                    ),
+                }
+            },
+            btp::Packet::Message { .. } | btp::Packet::Transfer { .. } => {
+                send(&self.sender, &make_error(
+                    request_id,
+                    "NotAcceptedError",
+                    b"incoming requests are not supported",
+                ));
+            },
+        }
+    }
+}
+
+impl<'a> Drop for PendingGuard<'a> {
+    fn drop(&mut self) {
+        self.pending.lock().unwrap().remove(&self.request_id);
+    }
+}
+
+impl error::Error for BtpError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            BtpError::Io(inner) => Some(inner),
+            BtpError::Tls(inner) => Some(inner),
+            BtpError::WebSocket(inner) => Some(inner),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for BtpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BtpError::InvalidUri => f.write_str("InvalidUri"),
+            BtpError::ConnectTimeout => f.write_str("ConnectTimeout"),
+            BtpError::Io(inner) => write!(f, "Io({})", inner),
+            BtpError::Tls(inner) => write!(f, "Tls({})", inner),
+            BtpError::WebSocket(inner) => write!(f, "WebSocket({})", inner),
+            BtpError::Closed => f.write_str("Closed"),
+            BtpError::Remote(error) => {
+                write!(f, "Remote({} {})", error.code, error.name)
+            },
+            BtpError::InvalidResponse => f.write_str("InvalidResponse"),
+        }
+    }
+}
+
+impl From<io::Error> for BtpError {
+    fn from(error: io::Error) -> Self {
+        BtpError::Io(error)
+    }
+}
+
+impl From<native_tls::Error> for BtpError {
+    fn from(error: native_tls::Error) -> Self {
+        BtpError::Tls(error)
+    }
+}
+
+impl From<tungstenite::Error> for BtpError {
+    fn from(error: tungstenite::Error) -> Self {
+        BtpError::WebSocket(error)
+    }
+}
+
+#[cfg(test)]
+mod test_btp_client {
+    use bytes::BytesMut;
+
+    use crate::{RequestWithPeerName, RequestWithHeaders};
+    use crate::testing::{self, FULFILL, PREPARE, REJECT};
+    use super::*;
+
+    fn make_uri(addr: std::net::SocketAddr) -> Uri {
+        format!("ws://{}/btp", addr).parse::<Uri>().unwrap()
+    }
+
+    fn prepare_data() -> Bytes {
+        BytesMut::from(PREPARE.clone()).freeze()
+    }
+
+    #[tokio::test]
+    async fn test_request() {
+        let addr = testing::serve_btp(|request: RequestWithHeaders| {
+            assert_eq!(request.prepare, *PREPARE);
+            assert_eq!(request.peer.as_ref().unwrap().account.as_str(), "btp_account");
+            assert_eq!(request.peer_name(), None);
+            future::ok(FULFILL.clone())
+        });
+        let client = BtpClient::default();
+        let uri = make_uri(addr);
+        let auth = Some(Bytes::from("btp_secret"));
+
+        // The connection is reused by the second request.
+        for _ in 0..2 {
+            let response = client
+                .request(&uri, auth.clone(), prepare_data())
+                .await
+                .unwrap();
+            assert_eq!(response.as_ref(), FULFILL.as_ref());
+        }
+        assert_eq!(client.connections.lock().unwrap().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn test_request_reject() {
+        let addr = testing::serve_btp(|_request: RequestWithHeaders| {
+            future::err(REJECT.clone())
+        });
+        let response = BtpClient::default()
+            .request(&make_uri(addr), Some(Bytes::from("btp_secret")), prepare_data())
+            .await
+            .unwrap();
+        assert_eq!(response.as_ref(), REJECT.as_ref());
+    }
+
+    #[tokio::test]
+    async fn test_invalid_auth() {
+        let addr = testing::serve_btp(testing::PanicService);
+        let result = BtpClient::default()
+            .request(&make_uri(addr), Some(Bytes::from("wrong")), prepare_data())
+            .await;
+        match result {
+            Err(BtpError::Remote(error)) => {
+                assert_eq!(error.code, ilp::ErrorCode::F00_BAD_REQUEST);
+                assert_eq!(error.name, "NotAcceptedError");
+            },
+            result => panic!("unexpected result: {:?}", result),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_invalid_uri() {
+        let uri = "http://127.0.0.1:3001/btp".parse::<Uri>().unwrap();
+        let result = BtpClient::default()
+            .request(&uri, None, prepare_data())
+            .await;
+        assert!(matches!(result, Err(BtpError::InvalidUri)));
+    }
+
+    #[tokio::test]
+    async fn test_client_request_btp() {
+        let addr = testing::serve_btp(|_request: RequestWithHeaders| {
+            future::ok(FULFILL.clone())
+        });
+        let client = crate::Client::new(testing::ADDRESS.to_address());
+        let result = client.clone()
+            .request_btp(
+                make_uri(addr),
+                Some(Bytes::from("btp_secret")),
+                crate::RequestId::generate(),
+                PREPARE.clone(),
+            )
+            .await;
+        assert_eq!(result.unwrap(), Ok(FULFILL.clone()));
+
+        // Connection errors are rejected as `T01`.
+        let error = client
+            .request_btp(
+                "ws://127.0.0.1:1/btp".parse::<Uri>().unwrap(),
+                None,
+                crate::RequestId::generate(),
+                PREPARE.clone(),
+            )
+            .await
+            .unwrap_err();
+        assert!(error.is_peer_unreachable());
+        let reject = error.to_reject(testing::ADDRESS);
+        assert_eq!(reject.code(), ilp::ErrorCode::T01_PEER_UNREACHABLE);
+    }
+}
@@ -0,0 +1,75 @@
+//! Bilateral Transfer Protocol (BTP/2.0) over WebSockets.
+//!
+//! <https://github.com/interledger/rfcs/blob/master/0023-bilateral-transfer-protocol/0023-bilateral-transfer-protocol.md>
+
+mod client;
+mod receiver;
+
+use futures::channel::mpsc;
+use futures::prelude::*;
+use ilp::btp;
+use log::{debug, warn};
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+use crate::middlewares::MAX_REQUEST_SIZE;
+
+pub(crate) use self::client::{BtpClient, BtpError};
+pub use self::receiver::BtpReceiver;
+
+/// The largest Prepare, plus room for the BTP envelope.
+const MAX_MESSAGE_SIZE: usize = MAX_REQUEST_SIZE + 256;
+
+fn websocket_config() -> WebSocketConfig {
+    WebSocketConfig {
+        max_message_size: Some(MAX_MESSAGE_SIZE),
+        max_frame_size: Some(MAX_MESSAGE_SIZE),
+        ..WebSocketConfig::default()
+    }
+}
+
+/// Read the next BTP packet, skipping invalid ones. Returns `None` once the
+/// connection is closed.
+async fn read_packet<St, E>(stream: &mut St) -> Option<btp::Packet>
+where
+    St: Stream<Item = Result<Message, E>> + Unpin,
+    E: std::fmt::Display,
+{
+    loop {
+        let message = match stream.next().await? {
+            Ok(message) => message,
+            Err(error) => {
+                debug!("BTP read error: error={}", error);
+                return None;
+            },
+        };
+        match message {
+            Message::Binary(buffer) => match btp::Packet::try_from(&buffer) {
+                Ok(packet) => return Some(packet),
+                Err(error) => {
+                    warn!("error parsing BTP packet: error={:?}", error);
+                },
+            },
+            Message::Close(_) => return None,
+            // Pings are answered by the WebSocket itself.
+            Message::Text(_) | Message::Ping(_) | Message::Pong(_) => {},
+        }
+    }
+}
+
+/// Returns the size of the sent message.
+fn send(sender: &mpsc::UnboundedSender<Message>, packet: &btp::Packet) -> usize {
+    let buffer = packet.to_bytes().to_vec();
+    let size = buffer.len();
+    // The connection is already closed if this fails.
+    let _ = sender.unbounded_send(Message::Binary(buffer));
+    size
+}
+
+fn make_error(request_id: u32, name: &str, message: &'static [u8])
+    -> btp::Packet
+{
+    let mut error = btp::ErrorDetails::new(ilp::ErrorCode::F00_BAD_REQUEST, name);
+    error.data = bytes::Bytes::from_static(message);
+    btp::Packet::Error { request_id, error }
+}
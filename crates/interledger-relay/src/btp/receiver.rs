@@ -0,0 +1,444 @@
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time;
+
+use bytes::BytesMut;
+use futures::channel::mpsc;
+use futures::future::{Either, Ready};
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use hyper::service::Service as HyperService;
+use hyper::upgrade::Upgraded;
+use ilp::btp;
+use log::{debug, warn};
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::create_response;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+use crate::{ClientCertificate, RequestId, RequestWithHeaders, Service};
+use crate::metrics::{Metrics, PeerTraffic};
+use crate::middlewares::IpAllowlist;
+use crate::peers::{ConnectorPeer, PeerIndex};
+use super::{make_error, read_packet, send, websocket_config};
+
+type HTTPRequest = http::Request<hyper::Body>;
+
+/// The client must authenticate within this long after connecting.
+const AUTH_TIMEOUT: time::Duration = time::Duration::from_secs(10);
+
+static PEER_NAME: &str = "ILP-Peer-Name";
+
+/// Accept BTP/2.0 connections (WebSocket upgrades to the configured `path`).
+///
+/// The first BTP Message on a connection must authenticate it with the
+/// `auth_token` of a relation. After that, each `ilp` Message is sent to the
+/// ILP services as if it had been received over HTTP from that relation. The
+/// `auth_username` (if any) is used as the `ILP-Peer-Name`. The size of the
+/// BTP messages received from and sent to the peer after authentication is
+/// counted in the metrics. Peers with `allowed_ips` must connect from one of
+/// those networks.
+///
+/// All other requests are passed on to `next`.
+#[derive(Clone)]
+pub struct BtpReceiver<S, H> {
+    path: Option<Arc<String>>,
+    peers: Arc<PeerIndex>,
+    ip_allowlist: Arc<IpAllowlist>,
+    metrics: Arc<Metrics>,
+    service: S,
+    next: H,
+}
+
+impl<S, H> BtpReceiver<S, H>
+where
+    S: Service<RequestWithHeaders> + Send + 'static,
+{
+    pub fn new(
+        path: Option<String>,
+        peers: Arc<PeerIndex>,
+        ip_allowlist: Arc<IpAllowlist>,
+        metrics: Arc<Metrics>,
+        service: S,
+        next: H,
+    ) -> Self {
+        BtpReceiver {
+            path: path.map(Arc::new),
+            peers,
+            ip_allowlist,
+            metrics,
+            service,
+            next,
+        }
+    }
+
+    fn upgrade(&self, request: HTTPRequest) -> hyper::Response<hyper::Body> {
+        let (mut parts, body) = request.into_parts();
+        let client_certificate =
+            parts.extensions.remove::<Arc<ClientCertificate>>();
+        let client_ip =
+            self.ip_allowlist.client_ip(&parts.extensions, &parts.headers);
+        let response = match create_response(&http::Request::from_parts(parts, ())) {
+            Ok(response) => response,
+            Err(error) => {
+                debug!("invalid BTP handshake: error={}", error);
+                return hyper::Response::builder()
+                    .status(hyper::StatusCode::BAD_REQUEST)
+                    .body(hyper::Body::from("Invalid WebSocket handshake"))
+                    .expect("response builder error");
+            },
+        };
+
+        let session = Session {
+            peers: Arc::clone(&self.peers),
+            ip_allowlist: Arc::clone(&self.ip_allowlist),
+            metrics: Arc::clone(&self.metrics),
+            service: self.service.clone(),
+            client_certificate,
+            client_ip,
+        };
+        tokio::spawn(body.on_upgrade().then(|upgraded| async move {
+            match upgraded {
+                Ok(upgraded) => session.run(upgraded).await,
+                Err(error) => warn!("BTP upgrade error: error={}", error),
+            }
+        }));
+        response.map(|()| hyper::Body::empty())
+    }
+}
+
+impl<S, H> HyperService<HTTPRequest> for BtpReceiver<S, H>
+where
+    S: Service<RequestWithHeaders> + Send + 'static,
+    H: HyperService<
+        HTTPRequest,
+        Response = hyper::Response<hyper::Body>,
+        Error = hyper::Error,
+    >,
+{
+    type Response = hyper::Response<hyper::Body>;
+    type Error = hyper::Error;
+    type Future = Either<
+        H::Future,
+        Ready<Result<Self::Response, Self::Error>>,
+    >;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+        self.next.poll_ready(context)
+    }
+
+    fn call(&mut self, request: HTTPRequest) -> Self::Future {
+        let is_btp = match &self.path {
+            Some(path) => request.uri().path() == path.as_str(),
+            None => false,
+        };
+        if is_btp {
+            Either::Right(future::ok(self.upgrade(request)))
+        } else {
+            Either::Left(self.next.call(request))
+        }
+    }
+}
+
+struct Session<S> {
+    peers: Arc<PeerIndex>,
+    ip_allowlist: Arc<IpAllowlist>,
+    metrics: Arc<Metrics>,
+    service: S,
+    client_certificate: Option<Arc<ClientCertificate>>,
+    client_ip: Option<IpAddr>,
+}
+
+impl<S> Session<S>
+where
+    S: Service<RequestWithHeaders> + Send + 'static,
+{
+    async fn run(self, upgraded: Upgraded) {
+        let socket = WebSocketStream::from_raw_socket(
+            upgraded,
+            Role::Server,
+            Some(websocket_config()),
+        ).await;
+        let (sink, mut stream) = socket.split();
+        // Responses are written in the order that they complete.
+        let (sender, receiver) = mpsc::unbounded();
+        tokio::spawn(receiver.map(Ok).forward(sink).map(|result| {
+            if let Err(error) = result {
+                debug!("BTP write error: error={}", error);
+            }
+        }));
+
+        let auth = tokio::time::timeout(AUTH_TIMEOUT, read_packet(&mut stream));
+        let auth_packet = match auth.await {
+            Ok(Some(packet)) => packet,
+            Ok(None) => return,
+            Err(_elapsed) => {
+                warn!("BTP auth timed out");
+                return;
+            },
+        };
+        let (peer, headers) = match self.authenticate(&auth_packet) {
+            Some(peer) => peer,
+            None => {
+                warn!("BTP auth failed: request_id={}", auth_packet.request_id());
+                send(&sender, &make_error(
+                    auth_packet.request_id(),
+                    "NotAcceptedError",
+                    b"invalid auth token",
+                ));
+                return;
+            },
+        };
+        if !self.ip_allowlist.is_allowed(&peer.account, self.client_ip) {
+            send(&sender, &make_error(
+                auth_packet.request_id(),
+                "NotAcceptedError",
+                b"address not allowed",
+            ));
+            return;
+        }
+        let traffic = PeerTraffic::new(
+            Arc::clone(&self.metrics),
+            Arc::clone(&peer.account),
+        );
+        traffic.sent(send(&sender, &btp::Packet::Response {
+            request_id: auth_packet.request_id(),
+            protocol_data: vec![],
+        }));
+
+        let received = traffic.clone();
+        let mut stream = stream.inspect(move |message| {
+            if let Ok(message) = message {
+                received.received(message.len());
+            }
+        });
+        while let Some(packet) = read_packet(&mut stream).await {
+            self.handle(packet, &peer, &headers, &traffic, &sender);
+        }
+    }
+
+    fn authenticate(&self, packet: &btp::Packet)
+        -> Option<(Arc<ConnectorPeer>, hyper::HeaderMap)>
+    {
+        match packet {
+            btp::Packet::Message { .. } => {},
+            _ => return None,
+        }
+        packet.protocol(btp::PROTOCOL_AUTH)?;
+        let token = packet.protocol(btp::PROTOCOL_AUTH_TOKEN)?;
+        let peer = self.peers.find(&token.data)?;
+
+        let mut headers = hyper::HeaderMap::new();
+        let username = packet
+            .protocol(btp::PROTOCOL_AUTH_USERNAME)
+            .filter(|username| !username.data.is_empty());
+        if let Some(username) = username {
+            let peer_name = hyper::header::HeaderValue::from_maybe_shared({
+                username.data.clone()
+            }).ok()?;
+            headers.insert(PEER_NAME, peer_name);
+        }
+        Some((peer, headers))
+    }
+
+    fn handle(
+        &self,
+        packet: btp::Packet,
+        peer: &Arc<ConnectorPeer>,
+        headers: &hyper::HeaderMap,
+        traffic: &PeerTraffic,
+        sender: &mpsc::UnboundedSender<Message>,
+    ) {
+        let request_id = packet.request_id();
+        let ilp_data = match &packet {
+            btp::Packet::Message { .. } => packet.protocol(btp::PROTOCOL_ILP),
+            btp::Packet::Transfer { .. } => {
+                traffic.sent(send(sender, &make_error(
+                    request_id,
+                    "NotAcceptedError",
+                    b"transfers are not supported",
+                )));
+                return;
+            },
+            btp::Packet::Response { .. } | btp::Packet::Error { .. } => {
+                debug!("unexpected BTP response: request_id={}", request_id);
+                return;
+            },
+        };
+        let ilp_data = match ilp_data {
+            Some(ilp_data) => BytesMut::from(&ilp_data.data[..]),
+            None => {
+                traffic.sent(send(sender, &make_error(
+                    request_id,
+                    "NotAcceptedError",
+                    b"unsupported protocol",
+                )));
+                return;
+            },
+        };
+        let prepare = match ilp::Prepare::try_from(ilp_data) {
+            Ok(prepare) => prepare,
+            Err(error) => {
+                warn!("error parsing incoming BTP prepare: error={:?}", error);
+                traffic.sent(send(sender, &make_error(
+                    request_id,
+                    "InvalidFieldsError",
+                    b"error parsing ILP Prepare",
+                )));
+                return;
+            },
+        };
+
+        let sender = sender.clone();
+        let traffic = traffic.clone();
+        let request = RequestWithHeaders {
+            prepare,
+            headers: headers.clone(),
+            peer: Some(Arc::clone(peer)),
+            client_certificate: self.client_certificate.clone(),
+            request_id: RequestId::generate(),
+        };
+        tokio::spawn(self.service.clone().call(request).map(move |result| {
+            let ilp_data = match result {
+                Ok(fulfill) => BytesMut::from(fulfill),
+                Err(reject) => BytesMut::from(reject),
+            };
+            traffic.sent(send(&sender, &btp::Packet::Response {
+                request_id,
+                protocol_data: vec![btp::ProtocolData::new(
+                    btp::PROTOCOL_ILP,
+                    btp::ContentType::OctetStream,
+                    ilp_data.freeze(),
+                )],
+            }));
+        }));
+    }
+}
+
+#[cfg(test)]
+mod test_btp_receiver {
+    use bytes::Bytes;
+    use tokio::net::TcpStream;
+
+    use crate::RequestWithPeerName;
+    use crate::testing::{self, FULFILL, PREPARE, PanicService};
+    use super::*;
+
+    async fn connect(addr: std::net::SocketAddr) -> WebSocketStream<TcpStream> {
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let uri = format!("ws://{}/btp", addr);
+        tokio_tungstenite::client_async(uri.as_str(), tcp).await.unwrap().0
+    }
+
+    fn to_message(packet: btp::Packet) -> Message {
+        Message::Binary(packet.to_bytes().to_vec())
+    }
+
+    fn make_auth(request_id: u32, username: &'static str) -> Message {
+        to_message(btp::Packet::Message {
+            request_id,
+            protocol_data: vec![
+                btp::ProtocolData::new(
+                    btp::PROTOCOL_AUTH,
+                    btp::ContentType::OctetStream,
+                    Bytes::new(),
+                ),
+                btp::ProtocolData::new(
+                    btp::PROTOCOL_AUTH_USERNAME,
+                    btp::ContentType::TextPlainUtf8,
+                    Bytes::from(username),
+                ),
+                btp::ProtocolData::new(
+                    btp::PROTOCOL_AUTH_TOKEN,
+                    btp::ContentType::TextPlainUtf8,
+                    Bytes::from("btp_secret"),
+                ),
+            ],
+        })
+    }
+
+    #[tokio::test]
+    async fn test_session() {
+        let addr = testing::serve_btp(|request: RequestWithHeaders| {
+            assert_eq!(request.peer_name(), Some(&b"carl"[..]));
+            future::ok(FULFILL.clone())
+        });
+        let mut socket = connect(addr).await;
+
+        socket.send(make_auth(1, "carl")).await.unwrap();
+        assert_eq!(
+            read_packet(&mut socket).await,
+            Some(btp::Packet::Response { request_id: 1, protocol_data: vec![] }),
+        );
+
+        socket.send(to_message(btp::Packet::Message {
+            request_id: 2,
+            protocol_data: vec![btp::ProtocolData::new(
+                btp::PROTOCOL_ILP,
+                btp::ContentType::OctetStream,
+                Bytes::copy_from_slice(PREPARE.as_ref()),
+            )],
+        })).await.unwrap();
+        let response = read_packet(&mut socket).await.unwrap();
+        assert_eq!(response.request_id(), 2);
+        assert_eq!(
+            response.protocol(btp::PROTOCOL_ILP).unwrap().data.as_ref(),
+            FULFILL.as_ref(),
+        );
+
+        // Transfers aren't supported.
+        socket.send(to_message(btp::Packet::Transfer {
+            request_id: 3,
+            amount: 100,
+            protocol_data: vec![],
+        })).await.unwrap();
+        match read_packet(&mut socket).await {
+            Some(btp::Packet::Error { request_id, error }) => {
+                assert_eq!(request_id, 3);
+                assert_eq!(error.name, "NotAcceptedError");
+            },
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+    }
+
+    #[tokio::test]
+    async fn test_auth_required() {
+        let addr = testing::serve_btp(PanicService);
+        let mut socket = connect(addr).await;
+
+        // The first message must authenticate.
+        socket.send(to_message(btp::Packet::Message {
+            request_id: 1,
+            protocol_data: vec![btp::ProtocolData::new(
+                btp::PROTOCOL_ILP,
+                btp::ContentType::OctetStream,
+                Bytes::copy_from_slice(PREPARE.as_ref()),
+            )],
+        })).await.unwrap();
+        match read_packet(&mut socket).await {
+            Some(btp::Packet::Error { request_id, error }) => {
+                assert_eq!(request_id, 1);
+                assert_eq!(error.code, ilp::ErrorCode::F00_BAD_REQUEST);
+            },
+            packet => panic!("unexpected packet: {:?}", packet),
+        }
+        assert_eq!(read_packet(&mut socket).await, None);
+    }
+
+    #[tokio::test]
+    async fn test_http() {
+        let addr = testing::serve_btp(PanicService);
+        let client = hyper::Client::new();
+
+        let uri = format!("http://{}/ilp", addr).parse::<hyper::Uri>().unwrap();
+        let response = client.get(uri).await.unwrap();
+        assert_eq!(response.status(), 404);
+
+        // Not a WebSocket upgrade:
+        let uri = format!("http://{}/btp", addr).parse::<hyper::Uri>().unwrap();
+        let response = client.get(uri).await.unwrap();
+        assert_eq!(response.status(), 400);
+    }
+}
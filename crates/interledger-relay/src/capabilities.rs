@@ -0,0 +1,148 @@
+//! Peer capability discovery. Each connector advertises the features that it
+//! supports in response to an `OPTIONS` request (see `CapabilitiesFilter`).
+//! When `peer_discovery` is enabled, the `Client` fetches its next hops'
+//! capabilities, and adapts its requests to them.
+
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+use std::time;
+
+use serde::{Deserialize, Serialize};
+
+use crate::middlewares::MAX_REQUEST_SIZE;
+
+/// How long a next hop's capabilities are used before they are fetched again.
+const DISCOVERY_TTL: time::Duration = time::Duration::from_secs(5 * 60);
+
+/// The features that a connector supports. Missing fields (e.g. from an older
+/// version, or a connector that doesn't advertise anything at all) default to
+/// the behavior from before discovery existed.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize, Serialize)]
+#[serde(default)]
+pub struct Capabilities {
+    /// The largest Prepare (in bytes) that the connector accepts.
+    pub max_packet_size: Option<usize>,
+    /// Whether the connector honors the `ILP-Deadline` header (from trusted
+    /// peers).
+    pub deadline_header: bool,
+    /// Whether the connector accepts multiple Prepares per request.
+    pub batching: bool,
+    /// The `Content-Encoding`s that the connector accepts.
+    pub compression: Vec<String>,
+}
+
+impl Capabilities {
+    /// The features of this connector. It neither batches nor compresses
+    /// packets, so those are never advertised.
+    pub(crate) fn local() -> Self {
+        Capabilities {
+            max_packet_size: Some(MAX_REQUEST_SIZE),
+            deadline_header: true,
+            batching: false,
+            compression: Vec::new(),
+        }
+    }
+}
+
+/// The discovered capabilities of the next hops, keyed by authority.
+#[derive(Debug, Default)]
+pub(crate) struct CapabilityCache {
+    entries: RwLock<HashMap<String, CacheEntry>>,
+}
+
+#[derive(Debug)]
+struct CacheEntry {
+    /// `None` until the first discovery request finishes.
+    capabilities: Option<Arc<Capabilities>>,
+    checked_at: time::Instant,
+}
+
+impl CapabilityCache {
+    /// The host's capabilities (if they are known), and whether the caller
+    /// should fetch them. Only one caller at a time is told to fetch them; it
+    /// must then `set` them, even if the request failed.
+    pub(crate) fn get(&self, host: &str) -> (Option<Arc<Capabilities>>, bool) {
+        self.get_at(host, time::Instant::now())
+    }
+
+    pub(crate) fn set(&self, host: &str, capabilities: Capabilities) {
+        let mut entries = self.entries.write().unwrap();
+        entries.insert(host.to_owned(), CacheEntry {
+            capabilities: Some(Arc::new(capabilities)),
+            checked_at: time::Instant::now(),
+        });
+    }
+
+    fn get_at(&self, host: &str, now: time::Instant)
+        -> (Option<Arc<Capabilities>>, bool)
+    {
+        {
+            let entries = self.entries.read().unwrap();
+            if let Some(entry) = entries.get(host) {
+                if now < entry.checked_at + DISCOVERY_TTL {
+                    return (entry.capabilities.clone(), false);
+                }
+            }
+        }
+        let mut entries = self.entries.write().unwrap();
+        let mut is_new = false;
+        let entry = entries.entry(host.to_owned()).or_insert_with(|| {
+            is_new = true;
+            CacheEntry { capabilities: None, checked_at: now }
+        });
+        // Another request may have claimed the fetch in the meantime.
+        let fetch = is_new || entry.checked_at + DISCOVERY_TTL <= now;
+        if fetch {
+            // The stale capabilities are still used until the fetch finishes.
+            entry.checked_at = now;
+        }
+        (entry.capabilities.clone(), fetch)
+    }
+}
+
+#[cfg(test)]
+mod test_capability_cache {
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        let cache = CapabilityCache::default();
+        let start = time::Instant::now();
+        // Only the first request fetches the capabilities.
+        assert_eq!(cache.get_at("peer.example", start), (None, true));
+        assert_eq!(cache.get_at("peer.example", start), (None, false));
+        assert_eq!(cache.get_at("other.example", start), (None, true));
+
+        cache.set("peer.example", Capabilities::local());
+        let local = Some(Arc::new(Capabilities::local()));
+        assert_eq!(cache.get("peer.example"), (local.clone(), false));
+
+        // Once they are stale, they are fetched again (once), and the stale
+        // capabilities are used in the meantime.
+        let later = time::Instant::now() + DISCOVERY_TTL;
+        assert_eq!(cache.get_at("peer.example", later), (local.clone(), true));
+        assert_eq!(cache.get_at("peer.example", later), (local, false));
+    }
+
+    #[test]
+    fn test_deserialize() {
+        assert_eq!(
+            serde_json::from_str::<Capabilities>(r#"{
+                "max_packet_size": 1000,
+                "deadline_header": true,
+                "compression": ["gzip"],
+                "unknown_feature": true
+            }"#).unwrap(),
+            Capabilities {
+                max_packet_size: Some(1000),
+                deadline_header: true,
+                batching: false,
+                compression: vec!["gzip".to_owned()],
+            },
+        );
+        assert_eq!(
+            serde_json::from_str::<Capabilities>("{}").unwrap(),
+            Capabilities::default(),
+        );
+    }
+}
@@ -1,20 +1,30 @@
+use std::error::Error as StdError;
+use std::fmt;
+use std::pin::Pin;
 use std::str;
 use std::sync::Arc;
+use std::time;
 
 use bytes::{Bytes, BytesMut};
-use futures::future::{Either, err, ok};
 use futures::prelude::*;
 use hyper::{Response, StatusCode};
-use hyper::client::HttpConnector;
-use hyper_tls::HttpsConnector;
-use log::warn;
+use log::debug;
 
+use crate::{RequestId, random_fraction};
+use crate::btp::{BtpClient, BtpError};
+use crate::capabilities::{Capabilities, CapabilityCache};
+use crate::client_pool::{
+    ClientPool, ClientPoolConfig, HyperClient, OUTGOING_REQUESTS,
+};
 use crate::combinators;
-
-type HyperClient = hyper::Client<HttpsConnector<HttpConnector>, hyper::Body>;
+use crate::connector_info::ConnectorInfo;
+use crate::metrics::Metrics;
+use crate::packets::{DEADLINE_HEADER, REQUEST_ID_HEADER};
+use crate::reject_reasons::{self, RejectReason};
+use crate::transport::{Endpoint, Headers, HttpVersion, NextHopClient, NextHopError, NextHopRequest, PeerResponse, ProbeRequest, RetryPolicy};
 
 // Use the size of a Reject, since they can be larger than Fulfills.
-const MAX_RESPONSE_SIZE: usize = {
+pub(crate) const MAX_RESPONSE_SIZE: usize = {
     const ENVELOPE: usize = 1 + 8;
     const CODE: usize = 3;
     const TRIGGERED_BY: usize = 8 + 1024;
@@ -24,11 +34,20 @@ const MAX_RESPONSE_SIZE: usize = {
 };
 
 static OCTET_STREAM: &[u8] = b"application/octet-stream";
+/// Give up on fetching a next hop's capabilities after this long.
+const DISCOVERY_TIMEOUT: time::Duration = time::Duration::from_secs(5);
+/// The largest capabilities document that is accepted.
+const MAX_CAPABILITIES_SIZE: usize = 4096;
+static OUTGOING_REQUEST_ERRORS: &str = "ilp_relay_outgoing_request_errors_total";
 
 #[derive(Clone, Debug)]
 pub struct Client {
-    address: ilp::Address,
-    hyper: Arc<HyperClient>,
+    connector: ConnectorInfo,
+    pool: Arc<ClientPool>,
+    btp: BtpClient,
+    metrics: Option<Arc<Metrics>>,
+    /// Set when peer discovery is enabled.
+    capabilities: Option<Arc<CapabilityCache>>,
 }
 
 #[derive(Clone, Debug)]
@@ -37,11 +56,20 @@ pub struct RequestOptions {
     pub uri: hyper::Uri,
     pub auth: Option<Bytes>,
     pub peer_name: Option<Bytes>,
+    /// Extra headers, from the route's next hop.
+    pub headers: Option<hyper::HeaderMap>,
+    pub retry: Arc<RetryPolicy>,
+    pub http_version: HttpVersion,
+    /// Give up on the request (including any retries) after this long, and
+    /// reject the Prepare with `R00_TRANSFER_TIMED_OUT`.
+    pub response_timeout: Option<time::Duration>,
+    /// Sent as the `X-Request-Id` header, and included in the logs.
+    pub request_id: RequestId,
 }
 
 impl RequestOptions {
     // This _shouldn't_ ever return an error.
-    fn build(&self, prepare: Bytes)
+    fn build(&self, prepare: Bytes, deadline: Option<time::SystemTime>)
         -> Result<hyper::Request<hyper::Body>, hyper::header::InvalidHeaderValue>
     {
         use hyper::header::HeaderValue;
@@ -60,7 +88,22 @@ impl RequestOptions {
                 HeaderValue::from_maybe_shared(peer_name.clone())?,
             );
         }
+        if let (Some(headers), Some(request_headers)) =
+            (&self.headers, builder.headers_mut())
+        {
+            for (name, value) in headers {
+                request_headers.append(name.clone(), value.clone());
+            }
+        }
+        if let Some(deadline) = deadline {
+            builder = builder.header(
+                DEADLINE_HEADER,
+                chrono::DateTime::<chrono::Utc>::from(deadline)
+                    .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            );
+        }
         Ok(builder
+            .header(REQUEST_ID_HEADER, self.request_id.as_str())
             .header(hyper::header::CONTENT_TYPE, OCTET_STREAM)
             .body(hyper::Body::from(prepare))
             .expect("RequestOptions::build error"))
@@ -68,81 +111,299 @@ impl RequestOptions {
 }
 
 impl Client {
-    pub fn new(address: ilp::Address) -> Self {
-        let agent = hyper_tls::HttpsConnector::new();
-        let client = hyper::Client::builder().build(agent);
-        Client::new_with_client(address, client)
+    pub fn new(connector: impl Into<ConnectorInfo>) -> Self {
+        Client::new_with_pool(connector, &ClientPoolConfig::default(), None)
+    }
+
+    /// When `metrics` is set, outgoing requests and connections are counted.
+    pub fn new_with_pool(
+        connector: impl Into<ConnectorInfo>,
+        pool: &ClientPoolConfig,
+        metrics: Option<Arc<Metrics>>,
+    ) -> Self {
+        Client {
+            connector: connector.into(),
+            pool: Arc::new(ClientPool::new(pool, metrics.clone())),
+            btp: BtpClient::default(),
+            metrics,
+            capabilities: None,
+        }
     }
 
-    pub fn new_with_client(address: ilp::Address, hyper: HyperClient) -> Self {
+    /// `hyper` is used for every request, regardless of its `http_version`.
+    pub fn new_with_client(
+        connector: impl Into<ConnectorInfo>,
+        hyper: HyperClient,
+    ) -> Self {
         Client {
-            address,
-            hyper: Arc::new(hyper),
+            connector: connector.into(),
+            pool: Arc::new(ClientPool::with_client(hyper)),
+            btp: BtpClient::default(),
+            metrics: None,
+            capabilities: None,
         }
     }
 
-    pub fn address(&self) -> &ilp::Address {
-        &self.address
+    /// Fetch the capabilities of each HTTP next hop (with an `OPTIONS`
+    /// request), and adapt the requests to them.
+    pub fn with_peer_discovery(mut self) -> Self {
+        self.capabilities = Some(Arc::new(CapabilityCache::default()));
+        self
+    }
+
+    /// The outgoing connections, for the admin API.
+    pub fn pool(&self) -> &Arc<ClientPool> {
+        &self.pool
     }
 
     /// `req_builder` is the base request.
     /// The URI and method should be set, along with extra headers.
     /// `Content-Type` and `Content-Length` should not be set.
+    ///
+    /// Failures (e.g. connection errors) are rejected by this connector.
     pub fn request(self, req_opts: RequestOptions, prepare: ilp::Prepare)
         -> impl Future<Output = Result<ilp::Fulfill, ilp::Reject>>
     {
-        let prepare_bytes = BytesMut::from(prepare).freeze();
-        let prepare_bytes2 = prepare_bytes.clone();
+        let connector = self.connector.clone();
+        self.try_request(req_opts, prepare)
+            .map(move |result| result.unwrap_or_else(|error| {
+                Err(error.to_reject(connector.address().as_addr()))
+            }))
+    }
+
+    /// Like `request`, but failures are returned as a `ClientError`, so that
+    /// the caller can tell them apart from the next hop's Rejects.
+    pub(crate) fn try_request(self, req_opts: RequestOptions, prepare: ilp::Prepare)
+        -> impl Future<Output = Result<PeerResponse, ClientError>>
+    {
+        let metrics = self.metrics.clone();
+        let response_timeout = match req_opts.response_timeout {
+            Some(response_timeout) => response_timeout,
+            None => return self.send_request(req_opts, prepare)
+                .inspect_err(move |error| record_error(&metrics, error))
+                .left_future(),
+        };
         let uri = req_opts.uri.clone();
-        let hyper = Arc::clone(&self.hyper);
-
-        let request =
-            match req_opts.build(prepare_bytes.clone()) {
-                Ok(request) => request,
-                Err(_error) => return Either::Right(err({
-                    self.make_invalid_header_value_reject()
-                })),
-            };
-        Either::Left(self.hyper
-            .request(request)
-            .and_then(move |response| {
-                // When the first attempt to send the packet failed with a 502,
-                // retry once. The 502 is probably caused by the hidden request/
-                // connection limit described in <https://github.com/interledgerjs/ilp-plugin-http/pull/3>.
-                if response.status() == hyper::StatusCode::BAD_GATEWAY {
-                    warn!(
-                        "remote error; retrying: uri=\"{}\" status={:?}",
-                        req_opts.uri, response.status(),
+        let request_id = req_opts.request_id.clone();
+        tokio::time::timeout(
+            response_timeout,
+            self.send_request(req_opts, prepare),
+        )
+            .map(move |result| result.unwrap_or_else(|_elapsed| {
+                throttled_warn!(
+                    next_hop(&uri),
+                    "outgoing request timed out: request_id={} uri=\"{}\" timeout={:?}",
+                    request_id, uri, response_timeout,
+                );
+                Err(ClientError::Timeout)
+            }))
+            .inspect_err(move |error| record_error(&metrics, error))
+            .right_future()
+    }
+
+    fn send_request(self, req_opts: RequestOptions, prepare: ilp::Prepare)
+        -> impl Future<Output = Result<PeerResponse, ClientError>>
+    {
+        let expires_at = prepare.expires_at();
+        let prepare_bytes = BytesMut::from(prepare).freeze();
+        async move {
+            let uri = req_opts.uri.clone();
+            let request_id = &req_opts.request_id;
+            let retry = &req_opts.retry;
+            // The connector doesn't offer HTTP/2 via ALPN, so `Adaptive` and
+            // `Http1Only` are (currently) the same.
+            let http2_only = req_opts.http_version == HttpVersion::Http2Only;
+            let capabilities = self.peer_capabilities(&req_opts, http2_only);
+            let max_packet_size = capabilities
+                .as_ref()
+                .and_then(|capabilities| capabilities.max_packet_size);
+            if let Some(max_packet_size) = max_packet_size {
+                if prepare_bytes.len() > max_packet_size {
+                    throttled_warn!(
+                        next_hop(&uri),
+                        "prepare too large for next hop: request_id={} uri=\"{}\" size={} max_packet_size={}",
+                        request_id, uri, prepare_bytes.len(), max_packet_size,
                     );
-                    let request = req_opts.build(prepare_bytes2).unwrap();
-                    Either::Left(hyper.request(request))
-                } else {
-                    Either::Right(ok(response))
+                    return Err(ClientError::PacketTooLarge);
                 }
-            })
-            .then(move |response| match response {
-                Ok(response) => Either::Left({
-                    self.decode_http_response(uri, response, prepare_bytes)
-                }),
+            }
+            // Tell the next hop when this request will be given up on.
+            let deadline = match (&capabilities, req_opts.response_timeout) {
+                (Some(capabilities), Some(response_timeout))
+                    if capabilities.deadline_header =>
+                    Some(time::SystemTime::now() + response_timeout),
+                _ => None,
+            };
+            let _in_flight = self.pool.start_request(&uri);
+            let mut attempt = 1;
+            loop {
+                let request = req_opts
+                    .build(prepare_bytes.clone(), deadline)
+                    .map_err(ClientError::InvalidHeader)?;
+                let hyper = self.pool.client(&uri, http2_only);
+                if let Some(metrics) = &self.metrics {
+                    metrics.increment(OUTGOING_REQUESTS, vec![], 1);
+                }
+                let response = hyper.request(request).await;
+
+                let is_retryable = match &response {
+                    Ok(response) => retry.is_retryable_status(response.status().as_u16()),
+                    Err(_error) => retry.connection_errors,
+                };
+                let delay = retry.backoff(attempt, random_fraction());
+                // Never retry if the Prepare would expire before the retry.
+                let is_expired = time::SystemTime::now() + delay >= expires_at;
+                if is_retryable && attempt < retry.max_attempts && !is_expired {
+                    match &response {
+                        Ok(response) => throttled_warn!(
+                            next_hop(&uri),
+                            "remote error; retrying: request_id={} uri=\"{}\" status={:?} attempt={} delay={:?}",
+                            request_id, uri, response.status(), attempt, delay,
+                        ),
+                        Err(error) => throttled_warn!(
+                            next_hop(&uri),
+                            "outgoing connection error; retrying: request_id={} uri=\"{}\" error=\"{}\" attempt={} delay={:?}",
+                            request_id, uri, error, attempt, delay,
+                        ),
+                    }
+                    tokio::time::delay_for(delay).await;
+                    attempt += 1;
+                    continue;
+                }
+
+                return match response {
+                    Ok(response) => {
+                        self.decode_http_response(
+                            request_id,
+                            uri,
+                            response,
+                            prepare_bytes,
+                        ).await
+                    },
+                    Err(error) => {
+                        throttled_warn!(
+                            next_hop(&uri),
+                            "outgoing connection error: request_id={} uri=\"{}\" error=\"{}\"",
+                            request_id, uri, error,
+                        );
+                        Err(ClientError::from_hyper(error))
+                    },
+                };
+            }
+        }
+    }
+
+    /// The next hop's capabilities, when peer discovery is enabled and they are
+    /// known. They are fetched in the background, so the first request to a
+    /// next hop isn't delayed (and isn't adapted).
+    ///
+    /// The discovery request carries the route's `auth` and extra headers, since
+    /// the next hop may not answer unauthenticated requests.
+    fn peer_capabilities(&self, req_opts: &RequestOptions, http2_only: bool)
+        -> Option<Arc<Capabilities>>
+    {
+        let cache = self.capabilities.as_ref()?;
+        let uri = &req_opts.uri;
+        let (capabilities, fetch) = cache.get(next_hop(uri));
+        if fetch {
+            tokio::spawn(discover_capabilities(
+                Arc::clone(cache),
+                self.pool.client(uri, http2_only),
+                uri.clone(),
+                req_opts.auth.clone(),
+                req_opts.headers.clone(),
+            ));
+        }
+        capabilities
+    }
+
+    /// Send the Prepare over the BTP connection to `uri` (a `ws://` or `wss://`
+    /// URI). The connection is opened by the first request to `uri`.
+    ///
+    /// BTP has no headers, so `request_id` is only used in the logs.
+    pub(crate) fn request_btp(
+        self,
+        uri: hyper::Uri,
+        auth: Option<Bytes>,
+        request_id: RequestId,
+        prepare: ilp::Prepare,
+    ) -> impl Future<Output = Result<PeerResponse, ClientError>> {
+        let prepare_bytes = BytesMut::from(prepare).freeze();
+        async move {
+            let result = match self.btp.request(&uri, auth, prepare_bytes).await {
+                Ok(response) => {
+                    self.decode_response(
+                        &request_id,
+                        uri,
+                        BytesMut::from(&response[..]),
+                    )
+                },
                 Err(error) => {
-                    warn!(
-                        "outgoing connection error: uri=\"{}\" error=\"{}\"",
-                        uri, error,
-                    );
-                    Either::Right(err(self.make_reject(
-                        ilp::ErrorCode::T01_PEER_UNREACHABLE,
-                        b"peer connection error",
-                    )))
+                    match &error {
+                        BtpError::Remote(details) => throttled_warn!(
+                            next_hop(&uri),
+                            "remote BTP error: request_id={} uri=\"{}\" code={} name={:?}",
+                            request_id, uri, details.code, details.name,
+                        ),
+                        _ => throttled_warn!(
+                            next_hop(&uri),
+                            "outgoing BTP connection error: request_id={} uri=\"{}\" error=\"{}\"",
+                            request_id, uri, error,
+                        ),
+                    }
+                    Err(ClientError::Btp(error))
                 },
-            }))
+            };
+            if let Err(error) = &result {
+                record_error(&self.metrics, error);
+            }
+            result
+        }
+    }
+
+    /// Send a `HEAD` request to `uri`, to check that the next hop is up. Any
+    /// response other than a 5xx succeeds, since the endpoint may not allow
+    /// the method.
+    pub(crate) async fn probe_head(
+        self,
+        uri: hyper::Uri,
+        auth: Option<Bytes>,
+        headers: Option<hyper::HeaderMap>,
+        http_version: HttpVersion,
+    ) -> Result<(), ClientError> {
+        let mut builder = hyper::Request::head(&uri);
+        if let Some(auth) = auth {
+            builder = builder.header(
+                hyper::header::AUTHORIZATION,
+                hyper::header::HeaderValue::from_maybe_shared(auth)
+                    .map_err(ClientError::InvalidHeader)?,
+            );
+        }
+        if let (Some(headers), Some(request_headers)) =
+            (headers, builder.headers_mut())
+        {
+            request_headers.extend(headers);
+        }
+        let request = builder
+            .body(hyper::Body::empty())
+            .expect("probe_head request builder error");
+        let http2_only = http_version == HttpVersion::Http2Only;
+        let hyper = self.pool.client(&uri, http2_only);
+        let response = hyper.request(request).await
+            .map_err(ClientError::from_hyper)?;
+        if response.status().is_server_error() {
+            return Err(ClientError::Status(response.status()));
+        }
+        Ok(())
     }
 
     async fn decode_http_response(
         self,
+        request_id: &RequestId,
         uri: hyper::Uri,
         response: Response<hyper::Body>,
         prepare: Bytes,
-    ) -> Result<ilp::Fulfill, ilp::Reject> {
+    ) -> Result<PeerResponse, ClientError> {
         let status = response.status();
         let (parts, body) = response.into_parts();
         let res_body = combinators::collect_http_body(
@@ -150,20 +411,17 @@ impl Client {
             body,
             MAX_RESPONSE_SIZE,
         ).await;
-        // TODO timeout if response takes too long?
         let body = res_body.map_err(|error| {
-            warn!(
-                "remote response body error: uri=\"{}\" error={:?}",
-                uri, error,
+            throttled_warn!(
+                next_hop(&uri),
+                "remote response body error: request_id={} uri=\"{}\" error={:?}",
+                request_id, uri, error,
             );
-            self.make_reject(
-                ilp::ErrorCode::T00_INTERNAL_ERROR,
-                b"invalid response body from peer",
-            )
+            ClientError::Decode
         })?;
 
         if status == StatusCode::OK {
-            return self.decode_response(uri, body);
+            return self.decode_response(request_id, uri, body);
         }
 
         const TRUNCATE_BODY: usize = 64;
@@ -171,66 +429,316 @@ impl Client {
         let body_str = body_str.map(|s| truncate(s, TRUNCATE_BODY));
         let prepare_str = base64::encode(&prepare);
 
-        Err(if status.is_client_error() {
-            warn!(
-                "remote client error: uri=\"{}\" status={:?} body={:?} prepare={:?}",
-                uri, status, body_str, prepare_str,
+        if status.is_client_error() {
+            throttled_warn!(
+                next_hop(&uri),
+                "remote client error: request_id={} uri=\"{}\" status={:?} body={:?} prepare={:?}",
+                request_id, uri, status, body_str, prepare_str,
             );
-            self.make_reject(
-                ilp::ErrorCode::F00_BAD_REQUEST,
-                b"bad request to peer",
-            )
         } else if status.is_server_error() {
-            warn!(
-                "remote server error: uri=\"{}\" status={:?} body={:?} prepare={:?}",
-                uri, status, body_str, prepare_str,
+            throttled_warn!(
+                next_hop(&uri),
+                "remote server error: request_id={} uri=\"{}\" status={:?} body={:?} prepare={:?}",
+                request_id, uri, status, body_str, prepare_str,
             );
-            self.make_reject(
-                ilp::ErrorCode::T01_PEER_UNREACHABLE,
-                b"peer internal error",
-            )
         } else {
-            warn!(
-                "unexpected status code: uri=\"{}\" status={:?} body={:?} prepare={:?}",
-                uri, status, body_str, prepare_str,
+            throttled_warn!(
+                next_hop(&uri),
+                "unexpected status code: request_id={} uri=\"{}\" status={:?} body={:?} prepare={:?}",
+                request_id, uri, status, body_str, prepare_str,
             );
-            self.make_reject(
-                ilp::ErrorCode::T00_INTERNAL_ERROR,
-                b"unexpected response code from peer",
-            )
-        })
+        }
+        Err(ClientError::Status(status))
     }
 
-    fn decode_response(&self, uri: hyper::Uri, bytes: BytesMut)
-        -> Result<ilp::Fulfill, ilp::Reject>
-    {
+    fn decode_response(
+        &self,
+        request_id: &RequestId,
+        uri: hyper::Uri,
+        bytes: BytesMut,
+    ) -> Result<PeerResponse, ClientError> {
         match ilp::Packet::try_from(bytes) {
-            Ok(ilp::Packet::Fulfill(fulfill)) => Ok(fulfill),
-            Ok(ilp::Packet::Reject(reject)) => Err(reject),
+            Ok(ilp::Packet::Fulfill(fulfill)) => Ok(Ok(fulfill)),
+            Ok(ilp::Packet::Reject(reject)) => Ok(Err(reject)),
             _ => {
-                warn!("invalid response body: uri=\"{}\"", uri);
-                Err(self.make_reject(
-                    ilp::ErrorCode::T00_INTERNAL_ERROR,
-                    b"invalid response body from peer",
-                ))
+                throttled_warn!(
+                    next_hop(&uri),
+                    "invalid response body: request_id={} uri=\"{}\"",
+                    request_id, uri,
+                );
+                Err(ClientError::Decode)
             },
         }
     }
+}
+
+impl NextHopClient for Client {
+    fn send(&self, request: NextHopRequest) -> Pin<Box<
+        dyn Future<Output = Result<PeerResponse, NextHopError>> + Send + 'static,
+    >> {
+        let uri = endpoint_uri(&request.endpoint);
+        let auth = request.auth.map(Bytes::from);
+        let response = if request.is_btp {
+            self.clone()
+                .request_btp(uri, auth, request.request_id, request.prepare)
+                .left_future()
+        } else {
+            self.clone()
+                .try_request(RequestOptions {
+                    method: hyper::Method::POST,
+                    uri,
+                    auth,
+                    peer_name: None,
+                    headers: request.headers.as_ref().map(header_map),
+                    retry: request.retry,
+                    http_version: request.http_version,
+                    response_timeout: request.response_timeout,
+                    request_id: request.request_id,
+                }, request.prepare)
+                .right_future()
+        };
+        Box::pin(response.map_err(NextHopError::from))
+    }
+
+    fn probe(&self, request: ProbeRequest) -> Pin<Box<
+        dyn Future<Output = Result<(), NextHopError>> + Send + 'static,
+    >> {
+        Box::pin(self.clone()
+            .probe_head(
+                endpoint_uri(&request.endpoint),
+                request.auth.map(Bytes::from),
+                request.headers.as_ref().map(header_map),
+                request.http_version,
+            )
+            .map_err(NextHopError::from))
+    }
+}
+
+/// `Endpoint`s are always valid URIs.
+fn endpoint_uri(endpoint: &Endpoint) -> hyper::Uri {
+    endpoint.as_str()
+        .parse::<hyper::Uri>()
+        .expect("invalid endpoint")
+}
+
+/// `Headers` are always valid header names and values.
+fn header_map(headers: &Headers) -> hyper::HeaderMap {
+    headers
+        .iter()
+        .map(|(name, value)| (
+            hyper::header::HeaderName::from_bytes(name.as_bytes())
+                .expect("invalid header name"),
+            hyper::header::HeaderValue::from_maybe_shared(value.clone())
+                .expect("invalid header value"),
+        ))
+        .collect()
+}
+
+/// Why an outgoing request failed to get a Fulfill or Reject from the next hop.
+///
+/// Callers that need to tell failures apart (e.g. failover) use this, and
+/// convert it to a Reject (`to_reject`) at the edge.
+#[derive(Debug)]
+pub(crate) enum ClientError {
+    /// A header of the request (e.g. the route's `auth`) isn't a valid HTTP
+    /// header value.
+    InvalidHeader(hyper::header::InvalidHeaderValue),
+    /// The connection to the next hop failed (including retries), or was
+    /// aborted.
+    Connect(hyper::Error),
+    /// The TLS handshake with the next hop failed.
+    Tls(hyper::Error),
+    /// The next hop didn't respond within the route's `response_timeout`.
+    Timeout,
+    /// The BTP connection to the next hop failed, or it responded with a BTP
+    /// Error.
+    Btp(BtpError),
+    /// The next hop responded with a status other than `200`.
+    Status(StatusCode),
+    /// The response body couldn't be read, or isn't a Fulfill or Reject.
+    Decode,
+    /// The Prepare is larger than the next hop's advertised `max_packet_size`.
+    PacketTooLarge,
+}
+
+impl ClientError {
+    /// Tell TLS failures apart from other connection errors.
+    fn from_hyper(error: hyper::Error) -> Self {
+        let mut source = error.source();
+        while let Some(inner) = source {
+            if inner.is::<native_tls::Error>() {
+                return ClientError::Tls(error);
+            }
+            source = inner.source();
+        }
+        ClientError::Connect(error)
+    }
+
+    /// The category, used as the `kind` label of the metrics.
+    pub fn kind(&self) -> &'static str {
+        match self {
+            ClientError::InvalidHeader(_) => "invalid_header",
+            ClientError::Connect(_) => "connect",
+            ClientError::Tls(_) => "tls",
+            ClientError::Btp(BtpError::Tls(_)) => "tls",
+            ClientError::Timeout => "timeout",
+            ClientError::Btp(_) => "btp",
+            ClientError::Status(_) => "status",
+            ClientError::Decode => "decode",
+            ClientError::PacketTooLarge => "packet_too_large",
+        }
+    }
+
+    /// Whether the next hop appears to be down (i.e. it is a connection error
+    /// or a 5xx), as opposed to e.g. a misconfigured route.
+    pub fn is_peer_unreachable(&self) -> bool {
+        match self {
+            ClientError::Connect(_) | ClientError::Tls(_) | ClientError::Btp(_) =>
+                true,
+            ClientError::Status(status) => status.is_server_error(),
+            _ => false,
+        }
+    }
+
+    pub fn reject_reason(&self) -> &'static RejectReason {
+        match self {
+            ClientError::InvalidHeader(_) => &reject_reasons::INVALID_HEADER_VALUE,
+            ClientError::Connect(_) | ClientError::Tls(_) =>
+                &reject_reasons::PEER_CONNECTION_ERROR,
+            ClientError::Timeout => &reject_reasons::PEER_TIMED_OUT,
+            ClientError::Btp(BtpError::Remote(_)) => &reject_reasons::PEER_BTP_ERROR,
+            ClientError::Btp(_) => &reject_reasons::PEER_CONNECTION_ERROR,
+            ClientError::Status(status) if status.is_client_error() =>
+                &reject_reasons::PEER_BAD_REQUEST,
+            ClientError::Status(status) if status.is_server_error() =>
+                &reject_reasons::PEER_INTERNAL_ERROR,
+            ClientError::Status(_) => &reject_reasons::PEER_UNEXPECTED_STATUS,
+            ClientError::Decode => &reject_reasons::INVALID_PEER_RESPONSE,
+            ClientError::PacketTooLarge => &reject_reasons::PEER_PACKET_TOO_LARGE,
+        }
+    }
+
+    pub fn to_reject(&self, connector_address: ilp::Addr) -> ilp::Reject {
+        self.reject_reason().to_reject(connector_address)
+    }
+}
+
+impl From<ClientError> for NextHopError {
+    fn from(error: ClientError) -> Self {
+        NextHopError::new(
+            error.reject_reason(),
+            error.is_peer_unreachable(),
+            error,
+        )
+    }
+}
 
-    fn make_reject(&self, code: ilp::ErrorCode, message: &[u8]) -> ilp::Reject {
-        ilp::RejectBuilder {
-            code,
-            message,
-            triggered_by: Some(self.address.as_addr()),
-            data: b"",
-        }.build()
+impl StdError for ClientError {
+    fn source(&self) -> Option<&(dyn StdError + 'static)> {
+        match self {
+            ClientError::InvalidHeader(inner) => Some(inner),
+            ClientError::Connect(inner) | ClientError::Tls(inner) => Some(inner),
+            ClientError::Btp(inner) => Some(inner),
+            _ => None,
+        }
     }
+}
 
-    fn make_invalid_header_value_reject(&self) -> ilp::Reject {
-        self.make_reject(ilp::ErrorCode::F00_BAD_REQUEST, b"invalid header value")
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::InvalidHeader(inner) =>
+                write!(f, "invalid header value: {}", inner),
+            ClientError::Connect(inner) => write!(f, "connection error: {}", inner),
+            ClientError::Tls(inner) => write!(f, "TLS error: {}", inner),
+            ClientError::Timeout => f.write_str("response timed out"),
+            ClientError::Btp(inner) => write!(f, "BTP error: {}", inner),
+            ClientError::Status(status) => write!(f, "unexpected status: {}", status),
+            ClientError::Decode => f.write_str("invalid response body"),
+            ClientError::PacketTooLarge =>
+                f.write_str("prepare exceeds the next hop's max_packet_size"),
+        }
     }
 }
 
+/// Fetch the next hop's capabilities. A next hop that doesn't advertise any
+/// (e.g. it responds to `OPTIONS` with `405`) is treated as it was before
+/// discovery, so the request is only retried once the capabilities expire.
+async fn discover_capabilities(
+    cache: Arc<CapabilityCache>,
+    hyper: Arc<HyperClient>,
+    uri: hyper::Uri,
+    auth: Option<Bytes>,
+    headers: Option<hyper::HeaderMap>,
+) {
+    let mut builder = hyper::Request::options(&uri)
+        .header(hyper::header::ACCEPT, "application/json");
+    if let Some(auth) = auth {
+        builder = builder.header(hyper::header::AUTHORIZATION, auth.as_ref());
+    }
+    if let (Some(headers), Some(request_headers)) =
+        (headers, builder.headers_mut())
+    {
+        request_headers.extend(headers);
+    }
+    let fetch = async {
+        let request = builder
+            .body(hyper::Body::empty())
+            .map_err(|error| error.to_string())?;
+        let response = hyper.request(request).await
+            .map_err(|error| error.to_string())?;
+        let status = response.status();
+        let (parts, body) = response.into_parts();
+        let body = combinators::collect_http_body(
+            &parts.headers,
+            body,
+            MAX_CAPABILITIES_SIZE,
+        ).await.map_err(|error| format!("{:?}", error))?;
+        if status != StatusCode::OK {
+            debug!(
+                "next hop doesn't advertise capabilities: uri=\"{}\" status={:?}",
+                uri, status,
+            );
+            return Ok(Capabilities::default());
+        }
+        serde_json::from_slice(&body).map_err(|error| error.to_string())
+    };
+    let result = tokio::time::timeout(DISCOVERY_TIMEOUT, fetch).await
+        .unwrap_or_else(|_elapsed| Err("timed out".to_owned()));
+    let capabilities = result.unwrap_or_else(|error| {
+        throttled_warn!(
+            next_hop(&uri),
+            "error fetching next hop capabilities: uri=\"{}\" error=\"{}\"",
+            uri, error,
+        );
+        Capabilities::default()
+    });
+    debug!(
+        "discovered next hop capabilities: uri=\"{}\" capabilities={:?}",
+        uri, capabilities,
+    );
+    cache.set(next_hop(&uri), capabilities);
+}
+
+/// Count the failed request, by `ClientError::kind`.
+fn record_error(metrics: &Option<Arc<Metrics>>, error: &ClientError) {
+    if let Some(metrics) = metrics {
+        metrics.increment(
+            OUTGOING_REQUEST_ERRORS,
+            vec![("kind", error.kind().to_owned())],
+            1,
+        );
+    }
+}
+
+/// Warnings about a next hop are throttled by its authority, since a dynamic
+/// route has a URI per destination.
+fn next_hop(uri: &hyper::Uri) -> &str {
+    uri.authority()
+        .map(|authority| authority.as_str())
+        .unwrap_or("")
+}
+
 fn truncate(string: &str, size: usize) -> &str {
     if string.len() < size {
         string
@@ -241,8 +749,11 @@ fn truncate(string: &str, size: usize) -> &str {
 
 #[cfg(test)]
 mod tests {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
     use lazy_static::lazy_static;
 
+    use crate::AuthToken;
     use crate::testing::{self, RECEIVER_ORIGIN};
     use super::*;
 
@@ -257,7 +768,7 @@ mod tests {
             ADDRESS.to_address(),
             hyper::Client::builder()
                 .http2_only(true)
-                .build(hyper_tls::HttpsConnector::new()),
+                .build(ClientPoolConfig::default().connector(None)),
         );
 
         static ref REQUEST_OPTIONS: RequestOptions = RequestOptions {
@@ -265,7 +776,20 @@ mod tests {
             uri: hyper::Uri::from_static(RECEIVER_ORIGIN),
             auth: Some(Bytes::from("alice_auth")),
             peer_name: None,
+            headers: None,
+            retry: Arc::new(RetryPolicy::default()),
+            http_version: HttpVersion::Adaptive,
+            response_timeout: None,
+            request_id: RequestId::generate(),
         };
+
+        static ref RETRY_503: Arc<RetryPolicy> = Arc::new(RetryPolicy {
+            max_attempts: 3,
+            backoff_base: time::Duration::from_millis(1),
+            jitter: 0.5,
+            status_codes: vec![503],
+            connection_errors: true,
+        });
     }
 
     #[test]
@@ -286,6 +810,10 @@ mod tests {
                     req.headers().get("Content-Length").unwrap(),
                     &testing::PREPARE.as_ref().len().to_string(),
                 );
+                assert_eq!(
+                    req.headers().get("X-Request-Id").unwrap(),
+                    REQUEST_OPTIONS.request_id.as_str(),
+                );
             })
             .test_body(|body| {
                 assert_eq!(body.as_ref(), testing::PREPARE.as_ref());
@@ -305,6 +833,107 @@ mod tests {
             });
     }
 
+    fn make_next_hop_request() -> NextHopRequest {
+        let mut headers = Headers::default();
+        headers.insert("X-Extra", "extra").unwrap();
+        NextHopRequest {
+            endpoint: format!("{}/alice", RECEIVER_ORIGIN).parse().unwrap(),
+            is_btp: false,
+            auth: Some(AuthToken::new("alice_auth")),
+            headers: Some(headers),
+            retry: Arc::new(RetryPolicy::default()),
+            http_version: HttpVersion::Adaptive,
+            response_timeout: None,
+            request_id: RequestId::generate(),
+            prepare: testing::PREPARE.clone(),
+        }
+    }
+
+    #[test]
+    fn test_next_hop_send() {
+        testing::MockServer::new()
+            .test_request(|req| {
+                assert_eq!(req.method(), hyper::Method::POST);
+                assert_eq!(req.uri().path(), "/alice");
+                assert_eq!(
+                    req.headers().get("Authorization").unwrap(),
+                    "alice_auth",
+                );
+                assert_eq!(req.headers().get("X-Extra").unwrap(), "extra");
+                assert_eq!(
+                    req.headers().get("Content-Type").unwrap(),
+                    "application/octet-stream",
+                );
+            })
+            .test_body(|body| {
+                assert_eq!(body.as_ref(), testing::PREPARE.as_ref());
+            })
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(200)
+                    .body(hyper::Body::from(testing::REJECT.as_ref()))
+                    .unwrap()
+            })
+            .run({
+                CLIENT.send(make_next_hop_request()).map(|result| {
+                    assert_eq!(result.unwrap(), Err(testing::REJECT.clone()));
+                })
+            });
+    }
+
+    #[test]
+    fn test_next_hop_send_error() {
+        testing::MockServer::new()
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(500)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })
+            .run({
+                CLIENT.send(make_next_hop_request()).map(|result| {
+                    let error = result.unwrap_err();
+                    assert!(error.is_peer_unreachable());
+                    assert_eq!(
+                        error.to_reject(ADDRESS),
+                        reject_reasons::PEER_INTERNAL_ERROR.to_reject(ADDRESS),
+                    );
+                })
+            });
+    }
+
+    #[test]
+    fn test_next_hop_probe() {
+        testing::MockServer::new()
+            .test_request(|req| {
+                assert_eq!(req.method(), hyper::Method::HEAD);
+                assert_eq!(req.uri().path(), "/alice");
+                assert_eq!(
+                    req.headers().get("Authorization").unwrap(),
+                    "alice_auth",
+                );
+                assert_eq!(req.headers().get("X-Extra").unwrap(), "extra");
+            })
+            // The endpoint is up, even if it doesn't allow the method.
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(405)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })
+            .run({
+                let request = make_next_hop_request();
+                CLIENT
+                    .probe(ProbeRequest {
+                        endpoint: request.endpoint,
+                        auth: request.auth,
+                        headers: request.headers,
+                        http_version: request.http_version,
+                    })
+                    .map(|result| result.unwrap())
+            });
+    }
+
     #[test]
     fn test_outgoing_http2_only() {
         testing::MockServer::new()
@@ -326,6 +955,143 @@ mod tests {
             });
     }
 
+    #[test]
+    fn test_peer_capabilities() {
+        let client = Client::new(ADDRESS.to_address()).with_peer_discovery();
+        client.capabilities.as_ref().unwrap().set(
+            "127.0.0.1:3001",
+            Capabilities {
+                deadline_header: true,
+                ..Capabilities::default()
+            },
+        );
+        testing::MockServer::new()
+            .test_request(|req| {
+                assert!(req.headers().get("ILP-Deadline").is_some());
+            })
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(200)
+                    .body(hyper::Body::from(testing::FULFILL.as_ref()))
+                    .unwrap()
+            })
+            // The timeout must be created within the runtime.
+            .run(async move {
+                let result = client
+                    .request(RequestOptions {
+                        response_timeout: Some(time::Duration::from_secs(5)),
+                        ..REQUEST_OPTIONS.clone()
+                    }, testing::PREPARE.clone())
+                    .await;
+                assert_eq!(result.unwrap(), *testing::FULFILL);
+            });
+
+        // Prepares that are too large for the next hop aren't sent.
+        let client = Client::new(ADDRESS.to_address()).with_peer_discovery();
+        client.capabilities.as_ref().unwrap().set(
+            "127.0.0.1:3001",
+            Capabilities {
+                max_packet_size: Some(testing::PREPARE.as_ref().len() - 1),
+                ..Capabilities::default()
+            },
+        );
+        let result = futures::executor::block_on({
+            client.try_request(REQUEST_OPTIONS.clone(), testing::PREPARE.clone())
+        });
+        assert!(matches!(result, Err(ClientError::PacketTooLarge)));
+    }
+
+    #[test]
+    fn test_discover_capabilities() {
+        let client = Client::new(ADDRESS.to_address());
+        let cache = Arc::new(CapabilityCache::default());
+        testing::MockServer::new()
+            .test_request(|req| {
+                assert_eq!(req.method(), hyper::Method::OPTIONS);
+                assert_eq!(
+                    req.headers().get("Authorization").unwrap(),
+                    "alice_auth",
+                );
+                assert_eq!(req.headers().get("X-Extra").unwrap(), "extra");
+            })
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(405)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })
+            .run({
+                let cache = Arc::clone(&cache);
+                let mut headers = hyper::HeaderMap::new();
+                headers.insert("X-Extra", "extra".parse().unwrap());
+                discover_capabilities(
+                    cache,
+                    client.pool.client(&REQUEST_OPTIONS.uri, false),
+                    REQUEST_OPTIONS.uri.clone(),
+                    REQUEST_OPTIONS.auth.clone(),
+                    Some(headers),
+                )
+            });
+        assert_eq!(
+            cache.get("127.0.0.1:3001").0,
+            Some(Arc::new(Capabilities::default())),
+        );
+    }
+
+    #[test]
+    fn test_outgoing_http_version() {
+        testing::MockServer::new()
+            .test_request(|req| {
+                assert_eq!(req.version(), hyper::Version::HTTP_2);
+            })
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(200)
+                    .body(hyper::Body::from(testing::FULFILL.as_ref()))
+                    .unwrap()
+            })
+            .run({
+                let req_opts = RequestOptions {
+                    http_version: HttpVersion::Http2Only,
+                    ..REQUEST_OPTIONS.clone()
+                };
+                CLIENT.clone()
+                    .request(req_opts, testing::PREPARE.clone())
+                    .map(|result| {
+                        assert_eq!(result.unwrap(), *testing::FULFILL);
+                    })
+            });
+    }
+
+    #[tokio::test]
+    async fn test_response_timeout() {
+        // The next hop accepts connections, but never responds.
+        let mut listener =
+            tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let uri = format!("http://{}/", listener.local_addr().unwrap())
+            .parse::<hyper::Uri>()
+            .unwrap();
+        tokio::spawn(async move {
+            let mut sockets = Vec::new();
+            while let Ok((socket, _addr)) = listener.accept().await {
+                sockets.push(socket);
+            }
+        });
+
+        let start = time::Instant::now();
+        let reject = CLIENT.clone()
+            .request(RequestOptions {
+                uri,
+                response_timeout: Some(time::Duration::from_millis(50)),
+                ..REQUEST_OPTIONS.clone()
+            }, testing::PREPARE.clone())
+            .await
+            .unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::R00_TRANSFER_TIMED_OUT);
+        assert_eq!(reject.triggered_by(), Some(ADDRESS));
+        assert!(start.elapsed() < time::Duration::from_secs(1));
+    }
+
     #[test]
     fn test_incoming_reject() {
         testing::MockServer::new()
@@ -346,12 +1112,8 @@ mod tests {
 
     #[test]
     fn test_incoming_invalid_packet() {
-        let expect_reject = ilp::RejectBuilder {
-            code: ilp::ErrorCode::T00_INTERNAL_ERROR,
-            message: b"invalid response body from peer",
-            triggered_by: Some(ADDRESS),
-            data: b"",
-        }.build();
+        let expect_reject =
+            reject_reasons::INVALID_PEER_RESPONSE.to_reject(ADDRESS);
         testing::MockServer::new()
             .with_response(|| {
                 hyper::Response::builder()
@@ -372,18 +1134,12 @@ mod tests {
         ($(
             fn $fn:ident(
                 status_code: $status_code:expr,
-                error_code: $error_code:expr,
-                error_message: $error_message:expr $(,)?
+                reason: $reason:expr $(,)?
             );
         )+) => {$(
             #[test]
             fn $fn() {
-                let expect_reject = ilp::RejectBuilder {
-                    code: $error_code,
-                    message: $error_message,
-                    triggered_by: Some(ADDRESS),
-                    data: b"",
-                }.build();
+                let expect_reject = $reason.to_reject(ADDRESS);
                 testing::MockServer::new()
                     .with_response(|| {
                         hyper::Response::builder()
@@ -405,31 +1161,24 @@ mod tests {
     make_test_incoming_error_code! {
         fn test_incoming_300(
             status_code: 300,
-            error_code: ilp::ErrorCode::T00_INTERNAL_ERROR,
-            error_message: b"unexpected response code from peer",
+            reason: reject_reasons::PEER_UNEXPECTED_STATUS,
         );
 
         fn test_incoming_400(
             status_code: 400,
-            error_code: ilp::ErrorCode::F00_BAD_REQUEST,
-            error_message: b"bad request to peer",
+            reason: reject_reasons::PEER_BAD_REQUEST,
         );
 
         fn test_incoming_500(
             status_code: 500,
-            error_code: ilp::ErrorCode::T01_PEER_UNREACHABLE,
-            error_message: b"peer internal error",
+            reason: reject_reasons::PEER_INTERNAL_ERROR,
         );
     }
 
     #[test]
     fn test_incoming_abort() {
-        let expect_reject = ilp::RejectBuilder {
-            code: ilp::ErrorCode::T01_PEER_UNREACHABLE,
-            message: b"peer connection error",
-            triggered_by: Some(ADDRESS),
-            data: b"",
-        }.build();
+        let expect_reject =
+            reject_reasons::PEER_CONNECTION_ERROR.to_reject(ADDRESS);
         testing::MockServer::new()
             .with_abort()
             .run({
@@ -441,6 +1190,157 @@ mod tests {
             });
     }
 
+    #[test]
+    fn test_client_error() {
+        let metrics = Arc::new(Metrics::default());
+        let client = Client::new_with_pool(
+            ADDRESS.to_address(),
+            &ClientPoolConfig::default(),
+            Some(Arc::clone(&metrics)),
+        );
+        testing::MockServer::new()
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(503)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })
+            .run({
+                client
+                    .try_request(REQUEST_OPTIONS.clone(), testing::PREPARE.clone())
+                    .map(|result| {
+                        let error = result.unwrap_err();
+                        assert!(matches!(error, ClientError::Status(status) if status == 503));
+                        assert!(error.is_peer_unreachable());
+                        assert_eq!(
+                            error.to_reject(ADDRESS),
+                            reject_reasons::PEER_INTERNAL_ERROR.to_reject(ADDRESS),
+                        );
+                    })
+            });
+        assert_eq!(
+            metrics.get(OUTGOING_REQUEST_ERRORS, vec![("kind", "status".to_owned())]),
+            1,
+        );
+
+        let error = ClientError::Status(StatusCode::UNAUTHORIZED);
+        assert!(!error.is_peer_unreachable());
+        assert_eq!(error.reject_reason(), &reject_reasons::PEER_BAD_REQUEST);
+        assert!(!ClientError::Timeout.is_peer_unreachable());
+        assert_eq!(ClientError::Decode.kind(), "decode");
+    }
+
+    #[test]
+    fn test_retry_502() {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        testing::MockServer::new()
+            .test_request(|_req| {
+                ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+            })
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(502)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })
+            .run({
+                CLIENT.clone()
+                    .request(REQUEST_OPTIONS.clone(), testing::PREPARE.clone())
+                    .map(|result| {
+                        assert_eq!(
+                            result.unwrap_err().code(),
+                            ilp::ErrorCode::T01_PEER_UNREACHABLE,
+                        );
+                    })
+            });
+        // The default policy retries once.
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 2);
+    }
+
+    #[test]
+    fn test_retry_until_success() {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        testing::MockServer::new()
+            .with_response(|| {
+                let attempt = ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+                let (status, body) = if attempt < 2 {
+                    (503, &b""[..])
+                } else {
+                    (200, testing::FULFILL.as_ref())
+                };
+                hyper::Response::builder()
+                    .status(status)
+                    .body(hyper::Body::from(body))
+                    .unwrap()
+            })
+            .run({
+                let req_opts = RequestOptions {
+                    retry: Arc::clone(&RETRY_503),
+                    ..REQUEST_OPTIONS.clone()
+                };
+                CLIENT.clone()
+                    .request(req_opts, testing::PREPARE.clone())
+                    .map(|result| {
+                        assert_eq!(result.unwrap(), *testing::FULFILL);
+                    })
+            });
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_retry_connection_error() {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        testing::MockServer::new()
+            .test_request(|_req| {
+                ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+            })
+            .with_abort()
+            .run({
+                let req_opts = RequestOptions {
+                    retry: Arc::clone(&RETRY_503),
+                    ..REQUEST_OPTIONS.clone()
+                };
+                CLIENT.clone()
+                    .request(req_opts, testing::PREPARE.clone())
+                    .map(|result| {
+                        assert_eq!(
+                            result.unwrap_err().code(),
+                            ilp::ErrorCode::T01_PEER_UNREACHABLE,
+                        );
+                    })
+            });
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 3);
+    }
+
+    #[test]
+    fn test_retry_after_expiry() {
+        static ATTEMPTS: AtomicUsize = AtomicUsize::new(0);
+        testing::MockServer::new()
+            .test_request(|_req| {
+                ATTEMPTS.fetch_add(1, Ordering::SeqCst);
+            })
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(502)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })
+            .run({
+                // The backoff is longer than the Prepare's expiry.
+                let req_opts = RequestOptions {
+                    retry: Arc::new(RetryPolicy {
+                        backoff_base: time::Duration::from_secs(60),
+                        ..RetryPolicy::default()
+                    }),
+                    ..REQUEST_OPTIONS.clone()
+                };
+                CLIENT.clone()
+                    .request(req_opts, testing::PREPARE.clone())
+                    .map(|result| assert!(result.is_err()))
+            });
+        assert_eq!(ATTEMPTS.load(Ordering::SeqCst), 1);
+    }
+
     #[test]
     fn test_truncate() {
         let tests = &[
@@ -0,0 +1,465 @@
+//! Tuning and instrumentation for the outgoing HTTP client's connection pool.
+
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::future::Future;
+use std::io;
+use std::mem::MaybeUninit;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll};
+use std::time;
+
+use hyper::Uri;
+use hyper::client::HttpConnector;
+use hyper::client::connect::{Connected, Connection};
+use hyper::service::Service;
+use hyper_tls::{HttpsConnector, MaybeHttpsStream};
+use log::info;
+use serde::{Deserialize, Serialize};
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::TcpStream;
+
+use crate::metrics::Metrics;
+
+static CONNECTIONS_OPENED: &str = "ilp_relay_outgoing_connections_opened_total";
+static CONNECTIONS_CLOSED: &str = "ilp_relay_outgoing_connections_closed_total";
+pub(crate) static OUTGOING_REQUESTS: &str = "ilp_relay_outgoing_requests_total";
+
+/// Each host's connection rate is measured over windows of this length.
+const RATE_WINDOW: time::Duration = time::Duration::from_secs(10);
+
+type BoxError = Box<dyn std::error::Error + Send + Sync>;
+pub(crate) type HyperClient = hyper::Client<MeteredConnector, hyper::Body>;
+
+/// Unset fields keep hyper's defaults.
+#[derive(Clone, Debug, Default, PartialEq, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientPoolConfig {
+    /// The maximum number of idle connections kept open per host.
+    pub max_idle_per_host: Option<usize>,
+    /// Close idle connections after this long.
+    pub idle_timeout: Option<time::Duration>,
+    /// Enable TCP keep-alive, with this interval.
+    pub keep_alive: Option<time::Duration>,
+}
+
+impl ClientPoolConfig {
+    pub(crate) fn builder(&self) -> hyper::client::Builder {
+        let mut builder = hyper::Client::builder();
+        if let Some(max_idle_per_host) = self.max_idle_per_host {
+            builder.pool_max_idle_per_host(max_idle_per_host);
+        }
+        if let Some(idle_timeout) = self.idle_timeout {
+            builder.pool_idle_timeout(idle_timeout);
+        }
+        builder
+    }
+
+    pub(crate) fn connector(&self, metrics: Option<Arc<Metrics>>)
+        -> MeteredConnector
+    {
+        let mut http = HttpConnector::new();
+        http.enforce_http(false);
+        http.set_keepalive(self.keep_alive);
+        MeteredConnector {
+            inner: HttpsConnector::new_with_connector(http),
+            metrics,
+            stats: Arc::new(PoolStats::default()),
+        }
+    }
+}
+
+/// The outgoing HTTP clients. Each host gets its own hyper client (and so its
+/// own pool of connections), so that a single host's connections can be
+/// flushed, e.g. after the peer swaps its load balancer.
+pub struct ClientPool {
+    make_client: Box<dyn Fn(bool) -> HyperClient + Send + Sync>,
+    /// Keyed by authority, and whether the client is HTTP/2-only.
+    clients: Mutex<HashMap<(String, bool), Arc<HyperClient>>>,
+    stats: Arc<PoolStats>,
+}
+
+impl ClientPool {
+    pub(crate) fn new(config: &ClientPoolConfig, metrics: Option<Arc<Metrics>>)
+        -> Self
+    {
+        let connector = config.connector(metrics);
+        let stats = Arc::clone(&connector.stats);
+        let config = config.clone();
+        ClientPool {
+            make_client: Box::new(move |http2_only| {
+                config.builder()
+                    .http2_only(http2_only)
+                    .build(connector.clone())
+            }),
+            clients: Mutex::new(HashMap::new()),
+            stats,
+        }
+    }
+
+    /// Every host shares `hyper` (and its connections), so flushing a host
+    /// has no effect, and connections aren't counted.
+    pub(crate) fn with_client(hyper: HyperClient) -> Self {
+        ClientPool {
+            make_client: Box::new(move |_http2_only| hyper.clone()),
+            clients: Mutex::new(HashMap::new()),
+            stats: Arc::new(PoolStats::default()),
+        }
+    }
+
+    /// The client for requests to the URI's host.
+    pub(crate) fn client(&self, uri: &Uri, http2_only: bool)
+        -> Arc<HyperClient>
+    {
+        let key = (authority(uri).to_owned(), http2_only);
+        let mut clients = self.clients.lock().unwrap();
+        Arc::clone(clients
+            .entry(key)
+            .or_insert_with(|| Arc::new((self.make_client)(http2_only))))
+    }
+
+    /// The request is counted as in flight until the guard is dropped.
+    pub(crate) fn start_request(&self, uri: &Uri) -> InFlight {
+        let host = authority(uri).to_owned();
+        self.stats.update(&host, |stats| stats.in_flight += 1);
+        InFlight {
+            stats: Arc::clone(&self.stats),
+            host,
+        }
+    }
+
+    /// Drop the host's clients, closing their idle connections. Requests that
+    /// are in flight finish on their connections (which are then closed). The
+    /// next request to the host opens a fresh connection, with a new DNS
+    /// lookup and TLS handshake.
+    ///
+    /// Returns `false` when there are no clients for the host.
+    pub(crate) fn flush(&self, host: &str) -> bool {
+        let mut clients = self.clients.lock().unwrap();
+        let count = clients.len();
+        clients.retain(|(authority, _http2_only), _client| authority != host);
+        let flushed = clients.len() < count;
+        if flushed {
+            info!("flushed connection pool: host={}", host);
+        }
+        flushed
+    }
+
+    pub(crate) fn stats(&self) -> BTreeMap<String, HostPoolStats> {
+        self.stats.snapshot(time::Instant::now())
+    }
+}
+
+impl fmt::Debug for ClientPool {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientPool")
+            .field("clients", &self.clients)
+            .field("stats", &self.stats)
+            .finish()
+    }
+}
+
+/// A snapshot of a host's connections.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct HostPoolStats {
+    /// The connections that are currently open.
+    pub open: usize,
+    /// The open connections that aren't serving a request. Since HTTP/2
+    /// connections carry several requests at once, this is an estimate.
+    pub idle: usize,
+    pub in_flight: usize,
+    /// The connections that were opened (since startup).
+    pub opened: u64,
+    /// The rate that connections were opened at, over the last full window.
+    pub opened_per_sec: f64,
+}
+
+#[derive(Debug, Default)]
+pub(crate) struct PoolStats {
+    hosts: Mutex<HashMap<String, HostStats>>,
+}
+
+#[derive(Debug, Default)]
+struct HostStats {
+    open: usize,
+    in_flight: usize,
+    opened: u64,
+    /// The start of the current `RATE_WINDOW`.
+    window_start: Option<time::Instant>,
+    window_opened: u64,
+    /// The connections opened during the previous window.
+    last_window_opened: u64,
+}
+
+impl PoolStats {
+    fn update(&self, host: &str, update: impl FnOnce(&mut HostStats)) {
+        let mut hosts = self.hosts.lock().unwrap();
+        match hosts.get_mut(host) {
+            Some(stats) => update(stats),
+            None => update(hosts.entry(host.to_owned()).or_default()),
+        }
+    }
+
+    fn opened(&self, host: &str, now: time::Instant) {
+        self.update(host, |stats| {
+            stats.roll_window(now);
+            stats.open += 1;
+            stats.opened += 1;
+            stats.window_opened += 1;
+        });
+    }
+
+    fn closed(&self, host: &str) {
+        self.update(host, |stats| stats.open = stats.open.saturating_sub(1));
+    }
+
+    fn snapshot(&self, now: time::Instant) -> BTreeMap<String, HostPoolStats> {
+        let mut hosts = self.hosts.lock().unwrap();
+        hosts
+            .iter_mut()
+            .map(|(host, stats)| {
+                stats.roll_window(now);
+                (host.clone(), HostPoolStats {
+                    open: stats.open,
+                    idle: stats.open.saturating_sub(stats.in_flight),
+                    in_flight: stats.in_flight,
+                    opened: stats.opened,
+                    opened_per_sec: stats.last_window_opened as f64
+                        / RATE_WINDOW.as_secs_f64(),
+                })
+            })
+            .collect()
+    }
+}
+
+impl HostStats {
+    fn roll_window(&mut self, now: time::Instant) {
+        let window_start = match self.window_start {
+            Some(window_start) => window_start,
+            None => {
+                self.window_start = Some(now);
+                return;
+            },
+        };
+        let elapsed = now.saturating_duration_since(window_start);
+        if elapsed < RATE_WINDOW {
+            return;
+        }
+        let windows = elapsed.as_secs() / RATE_WINDOW.as_secs();
+        self.last_window_opened = if windows == 1 { self.window_opened } else { 0 };
+        self.window_opened = 0;
+        self.window_start = Some(window_start + RATE_WINDOW * windows as u32);
+    }
+}
+
+/// Returned by `ClientPool::start_request`.
+#[derive(Debug)]
+pub(crate) struct InFlight {
+    stats: Arc<PoolStats>,
+    host: String,
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        self.stats.update(&self.host, |stats| {
+            stats.in_flight = stats.in_flight.saturating_sub(1);
+        });
+    }
+}
+
+fn authority(uri: &Uri) -> &str {
+    uri.authority().map_or("", |authority| authority.as_str())
+}
+
+/// Count the connections that are opened and closed, to tell how often pooled
+/// connections are reused (compared to `ilp_relay_outgoing_requests_total`).
+#[derive(Clone, Debug)]
+pub struct MeteredConnector {
+    inner: HttpsConnector<HttpConnector>,
+    metrics: Option<Arc<Metrics>>,
+    stats: Arc<PoolStats>,
+}
+
+impl Service<Uri> for MeteredConnector {
+    type Response = MeteredStream<MaybeHttpsStream<TcpStream>>;
+    type Error = BoxError;
+    type Future = Pin<Box<
+        dyn Future<Output = Result<Self::Response, Self::Error>> + Send,
+    >>;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+        self.inner.poll_ready(context)
+    }
+
+    fn call(&mut self, uri: Uri) -> Self::Future {
+        let host = authority(&uri).to_owned();
+        let connecting = self.inner.call(uri);
+        let metrics = self.metrics.clone();
+        let stats = Arc::clone(&self.stats);
+        Box::pin(async move {
+            let stream = connecting.await?;
+            if let Some(metrics) = &metrics {
+                metrics.increment(CONNECTIONS_OPENED, vec![], 1);
+            }
+            stats.opened(&host, time::Instant::now());
+            Ok(MeteredStream { inner: stream, metrics, stats, host })
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct MeteredStream<S> {
+    inner: S,
+    metrics: Option<Arc<Metrics>>,
+    stats: Arc<PoolStats>,
+    host: String,
+}
+
+impl<S> Drop for MeteredStream<S> {
+    fn drop(&mut self) {
+        if let Some(metrics) = &self.metrics {
+            metrics.increment(CONNECTIONS_CLOSED, vec![], 1);
+        }
+        self.stats.closed(&self.host);
+    }
+}
+
+impl<S: Connection> Connection for MeteredStream<S> {
+    fn connected(&self) -> Connected {
+        self.inner.connected()
+    }
+}
+
+impl<S: AsyncRead + Unpin> AsyncRead for MeteredStream<S> {
+    unsafe fn prepare_uninitialized_buffer(&self, buf: &mut [MaybeUninit<u8>])
+        -> bool
+    {
+        self.inner.prepare_uninitialized_buffer(buf)
+    }
+
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buf: &mut [u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_read(context, buf)
+    }
+}
+
+impl<S: AsyncWrite + Unpin> AsyncWrite for MeteredStream<S> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        context: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.inner).poll_write(context, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, context: &mut Context<'_>)
+        -> Poll<io::Result<()>>
+    {
+        Pin::new(&mut self.inner).poll_flush(context)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, context: &mut Context<'_>)
+        -> Poll<io::Result<()>>
+    {
+        Pin::new(&mut self.inner).poll_shutdown(context)
+    }
+}
+
+#[cfg(test)]
+mod test_client_pool {
+    use crate::{Client, HttpVersion, RetryPolicy};
+    use crate::RequestId;
+    use crate::client::RequestOptions;
+    use crate::testing::{self, RECEIVER_ORIGIN};
+    use super::*;
+
+    #[test]
+    fn test_metrics() {
+        let metrics = Arc::new(Metrics::default());
+        let client = Client::new_with_pool(
+            testing::ADDRESS.to_address(),
+            &ClientPoolConfig::default(),
+            Some(Arc::clone(&metrics)),
+        );
+        let req_opts = RequestOptions {
+            method: hyper::Method::POST,
+            uri: hyper::Uri::from_static(RECEIVER_ORIGIN),
+            auth: None,
+            peer_name: None,
+            headers: None,
+            retry: Arc::new(RetryPolicy::default()),
+            http_version: HttpVersion::Adaptive,
+            response_timeout: None,
+            request_id: RequestId::generate(),
+        };
+        testing::MockServer::new()
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(200)
+                    .body(hyper::Body::from(testing::FULFILL.as_ref()))
+                    .unwrap()
+            })
+            .run(async move {
+                for _i in 0..2 {
+                    client.clone()
+                        .request(req_opts.clone(), testing::PREPARE.clone())
+                        .await
+                        .unwrap();
+                }
+                let stats = client.pool().stats();
+                assert_eq!(stats.len(), 1);
+                let stats = &stats["127.0.0.1:3001"];
+                assert_eq!((stats.open, stats.idle, stats.in_flight), (1, 1, 0));
+                assert_eq!(stats.opened, 1);
+
+                // After a flush, the next request opens a fresh connection.
+                assert!(client.pool().flush("127.0.0.1:3001"));
+                assert!(!client.pool().flush("127.0.0.2:3001"));
+                client.clone()
+                    .request(req_opts.clone(), testing::PREPARE.clone())
+                    .await
+                    .unwrap();
+                assert_eq!(client.pool().stats()["127.0.0.1:3001"].opened, 2);
+            });
+
+        assert_eq!(metrics.get(OUTGOING_REQUESTS, vec![]), 3);
+        // The second request reused the pooled connection, and the third
+        // opened a new one.
+        assert_eq!(metrics.get(CONNECTIONS_OPENED, vec![]), 2);
+    }
+
+    #[test]
+    fn test_opened_per_sec() {
+        let stats = PoolStats::default();
+        let start = time::Instant::now();
+        for _i in 0..20 {
+            stats.opened("alice", start);
+        }
+        stats.closed("alice");
+        let snapshot = stats.snapshot(start + RATE_WINDOW / 2);
+        assert_eq!(snapshot["alice"], HostPoolStats {
+            open: 19,
+            idle: 19,
+            in_flight: 0,
+            opened: 20,
+            // The first window isn't over yet.
+            opened_per_sec: 0.0,
+        });
+        stats.opened("alice", start + RATE_WINDOW);
+        assert_eq!(
+            stats.snapshot(start + RATE_WINDOW * 3 / 2)["alice"].opened_per_sec,
+            2.0,
+        );
+        // Nothing was opened during the last full window.
+        assert_eq!(
+            stats.snapshot(start + RATE_WINDOW * 3)["alice"].opened_per_sec,
+            0.0,
+        );
+    }
+}
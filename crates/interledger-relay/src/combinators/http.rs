@@ -1,8 +1,11 @@
 use bytes::BytesMut;
+use futures::future::{Either, err};
 use futures::prelude::*;
 
 use super::{LimitStream, LimitStreamError};
 
+/// Collect the body, up to its `Content-Length` (if any) or `max_capacity`. A
+/// `Content-Length` that exceeds `max_capacity` fails without reading the body.
 pub fn collect_http_body(
     headers: &hyper::HeaderMap<hyper::header::HeaderValue>,
     body: hyper::Body,
@@ -10,13 +13,14 @@ pub fn collect_http_body(
 ) -> impl Future<Output =
     Result<BytesMut, LimitStreamError<hyper::Error>>
 > + Send + 'static {
-    // TODO should this return an error if the Content-Length is too large instead of just truncating?
-    let capacity = std::cmp::min(
-        max_capacity,
-        get_content_length(headers).unwrap_or(std::usize::MAX),
-    );
-
-    collect_body(body, capacity)
+    let capacity = match get_content_length(headers) {
+        Some(content_length) if content_length > max_capacity =>
+            return Either::Left(err(LimitStreamError::LimitExceeded)),
+        Some(content_length) => content_length,
+        None => max_capacity,
+    };
+
+    Either::Right(collect_body(body, capacity))
 }
 
 /// Missing or invalid `Content-Length`s return `0`.
@@ -106,6 +110,50 @@ mod test_http {
             )),
             Err(LimitStreamError::LimitExceeded)
         ));
+
+        // A `Content-Length` that exceeds `max_capacity` fails before the body
+        // is read (a body that never ends would otherwise hang).
+        let (_sender, body) = hyper::Body::channel();
+        assert!(matches!(
+            block_on(collect_http_body(&make_headers("1001"), body, 1000)),
+            Err(LimitStreamError::LimitExceeded)
+        ));
+    }
+
+    #[test]
+    fn test_collect_http_body_chunked() {
+        let chunks = || stream::iter(vec![
+            Ok::<_, std::io::Error>("12345"),
+            Ok("67890"),
+        ]);
+
+        // Without a `Content-Length`, the body is read up to `max_capacity`.
+        assert_eq!(
+            block_on(collect_http_body(
+                &hyper::HeaderMap::new(),
+                hyper::Body::wrap_stream(chunks()),
+                10,
+            )).unwrap().as_ref(),
+            b"1234567890",
+        );
+        assert!(matches!(
+            block_on(collect_http_body(
+                &hyper::HeaderMap::new(),
+                hyper::Body::wrap_stream(chunks()),
+                9,
+            )),
+            Err(LimitStreamError::LimitExceeded)
+        ));
+
+        // An invalid `Content-Length` is ignored.
+        assert!(matches!(
+            block_on(collect_http_body(
+                &make_headers("-1"),
+                hyper::Body::wrap_stream(chunks()),
+                9,
+            )),
+            Err(LimitStreamError::LimitExceeded)
+        ));
     }
 
     #[test]
@@ -0,0 +1,86 @@
+use std::pin::Pin;
+use std::sync::Arc;
+
+use futures::prelude::*;
+use yup_oauth2 as oauth2;
+
+use crate::https_client::HttpsClient;
+use crate::transport::{HttpClient, HttpError, HttpRequest, HttpResponse};
+
+type Authenticator = oauth2::authenticator::Authenticator<
+    <yup_oauth2::authenticator::DefaultHyperClient
+        as yup_oauth2::authenticator::HyperClientBuilder>::Connector
+>;
+
+/// An HTTP client for the Google Cloud APIs that the sinks write to. Each
+/// request is authorized with the service account's access token, if any.
+#[derive(Clone)]
+pub struct GoogleClient {
+    https: HttpsClient,
+    authenticator: Option<Arc<Authenticator>>,
+    /// The OAuth scopes of the API.
+    scopes: &'static [&'static str],
+}
+
+impl GoogleClient {
+    fn new(
+        authenticator: Option<Authenticator>,
+        scopes: &'static [&'static str],
+    ) -> Self {
+        GoogleClient {
+            https: HttpsClient::new(),
+            authenticator: authenticator.map(Arc::new),
+            scopes,
+        }
+    }
+
+    /// Authenticate with the service account key, if any.
+    pub async fn from_key_file(
+        service_account_key_file: Option<&std::path::Path>,
+        scopes: &'static [&'static str],
+    ) -> Result<Self, oauth2::Error> {
+        let authenticator = match service_account_key_file {
+            Some(sa_key_file) => Some({
+                let sa_key =
+                    oauth2::read_service_account_key(sa_key_file).await?;
+                oauth2::ServiceAccountAuthenticator::builder(sa_key)
+                    .build()
+                    .await?
+            }),
+            None => None,
+        };
+        Ok(GoogleClient::new(authenticator, scopes))
+    }
+}
+
+impl HttpClient for GoogleClient {
+    fn request(&self, request: HttpRequest) -> Pin<Box<
+        dyn Future<Output = Result<HttpResponse, HttpError>> + Send + 'static,
+    >> {
+        let client = self.clone();
+        Box::pin(async move {
+            let request = match &client.authenticator {
+                Some(authenticator) => {
+                    let token = authenticator.token(client.scopes).await
+                        .map_err(HttpError::other)?;
+                    request.header(
+                        "Authorization",
+                        format!("Bearer {}", token.as_str()),
+                    )
+                },
+                None => request,
+            };
+            client.https.request(request).await
+        })
+    }
+}
+
+impl std::fmt::Debug for GoogleClient {
+    fn fmt(&self, formatter: &mut std::fmt::Formatter) -> std::fmt::Result {
+        formatter
+            .debug_struct("GoogleClient")
+            .field("https", &self.https)
+            .field("scopes", &self.scopes)
+            .finish()
+    }
+}
@@ -0,0 +1,150 @@
+use std::pin::Pin;
+
+use futures::prelude::*;
+
+use crate::combinators::{self, LimitStreamError};
+use crate::transport::{HttpClient, HttpError, HttpMethod, HttpRequest, HttpResponse};
+
+type HyperClient = hyper::Client<
+    hyper_tls::HttpsConnector<hyper::client::HttpConnector>,
+    hyper::Body,
+>;
+
+/// An HTTP(S) client for the services' plain requests, e.g. to poll exchange
+/// rates, or to talk to the settlement engine.
+#[derive(Clone, Debug)]
+pub struct HttpsClient {
+    hyper: HyperClient,
+}
+
+impl HttpsClient {
+    pub fn new() -> Self {
+        HttpsClient {
+            hyper: hyper::Client::builder()
+                .build(hyper_tls::HttpsConnector::new()),
+        }
+    }
+}
+
+impl Default for HttpsClient {
+    fn default() -> Self {
+        HttpsClient::new()
+    }
+}
+
+impl HttpClient for HttpsClient {
+    fn request(&self, request: HttpRequest) -> Pin<Box<
+        dyn Future<Output = Result<HttpResponse, HttpError>> + Send + 'static,
+    >> {
+        let hyper = self.hyper.clone();
+        Box::pin(async move {
+            let method = match request.method {
+                HttpMethod::Get => hyper::Method::GET,
+                HttpMethod::Post => hyper::Method::POST,
+            };
+            let mut builder = hyper::Request::builder()
+                .method(method)
+                .uri(request.uri.as_str());
+            for (name, value) in &request.headers {
+                builder = builder.header(*name, value.as_str());
+            }
+            let hyper_request = builder
+                .body(hyper::Body::from(request.body))
+                .map_err(HttpError::other)?;
+            let response = hyper.request(hyper_request).await
+                .map_err(HttpError::other)?;
+            let (parts, body) = response.into_parts();
+            let body = combinators::collect_http_body(
+                &parts.headers,
+                body,
+                request.max_response_size,
+            )
+                .await
+                .map_err(|error| match error {
+                    LimitStreamError::LimitExceeded => HttpError::ResponseTooLarge,
+                    LimitStreamError::StreamError(error) => HttpError::other(error),
+                })?;
+            Ok(HttpResponse {
+                status: parts.status.as_u16(),
+                body: body.freeze(),
+            })
+        })
+    }
+}
+
+#[cfg(test)]
+mod test_https_client {
+    use bytes::Bytes;
+
+    use crate::testing::{self, RECEIVER_ORIGIN};
+    use super::*;
+
+    #[test]
+    fn test_get() {
+        testing::MockServer::new()
+            .test_request(|request| {
+                assert_eq!(request.method(), hyper::Method::GET);
+                assert_eq!(request.uri().path(), "/rates");
+                assert_eq!(request.headers()["accept"], "application/json");
+            })
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(503)
+                    .body(hyper::Body::from("RESPONSE"))
+                    .unwrap()
+            })
+            .run(async {
+                let request = HttpRequest::get(format!("{}/rates", RECEIVER_ORIGIN), 1024)
+                    .header("Accept", "application/json");
+                let response = HttpsClient::new().request(request).await.unwrap();
+                assert_eq!(response, HttpResponse {
+                    status: 503,
+                    body: Bytes::from("RESPONSE"),
+                });
+            });
+    }
+
+    #[test]
+    fn test_post() {
+        testing::MockServer::new()
+            .test_request(|request| {
+                assert_eq!(request.method(), hyper::Method::POST);
+                assert_eq!(request.headers()["content-type"], "application/json");
+            })
+            .test_body(|body| {
+                assert_eq!(body.as_ref(), b"{}");
+            })
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(200)
+                    .body(hyper::Body::from("RESPONSE"))
+                    .unwrap()
+            })
+            .run(async {
+                let request = HttpRequest::post(RECEIVER_ORIGIN, "{}", 1024)
+                    .header("Content-Type", "application/json");
+                let response = HttpsClient::new().request(request).await.unwrap();
+                assert!(response.is_success());
+                assert_eq!(response.body, Bytes::from("RESPONSE"));
+            });
+    }
+
+    #[test]
+    fn test_response_too_large() {
+        testing::MockServer::new()
+            .with_response(|| {
+                hyper::Response::builder()
+                    .status(200)
+                    .body(hyper::Body::from("RESPONSE"))
+                    .unwrap()
+            })
+            .run(async {
+                let request = HttpRequest::get(RECEIVER_ORIGIN, 4);
+                let result = HttpsClient::new().request(request).await;
+                match result {
+                    Err(HttpError::ResponseTooLarge) => {},
+                    result => panic!("unexpected result: {:?}", result),
+                }
+            });
+    }
+}
@@ -0,0 +1,228 @@
+//! Decode ILP packets copied out of logs (`ilprelay inspect`).
+
+use std::error;
+use std::fmt;
+
+use bytes::BytesMut;
+use serde_json::{Value, json};
+
+#[derive(Debug)]
+pub enum InspectError {
+    /// The input is neither hex nor base64.
+    Encoding,
+    Packet(ilp::ParseError),
+}
+
+/// Decode a hex or base64 (standard or URL-safe) encoded packet into its
+/// fields (see `ilp::Prepare::to_json`). ILDCP requests and responses, the
+/// details of `F08` and `R01` Rejects, and Reject diagnostics are decoded too.
+pub fn inspect_packet(input: &str) -> Result<Value, InspectError> {
+    let bytes = decode(input)?;
+    let packet = ilp::Packet::try_from(BytesMut::from(&bytes[..]))
+        .map_err(InspectError::Packet)?;
+    let mut fields = packet.to_json();
+    match packet {
+        ilp::Packet::Prepare(prepare) => {
+            if ilp::ildcp::Request::try_from(prepare).is_ok() {
+                fields["ildcp_request"] = Value::Bool(true);
+            }
+        },
+        ilp::Packet::Fulfill(fulfill) => {
+            if let Ok(response) = ilp::ildcp::Response::try_from(fulfill) {
+                fields["ildcp_response"] = json!({
+                    "client_address": response.client_address().to_string(),
+                    "asset_scale": response.asset_scale(),
+                    "asset_code":
+                        String::from_utf8_lossy(response.asset_code()),
+                });
+            }
+        },
+        ilp::Packet::Reject(reject) => match reject.code() {
+            ilp::ErrorCode::F08_AMOUNT_TOO_LARGE => {
+                let details =
+                    ilp::MaxPacketAmountDetails::from_bytes(reject.data());
+                if let Ok(details) = details {
+                    fields["max_packet_amount"] = json!({
+                        "amount_received":
+                            details.amount_received().to_string(),
+                        "max_amount": details.max_amount().to_string(),
+                    });
+                }
+            },
+            ilp::ErrorCode::R01_INSUFFICIENT_SOURCE_AMOUNT => {
+                let details =
+                    ilp::InsufficientAmountDetails::from_bytes(reject.data());
+                if let Ok(details) = details {
+                    fields["insufficient_amount"] = json!({
+                        "amount_received":
+                            details.amount_received().to_string(),
+                        "min_amount": details.min_amount().to_string(),
+                    });
+                }
+            },
+            _ => {
+                let diagnostics =
+                    ilp::RejectDiagnostics::from_bytes(reject.data());
+                if let Ok(diagnostics) = diagnostics {
+                    fields["diagnostics"] = diagnostics
+                        .iter()
+                        .map(|(key, value)| json!([key, value]))
+                        .collect();
+                }
+            },
+        },
+    }
+    Ok(fields)
+}
+
+fn decode(input: &str) -> Result<Vec<u8>, InspectError> {
+    let input = input.trim();
+    let hex_input = input.trim_start_matches("0x");
+    if !hex_input.is_empty()
+        && hex_input.bytes().all(|byte| byte.is_ascii_hexdigit())
+    {
+        if let Ok(bytes) = hex::decode(hex_input) {
+            return Ok(bytes);
+        }
+    }
+    base64::decode(input)
+        .or_else(|_| base64::decode_config(input, base64::URL_SAFE))
+        .map_err(|_| InspectError::Encoding)
+}
+
+impl error::Error for InspectError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            InspectError::Packet(inner) => Some(inner),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for InspectError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            InspectError::Encoding =>
+                f.write_str("InspectError(expected hex or base64)"),
+            InspectError::Packet(inner) =>
+                write!(f, "InspectError({})", inner),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_inspect {
+    use std::time::SystemTime;
+
+    use crate::testing;
+    use super::*;
+
+    #[test]
+    fn test_prepare() {
+        let prepare = testing::PREPARE.clone();
+        let expect = prepare.to_json();
+        let encoded = base64::encode(&prepare);
+        assert_eq!(inspect_packet(&encoded).unwrap(), expect);
+        assert_eq!(
+            inspect_packet(&base64::encode_config(&prepare, base64::URL_SAFE))
+                .unwrap(),
+            expect,
+        );
+        assert_eq!(inspect_packet(&hex::encode(&prepare)).unwrap(), expect);
+        assert_eq!(
+            inspect_packet(&format!("  0x{}\n", hex::encode(&prepare))).unwrap(),
+            expect,
+        );
+    }
+
+    #[test]
+    fn test_ildcp() {
+        let request = ilp::ildcp::Request::new()
+            .to_prepare_expiring_at(SystemTime::UNIX_EPOCH);
+        let fields = inspect_packet(&hex::encode(&request)).unwrap();
+        assert_eq!(fields["destination"], "peer.config");
+        assert_eq!(fields["ildcp_request"], true);
+
+        let response = ilp::Fulfill::from(ilp::ildcp::ResponseBuilder {
+            client_address: ilp::Addr::new(b"example.alice"),
+            asset_scale: 9,
+            asset_code: b"XRP",
+        }.build());
+        let fields = inspect_packet(&base64::encode(&response)).unwrap();
+        assert_eq!(fields["ildcp_response"], json!({
+            "client_address": "example.alice",
+            "asset_scale": 9,
+            "asset_code": "XRP",
+        }));
+
+        let fields = inspect_packet(&base64::encode(&*testing::FULFILL)).unwrap();
+        assert!(fields.get("ildcp_response").is_none());
+    }
+
+    #[test]
+    fn test_max_packet_amount() {
+        let details = ilp::MaxPacketAmountDetails::new(100, 50).to_bytes();
+        let reject = ilp::RejectBuilder {
+            code: ilp::ErrorCode::F08_AMOUNT_TOO_LARGE,
+            message: b"too large",
+            triggered_by: Some(ilp::Addr::new(b"example.connector")),
+            data: &details,
+        }.build();
+        let fields = inspect_packet(&base64::encode(&reject)).unwrap();
+        assert_eq!(fields["code"], "F08");
+        assert_eq!(fields["max_packet_amount"], json!({
+            "amount_received": "100",
+            "max_amount": "50",
+        }));
+
+        let fields = inspect_packet(&base64::encode(&*testing::REJECT)).unwrap();
+        assert!(fields.get("max_packet_amount").is_none());
+    }
+
+    #[test]
+    fn test_insufficient_amount() {
+        let details = ilp::InsufficientAmountDetails::new(3, 4).to_bytes();
+        let reject = ilp::RejectBuilder {
+            code: ilp::ErrorCode::R01_INSUFFICIENT_SOURCE_AMOUNT,
+            message: b"too small",
+            triggered_by: None,
+            data: &details,
+        }.build();
+        let fields = inspect_packet(&base64::encode(&reject)).unwrap();
+        assert_eq!(fields["insufficient_amount"], json!({
+            "amount_received": "3",
+            "min_amount": "4",
+        }));
+    }
+
+    #[test]
+    fn test_diagnostics() {
+        let reject = crate::reject_reasons::NO_ROUTE.to_reject_with_diagnostics(
+            ilp::Addr::new(b"example.connector"),
+            &[("destination", "example.bob")],
+        );
+        let fields = inspect_packet(&base64::encode(&reject)).unwrap();
+        assert_eq!(fields["diagnostics"], json!([
+            ["reason", "no_route"],
+            ["destination", "example.bob"],
+        ]));
+
+        let reject = crate::reject_reasons::NO_ROUTE
+            .to_reject(ilp::Addr::new(b"example.connector"));
+        let fields = inspect_packet(&base64::encode(&reject)).unwrap();
+        assert!(fields.get("diagnostics").is_none());
+    }
+
+    #[test]
+    fn test_invalid() {
+        assert!(matches!(
+            inspect_packet("not a packet!"),
+            Err(InspectError::Encoding),
+        ));
+        assert!(matches!(
+            inspect_packet(&base64::encode(b"\x99")),
+            Err(InspectError::Packet(_)),
+        ));
+        assert!(matches!(inspect_packet(""), Err(InspectError::Packet(_))));
+    }
+}
@@ -1,50 +1,40 @@
+// For `throttled_warn!`.
+#[macro_use]
+extern crate interledger_relay_core;
+
 pub mod app;
+mod btp;
+mod capabilities;
 mod client;
+mod client_pool;
 mod combinators;
+mod google_client;
+mod https_client;
+mod inspect;
 mod middlewares;
 mod packets;
+mod peers;
 mod serde;
-mod services;
+mod sink_transport;
 #[cfg(test)]
 mod testing;
+mod tls;
+mod tower;
 
-use futures::prelude::*;
+pub use interledger_relay_core::{AllocatorStats, AssetAmount, CountingAllocator, RejectReason, Relation, REJECT_REASONS, Request, RequestId, RequestWithDeadline, RequestWithFrom, RequestWithIdempotencyKey, RequestWithPeerName, Service};
+pub use interledger_relay_core::events::{PacketEvent, PacketEvents, PacketResult};
+pub use interledger_relay_core::transport::{AuthToken, HttpVersion, RetryPolicy};
+use interledger_relay_core::{connector_info, events, metrics, random_fraction, reject_reasons, services, toggles, transport, SetupError};
 
+pub use self::capabilities::Capabilities;
 pub use self::client::Client;
-pub use self::middlewares::AuthToken;
+pub use self::client_pool::ClientPoolConfig;
+pub use self::inspect::{InspectError, inspect_packet};
+pub use self::middlewares::{AccessLogConfig, AdminApiConfig, AuthHeader, AuthLockoutConfig};
+pub use self::middlewares::{IpNetwork, JwtAuthConfig, JwtKeyConfig, PeerAuthToken, SigningSecret};
 pub use self::packets::*;
-pub use self::services::{BigQueryConfig, BigQueryServiceConfig, DebugServiceOptions};
-pub use self::services::{NextHop, RouteFailover, RoutingPartition, RoutingTable, RoutingTableData, StaticRoute};
-
-// TODO maybe support ping protocol
-
-pub trait Service<Req: Request>: Clone {
-    type Future: 'static + Send
-        + Future<Output = Result<ilp::Fulfill, ilp::Reject>>;
-
-    fn setup(&mut self) {}
-    fn call(self, request: Req) -> Self::Future;
-}
-
-
-#[derive(Clone, Copy, Debug, PartialEq)]
-pub enum Relation {
-    Child,
-    Peer,
-    Parent,
-}
-
-/// Allow closures as services when testing.
-#[cfg(test)]
-impl<F, Req, Res> Service<Req> for F
-where
-    F: Clone + Fn(Req) -> Res,
-    Req: Request,
-    Res: 'static + Send + Future<Output = Result<ilp::Fulfill, ilp::Reject>>,
-{
-    type Future = Res;
-
-    fn call(self, request: Req) -> Self::Future {
-        (self)(request)
-    }
-}
+pub use self::peers::ChildRegistrationConfig;
+pub use self::services::{BigQueryConfig, DebugServiceOptions, DedupeConfig, EchoServiceOptions, FileConfig, GreylistAction, GreylistConfig, KafkaCompression, KafkaConfig, PubSubConfig, RateLimitConfig, RowColumn, SettlementAccountConfig, SettlementConfig, SinkConfig, SpillConfig, TelemetryServiceConfig, ThroughputLimitConfig, UnavailablePolicy};
+pub use self::services::{CatchAllWarningConfig, RejectJitterConfig, ConcurrencyLimit, ExchangeRates, ExchangeRatesConfig, HealthCheck, HealthProbe, HedgingPolicy, NextHop, RollbackPolicy, RateProvider, RouteExchange, RouteFailover, RouteMaintenance, RoutingPartition, RoutingTable, RoutingTableData, ScheduledRoutes, ServerSecret, SpspConfig, StaticResponse, StaticRoute};
+pub use self::tls::{CertificateBinding, ClientCertificate, Fingerprint, TlsConfig};
+pub use self::tower::{FromTower, IntoTower};
@@ -0,0 +1,246 @@
+use std::fs;
+use std::io::{self, Write};
+use std::path::PathBuf;
+use std::pin::Pin;
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use hyper::service::Service as HyperService;
+use log::warn;
+use serde::{Deserialize, Serialize};
+
+type HTTPRequest = http::Request<hyper::Body>;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AccessLogConfig {
+    /// The file to append access log lines to, or `"-"` for stdout.
+    pub file: PathBuf,
+}
+
+/// Attached to the extensions of responses to ILP packets (by the `Receiver`),
+/// so that the `AccessLogFilter` can include them.
+#[derive(Clone, Debug)]
+pub(crate) struct AccessLogPacket {
+    pub account: Option<Arc<String>>,
+    pub packet_type: ilp::PacketType,
+    pub error_code: Option<ilp::ErrorCode>,
+}
+
+/// Write one JSON line per HTTP request to the configured file (or stdout).
+///
+/// Each line includes the request's method and path, the response status, and
+/// how long the request took. Responses to ILP Prepares also include the peer's
+/// account, the type of the response packet, and the Reject's error code.
+#[derive(Clone)]
+pub struct AccessLogFilter<S> {
+    log: Option<Arc<AccessLog>>,
+    next: S,
+}
+
+pub struct AccessLog {
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+#[derive(Serialize)]
+struct AccessLogLine<'a> {
+    time: String,
+    method: &'a str,
+    path: &'a str,
+    status: u16,
+    duration_ms: f64,
+    account: Option<&'a str>,
+    packet_type: Option<&'static str>,
+    error_code: Option<String>,
+}
+
+impl<S> AccessLogFilter<S>
+where
+    S: HyperService<HTTPRequest>,
+{
+    pub fn new(log: Option<Arc<AccessLog>>, next: S) -> Self {
+        AccessLogFilter { log, next }
+    }
+}
+
+impl<S> HyperService<HTTPRequest> for AccessLogFilter<S>
+where
+    S: HyperService<
+        HTTPRequest,
+        Response = hyper::Response<hyper::Body>,
+        Error = hyper::Error,
+    >,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<hyper::Body>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<
+        dyn Future<Output = Result<Self::Response, Self::Error>>
+            + Send + 'static
+    >>;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+       self.next.poll_ready(context)
+    }
+
+    fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+        let log = match &self.log {
+            Some(log) => Arc::clone(log),
+            None => return Box::pin(self.next.call(request)),
+        };
+        let start = time::Instant::now();
+        let method = request.method().clone();
+        let path = request.uri().path().to_owned();
+        Box::pin(self.next.call(request).inspect(move |result| {
+            if let Ok(response) = result {
+                log.write(&method, &path, response, start.elapsed());
+            }
+        }))
+    }
+}
+
+impl AccessLog {
+    pub fn open(config: &AccessLogConfig) -> io::Result<Self> {
+        let writer: Box<dyn Write + Send> = if config.file.as_os_str() == "-" {
+            Box::new(io::stdout())
+        } else {
+            Box::new(fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&config.file)?)
+        };
+        Ok(AccessLog::new(writer))
+    }
+
+    fn new(writer: Box<dyn Write + Send>) -> Self {
+        AccessLog { writer: Mutex::new(writer) }
+    }
+
+    fn write(
+        &self,
+        method: &hyper::Method,
+        path: &str,
+        response: &hyper::Response<hyper::Body>,
+        duration: time::Duration,
+    ) {
+        let packet = response.extensions().get::<AccessLogPacket>();
+        let line = AccessLogLine {
+            time: chrono::Utc::now()
+                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            method: method.as_str(),
+            path,
+            status: response.status().as_u16(),
+            duration_ms: duration.as_micros() as f64 / 1_000.0,
+            account: packet
+                .and_then(|packet| packet.account.as_ref())
+                .map(|account| account.as_str()),
+            packet_type: packet.map(|packet| match packet.packet_type {
+                ilp::PacketType::Prepare => "prepare",
+                ilp::PacketType::Fulfill => "fulfill",
+                ilp::PacketType::Reject => "reject",
+            }),
+            error_code: packet
+                .and_then(|packet| packet.error_code)
+                .map(|code| code.to_string()),
+        };
+        let mut buffer = serde_json::to_vec(&line)
+            .expect("access log serialization error");
+        buffer.push(b'\n');
+        let mut writer = self.writer.lock().unwrap();
+        if let Err(error) = writer.write_all(&buffer) {
+            warn!("error writing access log: error={}", error);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_access_log_filter {
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    use super::*;
+
+    #[derive(Clone, Default)]
+    struct SharedBuffer(Arc<Mutex<Vec<u8>>>);
+
+    impl Write for SharedBuffer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Write::write(&mut *self.0.lock().unwrap(), buf)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn test_service() {
+        let buffer = SharedBuffer::default();
+        let log = Arc::new(AccessLog::new(Box::new(buffer.clone())));
+        let next = service_fn(|request: HTTPRequest| {
+            let mut response = hyper::Response::builder()
+                .status(200)
+                .body(hyper::Body::empty())
+                .unwrap();
+            if request.uri().path() == "/ilp" {
+                response.extensions_mut().insert(AccessLogPacket {
+                    account: Some(Arc::new("alice".to_owned())),
+                    packet_type: ilp::PacketType::Reject,
+                    error_code: Some(ilp::ErrorCode::F02_UNREACHABLE),
+                });
+            }
+            future::ok::<_, hyper::Error>(response)
+        });
+        let mut service = AccessLogFilter::new(Some(log), next);
+
+        for &path in &["/ilp", "/health"] {
+            block_on(service.call({
+                hyper::Request::post(path)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })).unwrap();
+        }
+
+        let output = String::from_utf8(buffer.0.lock().unwrap().clone()).unwrap();
+        let lines = output
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect::<Vec<serde_json::Value>>();
+        assert_eq!(lines.len(), 2);
+
+        assert_eq!(lines[0]["method"], "POST");
+        assert_eq!(lines[0]["path"], "/ilp");
+        assert_eq!(lines[0]["status"], 200);
+        assert!(lines[0]["duration_ms"].is_number());
+        assert!(lines[0]["time"].is_string());
+        assert_eq!(lines[0]["account"], "alice");
+        assert_eq!(lines[0]["packet_type"], "reject");
+        assert_eq!(lines[0]["error_code"], "F02");
+
+        assert_eq!(lines[1]["path"], "/health");
+        assert_eq!(lines[1]["account"], serde_json::Value::Null);
+        assert_eq!(lines[1]["packet_type"], serde_json::Value::Null);
+        assert_eq!(lines[1]["error_code"], serde_json::Value::Null);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let next = service_fn(|_req| {
+            future::ok::<_, hyper::Error>(hyper::Response::builder()
+                .status(204)
+                .body(hyper::Body::empty())
+                .unwrap())
+        });
+        let mut service = AccessLogFilter::new(None, next);
+        let response = block_on(service.call({
+            hyper::Request::get("/")
+                .body(hyper::Body::empty())
+                .unwrap()
+        })).unwrap();
+        assert_eq!(response.status(), 204);
+    }
+}
@@ -0,0 +1,1081 @@
+use std::borrow::Borrow;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::future::{Either, ok};
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use hyper::service::Service as HyperService;
+use log::{info, warn};
+use percent_encoding::percent_decode_str;
+use serde::Deserialize;
+use serde::de::value::{BorrowedStrDeserializer, Error as ValueError};
+
+use crate::{AllocatorStats, AuthToken, PacketEvents, RouteMaintenance};
+use crate::services::{ScheduleState, ScheduleStatus};
+use crate::client_pool::ClientPool;
+use crate::combinators::{self, LimitStreamError};
+use crate::services::{Quantity, ReceiveError, RouterService, Settlement};
+use crate::peers::{PeerIndex, Registration, RegistrationError};
+use crate::serde::deserialize_error_code;
+use crate::metrics::Metrics;
+use crate::reject_reasons::REJECT_REASONS;
+use crate::toggles::ServiceToggles;
+use super::auth::{authorization_token, constant_time_eq};
+use super::tap::tap_response;
+
+type HTTPRequest = http::Request<hyper::Body>;
+
+static PATH_PREFIX: &str = "/admin/";
+static CHILDREN_PREFIX: &str = "children/";
+static MAINTENANCE_PREFIX: &str = "maintenance/";
+static POOL_PREFIX: &str = "pool/";
+static SETTLEMENTS_PREFIX: &str = "settlements/";
+static TOGGLES_PREFIX: &str = "toggles/";
+
+/// The largest incoming settlement body (a `Quantity`).
+const MAX_SETTLEMENT_SIZE: usize = 1_024;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AdminApiConfig {
+    /// Valid tokens for the `Authorization` header of admin requests. These
+    /// are independent of the peers' tokens.
+    pub auth: Vec<AuthToken>,
+}
+
+/// Serve the admin API under `/admin/`:
+///
+/// * `GET /admin/children`: the children registered at runtime, as JSON
+///   (`404` when `child_registration` isn't configured).
+/// * `POST /admin/children/{account}`: register a child, and respond with its
+///   suffix, address, and auth token (`400` for an invalid account, `409` for
+///   an account that is already used, `403` when the registry is full, `500`
+///   when its `store` can't be written). `DELETE` unregisters it (`404` for
+///   an unknown account).
+/// * `GET /admin/config`: the (redacted) effective configuration, as JSON.
+/// * `GET /admin/metrics`: metrics, in the Prometheus text format.
+/// * `GET /admin/maintenance`: the routes in maintenance, by account, as JSON.
+/// * `PUT /admin/maintenance/{account}`: put the account's routes in
+///   maintenance, with the optional `code` and `message` query parameters
+///   (`400` for an invalid parameter, e.g. a message too long for a Reject).
+///   `DELETE` takes them out of maintenance. Both respond with the routes in
+///   maintenance (`404` for an unknown account).
+/// * `GET /admin/memory`: the `AllocatorStats`, as JSON (`404` when the
+///   `CountingAllocator` isn't installed).
+/// * `GET /admin/pool`: each host's outgoing connection stats, as JSON.
+/// * `DELETE /admin/pool/{host}`: close the host's pooled connections, so
+///   that the next request opens a fresh one (`404` for an unknown host).
+/// * `GET /admin/reject_reasons`: every `RejectReason`, as JSON.
+/// * `GET /admin/schedule`: the state of the scheduled routes, as JSON (`404`
+///   when there are none). `DELETE` cancels them, unless they were already
+///   activated (`409`).
+/// * `GET /admin/tap`: a WebSocket that streams a summary of each packet, as
+///   JSON text messages, filtered by the optional `account`, `prefix`, and
+///   `sample_rate` query parameters (`400` for an invalid parameter).
+/// * `GET /admin/settlements`: each settled account's balances, as JSON
+///   (`404` when `settlement` isn't configured).
+/// * `POST /admin/settlements/{account}`: record an incoming settlement from
+///   the account, reported by the settlement engine as a JSON `Quantity`
+///   with an `Idempotency-Key` header. Responds with `201` and the credited
+///   `Quantity`, in the account's scale (`400` for an invalid body or a
+///   missing key, `404` for an account that isn't settled, `409` for a key
+///   that was used with another account or amount).
+/// * `GET /admin/toggles`: the runtime service toggles, as JSON.
+/// * `PUT /admin/toggles/{name}`: enable a service (`echo`, `ildcp`,
+///   `debug`, `simulation`, or `telemetry_cutover`). `DELETE` disables it.
+///   Both respond with the toggles.
+///
+/// When the admin API is not configured, all requests are passed through.
+#[derive(Clone, Debug)]
+pub struct AdminFilter<S> {
+    data: Option<Arc<AdminData>>,
+    next: S,
+}
+
+#[derive(Debug)]
+struct AdminData {
+    tokens: Vec<AuthToken>,
+    config_summary: Bytes,
+    metrics: Arc<Metrics>,
+    pool: Arc<ClientPool>,
+    router: RouterService,
+    toggles: ServiceToggles,
+    peers: Arc<PeerIndex>,
+    events: PacketEvents,
+    settlement: Option<Settlement>,
+}
+
+impl<S> AdminFilter<S>
+where
+    S: HyperService<HTTPRequest>,
+{
+    #[allow(clippy::too_many_arguments)]
+    pub fn new(
+        config: Option<AdminApiConfig>,
+        config_summary: Bytes,
+        metrics: Arc<Metrics>,
+        pool: Arc<ClientPool>,
+        router: RouterService,
+        toggles: ServiceToggles,
+        peers: Arc<PeerIndex>,
+        events: PacketEvents,
+        settlement: Option<Settlement>,
+        next: S,
+    ) -> Self {
+        AdminFilter {
+            data: config.map(|config| Arc::new(AdminData {
+                tokens: config.auth.into_iter().collect(),
+                config_summary,
+                metrics,
+                pool,
+                router,
+                toggles,
+                peers,
+                events,
+                settlement,
+            })),
+            next,
+        }
+    }
+}
+
+impl<S> HyperService<HTTPRequest> for AdminFilter<S>
+where
+    S: HyperService<
+        HTTPRequest,
+        Response = hyper::Response<hyper::Body>,
+        Error = hyper::Error,
+    >,
+{
+    type Response = http::Response<hyper::Body>;
+    type Error = hyper::Error;
+    type Future = Either<
+        Pin<Box<dyn Future<
+            Output = Result<Self::Response, Self::Error>,
+        > + Send + 'static>>,
+        S::Future,
+    >;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+       self.next.poll_ready(context)
+    }
+
+    fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+        let data = match &self.data {
+            Some(data) if request.uri().path().starts_with(PATH_PREFIX) => data,
+            _ => return Either::Right(self.next.call(request)),
+        };
+
+        let is_authorized = match authorization_token(request.headers()) {
+            Some(token) => data.tokens
+                .iter()
+                .fold(false, |found, admin_token| {
+                    found | constant_time_eq(admin_token.borrow(), token)
+                }),
+            None => false,
+        };
+        if !is_authorized {
+            warn!(
+                "invalid admin authorization: method={} path={:?}",
+                request.method(), request.uri().path(),
+            );
+            return Either::Left(Box::pin(ok(empty_response(hyper::StatusCode::UNAUTHORIZED))));
+        }
+
+        let path = &request.uri().path()[PATH_PREFIX.len()..];
+        if request.method() == hyper::Method::GET && path == "tap" {
+            return Either::Left(Box::pin(ok(tap_response(&data.events, request))));
+        }
+        if let Some(account) = path.strip_prefix(SETTLEMENTS_PREFIX) {
+            let account = account.to_owned();
+            return Either::Left(Box::pin({
+                Arc::clone(data).receive_settlement(account, request)
+            }));
+        }
+        Either::Left(Box::pin(ok(match (request.method(), path) {
+            (&hyper::Method::GET, "children") => data.children_response(),
+            (&hyper::Method::GET, "config") => hyper::Response::builder()
+                .status(hyper::StatusCode::OK)
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .header(hyper::header::CONTENT_LENGTH, data.config_summary.len())
+                .body(hyper::Body::from(data.config_summary.clone()))
+                .expect("response builder error"),
+            (&hyper::Method::GET, "metrics") => {
+                let metrics = data.metrics.render();
+                hyper::Response::builder()
+                    .status(hyper::StatusCode::OK)
+                    .header(hyper::header::CONTENT_TYPE, "text/plain; version=0.0.4")
+                    .header(hyper::header::CONTENT_LENGTH, metrics.len())
+                    .body(hyper::Body::from(metrics))
+                    .expect("response builder error")
+            },
+            (&hyper::Method::GET, "maintenance") => data.maintenance_response(),
+            (&hyper::Method::GET, "memory") => match AllocatorStats::get() {
+                Some(stats) => {
+                    let stats = serde_json::to_vec(&stats)
+                        .expect("allocator stats serialization error");
+                    hyper::Response::builder()
+                        .status(hyper::StatusCode::OK)
+                        .header(hyper::header::CONTENT_TYPE, "application/json")
+                        .header(hyper::header::CONTENT_LENGTH, stats.len())
+                        .body(hyper::Body::from(stats))
+                        .expect("response builder error")
+                },
+                None => empty_response(hyper::StatusCode::NOT_FOUND),
+            },
+            (&hyper::Method::GET, "pool") => {
+                let stats = serde_json::to_vec(&data.pool.stats())
+                    .expect("pool stats serialization error");
+                hyper::Response::builder()
+                    .status(hyper::StatusCode::OK)
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .header(hyper::header::CONTENT_LENGTH, stats.len())
+                    .body(hyper::Body::from(stats))
+                    .expect("response builder error")
+            },
+            (&hyper::Method::GET, "reject_reasons") => {
+                let reasons = serde_json::to_vec(REJECT_REASONS)
+                    .expect("reject reasons serialization error");
+                hyper::Response::builder()
+                    .status(hyper::StatusCode::OK)
+                    .header(hyper::header::CONTENT_TYPE, "application/json")
+                    .header(hyper::header::CONTENT_LENGTH, reasons.len())
+                    .body(hyper::Body::from(reasons))
+                    .expect("response builder error")
+            },
+            (&hyper::Method::GET, "schedule") =>
+                match data.router.schedule() {
+                    Some(status) => schedule_response(hyper::StatusCode::OK, &status),
+                    None => empty_response(hyper::StatusCode::NOT_FOUND),
+                },
+            (&hyper::Method::DELETE, "schedule") =>
+                match data.router.cancel_schedule() {
+                    Some(status) if status.state == ScheduleState::Cancelled =>
+                        schedule_response(hyper::StatusCode::OK, &status),
+                    Some(status) =>
+                        schedule_response(hyper::StatusCode::CONFLICT, &status),
+                    None => empty_response(hyper::StatusCode::NOT_FOUND),
+                },
+            (&hyper::Method::GET, "settlements") => data.settlements_response(),
+            (&hyper::Method::GET, "toggles") => data.toggles_response(),
+            (_, "children") | (_, "config") | (_, "maintenance") | (_, "memory")
+                | (_, "metrics")
+                | (_, "pool") | (_, "reject_reasons") | (_, "schedule")
+                | (_, "settlements") | (_, "tap") | (_, "toggles") =>
+                empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
+            (method, path) if path.starts_with(CHILDREN_PREFIX) =>
+                data.register_child(method, &path[CHILDREN_PREFIX.len()..]),
+            (method, path) if path.starts_with(MAINTENANCE_PREFIX) => data
+                .set_maintenance(
+                    method,
+                    &path[MAINTENANCE_PREFIX.len()..],
+                    request.uri().query(),
+                ),
+            (method, path) if path.starts_with(POOL_PREFIX) =>
+                data.flush_pool(method, &path[POOL_PREFIX.len()..]),
+            (method, path) if path.starts_with(TOGGLES_PREFIX) =>
+                data.set_toggle(method, &path[TOGGLES_PREFIX.len()..]),
+            _ => empty_response(hyper::StatusCode::NOT_FOUND),
+        })))
+    }
+}
+
+impl AdminData {
+    fn children_response(&self) -> hyper::Response<hyper::Body> {
+        let registry = match self.peers.registry() {
+            Some(registry) => registry,
+            None => return empty_response(hyper::StatusCode::NOT_FOUND),
+        };
+        let children = serde_json::to_vec(&registry.children())
+            .expect("children serialization error");
+        hyper::Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CONTENT_LENGTH, children.len())
+            .body(hyper::Body::from(children))
+            .expect("response builder error")
+    }
+
+    fn register_child(&self, method: &hyper::Method, account: &str)
+        -> hyper::Response<hyper::Body>
+    {
+        let registry = match self.peers.registry() {
+            Some(registry) => registry,
+            None => return empty_response(hyper::StatusCode::NOT_FOUND),
+        };
+        let account = match percent_decode_str(account).decode_utf8() {
+            Ok(account) => account,
+            Err(_) => return empty_response(hyper::StatusCode::BAD_REQUEST),
+        };
+        match *method {
+            hyper::Method::POST =>
+                registration_response(self.peers.register_child(&account)),
+            hyper::Method::DELETE => match registry.unregister(&account) {
+                Ok(true) => empty_response(hyper::StatusCode::NO_CONTENT),
+                Ok(false) => empty_response(hyper::StatusCode::NOT_FOUND),
+                Err(_error) =>
+                    empty_response(hyper::StatusCode::INTERNAL_SERVER_ERROR),
+            },
+            _ => empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
+        }
+    }
+
+    fn toggles_response(&self) -> hyper::Response<hyper::Body> {
+        let toggles = serde_json::to_vec(&self.toggles)
+            .expect("toggles serialization error");
+        hyper::Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CONTENT_LENGTH, toggles.len())
+            .body(hyper::Body::from(toggles))
+            .expect("response builder error")
+    }
+
+    fn maintenance_response(&self) -> hyper::Response<hyper::Body> {
+        let maintenance = serde_json::to_vec(&self.router.maintenance())
+            .expect("maintenance serialization error");
+        hyper::Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CONTENT_LENGTH, maintenance.len())
+            .body(hyper::Body::from(maintenance))
+            .expect("response builder error")
+    }
+
+    fn set_maintenance(
+        &self,
+        method: &hyper::Method,
+        account: &str,
+        query: Option<&str>,
+    ) -> hyper::Response<hyper::Body> {
+        let account = match percent_decode_str(account).decode_utf8() {
+            Ok(account) => account,
+            Err(_) => return empty_response(hyper::StatusCode::NOT_FOUND),
+        };
+        let maintenance = match *method {
+            hyper::Method::PUT => match parse_maintenance(query) {
+                Some(maintenance) => Some(maintenance),
+                None => return empty_response(hyper::StatusCode::BAD_REQUEST),
+            },
+            hyper::Method::DELETE => None,
+            _ => return empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
+        };
+        if !self.router.set_maintenance(&account, maintenance) {
+            return empty_response(hyper::StatusCode::NOT_FOUND);
+        }
+        self.maintenance_response()
+    }
+
+    fn flush_pool(&self, method: &hyper::Method, host: &str)
+        -> hyper::Response<hyper::Body>
+    {
+        if method != hyper::Method::DELETE {
+            return empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED);
+        }
+        if self.pool.flush(host) {
+            empty_response(hyper::StatusCode::NO_CONTENT)
+        } else {
+            empty_response(hyper::StatusCode::NOT_FOUND)
+        }
+    }
+
+    fn settlements_response(&self) -> hyper::Response<hyper::Body> {
+        let settlement = match &self.settlement {
+            Some(settlement) => settlement,
+            None => return empty_response(hyper::StatusCode::NOT_FOUND),
+        };
+        let balances = serde_json::to_vec(&settlement.balances())
+            .expect("balances serialization error");
+        hyper::Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CONTENT_LENGTH, balances.len())
+            .body(hyper::Body::from(balances))
+            .expect("response builder error")
+    }
+
+    async fn receive_settlement(
+        self: Arc<Self>,
+        account: String,
+        request: HTTPRequest,
+    ) -> Result<hyper::Response<hyper::Body>, hyper::Error> {
+        if request.method() != hyper::Method::POST {
+            return Ok(empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED));
+        }
+        let settlement = match &self.settlement {
+            Some(settlement) => settlement,
+            None => return Ok(empty_response(hyper::StatusCode::NOT_FOUND)),
+        };
+        let account = match percent_decode_str(&account).decode_utf8() {
+            Ok(account) => account,
+            Err(_) => return Ok(empty_response(hyper::StatusCode::NOT_FOUND)),
+        };
+        let idempotency_key = request.headers()
+            .get("Idempotency-Key")
+            .and_then(|key| key.to_str().ok())
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned);
+        let idempotency_key = match idempotency_key {
+            Some(key) => key,
+            None => return Ok(empty_response(hyper::StatusCode::BAD_REQUEST)),
+        };
+        let (parts, body) = request.into_parts();
+        let body = match combinators::collect_http_body(
+            &parts.headers,
+            body,
+            MAX_SETTLEMENT_SIZE,
+        ).await {
+            Ok(body) => body,
+            Err(LimitStreamError::StreamError(error)) => return Err(error),
+            Err(LimitStreamError::LimitExceeded) =>
+                return Ok(empty_response(hyper::StatusCode::PAYLOAD_TOO_LARGE)),
+        };
+        let quantity = match serde_json::from_slice::<Quantity>(&body) {
+            Ok(quantity) => quantity,
+            Err(_) => return Ok(empty_response(hyper::StatusCode::BAD_REQUEST)),
+        };
+        let credited = match settlement
+            .receive_settlement(&account, &idempotency_key, quantity)
+        {
+            Ok(credited) => credited,
+            Err(ReceiveError::UnknownAccount) =>
+                return Ok(empty_response(hyper::StatusCode::NOT_FOUND)),
+            Err(ReceiveError::InvalidAmount) =>
+                return Ok(empty_response(hyper::StatusCode::BAD_REQUEST)),
+            Err(ReceiveError::KeyReused) =>
+                return Ok(empty_response(hyper::StatusCode::CONFLICT)),
+        };
+        let credited = serde_json::to_vec(&credited)
+            .expect("quantity serialization error");
+        Ok(hyper::Response::builder()
+            .status(hyper::StatusCode::CREATED)
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CONTENT_LENGTH, credited.len())
+            .body(hyper::Body::from(credited))
+            .expect("response builder error"))
+    }
+
+    fn set_toggle(&self, method: &hyper::Method, name: &str)
+        -> hyper::Response<hyper::Body>
+    {
+        let toggle = match self.toggles.get(name) {
+            Some(toggle) => toggle,
+            None => return empty_response(hyper::StatusCode::NOT_FOUND),
+        };
+        let enabled = match *method {
+            hyper::Method::PUT => true,
+            hyper::Method::DELETE => false,
+            _ => return empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED),
+        };
+        info!("admin service toggle: service={} enabled={}", name, enabled);
+        toggle.set(enabled);
+        self.toggles_response()
+    }
+}
+
+/// Parse the (optional) `code` and `message` query parameters. Returns `None`
+/// if a parameter is invalid or unknown.
+fn parse_maintenance(query: Option<&str>) -> Option<RouteMaintenance> {
+    let mut maintenance = RouteMaintenance::default();
+    let params = query
+        .unwrap_or("")
+        .split('&')
+        .filter(|param| !param.is_empty());
+    for param in params {
+        let split = param.find('=')?;
+        let value = param[split + 1..].replace('+', " ");
+        let value = percent_decode_str(&value).decode_utf8().ok()?;
+        match &param[..split] {
+            "code" => {
+                maintenance.code = deserialize_error_code(
+                    BorrowedStrDeserializer::<ValueError>::new(&value),
+                ).ok()?;
+            },
+            "message" => maintenance.message = value.into_owned(),
+            _ => return None,
+        }
+    }
+    maintenance.validate().ok()?;
+    Some(maintenance)
+}
+
+fn schedule_response(
+    status: hyper::StatusCode,
+    schedule: &ScheduleStatus,
+) -> hyper::Response<hyper::Body> {
+    let schedule = serde_json::to_vec(schedule)
+        .expect("schedule serialization error");
+    hyper::Response::builder()
+        .status(status)
+        .header(hyper::header::CONTENT_TYPE, "application/json")
+        .header(hyper::header::CONTENT_LENGTH, schedule.len())
+        .body(hyper::Body::from(schedule))
+        .expect("response builder error")
+}
+
+/// The response to a child registration, from the admin API or the
+/// `RegistrationFilter`.
+pub(crate) fn registration_response(
+    registration: Option<Result<Registration, RegistrationError>>,
+) -> hyper::Response<hyper::Body> {
+    match registration {
+        Some(Ok(registration)) => {
+            let registration = serde_json::to_vec(&registration)
+                .expect("registration serialization error");
+            hyper::Response::builder()
+                .status(hyper::StatusCode::CREATED)
+                .header(hyper::header::CONTENT_TYPE, "application/json")
+                .header(hyper::header::CONTENT_LENGTH, registration.len())
+                .body(hyper::Body::from(registration))
+                .expect("response builder error")
+        },
+        Some(Err(RegistrationError::InvalidAccount)) =>
+            empty_response(hyper::StatusCode::BAD_REQUEST),
+        Some(Err(RegistrationError::AccountExists)) =>
+            empty_response(hyper::StatusCode::CONFLICT),
+        Some(Err(RegistrationError::Full)) =>
+            empty_response(hyper::StatusCode::FORBIDDEN),
+        Some(Err(RegistrationError::Store)) =>
+            empty_response(hyper::StatusCode::INTERNAL_SERVER_ERROR),
+        None => empty_response(hyper::StatusCode::NOT_FOUND),
+    }
+}
+
+pub(crate) fn empty_response(status: hyper::StatusCode) -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(status)
+        .body(hyper::Body::empty())
+        .expect("response builder error")
+}
+
+#[cfg(test)]
+mod test_admin_filter {
+    use std::time;
+
+    use futures::executor::block_on;
+    use futures::future::Ready;
+    use hyper::service::service_fn;
+
+    use crate::{Client, RoutingPartition, RoutingTable};
+    use crate::https_client::HttpsClient;
+    use crate::services::{SettlementAccountConfig, SettlementConfig};
+    use crate::testing::{ADDRESS, ILDCP_RESPONSE, ROUTES};
+    use crate::toggles::Toggle;
+    use super::*;
+
+    static SUMMARY: &[u8] = b"{\"address\":\"test.relay\"}";
+
+    type Response = hyper::Response<hyper::Body>;
+    type ResponseFuture = Ready<Result<Response, hyper::Error>>;
+    type AdminFuture = Pin<Box<dyn Future<
+        Output = Result<Response, hyper::Error>,
+    > + Send + 'static>>;
+
+    fn make_toggles() -> ServiceToggles {
+        ServiceToggles {
+            echo: Toggle::new(false),
+            ildcp: Toggle::new(true),
+            debug: Toggle::new(true),
+            simulation: Toggle::new(false),
+            telemetry_cutover: Toggle::new(false),
+        }
+    }
+
+    fn make_pool() -> Arc<ClientPool> {
+        Arc::new(ClientPool::new(&Default::default(), None))
+    }
+
+    fn make_peers() -> Arc<PeerIndex> {
+        Arc::new(PeerIndex::default())
+    }
+
+    fn make_router() -> RouterService {
+        RouterService::new(
+            ADDRESS.to_address(),
+            Arc::new(Client::new(ADDRESS.to_address())),
+            RoutingTable::new(ROUTES.clone(), RoutingPartition::default()),
+            false,
+        )
+    }
+
+    fn make_filter(config: Option<AdminApiConfig>) -> impl HyperService<
+        HTTPRequest,
+        Response = Response,
+        Error = hyper::Error,
+        Future = Either<AdminFuture, ResponseFuture>,
+    > {
+        let next = service_fn(|_req| {
+            ok::<_, hyper::Error>(hyper::Response::builder()
+                .status(204)
+                .body(hyper::Body::empty())
+                .unwrap())
+        });
+        let metrics = Metrics::default();
+        metrics.increment("test_total", vec![], 1);
+        AdminFilter::new(
+            config,
+            Bytes::from(SUMMARY),
+            Arc::new(metrics),
+            make_pool(),
+            make_router(),
+            make_toggles(),
+            make_peers(),
+            PacketEvents::new(1),
+            None,
+            next,
+        )
+    }
+
+    fn admin_request(method: &str, path: &str, auth: Option<&str>)
+        -> hyper::Request<hyper::Body>
+    {
+        let mut builder = hyper::Request::builder()
+            .method(method)
+            .uri(path);
+        if let Some(auth) = auth {
+            builder = builder.header("Authorization", auth);
+        }
+        builder.body(hyper::Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_disabled() {
+        let mut service = make_filter(None);
+        assert_eq!(
+            block_on(service.call(admin_request("GET", "/admin/config", None)))
+                .unwrap()
+                .status(),
+            204,
+        );
+    }
+
+    #[test]
+    fn test_get_config() {
+        let mut service = make_filter(Some(AdminApiConfig {
+            auth: vec![AuthToken::new("admin_secret")],
+        }));
+
+        let response = block_on(service.call({
+            admin_request("GET", "/admin/config", Some("Bearer admin_secret"))
+        })).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get("Content-Type").unwrap(),
+            "application/json",
+        );
+        let body = block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        assert_eq!(body.as_ref(), SUMMARY);
+
+        let response = block_on(service.call({
+            admin_request("GET", "/admin/metrics", Some("admin_secret"))
+        })).unwrap();
+        assert_eq!(response.status(), 200);
+        let body = block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        assert_eq!(body.as_ref(), b"# TYPE test_total counter\ntest_total 1\n");
+
+        let response = block_on(service.call({
+            admin_request("GET", "/admin/reject_reasons", Some("admin_secret"))
+        })).unwrap();
+        assert_eq!(response.status(), 200);
+        let body = block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        let reasons: Vec<serde_json::Value> =
+            serde_json::from_slice(&body).unwrap();
+        assert_eq!(reasons.len(), REJECT_REASONS.len());
+        assert!(reasons.iter().any(|reason| {
+            reason["id"] == "no_route" && reason["code"] == "F02"
+        }));
+
+        let response = block_on(service.call({
+            admin_request("GET", "/admin/pool", Some("admin_secret"))
+        })).unwrap();
+        assert_eq!(response.status(), 200);
+        let body = block_on(hyper::body::to_bytes(response.into_body())).unwrap();
+        assert_eq!(body.as_ref(), b"{}");
+        assert_eq!(
+            block_on(service.call({
+                admin_request("DELETE", "/admin/pool/127.0.0.1:3001", Some("admin_secret"))
+            })).unwrap().status(),
+            404,
+        );
+        assert_eq!(
+            block_on(service.call({
+                admin_request("PUT", "/admin/pool/127.0.0.1:3001", Some("admin_secret"))
+            })).unwrap().status(),
+            405,
+        );
+
+        // The tests don't install the `CountingAllocator`.
+        assert_eq!(
+            block_on(service.call({
+                admin_request("GET", "/admin/memory", Some("admin_secret"))
+            })).unwrap().status(),
+            404,
+        );
+
+        // Missing or incorrect token.
+        assert_eq!(
+            block_on(service.call(admin_request("GET", "/admin/config", None)))
+                .unwrap()
+                .status(),
+            401,
+        );
+        assert_eq!(
+            block_on(service.call({
+                admin_request("GET", "/admin/config", Some("not_a_token"))
+            })).unwrap().status(),
+            401,
+        );
+
+        // Wrong method or path.
+        assert_eq!(
+            block_on(service.call({
+                admin_request("POST", "/admin/config", Some("admin_secret"))
+            })).unwrap().status(),
+            405,
+        );
+        assert_eq!(
+            block_on(service.call({
+                admin_request("GET", "/admin/nope", Some("admin_secret"))
+            })).unwrap().status(),
+            404,
+        );
+
+        // Other paths are passed through.
+        assert_eq!(
+            block_on(service.call(admin_request("POST", "/ilp", None)))
+                .unwrap()
+                .status(),
+            204,
+        );
+    }
+
+    #[test]
+    fn test_toggles() {
+        let toggles = make_toggles();
+        let mut service = AdminFilter::new(
+            Some(AdminApiConfig { auth: vec![AuthToken::new("admin_secret")] }),
+            Bytes::from(SUMMARY),
+            Arc::new(Metrics::default()),
+            make_pool(),
+            make_router(),
+            toggles.clone(),
+            make_peers(),
+            PacketEvents::new(1),
+            None,
+            service_fn(|_req| {
+                ok::<_, hyper::Error>(empty_response(hyper::StatusCode::NO_CONTENT))
+            }),
+        );
+        let mut call = |method: &str, path: &str| {
+            let response = block_on(service.call({
+                admin_request(method, path, Some("admin_secret"))
+            })).unwrap();
+            let status = response.status();
+            let body = block_on(hyper::body::to_bytes(response.into_body()))
+                .unwrap();
+            (status, body)
+        };
+
+        assert_eq!(
+            call("GET", "/admin/toggles"),
+            (
+                hyper::StatusCode::OK,
+                Bytes::from(r#"{"echo":false,"ildcp":true,"debug":true,"simulation":false,"telemetry_cutover":false}"#),
+            ),
+        );
+        assert_eq!(
+            call("PUT", "/admin/toggles/echo"),
+            (
+                hyper::StatusCode::OK,
+                Bytes::from(r#"{"echo":true,"ildcp":true,"debug":true,"simulation":false,"telemetry_cutover":false}"#),
+            ),
+        );
+        assert!(toggles.echo.is_enabled());
+        assert_eq!(call("DELETE", "/admin/toggles/debug").0, 200);
+        assert!(!toggles.debug.is_enabled());
+
+        assert_eq!(call("POST", "/admin/toggles/echo").0, 405);
+        assert_eq!(call("PUT", "/admin/toggles").0, 405);
+        assert_eq!(call("PUT", "/admin/toggles/unknown").0, 404);
+    }
+
+    #[test]
+    fn test_maintenance() {
+        let router = make_router();
+        let mut service = AdminFilter::new(
+            Some(AdminApiConfig { auth: vec![AuthToken::new("admin_secret")] }),
+            Bytes::from(SUMMARY),
+            Arc::new(Metrics::default()),
+            make_pool(),
+            router.clone(),
+            make_toggles(),
+            make_peers(),
+            PacketEvents::new(1),
+            None,
+            service_fn(|_req| {
+                ok::<_, hyper::Error>(empty_response(hyper::StatusCode::NO_CONTENT))
+            }),
+        );
+        let mut call = |method: &str, path: &str| {
+            let response = block_on(service.call({
+                admin_request(method, path, Some("admin_secret"))
+            })).unwrap();
+            let status = response.status();
+            let body = block_on(hyper::body::to_bytes(response.into_body()))
+                .unwrap();
+            (status, body)
+        };
+
+        assert_eq!(call("GET", "/admin/maintenance"), (
+            hyper::StatusCode::OK,
+            Bytes::from("{}"),
+        ));
+        assert_eq!(call("PUT", "/admin/maintenance/alice"), (
+            hyper::StatusCode::OK,
+            Bytes::from(r#"{"alice":{"code":"T01","message":"route is under maintenance"}}"#),
+        ));
+        assert_eq!(
+            call("PUT", "/admin/maintenance/bob?code=T00&message=back+at+10%3A00").1,
+            Bytes::from(r#"{"alice":{"code":"T01","message":"route is under maintenance"},"bob":{"code":"T00","message":"back at 10:00"}}"#),
+        );
+        assert_eq!(router.maintenance()["bob"].message, "back at 10:00");
+        assert_eq!(call("DELETE", "/admin/maintenance/alice"), (
+            hyper::StatusCode::OK,
+            Bytes::from(r#"{"bob":{"code":"T00","message":"back at 10:00"}}"#),
+        ));
+
+        assert_eq!(call("PUT", "/admin/maintenance/carl").0, 404);
+        assert_eq!(call("PUT", "/admin/maintenance/alice?code=X00").0, 400);
+        assert_eq!(call("PUT", "/admin/maintenance/alice?other=1").0, 400);
+        let long_message = format!(
+            "/admin/maintenance/alice?message={}",
+            "a".repeat(8192),
+        );
+        assert_eq!(call("PUT", &long_message).0, 400);
+        assert_eq!(call("POST", "/admin/maintenance/alice").0, 405);
+        assert_eq!(call("PUT", "/admin/maintenance").0, 405);
+    }
+
+    #[test]
+    fn test_children() {
+        use std::collections::HashSet;
+        use crate::Relation;
+        use crate::middlewares::AuthHeader;
+        use crate::peers::{ChildRegistrationConfig, ChildRegistry, ConnectorPeer};
+
+        let registry = ChildRegistry::new(
+            ChildRegistrationConfig {
+                suffix_prefix: String::new(),
+                max_children: 1,
+                provisioning_tokens: Vec::new(),
+                store: None,
+            },
+            ADDRESS.to_address(),
+        );
+        let peers = PeerIndex::new(AuthHeader::default(), vec![ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new("static_child".to_owned()),
+            address: ADDRESS.with_suffix(b"static_child").unwrap(),
+            auth: HashSet::new(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline: false,
+        }]).with_registry(Arc::new(registry));
+        let peers = Arc::new(peers);
+        let mut service = AdminFilter::new(
+            Some(AdminApiConfig { auth: vec![AuthToken::new("admin_secret")] }),
+            Bytes::from(SUMMARY),
+            Arc::new(Metrics::default()),
+            make_pool(),
+            make_router(),
+            make_toggles(),
+            Arc::clone(&peers),
+            PacketEvents::new(1),
+            None,
+            service_fn(|_req| {
+                ok::<_, hyper::Error>(empty_response(hyper::StatusCode::NO_CONTENT))
+            }),
+        );
+        let mut call = |method: &str, path: &str| {
+            let response = block_on(service.call({
+                admin_request(method, path, Some("admin_secret"))
+            })).unwrap();
+            let status = response.status();
+            let body = block_on(hyper::body::to_bytes(response.into_body()))
+                .unwrap();
+            (status, body)
+        };
+
+        let (status, body) = call("POST", "/admin/children/alice");
+        assert_eq!(status, 201);
+        let registration: serde_json::Value =
+            serde_json::from_slice(&body).unwrap();
+        assert_eq!(registration["account"], "alice");
+        assert_eq!(registration["address"], "test.relay.alice");
+        let token = registration["auth"].as_str().unwrap();
+        assert_eq!(
+            peers.find(token.as_bytes()).unwrap().account.as_str(),
+            "alice",
+        );
+        assert_eq!(call("GET", "/admin/children"), (
+            hyper::StatusCode::OK,
+            Bytes::from(r#"[{"account":"alice","address":"test.relay.alice"}]"#),
+        ));
+
+        assert_eq!(call("POST", "/admin/children/alice").0, 409);
+        assert_eq!(call("POST", "/admin/children/static_child").0, 409);
+        assert_eq!(call("POST", "/admin/children/a.b").0, 400);
+        assert_eq!(call("POST", "/admin/children/bob").0, 403);
+        assert_eq!(call("PUT", "/admin/children/bob").0, 405);
+        assert_eq!(call("POST", "/admin/children").0, 405);
+
+        assert_eq!(call("DELETE", "/admin/children/alice").0, 204);
+        assert_eq!(call("DELETE", "/admin/children/alice").0, 404);
+        assert!(peers.find(token.as_bytes()).is_none());
+
+        // Registration isn't configured.
+        let mut service = make_filter(Some(AdminApiConfig {
+            auth: vec![AuthToken::new("admin_secret")],
+        }));
+        for (method, path) in &[("GET", "/admin/children"), ("POST", "/admin/children/alice")] {
+            let response = block_on(service.call({
+                admin_request(method, path, Some("admin_secret"))
+            })).unwrap();
+            assert_eq!(response.status(), 404);
+        }
+    }
+
+    #[test]
+    fn test_schedule() {
+        let router = make_router();
+        let mut service = AdminFilter::new(
+            Some(AdminApiConfig { auth: vec![AuthToken::new("admin_secret")] }),
+            Bytes::from(SUMMARY),
+            Arc::new(Metrics::default()),
+            make_pool(),
+            router.clone(),
+            make_toggles(),
+            make_peers(),
+            PacketEvents::new(1),
+            None,
+            service_fn(|_req| {
+                ok::<_, hyper::Error>(empty_response(hyper::StatusCode::NO_CONTENT))
+            }),
+        );
+        let mut call = |method: &str| {
+            let response = block_on(service.call({
+                admin_request(method, "/admin/schedule", Some("admin_secret"))
+            })).unwrap();
+            let status = response.status();
+            let body = block_on(hyper::body::to_bytes(response.into_body()))
+                .unwrap();
+            (status, body)
+        };
+        let schedule = |activate_at| {
+            let mut runtime = tokio::runtime::Builder::new()
+                .basic_scheduler()
+                .enable_time()
+                .build()
+                .unwrap();
+            runtime.block_on(async {
+                router.schedule_routes(
+                    activate_at,
+                    RoutingTable::new(vec![], RoutingPartition::default()),
+                    None,
+                );
+            });
+        };
+
+        assert_eq!(call("GET").0, 404);
+        assert_eq!(call("DELETE").0, 404);
+
+        // 2100-01-01T00:00:00Z
+        schedule(time::UNIX_EPOCH + time::Duration::from_secs(4_102_444_800));
+        assert_eq!(call("GET"), (
+            hyper::StatusCode::OK,
+            Bytes::from(r#"{"state":"Pending","activate_at":"2100-01-01T00:00:00Z","prepares":0,"errors":0}"#),
+        ));
+        assert_eq!(call("DELETE"), (
+            hyper::StatusCode::OK,
+            Bytes::from(r#"{"state":"Cancelled","activate_at":"2100-01-01T00:00:00Z","prepares":0,"errors":0}"#),
+        ));
+
+        // 2020-10-01T02:00:00Z
+        schedule(time::UNIX_EPOCH + time::Duration::from_secs(1_601_517_600));
+        assert_eq!(call("DELETE"), (
+            hyper::StatusCode::CONFLICT,
+            Bytes::from(r#"{"state":"Committed","activate_at":"2020-10-01T02:00:00Z","prepares":0,"errors":0}"#),
+        ));
+        assert_eq!(call("PUT").0, 405);
+    }
+
+    #[test]
+    fn test_settlements() {
+        let settlement = Settlement::new(SettlementConfig {
+            engine_url: "http://127.0.0.1:3000".to_owned(),
+            accounts: vec![("alice".to_owned(), SettlementAccountConfig {
+                settle_threshold: 1_000,
+                settle_to: 0,
+                asset_scale: None,
+            })].into_iter().collect(),
+        }, ILDCP_RESPONSE.clone(), Arc::new(Metrics::default()), Arc::new(HttpsClient::new())).unwrap();
+        settlement.record_receivable("alice", 100);
+        let mut service = AdminFilter::new(
+            Some(AdminApiConfig { auth: vec![AuthToken::new("admin_secret")] }),
+            Bytes::from(SUMMARY),
+            Arc::new(Metrics::default()),
+            make_pool(),
+            make_router(),
+            make_toggles(),
+            make_peers(),
+            PacketEvents::new(1),
+            Some(settlement),
+            service_fn(|_req| {
+                ok::<_, hyper::Error>(empty_response(hyper::StatusCode::NO_CONTENT))
+            }),
+        );
+        let mut call = |request| {
+            let response = block_on(service.call(request)).unwrap();
+            let status = response.status();
+            let body = block_on(hyper::body::to_bytes(response.into_body()))
+                .unwrap();
+            (status, body)
+        };
+        let receive = |method: &str, account: &str, key: Option<&str>, body: &'static str| {
+            let mut builder = hyper::Request::builder()
+                .method(method)
+                .uri(format!("/admin/settlements/{}", account))
+                .header("Authorization", "admin_secret");
+            if let Some(key) = key {
+                builder = builder.header("Idempotency-Key", key);
+            }
+            builder.body(hyper::Body::from(body)).unwrap()
+        };
+        let quantity = r#"{"amount":"3000","scale":11}"#;
+
+        assert_eq!(call(receive("POST", "alice", Some("key1"), quantity)), (
+            hyper::StatusCode::CREATED,
+            Bytes::from(r#"{"amount":"30","scale":9}"#),
+        ));
+        // A retry isn't credited twice.
+        assert_eq!(call(receive("POST", "alice", Some("key1"), quantity)), (
+            hyper::StatusCode::CREATED,
+            Bytes::from(r#"{"amount":"30","scale":9}"#),
+        ));
+        assert_eq!(call(admin_request("GET", "/admin/settlements", Some("admin_secret"))), (
+            hyper::StatusCode::OK,
+            Bytes::from(r#"{"alice":{"payable":0,"receivable":70,"is_settling":false,"pending":0}}"#),
+        ));
+        assert_eq!(call(receive("POST", "alice", Some("key1"), r#"{"amount":"1","scale":9}"#)).0, 409);
+        assert_eq!(call(receive("POST", "alice", Some("key2"), r#"{"amount":"x","scale":9}"#)).0, 400);
+        assert_eq!(call(receive("POST", "alice", Some("key2"), "30")).0, 400);
+        assert_eq!(call(receive("POST", "alice", None, quantity)).0, 400);
+        assert_eq!(call(receive("POST", "bob", Some("key2"), quantity)).0, 404);
+        assert_eq!(call(receive("PUT", "alice", Some("key2"), quantity)).0, 405);
+        assert_eq!(call(admin_request("POST", "/admin/settlements", Some("admin_secret"))).0, 405);
+    }
+}
@@ -0,0 +1,201 @@
+use std::collections::HashMap;
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time;
+
+use bytes::Bytes;
+use log::warn;
+use serde::Deserialize;
+
+use crate::metrics::Metrics;
+
+/// The number of token bytes that identify a source when its IP is unknown.
+const TOKEN_PREFIX_SIZE: usize = 4;
+/// Expired entries are pruned once there are more sources than this.
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct AuthLockoutConfig {
+    /// The number of failed attempts (within `window`) that triggers a lockout.
+    pub max_failures: u32,
+    // <https://docs.serde.rs/serde/de/trait.Deserialize.html#impl-Deserialize%3C%27de%3E-for-Duration>
+    pub window: time::Duration,
+    /// How long a source is locked out for.
+    pub lockout: time::Duration,
+}
+
+/// Track failed authentication attempts per source, and lock out sources with
+/// too many failures.
+///
+/// A source is identified by its IP address (from the `SocketAddr` request
+/// extension). If that is unavailable, the first few bytes of the offered
+/// credentials are used instead.
+#[derive(Debug)]
+pub struct AuthLockout {
+    config: AuthLockoutConfig,
+    metrics: Arc<Metrics>,
+    sources: Mutex<HashMap<SourceKey, SourceState>>,
+}
+
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub(crate) enum SourceKey {
+    Ip(IpAddr),
+    TokenPrefix(Bytes),
+}
+
+#[derive(Debug)]
+struct SourceState {
+    failures: u32,
+    window_start: time::Instant,
+    locked_until: Option<time::Instant>,
+}
+
+impl AuthLockout {
+    pub fn new(config: AuthLockoutConfig, metrics: Arc<Metrics>) -> Self {
+        AuthLockout {
+            config,
+            metrics,
+            sources: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn source_key(
+        extensions: &http::Extensions,
+        credentials: Option<&[u8]>,
+    ) -> SourceKey {
+        match extensions.get::<SocketAddr>() {
+            Some(socket_addr) => SourceKey::Ip(socket_addr.ip()),
+            None => {
+                let credentials = credentials.unwrap_or(&[]);
+                let size = std::cmp::min(TOKEN_PREFIX_SIZE, credentials.len());
+                SourceKey::TokenPrefix(Bytes::copy_from_slice(&credentials[..size]))
+            },
+        }
+    }
+
+    pub(crate) fn is_locked(&self, key: &SourceKey, now: time::Instant) -> bool {
+        let sources = self.sources.lock().unwrap();
+        let locked_until = sources
+            .get(key)
+            .and_then(|state| state.locked_until);
+        matches!(locked_until, Some(locked_until) if now < locked_until)
+    }
+
+    pub(crate) fn record_failure(&self, key: SourceKey, now: time::Instant) {
+        self.metrics.increment("ilp_relay_auth_failures_total", vec![], 1);
+        let mut sources = self.sources.lock().unwrap();
+        if PRUNE_THRESHOLD <= sources.len() {
+            let window = self.config.window;
+            sources.retain(|_key, state| state.is_active(window, now));
+        }
+
+        let state = sources.entry(key.clone()).or_insert(SourceState {
+            failures: 0,
+            window_start: now,
+            locked_until: None,
+        });
+        if state.window_start + self.config.window <= now {
+            state.failures = 0;
+            state.window_start = now;
+        }
+        state.failures += 1;
+
+        if self.config.max_failures <= state.failures {
+            let locked_until = now + self.config.lockout;
+            state.failures = 0;
+            state.locked_until = Some(locked_until);
+            warn!(
+                "auth lockout: source={} lockout={:?}",
+                key, self.config.lockout,
+            );
+            self.metrics.increment("ilp_relay_auth_lockouts_total", vec![], 1);
+        }
+    }
+}
+
+impl SourceState {
+    fn is_active(&self, window: time::Duration, now: time::Instant) -> bool {
+        now < self.window_start + window
+            || matches!(self.locked_until, Some(until) if now < until)
+    }
+}
+
+impl fmt::Display for SourceKey {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SourceKey::Ip(ip) => write!(f, "ip:{}", ip),
+            SourceKey::TokenPrefix(prefix) => {
+                write!(f, "token_prefix:{:?}", prefix)
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_auth_lockout {
+    use super::*;
+
+    static CONFIG: AuthLockoutConfig = AuthLockoutConfig {
+        max_failures: 3,
+        window: time::Duration::from_secs(10),
+        lockout: time::Duration::from_secs(60),
+    };
+
+    #[test]
+    fn test_lockout() {
+        let metrics = Arc::new(Metrics::default());
+        let lockout = AuthLockout::new(CONFIG.clone(), Arc::clone(&metrics));
+        let key = SourceKey::Ip("127.0.0.1".parse().unwrap());
+        let other_key = SourceKey::Ip("127.0.0.2".parse().unwrap());
+        let start = time::Instant::now();
+        let secs = time::Duration::from_secs;
+
+        lockout.record_failure(key.clone(), start);
+        lockout.record_failure(key.clone(), start + secs(1));
+        assert!(!lockout.is_locked(&key, start + secs(1)));
+        lockout.record_failure(key.clone(), start + secs(2));
+        assert!(lockout.is_locked(&key, start + secs(2)));
+        assert!(lockout.is_locked(&key, start + secs(61)));
+        assert!(!lockout.is_locked(&key, start + secs(62)));
+        assert!(!lockout.is_locked(&other_key, start + secs(2)));
+
+        assert_eq!(metrics.get("ilp_relay_auth_failures_total", vec![]), 3);
+        assert_eq!(metrics.get("ilp_relay_auth_lockouts_total", vec![]), 1);
+    }
+
+    #[test]
+    fn test_window() {
+        let lockout = AuthLockout::new(CONFIG.clone(), Arc::default());
+        let key = SourceKey::TokenPrefix(Bytes::from("toke"));
+        let start = time::Instant::now();
+        let secs = time::Duration::from_secs;
+
+        // Failures in different windows don't add up.
+        lockout.record_failure(key.clone(), start);
+        lockout.record_failure(key.clone(), start + secs(1));
+        lockout.record_failure(key.clone(), start + secs(11));
+        assert!(!lockout.is_locked(&key, start + secs(11)));
+    }
+
+    #[test]
+    fn test_source_key() {
+        let mut extensions = http::Extensions::new();
+        assert_eq!(
+            AuthLockout::source_key(&extensions, Some(b"token_1")),
+            SourceKey::TokenPrefix(Bytes::from("toke")),
+        );
+        assert_eq!(
+            AuthLockout::source_key(&extensions, None),
+            SourceKey::TokenPrefix(Bytes::new()),
+        );
+
+        let socket_addr = "127.0.0.1:1234".parse::<SocketAddr>().unwrap();
+        extensions.insert(socket_addr);
+        assert_eq!(
+            AuthLockout::source_key(&extensions, Some(b"token_1")),
+            SourceKey::Ip(socket_addr.ip()),
+        );
+    }
+}
@@ -0,0 +1,114 @@
+use bytes::Bytes;
+use futures::future::{Either, Ready, ok};
+use futures::task::{Context, Poll};
+use hyper::service::Service as HyperService;
+
+use crate::capabilities::Capabilities;
+
+type HTTPRequest = http::Request<hyper::Body>;
+
+/// Respond to `OPTIONS` requests with the connector's `Capabilities` (as
+/// JSON), so that peers can adapt their requests to it. The response doesn't
+/// depend on the peer, so the request isn't authenticated.
+#[derive(Clone, Debug)]
+pub struct CapabilitiesFilter<S> {
+    body: Bytes,
+    next: S,
+}
+
+impl<S> CapabilitiesFilter<S>
+where
+    S: HyperService<HTTPRequest>,
+{
+    pub fn new(next: S) -> Self {
+        let body = serde_json::to_vec(&Capabilities::local())
+            .expect("Capabilities serialize error");
+        CapabilitiesFilter { body: Bytes::from(body), next }
+    }
+}
+
+impl<S> HyperService<HTTPRequest> for CapabilitiesFilter<S>
+where
+    S: HyperService<
+        HTTPRequest,
+        Response = hyper::Response<hyper::Body>,
+        Error = hyper::Error,
+    >,
+{
+    type Response = http::Response<hyper::Body>;
+    type Error = hyper::Error;
+    type Future = Either<
+        Ready<Result<Self::Response, Self::Error>>,
+        S::Future,
+    >;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+       self.next.poll_ready(context)
+    }
+
+    fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+        if request.method() != hyper::Method::OPTIONS {
+            return Either::Right(self.next.call(request));
+        }
+        Either::Left(ok(hyper::Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(hyper::header::ALLOW, "OPTIONS, POST")
+            .header(hyper::header::CONTENT_TYPE, "application/json")
+            .header(hyper::header::CONTENT_LENGTH, self.body.len())
+            .body(hyper::Body::from(self.body.clone()))
+            .expect("response builder error")))
+    }
+}
+
+#[cfg(test)]
+mod test_capabilities_filter {
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    use crate::combinators;
+    use super::*;
+
+    #[test]
+    fn test_service() {
+        let next = service_fn(|_req| {
+            ok(hyper::Response::builder()
+                .status(500)
+                .body(hyper::Body::empty())
+                .unwrap())
+        });
+        let mut service = CapabilitiesFilter::new(next);
+
+        let response = block_on(service.call({
+            hyper::Request::options("/ilp")
+                .body(hyper::Body::empty())
+                .unwrap()
+        })).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get("Content-Type").unwrap(),
+            "application/json",
+        );
+        let (parts, body) = response.into_parts();
+        let body = block_on(combinators::collect_http_body(
+            &parts.headers,
+            body,
+            1024,
+        )).unwrap();
+        assert_eq!(
+            serde_json::from_slice::<Capabilities>(&body).unwrap(),
+            Capabilities::local(),
+        );
+
+        // Other methods are passed through.
+        assert_eq!(
+            block_on(service.call({
+                hyper::Request::post("/ilp")
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })).unwrap().status(),
+            500,
+        );
+    }
+}
@@ -2,11 +2,16 @@ use futures::future::{Either, Ready, ok};
 use futures::task::{Context, Poll};
 use hyper::service::Service as HyperService;
 
+use crate::toggles::Toggle;
+
 type HTTPRequest = http::Request<hyper::Body>;
 
-/// Respond with `200: OK` to `GET` requests.
+/// Respond with `200: OK` to `GET` requests, or with
+/// `503: Service Unavailable` once the connector is draining (see
+/// `PreStopFilter`), so that load balancers stop routing to it.
 #[derive(Clone, Debug)]
 pub struct HealthCheckFilter<S> {
+    draining: Toggle,
     next: S,
 }
 
@@ -14,8 +19,8 @@ impl<S> HealthCheckFilter<S>
 where
     S: HyperService<HTTPRequest>,
 {
-    pub fn new(next: S) -> Self {
-        HealthCheckFilter { next }
+    pub fn new(draining: Toggle, next: S) -> Self {
+        HealthCheckFilter { draining, next }
     }
 }
 
@@ -42,14 +47,21 @@ where
 
     fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
         static BODY: &[u8] = b"OK";
-        if request.method() == hyper::Method::GET {
+        static DRAINING_BODY: &[u8] = b"service stopping";
+        if request.method() != hyper::Method::GET {
+            Either::Right(self.next.call(request))
+        } else if self.draining.is_enabled() {
+            Either::Left(ok(hyper::Response::builder()
+                .status(hyper::StatusCode::SERVICE_UNAVAILABLE) // 503
+                .header(hyper::header::CONTENT_LENGTH, DRAINING_BODY.len())
+                .body(hyper::Body::from(DRAINING_BODY))
+                .expect("response builder error")))
+        } else {
             Either::Left(ok(hyper::Response::builder()
                 .status(hyper::StatusCode::OK)
                 .header(hyper::header::CONTENT_LENGTH, BODY.len())
                 .body(hyper::Body::from(BODY))
                 .expect("response builder error")))
-        } else {
-            Either::Right(self.next.call(request))
         }
     }
 }
@@ -69,7 +81,8 @@ mod test_health_check_filter {
                 .body(hyper::Body::empty())
                 .unwrap())
         });
-        let mut service = HealthCheckFilter::new(next);
+        let draining = Toggle::new(false);
+        let mut service = HealthCheckFilter::new(draining.clone(), next);
 
         // GET
         assert_eq!(
@@ -90,5 +103,16 @@ mod test_health_check_filter {
             })).unwrap().status(),
             500,
         );
+
+        // GET (draining)
+        draining.set(true);
+        assert_eq!(
+            block_on(service.call({
+                hyper::Request::get("/")
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })).unwrap().status(),
+            503,
+        );
     }
 }
@@ -0,0 +1,383 @@
+use std::collections::HashMap;
+use std::error::Error as StdError;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::str::FromStr;
+use std::sync::Arc;
+
+use futures::future::{Either, Ready, ok};
+use futures::task::{Context, Poll};
+use hyper::service::Service as HyperService;
+use log::debug;
+use serde::de::{Deserialize, Deserializer, Error as _};
+
+use crate::peers::ConnectorPeer;
+
+type HTTPRequest = http::Request<hyper::Body>;
+
+static FORWARDED_FOR: &str = "X-Forwarded-For";
+
+/// Reject requests from peers whose `allowed_ips` don't include the client's
+/// IP address with `403 Forbidden`.
+///
+/// This must follow the authentication filters, since it checks the
+/// `ConnectorPeer` that they attach to the request. Requests without one are
+/// passed on unchanged.
+#[derive(Clone, Debug)]
+pub struct IpAllowlistFilter<S> {
+    allowlist: Arc<IpAllowlist>,
+    next: S,
+}
+
+impl<S> IpAllowlistFilter<S>
+where
+    S: HyperService<HTTPRequest>,
+{
+    pub fn new(allowlist: Arc<IpAllowlist>, next: S) -> Self {
+        IpAllowlistFilter { allowlist, next }
+    }
+}
+
+impl<S> HyperService<HTTPRequest> for IpAllowlistFilter<S>
+where
+    S: HyperService<
+        HTTPRequest,
+        Response = hyper::Response<hyper::Body>,
+        Error = hyper::Error,
+    >,
+{
+    type Response = http::Response<hyper::Body>;
+    type Error = hyper::Error;
+    type Future = Either<
+        S::Future,
+        // This Future never fails.
+        Ready<Result<Self::Response, Self::Error>>,
+    >;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+       self.next.poll_ready(context)
+    }
+
+    fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+        let is_allowed = match request.extensions().get::<Arc<ConnectorPeer>>() {
+            Some(peer) => {
+                let client_ip = self.allowlist
+                    .client_ip(request.extensions(), request.headers());
+                self.allowlist.is_allowed(&peer.account, client_ip)
+            },
+            None => true,
+        };
+        if is_allowed {
+            Either::Left(self.next.call(request))
+        } else {
+            debug!("disallowed address: headers={:?}", request.headers());
+            Either::Right(ok(hyper::Response::builder()
+                .status(hyper::StatusCode::FORBIDDEN)
+                .body(hyper::Body::empty())
+                .expect("response builder error")))
+        }
+    }
+}
+
+/// The IP addresses that each peer may connect from. Peers without an
+/// allowlist may connect from anywhere.
+#[derive(Debug, Default)]
+pub struct IpAllowlist {
+    peers: HashMap<String, Vec<IpNetwork>>,
+    trusted_proxies: Vec<IpNetwork>,
+}
+
+impl IpAllowlist {
+    /// Peers with an empty list of networks aren't restricted.
+    pub fn new(
+        peers: impl IntoIterator<Item = (String, Vec<IpNetwork>)>,
+        trusted_proxies: Vec<IpNetwork>,
+    ) -> Self {
+        IpAllowlist {
+            peers: peers
+                .into_iter()
+                .filter(|(_account, networks)| !networks.is_empty())
+                .collect(),
+            trusted_proxies,
+        }
+    }
+
+    /// The client's IP address is the remote address of the connection (the
+    /// `SocketAddr` request extension). When that is a trusted proxy, the
+    /// client is the last address in `X-Forwarded-For` that isn't a trusted
+    /// proxy.
+    pub(crate) fn client_ip(
+        &self,
+        extensions: &http::Extensions,
+        headers: &hyper::HeaderMap,
+    ) -> Option<IpAddr> {
+        let remote_ip = extensions.get::<SocketAddr>()?.ip();
+        if !self.is_trusted_proxy(remote_ip) {
+            return Some(remote_ip);
+        }
+
+        let mut client_ip = remote_ip;
+        let forwarded_for = headers
+            .get_all(FORWARDED_FOR)
+            .iter()
+            .collect::<Vec<_>>();
+        for header in forwarded_for.into_iter().rev() {
+            let header = header.to_str().ok()?;
+            for hop in header.rsplit(',') {
+                client_ip = hop.trim().parse::<IpAddr>().ok()?;
+                if !self.is_trusted_proxy(client_ip) {
+                    return Some(client_ip);
+                }
+            }
+        }
+        Some(client_ip)
+    }
+
+    /// A restricted peer is never allowed from an unknown address.
+    pub(crate) fn is_allowed(&self, account: &str, client_ip: Option<IpAddr>)
+        -> bool
+    {
+        let networks = match self.peers.get(account) {
+            Some(networks) => networks,
+            None => return true,
+        };
+        let is_allowed = match client_ip {
+            Some(client_ip) => networks
+                .iter()
+                .any(|network| network.contains(client_ip)),
+            None => false,
+        };
+        if !is_allowed {
+            throttled_warn!(
+                account,
+                "disallowed address: account={} client_ip={:?}",
+                account, client_ip,
+            );
+        }
+        is_allowed
+    }
+
+    fn is_trusted_proxy(&self, ip: IpAddr) -> bool {
+        self.trusted_proxies
+            .iter()
+            .any(|network| network.contains(ip))
+    }
+}
+
+/// An IP network in CIDR notation (e.g. `10.0.0.0/8` or `2001:db8::/32`). A
+/// bare address is a network of just that address.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct IpNetwork {
+    addr: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNetwork {
+    pub fn contains(&self, ip: IpAddr) -> bool {
+        match (self.addr, unmap_ipv4(ip)) {
+            (IpAddr::V4(network), IpAddr::V4(ip)) => {
+                let mask = prefix_mask(self.prefix_len, 32) as u32;
+                u32::from(network) & mask == u32::from(ip) & mask
+            },
+            (IpAddr::V6(network), IpAddr::V6(ip)) => {
+                let mask = prefix_mask(self.prefix_len, 128);
+                u128::from(network) & mask == u128::from(ip) & mask
+            },
+            _ => false,
+        }
+    }
+}
+
+/// Clients of a dual-stack listener have IPv4-mapped IPv6 addresses (e.g.
+/// `::ffff:10.0.0.1`); compare them as IPv4 addresses.
+fn unmap_ipv4(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(ipv6) => match ipv6.segments() {
+            [0, 0, 0, 0, 0, 0xffff, high, low] => IpAddr::V4(Ipv4Addr::from(
+                (u32::from(high) << 16) | u32::from(low),
+            )),
+            _ => ip,
+        },
+        IpAddr::V4(_) => ip,
+    }
+}
+
+fn prefix_mask(prefix_len: u8, bits: u8) -> u128 {
+    if prefix_len == 0 {
+        0
+    } else {
+        (!0u128 << (bits - prefix_len)) & (!0u128 >> (128 - bits))
+    }
+}
+
+impl FromStr for IpNetwork {
+    type Err = IpNetworkError;
+
+    fn from_str(network: &str) -> Result<Self, Self::Err> {
+        let (addr, prefix_len) = match network.find('/') {
+            Some(split) => (&network[..split], Some(&network[split + 1..])),
+            None => (network, None),
+        };
+        let addr = addr.parse::<IpAddr>().map_err(|_| IpNetworkError)?;
+        let max_prefix_len = match addr {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        let prefix_len = match prefix_len {
+            Some(prefix_len) => prefix_len.parse::<u8>()
+                .map_err(|_| IpNetworkError)?,
+            None => max_prefix_len,
+        };
+        if prefix_len > max_prefix_len {
+            return Err(IpNetworkError);
+        }
+        Ok(IpNetwork { addr, prefix_len })
+    }
+}
+
+impl fmt::Display for IpNetwork {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.addr, self.prefix_len)
+    }
+}
+
+impl<'de> Deserialize<'de> for IpNetwork {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let network = String::deserialize(deserializer)?;
+        network.parse::<IpNetwork>().map_err(|_| {
+            D::Error::custom(format!("invalid IP network: {:?}", network))
+        })
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub struct IpNetworkError;
+
+impl StdError for IpNetworkError {}
+
+impl fmt::Display for IpNetworkError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("invalid IP network")
+    }
+}
+
+#[cfg(test)]
+mod test_ip_allowlist {
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    use crate::Relation;
+    use super::*;
+
+    fn network(network: &str) -> IpNetwork {
+        network.parse().unwrap()
+    }
+
+    fn ip(ip: &str) -> IpAddr {
+        ip.parse().unwrap()
+    }
+
+    #[test]
+    fn test_ip_network() {
+        assert!(network("10.0.0.0/8").contains(ip("10.1.2.3")));
+        assert!(!network("10.0.0.0/8").contains(ip("11.0.0.1")));
+        assert!(network("10.1.2.3").contains(ip("10.1.2.3")));
+        assert!(!network("10.1.2.3").contains(ip("10.1.2.4")));
+        assert!(network("0.0.0.0/0").contains(ip("192.168.0.1")));
+        assert!(!network("0.0.0.0/0").contains(ip("::1")));
+        assert!(network("2001:db8::/32").contains(ip("2001:db8:1::1")));
+        assert!(!network("2001:db8::/32").contains(ip("2001:db9::1")));
+        // IPv4-mapped IPv6 addresses.
+        assert!(network("10.0.0.0/8").contains(ip("::ffff:10.0.0.1")));
+
+        assert_eq!(network("10.0.0.1").to_string(), "10.0.0.1/32");
+        assert_eq!("10.0.0.0/33".parse::<IpNetwork>(), Err(IpNetworkError));
+        assert_eq!("10.0.0.0/".parse::<IpNetwork>(), Err(IpNetworkError));
+        assert_eq!("example.com".parse::<IpNetwork>(), Err(IpNetworkError));
+    }
+
+    #[test]
+    fn test_client_ip() {
+        let allowlist = IpAllowlist::new(vec![], vec![network("10.0.0.0/8")]);
+        let client_ip = |remote: Option<&str>, forwarded_for: &[&str]| {
+            let mut request = hyper::Request::new(());
+            if let Some(remote) = remote {
+                request.extensions_mut()
+                    .insert(remote.parse::<SocketAddr>().unwrap());
+            }
+            for header in forwarded_for {
+                request.headers_mut()
+                    .append(FORWARDED_FOR, header.parse().unwrap());
+            }
+            allowlist.client_ip(request.extensions(), request.headers())
+        };
+
+        assert_eq!(client_ip(None, &[]), None);
+        assert_eq!(client_ip(Some("1.2.3.4:80"), &[]), Some(ip("1.2.3.4")));
+        // Only trusted proxies can forward.
+        assert_eq!(
+            client_ip(Some("1.2.3.4:80"), &["5.6.7.8"]),
+            Some(ip("1.2.3.4")),
+        );
+        assert_eq!(
+            client_ip(Some("10.0.0.1:80"), &["5.6.7.8"]),
+            Some(ip("5.6.7.8")),
+        );
+        // Addresses before the last untrusted one may be forged.
+        assert_eq!(
+            client_ip(Some("10.0.0.1:80"), &["1.1.1.1, 5.6.7.8", "10.0.0.2"]),
+            Some(ip("5.6.7.8")),
+        );
+        assert_eq!(
+            client_ip(Some("10.0.0.1:80"), &["10.0.0.3, 10.0.0.2"]),
+            Some(ip("10.0.0.3")),
+        );
+        assert_eq!(client_ip(Some("10.0.0.1:80"), &[]), Some(ip("10.0.0.1")));
+        assert_eq!(client_ip(Some("10.0.0.1:80"), &["nope"]), None);
+    }
+
+    #[test]
+    fn test_service() {
+        let allowlist = IpAllowlist::new(vec![
+            ("alice".to_owned(), vec![network("1.2.3.0/24")]),
+            ("bob".to_owned(), vec![]),
+        ], vec![]);
+        let next = service_fn(|_req| {
+            ok::<_, hyper::Error>(hyper::Response::builder()
+                .status(204)
+                .body(hyper::Body::empty())
+                .unwrap())
+        });
+        let mut service = IpAllowlistFilter::new(Arc::new(allowlist), next);
+        let mut call = |account: Option<&str>, remote: Option<&str>| {
+            let mut request = hyper::Request::new(hyper::Body::empty());
+            if let Some(account) = account {
+                request.extensions_mut().insert(Arc::new(ConnectorPeer {
+                    relation: Relation::Child,
+                    account: Arc::new(account.to_owned()),
+                    address: ilp::Address::new(b"test.relay.child"),
+                    auth: Default::default(),
+                    certificate: None,
+                    signing_secret: None,
+                    trust_deadline: false,
+                }));
+            }
+            if let Some(remote) = remote {
+                request.extensions_mut()
+                    .insert(remote.parse::<SocketAddr>().unwrap());
+            }
+            block_on(service.call(request)).unwrap().status().as_u16()
+        };
+
+        assert_eq!(call(Some("alice"), Some("1.2.3.4:80")), 204);
+        assert_eq!(call(Some("alice"), Some("1.2.4.4:80")), 403);
+        assert_eq!(call(Some("alice"), None), 403);
+        assert_eq!(call(Some("bob"), Some("1.2.4.4:80")), 204);
+        assert_eq!(call(None, Some("1.2.4.4:80")), 204);
+    }
+}
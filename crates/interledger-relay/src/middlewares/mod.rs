@@ -1,11 +1,33 @@
+mod access_log;
+mod admin;
 mod auth;
+mod auth_lockout;
+mod capabilities;
 mod health_check;
+mod ip_allowlist;
+mod jwt;
 mod method;
 mod pre_stop;
 mod receiver;
+mod registration;
+mod signature;
+mod spsp;
+mod tap;
 
-pub use self::auth::{AuthToken, AuthTokenFilter};
+pub use self::access_log::{AccessLog, AccessLogConfig, AccessLogFilter};
+pub(crate) use self::access_log::AccessLogPacket;
+pub use self::admin::{AdminApiConfig, AdminFilter};
+pub use self::auth::{AuthHeader, AuthTokenFilter, PeerAuthToken};
+pub use self::auth_lockout::{AuthLockout, AuthLockoutConfig};
+pub use self::capabilities::CapabilitiesFilter;
+pub(crate) use self::auth::constant_time_eq;
 pub use self::health_check::HealthCheckFilter;
+pub use self::ip_allowlist::{IpAllowlist, IpAllowlistFilter, IpNetwork};
+pub use self::jwt::{JwksError, JwtAuthConfig, JwtKeyConfig, JwtVerifier};
 pub use self::method::MethodFilter;
 pub use self::pre_stop::PreStopFilter;
 pub use self::receiver::Receiver;
+pub(crate) use self::receiver::MAX_REQUEST_SIZE;
+pub use self::registration::RegistrationFilter;
+pub use self::signature::{SignatureFilter, SigningSecret};
+pub use self::spsp::SpspFilter;
@@ -1,5 +1,5 @@
 use std::pin::Pin;
-use std::sync::{Arc, RwLock};
+use std::sync::Arc;
 use std::time;
 
 use futures::prelude::*;
@@ -7,6 +7,8 @@ use futures::task::{Context, Poll};
 use hyper::service::Service as HyperService;
 use log::{info, trace};
 
+use crate::toggles::Toggle;
+
 type HTTPRequest = http::Request<hyper::Body>;
 
 type StopFn = Box<
@@ -15,13 +17,18 @@ type StopFn = Box<
     >> + Send + Sync + 'static
 >;
 
-// TODO test middleware
-
 /// When the server receives a `GET` to the configured `pre_stop_path`, this
 /// middleware will:
 ///
-/// * Stop accepting requests.
-/// * Flush all of the `BigQueryService` logger queues.
+/// * Start draining: the `draining` flag (shared with the `HealthCheckFilter`)
+///   is set so that readiness checks fail, and every subsequent response
+///   includes `Connection: close` so that keep-alive connections are closed.
+///   Requests are still served, so that load balancers can stop sending new
+///   connections without clients receiving `503`s.
+/// * If there is a `grace_period`, wait for it to elapse, then reject all new
+///   requests with `503`. Without one, requests are served until the process
+///   exits.
+/// * Flush all of the `TelemetryService` logger queues.
 /// * Respond to the `GET` request once the queues are flushed
 ///   (or it has taken too long).
 #[derive(Clone)]
@@ -33,7 +40,10 @@ pub struct PreStopFilter<S> {
 struct FilterData {
     path: Option<String>,
     stop: StopFn,
-    stopping: RwLock<bool>,
+    grace_period: Option<time::Duration>,
+    draining: Toggle,
+    /// Set once the grace period has elapsed.
+    stopped: Toggle,
 }
 
 impl<S> PreStopFilter<S>
@@ -43,19 +53,34 @@ where
     pub fn new(
         path: Option<String>,
         stop: StopFn,
+        grace_period: Option<time::Duration>,
+        draining: Toggle,
         next: S,
     ) -> Self {
         PreStopFilter {
             data: Arc::new(FilterData {
                 path,
                 stop,
-                stopping: RwLock::new(false),
+                grace_period,
+                draining,
+                stopped: Toggle::new(false),
             }),
             next,
         }
     }
 }
 
+impl<S> PreStopFilter<S> {
+    /// Stop right away, without a grace period (e.g. once the server has
+    /// finished draining after a `SIGTERM`): reject new requests with `503`,
+    /// and flush the `TelemetryService` logger queues.
+    pub fn shutdown(&self) -> impl Future<Output = ()> {
+        self.data.draining.set(true);
+        self.data.stopped.set(true);
+        (self.data.stop)()
+    }
+}
+
 impl<S> HyperService<HTTPRequest> for PreStopFilter<S>
 where
     S: Clone + 'static + HyperService<
@@ -79,40 +104,52 @@ where
     }
 
     fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+        if self.data.stopped.is_enabled() {
+            trace!("relay is stopped; dropping request");
+            return Box::pin(future::ok(hyper::Response::builder()
+                .status(hyper::StatusCode::SERVICE_UNAVAILABLE) // 503
+                .header(hyper::header::CONNECTION, "close")
+                .body(hyper::Body::from("service stopping"))
+                .expect("response builder error")));
+        }
+
         let path = match &self.data.path {
             Some(path) => path,
             None => return Box::pin(self.next.call(request)),
         };
 
-        {
-            let stopping = self.data.stopping.read().unwrap();
-            if *stopping {
-                trace!("relay is stopping; dropping request");
-                return Box::pin(future::ok(hyper::Response::builder()
-                    .status(hyper::StatusCode::SERVICE_UNAVAILABLE) // 503
-                    .body(hyper::Body::from("service stopping"))
-                    .expect("response builder error")));
-            }
-        }
-
         let is_pre_stop =
             request.method() == hyper::Method::GET
                 && request.uri().path() == path;
         if is_pre_stop {
-            {
-                let mut stopping = self.data.stopping.write().unwrap();
-                *stopping = true;
-            }
+            self.data.draining.set(true);
             info!("relay stopping");
             let start = time::Instant::now();
             let data = Arc::clone(&self.data);
+            return Box::pin(async move {
+                if let Some(grace_period) = data.grace_period {
+                    tokio::time::delay_for(grace_period).await;
+                    info!("relay grace period elapsed; rejecting new requests");
+                    data.stopped.set(true);
+                }
+                (data.stop)().await;
+                info!("relay stopped: duration={:?}", time::Instant::now() - start);
+                Ok(hyper::Response::builder()
+                    .status(hyper::StatusCode::OK)
+                    .header(hyper::header::CONNECTION, "close")
+                    .body(hyper::Body::empty())
+                    .expect("response builder error"))
+            });
+        }
+
+        if self.data.draining.is_enabled() {
             return Box::pin({
-                (data.stop)().map(move |_| {
-                    info!("relay stopped: duration={:?}", time::Instant::now() - start);
-                    Ok(hyper::Response::builder()
-                        .status(hyper::StatusCode::OK)
-                        .body(hyper::Body::empty())
-                        .expect("response builder error"))
+                self.next.call(request).map_ok(|mut response| {
+                    response.headers_mut().insert(
+                        hyper::header::CONNECTION,
+                        hyper::header::HeaderValue::from_static("close"),
+                    );
+                    response
                 })
             });
         }
@@ -120,3 +157,141 @@ where
         Box::pin(self.next.call(request))
     }
 }
+
+#[cfg(test)]
+mod test_pre_stop_filter {
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    use super::*;
+
+    #[test]
+    fn test_drain() {
+        let stops = Arc::new(AtomicUsize::new(0));
+        let draining = Toggle::new(false);
+        let next = service_fn(|_req| {
+            future::ok(hyper::Response::builder()
+                .status(200)
+                .body(hyper::Body::empty())
+                .unwrap())
+        });
+        let mut service = PreStopFilter::new(
+            Some("/pre_stop".to_owned()),
+            {
+                let stops = Arc::clone(&stops);
+                Box::new(move || {
+                    stops.fetch_add(1, Ordering::SeqCst);
+                    Box::pin(future::ready(()))
+                })
+            },
+            None,
+            draining.clone(),
+            next,
+        );
+        let mut call = |method: hyper::Method, path: &str| {
+            block_on(service.call({
+                hyper::Request::builder()
+                    .method(method)
+                    .uri(path)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })).unwrap()
+        };
+
+        let response = call(hyper::Method::POST, "/ilp");
+        assert_eq!(response.status(), 200);
+        assert!(response.headers().get(hyper::header::CONNECTION).is_none());
+
+        let response = call(hyper::Method::GET, "/pre_stop");
+        assert_eq!(response.status(), 200);
+        assert_eq!(stops.load(Ordering::SeqCst), 1);
+        assert!(draining.is_enabled());
+
+        // Requests are still served while draining.
+        let response = call(hyper::Method::POST, "/ilp");
+        assert_eq!(response.status(), 200);
+        assert_eq!(response.headers()[hyper::header::CONNECTION], "close");
+    }
+
+    #[tokio::test]
+    async fn test_grace_period() {
+        let next = service_fn(|_req| {
+            future::ok(hyper::Response::builder()
+                .status(200)
+                .body(hyper::Body::empty())
+                .unwrap())
+        });
+        let mut service = PreStopFilter::new(
+            Some("/pre_stop".to_owned()),
+            Box::new(|| Box::pin(future::ready(()))),
+            Some(time::Duration::from_millis(50)),
+            Toggle::new(false),
+            next,
+        );
+        let make_request = |method: hyper::Method, path: &str| {
+            hyper::Request::builder()
+                .method(method)
+                .uri(path)
+                .body(hyper::Body::empty())
+                .unwrap()
+        };
+
+        let pre_stop = tokio::spawn({
+            service.call(make_request(hyper::Method::GET, "/pre_stop"))
+        });
+        // Requests are served during the grace period.
+        let response = service
+            .call(make_request(hyper::Method::POST, "/ilp"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 200);
+
+        assert_eq!(pre_stop.await.unwrap().unwrap().status(), 200);
+        let response = service
+            .call(make_request(hyper::Method::POST, "/ilp"))
+            .await
+            .unwrap();
+        assert_eq!(response.status(), 503);
+    }
+
+    #[test]
+    fn test_shutdown() {
+        let stops = Arc::new(AtomicUsize::new(0));
+        let draining = Toggle::new(false);
+        let next = service_fn(|_req| {
+            future::ok(hyper::Response::builder()
+                .status(200)
+                .body(hyper::Body::empty())
+                .unwrap())
+        });
+        // Shutting down doesn't require a `pre_stop_path`.
+        let mut service = PreStopFilter::new(
+            None,
+            {
+                let stops = Arc::clone(&stops);
+                Box::new(move || {
+                    stops.fetch_add(1, Ordering::SeqCst);
+                    Box::pin(future::ready(()))
+                })
+            },
+            Some(time::Duration::from_secs(60)),
+            draining.clone(),
+            next,
+        );
+
+        block_on(service.shutdown());
+        assert_eq!(stops.load(Ordering::SeqCst), 1);
+        assert!(draining.is_enabled());
+
+        let response = block_on(service.call({
+            hyper::Request::builder()
+                .method(hyper::Method::POST)
+                .uri("/ilp")
+                .body(hyper::Body::empty())
+                .unwrap()
+        })).unwrap();
+        assert_eq!(response.status(), 503);
+    }
+}
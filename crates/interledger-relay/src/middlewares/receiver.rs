@@ -1,16 +1,23 @@
 use std::pin::Pin;
+use std::sync::Arc;
+use std::time;
 
 use bytes::BytesMut;
 use futures::future::{Either, err, ok};
 use futures::prelude::*;
 use futures::task::{Context, Poll};
 use hyper::StatusCode;
+use hyper::body::HttpBody;
 use log::warn;
 
-use crate::{RequestWithHeaders, Service};
+use crate::{ClientCertificate, RequestId, RequestWithHeaders, Service};
+use crate::packets::REQUEST_ID_HEADER;
 use crate::combinators::{self, LimitStreamError};
+use crate::metrics::{Metrics, PeerTraffic};
+use crate::peers::ConnectorPeer;
+use super::AccessLogPacket;
 
-const MAX_REQUEST_SIZE: usize = {
+pub(crate) const MAX_REQUEST_SIZE: usize = {
     const ENVELOPE: usize = 1 + 8;
     const FIXED_FIELDS: usize = 8 + 13 + 32;
     const DESTINATION: usize = 8 + 1024;
@@ -19,8 +26,23 @@ const MAX_REQUEST_SIZE: usize = {
     ENVELOPE + FIXED_FIELDS + DESTINATION + DATA
 };
 
+/// Responses include this header when the connector is configured with an
+/// instance ID, to help attribute them to a specific replica.
+static INSTANCE_HEADER: &str = "ILP-Relay-Instance";
+
+/// Parse incoming Prepares and pass them to the ILP services. Each request is
+/// tagged with the incoming `X-Request-Id` (or a new one, if it is missing or
+/// invalid), which is echoed in the response. The bytes
+/// (headers and body) received from and sent to each authenticated peer are
+/// counted in the metrics.
+///
+/// When a `read_timeout` is set, a request whose body isn't received within it
+/// (e.g. one that is trickled in a byte at a time) is answered with `408`.
 #[derive(Clone, Debug)]
 pub struct Receiver<S> {
+    instance_id: Option<hyper::header::HeaderValue>,
+    read_timeout: Option<time::Duration>,
+    metrics: Arc<Metrics>,
     next: S,
 }
 
@@ -52,8 +74,13 @@ where
     S: Service<RequestWithHeaders> + 'static + Clone + Send,
 {
     #[inline]
-    pub fn new(next: S) -> Self {
-        Receiver { next }
+    pub fn new(
+        instance_id: Option<hyper::header::HeaderValue>,
+        read_timeout: Option<time::Duration>,
+        metrics: Arc<Metrics>,
+        next: S,
+    ) -> Self {
+        Receiver { instance_id, read_timeout, metrics, next }
     }
 
     fn handle(&self, req: hyper::Request<hyper::Body>)
@@ -62,28 +89,82 @@ where
         > + Send + 'static
     {
         let next = self.next.clone();
-        let (parts, body) = req.into_parts();
-        combinators::collect_http_body(
+        let instance_id = self.instance_id.clone();
+        let (mut parts, body) = req.into_parts();
+        let peer = parts.extensions.remove::<Arc<ConnectorPeer>>();
+        let client_certificate =
+            parts.extensions.remove::<Arc<ClientCertificate>>();
+        let traffic = peer.as_ref().map(|peer| {
+            PeerTraffic::new(Arc::clone(&self.metrics), Arc::clone(&peer.account))
+        });
+        let request_id = parts.headers
+            .get(REQUEST_ID_HEADER)
+            .and_then(|value| RequestId::from_header(value.as_bytes()))
+            .unwrap_or_else(RequestId::generate);
+        let request_header_size = header_size(&parts.headers);
+        let response_traffic = traffic.clone();
+        let chunk_future = combinators::collect_http_body(
             &parts.headers,
             body,
             MAX_REQUEST_SIZE
-        ).then(move |chunk_result| {
-            let prepare_result = chunk_result.map(ilp::Prepare::try_from);
+        ).map(Some);
+        // `None` when the body took too long.
+        let chunk_future = match self.read_timeout {
+            Some(read_timeout) => Either::Left(
+                tokio::time::timeout(read_timeout, chunk_future)
+                    .map(|result| result.unwrap_or(None)),
+            ),
+            None => Either::Right(chunk_future),
+        };
+        chunk_future.then(move |chunk_result| {
+            let chunk_result = match chunk_result {
+                Some(chunk_result) => chunk_result,
+                None => return Either::Right(ok({
+                    warn!(
+                        "incoming request body timed out: request_id={}",
+                        request_id,
+                    );
+                    hyper::Response::builder()
+                        .status(StatusCode::REQUEST_TIMEOUT)
+                        .body(hyper::Body::from("Request Timeout"))
+                        .expect("response builder error")
+                })),
+            };
+            let prepare_result = chunk_result.map(|chunk| {
+                if let Some(traffic) = &traffic {
+                    traffic.received(request_header_size + chunk.len());
+                }
+                ilp::Prepare::try_from(chunk)
+            });
             match prepare_result {
                 Ok(Ok(prepare)) => Either::Left({
+                    let account = peer.as_ref()
+                        .map(|peer| Arc::clone(&peer.account));
+                    let response_id = request_id.clone();
                     next
                         .call(RequestWithHeaders {
                             prepare,
                             headers: parts.headers,
+                            peer,
+                            client_certificate,
+                            request_id,
                         })
-                        .map(make_http_response)
+                        .map(move |packet| make_http_response(
+                            instance_id,
+                            response_id,
+                            account,
+                            packet,
+                        ))
                         .map(Result::Ok)
                 }),
                 Err(LimitStreamError::StreamError(error)) =>
                     Either::Right(err(error)),
                 // The incoming request body was too large.
                 Err(LimitStreamError::LimitExceeded) => Either::Right(ok({
-                    warn!("incoming request body too large");
+                    warn!(
+                        "incoming request body too large: request_id={}",
+                        request_id,
+                    );
                     hyper::Response::builder()
                         .status(StatusCode::PAYLOAD_TOO_LARGE)
                         .body(hyper::Body::from("Payload Too Large"))
@@ -91,31 +172,71 @@ where
                 })),
                 // The packet could not be decoded.
                 Ok(Err(error)) => Either::Right(ok({
-                    warn!("error parsing incoming prepare: error={:?}", error);
+                    warn!(
+                        "error parsing incoming prepare: request_id={} error={:?}",
+                        request_id, error,
+                    );
                     hyper::Response::builder()
                         .status(StatusCode::BAD_REQUEST)
                         .body(hyper::Body::from("Error parsing ILP Prepare"))
                         .expect("response builder error")
                 })),
             }
+        }).inspect(move |response_result| {
+            let traffic = response_traffic.as_ref();
+            if let (Some(traffic), Ok(response)) = (traffic, response_result) {
+                let body_size = HttpBody::size_hint(response.body())
+                    .exact()
+                    .unwrap_or(0);
+                traffic.sent(header_size(response.headers()) + body_size as usize);
+            }
         })
     }
 }
 
-fn make_http_response(packet: Result<ilp::Fulfill, ilp::Reject>)
-    -> hyper::Response<hyper::Body>
-{
+fn make_http_response(
+    instance_id: Option<hyper::header::HeaderValue>,
+    request_id: RequestId,
+    account: Option<Arc<String>>,
+    packet: Result<ilp::Fulfill, ilp::Reject>,
+) -> hyper::Response<hyper::Body> {
     static OCTET_STREAM: &[u8] = b"application/octet-stream";
-    let buffer = match packet {
-        Ok(fulfill) => BytesMut::from(fulfill),
-        Err(reject) => BytesMut::from(reject),
+    let (buffer, access_log) = match packet {
+        Ok(fulfill) => (BytesMut::from(fulfill), AccessLogPacket {
+            account,
+            packet_type: ilp::PacketType::Fulfill,
+            error_code: None,
+        }),
+        Err(reject) => {
+            let error_code = Some(reject.code());
+            (BytesMut::from(reject), AccessLogPacket {
+                account,
+                packet_type: ilp::PacketType::Reject,
+                error_code,
+            })
+        },
     };
-    hyper::Response::builder()
+    let mut builder = hyper::Response::builder()
         .status(StatusCode::OK)
         .header(hyper::header::CONTENT_TYPE, OCTET_STREAM)
         .header(hyper::header::CONTENT_LENGTH, buffer.len())
+        .header(REQUEST_ID_HEADER, request_id.as_str());
+    if let Some(instance_id) = instance_id {
+        builder = builder.header(INSTANCE_HEADER, instance_id);
+    }
+    let mut response = builder
         .body(hyper::Body::from(buffer.freeze()))
-        .expect("response builder error")
+        .expect("response builder error");
+    response.extensions_mut().insert(access_log);
+    response
+}
+
+/// The size of the headers as they are written in HTTP/1.1 (`name: value\r\n`).
+fn header_size(headers: &hyper::HeaderMap) -> usize {
+    headers
+        .iter()
+        .map(|(name, value)| name.as_str().len() + value.len() + 4)
+        .sum()
 }
 
 #[cfg(test)]
@@ -146,12 +267,88 @@ mod test_receiver {
         );
     }
 
+    #[test]
+    fn test_instance_header() {
+        let service = Receiver::new(
+            Some(hyper::header::HeaderValue::from_static("relay-1")),
+            None,
+            Arc::new(Metrics::default()),
+            MockService::new(Ok(FULFILL.clone())),
+        );
+        let response = block_on(service.handle({
+            hyper::Request::post(URI)
+                .body(hyper::Body::from(PREPARE.as_ref()))
+                .unwrap()
+        })).unwrap();
+        assert_eq!(
+            response.headers().get("ILP-Relay-Instance").unwrap(),
+            "relay-1",
+        );
+
+        let service = Receiver::new(
+            None,
+            None,
+            Arc::new(Metrics::default()),
+            MockService::new(Ok(FULFILL.clone())),
+        );
+        let response = block_on(service.handle({
+            hyper::Request::post(URI)
+                .body(hyper::Body::from(PREPARE.as_ref()))
+                .unwrap()
+        })).unwrap();
+        assert!(response.headers().get("ILP-Relay-Instance").is_none());
+    }
+
+    #[test]
+    fn test_request_id() {
+        let service = Receiver::new(
+            None,
+            None,
+            Arc::new(Metrics::default()),
+            |req: RequestWithHeaders| {
+                assert_eq!(req.request_id.to_string(), "abc-123");
+                ok(FULFILL.clone())
+            },
+        );
+        let response = block_on(service.handle({
+            hyper::Request::post(URI)
+                .header("X-Request-Id", "abc-123")
+                .body(hyper::Body::from(PREPARE.as_ref()))
+                .unwrap()
+        })).unwrap();
+        assert_eq!(response.headers().get("X-Request-Id").unwrap(), "abc-123");
+
+        // Missing or invalid IDs are replaced by a generated one.
+        for header in &[None, Some("has spaces"), Some("")] {
+            let service = Receiver::new(
+                None,
+                None,
+                Arc::new(Metrics::default()),
+                |req: RequestWithHeaders| {
+                    assert_eq!(req.request_id.to_string().len(), 36);
+                    ok(FULFILL.clone())
+                },
+            );
+            let mut request = hyper::Request::post(URI);
+            if let Some(header) = header {
+                request = request.header("X-Request-Id", *header);
+            }
+            let response = block_on(service.handle({
+                request.body(hyper::Body::from(PREPARE.as_ref())).unwrap()
+            })).unwrap();
+            assert_eq!(
+                response.headers().get("X-Request-Id").unwrap().len(),
+                36,
+            );
+        }
+    }
+
     fn test_request_response(
         request: hyper::Request<hyper::Body>,
         ilp_response: IlpResult,
     ) {
         let next = MockService::new(ilp_response.clone());
-        let service = Receiver::new(next);
+        let service = Receiver::new(None, None, Arc::new(Metrics::default()), next);
 
         let response = block_on(service.handle(request)).unwrap();
         assert_eq!(response.status(), 200);
@@ -159,6 +356,14 @@ mod test_receiver {
             response.headers().get("Content-Type").unwrap(),
             "application/octet-stream",
         );
+        let access_log = response.extensions()
+            .get::<AccessLogPacket>()
+            .unwrap();
+        assert_eq!(access_log.account, None);
+        assert_eq!(
+            access_log.error_code,
+            ilp_response.as_ref().err().map(|reject| reject.code()),
+        );
 
         let next = service.next.clone();
         assert_eq!(
@@ -186,7 +391,12 @@ mod test_receiver {
 
     #[test]
     fn test_bad_request() {
-        let service = Receiver::new(PanicService);
+        let service = Receiver::new(
+            None,
+            None,
+            Arc::new(Metrics::default()),
+            PanicService,
+        );
         let response = block_on(service.handle(
             hyper::Request::post(URI)
                 .body(hyper::Body::from(&b"this is not a prepare"[..]))
@@ -205,10 +415,15 @@ mod test_receiver {
 
     #[test]
     fn test_peer_name() {
-        let service = Receiver::new(|req: RequestWithHeaders| {
-            assert_eq!(req.peer_name(), Some(&b"alice"[..]));
-            ok(FULFILL.clone())
-        });
+        let service = Receiver::new(
+            None,
+            None,
+            Arc::new(Metrics::default()),
+            |req: RequestWithHeaders| {
+                assert_eq!(req.peer_name(), Some(&b"alice"[..]));
+                ok(FULFILL.clone())
+            },
+        );
 
         let request = hyper::Request::post(URI)
             .header("ILP-Peer-Name", "alice")
@@ -218,6 +433,50 @@ mod test_receiver {
         assert_eq!(response.status(), 200);
     }
 
+    #[test]
+    fn test_traffic() {
+        let metrics = Arc::new(Metrics::default());
+        let service = Receiver::new(
+            None,
+            None,
+            Arc::clone(&metrics),
+            MockService::new(Ok(FULFILL.clone())),
+        );
+        let mut request = hyper::Request::post(URI)
+            .header("ILP-Peer-Name", "alice")
+            .body(hyper::Body::from(PREPARE.as_ref()))
+            .unwrap();
+        request.extensions_mut().insert(Arc::new(ConnectorPeer {
+            relation: crate::Relation::Child,
+            account: Arc::new("alice".to_owned()),
+            address: ilp::Address::new(b"test.relay.alice"),
+            auth: std::collections::HashSet::new(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline: false,
+        }));
+        let response = block_on(service.handle(request)).unwrap();
+        assert_eq!(response.status(), 200);
+
+        let labels = || vec![("account", "alice".to_owned())];
+        assert_eq!(
+            metrics.get("ilp_relay_received_bytes_total", labels()),
+            ("ilp-peer-name: alice\r\n".len() + PREPARE.as_ref().len()) as u64,
+        );
+        assert_eq!(
+            metrics.get("ilp_relay_sent_bytes_total", labels()),
+            (header_size(response.headers()) + FULFILL.as_ref().len()) as u64,
+        );
+
+        // Unauthenticated requests aren't counted.
+        block_on(service.handle({
+            hyper::Request::post(URI)
+                .body(hyper::Body::from(PREPARE.as_ref()))
+                .unwrap()
+        })).unwrap();
+        assert_eq!(metrics.render().matches("_bytes_total").count(), 4);
+    }
+
     #[test]
     fn test_body_too_large() {
         let prepare = ilp::PrepareBuilder {
@@ -234,7 +493,12 @@ mod test_receiver {
             },
         }.build();
 
-        let service = Receiver::new(PanicService);
+        let service = Receiver::new(
+            None,
+            None,
+            Arc::new(Metrics::default()),
+            PanicService,
+        );
         let request = hyper::Request::post(URI)
             .header("ILP-Peer-Name", "alice")
             .body(hyper::Body::from({
@@ -244,4 +508,152 @@ mod test_receiver {
         let response = block_on(service.handle(request)).unwrap();
         assert_eq!(response.status(), 413);
     }
+
+    #[test]
+    fn test_chunked_body() {
+        // No `Content-Length`: the body is read until it ends.
+        let chunks = PREPARE.as_ref()
+            .chunks(16)
+            .map(|chunk| Ok::<_, std::io::Error>(Bytes::copy_from_slice(chunk)))
+            .collect::<Vec<_>>();
+        test_request_response(
+            hyper::Request::post(URI)
+                .body(hyper::Body::wrap_stream(stream::iter(chunks)))
+                .unwrap(),
+            Ok(FULFILL.clone()),
+        );
+
+        // An endless body is cut off at `MAX_REQUEST_SIZE`.
+        let service = Receiver::new(
+            None,
+            None,
+            Arc::new(Metrics::default()),
+            PanicService,
+        );
+        let chunk = Bytes::from(vec![b'.'; 1024]);
+        let endless = stream::repeat(chunk).map(Ok::<_, std::io::Error>);
+        let response = block_on(service.handle({
+            hyper::Request::post(URI)
+                .body(hyper::Body::wrap_stream(endless))
+                .unwrap()
+        })).unwrap();
+        assert_eq!(response.status(), 413);
+
+        // An empty body.
+        let response = block_on(service.handle({
+            hyper::Request::post(URI)
+                .body(hyper::Body::empty())
+                .unwrap()
+        })).unwrap();
+        assert_eq!(response.status(), 400);
+    }
+
+    #[test]
+    fn test_content_length_too_large() {
+        let service = Receiver::new(
+            None,
+            None,
+            Arc::new(Metrics::default()),
+            PanicService,
+        );
+        // The body is never sent, but the declared length is enough to
+        // respond.
+        let (_sender, body) = hyper::Body::channel();
+        let response = block_on(service.handle({
+            hyper::Request::post(URI)
+                .header("Content-Length", MAX_REQUEST_SIZE + 1)
+                .body(body)
+                .unwrap()
+        })).unwrap();
+        assert_eq!(response.status(), 413);
+    }
+
+    #[test]
+    fn test_large_headers() {
+        let metrics = Arc::new(Metrics::default());
+        let service = Receiver::new(
+            None,
+            None,
+            Arc::clone(&metrics),
+            MockService::new(Ok(FULFILL.clone())),
+        );
+        // hyper's default limit is 100 headers.
+        let value = "x".repeat(4096);
+        let mut request = hyper::Request::post(URI);
+        for i in 0..100 {
+            request = request.header(format!("x-header-{}", i).as_str(), value.as_str());
+        }
+        let mut request = request
+            .body(hyper::Body::from(PREPARE.as_ref()))
+            .unwrap();
+        request.extensions_mut().insert(Arc::new(ConnectorPeer {
+            relation: crate::Relation::Child,
+            account: Arc::new("alice".to_owned()),
+            address: ilp::Address::new(b"test.relay.alice"),
+            auth: std::collections::HashSet::new(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline: false,
+        }));
+        let header_bytes = header_size(request.headers());
+        let response = block_on(service.handle(request)).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            metrics.get(
+                "ilp_relay_received_bytes_total",
+                vec![("account", "alice".to_owned())],
+            ),
+            (header_bytes + PREPARE.as_ref().len()) as u64,
+        );
+    }
+
+    #[test]
+    fn test_read_timeout() {
+        let mut runtime = tokio::runtime::Builder::new()
+            .basic_scheduler()
+            .enable_time()
+            .build()
+            .unwrap();
+        let service = Receiver::new(
+            None,
+            Some(time::Duration::from_millis(50)),
+            Arc::new(Metrics::default()),
+            MockService::new(Ok(FULFILL.clone())),
+        );
+
+        // A body that is trickled in too slowly.
+        let (mut sender, body) = hyper::Body::channel();
+        let response = runtime.block_on(async {
+            let trickle = async move {
+                for byte in PREPARE.as_ref() {
+                    tokio::time::delay_for(time::Duration::from_millis(10)).await;
+                    if sender.send_data(Bytes::copy_from_slice(&[*byte])).await.is_err() {
+                        break;
+                    }
+                }
+            };
+            let request = hyper::Request::post(URI).body(body).unwrap();
+            future::join(service.handle(request), trickle).await.0
+        }).unwrap();
+        assert_eq!(response.status(), 408);
+        assert_eq!(service.next.prepares().count(), 0);
+
+        // A body that arrives in time.
+        let response = runtime.block_on(async {
+            service.handle({
+                hyper::Request::post(URI)
+                    .body(hyper::Body::from(PREPARE.as_ref()))
+                    .unwrap()
+            }).await
+        }).unwrap();
+        assert_eq!(response.status(), 200);
+    }
+
+    #[test]
+    fn test_header_size() {
+        let mut headers = hyper::HeaderMap::new();
+        assert_eq!(header_size(&headers), 0);
+        headers.insert("Content-Length", "123".parse().unwrap());
+        assert_eq!(header_size(&headers), "content-length: 123\r\n".len());
+    }
 }
@@ -0,0 +1,199 @@
+use std::sync::Arc;
+
+use futures::future::{Either, Ready, ok};
+use futures::task::{Context, Poll};
+use hyper::service::Service as HyperService;
+use log::warn;
+use percent_encoding::percent_decode_str;
+
+use crate::peers::PeerIndex;
+use super::admin::{empty_response, registration_response};
+use super::auth::authorization_token;
+
+type HTTPRequest = http::Request<hyper::Body>;
+
+static PATH_PREFIX: &str = "/register/";
+
+/// Serve `POST /register/{account}`, through which a prospective child
+/// registers itself, authenticated by one of the `provisioning_tokens` (see
+/// `ChildRegistrationConfig`). The response is the same as the admin API's
+/// `POST /admin/children/{account}`.
+///
+/// When there are no provisioning tokens, all requests are passed through.
+#[derive(Clone, Debug)]
+pub struct RegistrationFilter<S> {
+    peers: Arc<PeerIndex>,
+    next: S,
+}
+
+impl<S> RegistrationFilter<S>
+where
+    S: HyperService<HTTPRequest>,
+{
+    pub fn new(peers: Arc<PeerIndex>, next: S) -> Self {
+        RegistrationFilter { peers, next }
+    }
+}
+
+impl<S> HyperService<HTTPRequest> for RegistrationFilter<S>
+where
+    S: HyperService<
+        HTTPRequest,
+        Response = hyper::Response<hyper::Body>,
+        Error = hyper::Error,
+    >,
+{
+    type Response = http::Response<hyper::Body>;
+    type Error = hyper::Error;
+    type Future = Either<
+        Ready<Result<Self::Response, Self::Error>>,
+        S::Future,
+    >;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+       self.next.poll_ready(context)
+    }
+
+    fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+        let registry = match self.peers.registry() {
+            Some(registry)
+                if registry.has_provisioning_tokens()
+                    && request.uri().path().starts_with(PATH_PREFIX) =>
+                registry,
+            _ => return Either::Right(self.next.call(request)),
+        };
+
+        let is_authorized = match authorization_token(request.headers()) {
+            Some(token) => registry.is_provisioning_token(token),
+            None => false,
+        };
+        if !is_authorized {
+            warn!(
+                "invalid registration authorization: path={:?}",
+                request.uri().path(),
+            );
+            return Either::Left(ok(empty_response(hyper::StatusCode::UNAUTHORIZED)));
+        }
+        if request.method() != hyper::Method::POST {
+            return Either::Left(ok(empty_response(hyper::StatusCode::METHOD_NOT_ALLOWED)));
+        }
+
+        let account = &request.uri().path()[PATH_PREFIX.len()..];
+        let account = match percent_decode_str(account).decode_utf8() {
+            Ok(account) => account,
+            Err(_) => return Either::Left(ok(empty_response(hyper::StatusCode::BAD_REQUEST))),
+        };
+        Either::Left(ok(registration_response(self.peers.register_child(&account))))
+    }
+}
+
+#[cfg(test)]
+mod test_registration_filter {
+    use std::collections::HashSet;
+
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    use crate::{AuthToken, Relation};
+    use crate::middlewares::AuthHeader;
+    use crate::peers::{ChildRegistrationConfig, ChildRegistry, ConnectorPeer};
+    use crate::testing::ADDRESS;
+    use super::*;
+
+    fn make_filter(provisioning_tokens: Vec<AuthToken>)
+        -> (Arc<PeerIndex>, impl HyperService<
+            HTTPRequest,
+            Response = hyper::Response<hyper::Body>,
+            Error = hyper::Error,
+        >)
+    {
+        let registry = ChildRegistry::new(
+            ChildRegistrationConfig {
+                suffix_prefix: "c-".to_owned(),
+                max_children: 10,
+                provisioning_tokens,
+                store: None,
+            },
+            ADDRESS.to_address(),
+        );
+        let peers = PeerIndex::new(AuthHeader::default(), vec![ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new("static_child".to_owned()),
+            address: ADDRESS.with_suffix(b"static_child").unwrap(),
+            auth: HashSet::new(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline: false,
+        }]).with_registry(Arc::new(registry));
+        let peers = Arc::new(peers);
+        let filter = RegistrationFilter::new(
+            Arc::clone(&peers),
+            service_fn(|_req| {
+                ok::<_, hyper::Error>(empty_response(hyper::StatusCode::NO_CONTENT))
+            }),
+        );
+        (peers, filter)
+    }
+
+    fn make_request(method: &str, path: &str, token: Option<&str>)
+        -> HTTPRequest
+    {
+        let mut builder = hyper::Request::builder()
+            .method(method)
+            .uri(format!("http://relay.example{}", path));
+        if let Some(token) = token {
+            builder = builder.header("Authorization", token);
+        }
+        builder.body(hyper::Body::empty()).unwrap()
+    }
+
+    #[test]
+    fn test_register() {
+        let (peers, mut service) =
+            make_filter(vec![AuthToken::new("provisioning_secret")]);
+        let mut call = |method: &str, path: &str, token: Option<&str>| {
+            let response = block_on(service.call({
+                make_request(method, path, token)
+            })).unwrap();
+            let status = response.status();
+            let body = block_on(hyper::body::to_bytes(response.into_body()))
+                .unwrap();
+            (status, body)
+        };
+
+        let (status, body) =
+            call("POST", "/register/alice", Some("provisioning_secret"));
+        assert_eq!(status, 201);
+        let registration: serde_json::Value =
+            serde_json::from_slice(&body).unwrap();
+        assert_eq!(registration["account"], "alice");
+        assert_eq!(registration["suffix"], "c-alice");
+        assert_eq!(registration["address"], "test.relay.c-alice");
+        let token = registration["auth"].as_str().unwrap();
+        assert_eq!(
+            peers.find(token.as_bytes()).unwrap().account.as_str(),
+            "alice",
+        );
+
+        let token = Some("provisioning_secret");
+        assert_eq!(call("POST", "/register/alice", token).0, 409);
+        assert_eq!(call("POST", "/register/static_child", token).0, 409);
+        assert_eq!(call("POST", "/register/a.b", token).0, 400);
+        assert_eq!(call("DELETE", "/register/alice", token).0, 405);
+        assert_eq!(call("POST", "/register/bob", Some("wrong")).0, 401);
+        assert_eq!(call("POST", "/register/bob", None).0, 401);
+        // Other paths are passed through.
+        assert_eq!(call("POST", "/ilp", None).0, 204);
+    }
+
+    #[test]
+    fn test_disabled() {
+        let (_peers, mut service) = make_filter(Vec::new());
+        let response = block_on(service.call({
+            make_request("POST", "/register/alice", Some("provisioning_secret"))
+        })).unwrap();
+        assert_eq!(response.status(), 204);
+    }
+}
@@ -0,0 +1,299 @@
+use std::fmt;
+use std::pin::Pin;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use futures::prelude::*;
+use futures::task::{Context, Poll};
+use hyper::service::Service as HyperService;
+use log::{debug, warn};
+use ring::hmac;
+use serde::de::{Deserialize, Deserializer};
+
+use crate::combinators::{self, LimitStreamError};
+use crate::peers::PeerIndex;
+use super::MAX_REQUEST_SIZE;
+
+type HTTPRequest = http::Request<hyper::Body>;
+
+/// The request header that holds a peer's signature of the request body:
+/// `<account>:<signature>`, where the signature is the base64-encoded
+/// HMAC-SHA256 of the body, keyed with the peer's `signing_secret`.
+pub(crate) static SIGNATURE_HEADER: &str = "ILP-Signature";
+
+/// Authenticate requests that are signed by a peer, instead of carrying one of
+/// its tokens. Unlike a token, the signature is only valid for a single request
+/// body, so it is useless to anyone who reads it from a log or proxy.
+///
+/// Requests without an `ILP-Signature` header are passed on unchanged (to the
+/// `AuthTokenFilter`). Requests with a valid signature are passed on with the
+/// signing `ConnectorPeer` attached to their extensions. Otherwise, the
+/// response is `401 Unauthorized`.
+#[derive(Clone, Debug)]
+pub struct SignatureFilter<S> {
+    peers: Arc<PeerIndex>,
+    next: S,
+}
+
+impl<S> SignatureFilter<S>
+where
+    S: HyperService<HTTPRequest>,
+{
+    pub fn new(peers: Arc<PeerIndex>, next: S) -> Self {
+        SignatureFilter { peers, next }
+    }
+}
+
+impl<S> HyperService<HTTPRequest> for SignatureFilter<S>
+where
+    S: HyperService<
+        HTTPRequest,
+        Response = hyper::Response<hyper::Body>,
+        Error = hyper::Error,
+    > + Clone + Send + 'static,
+    S::Future: Send + 'static,
+{
+    type Response = http::Response<hyper::Body>;
+    type Error = hyper::Error;
+    type Future = Pin<Box<dyn Future<
+        Output = Result<Self::Response, Self::Error>,
+    > + Send + 'static>>;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+       self.next.poll_ready(context)
+    }
+
+    fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+        let signature = match request.headers().get(SIGNATURE_HEADER) {
+            Some(signature) => signature,
+            None => return Box::pin(self.next.call(request)),
+        };
+        let signer = parse_signature(signature.as_bytes())
+            .and_then(|(account, signature)| {
+                let peer = self.peers.find_account(account)?;
+                let secret = peer.signing_secret.clone()?;
+                Some((Arc::clone(peer), secret, signature))
+            });
+        let (peer, secret, signature) = match signer {
+            Some(signer) => signer,
+            None => {
+                warn!(
+                    "invalid signature: header={:?}",
+                    request.headers().get(SIGNATURE_HEADER),
+                );
+                return Box::pin(future::ok(unauthorized()));
+            },
+        };
+
+        let mut next = self.next.clone();
+        let (mut parts, body) = request.into_parts();
+        Box::pin(async move {
+            let body = match combinators::collect_http_body(
+                &parts.headers,
+                body,
+                MAX_REQUEST_SIZE,
+            ).await {
+                Ok(body) => body.freeze(),
+                Err(LimitStreamError::StreamError(error)) => return Err(error),
+                Err(LimitStreamError::LimitExceeded) => {
+                    warn!(
+                        "incoming request body too large: account={}",
+                        peer.account,
+                    );
+                    return Ok(hyper::Response::builder()
+                        .status(hyper::StatusCode::PAYLOAD_TOO_LARGE)
+                        .body(hyper::Body::from("Payload Too Large"))
+                        .expect("response builder error"));
+                },
+            };
+            if !secret.verify(&body, &signature) {
+                warn!("invalid signature: account={}", peer.account);
+                debug!("invalid signature: headers={:?}", parts.headers);
+                return Ok(unauthorized());
+            }
+            parts.extensions.insert(peer);
+            next.call(hyper::Request::from_parts(parts, body.into())).await
+        })
+    }
+}
+
+/// Split the header value into the signing account and the (decoded) signature.
+fn parse_signature(header: &[u8]) -> Option<(&str, Vec<u8>)> {
+    let header = std::str::from_utf8(header).ok()?;
+    let split = header.rfind(':')?;
+    let signature = base64::decode(&header[split + 1..]).ok()?;
+    Some((&header[..split], signature))
+}
+
+fn unauthorized() -> hyper::Response<hyper::Body> {
+    hyper::Response::builder()
+        .status(hyper::StatusCode::UNAUTHORIZED)
+        .body(hyper::Body::empty())
+        .expect("response builder error")
+}
+
+/// A secret shared with a peer, that it signs its requests with.
+#[derive(Clone, PartialEq)]
+pub struct SigningSecret(Bytes);
+
+impl SigningSecret {
+    pub fn new(secret: Bytes) -> Self {
+        SigningSecret(secret)
+    }
+
+    /// Sign the body (this is what the peer does).
+    pub fn sign(&self, body: &[u8]) -> Vec<u8> {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.0);
+        hmac::sign(&key, body).as_ref().to_vec()
+    }
+
+    /// Verify the body's signature (in constant time).
+    pub fn verify(&self, body: &[u8], signature: &[u8]) -> bool {
+        let key = hmac::Key::new(hmac::HMAC_SHA256, &self.0);
+        hmac::verify(&key, body, signature).is_ok()
+    }
+}
+
+// Don't log the secret.
+impl fmt::Debug for SigningSecret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("SigningSecret(..)")
+    }
+}
+
+impl<'de> Deserialize<'de> for SigningSecret {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let secret = String::deserialize(deserializer)?;
+        Ok(SigningSecret(Bytes::from(secret)))
+    }
+}
+
+#[cfg(test)]
+mod test_signature_filter {
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    use crate::Relation;
+    use crate::middlewares::AuthHeader;
+    use crate::peers::ConnectorPeer;
+    use super::*;
+
+    static BODY: &[u8] = b"prepare";
+
+    fn make_service() -> impl HyperService<
+        HTTPRequest,
+        Response = hyper::Response<hyper::Body>,
+        Error = hyper::Error,
+    > {
+        // Respond with `200` only when the peer was attached to the request
+        // and the body was preserved, `204` when no peer was attached.
+        let next = service_fn(|req: HTTPRequest| {
+            let peer = req.extensions().get::<Arc<ConnectorPeer>>().cloned();
+            hyper::body::to_bytes(req.into_body()).map_ok(move |body| {
+                let status = match peer {
+                    Some(peer) if peer.account.as_str() == "alice"
+                        && body.as_ref() == BODY => 200,
+                    Some(_) => 500,
+                    None => 204,
+                };
+                hyper::Response::builder()
+                    .status(status)
+                    .body(hyper::Body::empty())
+                    .unwrap()
+            })
+        });
+        let make_peer = |account: &str, signing_secret| ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new(account.to_owned()),
+            address: ilp::Address::new(b"test.relay.child"),
+            auth: Default::default(),
+            certificate: None,
+            signing_secret,
+            trust_deadline: false,
+        };
+        let peers = PeerIndex::new(AuthHeader::default(), vec![
+            make_peer("alice", Some(SigningSecret::new(Bytes::from("secret")))),
+            make_peer("bob", None),
+        ]);
+        SignatureFilter::new(Arc::new(peers), next)
+    }
+
+    fn call<S>(service: &mut S, signature: Option<String>) -> u16
+    where
+        S: HyperService<
+            HTTPRequest,
+            Response = hyper::Response<hyper::Body>,
+            Error = hyper::Error,
+        >,
+    {
+        let mut builder = hyper::Request::post("/");
+        if let Some(signature) = signature {
+            builder = builder.header(SIGNATURE_HEADER, signature);
+        }
+        let request = builder.body(hyper::Body::from(BODY)).unwrap();
+        block_on(service.call(request)).unwrap().status().as_u16()
+    }
+
+    fn sign(secret: &'static str, body: &[u8]) -> String {
+        base64::encode(SigningSecret::new(Bytes::from(secret)).sign(body))
+    }
+
+    #[test]
+    fn test_service() {
+        let mut service = make_service();
+        // Valid signature.
+        assert_eq!(
+            call(&mut service, Some(format!("alice:{}", sign("secret", BODY)))),
+            200,
+        );
+        // No signature.
+        assert_eq!(call(&mut service, None), 204);
+        // Wrong secret.
+        assert_eq!(
+            call(&mut service, Some(format!("alice:{}", sign("other", BODY)))),
+            401,
+        );
+        // Signature of a different body.
+        assert_eq!(
+            call(&mut service, Some(format!("alice:{}", sign("secret", b"x")))),
+            401,
+        );
+        // Unknown account, or an account without a signing secret.
+        assert_eq!(
+            call(&mut service, Some(format!("carl:{}", sign("secret", BODY)))),
+            401,
+        );
+        assert_eq!(
+            call(&mut service, Some(format!("bob:{}", sign("secret", BODY)))),
+            401,
+        );
+        // Malformed header.
+        assert_eq!(call(&mut service, Some("alice".to_owned())), 401);
+        assert_eq!(call(&mut service, Some("alice:!!!".to_owned())), 401);
+    }
+
+    #[test]
+    fn test_sign() {
+        // RFC 4231, test case 2.
+        let secret = SigningSecret::new(Bytes::from("Jefe"));
+        let signature = secret.sign(b"what do ya want for nothing?");
+        assert_eq!(
+            signature,
+            hex_decode("5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"),
+        );
+        assert!(secret.verify(b"what do ya want for nothing?", &signature));
+        assert!(!secret.verify(b"what do ya want for nothing", &signature));
+    }
+
+    fn hex_decode(hex: &str) -> Vec<u8> {
+        (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(&hex[i..i + 2], 16).unwrap())
+            .collect()
+    }
+}
@@ -0,0 +1,143 @@
+use std::sync::Arc;
+
+use futures::future::{Either, Ready, ok};
+use futures::task::{Context, Poll};
+use hyper::service::Service as HyperService;
+
+use crate::services::SpspReceiver;
+
+type HTTPRequest = http::Request<hyper::Body>;
+
+static PATH: &str = "/.well-known/pay";
+static CONTENT_TYPE: &str = "application/spsp4+json";
+
+/// Serve SPSP queries (`GET /.well-known/pay`) for the local `SpspReceiver`.
+/// Each query sets up a new STREAM connection.
+///
+/// Without a receiver, all requests are passed through.
+#[derive(Clone, Debug)]
+pub struct SpspFilter<S> {
+    receiver: Option<Arc<SpspReceiver>>,
+    next: S,
+}
+
+impl<S> SpspFilter<S>
+where
+    S: HyperService<HTTPRequest>,
+{
+    pub fn new(receiver: Option<Arc<SpspReceiver>>, next: S) -> Self {
+        SpspFilter { receiver, next }
+    }
+}
+
+impl<S> HyperService<HTTPRequest> for SpspFilter<S>
+where
+    S: HyperService<
+        HTTPRequest,
+        Response = hyper::Response<hyper::Body>,
+        Error = hyper::Error,
+    >,
+{
+    type Response = http::Response<hyper::Body>;
+    type Error = hyper::Error;
+    type Future = Either<
+        Ready<Result<Self::Response, Self::Error>>,
+        S::Future,
+    >;
+
+    fn poll_ready(&mut self, context: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+       self.next.poll_ready(context)
+    }
+
+    fn call(&mut self, request: hyper::Request<hyper::Body>) -> Self::Future {
+        let receiver = match &self.receiver {
+            Some(receiver)
+                if request.method() == hyper::Method::GET
+                    && request.uri().path() == PATH =>
+                receiver,
+            _ => return Either::Right(self.next.call(request)),
+        };
+        let body = serde_json::to_vec(&receiver.query())
+            .expect("failed to serialize SPSP response");
+        Either::Left(ok(hyper::Response::builder()
+            .status(hyper::StatusCode::OK)
+            .header(hyper::header::CONTENT_TYPE, CONTENT_TYPE)
+            .header(hyper::header::CONTENT_LENGTH, body.len())
+            // Every response has a new shared secret.
+            .header(hyper::header::CACHE_CONTROL, "no-store")
+            .body(hyper::Body::from(body))
+            .expect("response builder error")))
+    }
+}
+
+#[cfg(test)]
+mod test_spsp_filter {
+    use bytes::Bytes;
+    use futures::executor::block_on;
+    use hyper::service::service_fn;
+
+    use crate::combinators;
+    use crate::services::{ServerSecret, SpspConfig};
+    use super::*;
+
+    #[test]
+    fn test_service() {
+        let next = service_fn(|_req| {
+            ok(hyper::Response::builder()
+                .status(500)
+                .body(hyper::Body::empty())
+                .unwrap())
+        });
+        let ildcp = ilp::ildcp::ResponseBuilder {
+            client_address: ilp::Addr::new(b"example.connector"),
+            asset_scale: 9,
+            asset_code: b"XRP",
+        }.build();
+        let receiver = SpspReceiver::new(SpspConfig {
+            segment: "spsp".to_owned(),
+            server_secret: ServerSecret::new(Bytes::from_static(&[0x42; 32])),
+        }, ildcp).unwrap();
+        let mut service = SpspFilter::new(Some(Arc::new(receiver)), next);
+
+        let response = block_on(service.call({
+            hyper::Request::get(PATH)
+                .header(hyper::header::ACCEPT, CONTENT_TYPE)
+                .body(hyper::Body::empty())
+                .unwrap()
+        })).unwrap();
+        assert_eq!(response.status(), 200);
+        assert_eq!(
+            response.headers().get(hyper::header::CONTENT_TYPE).unwrap(),
+            CONTENT_TYPE,
+        );
+        let (parts, body) = response.into_parts();
+        let body = block_on(combinators::collect_http_body(
+            &parts.headers,
+            body,
+            1024,
+        )).unwrap();
+        let body: serde_json::Value = serde_json::from_slice(&body).unwrap();
+        assert!(body["destination_account"]
+            .as_str()
+            .unwrap()
+            .starts_with("example.connector.spsp."));
+        assert!(body["shared_secret"].is_string());
+
+        // Other paths and methods:
+        let request = hyper::Request::get("/").body(hyper::Body::empty()).unwrap();
+        assert_eq!(block_on(service.call(request)).unwrap().status(), 500);
+        let request = hyper::Request::post(PATH).body(hyper::Body::empty()).unwrap();
+        assert_eq!(block_on(service.call(request)).unwrap().status(), 500);
+
+        let mut service = SpspFilter::new(None, service_fn(|_req| {
+            ok::<_, hyper::Error>(hyper::Response::builder()
+                .status(500)
+                .body(hyper::Body::empty())
+                .unwrap())
+        }));
+        let request = hyper::Request::get(PATH).body(hyper::Body::empty()).unwrap();
+        assert_eq!(block_on(service.call(request)).unwrap().status(), 500);
+    }
+}
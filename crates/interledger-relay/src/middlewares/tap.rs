@@ -0,0 +1,295 @@
+//! The admin API's live packet tap (`GET /admin/tap`): a WebSocket that
+//! streams a summary of each `PacketEvent` as a JSON text message.
+
+use futures::future::{self, Either};
+use futures::prelude::*;
+use hyper::upgrade::Upgraded;
+use log::{debug, info, warn};
+use percent_encoding::percent_decode_str;
+use serde::Serialize;
+use tokio::sync::broadcast::RecvError;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_tungstenite::tungstenite::handshake::server::create_response;
+use tokio_tungstenite::tungstenite::protocol::Role;
+
+use crate::random_fraction;
+use crate::events::{PacketEvent, PacketEvents, PacketResult};
+use super::admin::empty_response;
+
+type HTTPRequest = http::Request<hyper::Body>;
+
+/// Which events a tap streams, from the `account`, `prefix`, and
+/// `sample_rate` query parameters.
+#[derive(Clone, Debug, PartialEq)]
+struct TapFilter {
+    /// Matches either the source or the destination account.
+    account: Option<String>,
+    prefix: Option<String>,
+    sample_rate: f64,
+}
+
+/// A packet summary. The packet's data, condition, and fulfillment, and the
+/// reject message (which may all carry user data) are left out.
+#[derive(Debug, Serialize)]
+struct TapSummary<'a> {
+    account: &'a str,
+    to_account: Option<&'a str>,
+    destination: &'a ilp::Address,
+    amount: u64,
+    /// `"fulfill"`, or the reject code.
+    result: String,
+    triggered_by: Option<&'a ilp::Address>,
+    latency_ms: f64,
+}
+
+/// Upgrade the (already authenticated) request to a WebSocket, and stream the
+/// matching events until the client disconnects.
+pub(crate) fn tap_response(events: &PacketEvents, request: HTTPRequest)
+    -> hyper::Response<hyper::Body>
+{
+    let filter = match TapFilter::parse(request.uri().query()) {
+        Some(filter) => filter,
+        None => return empty_response(hyper::StatusCode::BAD_REQUEST),
+    };
+    let (parts, body) = request.into_parts();
+    let response = match create_response(&http::Request::from_parts(parts, ())) {
+        Ok(response) => response,
+        Err(error) => {
+            debug!("invalid tap handshake: error={}", error);
+            return hyper::Response::builder()
+                .status(hyper::StatusCode::BAD_REQUEST)
+                .body(hyper::Body::from("Invalid WebSocket handshake"))
+                .expect("response builder error");
+        },
+    };
+
+    // Subscribe before responding, so that no event after the handshake is
+    // missed.
+    let receiver = events.subscribe();
+    tokio::spawn(body.on_upgrade().then(|upgraded| async move {
+        match upgraded {
+            Ok(upgraded) => run(upgraded, receiver, filter).await,
+            Err(error) => warn!("tap upgrade error: error={}", error),
+        }
+    }));
+    response.map(|()| hyper::Body::empty())
+}
+
+async fn run(
+    upgraded: Upgraded,
+    mut receiver: tokio::sync::broadcast::Receiver<PacketEvent>,
+    filter: TapFilter,
+) {
+    info!("starting packet tap: filter={:?}", filter);
+    let socket =
+        WebSocketStream::from_raw_socket(upgraded, Role::Server, None).await;
+    let (mut sink, mut stream) = socket.split();
+    // Incoming messages are ignored (pings are answered by the WebSocket
+    // itself), until the client closes the connection.
+    let mut closed = Box::pin(async move {
+        while let Some(Ok(message)) = stream.next().await {
+            if message.is_close() { break; }
+        }
+    });
+
+    loop {
+        let next_event = receiver.recv();
+        futures::pin_mut!(next_event);
+        let event = match future::select(next_event, &mut closed).await {
+            Either::Left((Ok(event), _closed)) => event,
+            Either::Left((Err(RecvError::Lagged(skipped)), _closed)) => {
+                debug!("packet tap lagged: skipped={}", skipped);
+                continue;
+            },
+            Either::Left((Err(RecvError::Closed), _closed)) => break,
+            Either::Right(((), _next_event)) => break,
+        };
+        if !filter.matches(&event) {
+            continue;
+        }
+        let summary = serde_json::to_string(&TapSummary::new(&event))
+            .expect("tap summary serialization error");
+        if let Err(error) = sink.send(Message::Text(summary)).await {
+            debug!("tap write error: error={}", error);
+            break;
+        }
+    }
+    info!("stopped packet tap");
+}
+
+impl TapFilter {
+    /// Returns `None` if a parameter is invalid or unknown.
+    fn parse(query: Option<&str>) -> Option<Self> {
+        let mut filter = TapFilter {
+            account: None,
+            prefix: None,
+            sample_rate: 1.0,
+        };
+        let params = query
+            .unwrap_or("")
+            .split('&')
+            .filter(|param| !param.is_empty());
+        for param in params {
+            let split = param.find('=')?;
+            let value = percent_decode_str(&param[split + 1..])
+                .decode_utf8()
+                .ok()?
+                .into_owned();
+            match &param[..split] {
+                "account" => filter.account = Some(value),
+                "prefix" => filter.prefix = Some(value),
+                "sample_rate" => {
+                    filter.sample_rate = value.parse().ok()?;
+                    if !(0.0..=1.0).contains(&filter.sample_rate) {
+                        return None;
+                    }
+                },
+                _ => return None,
+            }
+        }
+        Some(filter)
+    }
+
+    fn matches(&self, event: &PacketEvent) -> bool {
+        if let Some(account) = &self.account {
+            let to_account = event.to_account
+                .as_ref()
+                .map(|to_account| to_account.as_str());
+            if event.account.as_str() != account && to_account != Some(account) {
+                return false;
+            }
+        }
+        if let Some(prefix) = &self.prefix {
+            let destination: &[u8] = event.destination.as_ref();
+            if !destination.starts_with(prefix.as_bytes()) {
+                return false;
+            }
+        }
+        self.sample_rate >= 1.0 || random_fraction() < self.sample_rate
+    }
+}
+
+impl<'a> TapSummary<'a> {
+    fn new(event: &'a PacketEvent) -> Self {
+        let (result, triggered_by) = match &event.result {
+            PacketResult::Fulfilled => ("fulfill".to_owned(), None),
+            PacketResult::Rejected { code, triggered_by } =>
+                (code.to_string(), triggered_by.as_ref()),
+        };
+        TapSummary {
+            account: &event.account,
+            to_account: event.to_account
+                .as_ref()
+                .map(|to_account| to_account.as_str()),
+            destination: &event.destination,
+            amount: event.amount,
+            result,
+            triggered_by,
+            latency_ms: event.latency.as_secs_f64() * 1_000.0,
+        }
+    }
+}
+
+#[cfg(test)]
+mod test_tap {
+    use std::sync::Arc;
+    use std::time;
+
+    use tokio::net::TcpStream;
+
+    use super::*;
+
+    fn make_event(account: &str, destination: &'static [u8]) -> PacketEvent {
+        PacketEvent {
+            account: Arc::new(account.to_owned()),
+            to_account: Some(Arc::new("bob".to_owned())),
+            destination: ilp::Address::new(destination),
+            amount: 123,
+            result: PacketResult::Rejected {
+                code: ilp::ErrorCode::F02_UNREACHABLE,
+                triggered_by: Some(ilp::Address::new(b"test.relay")),
+            },
+            latency: time::Duration::from_millis(5),
+        }
+    }
+
+    #[test]
+    fn test_parse() {
+        assert_eq!(TapFilter::parse(None), Some(TapFilter {
+            account: None,
+            prefix: None,
+            sample_rate: 1.0,
+        }));
+        assert_eq!(
+            TapFilter::parse(Some("account=al%20ice&prefix=test.b&sample_rate=0.5")),
+            Some(TapFilter {
+                account: Some("al ice".to_owned()),
+                prefix: Some("test.b".to_owned()),
+                sample_rate: 0.5,
+            }),
+        );
+        assert_eq!(TapFilter::parse(Some("sample_rate=2")), None);
+        assert_eq!(TapFilter::parse(Some("sample_rate=x")), None);
+        assert_eq!(TapFilter::parse(Some("unknown=1")), None);
+        assert_eq!(TapFilter::parse(Some("account")), None);
+    }
+
+    #[test]
+    fn test_matches() {
+        let filter = |query| TapFilter::parse(Some(query)).unwrap();
+        let event = make_event("alice", b"test.bob.123");
+        assert!(filter("").matches(&event));
+        assert!(filter("account=alice").matches(&event));
+        assert!(filter("account=bob").matches(&event));
+        assert!(!filter("account=carl").matches(&event));
+        assert!(filter("prefix=test.bob.").matches(&event));
+        assert!(!filter("prefix=test.carl.").matches(&event));
+        assert!(!filter("sample_rate=0").matches(&event));
+    }
+
+    #[tokio::test]
+    async fn test_tap() {
+        let events = PacketEvents::new(16);
+        let server_events = events.clone();
+        let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+            .serve(hyper::service::make_service_fn(move |_socket| {
+                let events = server_events.clone();
+                future::ok::<_, std::convert::Infallible>(
+                    hyper::service::service_fn(move |request| {
+                        future::ok::<_, hyper::Error>(tap_response(&events, request))
+                    }),
+                )
+            }));
+        let addr = server.local_addr();
+        tokio::spawn(server.map(|result| result.unwrap()));
+
+        let tcp = TcpStream::connect(addr).await.unwrap();
+        let uri = format!("ws://{}/admin/tap?account=alice", addr);
+        let (mut socket, _response) =
+            tokio_tungstenite::client_async(uri.as_str(), tcp).await.unwrap();
+
+        events.publish(make_event("carl", b"test.bob.1"));
+        events.publish(make_event("alice", b"test.bob.2"));
+        let message = socket.next().await.unwrap().unwrap();
+        let summary: serde_json::Value =
+            serde_json::from_str(message.to_text().unwrap()).unwrap();
+        assert_eq!(summary, serde_json::json!({
+            "account": "alice",
+            "to_account": "bob",
+            "destination": "test.bob.2",
+            "amount": 123,
+            "result": "F02",
+            "triggered_by": "test.relay",
+            "latency_ms": 5.0,
+        }));
+
+        // The tap stops once the client disconnects.
+        socket.close(None).await.unwrap();
+        for _i in 0..100 {
+            if events.subscriber_count() == 0 { return; }
+            tokio::time::delay_for(time::Duration::from_millis(10)).await;
+        }
+        panic!("tap was not stopped");
+    }
+}
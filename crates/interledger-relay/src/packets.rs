@@ -1,35 +1,44 @@
-use std::borrow::Borrow;
+use std::borrow::{Borrow, BorrowMut};
 use std::sync::Arc;
+use std::time;
 
-use crate::services;
-use super::Relation;
+use crate::{ClientCertificate, Relation, Request, RequestId, RequestWithDeadline, RequestWithFrom, RequestWithIdempotencyKey, RequestWithPeerName};
+use crate::peers::ConnectorPeer;
 
-pub trait Request: Into<ilp::Prepare> + Borrow<ilp::Prepare> {}
-impl Request for ilp::Prepare {}
-impl Request for RequestWithHeaders {}
-impl Request for RequestFromPeer {}
-
-pub trait RequestWithPeerName: Request {
-    /// The value of the `ILP-Peer-Name` header.
-    fn peer_name(&self) -> Option<&[u8]>;
+impl Request for RequestWithHeaders {
+    fn request_id(&self) -> Option<&RequestId> {
+        Some(&self.request_id)
+    }
 }
 
-pub trait RequestWithFrom: Request {
-    fn from_account(&self) -> &Arc<String>;
-    fn from_relation(&self) -> Relation;
-    fn from_address(&self) -> ilp::Addr;
+impl Request for RequestFromPeer {
+    fn request_id(&self) -> Option<&RequestId> {
+        self.base.request_id()
+    }
 }
 
 #[derive(Clone, Debug, PartialEq)]
 pub struct RequestWithHeaders {
     pub(crate) prepare: ilp::Prepare,
     pub(crate) headers: hyper::HeaderMap,
+    /// The peer that was authenticated by the `AuthTokenFilter`.
+    pub(crate) peer: Option<Arc<ConnectorPeer>>,
+    /// The certificate that the client presented, when the listener uses TLS.
+    pub(crate) client_certificate: Option<Arc<ClientCertificate>>,
+    /// Taken from the incoming `X-Request-Id` header, or generated.
+    pub(crate) request_id: RequestId,
 }
 
 impl RequestWithHeaders {
     #[cfg(test)]
     pub fn new(prepare: ilp::Prepare, headers: hyper::HeaderMap) -> Self {
-        RequestWithHeaders { prepare, headers }
+        RequestWithHeaders {
+            prepare,
+            headers,
+            peer: None,
+            client_certificate: None,
+            request_id: RequestId::generate(),
+        }
     }
 
     pub fn header<K>(&self, header_name: K) -> Option<&[u8]>
@@ -53,6 +62,12 @@ impl Borrow<ilp::Prepare> for RequestWithHeaders {
     }
 }
 
+impl BorrowMut<ilp::Prepare> for RequestWithHeaders {
+    fn borrow_mut(&mut self) -> &mut ilp::Prepare {
+        &mut self.prepare
+    }
+}
+
 impl RequestWithPeerName for RequestWithHeaders {
     fn peer_name(&self) -> Option<&[u8]> {
         static PEER_NAME: &str = "ILP-Peer-Name";
@@ -63,6 +78,44 @@ impl RequestWithPeerName for RequestWithHeaders {
     }
 }
 
+impl RequestWithIdempotencyKey for RequestWithHeaders {
+    fn idempotency_key(&self) -> Option<&[u8]> {
+        static IDEMPOTENCY_KEY: &str = "Idempotency-Key";
+        self.headers
+            .get(IDEMPOTENCY_KEY)
+            .map(|header| header.as_ref())
+    }
+}
+
+/// The deadline is an RFC 3339 timestamp. When both headers are set, the
+/// earlier one is used.
+static DEADLINE_HEADERS: &[&str] = &[DEADLINE_HEADER, "X-Request-Deadline"];
+
+/// Sent to next hops that advertise `deadline_header` (see `Capabilities`).
+pub(crate) const DEADLINE_HEADER: &str = "ILP-Deadline";
+
+impl RequestWithDeadline for RequestWithHeaders {
+    fn deadline(&self) -> Option<time::SystemTime> {
+        let is_trusted = self.peer
+            .as_ref()
+            .map(|peer| peer.trust_deadline)
+            .unwrap_or(false);
+        if !is_trusted {
+            return None;
+        }
+        DEADLINE_HEADERS
+            .iter()
+            .filter_map(|name| self.headers.get(*name)?.to_str().ok())
+            .filter_map(|deadline| {
+                chrono::DateTime::parse_from_rfc3339(deadline).ok()
+            })
+            .map(time::SystemTime::from)
+            .min()
+    }
+}
+
+pub(crate) static REQUEST_ID_HEADER: &str = "X-Request-Id";
+
 #[derive(Clone, Debug, PartialEq)]
 pub struct RequestFromPeer {
     pub(crate) base: RequestWithHeaders,
@@ -83,12 +136,24 @@ impl Borrow<ilp::Prepare> for RequestFromPeer {
     }
 }
 
+impl BorrowMut<ilp::Prepare> for RequestFromPeer {
+    fn borrow_mut(&mut self) -> &mut ilp::Prepare {
+        self.base.borrow_mut()
+    }
+}
+
 impl RequestWithPeerName for RequestFromPeer {
     fn peer_name(&self) -> Option<&[u8]> {
         self.base.peer_name()
     }
 }
 
+impl RequestWithIdempotencyKey for RequestFromPeer {
+    fn idempotency_key(&self) -> Option<&[u8]> {
+        self.base.idempotency_key()
+    }
+}
+
 impl RequestWithFrom for RequestFromPeer {
     fn from_account(&self) -> &Arc<String> {
         &self.from_account
@@ -103,21 +168,57 @@ impl RequestWithFrom for RequestFromPeer {
     }
 }
 
-#[derive(Debug)]
-pub(crate) struct ResponseWithRoute {
-    pub(crate) packet: ResponsePacket,
-    /// The route which forwarded (outgoing) this response's corresponding
-    /// ILP-Prepare.
-    pub(crate) route: Option<services::RouteIndex>,
-}
+#[cfg(test)]
+mod test_request_with_headers {
+    use std::collections::HashSet;
+
+    use crate::testing::PREPARE;
+    use super::*;
+
+    #[test]
+    fn test_deadline() {
+        let early = time::UNIX_EPOCH + time::Duration::from_secs(1_600_000_000);
+        let late = early + time::Duration::from_secs(5);
+        let tests = &[
+            (true, vec![("ILP-Deadline", early)], Some(early)),
+            (true, vec![("X-Request-Deadline", early)], Some(early)),
+            // The earlier of the two deadlines is used.
+            (true, vec![("ILP-Deadline", late), ("X-Request-Deadline", early)], Some(early)),
+            // Untrusted peers' deadlines are ignored.
+            (false, vec![("ILP-Deadline", early)], None),
+        ];
+        for (trust_deadline, deadlines, expect) in tests {
+            let mut headers = hyper::HeaderMap::new();
+            for (name, deadline) in deadlines {
+                headers.insert(*name, {
+                    chrono::DateTime::<chrono::Utc>::from(*deadline)
+                        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                        .parse()
+                        .unwrap()
+                });
+            }
+            let mut request = RequestWithHeaders::new(PREPARE.clone(), headers);
+            request.peer = Some(make_peer(*trust_deadline));
+            assert_eq!(request.deadline(), *expect);
+        }
 
-type ResponsePacket = Result<ilp::Fulfill, ilp::Reject>;
+        // Invalid timestamps are ignored.
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("ILP-Deadline", "tomorrow".parse().unwrap());
+        let mut request = RequestWithHeaders::new(PREPARE.clone(), headers);
+        request.peer = Some(make_peer(true));
+        assert_eq!(request.deadline(), None);
+    }
 
-impl From<ResponsePacket> for ResponseWithRoute {
-    fn from(packet: ResponsePacket) -> Self {
-        ResponseWithRoute {
-            packet,
-            route: None,
-        }
+    fn make_peer(trust_deadline: bool) -> Arc<ConnectorPeer> {
+        Arc::new(ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new("alice".to_owned()),
+            address: ilp::Address::new(b"test.relay.alice"),
+            auth: HashSet::new(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline,
+        })
     }
 }
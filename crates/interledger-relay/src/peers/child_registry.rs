@@ -0,0 +1,435 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::{Arc, RwLock};
+
+use log::{error, info};
+use ring::digest;
+use serde::{Deserialize, Serialize};
+
+use crate::{AuthToken, Relation};
+use crate::app::SetupError;
+use crate::connector_info::ConnectorInfo;
+use crate::middlewares::constant_time_eq;
+use super::ConnectorPeer;
+
+/// The length (in random bytes) of a registered child's auth token.
+const TOKEN_LENGTH: usize = 32;
+
+#[derive(Clone, Debug, PartialEq, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ChildRegistrationConfig {
+    /// Prepended to each registered child's account, to derive its address
+    /// suffix.
+    #[serde(default)]
+    pub suffix_prefix: String,
+    /// The maximum number of registered children.
+    pub max_children: usize,
+    /// Valid tokens for the `Authorization` header of self-registration
+    /// requests. Without any, children can only be registered through the
+    /// admin API.
+    #[serde(default)]
+    pub provisioning_tokens: Vec<AuthToken>,
+    /// A JSON file that the registrations are saved to, and restored from on
+    /// startup.
+    #[serde(default)]
+    pub store: Option<PathBuf>,
+}
+
+/// Children that were registered at runtime (through the admin API), rather
+/// than configured as `relatives`. Each one gets an address that is derived
+/// from its account, and a generated auth token.
+///
+/// Without a `store`, registrations are kept in memory, so they are lost on
+/// restart. Either way, they aren't shared between instances.
+#[derive(Debug)]
+pub struct ChildRegistry {
+    config: ChildRegistrationConfig,
+    /// Children's addresses are derived from the parent's initial address (like
+    /// the configured relatives'), and rebased when they are shown.
+    parent: ConnectorInfo,
+    children: RwLock<RegisteredChildren>,
+}
+
+#[derive(Debug, Default)]
+struct RegisteredChildren {
+    /// Keyed by the SHA-256 digest of the auth token, so that the tokens
+    /// themselves aren't kept, and lookups don't leak (useful) timing.
+    tokens: HashMap<Vec<u8>, Arc<ConnectorPeer>>,
+    accounts: HashMap<Arc<String>, Vec<u8>>,
+}
+
+/// The response to a successful registration. This is the only time that the
+/// child's token is revealed.
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct Registration {
+    pub account: Arc<String>,
+    /// The segment that the child's address adds to the connector's address.
+    pub suffix: String,
+    /// The child's address. Its own address (in the ILDCP response) is this,
+    /// plus its `ILP-Peer-Name`.
+    pub address: String,
+    pub auth: String,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct RegisteredChild {
+    pub account: Arc<String>,
+    pub address: String,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum RegistrationError {
+    /// The account isn't a valid address segment.
+    InvalidAccount,
+    /// The account is already used by a relation or a registered child.
+    AccountExists,
+    /// The registry already has `max_children` children.
+    Full,
+    /// The change couldn't be saved to the `store`, so it was reverted.
+    Store,
+}
+
+/// A registration, as it is saved in the `store`.
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(deny_unknown_fields)]
+struct StoredChild {
+    account: String,
+    /// The base64 SHA-256 digest of the child's token.
+    token_digest: String,
+}
+
+impl ChildRegistry {
+    pub fn new(
+        config: ChildRegistrationConfig,
+        parent: impl Into<ConnectorInfo>,
+    ) -> Self {
+        ChildRegistry {
+            config,
+            parent: parent.into(),
+            children: RwLock::new(RegisteredChildren::default()),
+        }
+    }
+
+    /// Restore the registrations from the `store`, if it exists.
+    pub fn restore(&self) -> Result<(), SetupError> {
+        let path = match &self.config.store {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let stored = match fs::read(path) {
+            Ok(bytes) => serde_json::from_slice::<Vec<StoredChild>>(&bytes)
+                .map_err(|error| SetupError::invalid_config(format!(
+                    "invalid child_registration store: {}", error,
+                )))?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound =>
+                return Ok(()),
+            Err(error) => return Err(SetupError::from(error)),
+        };
+        let mut children = self.children.write().unwrap();
+        for child in stored {
+            let invalid = |reason: &str| SetupError::invalid_config(format!(
+                "invalid child_registration store: {}: account={:?}",
+                reason, child.account,
+            ));
+            let digest = base64::decode(&child.token_digest)
+                .map_err(|_| invalid("invalid token_digest"))?;
+            let peer = self.make_peer(&child.account)
+                .map_err(|_| invalid("invalid account"))?;
+            children.accounts.insert(Arc::clone(&peer.account), digest.clone());
+            children.tokens.insert(digest, Arc::new(peer));
+        }
+        info!("restored registered children: count={}", children.accounts.len());
+        Ok(())
+    }
+
+    /// Whether children may register themselves (without the admin API).
+    pub(crate) fn has_provisioning_tokens(&self) -> bool {
+        !self.config.provisioning_tokens.is_empty()
+    }
+
+    /// Whether the token may be used to register a child (without the admin
+    /// API).
+    pub(crate) fn is_provisioning_token(&self, token: &[u8]) -> bool {
+        self.config.provisioning_tokens
+            .iter()
+            .fold(false, |found, provisioning_token| {
+                found | constant_time_eq(provisioning_token.borrow(), token)
+            })
+    }
+
+    pub(crate) fn register(&self, account: &str)
+        -> Result<Registration, RegistrationError>
+    {
+        let peer = self.make_peer(account)?;
+        let account = Arc::clone(&peer.account);
+        let address = self.parent.rebase(&peer.address);
+        let token = generate_token();
+        let digest = token_digest(token.as_bytes());
+
+        let mut children = self.children.write().unwrap();
+        if children.accounts.contains_key(&account) {
+            return Err(RegistrationError::AccountExists);
+        }
+        if children.accounts.len() >= self.config.max_children {
+            return Err(RegistrationError::Full);
+        }
+        children.accounts.insert(Arc::clone(&account), digest.clone());
+        children.tokens.insert(digest.clone(), Arc::new(peer));
+        if let Err(error) = self.save(&children) {
+            error!("error saving registered child: account={} error={}", account, error);
+            children.accounts.remove(&account);
+            children.tokens.remove(&digest);
+            return Err(RegistrationError::Store);
+        }
+        info!("registered child: account={} address={}", account, address);
+        Ok(Registration {
+            suffix: format!("{}{}", self.config.suffix_prefix, account),
+            account,
+            address: address.to_string(),
+            auth: token,
+        })
+    }
+
+    /// Returns `Ok(false)` when the account isn't registered.
+    pub(crate) fn unregister(&self, account: &str)
+        -> Result<bool, RegistrationError>
+    {
+        let mut children = self.children.write().unwrap();
+        let (account, digest) =
+            match children.accounts.remove_entry(&String::from(account)) {
+                Some(entry) => entry,
+                None => return Ok(false),
+            };
+        let peer = children.tokens.remove(&digest)
+            .expect("registered child without token");
+        if let Err(error) = self.save(&children) {
+            error!("error saving unregistered child: account={} error={}", account, error);
+            children.accounts.insert(account, digest.clone());
+            children.tokens.insert(digest, peer);
+            return Err(RegistrationError::Store);
+        }
+        info!("unregistered child: account={}", account);
+        Ok(true)
+    }
+
+    /// Find the child that owns the token.
+    pub(crate) fn find(&self, token: &[u8]) -> Option<Arc<ConnectorPeer>> {
+        let children = self.children.read().unwrap();
+        children.tokens.get(&token_digest(token)).cloned()
+    }
+
+    /// The registered children, ordered by account.
+    pub(crate) fn children(&self) -> Vec<RegisteredChild> {
+        let children = self.children.read().unwrap();
+        let mut list = children.tokens
+            .values()
+            .map(|peer| RegisteredChild {
+                account: Arc::clone(&peer.account),
+                address: self.parent.rebase(&peer.address).to_string(),
+            })
+            .collect::<Vec<_>>();
+        list.sort_by(|a, b| a.account.cmp(&b.account));
+        list
+    }
+
+    fn make_peer(&self, account: &str)
+        -> Result<ConnectorPeer, RegistrationError>
+    {
+        let suffix = format!("{}{}", self.config.suffix_prefix, account);
+        if account.is_empty() || !suffix.bytes().all(is_segment_byte) {
+            return Err(RegistrationError::InvalidAccount);
+        }
+        let address = self.parent
+            .initial_address()
+            .with_suffix(suffix.as_bytes())
+            .map_err(|_| RegistrationError::InvalidAccount)?;
+        Ok(ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new(account.to_owned()),
+            address,
+            auth: HashSet::new(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline: false,
+        })
+    }
+
+    /// Write the registrations to the `store` (if any). The file is replaced
+    /// atomically, so a crash can't leave it half-written.
+    fn save(&self, children: &RegisteredChildren) -> io::Result<()> {
+        let path = match &self.config.store {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let mut stored = children.accounts
+            .iter()
+            .map(|(account, digest)| StoredChild {
+                account: account.as_ref().clone(),
+                token_digest: base64::encode(digest),
+            })
+            .collect::<Vec<_>>();
+        stored.sort_by(|a, b| a.account.cmp(&b.account));
+        let json = serde_json::to_vec(&stored)
+            .expect("registered children serialization error");
+        let mut temp_path = path.clone().into_os_string();
+        temp_path.push(".tmp");
+        fs::write(&temp_path, json)?;
+        fs::rename(&temp_path, path)
+    }
+}
+
+/// Whether the byte is allowed in an address segment (other than the scheme).
+fn is_segment_byte(byte: u8) -> bool {
+    byte.is_ascii_alphanumeric() || byte == b'_' || byte == b'-' || byte == b'~'
+}
+
+fn generate_token() -> String {
+    use ring::rand::SecureRandom;
+    let mut bytes = [0; TOKEN_LENGTH];
+    ring::rand::SystemRandom::new()
+        .fill(&mut bytes)
+        .expect("child token generation error");
+    base64::encode_config(bytes, base64::URL_SAFE_NO_PAD)
+}
+
+fn token_digest(token: &[u8]) -> Vec<u8> {
+    digest::digest(&digest::SHA256, token).as_ref().to_vec()
+}
+
+#[cfg(test)]
+mod test_child_registry {
+    use super::*;
+
+    fn make_registry(max_children: usize) -> ChildRegistry {
+        make_registry_with_store(max_children, None)
+    }
+
+    fn make_registry_with_store(max_children: usize, store: Option<PathBuf>)
+        -> ChildRegistry
+    {
+        ChildRegistry::new(
+            ChildRegistrationConfig {
+                suffix_prefix: "c-".to_owned(),
+                max_children,
+                provisioning_tokens: vec![AuthToken::new("provisioning_secret")],
+                store,
+            },
+            ilp::Address::new(b"test.relay"),
+        )
+    }
+
+    #[test]
+    fn test_register() {
+        let registry = make_registry(2);
+        let alice = registry.register("alice").unwrap();
+        assert_eq!(alice.account.as_str(), "alice");
+        assert_eq!(alice.suffix, "c-alice");
+        assert_eq!(alice.address, "test.relay.c-alice");
+        assert_eq!(alice.auth.len(), 43);
+
+        let peer = registry.find(alice.auth.as_bytes()).unwrap();
+        assert_eq!(peer.relation, Relation::Child);
+        assert_eq!(peer.account.as_str(), "alice");
+        assert_eq!(peer.address, ilp::Address::new(b"test.relay.c-alice"));
+        assert!(registry.find(b"").is_none());
+        assert!(registry.find(&alice.auth.as_bytes()[1..]).is_none());
+
+        assert_eq!(
+            registry.register("alice"),
+            Err(RegistrationError::AccountExists),
+        );
+        let bob = registry.register("bob").unwrap();
+        assert_ne!(alice.auth, bob.auth);
+        assert_eq!(registry.register("carl"), Err(RegistrationError::Full));
+        assert_eq!(
+            registry.children(),
+            vec![
+                RegisteredChild {
+                    account: Arc::new("alice".to_owned()),
+                    address: "test.relay.c-alice".to_owned(),
+                },
+                RegisteredChild {
+                    account: Arc::new("bob".to_owned()),
+                    address: "test.relay.c-bob".to_owned(),
+                },
+            ],
+        );
+    }
+
+    #[test]
+    fn test_register_invalid() {
+        let registry = make_registry(10);
+        for account in &["", "a.b", "a b", "ä", "a/b"] {
+            assert_eq!(
+                registry.register(account),
+                Err(RegistrationError::InvalidAccount),
+            );
+        }
+        // The address would be too long.
+        assert_eq!(
+            registry.register(&"a".repeat(1024)),
+            Err(RegistrationError::InvalidAccount),
+        );
+    }
+
+    #[test]
+    fn test_unregister() {
+        let registry = make_registry(1);
+        let alice = registry.register("alice").unwrap();
+        assert_eq!(registry.unregister("alice"), Ok(true));
+        assert_eq!(registry.unregister("alice"), Ok(false));
+        assert!(registry.find(alice.auth.as_bytes()).is_none());
+        // The slot is freed.
+        registry.register("bob").unwrap();
+    }
+
+    #[test]
+    fn test_is_provisioning_token() {
+        let registry = make_registry(1);
+        assert!(registry.has_provisioning_tokens());
+        assert!(registry.is_provisioning_token(b"provisioning_secret"));
+        assert!(!registry.is_provisioning_token(b"provisioning"));
+        assert!(!registry.is_provisioning_token(b""));
+    }
+
+    #[test]
+    fn test_store() {
+        let dir = std::env::temp_dir()
+            .join(format!("ilp-relay-children-{}", uuid::Uuid::new_v4()));
+        fs::create_dir(&dir).unwrap();
+        let store = dir.join("children.json");
+
+        // A missing store is empty.
+        let registry = make_registry_with_store(10, Some(store.clone()));
+        registry.restore().unwrap();
+        assert_eq!(registry.children(), vec![]);
+        let alice = registry.register("alice").unwrap();
+        let bob = registry.register("bob").unwrap();
+        assert_eq!(registry.unregister("bob"), Ok(true));
+
+        let restored = make_registry_with_store(10, Some(store.clone()));
+        restored.restore().unwrap();
+        assert_eq!(restored.children(), registry.children());
+        assert_eq!(
+            restored.find(alice.auth.as_bytes()).unwrap().address,
+            ilp::Address::new(b"test.relay.c-alice"),
+        );
+        assert!(restored.find(bob.auth.as_bytes()).is_none());
+        // The tokens themselves aren't saved.
+        let saved = fs::read_to_string(&store).unwrap();
+        assert!(!saved.contains(&alice.auth));
+
+        fs::write(&store, "{").unwrap();
+        assert!(make_registry_with_store(10, Some(store)).restore().is_err());
+
+        // The change is reverted when the store can't be written.
+        let registry =
+            make_registry_with_store(10, Some(dir.join("missing/children.json")));
+        assert_eq!(registry.register("carl"), Err(RegistrationError::Store));
+        assert_eq!(registry.children(), vec![]);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,557 @@
+use std::borrow::Borrow;
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time;
+
+use futures::future::{Either, Ready, err};
+use log::{debug, error, warn};
+
+use crate::{CertificateBinding, PeerAuthToken, Relation, Service};
+use crate::{RequestFromPeer, RequestWithHeaders};
+use crate::connector_info::ConnectorInfo;
+use crate::middlewares::{AuthHeader, JwtVerifier, SigningSecret, constant_time_eq};
+use crate::reject_reasons;
+use super::child_registry::{ChildRegistry, Registration, RegistrationError};
+
+/// Use the incoming auth header to tag requests with their peer's
+/// address.
+#[derive(Clone, Debug)]
+pub struct FromPeerService<S> {
+    connector: ConnectorInfo,
+    peers: Arc<PeerIndex>,
+    next: S,
+}
+
+impl<S> FromPeerService<S> {
+    pub fn new(
+        connector: impl Into<ConnectorInfo>,
+        peers: Arc<PeerIndex>,
+        next: S,
+    ) -> Self {
+        FromPeerService {
+            connector: connector.into(),
+            peers,
+            next,
+        }
+    }
+}
+
+impl<S> Service<RequestWithHeaders> for FromPeerService<S>
+where
+    S: Service<RequestFromPeer>,
+{
+    type Future = Either<
+        S::Future,
+        Ready<Result<ilp::Fulfill, ilp::Reject>>,
+    >;
+
+    fn call(self, mut req: RequestWithHeaders) -> Self::Future {
+        // The peer was usually already found by the auth middleware.
+        let peer = match req.peer.take() {
+            Some(peer) => Some(peer),
+            None => self.peers.authenticate(&req.headers),
+        };
+
+        // The auth middleware has already been run, so a peer should always be
+        // found. Check just to be safe.
+        let peer = match peer {
+            Some(peer) => peer,
+            None => {
+                error!(
+                    "could not determine packet source: request_id={} auth={:?}",
+                    req.request_id, req.header(self.peers.auth_header().name()),
+                );
+                return Either::Right(err({
+                    reject_reasons::UNKNOWN_SOURCE
+                        .to_reject(self.connector.address().as_addr())
+                }))
+            },
+        };
+
+        if let Some(binding) = &peer.certificate {
+            let is_match = match &req.client_certificate {
+                Some(certificate) => binding.matches(certificate),
+                None => false,
+            };
+            if !is_match {
+                warn!(
+                    "client certificate mismatch: request_id={} account={} certificate={:?}",
+                    req.request_id, peer.account, req.client_certificate,
+                );
+                return Either::Right(err({
+                    reject_reasons::CLIENT_CERTIFICATE_MISMATCH
+                        .to_reject(self.connector.address().as_addr())
+                }))
+            }
+        }
+
+        Either::Left(self.next.call(RequestFromPeer {
+            base: req,
+            from_account: Arc::clone(&peer.account),
+            from_relation: peer.relation,
+            // Children's addresses were derived from the connector's address
+            // at startup; follow it if the parent has since changed it.
+            from_address: self.connector.rebase(&peer.address),
+        }))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct ConnectorPeer {
+    pub relation: Relation,
+    /// A label for the peer. This is tagged as the `account` (i.e. the originating
+    /// account) when a packet is logged by the telemetry service.
+    pub account: Arc<String>,
+    pub address: ilp::Address,
+    /// The list of incoming authentication tokens (which are only valid within
+    /// their `not_before` and `expires_at`).
+    pub auth: HashSet<PeerAuthToken>,
+    /// When set, the peer must connect with a matching client certificate.
+    pub certificate: Option<CertificateBinding>,
+    /// When set, the peer may sign its requests instead of sending a token.
+    pub signing_secret: Option<SigningSecret>,
+    /// Whether the peer's `ILP-Deadline` header is honored.
+    pub trust_deadline: bool,
+}
+
+/// A precomputed map of incoming auth tokens to their peers. It is shared by
+/// the `AuthTokenFilter` (which authenticates requests) and the
+/// `FromPeerService` (which tags them).
+///
+/// With a `JwtVerifier`, credentials that don't match a static token are
+/// verified as a JWT, whose account claim identifies the peer. With a
+/// `ChildRegistry`, they are also checked against the registered children.
+#[derive(Debug, Default)]
+pub struct PeerIndex {
+    auth_header: AuthHeader,
+    tokens: Vec<(PeerAuthToken, usize)>,
+    peers: Vec<Arc<ConnectorPeer>>,
+    jwt: Option<Arc<JwtVerifier>>,
+    /// The index of each account's peer, for peers that authenticate by JWT
+    /// or signature.
+    accounts: HashMap<Arc<String>, usize>,
+    registry: Option<Arc<ChildRegistry>>,
+}
+
+impl PeerIndex {
+    pub fn new(auth_header: AuthHeader, peers: Vec<ConnectorPeer>) -> Self {
+        let peers = peers
+            .into_iter()
+            .map(Arc::new)
+            .collect::<Vec<_>>();
+        let tokens = peers
+            .iter()
+            .enumerate()
+            .flat_map(|(index, peer)| {
+                peer.auth
+                    .iter()
+                    .map(move |token| (token.clone(), index))
+            })
+            .collect();
+        let accounts = peers
+            .iter()
+            .enumerate()
+            .map(|(index, peer)| (Arc::clone(&peer.account), index))
+            .collect();
+        PeerIndex {
+            auth_header,
+            tokens,
+            peers,
+            jwt: None,
+            accounts,
+            registry: None,
+        }
+    }
+
+    pub fn with_jwt(mut self, verifier: Arc<JwtVerifier>) -> Self {
+        self.jwt = Some(verifier);
+        self
+    }
+
+    pub fn with_registry(mut self, registry: Arc<ChildRegistry>) -> Self {
+        self.registry = Some(registry);
+        self
+    }
+
+    pub(crate) fn registry(&self) -> Option<&ChildRegistry> {
+        self.registry.as_deref()
+    }
+
+    /// Register a child at runtime. Its account must not be used by any of the
+    /// configured relations.
+    pub(crate) fn register_child(&self, account: &str)
+        -> Option<Result<Registration, RegistrationError>>
+    {
+        let registry = self.registry.as_ref()?;
+        if self.accounts.contains_key(&String::from(account)) {
+            return Some(Err(RegistrationError::AccountExists));
+        }
+        Some(registry.register(account))
+    }
+
+    pub fn auth_header(&self) -> &AuthHeader {
+        &self.auth_header
+    }
+
+    /// Find the peer that owns the credentials in the request's auth header.
+    pub fn authenticate(&self, headers: &hyper::HeaderMap)
+        -> Option<Arc<ConnectorPeer>>
+    {
+        self.auth_header
+            .credentials(headers)
+            .and_then(|credentials| self.find(&credentials))
+    }
+
+    /// Find the peer that owns the token (without a `Bearer ` prefix). Every
+    /// token is compared in constant time and the scan never stops early, so
+    /// the duration doesn't reveal which token (if any) matched.
+    ///
+    /// Tokens outside of their `not_before`/`expires_at` window don't match.
+    pub fn find(&self, token: &[u8]) -> Option<Arc<ConnectorPeer>> {
+        self.find_at(token, time::SystemTime::now())
+    }
+
+    fn find_at(&self, token: &[u8], now: time::SystemTime)
+        -> Option<Arc<ConnectorPeer>>
+    {
+        let mut found = None;
+        let mut inactive = None;
+        for (peer_token, index) in &self.tokens {
+            let is_match = constant_time_eq(peer_token.token.borrow(), token);
+            if is_match && found.is_none() {
+                if peer_token.is_valid_at(now) {
+                    found = Some((peer_token, *index));
+                } else {
+                    inactive = Some((peer_token, *index));
+                }
+            }
+        }
+        let mut found = match (found, inactive) {
+            (Some((peer_token, index)), _) => {
+                debug!(
+                    "authenticated: account={} label={:?}",
+                    self.peers[index].account, peer_token.label(),
+                );
+                Some(index)
+            },
+            (None, Some((peer_token, index))) => {
+                let account = &self.peers[index].account;
+                throttled_warn!(
+                    account,
+                    "inactive or expired auth token: account={} label={:?}",
+                    account, peer_token.label(),
+                );
+                None
+            },
+            (None, None) => None,
+        };
+        if found.is_none() {
+            if let Some(verifier) = &self.jwt {
+                found = verifier.verify(token)
+                    .and_then(|account| self.accounts.get(&account).copied());
+            }
+        }
+        match found {
+            Some(index) => Some(Arc::clone(&self.peers[index])),
+            None => self.registry
+                .as_ref()
+                .and_then(|registry| registry.find(token)),
+        }
+    }
+
+    /// Find the peer with the account (e.g. a signature's signer).
+    pub fn find_account(&self, account: &str) -> Option<&Arc<ConnectorPeer>> {
+        self.accounts
+            .get(&String::from(account))
+            .map(|&index| &self.peers[index])
+    }
+
+    pub fn peers(&self) -> &[Arc<ConnectorPeer>] {
+        &self.peers
+    }
+}
+
+#[cfg(test)]
+mod test_from_peer_service {
+    use std::iter::FromIterator;
+
+    use futures::executor::block_on;
+    use hyper::HeaderMap;
+    use lazy_static::lazy_static;
+
+    use crate::testing::{self, FULFILL, PREPARE, MockService, PanicService};
+    use super::*;
+
+    lazy_static! {
+        static ref PEERS: Arc<PeerIndex> = Arc::new(PeerIndex::new(AuthHeader::default(), vec![
+            ConnectorPeer {
+                relation: Relation::Child,
+                account: Arc::new("child_account".to_owned()),
+                address: ilp::Address::new(b"test.relay.child"),
+                auth: HashSet::from_iter(vec![PeerAuthToken::new("token_1")]),
+                certificate: None,
+                signing_secret: None,
+                trust_deadline: false,
+            },
+            ConnectorPeer {
+                relation: Relation::Parent,
+                account: Arc::new("parent_account".to_owned()),
+                address: ilp::Address::new(b"test.relay"),
+                auth: HashSet::from_iter(vec![PeerAuthToken::new("token_2")]),
+                certificate: None,
+                signing_secret: None,
+                trust_deadline: false,
+            },
+        ]));
+    }
+
+    #[test]
+    fn test_peer_not_found() {
+        let service = FromPeerService::new(
+            ilp::Address::new(b"test.relay"),
+            Arc::clone(&PEERS),
+            PanicService,
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            hyper::header::AUTHORIZATION,
+            "invalid_token".parse().unwrap(),
+        );
+
+        let reject = block_on({
+            service.call(RequestWithHeaders::new(PREPARE.clone(), headers))
+        }).unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F00_BAD_REQUEST);
+        assert_eq!(reject.message(), &b"could not determine packet source"[..]);
+    }
+
+    #[test]
+    fn test_peer_found() {
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let service = FromPeerService::new(
+            ilp::Address::new(b"test.relay"),
+            Arc::clone(&PEERS),
+            next.clone(),
+        );
+
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            hyper::header::AUTHORIZATION,
+            "token_1".parse().unwrap(),
+        );
+
+        let request = RequestWithHeaders::new(PREPARE.clone(), headers);
+        let fulfill = block_on(service.call(request.clone())).unwrap();
+        assert_eq!(fulfill, *FULFILL);
+
+        assert_eq!(
+            next.requests().collect::<Vec<_>>(),
+            vec![RequestFromPeer {
+                base: request,
+                from_account: Arc::new("child_account".to_owned()),
+                from_relation: Relation::Child,
+                from_address: ilp::Address::new(b"test.relay.child"),
+            }],
+        );
+    }
+
+    #[test]
+    fn test_authenticated_peer() {
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let service = FromPeerService::new(
+            ilp::Address::new(b"test.relay"),
+            Arc::clone(&PEERS),
+            next.clone(),
+        );
+
+        // The peer found by the `AuthTokenFilter` is used without a lookup.
+        let mut request = RequestWithHeaders::new(PREPARE.clone(), HeaderMap::new());
+        request.peer = Some(Arc::clone(&PEERS.peers()[1]));
+        block_on(service.call(request)).unwrap();
+        let request = next.requests().next().unwrap();
+        assert_eq!(request.from_account.as_str(), "parent_account");
+        assert_eq!(request.from_relation, Relation::Parent);
+        assert!(request.base.peer.is_none());
+    }
+
+    #[test]
+    fn test_certificate_binding() {
+        let binding = serde_json::from_str::<CertificateBinding>(r#"{
+            "dns_names": ["alice.example.com"]
+        }"#).unwrap();
+        let peer = Arc::new(ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new("alice".to_owned()),
+            address: ilp::Address::new(b"test.relay.alice"),
+            auth: HashSet::from_iter(vec![PeerAuthToken::new("token_1")]),
+            certificate: Some(binding),
+            signing_secret: None,
+            trust_deadline: false,
+        });
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let service = FromPeerService::new(
+            ilp::Address::new(b"test.relay"),
+            Arc::clone(&PEERS),
+            next.clone(),
+        );
+
+        let mut request = RequestWithHeaders::new(PREPARE.clone(), HeaderMap::new());
+        request.peer = Some(Arc::clone(&peer));
+        request.client_certificate = Some(Arc::new(testing::client_certificate()));
+        assert!(block_on(service.clone().call(request)).is_ok());
+
+        // The certificate is missing.
+        let mut request = RequestWithHeaders::new(PREPARE.clone(), HeaderMap::new());
+        request.peer = Some(peer);
+        let reject = block_on(service.call(request)).unwrap_err();
+        assert_eq!(reject.code(), ilp::ErrorCode::F00_BAD_REQUEST);
+        assert_eq!(reject.message(), b"client certificate mismatch");
+        assert_eq!(next.requests().count(), 1);
+    }
+}
+
+#[cfg(test)]
+mod test_peer_index {
+    use super::*;
+
+    static TOKENS: &[&str] = &["token_1", "token_2"];
+
+    #[test]
+    fn test_find() {
+        let peer = ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new("child_account".to_owned()),
+            address: ilp::Address::new(b"test.relay"),
+            auth: TOKENS
+                .iter()
+                .cloned()
+                .map(PeerAuthToken::new)
+                .collect::<HashSet<_>>(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline: false,
+        };
+        let index = PeerIndex::new(AuthHeader::default(), vec![peer.clone()]);
+        assert_eq!(index.find(b"token_1").as_deref(), Some(&peer));
+        assert_eq!(index.find(b"token_2").as_deref(), Some(&peer));
+        assert!(index.find(b"token_3").is_none());
+        assert!(index.find(b"token_").is_none());
+        assert!(index.find(b"token_11").is_none());
+        assert!(index.find(b"").is_none());
+        assert_eq!(index.peers().len(), 1);
+    }
+
+    #[test]
+    fn test_find_rotated_tokens() {
+        let tokens = serde_json::from_str::<Vec<PeerAuthToken>>(r#"[
+            { "token": "old_token", "label": "old", "expires_at": "2020-06-01T00:00:00Z" },
+            { "token": "new_token", "label": "new", "not_before": "2020-05-01T00:00:00Z" },
+            "plain_token"
+        ]"#).unwrap();
+        assert_eq!(tokens[0].label(), "old");
+        assert_eq!(tokens[2], PeerAuthToken::new("plain_token"));
+        let peer = ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new("child_account".to_owned()),
+            address: ilp::Address::new(b"test.relay"),
+            auth: tokens.into_iter().collect(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline: false,
+        };
+        let index = PeerIndex::new(AuthHeader::default(), vec![peer]);
+        let at = |timestamp: &str| time::SystemTime::from({
+            chrono::DateTime::parse_from_rfc3339(timestamp).unwrap()
+        });
+
+        let april = at("2020-04-01T00:00:00Z");
+        assert!(index.find_at(b"old_token", april).is_some());
+        assert!(index.find_at(b"new_token", april).is_none());
+        // Both tokens are valid during the rotation.
+        let may = at("2020-05-01T00:00:00Z");
+        assert!(index.find_at(b"old_token", may).is_some());
+        assert!(index.find_at(b"new_token", may).is_some());
+        let june = at("2020-06-01T00:00:00Z");
+        assert!(index.find_at(b"old_token", june).is_none());
+        assert!(index.find_at(b"new_token", june).is_some());
+        assert!(index.find_at(b"plain_token", june).is_some());
+
+        assert!(serde_json::from_str::<PeerAuthToken>(r#"{
+            "token": "token", "expires_at": "not a timestamp"
+        }"#).is_err());
+        assert!(serde_json::from_str::<PeerAuthToken>(r#"{
+            "token": "token", "unknown": true
+        }"#).is_err());
+    }
+
+    #[test]
+    fn test_authenticate_custom_header() {
+        let peer = ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new("child_account".to_owned()),
+            address: ilp::Address::new(b"test.relay"),
+            auth: vec![PeerAuthToken::new("token_1")].into_iter().collect(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline: false,
+        };
+        let header = serde_json::from_str::<AuthHeader>("\"X-Api-Key\"")
+            .unwrap();
+        let index = PeerIndex::new(header, vec![peer.clone()]);
+
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert("Authorization", "token_1".parse().unwrap());
+        assert!(index.authenticate(&headers).is_none());
+        headers.insert("X-Api-Key", "token_1".parse().unwrap());
+        assert_eq!(
+            index.authenticate(&headers).as_deref(),
+            Some(&peer),
+        );
+    }
+
+    #[test]
+    fn test_authenticate_jwt() {
+        let verifier = futures::executor::block_on(JwtVerifier::new(crate::JwtAuthConfig {
+            key: crate::JwtKeyConfig::Secret { secret: "jwt_secret".to_owned() },
+            issuer: "issuer".to_owned(),
+            audience: "relay".to_owned(),
+            account_claim: "sub".to_owned(),
+        })).unwrap();
+        let index = PeerIndex::new(AuthHeader::default(), vec![
+            ConnectorPeer {
+                relation: Relation::Child,
+                account: Arc::new("child_account".to_owned()),
+                address: ilp::Address::new(b"test.relay.child"),
+                auth: HashSet::new(),
+                certificate: None,
+                signing_secret: None,
+                trust_deadline: false,
+            },
+        ]).with_jwt(Arc::new(verifier));
+        let sign = |account: &str| {
+            jsonwebtoken::encode(
+                &jsonwebtoken::Header::default(),
+                &serde_json::json!({
+                    "iss": "issuer",
+                    "aud": "relay",
+                    "sub": account,
+                    "exp": 4_000_000_000_u64,
+                }),
+                &jsonwebtoken::EncodingKey::from_secret(b"jwt_secret"),
+            ).unwrap()
+        };
+
+        let mut headers = hyper::HeaderMap::new();
+        headers.insert(
+            "Authorization",
+            format!("Bearer {}", sign("child_account")).parse().unwrap(),
+        );
+        assert_eq!(
+            index.authenticate(&headers).as_deref().map(|peer| peer.account.as_str()),
+            Some("child_account"),
+        );
+        // The token is valid, but its account isn't a peer.
+        assert!(index.find(sign("unknown_account").as_bytes()).is_none());
+    }
+}
@@ -0,0 +1,5 @@
+mod child_registry;
+mod from_peer;
+
+pub use self::child_registry::{ChildRegistrationConfig, ChildRegistry, Registration, RegistrationError};
+pub use self::from_peer::{ConnectorPeer, FromPeerService, PeerIndex};
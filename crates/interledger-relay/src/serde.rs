@@ -1,6 +1,8 @@
 use hyper::Uri;
 use serde::de::{self, Deserialize, Deserializer};
 
+pub use interledger_relay_core::serde::{deserialize_error_code, deserialize_timestamp};
+
 pub fn deserialize_uri<'de, D>(deserializer: D) -> Result<Uri, D::Error>
 where
     D: Deserializer<'de>,
@@ -12,13 +14,14 @@ where
 
 #[cfg(test)]
 mod tests {
+    use std::collections::HashMap;
     use std::sync::Arc;
     use std::time;
 
     use serde::Deserialize;
 
-    use crate::{AuthToken, BigQueryConfig, BigQueryServiceConfig, DebugServiceOptions, RoutingPartition, RoutingTableData};
-    use crate::app::{Config, ConnectorRoot, RelationConfig};
+    use crate::{AccessLogConfig, AdminApiConfig, AuthHeader, AuthLockoutConfig, AuthToken, CatchAllWarningConfig, CertificateBinding, ChildRegistrationConfig, ClientPoolConfig, RateLimitConfig, RejectJitterConfig, BigQueryConfig, DebugServiceOptions, DedupeConfig, EchoServiceOptions, ExchangeRatesConfig, GreylistAction, GreylistConfig, JwtAuthConfig, JwtKeyConfig, PeerAuthToken, RateProvider, RoutingPartition, RoutingTableData, RowColumn, ServerSecret, SettlementAccountConfig, SettlementConfig, SigningSecret, SinkConfig, SpillConfig, SpspConfig, TelemetryServiceConfig, ThroughputLimitConfig, TlsConfig, UnavailablePolicy};
+    use crate::app::{Config, ConnectorRoot, InstanceConfig, RelationConfig};
     use crate::testing::ROUTES;
     use super::*;
 
@@ -54,6 +57,16 @@ mod tests {
             , "account": "child_account"
             , "auth": ["child_secret"]
             , "suffix": "child"
+            , "rate_limit": { "packets_per_second": 100.0, "burst": 50 }
+            , "throughput_limit":
+                { "amount": 1000000
+                , "interval": { "secs": 60, "nanos": 0 }
+                }
+            , "max_in_flight": 20
+            , "certificate": { "dns_names": ["child.example.com"] }
+            , "signing_secret": "child_signing_secret"
+            , "allowed_ips": ["10.0.0.0/8", "2001:db8::1"]
+            , "trust_deadline": true
             }
           , { "type": "Parent"
             , "account": "parent_account"
@@ -95,15 +108,103 @@ mod tests {
             , "log_fulfill": false
             , "log_reject": true
             }
+        , "echo_service": { "enabled": true }
+        , "spsp": { "server_secret": "spsp_server_secret" }
+        , "simulation_mode": true
+        , "max_in_flight": 1000
+        , "dedupe": { "ttl": { "secs": 30, "nanos": 0 } }
+        , "catch_all_warning":
+            { "max_packets": 1000
+            , "window": { "secs": 60, "nanos": 0 }
+            }
+        , "client_pool":
+            { "max_idle_per_host": 16
+            , "idle_timeout": { "secs": 30, "nanos": 0 }
+            }
+        , "peer_discovery": true
         , "big_query_service":
             { "queue_count": 5
             , "flush_interval": { "secs": 123, "nanos": 0 }
+            , "max_row_age": { "secs": 5, "nanos": 0 }
+            , "max_attempts": 10
             , "project_id": "PROJECT_ID"
             , "dataset_id": "DATASET_ID"
             , "table_id": "TABLE_ID"
+            , "reject_sink":
+                { "project_id": "PROJECT_ID"
+                , "dataset_id": "DATASET_ID"
+                , "table_id": "REJECTS"
+                }
+            , "spill":
+                { "directory": "/var/lib/relay/spill"
+                , "max_size": 1073741824
+                }
+            , "on_unavailable": "forward"
+            , "extra_columns": ["latency_ms", "has_connection_tag"]
+            , "sample_rate": 0.5
+            , "account_sample_rates": { "alice": 1.0 }
+            }
+        , "settlement":
+            { "engine_url": "http://127.0.0.1:3000"
+            , "accounts":
+                { "alice": { "settle_threshold": 1000000, "settle_to": 1000 }
+                }
             }
         , "pre_stop_path": "/pre_stop"
+        , "pre_stop_grace_period": { "secs": 15, "nanos": 0 }
+        , "drain_timeout": { "secs": 20, "nanos": 0 }
+        , "request_read_timeout": { "secs": 5, "nanos": 0 }
+        , "btp_path": "/btp"
+        , "tls":
+            { "cert_file": "/etc/relay/cert.pem"
+            , "key_file": "/etc/relay/key.pem"
+            , "client_ca_file": "/etc/relay/ca.pem"
+            }
         , "routing_partition": "ExecutionCondition"
+        , "auth_header": "X-Api-Key"
+        , "auth_lockout":
+            { "max_failures": 10
+            , "window": { "secs": 60, "nanos": 0 }
+            , "lockout": { "secs": 300, "nanos": 0 }
+            }
+        , "reject_jitter":
+            { "max_delay": { "secs": 0, "nanos": 200000000 }
+            , "codes": ["F02", "F99"]
+            }
+        , "greylist":
+            { "window": { "secs": 60, "nanos": 0 }
+            , "max_novel_prefixes": 100
+            , "action": "throttle"
+            }
+        , "exchange_rates":
+            { "provider":
+                { "type": "Http"
+                , "url": "https://rates.example/latest"
+                , "interval": { "secs": 60, "nanos": 0 }
+                }
+            , "max_age": { "secs": 300, "nanos": 0 }
+            }
+        , "trusted_proxies": ["10.1.0.0/16"]
+        , "jwt_auth":
+            { "key":
+                { "type": "Jwks"
+                , "url": "https://auth.example/.well-known/jwks.json"
+                }
+            , "issuer": "https://auth.example/"
+            , "audience": "relay"
+            }
+        , "child_registration":
+            { "suffix_prefix": "c-"
+            , "max_children": 1000
+            , "provisioning_tokens": ["provisioning_secret"]
+            , "store": "/var/lib/relay/children.json"
+            }
+        , "admin_api": { "auth": ["admin_secret"] }
+        , "access_log": { "file": "-" }
+        , "instance":
+            { "id": "relay-1"
+            , "labels": { "region": "us-west1" }
+            }
         }"#).expect("valid json");
 
         assert_eq!(
@@ -117,34 +218,194 @@ mod tests {
                 relatives: vec![
                     RelationConfig::Child {
                         account: Arc::new("child_account".to_owned()),
-                        auth: vec![AuthToken::new("child_secret")],
+                        auth: vec![PeerAuthToken::new("child_secret")],
                         suffix: "child".to_owned(),
+                        rate_limit: Some(RateLimitConfig {
+                            packets_per_second: 100.0,
+                            burst: 50,
+                        }),
+                        throughput_limit: Some(ThroughputLimitConfig {
+                            amount: 1_000_000,
+                            interval: time::Duration::from_secs(60),
+                        }),
+                        max_in_flight: Some(20),
+                        certificate: Some(CertificateBinding {
+                            fingerprints: vec![],
+                            dns_names: vec!["child.example.com".to_owned()],
+                        }),
+                        signing_secret: Some(SigningSecret::new({
+                            bytes::Bytes::from("child_signing_secret")
+                        })),
+                        allowed_ips: vec![
+                            "10.0.0.0/8".parse().unwrap(),
+                            "2001:db8::1".parse().unwrap(),
+                        ],
+                        trust_deadline: true,
                     },
                     RelationConfig::Parent {
                         account: Arc::new("parent_account".to_owned()),
-                        auth: vec![AuthToken::new("parent_secret")],
+                        auth: vec![PeerAuthToken::new("parent_secret")],
+                        rate_limit: None,
+                        throughput_limit: None,
+                        max_in_flight: None,
+                        certificate: None,
+                        signing_secret: None,
+                        allowed_ips: vec![],
                     },
                 ],
                 routes: RoutingTableData(ROUTES.to_vec()),
+                scheduled_routes: None,
                 debug_service: DebugServiceOptions {
                     log_prepare: false,
                     log_fulfill: false,
                     log_reject: true,
                 },
-                big_query_service: Some(BigQueryServiceConfig {
+                echo_service: EchoServiceOptions { enabled: true },
+                spsp: Some(SpspConfig {
+                    segment: "spsp".to_owned(),
+                    server_secret: ServerSecret::new(bytes::Bytes::from("spsp_server_secret")),
+                }),
+                simulation_mode: true,
+                max_in_flight: Some(1000),
+                dedupe: Some(DedupeConfig {
+                    ttl: time::Duration::from_secs(30),
+                    max_entries: 100_000,
+                }),
+                catch_all_warning: Some(CatchAllWarningConfig {
+                    max_packets: 1000,
+                    window: time::Duration::from_secs(60),
+                }),
+                client_pool: ClientPoolConfig {
+                    max_idle_per_host: Some(16),
+                    idle_timeout: Some(time::Duration::from_secs(30)),
+                    keep_alive: None,
+                },
+                peer_discovery: true,
+                telemetry_service: Some(TelemetryServiceConfig {
                     queue_count: 5,
                     batch_capacity: 500,
+                    max_batch_bytes: 9_000_000,
+                    max_row_age: Some(time::Duration::from_secs(5)),
+                    retry_backoff: time::Duration::from_secs(1),
+                    max_retry_backoff: time::Duration::from_secs(60),
+                    max_attempts: Some(10),
+                    dead_letter: None,
                     flush_interval: time::Duration::from_secs(123),
-                    big_query: BigQueryConfig {
+                    sink: SinkConfig::BigQuery(BigQueryConfig {
                         origin: "https://bigquery.googleapis.com".to_owned(),
                         project_id: "PROJECT_ID".to_owned(),
                         dataset_id: "DATASET_ID".to_owned(),
                         table_id: "TABLE_ID".to_owned(),
                         service_account_key_file: None,
+                    }),
+                    reject_sink: Some(SinkConfig::BigQuery(BigQueryConfig {
+                        origin: "https://bigquery.googleapis.com".to_owned(),
+                        project_id: "PROJECT_ID".to_owned(),
+                        dataset_id: "DATASET_ID".to_owned(),
+                        table_id: "REJECTS".to_owned(),
+                        service_account_key_file: None,
+                    })),
+                    shadow_sink: None,
+                    cutover: false,
+                    extra_columns: vec![
+                        RowColumn::LatencyMs,
+                        RowColumn::HasConnectionTag,
+                    ],
+                    sample_rate: 0.5,
+                    account_sample_rates: {
+                        let mut rates = HashMap::new();
+                        rates.insert("alice".to_owned(), 1.0);
+                        rates
+                    },
+                    spill: Some(SpillConfig {
+                        directory: "/var/lib/relay/spill".into(),
+                        segment_size: 16 * 1024 * 1024,
+                        max_size: 1_073_741_824,
+                    }),
+                    on_unavailable: UnavailablePolicy::Forward,
+                    unavailable_sample_rate: 0.1,
+                }),
+                settlement: Some(SettlementConfig {
+                    engine_url: "http://127.0.0.1:3000".to_owned(),
+                    accounts: {
+                        let mut accounts = HashMap::new();
+                        accounts.insert("alice".to_owned(), SettlementAccountConfig {
+                            settle_threshold: 1_000_000,
+                            settle_to: 1_000,
+                            asset_scale: None,
+                        });
+                        accounts
                     },
                 }),
                 pre_stop_path: Some("/pre_stop".to_owned()),
+                pre_stop_grace_period: Some(time::Duration::from_secs(15)),
+                drain_timeout: Some(time::Duration::from_secs(20)),
+                request_read_timeout: Some(time::Duration::from_secs(5)),
+                btp_path: Some("/btp".to_owned()),
+                tls: Some(TlsConfig {
+                    cert_file: "/etc/relay/cert.pem".to_owned(),
+                    key_file: "/etc/relay/key.pem".to_owned(),
+                    client_ca_file: Some("/etc/relay/ca.pem".to_owned()),
+                }),
                 routing_partition: RoutingPartition::ExecutionCondition,
+                auth_header: serde_json::from_str::<AuthHeader>("\"X-Api-Key\"").unwrap(),
+                auth_lockout: Some(AuthLockoutConfig {
+                    max_failures: 10,
+                    window: time::Duration::from_secs(60),
+                    lockout: time::Duration::from_secs(300),
+                }),
+                reject_jitter: Some(RejectJitterConfig {
+                    max_delay: time::Duration::from_millis(200),
+                    codes: vec![
+                        ilp::ErrorCode::F02_UNREACHABLE,
+                        ilp::ErrorCode::F99_APPLICATION_ERROR,
+                    ],
+                }),
+                greylist: Some(GreylistConfig {
+                    segments: 3,
+                    window: time::Duration::from_secs(60),
+                    max_novel_prefixes: 100,
+                    learning_period: time::Duration::from_secs(0),
+                    action: GreylistAction::Throttle,
+                    max_known_prefixes: 10_000,
+                }),
+                exchange_rates: Some(ExchangeRatesConfig {
+                    provider: RateProvider::Http {
+                        url: "https://rates.example/latest".parse().unwrap(),
+                        interval: time::Duration::from_secs(60),
+                    },
+                    max_age: Some(time::Duration::from_secs(300)),
+                }),
+                trusted_proxies: vec!["10.1.0.0/16".parse().unwrap()],
+                jwt_auth: Some(JwtAuthConfig {
+                    key: JwtKeyConfig::Jwks {
+                        url: "https://auth.example/.well-known/jwks.json".parse().unwrap(),
+                        refresh_interval: None,
+                    },
+                    issuer: "https://auth.example/".to_owned(),
+                    audience: "relay".to_owned(),
+                    account_claim: "sub".to_owned(),
+                }),
+                child_registration: Some(ChildRegistrationConfig {
+                    suffix_prefix: "c-".to_owned(),
+                    max_children: 1000,
+                    provisioning_tokens: vec![
+                        AuthToken::new("provisioning_secret"),
+                    ],
+                    store: Some("/var/lib/relay/children.json".into()),
+                }),
+                admin_api: Some(AdminApiConfig {
+                    auth: vec![AuthToken::new("admin_secret")],
+                }),
+                access_log: Some(AccessLogConfig {
+                    file: "-".into(),
+                }),
+                instance: InstanceConfig {
+                    id: Some(Arc::new("relay-1".to_owned())),
+                    labels: vec![("region".to_owned(), "us-west1".to_owned())]
+                        .into_iter()
+                        .collect(),
+                },
             },
         );
     }
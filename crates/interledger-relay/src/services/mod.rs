@@ -1,15 +0,0 @@
-mod big_query;
-mod debug;
-mod echo;
-mod expiry;
-mod from_peer;
-mod ildcp;
-mod router;
-
-pub use self::big_query::{BigQueryConfig, BigQueryService, BigQueryServiceConfig};
-pub use self::debug::{DebugService, DebugServiceOptions};
-pub use self::echo::EchoService;
-pub use self::expiry::ExpiryService;
-pub use self::from_peer::{ConnectorPeer, FromPeerService};
-pub use self::ildcp::ConfigService;
-pub use self::router::*;
@@ -1,13 +0,0 @@
-mod dynamic_route;
-mod partition;
-mod serde;
-mod service;
-mod static_route;
-mod table;
-
-pub use self::dynamic_route::{DynamicRoute, RouteStatus};
-pub use self::partition::RoutingPartition;
-pub use self::serde::RoutingTableData;
-pub use self::service::RouterService;
-pub use self::static_route::{NextHop, RouteFailover, StaticRoute};
-pub use self::table::{RouteIndex, RoutingError, RoutingTable};
@@ -0,0 +1,37 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use tokio::net::TcpStream;
+
+use crate::SetupError;
+use crate::google_client::GoogleClient;
+use crate::transport::{ConnectFuture, Connection, GoogleClientFuture, HttpClient, SinkTransport};
+
+/// Connects the telemetry sinks: Google Cloud APIs over HTTPS, and Kafka
+/// brokers over TCP.
+#[derive(Clone, Debug, Default)]
+pub struct TcpSinkTransport;
+
+impl SinkTransport for TcpSinkTransport {
+    fn google_client(
+        &self,
+        service_account_key_file: Option<PathBuf>,
+        scopes: &'static [&'static str],
+    ) -> GoogleClientFuture {
+        Box::pin(async move {
+            let client = GoogleClient::from_key_file(
+                service_account_key_file.as_deref(),
+                scopes,
+            ).await.map_err(SetupError::other)?;
+            Ok(Arc::new(client) as Arc<dyn HttpClient>)
+        })
+    }
+
+    fn connect(&self, address: &str) -> ConnectFuture {
+        let address = address.to_owned();
+        Box::pin(async move {
+            let stream = TcpStream::connect(address).await?;
+            Ok(Box::new(stream) as Box<dyn Connection>)
+        })
+    }
+}
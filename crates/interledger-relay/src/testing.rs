@@ -1,195 +1,89 @@
 //! Test helpers, mocks, and fixtures.
 
-use std::pin::Pin;
-use std::sync::{Arc, Mutex, RwLock};
-use std::time::{Duration, SystemTime};
+use std::collections::HashSet;
+use std::net::SocketAddr;
+use std::sync::{Arc, Mutex};
 
 use bytes::Bytes;
 use futures::prelude::*;
-use hyper::Uri;
 use lazy_static::lazy_static;
 
+use crate::btp::BtpReceiver;
 use crate::combinators;
-use crate::{AuthToken, NextHop, Request, Service, StaticRoute};
+use crate::metrics::Metrics;
+use crate::middlewares::IpAllowlist;
+use crate::peers::{ConnectorPeer, PeerIndex};
+use crate::tls::ClientCertificate;
+use crate::{AuthHeader, PeerAuthToken, Relation, RequestWithHeaders, Service};
 
-const EXPIRES_IN: Duration = Duration::from_secs(20);
+pub use interledger_relay_core::testing::*;
 
-pub static RECEIVER_ORIGIN: &'static str = "http://127.0.0.1:3001";
+/// The address of `RECEIVER_ORIGIN`.
 static RECEIVER_ADDR: ([u8; 4], u16) = ([127, 0, 0, 1], 3001);
-pub static ADDRESS: ilp::Addr<'static> = unsafe {
-    ilp::Addr::new_unchecked(b"test.relay")
-};
 
-lazy_static! {
-    pub static ref PREPARE: ilp::Prepare = ilp::PrepareBuilder {
-        amount: 123,
-        expires_at: truncate_nanos(SystemTime::now() + EXPIRES_IN),
-        execution_condition: b"\
-            \x11\x7b\x43\x4f\x1a\x54\xe9\x04\x4f\x4f\x54\x92\x3b\x2c\xff\x9e\
-            \x4a\x6d\x42\x0a\xe2\x81\xd5\x02\x5d\x7b\xb0\x40\xc4\xb4\xc0\x4a\
-        ",
-        destination: ilp::Addr::new(b"test.alice.1234"),
-        data: b"prepare data",
-    }.build();
-
-    pub static ref PREPARE_MULTILATERAL: ilp::Prepare = ilp::PrepareBuilder {
-        amount: 123,
-        expires_at: truncate_nanos(SystemTime::now() + EXPIRES_IN),
-        execution_condition: b"\
-            \x11\x7b\x43\x4f\x1a\x54\xe9\x04\x4f\x4f\x54\x92\x3b\x2c\xff\x9e\
-            \x4a\x6d\x42\x0a\xe2\x81\xd5\x02\x5d\x7b\xb0\x40\xc4\xb4\xc0\x4a\
-        ",
-        destination: ilp::Addr::new(b"test.relay.1234.5678"),
-        data: b"prepare data",
-    }.build();
-
-    pub static ref FULFILL: ilp::Fulfill = ilp::FulfillBuilder {
-        fulfillment: b"\
-            \x11\x7b\x43\x4f\x1a\x54\xe9\x04\x4f\x4f\x54\x92\x3b\x2c\xff\x9e\
-            \x4a\x6d\x42\x0a\xe2\x81\xd5\x02\x5d\x7b\xb0\x40\xc4\xb4\xc0\x4a\
-        ",
-        data: b"fulfill data",
-    }.build();
-
-    pub static ref REJECT: ilp::Reject = ilp::RejectBuilder {
-        code: ilp::ErrorCode::F99_APPLICATION_ERROR,
-        message: b"Some error",
-        triggered_by: Some(ilp::Addr::new(b"example.connector")),
-        data: b"reject data",
-    }.build();
-
-    pub static ref ROUTES: Vec<StaticRoute> = vec![
-        StaticRoute {
-            target_prefix: Bytes::from("test.alice."),
-            account: Arc::new("alice".to_owned()),
-            next_hop: NextHop::Bilateral {
-                endpoint: format!("{}/alice", RECEIVER_ORIGIN).parse::<Uri>().unwrap(),
-                auth: Some(AuthToken::new("alice_auth")),
-            },
-            failover: None,
-            partition: 1.0,
-        },
-        StaticRoute {
-            target_prefix: Bytes::from("test.relay."),
-            account: Arc::new("bob".to_owned()),
-            next_hop: NextHop::Multilateral {
-                endpoint_prefix: Bytes::from(format!("{}/bob/", RECEIVER_ORIGIN)),
-                endpoint_suffix: Bytes::from("/ilp"),
-                auth: Some(AuthToken::new("bob_auth")),
-            },
-            failover: None,
-            partition: 1.0,
-        },
-        StaticRoute {
-            target_prefix: Bytes::from(""),
-            account: Arc::new("default".to_owned()),
-            next_hop: NextHop::Bilateral {
-                endpoint: format!("{}/default", RECEIVER_ORIGIN).parse::<Uri>().unwrap(),
-                auth: Some(AuthToken::new("default_auth")),
-            },
-            failover: None,
-            partition: 1.0,
-        },
-    ];
-}
-
-fn truncate_nanos(time: SystemTime) -> SystemTime {
-    let since_epoch = time
-        .duration_since(SystemTime::UNIX_EPOCH)
+/// A self-signed certificate for `alice.example.com`.
+static CLIENT_CERT_PEM: &str = "\
+-----BEGIN CERTIFICATE-----
+MIIBrjCCAVOgAwIBAgIUfYQfUNc97s4Wkp2A4jh/b6xcWrcwCgYIKoZIzj0EAwIw
+HDEaMBgGA1UEAwwRYWxpY2UuZXhhbXBsZS5jb20wIBcNMjYxMDE3MDI1MTQ0WhgP
+MjEyNjA5MjMwMjUxNDRaMBwxGjAYBgNVBAMMEWFsaWNlLmV4YW1wbGUuY29tMFkw
+EwYHKoZIzj0CAQYIKoZIzj0DAQcDQgAEfh0jKnuAR3Ftz/LCA++dGOknh1u9TBtY
+pJ4j2FFGxUk+2eBzPkicoXJsc4QMdWZQnmxMewhdvbvxh8O4/nOYeqNxMG8wHQYD
+VR0OBBYEFMoNRIblrvGTbp8R2MHEIMrzF5BJMB8GA1UdIwQYMBaAFMoNRIblrvGT
+bp8R2MHEIMrzF5BJMA8GA1UdEwEB/wQFMAMBAf8wHAYDVR0RBBUwE4IRYWxpY2Uu
+ZXhhbXBsZS5jb20wCgYIKoZIzj0EAwIDSQAwRgIhAMumR+huU9WzvpXD8yCLH3sY
+84Pt2hCBE1nPgD1T+EuBAiEAh02zDN2HztQ/IeaxXSmRSDAh8R2PV2wBcD6CfhZo
+LY8=
+-----END CERTIFICATE-----
+";
+pub static CLIENT_CERT_FINGERPRINT: &str = "\
+    3A:DA:36:B8:B5:B2:E5:57:0F:AA:FB:18:08:3A:B2:0C:\
+    AE:B3:DD:AE:AA:A1:94:DB:72:3B:C8:2E:D8:77:C5:C2";
+
+pub fn client_certificate() -> ClientCertificate {
+    let mut certs = rustls::internal::pemfile::certs(&mut CLIENT_CERT_PEM.as_bytes())
         .unwrap();
-    let nanos = since_epoch.subsec_nanos();
-    time - Duration::from_nanos(nanos.into())
-}
-
-pub type IlpResult = Result<ilp::Fulfill, ilp::Reject>;
-
-#[derive(Clone, Debug)]
-pub struct MockService<Req> {
-    requests: Arc<RwLock<Vec<Req>>>,
-    response: Arc<IlpResult>,
-}
-
-impl<Req> MockService<Req>
-where
-    Req: Request + Clone,
-{
-    pub fn new(response: IlpResult) -> Self {
-        MockService {
-            requests: Default::default(),
-            response: Arc::new(response),
-        }
-    }
-
-    pub fn requests(&self) -> impl Iterator<Item = Req> {
-        self.requests
-            .read()
-            .unwrap()
-            .clone()
-            .into_iter()
-    }
-
-    pub fn prepares(&self) -> impl Iterator<Item = ilp::Prepare> {
-        self.requests()
-            .map(|request| request.borrow().clone())
-    }
-}
-
-impl<Req> Service<Req> for MockService<Req>
-where
-    Req: Request + Clone,
-{
-    type Future = future::Ready<Result<ilp::Fulfill, ilp::Reject>>;
-
-    fn call(self, request: Req) -> Self::Future {
-        self.requests
-            .write()
-            .unwrap()
-            .push(request);
-        future::ready(self.response.as_ref().clone())
-    }
-}
-
-/// Waits before responding.
-#[derive(Clone, Debug)]
-pub struct DelayService<S> {
-    delay: Duration,
-    next: S,
+    ClientCertificate::new(certs.remove(0).0)
 }
 
-impl<S> DelayService<S> {
-    pub fn new(delay: Duration, next: S) -> Self {
-        DelayService { delay, next }
-    }
-}
-
-impl<S, Req> Service<Req> for DelayService<S>
+/// Serve a `BtpReceiver` at `/btp` (on a random port), which authenticates
+/// the child `btp_account` with the token `btp_secret`. Other requests get a
+/// `404`. This must be called from within a runtime.
+pub fn serve_btp<S>(service: S) -> SocketAddr
 where
-    S: Service<Req> + Send + 'static,
-    Req: Request + Send + 'static,
+    S: Service<RequestWithHeaders> + Send + Sync + 'static,
 {
-    type Future = Pin<Box<dyn Future<
-        Output = Result<ilp::Fulfill, ilp::Reject>,
-    > + 'static + Send>>;
-
-    fn call(self, request: Req) -> Self::Future {
-        let future = tokio::time::delay_for(self.delay)
-            .then(move |_| self.next.call(request));
-        Box::pin(future)
-    }
-}
-
-/// Dummy service to verify that no prepares arrive.
-#[derive(Clone, Debug)]
-pub struct PanicService;
-
-impl<Req: Request> Service<Req> for PanicService {
-    type Future = Pin<Box<dyn Future<
-        Output = Result<ilp::Fulfill, ilp::Reject>,
-    > + Send + 'static>>;
-
-    fn call(self, request: Req) -> Self::Future {
-        panic!("PanicService received prepare={:?}", request.borrow());
-    }
+    let peers = Arc::new(PeerIndex::new(AuthHeader::default(), vec![
+        ConnectorPeer {
+            relation: Relation::Child,
+            account: Arc::new("btp_account".to_owned()),
+            address: ilp::Address::new(b"test.relay.btp"),
+            auth: vec![PeerAuthToken::new("btp_secret")].into_iter().collect::<HashSet<_>>(),
+            certificate: None,
+            signing_secret: None,
+            trust_deadline: false,
+        },
+    ]));
+    let receiver = BtpReceiver::new(
+        Some("/btp".to_owned()),
+        peers,
+        Arc::new(IpAllowlist::default()),
+        Arc::new(Metrics::default()),
+        service,
+        hyper::service::service_fn(|_request| future::ok::<_, hyper::Error>({
+            hyper::Response::builder()
+                .status(hyper::StatusCode::NOT_FOUND)
+                .body(hyper::Body::empty())
+                .unwrap()
+        })),
+    );
+    let server = hyper::Server::bind(&([127, 0, 0, 1], 0).into())
+        .serve(hyper::service::make_service_fn(move |_socket| {
+            future::ok::<_, std::convert::Infallible>(receiver.clone())
+        }));
+    let addr = server.local_addr();
+    tokio::spawn(server.map(|result| result.unwrap()));
+    addr
 }
 
 lazy_static! {
@@ -0,0 +1,233 @@
+//! TLS for the listener, and binding relations to client certificates.
+
+use std::fmt;
+use std::fs;
+use std::io;
+
+use rustls::Session;
+use serde::de::{Deserialize, Deserializer, Error as _};
+
+use crate::app::SetupError;
+
+/// Serve HTTPS instead of HTTP. When `client_ca_file` is set, clients must
+/// present a certificate signed by one of its CAs (mutual TLS).
+#[derive(Clone, Debug, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct TlsConfig {
+    /// A PEM file with the server's certificate chain.
+    pub cert_file: String,
+    /// A PEM file with the server's (PKCS #8 or RSA) private key.
+    pub key_file: String,
+    /// A PEM file with the CA certificates that client certificates are
+    /// verified against.
+    #[serde(default)]
+    pub client_ca_file: Option<String>,
+}
+
+impl TlsConfig {
+    pub fn server_config(&self) -> Result<rustls::ServerConfig, SetupError> {
+        use rustls::internal::pemfile;
+
+        let verifier = match &self.client_ca_file {
+            Some(client_ca_file) => {
+                let mut roots = rustls::RootCertStore::empty();
+                let (added, _ignored) = roots.add_pem_file(
+                    &mut read_file(client_ca_file)?.as_slice(),
+                ).map_err(|_| invalid_pem(client_ca_file))?;
+                if added == 0 {
+                    return Err(invalid_pem(client_ca_file));
+                }
+                rustls::AllowAnyAuthenticatedClient::new(roots)
+            },
+            None => rustls::NoClientAuth::new(),
+        };
+
+        let certs = pemfile::certs(&mut read_file(&self.cert_file)?.as_slice())
+            .map_err(|_| invalid_pem(&self.cert_file))?;
+        let key_pem = read_file(&self.key_file)?;
+        let key = pemfile::pkcs8_private_keys(&mut key_pem.as_slice())
+            .ok()
+            .filter(|keys| !keys.is_empty())
+            .or_else(|| pemfile::rsa_private_keys(&mut key_pem.as_slice()).ok())
+            .and_then(|mut keys| keys.pop())
+            .ok_or_else(|| invalid_pem(&self.key_file))?;
+
+        let mut config = rustls::ServerConfig::new(verifier);
+        config.set_single_cert(certs, key)
+            .map_err(|error| SetupError::invalid_config({
+                format!("invalid TLS certificate or key: {}", error)
+            }))?;
+        config.set_protocols(&[b"h2".to_vec(), b"http/1.1".to_vec()]);
+        Ok(config)
+    }
+}
+
+fn read_file(path: &str) -> Result<Vec<u8>, SetupError> {
+    fs::read(path).map_err(|error: io::Error| {
+        SetupError::invalid_config(format!("error reading {}: {}", path, error))
+    })
+}
+
+fn invalid_pem(path: &str) -> SetupError {
+    SetupError::invalid_config(format!("invalid PEM file: {}", path))
+}
+
+/// The end-entity certificate that a client presented during the handshake.
+///
+/// It is attached to each request's extensions by the listener.
+#[derive(Clone, PartialEq)]
+pub struct ClientCertificate {
+    der: Vec<u8>,
+    fingerprint: Fingerprint,
+}
+
+impl ClientCertificate {
+    pub fn new(der: Vec<u8>) -> Self {
+        let digest = ring::digest::digest(&ring::digest::SHA256, &der);
+        let mut fingerprint = [0; 32];
+        fingerprint.copy_from_slice(digest.as_ref());
+        ClientCertificate {
+            der,
+            fingerprint: Fingerprint(fingerprint),
+        }
+    }
+
+    pub fn from_session(session: &rustls::ServerSession) -> Option<Self> {
+        session
+            .get_peer_certificates()
+            .and_then(|certs| certs.into_iter().next())
+            .map(|cert| ClientCertificate::new(cert.0))
+    }
+
+    /// The SHA-256 digest of the DER-encoded certificate.
+    pub fn fingerprint(&self) -> &Fingerprint {
+        &self.fingerprint
+    }
+
+    /// Whether the certificate's subject alternative names include `dns_name`
+    /// (wildcard SANs are supported).
+    fn has_dns_name(&self, dns_name: &str) -> bool {
+        let dns_name = match webpki::DNSNameRef::try_from_ascii_str(dns_name) {
+            Ok(dns_name) => dns_name,
+            Err(_) => return false,
+        };
+        webpki::EndEntityCert::from(&self.der)
+            .and_then(|cert| cert.verify_is_valid_for_dns_name(dns_name))
+            .is_ok()
+    }
+}
+
+impl fmt::Debug for ClientCertificate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("ClientCertificate")
+            .field("fingerprint", &self.fingerprint)
+            .finish()
+    }
+}
+
+/// The client certificates that a relation may connect with. A certificate
+/// matches when its fingerprint or one of its DNS names is listed.
+#[derive(Clone, Debug, Default, PartialEq, serde::Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct CertificateBinding {
+    #[serde(default)]
+    pub fingerprints: Vec<Fingerprint>,
+    #[serde(default)]
+    pub dns_names: Vec<String>,
+}
+
+impl CertificateBinding {
+    pub fn matches(&self, certificate: &ClientCertificate) -> bool {
+        self.fingerprints.contains(certificate.fingerprint())
+            || self.dns_names
+                .iter()
+                .any(|dns_name| certificate.has_dns_name(dns_name))
+    }
+}
+
+/// A SHA-256 certificate fingerprint, written in hex (optionally with colons,
+/// as printed by `openssl x509 -noout -fingerprint -sha256`).
+#[derive(Clone, Copy, Eq, Hash, PartialEq)]
+pub struct Fingerprint([u8; 32]);
+
+impl Fingerprint {
+    fn parse(string: &str) -> Option<Self> {
+        let digits = string
+            .bytes()
+            .filter(|&byte| byte != b':')
+            .map(|byte| (byte as char).to_digit(16))
+            .collect::<Option<Vec<u32>>>()?;
+        if digits.len() != 64 {
+            return None;
+        }
+        let mut fingerprint = [0; 32];
+        for (byte, pair) in fingerprint.iter_mut().zip(digits.chunks(2)) {
+            *byte = (pair[0] * 16 + pair[1]) as u8;
+        }
+        Some(Fingerprint(fingerprint))
+    }
+}
+
+impl fmt::Debug for Fingerprint {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for byte in &self.0 {
+            write!(f, "{:02X}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl<'de> Deserialize<'de> for Fingerprint {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let string = <&str>::deserialize(deserializer)?;
+        Fingerprint::parse(string)
+            .ok_or_else(|| D::Error::custom("invalid SHA-256 fingerprint"))
+    }
+}
+
+#[cfg(test)]
+mod test_certificate_binding {
+    use crate::testing::{CLIENT_CERT_FINGERPRINT as FINGERPRINT, client_certificate};
+    use super::*;
+
+    fn make_binding(json: &str) -> CertificateBinding {
+        serde_json::from_str(json).unwrap()
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let fingerprint = Fingerprint::parse(FINGERPRINT).unwrap();
+        assert_eq!(client_certificate().fingerprint(), &fingerprint);
+        assert_eq!(
+            Fingerprint::parse(&FINGERPRINT.replace(':', "").to_lowercase()),
+            Some(fingerprint),
+        );
+        assert_eq!(format!("{:?}", fingerprint), FINGERPRINT.replace(':', ""));
+        assert!(Fingerprint::parse("3A:DA").is_none());
+        assert!(Fingerprint::parse(&FINGERPRINT.replace('A', "Z")).is_none());
+    }
+
+    #[test]
+    fn test_matches() {
+        let certificate = client_certificate();
+        assert!(make_binding(&format!(
+            r#"{{ "fingerprints": ["{}"] }}"#,
+            FINGERPRINT,
+        )).matches(&certificate));
+        assert!(make_binding(r#"{ "dns_names": ["alice.example.com"] }"#)
+            .matches(&certificate));
+
+        assert!(!make_binding(r#"{ "dns_names": ["bob.example.com"] }"#)
+            .matches(&certificate));
+        assert!(!make_binding(r#"{ "dns_names": ["not a name"] }"#)
+            .matches(&certificate));
+        assert!(!make_binding(&format!(
+            r#"{{ "fingerprints": ["{}"] }}"#,
+            "00".repeat(32),
+        )).matches(&certificate));
+        assert!(!CertificateBinding::default().matches(&certificate));
+    }
+}
@@ -0,0 +1,186 @@
+//! Adapters between the relay's `Service` and `tower_service::Service`, so
+//! that `tower` middleware (e.g. timeouts, load shedding, or buffers) can be
+//! used in a packet pipeline.
+//!
+//! For example, `FromTower::new(address, Timeout::new(IntoTower::new(svc), d))`
+//! is a relay `Service` that times out `svc`'s responses.
+
+use std::error;
+use std::pin::Pin;
+use std::task::{Context, Poll};
+
+use futures::future::poll_fn;
+use futures::prelude::*;
+use log::warn;
+
+use crate::{Request, RequestId, Service};
+use crate::reject_reasons::MIDDLEWARE_ERROR;
+
+type BoxError = Box<dyn error::Error + Send + Sync>;
+
+/// A `tower_service::Service` that calls a relay `Service`.
+///
+/// It is always ready, and its error is the `ilp::Reject`.
+#[derive(Clone, Debug)]
+pub struct IntoTower<S> {
+    next: S,
+}
+
+impl<S> IntoTower<S> {
+    pub fn new(next: S) -> Self {
+        IntoTower { next }
+    }
+
+    pub fn into_inner(self) -> S {
+        self.next
+    }
+}
+
+impl<S, Req> tower_service::Service<Req> for IntoTower<S>
+where
+    S: Service<Req>,
+    Req: Request,
+{
+    type Response = ilp::Fulfill;
+    type Error = ilp::Reject;
+    type Future = S::Future;
+
+    fn poll_ready(&mut self, _cx: &mut Context<'_>)
+        -> Poll<Result<(), Self::Error>>
+    {
+        Poll::Ready(Ok(()))
+    }
+
+    fn call(&mut self, request: Req) -> Self::Future {
+        self.next.clone().call(request)
+    }
+}
+
+/// A relay `Service` that calls a `tower_service::Service`.
+///
+/// Errors that are an `ilp::Reject` (e.g. from an `IntoTower` beneath the
+/// middleware) are returned as is. Other errors (e.g. a timeout) are logged,
+/// and the Prepare is rejected with `T00_INTERNAL_ERROR`.
+#[derive(Clone, Debug)]
+pub struct FromTower<T> {
+    address: ilp::Address,
+    next: T,
+}
+
+impl<T> FromTower<T> {
+    pub fn new(address: ilp::Address, next: T) -> Self {
+        FromTower { address, next }
+    }
+}
+
+impl<T, Req> Service<Req> for FromTower<T>
+where
+    T: 'static + tower_service::Service<Req, Response = ilp::Fulfill>
+        + Clone + Send,
+    T::Error: Into<BoxError>,
+    T::Future: Send,
+    Req: 'static + Request + Send,
+{
+    type Future = Pin<Box<
+        dyn Future<
+            Output = Result<ilp::Fulfill, ilp::Reject>,
+        > + Send + 'static,
+    >>;
+
+    fn call(self, request: Req) -> Self::Future {
+        let mut next = self.next;
+        let address = self.address;
+        Box::pin(async move {
+            let request_id = RequestId::of(&request);
+            // `T::Error` isn't necessarily `Send`, so it must not be held
+            // across an `await`.
+            if let Err(error) = poll_fn(|cx| next.poll_ready(cx)).await {
+                return Err(into_reject(&address, &request_id, error));
+            }
+            next.call(request)
+                .await
+                .map_err(|error| into_reject(&address, &request_id, error))
+        })
+    }
+}
+
+fn into_reject<E>(address: &ilp::Address, request_id: &RequestId, error: E)
+    -> ilp::Reject
+where
+    E: Into<BoxError>,
+{
+    match error.into().downcast::<ilp::Reject>() {
+        Ok(reject) => *reject,
+        Err(error) => {
+            warn!(
+                "tower service error: request_id={} error={}",
+                request_id, error,
+            );
+            MIDDLEWARE_ERROR.to_reject(address.as_addr())
+        },
+    }
+}
+
+#[cfg(test)]
+mod test_tower {
+    use std::fmt;
+
+    use futures::executor::block_on;
+    use tower_service::Service as _;
+
+    use crate::testing::{ADDRESS, FULFILL, MockService, PREPARE, REJECT};
+    use super::*;
+
+    /// A `tower` service that always fails with an error that isn't a Reject.
+    #[derive(Clone, Debug)]
+    struct FailingService;
+
+    impl tower_service::Service<ilp::Prepare> for FailingService {
+        type Response = ilp::Fulfill;
+        type Error = fmt::Error;
+        type Future = future::Ready<Result<ilp::Fulfill, fmt::Error>>;
+
+        fn poll_ready(&mut self, _cx: &mut Context<'_>)
+            -> Poll<Result<(), Self::Error>>
+        {
+            Poll::Ready(Ok(()))
+        }
+
+        fn call(&mut self, _request: ilp::Prepare) -> Self::Future {
+            future::err(fmt::Error)
+        }
+    }
+
+    #[test]
+    fn test_into_tower() {
+        let next = MockService::new(Err(REJECT.clone()));
+        let mut service = IntoTower::new(next.clone());
+        block_on(poll_fn(|cx| service.poll_ready(cx))).unwrap();
+        let reject = block_on(service.call(PREPARE.clone())).unwrap_err();
+        assert_eq!(reject, *REJECT);
+        assert_eq!(next.prepares().collect::<Vec<_>>(), vec![PREPARE.clone()]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let next = MockService::new(Ok(FULFILL.clone()));
+        let service = FromTower::new(
+            ADDRESS.to_address(),
+            IntoTower::new(next.clone()),
+        );
+        assert_eq!(block_on(service.call(PREPARE.clone())).unwrap(), *FULFILL);
+
+        let service = FromTower::new(
+            ADDRESS.to_address(),
+            IntoTower::new(MockService::new(Err(REJECT.clone()))),
+        );
+        assert_eq!(block_on(service.call(PREPARE.clone())).unwrap_err(), *REJECT);
+    }
+
+    #[test]
+    fn test_from_tower_error() {
+        let service = FromTower::new(ADDRESS.to_address(), FailingService);
+        let reject = block_on(service.call(PREPARE.clone())).unwrap_err();
+        assert_eq!(reject, MIDDLEWARE_ERROR.to_reject(ADDRESS));
+    }
+}